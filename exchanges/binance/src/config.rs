@@ -4,12 +4,19 @@
 use pyo3::{pyclass, pymethods};
 use rust_decimal_macros::dec;
 
-use rbot_lib::common::{env_api_key, env_api_secret, FeeType, MarketConfig, SecretString, ExchangeConfig};
+use chrono::{Datelike as _, Duration, NaiveDate, TimeZone as _, Utc, Weekday};
+use rbot_lib::common::{env_api_key, env_api_secret, FeeType, MarketConfig, MicroSec, SecretString, ExchangeConfig, NOW};
+use strum_macros::Display;
 
 use crate::BINANCE;
 
 /// see https://binance-docs.github.io/apidocs/spot/en/#general-info
 
+/// spot testnet(listenKeyの作成もtestnet側で行う)
+/// see https://testnet.binance.vision/
+pub const BINANCE_SPOT_TESTNET_REST: &str = "https://testnet.binance.vision";
+pub const BINANCE_SPOT_TESTNET_WS: &str = "wss://testnet.binance.vision";
+
 #[derive(Clone, Debug)]
 #[pyclass]
 pub struct BinanceServerConfig {
@@ -18,21 +25,60 @@ pub struct BinanceServerConfig {
 impl BinanceServerConfig {
     pub fn new(production: bool) -> ExchangeConfig {
         let rest_server = if production {
-            "https://api.binance.com"            
+            "https://api.binance.com".to_string()
+        } else {
+            BINANCE_SPOT_TESTNET_REST.to_string()
+        };
+
+        let public_ws_server = if production {
+            "wss://stream.binance.com:9443/ws".to_string()
+        } else {
+            format!("{}/ws", BINANCE_SPOT_TESTNET_WS)
+        };
+
+        let private_ws_server = if production {
+            "wss://stream.binance.com:9443".to_string()
+        } else {
+            BINANCE_SPOT_TESTNET_WS.to_string()
+        };
+
+        ExchangeConfig::new(
+            BINANCE,
+            production,
+            &rest_server,
+            &rest_server,
+            &public_ws_server,
+            &private_ws_server,
+            "https://data.binance.vision",
+        )    
+    }
+}
+
+/// COIN-M(コイン建て先物)のサーバ設定
+/// see https://binance-docs.github.io/apidocs/delivery/en/#general-info
+#[derive(Clone, Debug)]
+#[pyclass]
+pub struct BinanceCoinmServerConfig {
+}
+
+impl BinanceCoinmServerConfig {
+    pub fn new(production: bool) -> ExchangeConfig {
+        let rest_server = if production {
+            "https://dapi.binance.com"
         } else {
-            "https://testnet.binance.vision"
+            "https://testnet.binancefuture.com"
         };
 
         let public_ws_server = if production {
-            "wss://stream.binance.com:9443/ws"            
+            "wss://dstream.binance.com/ws"
         } else {
-            "wss://testnet.binance.vision/ws"
+            "wss://dstream.binancefuture.com/ws"
         };
 
         let private_ws_server = if production {
-            "wss://stream.binance.com:9443"            
+            "wss://dstream.binance.com"
         } else {
-            "wss://testnet.binance.vision"
+            "wss://dstream.binancefuture.com"
         };
 
         ExchangeConfig::new(
@@ -43,21 +89,141 @@ impl BinanceServerConfig {
             public_ws_server,
             private_ws_server,
             "https://data.binance.vision",
-        )    
+        )
     }
 }
 
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+/// COIN-Mの限月
+pub enum ContractType {
+    Perpetual,
+    CurrentQuarter,
+    NextQuarter,
+}
 
+/// COIN-Mの銘柄。pair("BTCUSD")と限月からtrade_symbol(BTCUSD_PERP, BTCUSD_240927など)を決める。
 #[pyclass]
-pub struct BinanceConfig {
+#[derive(Debug, Clone)]
+pub struct BinanceCoinmConfig {
+    #[pyo3(get, set)]
+    pub pair: String,
+    #[pyo3(get, set)]
+    pub contract_type: ContractType,
+}
+
+#[pymethods]
+impl BinanceCoinmConfig {
+    #[new]
+    #[pyo3(signature = (pair, contract_type=ContractType::Perpetual))]
+    pub fn new(pair: &str, contract_type: ContractType) -> Self {
+        Self {
+            pair: pair.to_uppercase(),
+            contract_type,
+        }
+    }
+
+    #[getter]
+    pub fn get_trade_symbol(&self) -> String {
+        self.trade_symbol_at(NOW())
+    }
+
+    /// exchange.jsonの無期限(例 "BTC/USD:BTC")の設定をもとに、trade_symbolを限月のものにしたMarketConfig
+    #[getter]
+    pub fn get_market_config(&self) -> anyhow::Result<MarketConfig> {
+        let base = self.pair.strip_suffix("USD").unwrap_or(&self.pair);
+        let mut config =
+            ExchangeConfig::open_exchange_market("binance", &format!("{}/USD:{}", base, base))?;
+
+        config.trade_symbol = self.get_trade_symbol();
+
+        Ok(config)
+    }
 
+    #[classattr]
+    pub fn BTCUSD_PERP() -> MarketConfig {
+        BinanceCoinmConfig::new("BTCUSD", ContractType::Perpetual)
+            .get_market_config()
+            .unwrap()
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("BinanceCoinmConfig({}, {})", self.pair, self.contract_type)
+    }
+}
+
+impl BinanceCoinmConfig {
+    pub fn trade_symbol_at(&self, now: MicroSec) -> String {
+        let expiry = match self.contract_type {
+            ContractType::Perpetual => return format!("{}_PERP", self.pair),
+            ContractType::CurrentQuarter => quarter_expiry(now, 0),
+            ContractType::NextQuarter => quarter_expiry(now, 1),
+        };
+
+        format!("{}_{}", self.pair, expiry.format("%y%m%d"))
+    }
+}
+
+/// 四半期限月の最終金曜日。受渡(08:00 UTC)を過ぎた限月は飛ばし、その先のoffset番目を返す。
+fn quarter_expiry(now: MicroSec, offset: usize) -> NaiveDate {
+    let now = Utc.timestamp_micros(now).unwrap();
+    let mut year = now.year();
+    let mut month = (now.month() + 2) / 3 * 3;
+    let mut skip = offset;
+
+    loop {
+        let expiry = last_friday(year, month);
+        let delivery = Utc.from_utc_datetime(&expiry.and_hms_opt(8, 0, 0).unwrap());
+
+        if now < delivery {
+            if skip == 0 {
+                return expiry;
+            }
+            skip -= 1;
+        }
+
+        month += 3;
+        if 12 < month {
+            month -= 12;
+            year += 1;
+        }
+    }
+}
+
+fn last_friday(year: i32, month: u32) -> NaiveDate {
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    let mut day = NaiveDate::from_ymd_opt(next_year, next_month, 1).unwrap() - Duration::days(1);
+
+    while day.weekday() != Weekday::Fri {
+        day = day - Duration::days(1);
+    }
+
+    day
+}
+
+
+#[pyclass]
+pub struct BinanceConfig {
+    /// アーカイブのダウンロードにaggTradesを使う(BinanceMarket.use_agg_trades の初期値)
+    #[pyo3(get, set)]
+    pub use_agg_trades: bool,
+    /// spot testnet(testnet.binance.vision)に接続する
+    #[pyo3(get, set)]
+    pub testnet: bool,
 }
 
 #[pymethods]
 impl BinanceConfig {
     #[new]
-    pub fn new() -> Self {
-        return BinanceConfig {};
+    #[pyo3(signature = (use_agg_trades=false, testnet=false))]
+    pub fn new(use_agg_trades: bool, testnet: bool) -> Self {
+        return BinanceConfig { use_agg_trades, testnet };
+    }
+
+    /// testnetの場合はREST, listenKey, WebSocketともtestnet.binance.visionを使う
+    #[getter]
+    pub fn get_server_config(&self) -> ExchangeConfig {
+        BinanceServerConfig::new(!self.testnet)
     }
 
     #[classattr]
@@ -83,3 +249,38 @@ impl BinanceConfig {
     }
 }
 
+
+#[cfg(test)]
+mod binance_coinm_config_test {
+    use super::*;
+    use rbot_lib::common::parse_time;
+
+    #[test]
+    fn test_coinm_trade_symbol() {
+        let now = parse_time("2024-08-23T00:00:00.000000+0000");
+
+        let perp = BinanceCoinmConfig::new("btcusd", ContractType::Perpetual);
+        assert_eq!(perp.trade_symbol_at(now), "BTCUSD_PERP");
+
+        let current = BinanceCoinmConfig::new("BTCUSD", ContractType::CurrentQuarter);
+        assert_eq!(current.trade_symbol_at(now), "BTCUSD_240927");
+
+        let next = BinanceCoinmConfig::new("BTCUSD", ContractType::NextQuarter);
+        assert_eq!(next.trade_symbol_at(now), "BTCUSD_241227");
+
+        // 受渡後は次の限月へロールする
+        let after_delivery = parse_time("2024-09-27T08:00:00.000000+0000");
+        assert_eq!(current.trade_symbol_at(after_delivery), "BTCUSD_241227");
+        assert_eq!(next.trade_symbol_at(after_delivery), "BTCUSD_250328");
+    }
+
+    #[test]
+    fn test_coinm_market_config() -> anyhow::Result<()> {
+        let config = BinanceCoinmConfig::new("BTCUSD", ContractType::Perpetual).get_market_config()?;
+
+        assert_eq!(config.trade_category, "inverse");
+        assert_eq!(config.trade_symbol, "BTCUSD_PERP");
+
+        Ok(())
+    }
+}
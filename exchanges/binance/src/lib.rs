@@ -13,6 +13,13 @@ pub use market::*;
 
 
 const BINANCE_BOARD_DEPTH: u32 = 1000;
+/// /api/v3/depthのlimit。WSは深さを指定できないdiff stream(@depth@100ms)を購読し、
+/// ローカルの板をこの深さで切る(partial depthを使わない理由はbinance_ws_channels)。
+const BINANCE_BOARD_DEPTHS: [u32; 9] = [1, 5, 10, 20, 50, 100, 500, 1000, 5000];
+
+fn binance_board_depth(config: &rbot_lib::common::MarketConfig) -> anyhow::Result<u32> {
+    config.resolve_board_depth(&BINANCE_BOARD_DEPTHS, BINANCE_BOARD_DEPTH)
+}
 
 
 
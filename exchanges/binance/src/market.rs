@@ -5,10 +5,13 @@ use std::sync::{Arc, Mutex, RwLock};
 use anyhow::Context;
 use futures::StreamExt;
 use pyo3_polars::PyDataFrame;
+use pyo3_polars::PyLazyFrame;
 use rbot_blockon::BLOCK_ON;
-use rbot_lib::common::{AccountCoins, ExchangeConfig, Trade, DAYS, FLOOR_DAY};
+use rbot_lib::common::{AccountCoins, Channel, Coin, ExchangeConfig, Trade, DAYS, FLOOR_DAY};
 use rbot_lib::common::BoardItem;
 use rbot_lib::common::MarketConfig;
+use rbot_lib::common::to_py_err;
+use rbot_lib::common::BoardEventStream;
 use rbot_lib::common::MarketMessage;
 use rbot_lib::common::MarketStream;
 use rbot_lib::common::MicroSec;
@@ -16,22 +19,32 @@ use rbot_lib::common::MultiMarketMessage;
 use rbot_lib::common::Order;
 use rbot_lib::common::OrderBook;
 use rbot_lib::common::MARKET_HUB;
-use rbot_lib::common::{time_string, NOW};
-use rbot_lib::db::{TradeArchive, TradeDataFrame};
-use rbot_lib::net::{BroadcastMessage, RestApi, WebSocketClient as _};
+use rbot_lib::common::{OrderType, TimeInForce};
+use rbot_lib::common::PyProgressCallback;
+use rbot_lib::common::{extract_time, time_string, NOW};
+use rbot_lib::db::{
+    convert_timems_to_datetime, BalanceDb, BalanceRecorder, BALANCE_SNAPSHOT_INTERVAL_SEC, CacheStats, ColumnStyle, CsvSchema, Ohlcvb, RangeStats, SpreadLogger, TradeArchive, TradeDataFrame, TradeSummary, SPREAD_LOG_DEFAULT_INTERVAL_MS, TimeFormat, GAP_REPORT_ALLOW_SIZE,
+};
+use rbot_lib::net::{
+    download_to_dataframe, make_py_raw_message_hook, BroadcastMessage, RawMessageHook, RestApi, WebSocketClient as _, WsStateHandle,
+    stop_stream_task, stream_stop_signal, STREAM_STOP_TIMEOUT_SEC,
+};
 use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
 // Copyright(c) 2022-2024. yasstake. All rights reserved.
 use tokio::task::JoinHandle;
 
 // use rbot_market::OrderInterface;
-use rbot_market::{extract_or_generate_config, MarketImpl};
+use rbot_market::{extract_or_generate_config, HealthStatus, measure_clock_skew, MarketImpl, OhlcvStream, TradeStream, TRADE_DEDUP_WINDOW};
 use rbot_market::OrderInterfaceImpl;
 // use rbot_market::MarketInterface;
 
-use crate::{BinancePrivateWsClient, BINANCE_BOARD_DEPTH};
+use crate::{binance_board_depth, binance_ws_channels, BinancePrivateWsClient};
 use crate::BinancePublicWsClient;
 use crate::BinanceRestApi;
 use crate::BinanceServerConfig;
+use crate::BinanceConfig;
+use crate::BinanceCoinmServerConfig;
 
 use pyo3::prelude::*;
 
@@ -39,13 +52,18 @@ use anyhow::anyhow;
 
 pub const BINANCE:&str = "BINANCE";
 
-#[pyclass]
+#[pyclass(subclass)]
 pub struct Binance {
     production: bool,
     enable_order: bool,
     server_config: ExchangeConfig,
+    trade_category: &'static str,
     user_handler: Option<JoinHandle<()>>,
+    user_stop: Option<tokio::sync::watch::Sender<bool>>,
     api: BinanceRestApi,
+    raw_message_hook: Option<RawMessageHook>,
+    /// open_marketで開いたマーケットのuse_agg_tradesの初期値
+    use_agg_trades: bool,
 }
 
 #[pymethods]
@@ -53,17 +71,27 @@ impl Binance {
     #[new]
     #[pyo3(signature = (production=false))]
     pub fn new(production: bool) -> Self {
-        let server_config = BinanceServerConfig::new(production);
+        Self::with_server_config(BinanceServerConfig::new(production), "spot")
+    }
 
-        let api = BinanceRestApi::new(&server_config);
+    /// BinanceConfig.testnetがtrueならtestnet.binance.visionのREST/WebSocketを使う
+    #[staticmethod]
+    pub fn from_config(config: &BinanceConfig) -> Self {
+        let mut binance = Self::with_server_config(config.get_server_config(), "spot");
+        binance.use_agg_trades = config.use_agg_trades;
 
-        Self {
-            production: production,
-            enable_order: false,
-            server_config: server_config,
-            user_handler: None,
-            api: api,
-        }
+        binance
+    }
+
+    /// trueの場合、open_marketで開いたマーケットはアーカイブの取得にaggTradesを使う
+    #[getter]
+    fn get_use_agg_trades(&self) -> bool {
+        self.use_agg_trades
+    }
+
+    #[setter]
+    fn set_use_agg_trades(&mut self, use_agg_trades: bool) {
+        self.use_agg_trades = use_agg_trades;
     }
 
     #[getter]
@@ -74,12 +102,15 @@ impl Binance {
     pub fn open_market(&self, config: &PyAny) -> anyhow::Result<BinanceMarket> {
         let config = extract_or_generate_config(&self.server_config.get_exchange_name(), config)?;
         
-        if config.trade_category != "spot"{
+        if config.trade_category != self.trade_category {
             return Err(anyhow!{"not supported trade category {:?}", config.trade_category});
         }
 
 
-        Ok(BinanceMarket::new(&self.server_config, &config))
+        let mut market = BinanceMarket::new(&self.server_config, &config);
+        market.set_use_agg_trades(self.use_agg_trades);
+
+        Ok(market)
     }
 
     //--- OrderInterfaceImpl ----
@@ -93,6 +124,7 @@ impl Binance {
         self.get_enable_order_feature()
     }
 
+    #[pyo3(signature = (market_config, side, price, size, client_order_id=None, time_in_force=None, reduce_only=false, dry_run=false))]
     pub fn limit_order(
         &self,
         market_config: &MarketConfig,
@@ -100,46 +132,151 @@ impl Binance {
         price: Decimal,
         size: Decimal,
         client_order_id: Option<&str>,
-    ) -> anyhow::Result<Vec<Order>> {
-        BLOCK_ON(async {
-            OrderInterfaceImpl::limit_order(self, market_config, side, price, size, client_order_id)
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
+        dry_run: bool,
+    ) -> PyResult<Vec<Order>> {
+        if dry_run {
+            return Ok(vec![BLOCK_ON(async {
+                OrderInterfaceImpl::validate_order(
+                    self,
+                    market_config,
+                    side,
+                    price,
+                    size,
+                    OrderType::Limit,
+                    client_order_id,
+                    time_in_force.unwrap_or_default(),
+                    reduce_only,
+                )
                 .await
-        })
+            }).map_err(to_py_err)?]);
+        }
+
+        BLOCK_ON(async {
+            OrderInterfaceImpl::limit_order(
+                self,
+                market_config,
+                side,
+                price,
+                size,
+                client_order_id,
+                time_in_force,
+                reduce_only,
+            )
+            .await
+        }).map_err(to_py_err)
     }
 
+    #[pyo3(signature = (market_config, side, size, client_order_id=None, reduce_only=false, dry_run=false))]
     pub fn market_order(
         &self,
         market_config: &MarketConfig,
         side: &str,
         size: Decimal,
         client_order_id: Option<&str>,
-    ) -> anyhow::Result<Vec<Order>> {
+        reduce_only: bool,
+        dry_run: bool,
+    ) -> PyResult<Vec<Order>> {
+        if dry_run {
+            return Ok(vec![BLOCK_ON(async {
+                OrderInterfaceImpl::validate_order(
+                    self,
+                    market_config,
+                    side,
+                    dec![0.0],
+                    size,
+                    OrderType::Market,
+                    client_order_id,
+                    TimeInForce::GTC,
+                    reduce_only,
+                )
+                .await
+            }).map_err(to_py_err)?]);
+        }
+
         BLOCK_ON(async {
-            OrderInterfaceImpl::market_order(self, market_config, side, size, client_order_id).await
-        })
+            OrderInterfaceImpl::market_order(
+                self,
+                market_config,
+                side,
+                size,
+                client_order_id,
+                reduce_only,
+            )
+            .await
+        }).map_err(to_py_err)
     }
 
     pub fn cancel_order(
         &self,
         market_config: &MarketConfig,
         order_id: &str,
-    ) -> anyhow::Result<Order> {
-        BLOCK_ON(async { OrderInterfaceImpl::cancel_order(self, market_config, order_id).await })
+    ) -> PyResult<Order> {
+        BLOCK_ON(async { OrderInterfaceImpl::cancel_order(self, market_config, order_id).await }).map_err(to_py_err)
     }
 
-    pub fn get_open_orders(&self, market_config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
-        BLOCK_ON(async { OrderInterfaceImpl::get_open_orders(self, market_config).await })
+    pub fn get_open_orders(&self, market_config: &MarketConfig) -> PyResult<Vec<Order>> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_open_orders(self, market_config).await }).map_err(to_py_err)
+    }
+
+    pub fn get_order_status(&self, market_config: &MarketConfig, order_id: &str) -> PyResult<Order> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_order_status(self, market_config, order_id).await }).map_err(to_py_err)
     }
 
     #[getter]
-    pub fn get_account(&self) -> anyhow::Result<AccountCoins> {
-        BLOCK_ON(async { OrderInterfaceImpl::get_account(self).await })
+    pub fn get_account(&self) -> PyResult<AccountCoins> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_account(self).await }).map_err(to_py_err)
+    }
+
+    pub fn balances(&self) -> anyhow::Result<Vec<Coin>> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_balances(self).await })
+    }
+
+    /// user streamで受け取った残高の履歴(timestamp, coin, equity, free, locked)
+    #[pyo3(signature = (start_time=0, end_time=0))]
+    pub fn balance_history_df(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<PyDataFrame> {
+        let db = BalanceDb::open(BINANCE, self.server_config.is_production())?;
+
+        Ok(PyDataFrame(db.select_df(start_time, end_time)?))
+    }
+
+    /// アーカイブを1日ずつダウンロードしてDataFrameとして返す(DBには保存しない)
+    #[pyo3(signature = (config, start_time, end_time=0))]
+    pub fn download_to_dataframe(&self, config: &PyAny, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<PyDataFrame> {
+        let config = extract_or_generate_config(&self.server_config.get_exchange_name(), config)?;
+
+        let mut df = BLOCK_ON(async {
+            download_to_dataframe(&self.api, &config, start_time, end_time).await
+        })?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
     }
 
     pub fn open_user_stream(&mut self) -> anyhow::Result<()> {
         BLOCK_ON(async { OrderInterfaceImpl::async_start_user_stream(self).await })
     }
 
+    /// user streamを停止し、タスクの終了を待つ
+    pub fn stop_user_stream(&mut self) {
+        let stop = self.user_stop.take();
+        let handle = self.user_handler.take();
+
+        BLOCK_ON(async { stop_stream_task(stop, handle, STREAM_STOP_TIMEOUT_SEC).await })
+    }
+
+    /// user data streamを開始する（listenKeyは25分毎に自動延長、切断時は再作成）
+    pub fn start_user_stream(&mut self) -> anyhow::Result<()> {
+        self.open_user_stream()
+    }
+
+    /// set callback(str) for raw user stream frames. must be set before open_user_stream.
+    #[pyo3(signature = (handler=None))]
+    pub fn on_raw_message(&mut self, handler: Option<PyObject>) {
+        self.raw_message_hook = handler.map(make_py_raw_message_hook);
+    }
+
     pub fn __str__(&self) -> String {
         format!(
             "{{production: {}, enable_order: {}, server_config: {:?} }}",
@@ -164,15 +301,53 @@ impl OrderInterfaceImpl<BinanceRestApi> for Binance {
     async fn async_start_user_stream(&mut self) -> anyhow::Result<()> {
         let exchange_name = BINANCE.to_string();
         let server_config = self.server_config.clone();
+        let raw_message_hook = self.raw_message_hook.clone();
+
+        // wallet更新と定期的な残高をbalance_historyへ記録する(開けなくてもstreamは続ける)
+        let balance_recorder = BalanceRecorder::open(&exchange_name, server_config.is_production())
+            .map_err(|e| log::error!("balance db open error {:?}", e))
+            .ok();
+        let snapshot_api = self.api.clone();
+
+        let (stop_tx, mut stop_rx) = stream_stop_signal();
+        self.user_stop = Some(stop_tx);
 
         self.user_handler = Some(tokio::task::spawn(async move {
             let mut ws = BinancePrivateWsClient::new(&server_config).await;
+            ws.set_raw_message_hook(raw_message_hook);
             ws.connect().await;
 
             let market_channel = MARKET_HUB.open_channel();
             let mut ws_stream = Box::pin(ws.open_stream().await);
 
-            while let Some(message) = ws_stream.next().await {
+            let mut balance_snapshot =
+                tokio::time::interval(std::time::Duration::from_secs(BALANCE_SNAPSHOT_INTERVAL_SEC));
+
+            loop {
+                let message = tokio::select! {
+                    _ = stop_rx.changed() => {
+                        log::info!("user stream stopped");
+                        break;
+                    }
+                    _ = balance_snapshot.tick(), if balance_recorder.is_some() => {
+                        match snapshot_api.get_account().await {
+                            Ok(coins) => {
+                                if let Err(e) = balance_recorder.as_ref().unwrap().record(NOW(), coins).await {
+                                    log::error!("balance history insert error {:?}", e);
+                                }
+                            }
+                            Err(e) => log::warn!("balance snapshot error {:?}", e),
+                        }
+                        continue;
+                    }
+                    message = ws_stream.next() => message,
+                };
+
+                let message = match message {
+                    Some(m) => m,
+                    None => break,
+                };
+
                 if message.is_err() {
                     log::error!("Error in ws_stream.recv: {:?}", message);
                     continue;
@@ -192,6 +367,12 @@ impl OrderInterfaceImpl<BinanceRestApi> for Binance {
                         }
                     }
                     MultiMarketMessage::Account(account) => {
+                        if let Some(recorder) = balance_recorder.as_ref() {
+                            if let Err(e) = recorder.record(NOW(), account.clone()).await {
+                                log::error!("balance history insert error {:?}", e);
+                            }
+                        }
+
                         let _ = market_channel.send(BroadcastMessage {
                             exchange: exchange_name.clone(),
                             category: "".to_string(),
@@ -211,6 +392,51 @@ impl OrderInterfaceImpl<BinanceRestApi> for Binance {
     
 }
 
+impl Binance {
+    fn with_server_config(server_config: ExchangeConfig, trade_category: &'static str) -> Self {
+        let api = BinanceRestApi::new(&server_config);
+
+        // 署名付きリクエストのtimestampを補正するため時差を測っておく
+        if server_config.get_api_key().extract() != "" {
+            if let Err(e) = BLOCK_ON(async { measure_clock_skew(&api).await }) {
+                log::warn!("failed to get server time: {:?}", e);
+            }
+        }
+
+        Self {
+            production: server_config.is_production(),
+            enable_order: false,
+            server_config: server_config,
+            trade_category: trade_category,
+            user_handler: None,
+            user_stop: None,
+            api: api,
+            raw_message_hook: None,
+            use_agg_trades: false,
+        }
+    }
+}
+
+/// COIN-M(コイン建て先物, dapi/dstream)。open_marketはinverseの市場のみ受け付ける。
+/// 発注・user streamは未対応。
+#[pyclass(extends=Binance)]
+pub struct BinanceCoinm {}
+
+#[pymethods]
+impl BinanceCoinm {
+    #[new]
+    #[pyo3(signature = (production=false))]
+    pub fn new(production: bool) -> (Self, Binance) {
+        (
+            Self {},
+            Binance::with_server_config(BinanceCoinmServerConfig::new(production), "inverse"),
+        )
+    }
+}
+
+/// COIN-Mの市場。REST/WSの接続先はBinanceCoinmServerConfig、パスと購読チャンネル(@aggTrade)はtrade_categoryで切り替える。
+pub type BinanceCoinmMarket = BinanceMarket;
+
 #[pyclass]
 pub struct BinanceMarket {
     server_config: ExchangeConfig,
@@ -219,6 +445,10 @@ pub struct BinanceMarket {
     pub db: Arc<Mutex<TradeDataFrame>>,
     pub board: Arc<RwLock<OrderBook>>,
     pub public_handler: Option<tokio::task::JoinHandle<()>>,
+    public_stop: Option<tokio::sync::watch::Sender<bool>>,
+    raw_message_hook: Option<RawMessageHook>,
+    spread_logger: Option<SpreadLogger>,
+    ws_state: WsStateHandle,
 }
 
 #[pymethods]
@@ -255,12 +485,31 @@ impl BinanceMarket {
         MarketImpl::get_db_info(self)
     }
 
+    #[pyo3(signature = (ndays=2))]
+    fn gap_report(&self, ndays: i64) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::gap_report(self, ndays)
+    }
+
+    fn range_stats(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<RangeStats> {
+        MarketImpl::range_stats(self, start_time, end_time)
+    }
+
+    fn count(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<u64> {
+        MarketImpl::count(self, start_time, end_time)
+    }
+
+    fn summary(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<TradeSummary> {
+        MarketImpl::summary(self, start_time, end_time)
+    }
+
+    #[pyo3(signature = (start_time, end_time, column_style=ColumnStyle::Default))]
     fn select_trades(
         &mut self,
         start_time: MicroSec,
         end_time: MicroSec,
+        column_style: ColumnStyle,
     ) -> anyhow::Result<PyDataFrame> {
-        MarketImpl::select_trades(self, start_time, end_time)
+        MarketImpl::select_trades(self, start_time, end_time, column_style)
     }
 
     fn _select_db_trades(
@@ -295,6 +544,15 @@ impl BinanceMarket {
         MarketImpl::select_cache_ohlcv_df(self, start_time, end_time)
     }
 
+    fn select_large_trades(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::select_large_trades(self, start_time, end_time, min_size)
+    }
+
     fn ohlcvv(
         &mut self,
         start_time: MicroSec,
@@ -304,13 +562,66 @@ impl BinanceMarket {
         MarketImpl::ohlcvv(self, start_time, end_time, window_sec)
     }
 
+    fn ohlcv_large(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::ohlcv_large(self, start_time, end_time, window_sec, min_size)
+    }
+
+    /// ohlcvの遅延評価版。filterなどを重ねてからcollect()する。
+    fn ohlcv_lazy(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+    ) -> anyhow::Result<PyLazyFrame> {
+        MarketImpl::ohlcv_lazy(self, start_time, end_time, window_sec)
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, column_style=ColumnStyle::Default, day_start_offset_sec=0))]
     fn ohlcv(
         &mut self,
         start_time: MicroSec,
         end_time: MicroSec,
         window_sec: i64,
+        column_style: ColumnStyle,
+        day_start_offset_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::ohlcv(
+            self,
+            start_time,
+            end_time,
+            window_sec,
+            column_style,
+            day_start_offset_sec,
+        )
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, absolute=false))]
+    fn cvd(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        absolute: bool,
     ) -> anyhow::Result<PyDataFrame> {
-        MarketImpl::ohlcv(self, start_time, end_time, window_sec)
+        MarketImpl::cvd(self, start_time, end_time, window_sec, absolute)
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, path, iso_time=true))]
+    fn ohlcv_csv(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        path: &str,
+        iso_time: bool,
+    ) -> anyhow::Result<i64> {
+        MarketImpl::ohlcv_csv(self, start_time, end_time, window_sec, path, iso_time)
     }
 
     fn vap(
@@ -322,10 +633,39 @@ impl BinanceMarket {
         MarketImpl::vap(self, start_time, end_time, price_unit)
     }
 
+    fn prefetch(&mut self, start_time: MicroSec, end_time: MicroSec) {
+        BLOCK_ON(async { MarketImpl::prefetch(self, start_time, end_time) })
+    }
+
+    #[getter]
+    fn get_cache_hit_rate(&self) -> f64 {
+        MarketImpl::cache_hit_rate(self)
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        MarketImpl::cache_stats(self)
+    }
+
+    #[getter]
+    fn get_cache_max_rows(&self) -> usize {
+        MarketImpl::get_cache_max_rows(self)
+    }
+
+    /// キャッシュの最大行数（0は無制限）。超えた分は古いデータから捨てる。
+    #[setter]
+    fn set_cache_max_rows(&mut self, max_rows: usize) -> anyhow::Result<()> {
+        MarketImpl::set_cache_max_rows(self, max_rows)
+    }
+
     fn get_board_json(&self, size: usize) -> anyhow::Result<String> {
         MarketImpl::get_board_json(self, size)
     }
 
+    /// 以降の板の更新ごとのBoardDiff(add/cancel/modify)を受け取る
+    fn board_events(&self) -> BoardEventStream {
+        MarketImpl::board_events(self)
+    }
+
     #[getter]
     fn get_board(&mut self) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
         BLOCK_ON(async {
@@ -338,6 +678,61 @@ impl BinanceMarket {
         MarketImpl::get_board_vec(self)
     }
 
+    /// 板をshape(2, depth, 2)のnumpy配列で返す(薄い板は0埋め)
+    fn get_board_tensor(&self, py: Python, depth: usize) -> PyObject {
+        MarketImpl::get_board_tensor(self, py, depth)
+    }
+
+    fn get_board_grouped(&self, group: Decimal) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
+        MarketImpl::get_board_grouped(self, group)
+    }
+
+    /// `async for trade in market.trade_stream():`
+    #[pyo3(signature = (dedup_window=TRADE_DEDUP_WINDOW))]
+    fn trade_stream(&self, dedup_window: usize) -> anyhow::Result<TradeStream> {
+        MarketImpl::trade_stream(self, dedup_window)
+    }
+
+    /// `async for bar in market.subscribe_ohlcv(60):`
+    #[pyo3(signature = (window_sec, dedup_window=TRADE_DEDUP_WINDOW))]
+    fn subscribe_ohlcv(&self, window_sec: i64, dedup_window: usize) -> anyhow::Result<OhlcvStream> {
+        MarketImpl::subscribe_ohlcv(self, window_sec, dedup_window)
+    }
+
+    #[getter]
+    fn get_db_thread_running(&self) -> bool {
+        MarketImpl::is_db_thread_running(self)
+    }
+
+    fn restart_db_thread(&mut self) -> anyhow::Result<()> {
+        MarketImpl::restart_db_thread(self)
+    }
+
+    #[pyo3(signature = (path, *, time_col=0, price_col=1, size_col=2, side_col=None, id_col=None, time_format=TimeFormat::UnixMilliseconds, has_header=true))]
+    fn import_csv(
+        &mut self,
+        path: &str,
+        time_col: usize,
+        price_col: usize,
+        size_col: usize,
+        side_col: Option<usize>,
+        id_col: Option<usize>,
+        time_format: TimeFormat,
+        has_header: bool,
+    ) -> anyhow::Result<u64> {
+        let schema = CsvSchema {
+            time_col,
+            time_format,
+            price_col,
+            size_col,
+            side_col,
+            id_col,
+            has_header,
+        };
+
+        MarketImpl::import_csv(self, path, schema)
+    }
+
     #[getter]
     fn get_edge_price(&mut self) -> anyhow::Result<(Decimal, Decimal)> {
         BLOCK_ON(async {
@@ -345,11 +740,90 @@ impl BinanceMarket {
         })
     }
 
+    fn server_time(&self) -> anyhow::Result<MicroSec> {
+        BLOCK_ON(async { MarketImpl::async_server_time(self).await })
+    }
+
+    /// 取引所のサーバ時刻 - ローカル時刻(usec)。1秒以上ずれている場合は警告をログに出す。
+    /// 測った値は署名付きリクエストのtimestampの補正に使う。
+    fn clock_skew(&self) -> anyhow::Result<MicroSec> {
+        BLOCK_ON(async { MarketImpl::async_clock_skew(self).await })
+    }
+
+    /// 取引所からtick size, 最小数量, 契約乗数を取得してconfigを更新する(結果はキャッシュされる)
+    #[pyo3(signature = (force=false))]
+    fn fetch_instrument_info(&mut self, force: bool) -> anyhow::Result<MarketConfig> {
+        let config = BLOCK_ON(async { MarketImpl::async_fetch_instrument_info(self, force).await })?;
+        self.config = config.clone();
+
+        Ok(config)
+    }
+
+    /// 発注せずにREST/WebSocketの接続とAPIキーの権限を確認する(ライブ実行前のチェック用)
+    fn health_check(&self) -> anyhow::Result<HealthStatus> {
+        let ws_url = self.server_config.get_public_ws_server();
+        BLOCK_ON(async { MarketImpl::async_health_check(self, &ws_url).await })
+    }
+
+    /// 板の最良気配(best bid/ask, mid, spread)をinterval_msごとにspread_logへ記録する
+    #[pyo3(signature = (interval_ms=SPREAD_LOG_DEFAULT_INTERVAL_MS))]
+    fn start_spread_logging(&mut self, interval_ms: u64) -> anyhow::Result<()> {
+        BLOCK_ON(async { MarketImpl::async_start_spread_logging(self, interval_ms).await })
+    }
+
+    fn stop_spread_logging(&mut self) {
+        BLOCK_ON(async { MarketImpl::async_stop_spread_logging(self).await })
+    }
+
+    #[pyo3(signature = (start_time=0, end_time=0))]
+    fn get_spread_history(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::get_spread_history(self, start_time, end_time)
+    }
+
+    /// OHLCVにspread(best_ask - best_bid)を加えた足。_repr_html_でspreadの幅を描く。
+    #[pyo3(signature = (start_time, end_time, window_sec=60))]
+    fn ohlcvb(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<Ohlcvb> {
+        MarketImpl::ohlcvb(self, start_time, end_time, window_sec)
+    }
+
+    /// 直近ndays分のDBの空白区間[(start, end)]
+    #[pyo3(signature = (ndays, allow_size=GAP_REPORT_ALLOW_SIZE))]
+    fn gap_chunks(&self, ndays: i64, allow_size: MicroSec) -> anyhow::Result<Vec<(MicroSec, MicroSec)>> {
+        MarketImpl::gap_chunks(self, ndays, allow_size)
+    }
+
+    /// Kyle's lambda(window_sec幅の足ごとの価格変化 ~ 符号付き出来高 の回帰係数)
+    fn kyles_lambda(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<f64> {
+        MarketImpl::kyles_lambda(self, start_time, end_time, window_sec)
+    }
+
+    /// step_secごとのKyle's lambda
+    fn rolling_kyles_lambda(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        step_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::rolling_kyles_lambda(self, start_time, end_time, window_sec, step_sec)
+    }
+
+    /// 約定代金がthresholdに達するごとに区切った足(dollar bar)
+    #[pyo3(signature = (start_time, end_time, threshold=Decimal::from(1_000_000)))]
+    fn dollar_bars(&mut self, start_time: MicroSec, end_time: MicroSec, threshold: Decimal) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::dollar_bars(self, start_time, end_time, threshold)
+    }
+
     fn _repr_html_(&self) -> String {
         MarketImpl::_repr_html_(self)
     }
 
-    #[pyo3(signature = (ndays, *, connect_ws=false, force=false, force_archive=false, force_recent=false, verbose=false))]
+    /// progress_callback(event, url, value, total) receives download progress instead of console output.
+    #[pyo3(signature = (ndays, *, connect_ws=false, force=false, force_archive=false, force_recent=false, verbose=false, progress_callback=None))]
     fn download(
         &mut self,
         ndays: i64,
@@ -358,6 +832,7 @@ impl BinanceMarket {
         force_archive: bool,
         force_recent: bool,
         verbose: bool,
+        progress_callback: Option<PyObject>,
     ) -> anyhow::Result<()> {
         BLOCK_ON(async {
             MarketImpl::async_download::<BinancePublicWsClient>(
@@ -368,14 +843,92 @@ impl BinanceMarket {
                 force_archive,
                 force_recent,
                 verbose,
+                PyProgressCallback::boxed(progress_callback),
             )
             .await
         })
     }
 
-    #[pyo3(signature = (ndays, force=false, verbose=false))]
-    fn _download_archive(&mut self, ndays: i64, force: bool, verbose: bool) -> anyhow::Result<i64> {
-        BLOCK_ON(async { MarketImpl::async_download_archive(self, ndays, force, verbose).await })
+    /// start_time〜end_timeの日のアーカイブをダウンロードする。時刻はMicroSecかISO8601の文字列("2024-08-23")。
+    #[pyo3(signature = (start_time, end_time, *, force=false, verbose=false, progress_callback=None))]
+    fn download_range(
+        &mut self,
+        start_time: &PyAny,
+        end_time: &PyAny,
+        force: bool,
+        verbose: bool,
+        progress_callback: Option<PyObject>,
+    ) -> anyhow::Result<i64> {
+        let start_time = extract_time(start_time)?;
+        let end_time = extract_time(end_time)?;
+
+        BLOCK_ON(async {
+            MarketImpl::async_download_archive_range(
+                self,
+                start_time,
+                end_time,
+                force,
+                verbose,
+                PyProgressCallback::boxed(progress_callback),
+            )
+            .await
+        })
+    }
+
+    /// aggTrades(同一価格・同一方向の連続約定をまとめたもの)のアーカイブをダウンロードする。
+    /// IDはagg_idになるので、同じマーケットでtradesのアーカイブと混在させないこと。
+    #[pyo3(signature = (ndays, force=false, verbose=false, progress_callback=None))]
+    fn download_agg_trades(
+        &mut self,
+        ndays: i64,
+        force: bool,
+        verbose: bool,
+        progress_callback: Option<PyObject>,
+    ) -> anyhow::Result<i64> {
+        let api = self.api.with_agg_trades(true);
+        let mut lock = self.db.lock().map_err(|_| anyhow!("db get lock error"))?;
+
+        BLOCK_ON(async {
+            lock.download_archive(
+                &api,
+                ndays,
+                force,
+                verbose,
+                PyProgressCallback::boxed(progress_callback),
+            )
+            .await
+        })
+    }
+
+    /// trueの場合、downloadなどのアーカイブ取得にaggTradesを使う
+    #[getter]
+    fn get_use_agg_trades(&self) -> bool {
+        self.api.is_agg_trades()
+    }
+
+    #[setter]
+    fn set_use_agg_trades(&mut self, use_agg_trades: bool) {
+        self.api = self.api.with_agg_trades(use_agg_trades);
+    }
+
+    #[pyo3(signature = (ndays, force=false, verbose=false, progress_callback=None))]
+    fn _download_archive(
+        &mut self,
+        ndays: i64,
+        force: bool,
+        verbose: bool,
+        progress_callback: Option<PyObject>,
+    ) -> anyhow::Result<i64> {
+        BLOCK_ON(async {
+            MarketImpl::async_download_archive(
+                self,
+                ndays,
+                force,
+                verbose,
+                PyProgressCallback::boxed(progress_callback),
+            )
+            .await
+        })
     }
 
     fn _download_realtime(
@@ -389,12 +942,23 @@ impl BinanceMarket {
         })
     }
 
+    /// speed: 0.0は待たずに流す。1.0で実時間、10.0で10倍速で再生する
+    #[pyo3(signature = (time_from, time_to, speed=0.0))]
     fn open_backtest_channel(
         &mut self,
         time_from: MicroSec,
         time_to: MicroSec,
+        speed: f64,
     ) -> anyhow::Result<(MicroSec, MicroSec, MarketStream)> {
-        MarketImpl::open_backtest_channel(self, time_from, time_to)
+        MarketImpl::open_backtest_channel_timed(self, time_from, time_to, speed)
+    }
+
+    /// market streamを停止し、タスクの終了を待つ
+    fn stop_market_stream(&mut self) {
+        let stop = self.public_stop.take();
+        let handle = self.public_handler.take();
+
+        BLOCK_ON(async { stop_stream_task(stop, handle, STREAM_STOP_TIMEOUT_SEC).await })
     }
 
     fn open_market_stream(&mut self) -> anyhow::Result<()> {
@@ -403,6 +967,35 @@ impl BinanceMarket {
         })
     }
 
+    /// set callback(str) for raw market stream frames. must be set before open_market_stream.
+    #[pyo3(signature = (handler=None))]
+    fn on_raw_message(&mut self, handler: Option<PyObject>) {
+        self.raw_message_hook = handler.map(make_py_raw_message_hook);
+    }
+
+    /// WSで購読するチャンネルを設定する(空はデフォルト)。open_market_streamの前に呼ぶ。
+    fn set_subscriptions(&mut self, channels: Vec<Channel>) -> anyhow::Result<()> {
+        let mut config = self.config.clone();
+        config.channels = channels;
+        binance_ws_channels(&config)?;
+
+        self.config.channels = config.channels;
+
+        Ok(())
+    }
+
+    /// WebSocketの接続状態(Disconnected, Connecting, Subscribed, Receiving, Reconnecting(n)など)
+    #[getter]
+    fn get_ws_state(&self) -> String {
+        self.ws_state.get().to_string()
+    }
+
+    /// market streamの接続を張り直す(次のメッセージ受信時に再接続する)
+    fn force_reconnect(&self) {
+        log::info!("force reconnect: {}", self.config.trade_symbol);
+        self.ws_state.request_reconnect();
+    }
+
     fn vaccum(&self) -> anyhow::Result<()> {
         let lock = self.db.lock().unwrap();
 
@@ -484,7 +1077,11 @@ impl MarketImpl<BinanceRestApi> for BinanceMarket {
 
         let hub_channel = MARKET_HUB.open_channel();
 
+        binance_ws_channels(&config)?;
+
         let mut public_ws = BinancePublicWsClient::new(&server_config, &config).await;
+        public_ws.set_raw_message_hook(self.raw_message_hook.clone());
+        public_ws.set_state_handle(self.ws_state.clone());
 
         let exchange_name = config.exchange_name.clone();
         let trade_category = config.trade_category.clone();
@@ -494,15 +1091,25 @@ impl MarketImpl<BinanceRestApi> for BinanceMarket {
 
         let _ = self.async_refresh_order_book().await;
 
+        let (stop_tx, mut stop_rx) = stream_stop_signal();
+        self.public_stop = Some(stop_tx);
+
         self.public_handler = Some(tokio::task::spawn(async move {
             let ws_stream = public_ws.open_stream().await;
             let mut ws_stream = Box::pin(ws_stream);
 
             loop {
-                let message = ws_stream.next().await;
+                let message = tokio::select! {
+                    _ = stop_rx.changed() => {
+                        log::info!("market stream stopped");
+                        break;
+                    }
+                    message = ws_stream.next() => message,
+                };
+
                 if message.is_none() {
-                    log::error!("Error in ws_stream.recv: {:?}", message);
-                    continue;
+                    log::error!("market stream closed");
+                    break;
                 }
 
                 let message = message.unwrap();
@@ -559,6 +1166,10 @@ impl MarketImpl<BinanceRestApi> for BinanceMarket {
         self.board.clone()
     }
 
+    fn get_spread_logger(&mut self) -> &mut Option<SpreadLogger> {
+        &mut self.spread_logger
+    }
+
     async fn async_download_range(
         &mut self,
         time_from: MicroSec,
@@ -584,14 +1195,19 @@ impl BinanceMarket {
         let db = TradeDataFrame::get(config, server_config.is_production())
             .with_context(|| format!("Error in TradeTable::open: {:?}", config))?;
 
+        let depth = binance_board_depth(config)?;
 
         let market = BinanceMarket {
             server_config: server_config.clone(),
             api: BinanceRestApi::new(server_config),
             config: config.clone(),
             db: db,
-            board: Arc::new(RwLock::new(OrderBook::new(&config, BINANCE_BOARD_DEPTH))),
+            board: Arc::new(RwLock::new(OrderBook::new(&config, depth))),
             public_handler: None,
+            public_stop: None,
+            raw_message_hook: None,
+            spread_logger: None,
+            ws_state: WsStateHandle::default(),
         };
 
         Ok(market)
@@ -653,6 +1269,17 @@ mod binance_market_test {
 
     use crate::BinanceConfig;
 
+    #[test]
+    fn test_from_config_use_agg_trades() {
+        use super::*;
+
+        let binance = Binance::from_config(&BinanceConfig::new(true, false));
+        assert!(binance.get_use_agg_trades());
+
+        let binance = Binance::from_config(&BinanceConfig::new(false, false));
+        assert!(!binance.get_use_agg_trades());
+    }
+
     #[test]
     fn test_down_load_latest() {
         init_debug_log();
@@ -672,6 +1299,45 @@ mod binance_market_test {
         sleep(std::time::Duration::from_secs(5));
     }
 
+    /// 板はMarketConfigのsymbolで作り、tickはexchangeInfoの値を使う(BTC固定ではない)
+    #[test]
+    fn test_order_book_symbol() -> anyhow::Result<()> {
+        use super::*;
+        use crate::BinanceSymbolInfo;
+        use rbot_lib::common::{BoardTransfer, OrderBookList};
+        use rust_decimal_macros::dec;
+
+        const ETHUSDT_INFO: &str = r#"{"symbol":"ETHUSDT","status":"TRADING","baseAsset":"ETH","quoteAsset":"USDT","filters":[{"filterType":"PRICE_FILTER","minPrice":"0.01000000","maxPrice":"1000000.00000000","tickSize":"0.01000000"},{"filterType":"LOT_SIZE","minQty":"0.00010000","maxQty":"9000.00000000","stepSize":"0.00010000"}]}"#;
+
+        let mut config = MarketConfig::default();
+        config.exchange_name = BINANCE.to_string();
+        config.trade_category = "spot".to_string();
+        config.trade_symbol = "ETHUSDT".to_string();
+        config.price_unit = dec![0.5];
+
+        let info: BinanceSymbolInfo = serde_json::from_str(ETHUSDT_INFO)?;
+        info.apply(&mut config)?;
+        assert_eq!(config.price_unit, dec![0.01]);
+        assert_eq!(config.size_unit, dec![0.0001]);
+
+        let depth = binance_board_depth(&config).unwrap();
+        let mut book = OrderBook::new(&config, depth);
+
+        assert_eq!(book.get_symbol(), "ETHUSDT");
+        assert_eq!(OrderBookList::make_path(&config), "BINANCE/spot/ETHUSDT");
+
+        // 板の価格はETHUSDTのtick(0.01)のまま保持される
+        let mut transfer = BoardTransfer::new();
+        transfer.snapshot = true;
+        transfer.insert_bid(&(dec![3000.01], dec![1.0]));
+        transfer.insert_ask(&(dec![3000.02], dec![1.0]));
+        book.update(&transfer);
+
+        assert_eq!(book.get_edge_price()?, (dec![3000.01], dec![3000.02]));
+
+        Ok(())
+    }
+
 }
 
 #[cfg(test)]
@@ -722,7 +1388,7 @@ mod test_market_impl {
 
         let mut market = BinanceMarket::new(&server, &market_config);
 
-        market.download(3, false, false, false, false, true).unwrap();
+        market.download(3, false, false, false, false, true, None).unwrap();
     }
 
     #[test]
@@ -734,7 +1400,7 @@ mod test_market_impl {
 
         let mut market = BinanceMarket::new(&server, &market_config);
 
-        market._download_archive(3, false, true).unwrap();
+        market._download_archive(3, false, true, None).unwrap();
 
         let trades = market._select_archive_trades(0, 0);
 
@@ -5,7 +5,7 @@
 
 use pyo3::{pyclass, pymethods};
 use rbot_lib::common::{
-    msec_to_microsec, orderside_deserialize, orderstatus_deserialize, ordertype_deserialize,
+    auto_timestamp, msec_to_microsec, orderside_deserialize, orderstatus_deserialize, ordertype_deserialize,
     string_to_decimal, string_to_f64, AccountCoins, BoardItem, BoardTransfer, Coin, ControlMessage,
     LogStatus, MarketConfig, MultiMarketMessage, Order, OrderSide, OrderStatus, OrderType, Trade,
 };
@@ -50,6 +50,9 @@ pub struct BinanceSubscriptionReply {
 pub enum BinancePublicWsMessage {
     #[serde(rename = "trade")]
     Trade(BinanceWsTradeMessage),
+    /// COIN-Mは@tradeがないため@aggTradeを使う
+    #[serde(rename = "aggTrade")]
+    AggTrade(BinanceWsAggTradeMessage),
     #[serde(rename = "depthUpdate")]
     BoardUpdate(BinanceWsBoardUpdate),
     #[serde(rename = "control")]
@@ -76,6 +79,13 @@ impl Into<MultiMarketMessage> for BinancePublicWsMessage {
                 trades.push(t);
                 MultiMarketMessage::Trade(trades)
             }
+            BinancePublicWsMessage::AggTrade(trade) => match trade.to_trade() {
+                Ok(t) => MultiMarketMessage::Trade(vec![t]),
+                Err(e) => {
+                    log::warn!("skip invalid aggTrade: {:?}", e);
+                    MultiMarketMessage::Trade(vec![])
+                }
+            },
             BinancePublicWsMessage::BoardUpdate(board_update) => {
                 let board: BoardTransfer = board_update.into();
 
@@ -107,8 +117,11 @@ pub struct BinanceTradeMessage {
     pub price: Decimal,
     #[serde(rename = "qty", deserialize_with = "string_to_decimal")]
     pub size: Decimal,
-    #[serde(rename = "quoteQty", deserialize_with = "string_to_decimal")]
+    #[serde(rename = "quoteQty", default, deserialize_with = "string_to_decimal")]
     pub volume_in_foreign: Decimal,
+    /// COIN-MはquoteQtyの代わりにbaseQty(base通貨建ての数量)が入る
+    #[serde(rename = "baseQty", default, deserialize_with = "string_to_decimal")]
+    pub base_qty: Decimal,
     pub time: i64,
     #[serde(rename = "isBuyerMaker")]
     pub is_buyer_maker: Option<bool>,
@@ -117,19 +130,24 @@ pub struct BinanceTradeMessage {
 }
 
 impl BinanceTradeMessage {
-    pub fn to_trade(&self) -> Trade {
-        return Trade {
-            time: msec_to_microsec(self.time),
+    pub fn to_trade(&self) -> anyhow::Result<Trade> {
+        let is_buyer_maker = self
+            .is_buyer_maker
+            .ok_or_else(|| anyhow::anyhow!("isBuyerMaker not found in {:?}", self))?;
+
+        Trade {
+            time: auto_timestamp(self.time),
             price: self.price,
             size: self.size,
-            order_side: if self.is_buyer_maker.unwrap() {
+            order_side: if is_buyer_maker {
                 OrderSide::Buy
             } else {
                 OrderSide::Sell
             },
             status: LogStatus::UnFix,
             id: self.id.to_string(),
-        };
+        }
+        .validate()
     }
 
     pub fn __str__(&self) -> String {
@@ -165,7 +183,7 @@ pub struct BinanceWsTradeMessage {
 impl BinanceWsTradeMessage {
     pub fn to_trade(&self) -> Trade {
         return Trade {
-            time: msec_to_microsec(self.time),
+            time: auto_timestamp(self.time),
             price: Decimal::from_str(&self.p).unwrap(), // self.p.parse::<f64>().unwrap(),
             size: Decimal::from_str(&self.q).unwrap(),  // parse::<f64>().unwrap(),
             order_side: if self.m {
@@ -187,6 +205,52 @@ impl BinanceWsTradeMessage {
     }
 }
 
+// {"e":"aggTrade","E":1591261134288,"a":424951,"s":"BTCUSD_200626","p":"9643.5","q":"2","f":606073,"l":606073,"T":1591261134199,"m":false}
+/// COIN-Mの@aggTrade。qは枚数(コントラクト数)。IDは集約ID(a)を使う。
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinanceWsAggTradeMessage {
+    #[serde(rename = "E")]
+    pub event_time: i64,
+    pub s: String,
+    pub a: BinanceMessageId,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub p: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub q: Decimal,
+    pub f: BinanceMessageId,
+    pub l: BinanceMessageId,
+    #[serde(rename = "T")]
+    pub time: i64,
+    pub m: bool,
+}
+
+impl BinanceWsAggTradeMessage {
+    pub fn to_trade(&self) -> anyhow::Result<Trade> {
+        Trade {
+            time: auto_timestamp(self.time),
+            price: self.p,
+            size: self.q,
+            order_side: if self.m {
+                OrderSide::Buy
+            } else {
+                OrderSide::Sell
+            },
+            status: LogStatus::UnFix,
+            id: self.a.to_string(),
+        }
+        .validate()
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+
+    pub fn __repr__(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
 // hold the latest board(as sample message blow)
 // {"lastUpdateId":18735297989,"bids":[["25993.48000000","0.15981000"],["25991.09000000","0.36750000"],["25991.08000000","0.03846000"]],"asks":[["25993.49000000","0.05770000"],["25993.50000000","0.00060000"],["25994.12000000","0.06100000"]]}
 #[pyclass]
@@ -841,6 +905,42 @@ impl BinanceExecutionReport {
     }
 }
 
+/// OCOなどのorder listの状態通知
+/// https://binance-docs.github.io/apidocs/spot/en/#payload-order-update
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinanceListStatusOrder {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "i")]
+    pub order_id: i64,
+    #[serde(rename = "c")]
+    pub client_order_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinanceListStatus {
+    #[serde(rename = "E")]
+    pub time: i64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "g")]
+    pub order_list_id: i64,
+    #[serde(rename = "c")]
+    pub contingency_type: String,
+    #[serde(rename = "l")]
+    pub list_status_type: String,
+    #[serde(rename = "L")]
+    pub list_order_status: String,
+    #[serde(rename = "r")]
+    pub list_reject_reason: String,
+    #[serde(rename = "C")]
+    pub list_client_order_id: String,
+    #[serde(rename = "T")]
+    pub transaction_time: i64,
+    #[serde(rename = "O")]
+    pub orders: Vec<BinanceListStatusOrder>,
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "e")]
@@ -848,6 +948,7 @@ pub enum BinanceUserWsMessage {
     outboundAccountPosition(BinanceAccountUpdate),
     balanceUpdate(BinanceBalanceUpdate),
     executionReport(BinanceExecutionReport),
+    listStatus(BinanceListStatus),
 }
 
 impl BinanceUserWsMessage {
@@ -867,6 +968,17 @@ impl BinanceUserWsMessage {
                 let order: Order = report.to_order(category);
                 MultiMarketMessage::Order(vec![order])
             }
+            BinanceUserWsMessage::listStatus(status) => {
+                // 各注文の状態はexecutionReportで通知されるため、ここではlist全体の状態のみ通知する
+                MultiMarketMessage::Message(format!(
+                    "listStatus {} {} {} {} {}",
+                    status.symbol,
+                    status.list_client_order_id,
+                    status.contingency_type,
+                    status.list_status_type,
+                    status.list_order_status
+                ))
+            }
         };
 
         message
@@ -1013,6 +1125,53 @@ impl BinanceAccountBalance {
     }
 }
 
+/// exchangeInfoのfiltersの1件。PRICE_FILTERとLOT_SIZE以外は使わない。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BinanceSymbolFilter {
+    pub filterType: String,
+    #[serde(default, deserialize_with = "string_to_decimal")]
+    pub tickSize: Decimal,
+    #[serde(default, deserialize_with = "string_to_decimal")]
+    pub stepSize: Decimal,
+    #[serde(default, deserialize_with = "string_to_decimal")]
+    pub minQty: Decimal,
+}
+
+/// /api/v3/exchangeInfo (COIN-Mは/dapi/v1/exchangeInfo)のsymbolsの1件
+/// {"symbol":"BTCUSDT","filters":[{"filterType":"PRICE_FILTER","tickSize":"0.01000000",...},{"filterType":"LOT_SIZE","stepSize":"0.00001000","minQty":"0.00001000",...}],...}
+/// COIN-Mはさらに"contractSize":100が入る。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BinanceSymbolInfo {
+    pub symbol: String,
+    #[serde(default)]
+    pub contractSize: Option<i64>,
+    pub filters: Vec<BinanceSymbolFilter>,
+}
+
+impl BinanceSymbolInfo {
+    fn find_filter(&self, filter_type: &str) -> anyhow::Result<&BinanceSymbolFilter> {
+        self.filters
+            .iter()
+            .find(|f| f.filterType == filter_type)
+            .ok_or_else(|| anyhow::anyhow!("{} not found in {}", filter_type, self.symbol))
+    }
+
+    pub fn apply(&self, config: &mut MarketConfig) -> anyhow::Result<()> {
+        let price = self.find_filter("PRICE_FILTER")?;
+        let lot = self.find_filter("LOT_SIZE")?;
+        let contract_size = Decimal::from(self.contractSize.unwrap_or(1));
+
+        config.set_instrument(price.tickSize, lot.stepSize, lot.minQty, contract_size);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinanceExchangeInfo {
+    pub symbols: Vec<BinanceSymbolInfo>,
+}
+
 /*
 BinanceOrderStatus is parse json as blow
 
@@ -1167,6 +1326,27 @@ mod binance_message_test {
     }
 
     const BOARD_UPDATE: &str = r#"{"e":"depthUpdate","E":1693266904308,"s":"BTCUSDT","U":38531387766,"u":38531387832,"b":[["26127.87000000","20.79393000"],["26126.82000000","0.02674000"],["26125.95000000","0.00000000"],["26125.78000000","0.38302000"],["26125.68000000","0.00000000"],["26125.10000000","0.00000000"],["26125.05000000","0.00000000"],["26124.76000000","0.00000000"],["26124.75000000","0.21458000"],["26114.84000000","1.14830000"],["26114.15000000","0.00000000"],["26090.85000000","0.00000000"],["26090.84000000","0.00000000"],["26090.32000000","2.29642000"],["26090.31000000","3.82738000"],["26087.99000000","0.03733000"],["26084.34000000","0.00000000"],["25553.07000000","0.13647000"],["25500.81000000","0.14160000"],["25496.85000000","0.00000000"],["25284.00000000","0.03996000"],["24827.83000000","0.00000000"],["24300.17000000","0.00000000"],["23772.50000000","0.00047000"],["23515.08000000","0.00000000"],["18289.50000000","0.00000000"],["13063.93000000","0.00091000"]],"a":[["26127.88000000","5.58099000"],["26128.39000000","0.20072000"],["26128.79000000","0.21483000"],["26129.26000000","0.38297000"],["26129.52000000","0.00000000"],["26129.53000000","0.00000000"],["26134.50000000","0.06000000"],["26134.99000000","1.07771000"],["26135.10000000","0.00700000"],["26155.27000000","0.00050000"],["26155.28000000","0.00000000"],["27027.87000000","0.00200000"],["27290.25000000","0.00000000"],["27817.92000000","0.00000000"],["28345.58000000","0.00000000"]]}"#;
+    const AGG_TRADE_WS: &str = r#"{"e":"aggTrade","E":1591261134288,"a":424951,"s":"BTCUSD_200626","p":"9643.5","q":"2","f":606073,"l":606073,"T":1591261134199,"m":false}"#;
+    #[test]
+    fn test_binance_agg_trade_message() -> anyhow::Result<()> {
+        let message: BinancePublicWsMessage = serde_json::from_str(AGG_TRADE_WS)?;
+        let message: MultiMarketMessage = message.into();
+
+        match message {
+            MultiMarketMessage::Trade(trades) => {
+                assert_eq!(trades.len(), 1);
+                assert_eq!(trades[0].id, "424951");
+                assert_eq!(trades[0].time, 1591261134_199_000);
+                assert_eq!(trades[0].price, dec![9643.5]);
+                assert_eq!(trades[0].size, dec![2]);
+                assert_eq!(trades[0].order_side, OrderSide::Sell);
+            }
+            _ => panic!("not a trade message {:?}", message),
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_binance_board_update() {
         let message: BinanceWsBoardUpdate = serde_json::from_str(BOARD_UPDATE).unwrap();
@@ -1196,6 +1376,18 @@ mod binance_message_test {
         println!("{:?}", message);
     }
 
+    const COINM_HISTORY: &str = r#"[{"id":28457,"price":"9635.0","qty":"1","baseQty":"0.01037883","time":1591250192508,"isBuyerMaker":true}]"#;
+
+    #[test]
+    fn test_binance_coinm_trade_history_message() {
+        let message: Vec<BinanceTradeMessage> = serde_json::from_str(COINM_HISTORY).unwrap();
+
+        assert_eq!(message[0].size, dec![1]);
+        assert_eq!(message[0].base_qty, dec![0.01037883]);
+        assert_eq!(message[0].volume_in_foreign, dec![0]);
+        assert_eq!(message[0].to_trade().unwrap().price, dec![9635.0]);
+    }
+
     const REST_BOARD: &str = r#"{
         "lastUpdateId": 1027024,
         "bids": [
@@ -1365,4 +1557,37 @@ mod binance_message_test {
 
         println!("{:?}", account_update);
     }
+
+    #[test]
+    fn test_list_status() {
+        let message: BinanceUserWsMessage = serde_json::from_str(
+            r#"
+            {
+                "e": "listStatus",
+                "E": 1564035303637,
+                "s": "ETHBTC",
+                "g": 2,
+                "c": "OCO",
+                "l": "EXEC_STARTED",
+                "L": "EXECUTING",
+                "r": "NONE",
+                "C": "F4QN4G8DlFATFlIUQ0cjdD",
+                "T": 1564035303625,
+                "O": [
+                  {"s": "ETHBTC", "i": 17, "c": "AJYsMjErWJesZvqlJCTUgL"},
+                  {"s": "ETHBTC", "i": 18, "c": "bfYPSQdLoqAAEkGnzL4v5W"}
+                ]
+            }
+            "#,
+        )
+        .unwrap();
+
+        match message.convert_multimarketmessage("SPOT") {
+            MultiMarketMessage::Message(m) => {
+                assert!(m.contains("F4QN4G8DlFATFlIUQ0cjdD"));
+                assert!(m.contains("EXECUTING"));
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
 }
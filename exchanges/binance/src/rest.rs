@@ -1,33 +1,58 @@
 // Copyright(c) 2022-2024. yasstake. All rights reserved.
 
 use crate::{
-    binance_order_status_vec_to_orders, BinanceAccountInformation, BinanceCancelOrderResponse,
-    BinanceOrderResponse, BinanceOrderStatus, BinanceRestBoard, BinanceServerConfig,
-    BinanceTradeMessage,
+    binance_board_depth, binance_order_status_vec_to_orders, BinanceAccountInformation, BinanceCancelOrderResponse,
+    BinanceExchangeInfo, BinanceOrderResponse, BinanceOrderStatus, BinanceRestBoard, BinanceServerConfig,
+    BinanceTradeMessage, BINANCE,
 };
 
 use anyhow::anyhow;
 use polars::{chunked_array::{ops::{ChunkApply, ChunkCast as _}, ChunkedArray}, datatypes::DataType, frame::DataFrame, prelude::NamedFrom as _, series::{IntoSeries, Series}};
 use rbot_lib::{
     common::{
-        flush_log, hmac_sign, split_yyyymmdd, AccountCoins, BoardTransfer, Kline, LogStatus,
-        MarketConfig, MicroSec, Order, OrderSide, OrderType, ExchangeConfig, Trade, NOW,
+        collect_trades, flush_log, hmac_sign, infer_timestamp_unit, normalize_timestamp, split_yyyymmdd, AccountCoins, BoardTransfer, Kline, LogStatus,
+        MarketConfig, MarketError, MicroSec, Order, OrderSide, OrderType, ExchangeConfig, TimeInForce, Trade, NOW, SERVER_NOW,
     }, db::KEY, net::{rest_delete, rest_get, rest_post, rest_put, RestApi, RestPage}
 };
+use csv::StringRecord;
 use rust_decimal::Decimal;
 use serde_json::Value;
+use std::str::FromStr;
 
 use anyhow::Context;
 
 #[derive(Clone, Debug)]
 pub struct BinanceRestApi {
     server_config: ExchangeConfig,
+    /// trueの場合はアーカイブとしてaggTrades(同一価格・同一方向の連続約定をまとめたもの)を使う
+    use_agg_trades: bool,
 }
 
 impl BinanceRestApi {
     pub fn new(server_config: &ExchangeConfig) -> Self {
         Self {
             server_config: server_config.clone(),
+            use_agg_trades: false,
+        }
+    }
+
+    pub fn with_agg_trades(&self, use_agg_trades: bool) -> Self {
+        let mut api = self.clone();
+        api.use_agg_trades = use_agg_trades;
+
+        api
+    }
+
+    pub fn is_agg_trades(&self) -> bool {
+        self.use_agg_trades
+    }
+
+    /// アーカイブURLのデータ種別
+    fn archive_kind(&self) -> &'static str {
+        if self.use_agg_trades {
+            "aggTrades"
+        } else {
+            "trades"
         }
     }
 }
@@ -40,11 +65,15 @@ impl RestApi for BinanceRestApi {
     }
 
     async fn get_board_snapshot(&self, config: &MarketConfig) -> anyhow::Result<BoardTransfer> {
-        let path = "/api/v3/depth";
-        let params = format!("symbol={}&limit=1000", &config.trade_symbol);
+        let path = Self::market_data_path(config, "depth");
+        let params = format!(
+            "symbol={}&limit={}",
+            &config.trade_symbol,
+            binance_board_depth(config)?
+        );
 
         let message = self
-            .get(path, &params)
+            .get(&path, &params)
             .await
             .with_context(|| format!("get_board_snapshot error"))?;
 
@@ -57,11 +86,11 @@ impl RestApi for BinanceRestApi {
     async fn get_recent_trades(&self, config: &MarketConfig) -> anyhow::Result<Vec<Trade>> {
         log::debug!("get_recent_trades: {:?}", &config.trade_symbol);
 
-        let path = "/api/v3/trades";
+        let path = Self::market_data_path(config, "trades");
         let params = format!("symbol={}&limit=1000", &config.trade_symbol);
 
         let messasge = self
-            .get(path, &params)
+            .get(&path, &params)
             .await
             .with_context(|| format!("get_recent_trades error"))?;
 
@@ -71,13 +100,7 @@ impl RestApi for BinanceRestApi {
 
         log::debug!("get_recent_trades: {:?}", trades.len());
 
-        let mut result: Vec<Trade> = vec![];
-
-        for t in trades {
-            result.push(t.to_trade());
-
-            log::debug!("trade: {:?}", t.to_trade());
-        }
+        let (result, _skipped) = collect_trades(trades, |t| t.to_trade());
 
         Ok(result)
     }
@@ -92,7 +115,7 @@ impl RestApi for BinanceRestApi {
     ) -> anyhow::Result<(Vec<Trade>, RestPage)> {
         log::debug!("get_recent_trades: {:?}", &config.trade_symbol);
 
-        let path = "/api/v3/historicalTrades";
+        let path = Self::market_data_path(config, "historicalTrades");
 
         let mut params = format!("symbol={}&limit=1000", &config.trade_symbol);
 
@@ -135,8 +158,9 @@ impl RestApi for BinanceRestApi {
             return Ok((vec![], RestPage::Done));
         }
 
-        for t in trades {
-            let trade: Trade = t.to_trade();
+        let (trades, _skipped) = collect_trades(trades, |t| t.to_trade());
+
+        for trade in trades {
 
             if trade.time < start_time {
                 log::debug!("trade: [{}]{:?}", start_time, trade);
@@ -182,13 +206,23 @@ impl RestApi for BinanceRestApi {
         size: Decimal,
         order_type: OrderType,
         client_order_id: Option<&str>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>> {
         let server = &self.server_config;
 
+        Self::check_order_category(config)?;
+
+        if reduce_only {
+            return Err(anyhow!("reduce_only is not supported in binance spot"));
+        }
+
         let path = "/api/v3/order";
         let side = Self::order_side_string(side);
 
+        // SpotのPostOnlyはtimeInForceではなくLIMIT_MAKERで指定する
         let order_type_str: &str = match order_type {
+            OrderType::Limit if time_in_force == TimeInForce::PostOnly => "LIMIT_MAKER",
             OrderType::Limit => "LIMIT",
             OrderType::Market => "MARKET",
             OrderType::Unknown => return Err(anyhow!("unknown order type")),
@@ -200,7 +234,11 @@ impl RestApi for BinanceRestApi {
         );
 
         if order_type == OrderType::Limit {
-            body = format!("{}&price={}&timeInForce=GTC", body, price);
+            body = format!("{}&price={}", body, price);
+
+            if let Some(tif) = Self::time_in_force_string(time_in_force) {
+                body = format!("{}&timeInForce={}", body, tif);
+            }
         }
 
         if client_order_id.is_some() {
@@ -222,6 +260,8 @@ impl RestApi for BinanceRestApi {
 
     /// https://binance-docs.github.io/apidocs/spot/en/#cancel-all-open-orders-on-a-symbol-trade
     async fn cancel_order(&self, config: &MarketConfig, order_id: &str) -> anyhow::Result<Order> {
+        Self::check_order_category(config)?;
+
         let path = "/api/v3/order";
         let body = format!("symbol={}&orderId={}", config.trade_symbol, order_id);
 
@@ -237,6 +277,8 @@ impl RestApi for BinanceRestApi {
 
     /// https://binance-docs.github.io/apidocs/spot/en/#current-open-orders-user_data
     async fn open_orders(&self, config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
+        Self::check_order_category(config)?;
+
         let path = "/api/v3/openOrders";
         let query = format!("symbol={}", config.trade_symbol);
 
@@ -252,6 +294,24 @@ impl RestApi for BinanceRestApi {
         Ok(orders)
     }
 
+    /// https://binance-docs.github.io/apidocs/spot/en/#query-order-user_data
+    async fn get_order_status(&self, config: &MarketConfig, order_id: &str) -> anyhow::Result<Order> {
+        Self::check_order_category(config)?;
+
+        let path = "/api/v3/order";
+        let query = format!("symbol={}&orderId={}", config.trade_symbol, order_id);
+
+        let message = self
+            .get_sign(&path, Some(&query))
+            .await
+            .with_context(|| format!("get_order_status error"))?;
+
+        let status: BinanceOrderStatus = serde_json::from_value(message)?;
+        let order = binance_order_status_vec_to_orders(config, &vec![status]).remove(0);
+
+        Ok(order)
+    }
+
     async fn get_account(&self) -> anyhow::Result<AccountCoins> {
         let path = "/api/v3/account";
 
@@ -265,19 +325,103 @@ impl RestApi for BinanceRestApi {
         Ok(account.into_coins())
     }
 
+    /// {"serverTime":1499827319559}
+    async fn get_server_time(&self) -> anyhow::Result<MicroSec> {
+        // COIN-Mはdapi、それ以外はspotのAPI
+        let path = if self.server_config.get_public_api().contains("dapi") {
+            "/dapi/v1/time"
+        } else {
+            "/api/v3/time"
+        };
+
+        let message = self
+            .get(path, "")
+            .await
+            .with_context(|| format!("get_server_time error"))?;
+
+        let time = message["serverTime"]
+            .as_i64()
+            .ok_or_else(|| anyhow!("invalid server time {:?}", message))?;
+
+        Ok(time * 1_000)
+    }
+
+    /// /api/v3/accountのpermissions(例 "SPOT")とcanTrade/canWithdraw/canDeposit
+    async fn get_api_permissions(&self) -> anyhow::Result<Vec<String>> {
+        let message = self
+            .get_sign("/api/v3/account", None)
+            .await
+            .with_context(|| format!("get_api_permissions error"))?;
+
+        let mut permissions: Vec<String> = message["permissions"]
+            .as_array()
+            .map(|p| p.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect())
+            .unwrap_or_default();
+
+        for (key, name) in [("canTrade", "TRADE"), ("canWithdraw", "WITHDRAW"), ("canDeposit", "DEPOSIT")] {
+            if message[key].as_bool() == Some(true) {
+                permissions.push(name.to_string());
+            }
+        }
+
+        Ok(permissions)
+    }
+
+    /// exchangeInfoのPRICE_FILTER(tickSize)とLOT_SIZE(stepSize, minQty)
+    async fn get_instrument_info(&self, config: &MarketConfig) -> anyhow::Result<MarketConfig> {
+        let path = Self::market_data_path(config, "exchangeInfo");
+        let params = format!("symbol={}", &config.trade_symbol);
+
+        let message = self
+            .get(&path, &params)
+            .await
+            .with_context(|| format!("get_instrument_info error"))?;
+
+        let info: BinanceExchangeInfo = serde_json::from_value(message)?;
+
+        let symbol = info
+            .symbols
+            .iter()
+            .find(|s| s.symbol == config.trade_symbol)
+            .ok_or_else(|| anyhow!("instrument not found: {}", config.trade_symbol))?;
+
+        let mut config = config.clone();
+        symbol.apply(&mut config)?;
+
+        Ok(config)
+    }
+
     fn history_web_url(&self, config: &MarketConfig, date: MicroSec) -> String {
         // https://data.binance.vision/data/spot/daily/trades/BTCBUSD/BTCBUSD-trades-2022-11-19.zip
         let category = config.trade_category.to_lowercase();
 
+        // aggTradesは https://data.binance.vision/data/spot/daily/aggTrades/BTCUSDT/BTCUSDT-aggTrades-2024-08-23.zip
+        let kind = self.archive_kind();
+
         let (yyyy, mm, dd) = split_yyyymmdd(date);
 
         // TODO: implement other than spot
         if category == "spot" {
             return format!(
-                "{}/data/spot/daily/trades/{}/{}-trades-{:04}-{:02}-{:02}.zip",
+                "{}/data/spot/daily/{}/{}/{}-{}-{:04}-{:02}-{:02}.zip",
                 self.server_config.get_historical_web_base(),
+                kind,
                 config.trade_symbol,
                 config.trade_symbol,
+                kind,
+                yyyy,
+                mm,
+                dd
+            );
+        } else if category == "inverse" {
+            // https://data.binance.vision/data/futures/cm/daily/trades/BTCUSD_PERP/BTCUSD_PERP-trades-2024-08-23.zip
+            return format!(
+                "{}/data/futures/cm/daily/{}/{}/{}-{}-{:04}-{:02}-{:02}.zip",
+                self.server_config.get_historical_web_base(),
+                kind,
+                config.trade_symbol,
+                config.trade_symbol,
+                kind,
                 yyyy,
                 mm,
                 dd
@@ -286,10 +430,12 @@ impl RestApi for BinanceRestApi {
             // https://data.binance.vision/data/futures/um/daily/trades/BTCUSDT/BTCUSDT-trades-2024-08-23.zip
 
             return format!(
-                "{}/data/futures/um/daily/trades/{}/{}-trades-{:04}-{:02}-{:02}.zip",
+                "{}/data/futures/um/daily/{}/{}/{}-{}-{:04}-{:02}-{:02}.zip",
                 self.server_config.get_historical_web_base(),
+                kind,
                 config.trade_symbol,
                 config.trade_symbol,
+                kind,
                 yyyy,
                 mm,
                 dd
@@ -310,13 +456,23 @@ impl RestApi for BinanceRestApi {
     /// │ i64        ┆ f64      ┆ f64      ┆ f64         ┆ i64           ┆ bool     ┆ bool     │
     /// ╞════════════╪══════════╪══════════╪═════════════╪═══════════════╪══════════╪══════════╡
     /// │ 3730692451 ┆ 56022.0  ┆ 0.005    ┆ 280.11      ┆ 1722988800052 ┆ true     ┆ true     │
+    ///
+    /// COIN-M(inverse)はヘッダ付きで id,price,qty,base_qty,time,is_buyer_maker の順。
+    /// column_4がquote_qtyではなくbase_qtyになるだけで、使う列の位置は同じ(sizeは枚数)。
     fn logdf_to_archivedf(&self, df: &DataFrame) -> anyhow::Result<DataFrame> {
+        if self.use_agg_trades {
+            return aggdf_to_archivedf(df);
+        }
+
         let _ = df;
         println!("{:?}", df);
 
         let df = df.clone();
 
-        let timestamp = df.select_at_idx(4).unwrap().i64()? * 1_000;
+        // 2025年以降のspotのアーカイブはμs、それ以前とfuturesはms
+        let raw_timestamp = df.select_at_idx(4).unwrap().i64()?;
+        let unit = infer_timestamp_unit(raw_timestamp.get(0).unwrap_or(0));
+        let timestamp = raw_timestamp * normalize_timestamp(1, unit);
         let timestamp = timestamp.cast(&DataType::Int64)?;
 
         let timestamp = timestamp.clone();
@@ -373,55 +529,6 @@ impl RestApi for BinanceRestApi {
         "".to_string()
     }
 
-    /// binance csv Formatter
-    /// example;
-    ///  630277243,16681.46000000,0.00298000,49.71075080,1668816000029,True,True
-    ///  630277244,16681.43000000,0.00299000,49.87747570,1668816000053,True,True
-    ///  630277245,16681.00000000,0.00299000,49.87619000,1668816000075,True,True
-    fn rec_to_trade(rec: &StringRecord) -> Trade {
-        let id = rec.get(0).unwrap_or_default().to_string();
-        let price = rec
-            .get(1)
-            .unwrap_or_default()
-            .parse::<f64>()
-            .unwrap_or_default();
-
-        let price = Decimal::from_f64(price).unwrap_or_default();
-
-        let size = rec
-            .get(2)
-            .unwrap_or_default()
-            .parse::<f64>()
-            .unwrap_or_default();
-
-        let size = Decimal::from_f64(size).unwrap_or_default();
-
-        let timestamp = rec
-            .get(4)
-            .unwrap_or_default()
-            .parse::<MicroSec>()
-            .unwrap_or_default()
-            * 1_000;
-
-        let is_buyer_make = rec.get(5).unwrap_or_default();
-        let order_side = match is_buyer_make {
-            "True" => OrderSide::Buy,
-            "False" => OrderSide::Sell,
-            _ => OrderSide::Unknown,
-        };
-
-        let trade = Trade::new(
-            timestamp,
-            order_side,
-            price,
-            size,
-            LogStatus::FixArchiveBlock,
-            &id,
-        );
-
-        return trade;
-    }
-
     /// binance csv dose not have header.
     fn archive_has_header() -> bool {
         false
@@ -430,6 +537,27 @@ impl RestApi for BinanceRestApi {
 }
 
 impl BinanceRestApi {
+    /// 板・約定の取得パス。COIN-M(inverse)は /dapi/v1、それ以外はspotの /api/v3
+    fn market_data_path(config: &MarketConfig, endpoint: &str) -> String {
+        if config.trade_category == "inverse" {
+            format!("/dapi/v1/{}", endpoint)
+        } else {
+            format!("/api/v3/{}", endpoint)
+        }
+    }
+
+    /// 発注系はspotのみ対応
+    fn check_order_category(config: &MarketConfig) -> anyhow::Result<()> {
+        if config.trade_category != "spot" {
+            return Err(anyhow!(
+                "order is not supported in binance {:?}",
+                config.trade_category
+            ));
+        }
+
+        Ok(())
+    }
+
     async fn get(&self, path: &str, params: &str) -> anyhow::Result<Value> {
         let server = &self.server_config;
         let query = format!("{}?{}", path, params);
@@ -439,6 +567,7 @@ impl BinanceRestApi {
 
         let response = rest_get(&server.get_public_api(), &query, vec![], None, None)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| format!("rest_get error: {}/{}", &server.get_public_api(), &query))?;
 
         log::debug!("path{} / body: {}", path, response);
@@ -464,6 +593,7 @@ impl BinanceRestApi {
         let query = Self::sign_with_timestamp(&api_secret, &q);
         let message = rest_get(&server.get_public_api(), path, headers, Some(&query), None)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| {
                 format!(
                     "binance_get_sign error {}/{}",
@@ -490,13 +620,14 @@ impl BinanceRestApi {
         log::debug!("path{} / body: {}", path, body);
         let message = rest_post(&server.get_public_api(), path, headers, &body)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| format!("post_sign error {}/{}", server.get_public_api(), path))?;
 
         Self::parse_binance_result(message)
     }
 
     fn sign_with_timestamp(secret_key: &str, message: &str) -> String {
-        let time = (NOW() / 1_000) as u64;
+        let time = (SERVER_NOW(BINANCE) / 1_000) as u64;
 
         let message = format!("{}&recvWindow={}&timestamp={}", message, 6000, time);
 
@@ -513,6 +644,7 @@ impl BinanceRestApi {
         headers.push(("X-MBX-APIKEY", &api_key));
         let result = rest_post(&server.get_public_api(), path, headers, body)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| format!("post_key error {}/{}", server.get_public_api(), path))?;
 
         Self::parse_binance_result(result)
@@ -527,6 +659,7 @@ impl BinanceRestApi {
         headers.push(("X-MBX-APIKEY", &api_key));
         let result = rest_put(&server.get_public_api(), path, headers, body)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| format!("post_key error {}/{}", server.get_public_api(), path))?;
 
         Self::parse_binance_result(result)
@@ -546,6 +679,7 @@ impl BinanceRestApi {
         log::debug!("path{} / body: {}", path, body);
         let result = rest_delete(&server.get_public_api(), path, headers, &body)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| format!("delete_sign error {}/{}", server.get_public_api(), path))?;
 
         Self::parse_binance_result(result)
@@ -561,6 +695,16 @@ impl BinanceRestApi {
         }
     }
 
+    /// PostOnlyはLIMIT_MAKERで表現するためtimeInForceは付与しない
+    fn time_in_force_string(time_in_force: TimeInForce) -> Option<&'static str> {
+        match time_in_force {
+            TimeInForce::GTC => Some("GTC"),
+            TimeInForce::IOC => Some("IOC"),
+            TimeInForce::FOK => Some("FOK"),
+            TimeInForce::PostOnly => None,
+        }
+    }
+
     pub fn parse_binance_result(message: String) -> anyhow::Result<Value> {
         let v = serde_json::from_str::<Value>(&message)
             .with_context(|| format!("json format error {:?}", message))?;
@@ -602,10 +746,11 @@ impl BinanceRestApi {
         Ok(())
     }
 
+    /// wss://{private_ws_server}/ws/{listenKey}
     pub fn make_connect_url(&self, key: &str) -> String {
         let server = &self.server_config;
 
-        format!("{}/ws/{}", server.get_public_ws_server(), key)
+        format!("{}/ws/{}", server.get_private_ws_server(), key)
     }
 
     pub async fn get_historical_trades(
@@ -614,7 +759,7 @@ impl BinanceRestApi {
         from_id: i64,
         from_time: MicroSec,
     ) -> anyhow::Result<Vec<Trade>> {
-        let path = "/api/v3/historicalTrades";
+        let path = Self::market_data_path(config, "historicalTrades");
 
         let params = if from_id == 0 {
             format!("symbol={}&limit=1000", config.trade_symbol)
@@ -625,14 +770,15 @@ impl BinanceRestApi {
             )
         };
 
-        let result = self.get(path, &params).await?;
+        let result = self.get(&path, &params).await?;
 
         let binance_trades: Vec<BinanceTradeMessage> = serde_json::from_value(result)?;
 
+        let (binance_trades, _skipped) = collect_trades(binance_trades, |t| t.to_trade());
+
         let mut trades: Vec<Trade> = vec![];
 
-        for t in binance_trades {
-            let mut trade = t.to_trade();
+        for mut trade in binance_trades {
 
             if from_time != 0 && trade.time < from_time {
                 continue;
@@ -647,13 +793,137 @@ impl BinanceRestApi {
     }
 }
 
+/// aggTradesのアーカイブCSV
+///     agg_id(0), price(1), qty(2), first_trade_id(3), last_trade_id(4), transact_time(5), is_buyer_maker(6), is_best_match(7)
+/// 例: 3126412893,60000.01000000,0.01000000,3730692451,3730692453,1724371200052,True,True
+/// futuresはヘッダ付きでis_best_matchがない。IDはagg_idを使う。
+pub fn rec_to_agg_trade(rec: &StringRecord) -> anyhow::Result<Trade> {
+    let field = |i: usize| -> anyhow::Result<&str> {
+        rec.get(i)
+            .map(|s| s.trim())
+            .ok_or_else(|| anyhow!("column {} not found in {:?}", i, rec))
+    };
+
+    let id = field(0)?.to_string();
+    let price = Decimal::from_str(field(1)?)?;
+    let size = Decimal::from_str(field(2)?)?;
+
+    let raw_timestamp = field(5)?.parse::<i64>()?;
+    let timestamp = normalize_timestamp(raw_timestamp, infer_timestamp_unit(raw_timestamp));
+
+    // tradesのアーカイブ(logdf_to_archivedf)と同じ対応にする
+    let order_side = match field(6)?.to_lowercase().as_str() {
+        "true" => OrderSide::Buy,
+        "false" => OrderSide::Sell,
+        _ => OrderSide::Unknown,
+    };
+
+    Ok(Trade::new(
+        timestamp,
+        order_side,
+        price,
+        size,
+        LogStatus::FixArchiveBlock,
+        &id,
+    ))
+}
+
+/// aggTradesのlog_dfをarchive_dfに変換する(列の位置がtradesと異なる)
+fn aggdf_to_archivedf(df: &DataFrame) -> anyhow::Result<DataFrame> {
+    let raw_timestamp = df.select_at_idx(5).unwrap().i64()?;
+    let unit = infer_timestamp_unit(raw_timestamp.get(0).unwrap_or(0));
+    let timestamp = raw_timestamp * normalize_timestamp(1, unit);
+    let mut timestamp = Series::from(timestamp);
+    timestamp.rename(KEY::timestamp);
+
+    let mut id = df.select_at_idx(0).unwrap().cast(&DataType::String)?;
+    id.rename(KEY::id);
+
+    let side_vec: Vec<String> = df
+        .select_at_idx(6)
+        .unwrap()
+        .bool()?
+        .into_iter()
+        .map(|is_buyer_maker| match is_buyer_maker {
+            Some(true) => "Buy".to_string(),
+            Some(false) => "Sell".to_string(),
+            _ => {
+                log::error!("unknown side in log");
+                "Unknown".to_string()
+            }
+        })
+        .collect();
+    let side = Series::new(KEY::order_side, side_vec);
+
+    let mut price = df.select_at_idx(1).unwrap().clone();
+    price.rename(KEY::price);
+
+    let mut size = df.select_at_idx(2).unwrap().clone();
+    size.rename(KEY::size);
+
+    Ok(DataFrame::new(vec![timestamp, side, price, size, id])?)
+}
+
 #[cfg(test)]
 mod binance_api_test {
     use super::*;
-    use crate::BinanceConfig;
-    use rbot_lib::common::{init_debug_log, init_log, DAYS};
+    use crate::{BinanceCoinmConfig, BinanceCoinmServerConfig, BinanceConfig};
+    use rbot_lib::common::{init_debug_log, init_log, parse_time, DAYS};
+    use rbot_lib::net::mock::{request_line, MockHttpServer};
     use rust_decimal_macros::dec;
 
+    #[tokio::test]
+    async fn test_health_check() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(|request| {
+            let line = request_line(request);
+            if line.contains("/api/v3/time") {
+                format!(r#"{{"serverTime":{}}}"#, NOW() / 1_000)
+            } else {
+                r#"{"makerCommission":15,"canTrade":true,"canWithdraw":false,"canDeposit":true,"accountType":"SPOT","balances":[],"permissions":["SPOT"],"uid":354937868}"#.to_string()
+            }
+        })
+        .await;
+        let url = mock.url();
+
+        let server = ExchangeConfig::new("binance", false, &url, &url, "ws://localhost", "ws://localhost", "");
+        let api = BinanceRestApi::new(&server);
+
+        let status = rbot_market::health_check(&api, "ws://127.0.0.1:1").await?;
+
+        assert!(status.rest_latency_ms < 5_000);
+        assert!(status.server_time_delta_ms.abs() < 5_000);
+        assert_eq!(status.ws_latency_ms, 0);
+        assert!(status.api_key_valid);
+        assert_eq!(status.permissions, vec!["SPOT", "TRADE", "DEPOSIT"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_instrument_info() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(|_request| {
+            r#"{"timezone":"UTC","serverTime":1565246363776,"rateLimits":[],"exchangeFilters":[],"symbols":[{"symbol":"BTCUSDT","status":"TRADING","baseAsset":"BTC","quoteAsset":"USDT","filters":[{"filterType":"PRICE_FILTER","minPrice":"0.01000000","maxPrice":"1000000.00000000","tickSize":"0.01000000"},{"filterType":"LOT_SIZE","minQty":"0.00001000","maxQty":"9000.00000000","stepSize":"0.00001000"},{"filterType":"NOTIONAL","minNotional":"5.00000000"}]}]}"#.to_string()
+        })
+        .await;
+        let url = mock.url();
+
+        let server = ExchangeConfig::new("binance", false, &url, &url, "ws://localhost", "ws://localhost", "");
+        let api = BinanceRestApi::new(&server);
+
+        let mut config = MarketConfig::default();
+        config.trade_category = "spot".to_string();
+        config.trade_symbol = "BTCUSDT".to_string();
+
+        let config = api.get_instrument_info(&config).await?;
+
+        assert_eq!(config.price_unit, dec![0.01]);
+        assert_eq!(config.size_unit, dec![0.00001]);
+        assert_eq!(config.min_size, dec![0.00001]);
+        assert_eq!(config.contract_size, dec![1]);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_board_snapshot() -> anyhow::Result<()> {
         let server = BinanceServerConfig::new(false);
@@ -719,6 +989,8 @@ mod binance_api_test {
                 dec![0.001],
                 OrderType::Limit,
                 None,
+                TimeInForce::GTC,
+                false,
             )
             .await;
         println!("result: {:?}", result);
@@ -740,6 +1012,8 @@ mod binance_api_test {
                 dec![0.001],
                 OrderType::Market,
                 None,
+                TimeInForce::GTC,
+                false,
             )
             .await;
         println!("result: {:?}", result);
@@ -820,4 +1094,93 @@ mod binance_api_test {
 
         assert!(url != "");
     }
+
+    #[test]
+    fn test_coinm_paths() {
+        let server = BinanceCoinmServerConfig::new(true);
+        let config = BinanceCoinmConfig::BTCUSD_PERP();
+        let api = BinanceRestApi::new(&server);
+
+        assert_eq!(server.get_public_api(), "https://dapi.binance.com");
+        assert_eq!(BinanceRestApi::market_data_path(&config, "depth"), "/dapi/v1/depth");
+        assert_eq!(
+            BinanceRestApi::market_data_path(&BinanceConfig::BTCUSDT(), "depth"),
+            "/api/v3/depth"
+        );
+
+        let url = api.history_web_url(&config, parse_time("2024-08-23T00:00:00.000000+0000"));
+        assert_eq!(
+            url,
+            "https://data.binance.vision/data/futures/cm/daily/trades/BTCUSD_PERP/BTCUSD_PERP-trades-2024-08-23.zip"
+        );
+
+        assert!(BinanceRestApi::check_order_category(&config).is_err());
+    }
+
+    #[test]
+    fn test_agg_trades_url() {
+        let server = BinanceServerConfig::new(true);
+        let api = BinanceRestApi::new(&server).with_agg_trades(true);
+        let date = parse_time("2024-08-23T00:00:00.000000+0000");
+
+        assert_eq!(
+            api.history_web_url(&BinanceConfig::BTCUSDT(), date),
+            "https://data.binance.vision/data/spot/daily/aggTrades/BTCUSDT/BTCUSDT-aggTrades-2024-08-23.zip"
+        );
+        assert_eq!(
+            api.history_web_url(&BinanceCoinmConfig::BTCUSD_PERP(), date),
+            "https://data.binance.vision/data/futures/cm/daily/aggTrades/BTCUSD_PERP/BTCUSD_PERP-aggTrades-2024-08-23.zip"
+        );
+    }
+
+    #[test]
+    fn test_aggdf_to_archivedf() -> anyhow::Result<()> {
+        use polars::df;
+
+        // aggTradesのアーカイブ: agg_id, price, qty, first_id, last_id, time, is_buyer_maker, is_best_match
+        let log_df = df![
+            "column_1" => [26129i64, 26130],
+            "column_2" => [0.01633102, 0.01633103],
+            "column_3" => [4.7, 0.5],
+            "column_4" => [27781i64, 27783],
+            "column_5" => [27782i64, 27783],
+            "column_6" => [1668816000029i64, 1668816000075],
+            "column_7" => [true, false],
+            "column_8" => [true, true]
+        ]?;
+
+        let server = BinanceServerConfig::new(true);
+        let api = BinanceRestApi::new(&server).with_agg_trades(true);
+        let df = api.logdf_to_archivedf(&log_df)?;
+
+        assert_eq!(df.get_column_names(), vec![KEY::timestamp, KEY::order_side, KEY::price, KEY::size, KEY::id]);
+        assert_eq!(df.column(KEY::timestamp)?.i64()?.get(0), Some(1668816000_029_000));
+        assert_eq!(df.column(KEY::order_side)?.str()?.get(0), Some("Buy"));
+        assert_eq!(df.column(KEY::order_side)?.str()?.get(1), Some("Sell"));
+        assert_eq!(df.column(KEY::size)?.f64()?.get(0), Some(4.7));
+        // レコードのidはagg_id
+        assert_eq!(df.column(KEY::id)?.str()?.get(1), Some("26130"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rec_to_agg_trade() -> anyhow::Result<()> {
+        let rec = StringRecord::from(vec![
+            "3126412893", "60000.01000000", "0.01000000", "3730692451", "3730692453", "1724371200052", "True", "True",
+        ]);
+        let trade = rec_to_agg_trade(&rec)?;
+
+        assert_eq!(trade.id, "3126412893");
+        assert_eq!(trade.time, 1724371200_052_000);
+        assert_eq!(trade.price, Decimal::from_str("60000.01")?);
+        assert_eq!(trade.size, Decimal::from_str("0.01")?);
+        assert_eq!(trade.order_side, OrderSide::Buy);
+
+        // futuresの形式(is_best_matchなし)
+        let rec = StringRecord::from(vec!["1", "100.5", "2", "10", "12", "1724371200052", "false"]);
+        assert_eq!(rec_to_agg_trade(&rec)?.order_side, OrderSide::Sell);
+
+        Ok(())
+    }
 }
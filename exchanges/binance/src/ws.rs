@@ -1,3 +1,4 @@
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use futures::Stream;
@@ -10,8 +11,8 @@ use tokio::task::JoinHandle;
 use async_stream::stream;
 
 use rbot_lib::{
-    common::{MarketConfig, MultiMarketMessage, ExchangeConfig, NOW},
-    net::{AutoConnectClient, WsOpMessage},
+    common::{Channel, ChannelType, MarketConfig, MultiMarketMessage, ExchangeConfig, NOW},
+    net::{AutoConnectClient, RawMessageHook, WsOpMessage, WsStateHandle},
 };
 use tokio::time::sleep;
 
@@ -32,13 +33,58 @@ use anyhow::anyhow;
 /// User data streams will close after 60 minutes.
 /// It's recommended to send a ping about every 30 minutes.
 
-// const KEY_EXTEND_INTERVAL: MicroSec = 5 * 60 * MICRO_SECOND; // 30 min
+pub const LISTEN_KEY_EXTEND_INTERVAL_SEC: u64 = 60 * 25; // every 25 min
 
 pub const PING_INTERVAL_SEC: i64 = 60 * 3; // every 3 min
 pub const SWITCH_INTERVAL_SEC: i64 = 60 * 60 * 12; // 12 hours
 const SYNC_WAIT_RECORDS_FOR_PUBLIC: i64 = 3; // no overlap
 const SYNC_WAIT_RECORDS_FOR_PRIVATE: i64 = 0; // no overlap
 
+/// depthの更新間隔。1000msは"<symbol>@depth"になる。
+pub const BINANCE_DEPTH_INTERVALS: [&str; 2] = ["100ms", "1000ms"];
+
+/// MarketConfig.channelsからstream名を作る。未設定の場合はtrade + depth@100ms。
+/// COIN-M(inverse)には@tradeがないため、約定は@aggTradeを購読する。
+/// board_depthは"<symbol>@depth<levels>"には使わない。partial depthは5/10/20段のスナップショットで
+/// update id(U/u)がなく、RESTのスナップショットにdiffを積む板の管理と両立しないため。
+/// 深さはRESTのスナップショットのlimitとローカルの板の切り詰めに使う(binance_board_depth)。
+/// bookTicker/klineはメッセージを解釈できないため購読しない(エラーにする)。
+pub fn binance_ws_channels(config: &MarketConfig) -> anyhow::Result<Vec<String>> {
+    let channels = if config.channels.is_empty() {
+        vec![Channel::trade(), Channel::depth("")]
+    } else {
+        config.channels.clone()
+    };
+
+    let symbol = config.trade_symbol.to_lowercase();
+
+    channels
+        .iter()
+        .map(|channel| match channel.channel_type {
+            ChannelType::Trade => {
+                if config.trade_category == "inverse" {
+                    Ok(format!("{}@aggTrade", symbol))
+                } else {
+                    Ok(format!("{}@trade", symbol))
+                }
+            }
+            ChannelType::Depth => match channel.param.as_str() {
+                "" | "100ms" => Ok(format!("{}@depth@100ms", symbol)),
+                "1000ms" => Ok(format!("{}@depth", symbol)),
+                p => Err(anyhow!(
+                    "unsupported depth interval {} for binance (supported={:?})",
+                    p,
+                    BINANCE_DEPTH_INTERVALS
+                )),
+            },
+            ChannelType::BookTicker | ChannelType::Kline => Err(anyhow!(
+                "unsupported channel {:?} for binance (supported=Trade, Depth)",
+                channel.channel_type
+            )),
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BinanceWsOpMessage {
     method: String,
@@ -91,10 +137,15 @@ impl WebSocketClient for BinancePublicWsClient {
             None,
         );
 
-        public_ws.subscribe(&vec![
-            format!("{}@trade", config.trade_symbol.to_lowercase()),
-            format!("{}@depth@100ms",  config.trade_symbol.to_lowercase())
-        ]).await;
+        // 不正なチャンネルはset_subscriptions/start_market_streamで弾いているのでここではデフォルトに戻す
+        let channels = binance_ws_channels(config).unwrap_or_else(|e| {
+            log::error!("{:?}, use default channels", e);
+            let mut config = config.clone();
+            config.channels.clear();
+            binance_ws_channels(&config).unwrap()
+        });
+
+        public_ws.subscribe(&channels).await;
 
         Self {
             ws: public_ws,
@@ -141,6 +192,14 @@ impl WebSocketClient for BinancePublicWsClient {
             }
         }
     }
+
+    fn set_raw_message_hook(&mut self, hook: Option<RawMessageHook>) {
+        self.ws.set_raw_message_hook(hook);
+    }
+
+    fn set_state_handle(&mut self, state: WsStateHandle) {
+        self.ws.set_state_handle(state);
+    }
 }
 
 impl BinancePublicWsClient{
@@ -163,21 +222,86 @@ impl BinancePublicWsClient{
     }
 }
 
+/// user data streamのlistenKeyを管理する。
+/// open()で作成し、keepaliveタスクで定期的に延長する。切断時はopen()で作り直す。
+#[derive(Clone)]
+pub struct BinanceUserStream {
+    api: BinanceRestApi,
+    listen_key: Arc<RwLock<String>>,
+}
+
+impl BinanceUserStream {
+    pub fn new(server: &ExchangeConfig) -> Self {
+        Self {
+            api: BinanceRestApi::new(server),
+            listen_key: Arc::new(RwLock::new("".to_string())),
+        }
+    }
+
+    pub fn listen_key(&self) -> String {
+        self.listen_key.read().unwrap().clone()
+    }
+
+    pub fn connect_url(&self) -> String {
+        self.api.make_connect_url(&self.listen_key())
+    }
+
+    /// POST /api/v3/userDataStream で新しいlistenKeyを作成する
+    pub async fn open(&self) -> anyhow::Result<String> {
+        let key = self.api.create_listen_key().await?;
+        *self.listen_key.write().unwrap() = key.clone();
+
+        Ok(key)
+    }
+
+    /// PUT /api/v3/userDataStream で現在のlistenKeyを延長する
+    pub async fn renew(&self) -> anyhow::Result<()> {
+        let key = self.listen_key();
+        if key == "" {
+            return Err(anyhow!("listenKey is not opened"));
+        }
+
+        self.api.extend_listen_key(&key).await
+    }
+
+    pub fn start_keepalive(&self, interval: Duration) -> JoinHandle<()> {
+        let stream = self.clone();
+
+        tokio::task::spawn(async move {
+            loop {
+                sleep(interval).await;
+                let r = stream.renew().await;
+                log::info!("Extend listen key");
+                if r.is_err() {
+                    log::error!("Failed to extend listen key: {:?}", r);
+                }
+            }
+        })
+    }
+}
+
 pub struct BinancePrivateWsClient {
     ws: AutoConnectClient<BinanceWsOpMessage>,
     server: ExchangeConfig,
     _handler: Option<JoinHandle<()>>,
-    listen_key: String,
+    user_stream: BinanceUserStream,
     key_update_handler: Option<JoinHandle<()>>,
-    api: BinanceRestApi,
+}
+
+impl Drop for BinancePrivateWsClient {
+    fn drop(&mut self) {
+        if let Some(handler) = self.key_update_handler.take() {
+            handler.abort();
+        }
+    }
 }
 
 impl BinancePrivateWsClient {
     pub async fn new(server: &ExchangeConfig) -> Self {
-        let api = BinanceRestApi::new(server);
+        let user_stream = BinanceUserStream::new(server);
 
-        let listen_key = api.create_listen_key().await.unwrap();
-        let url = api.make_connect_url(&listen_key);
+        user_stream.open().await.unwrap();
+        let url = user_stream.connect_url();
 
         let private_ws = AutoConnectClient::new(
             server,
@@ -194,39 +318,48 @@ impl BinancePrivateWsClient {
             server: server.clone(),
             ws: private_ws,
             _handler: None,
-            listen_key: listen_key,
+            user_stream: user_stream,
             key_update_handler: None,
-            api: BinanceRestApi::new(server)
         }
     }
 
+    pub fn set_raw_message_hook(&mut self, hook: Option<RawMessageHook>) {
+        self.ws.set_raw_message_hook(hook);
+    }
+
     pub async fn connect(&mut self) {
         self.ws.connect().await;
 
-        let key = self.listen_key.clone();
-        let api = self.api.clone();
+        if let Some(handler) = self.key_update_handler.take() {
+            handler.abort();
+        }
 
-        let handler = tokio::task::spawn(async move {
-            loop {
-                sleep(Duration::from_secs(60 * 60)).await;
-                let r = api.extend_listen_key(&key).await;
-                log::info!("Extend listen key");
-                if r.is_err() {
-                    log::error!("Failed to extend listen key: {:?}", r);
-                }
-            }
-        });
+        let handler = self
+            .user_stream
+            .start_keepalive(Duration::from_secs(LISTEN_KEY_EXTEND_INTERVAL_SEC));
 
         self.key_update_handler = Some(handler);
     }
 
+    /// 切断時は新しいlistenKeyを作成し、次の再接続で使うURLを差し替える
+    async fn reopen_listen_key(&mut self) {
+        match self.user_stream.open().await {
+            Ok(_) => {
+                self.ws.url = self.user_stream.connect_url();
+                log::info!("listenKey recreated");
+            }
+            Err(e) => {
+                log::error!("Failed to recreate listen key: {:?}", e);
+            }
+        }
+    }
+
     pub async fn open_stream<'a>(
         &'a mut self,
     ) -> impl Stream<Item = Result<MultiMarketMessage, String>> + 'a {
-        let mut s = Box::pin(self.ws.open_stream().await);
-
         stream! {
-            while let Some(message) = s.next().await {
+            loop {
+                let message = self.ws.receive_text().await;
                 match message {
                     Ok(m) => {
                         if let ReceiveMessage::Text(m) = m {
@@ -254,6 +387,7 @@ impl BinancePrivateWsClient {
                     }
                     Err(e) => {
                         println!("Receive Error: {:?}", e);
+                        self.reopen_listen_key().await;
                     }
                 }
             }
@@ -282,6 +416,36 @@ mod tests {
     use super::*;
     use crate::BinanceConfig;
     use rbot_lib::common::init_debug_log;
+    use rbot_lib::net::mock::{request_line, MockHttpServer};
+
+    #[test]
+    fn test_binance_ws_channels() -> anyhow::Result<()> {
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "ETHUSDT".to_string();
+
+        assert_eq!(binance_ws_channels(&config)?, vec!["ethusdt@trade", "ethusdt@depth@100ms"]);
+
+        config.channels = vec![Channel::trade(), Channel::depth("1000ms")];
+        assert_eq!(binance_ws_channels(&config)?, vec!["ethusdt@trade", "ethusdt@depth"]);
+
+        config.channels = vec![Channel::depth("500ms")];
+        assert!(binance_ws_channels(&config).is_err());
+
+        // 解釈できないstreamは購読しない
+        config.channels = vec![Channel::trade(), Channel::book_ticker()];
+        assert!(binance_ws_channels(&config).is_err());
+
+        config.channels = vec![Channel::kline("1m")];
+        assert!(binance_ws_channels(&config).is_err());
+
+        // COIN-M
+        config.trade_category = "inverse".to_string();
+        config.trade_symbol = "BTCUSD_PERP".to_string();
+        config.channels = vec![];
+        assert_eq!(binance_ws_channels(&config)?, vec!["btcusd_perp@aggTrade", "btcusd_perp@depth@100ms"]);
+
+        Ok(())
+    }
 
     #[tokio::test]
     async fn test_binance_public_ws_client() {
@@ -315,6 +479,85 @@ mod tests {
         }
     }
 
+    /// POSTにはlistenKeyを返し、それ以外には空のJSONを返す
+    fn listen_key_handler() -> impl FnMut(&str) -> String + Send + 'static {
+        let mut key_no = 0;
+
+        move |request| {
+            if request_line(request).starts_with("POST") {
+                key_no += 1;
+                format!(r#"{{"listenKey":"key-{}"}}"#, key_no)
+            } else {
+                "{}".to_string()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_stream_listen_key_renewal() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(listen_key_handler()).await;
+        let url = mock.url();
+        let server = ExchangeConfig::new("binance", false, &url, &url, "ws://localhost", "ws://localhost", "");
+
+        let user_stream = BinanceUserStream::new(&server);
+        assert!(user_stream.renew().await.is_err()); // not opened yet
+
+        assert_eq!(user_stream.open().await?, "key-1");
+
+        let handler = user_stream.start_keepalive(Duration::from_millis(100));
+        sleep(Duration::from_millis(350)).await;
+        handler.abort();
+
+        {
+            let log = mock.request_lines();
+            assert_eq!(log[0], "POST /api/v3/userDataStream HTTP/1.1");
+            let renewals = log.iter().filter(|l| l.starts_with("PUT /api/v3/userDataStream?listenKey=key-1")).count();
+            assert!(2 <= renewals);
+        }
+
+        // on disconnect, a new key is created and used for renewal
+        assert_eq!(user_stream.open().await?, "key-2");
+        assert!(user_stream.connect_url().ends_with("/ws/key-2"));
+        user_stream.renew().await?;
+
+        let log = mock.request_lines();
+        assert!(log.last().unwrap().starts_with("PUT /api/v3/userDataStream?listenKey=key-2"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_testnet_urls() {
+        const TESTNET: &str = "testnet.binance.vision";
+
+        let config = BinanceConfig::new(false, true);
+        let server = config.get_server_config();
+        assert!(!server.is_production());
+
+        let api = BinanceRestApi::new(&server);
+        // BinancePrivateWsClientと同じくserver configからuser streamを作る
+        let user_stream = BinanceUserStream::new(&server);
+
+        let urls = vec![
+            server.get_public_api(),
+            server.get_private_api(),
+            server.get_public_ws_server(),
+            server.get_private_ws_server(),
+            api.make_connect_url("KEY"),
+            user_stream.connect_url(),
+        ];
+
+        for url in urls {
+            assert!(url.contains(TESTNET), "{}", url);
+        }
+
+        assert_eq!(api.make_connect_url("KEY"), "wss://testnet.binance.vision/ws/KEY");
+
+        // productionは接続先が変わらない
+        let api = BinanceRestApi::new(&BinanceConfig::new(false, false).get_server_config());
+        assert_eq!(api.make_connect_url("KEY"), "wss://stream.binance.com:9443/ws/KEY");
+    }
+
     #[tokio::test]
     async fn test_make_connect_url() {
         let server = BinanceServerConfig::new(false);
@@ -1,7 +1,10 @@
-
 mod rest;
 mod message;
+mod private;
+mod market;
 
 pub use rest::*;
 pub use message::*;
+pub use private::*;
+pub use market::*;
 
@@ -0,0 +1,71 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use anyhow::anyhow;
+use rust_decimal::Decimal;
+
+use rbot_blockon::BLOCK_ON;
+use rbot_lib::common::{AccountCoins, ExchangeConfig, MarketConfig, Order};
+use rbot_market::OrderInterfaceImpl;
+
+use crate::BitbankRestApi;
+
+/// bitbank現物の注文。WebSocketのuser streamはまだない。
+pub struct BitbankMarket {
+    config: MarketConfig,
+    api: BitbankRestApi,
+    enable_order: bool,
+}
+
+impl BitbankMarket {
+    pub fn new(server_config: &ExchangeConfig, config: &MarketConfig) -> Self {
+        Self {
+            config: config.clone(),
+            api: BitbankRestApi::new(server_config),
+            enable_order: false,
+        }
+    }
+
+    pub fn get_config(&self) -> MarketConfig {
+        self.config.clone()
+    }
+
+    pub fn limit_order(&self, side: &str, price: Decimal, size: Decimal) -> anyhow::Result<Vec<Order>> {
+        BLOCK_ON(async {
+            OrderInterfaceImpl::limit_order(self, &self.config, side, price, size, None, None, false).await
+        })
+    }
+
+    pub fn market_order(&self, side: &str, size: Decimal) -> anyhow::Result<Vec<Order>> {
+        BLOCK_ON(async { OrderInterfaceImpl::market_order(self, &self.config, side, size, None, false).await })
+    }
+
+    pub fn cancel_order(&self, order_id: &str) -> anyhow::Result<Order> {
+        BLOCK_ON(async { OrderInterfaceImpl::cancel_order(self, &self.config, order_id).await })
+    }
+
+    pub fn get_open_orders(&self) -> anyhow::Result<Vec<Order>> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_open_orders(self, &self.config).await })
+    }
+
+    pub fn get_account(&self) -> anyhow::Result<AccountCoins> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_account(self).await })
+    }
+}
+
+impl OrderInterfaceImpl<BitbankRestApi> for BitbankMarket {
+    fn get_restapi(&self) -> &BitbankRestApi {
+        &self.api
+    }
+
+    fn set_enable_order_feature(&mut self, enable_order: bool) {
+        self.enable_order = enable_order;
+    }
+
+    fn get_enable_order_feature(&self) -> bool {
+        self.enable_order
+    }
+
+    async fn async_start_user_stream(&mut self) -> anyhow::Result<()> {
+        Err(anyhow!("user stream is not supported in bitbank"))
+    }
+}
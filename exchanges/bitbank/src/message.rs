@@ -1,5 +1,8 @@
 
-use rbot_lib::common::{string_to_decimal, LogStatus, MicroSec, OrderSide, Trade};
+use rbot_lib::common::{
+    msec_to_microsec, string_to_decimal, AccountCoins, Coin, LogStatus, MarketError, MicroSec, Order, OrderSide,
+    OrderStatus, OrderType, Trade,
+};
 use rust_decimal::Decimal;
 use serde::{self, Deserialize, Serialize};
 use serde_derive;
@@ -57,6 +60,143 @@ pub struct BitbankRestResponse {
 
 
 
+/// private APIの応答。success=0の時はdata.codeにエラーコードが入る。
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BitbankPrivateResponse {
+    pub success: i64,
+    pub data: Value,
+}
+
+impl BitbankPrivateResponse {
+    pub fn is_success(&self) -> bool {
+        self.success == 1
+    }
+
+    pub fn error_code(&self) -> i64 {
+        self.data["code"].as_i64().unwrap_or(0)
+    }
+
+    /// エラーコードの分類 https://github.com/bitbankinc/bitbank-api-docs/blob/master/errors.md
+    pub fn to_market_error(&self) -> MarketError {
+        let code = self.error_code();
+        let msg = format!("bitbank error code={}", code);
+
+        match code {
+            20001..=20005 => MarketError::Auth(msg),
+            10009 => MarketError::RateLimited(msg),
+            50009 | 50026 | 50027 => MarketError::NotFound(msg),
+            60001..=60011 | 70001..=70020 => MarketError::invalid_order(&msg),
+            _ => MarketError::Exchange { code, msg },
+        }
+    }
+}
+
+/// UNFILLED / PARTIALLY_FILLED / FULLY_FILLED / CANCELED_UNFILLED / CANCELED_PARTIALLY_FILLED
+pub fn bitbank_order_status(status: &str) -> OrderStatus {
+    match status {
+        "UNFILLED" => OrderStatus::New,
+        "PARTIALLY_FILLED" => OrderStatus::PartiallyFilled,
+        "FULLY_FILLED" => OrderStatus::Filled,
+        "CANCELED_UNFILLED" | "CANCELED_PARTIALLY_FILLED" => OrderStatus::Canceled,
+        _ => {
+            log::warn!("unknown bitbank order status {}", status);
+            OrderStatus::Unknown
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BitbankOrder {
+    pub order_id: i64,
+    pub pair: String,
+    pub side: String,
+    #[serde(rename = "type")]
+    pub order_type: String,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub start_amount: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub remaining_amount: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub executed_amount: Decimal,
+    /// 成行注文にはない
+    #[serde(default)]
+    pub price: Option<Decimal>,
+    #[serde(default)]
+    pub average_price: Option<Decimal>,
+    pub ordered_at: i64,
+    pub status: String,
+}
+
+impl Into<Order> for BitbankOrder {
+    fn into(self) -> Order {
+        let order_type = if self.order_type == "market" {
+            OrderType::Market
+        } else {
+            OrderType::Limit
+        };
+
+        let mut order = Order::new(
+            "spot",
+            &self.pair,
+            msec_to_microsec(self.ordered_at),
+            &self.order_id.to_string(),
+            "",
+            OrderSide::from(&self.side),
+            order_type,
+            bitbank_order_status(&self.status),
+            self.price.unwrap_or_default(),
+            self.start_amount,
+        );
+
+        order.remain_size = self.remaining_amount;
+        order.execute_size = self.executed_amount;
+        order.execute_price = self.average_price.unwrap_or_default();
+        order.update_time = order.create_time;
+        order.is_maker = order_type.is_maker();
+
+        order
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BitbankOrders {
+    pub orders: Vec<BitbankOrder>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BitbankAsset {
+    pub asset: String,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub onhand_amount: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub locked_amount: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub free_amount: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BitbankAssets {
+    pub assets: Vec<BitbankAsset>,
+}
+
+impl Into<AccountCoins> for BitbankAssets {
+    fn into(self) -> AccountCoins {
+        let mut coins = AccountCoins::new();
+
+        for a in self.assets {
+            let mut coin = Coin::default();
+            coin.symbol = a.asset.to_uppercase();
+            coin.volume = a.onhand_amount;
+            coin.free = a.free_amount;
+            coin.locked = a.locked_amount;
+
+            coins.push(coin);
+        }
+
+        coins
+    }
+}
+
 #[cfg(test)]
 mod test_bitbank_message {
     use crate::BitbankRestResponse;
@@ -73,5 +213,32 @@ const MESSAGE: &str = r#"
 
     }
 
-
-}
\ No newline at end of file
+    #[test]
+    fn test_parse_order() -> anyhow::Result<()> {
+        use crate::{BitbankOrder, BitbankPrivateResponse};
+        use rbot_lib::common::{MarketError, Order, OrderSide, OrderStatus, OrderType};
+        use rust_decimal_macros::dec;
+
+        let message = r#"{"order_id":12345,"pair":"btc_jpy","side":"buy","type":"limit","start_amount":"0.0010","remaining_amount":"0.0004","executed_amount":"0.0006","price":"9000000","post_only":false,"average_price":"9000000","ordered_at":1724803202489,"status":"PARTIALLY_FILLED"}"#;
+        let order: Order = serde_json::from_str::<BitbankOrder>(message)?.into();
+
+        assert_eq!(order.order_id, "12345");
+        assert_eq!(order.order_side, OrderSide::Buy);
+        assert_eq!(order.order_type, OrderType::Limit);
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+        assert_eq!(order.order_price, dec![9000000]);
+        assert_eq!(order.remain_size, dec![0.0004]);
+        assert_eq!(order.execute_size, dec![0.0006]);
+
+        let message = r#"{"order_id":12346,"pair":"btc_jpy","side":"sell","type":"market","start_amount":"0.0010","remaining_amount":"0.0000","executed_amount":"0.0010","average_price":"9000000","ordered_at":1724803202489,"status":"FULLY_FILLED"}"#;
+        let order: Order = serde_json::from_str::<BitbankOrder>(message)?.into();
+        assert_eq!(order.order_type, OrderType::Market);
+        assert_eq!(order.status, OrderStatus::Filled);
+
+        let response = serde_json::from_str::<BitbankPrivateResponse>(r#"{"success":0,"data":{"code":20003}}"#)?;
+        assert!(!response.is_success());
+        assert!(matches!(response.to_market_error(), MarketError::Auth(_)));
+
+        Ok(())
+    }
+}
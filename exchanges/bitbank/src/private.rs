@@ -0,0 +1,260 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use anyhow::Context as _;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use rbot_lib::common::{hmac_sign, AccountCoins, ExchangeConfig, Order, NOW};
+use rbot_lib::net::{rest_get, rest_post};
+
+use crate::{BitbankAssets, BitbankOrder, BitbankOrders, BitbankPrivateResponse};
+
+#[derive(Serialize, Debug)]
+struct BitbankOrderRequest<'a> {
+    pair: &'a str,
+    amount: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price: Option<Decimal>,
+    side: &'a str,
+    #[serde(rename = "type")]
+    order_type: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct BitbankCancelRequest<'a> {
+    pair: &'a str,
+    order_id: i64,
+}
+
+/// 署名付きのprivate API(ACCESS-NONCE方式)。
+/// GETは nonce + path(+ "?" + query)、POSTは nonce + body をHMAC-SHA256で署名する。
+pub struct BitbankPrivateClient {
+    server_config: ExchangeConfig,
+}
+
+impl BitbankPrivateClient {
+    pub fn new(server_config: &ExchangeConfig) -> Self {
+        Self {
+            server_config: server_config.clone(),
+        }
+    }
+
+    /// ACCESS-KEY / ACCESS-NONCE / ACCESS-SIGNATURE ヘッダ
+    pub fn auth_headers(api_key: &str, api_secret: &str, nonce: &str, message: &str) -> Vec<(String, String)> {
+        let sign = hmac_sign(api_secret, &format!("{}{}", nonce, message));
+
+        vec![
+            ("ACCESS-KEY".to_string(), api_key.to_string()),
+            ("ACCESS-NONCE".to_string(), nonce.to_string()),
+            ("ACCESS-SIGNATURE".to_string(), sign),
+        ]
+    }
+
+    /// side: "buy" / "sell", type_: "limit" / "market"。成行の場合priceはNone。
+    pub async fn place_order(
+        &self,
+        pair: &str,
+        amount: Decimal,
+        price: Option<Decimal>,
+        side: &str,
+        type_: &str,
+    ) -> anyhow::Result<Order> {
+        let request = BitbankOrderRequest {
+            pair,
+            amount,
+            price,
+            side,
+            order_type: type_,
+        };
+
+        let body = serde_json::to_string(&request)?;
+        let response = self.post_sign("/v1/user/spot/order", &body).await?;
+
+        let order = serde_json::from_value::<BitbankOrder>(response.data)
+            .with_context(|| format!("parse error in place_order"))?;
+
+        Ok(order.into())
+    }
+
+    pub async fn cancel_order(&self, pair: &str, order_id: &str) -> anyhow::Result<Order> {
+        let request = BitbankCancelRequest {
+            pair,
+            order_id: order_id.parse().with_context(|| format!("invalid order_id {}", order_id))?,
+        };
+
+        let body = serde_json::to_string(&request)?;
+        let response = self.post_sign("/v1/user/spot/cancel_order", &body).await?;
+
+        let order = serde_json::from_value::<BitbankOrder>(response.data)
+            .with_context(|| format!("parse error in cancel_order"))?;
+
+        Ok(order.into())
+    }
+
+    pub async fn get_order(&self, pair: &str, order_id: &str) -> anyhow::Result<Order> {
+        let query = format!("pair={}&order_id={}", pair, order_id);
+        let response = self.get_sign("/v1/user/spot/order", &query).await?;
+
+        let order = serde_json::from_value::<BitbankOrder>(response.data)
+            .with_context(|| format!("parse error in get_order"))?;
+
+        Ok(order.into())
+    }
+
+    pub async fn get_active_orders(&self, pair: &str) -> anyhow::Result<Vec<Order>> {
+        let query = format!("pair={}", pair);
+        let response = self.get_sign("/v1/user/spot/active_orders", &query).await?;
+
+        let orders = serde_json::from_value::<BitbankOrders>(response.data)
+            .with_context(|| format!("parse error in get_active_orders"))?;
+
+        Ok(orders.orders.into_iter().map(|o| o.into()).collect())
+    }
+
+    pub async fn get_assets(&self) -> anyhow::Result<AccountCoins> {
+        let response = self.get_sign("/v1/user/assets", "").await?;
+
+        let assets = serde_json::from_value::<BitbankAssets>(response.data)
+            .with_context(|| format!("parse error in get_assets"))?;
+
+        Ok(assets.into())
+    }
+
+    async fn get_sign(&self, path: &str, query: &str) -> anyhow::Result<BitbankPrivateResponse> {
+        let server = &self.server_config;
+        let nonce = format!("{}", NOW() / 1_000);
+
+        let message = if query.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, query)
+        };
+
+        let headers = Self::auth_headers(
+            &server.get_api_key().extract(),
+            &server.get_api_secret().extract(),
+            &nonce,
+            &message,
+        );
+        let headers: Vec<(&str, &str)> = headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+
+        let param = if query.is_empty() { None } else { Some(query) };
+
+        let response = rest_get(&server.get_private_api(), path, headers, param, None)
+            .await
+            .with_context(|| format!("get_sign error: {}{}", server.get_private_api(), message))?;
+
+        Self::parse_response(&response)
+    }
+
+    async fn post_sign(&self, path: &str, body: &str) -> anyhow::Result<BitbankPrivateResponse> {
+        let server = &self.server_config;
+        let nonce = format!("{}", NOW() / 1_000);
+
+        let mut headers = Self::auth_headers(
+            &server.get_api_key().extract(),
+            &server.get_api_secret().extract(),
+            &nonce,
+            body,
+        );
+        headers.push(("Content-Type".to_string(), "application/json".to_string()));
+        let headers: Vec<(&str, &str)> = headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+
+        let response = rest_post(&server.get_private_api(), path, headers, body)
+            .await
+            .with_context(|| format!("post_sign error: {}{} / {}", server.get_private_api(), path, body))?;
+
+        Self::parse_response(&response)
+    }
+
+    fn parse_response(response: &str) -> anyhow::Result<BitbankPrivateResponse> {
+        let response = serde_json::from_str::<BitbankPrivateResponse>(response)
+            .with_context(|| format!("parse error in parse_response: {:?}", response))?;
+
+        if !response.is_success() {
+            return Err(anyhow::Error::new(response.to_market_error()));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod bitbank_private_test {
+    use rbot_lib::common::{hmac_sign, ExchangeConfig, OrderStatus};
+    use rbot_lib::net::mock::{request_body, request_header, MockHttpServer};
+    use rust_decimal_macros::dec;
+
+    use super::BitbankPrivateClient;
+
+    #[test]
+    fn test_auth_headers() {
+        let headers = BitbankPrivateClient::auth_headers("key", "secret", "1721121776490", "/v1/user/assets");
+
+        assert_eq!(headers[0], ("ACCESS-KEY".to_string(), "key".to_string()));
+        assert_eq!(headers[1], ("ACCESS-NONCE".to_string(), "1721121776490".to_string()));
+        assert_eq!(
+            headers[2],
+            (
+                "ACCESS-SIGNATURE".to_string(),
+                hmac_sign("secret", "1721121776490/v1/user/assets")
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_place_order_sign() -> anyhow::Result<()> {
+        let response = r#"{"success":1,"data":{"order_id":1,"pair":"btc_jpy","side":"buy","type":"limit","start_amount":"0.0010","remaining_amount":"0.0010","executed_amount":"0.0000","price":"9000000","average_price":"0","ordered_at":1724803202489,"status":"UNFILLED"}}"#;
+        let mock = MockHttpServer::start(move |_request| response.to_string()).await;
+        let url = mock.url();
+
+        let server = ExchangeConfig::new("bitbank", false, &url, &url, "", "", "");
+        let client = BitbankPrivateClient::new(&server);
+
+        let order = client
+            .place_order("btc_jpy", dec![0.001], Some(dec![9000000]), "buy", "limit")
+            .await?;
+        assert_eq!(order.order_id, "1");
+        assert_eq!(order.status, OrderStatus::New);
+
+        let request = mock.requests()[0].clone();
+        assert!(request.starts_with("POST /v1/user/spot/order "));
+
+        let body = request_body(&request);
+        assert_eq!(body, r#"{"pair":"btc_jpy","amount":"0.001","price":"9000000","side":"buy","type":"limit"}"#);
+
+        let nonce = request_header(&request, "ACCESS-NONCE").unwrap();
+        assert_eq!(
+            request_header(&request, "ACCESS-SIGNATURE"),
+            Some(hmac_sign(&server.get_api_secret().extract(), &format!("{}{}", nonce, body)).as_str())
+        );
+        assert_eq!(request_header(&request, "ACCESS-KEY"), Some(server.get_api_key().extract().as_str()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_assets_sign() -> anyhow::Result<()> {
+        let response = r#"{"success":1,"data":{"assets":[{"asset":"jpy","free_amount":"900","amount_precision":4,"onhand_amount":"1000","locked_amount":"100","withdrawal_fee":"0"}]}}"#;
+        let mock = MockHttpServer::start(move |_request| response.to_string()).await;
+        let url = mock.url();
+
+        let server = ExchangeConfig::new("bitbank", false, &url, &url, "", "", "");
+        let client = BitbankPrivateClient::new(&server);
+
+        let coins = client.get_assets().await?;
+        assert_eq!(coins.coins[0].symbol, "JPY");
+        assert_eq!(coins.coins[0].locked, dec![100]);
+
+        let request = mock.requests()[0].clone();
+        assert!(request.starts_with("GET /v1/user/assets "));
+
+        let nonce = request_header(&request, "ACCESS-NONCE").unwrap();
+        assert_eq!(
+            request_header(&request, "ACCESS-SIGNATURE"),
+            Some(hmac_sign(&server.get_api_secret().extract(), &format!("{}/v1/user/assets", nonce)).as_str())
+        );
+
+        Ok(())
+    }
+}
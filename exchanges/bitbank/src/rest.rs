@@ -1,5 +1,5 @@
 
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{fs::File, io::BufReader};
 use tempfile::tempdir;
 
 
@@ -8,9 +8,9 @@ use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
-use rbot_lib::{common::{split_yyyymmdd, AccountCoins, BoardTransfer, ExchangeConfig, Kline, MarketConfig, MicroSec, Order, OrderSide, OrderType, Trade}, db::{df_to_parquet, log_download_tmp, TradeBuffer}, net::{check_exist, rest_get, RestApi, RestPage}};
+use rbot_lib::{common::{split_yyyymmdd, AccountCoins, BoardTransfer, ExchangeConfig, Kline, MarketConfig, MicroSec, Order, OrderSide, OrderType, TimeInForce, Trade}, db::{log_download_tmp, TradeBuffer}, net::{check_exist, http_client_builder, rest_get, RestApi, RestPage}};
 
-use crate::{BitbankRestResponse, BitbankTransactions};
+use crate::{BitbankPrivateClient, BitbankRestResponse, BitbankTransactions};
 
 use anyhow::{anyhow, Context as _};
 
@@ -199,7 +199,6 @@ impl RestApi for BitbankRestApi {
         60
     }
 
-     // TODO: impl
     async fn new_order(
         &self,
         config: &MarketConfig,
@@ -208,194 +207,62 @@ impl RestApi for BitbankRestApi {
         size: Decimal,
         order_type: OrderType,
         client_order_id: Option<&str>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>> {
-        /*
-        let server = &self.server_config;
+        let client = BitbankPrivateClient::new(&self.server_config);
 
-        let category = config.trade_category.clone();
-        let symbol = config.trade_symbol.clone();
+        if client_order_id.is_some() {
+            log::warn!("bitbank does not support client_order_id, ignored");
+        }
 
-        let price = if order_type == OrderType::Market {
-            None
-        } else {
-            Some(price)
-        };
+        if time_in_force != TimeInForce::GTC || reduce_only {
+            log::warn!("bitbank spot order ignores time_in_force={:?} / reduce_only={:?}", time_in_force, reduce_only);
+        }
 
-        let order = BybitOrderRequest {
-            category: category.clone(),
-            symbol: symbol.clone(),
-            side: side.to_string(),
-            order_type: order_type.to_string(),
-            qty: size,
-            order_link_id: client_order_id,
-            price: price,
+        let (price, type_) = match order_type {
+            OrderType::Market => (None, "market"),
+            _ => (Some(price), "limit"),
         };
 
-        let order_json = serde_json::to_string(&order)?;
-        log::debug!("order_json={}", order_json);
-
-        let path = "/v5/order/create";
-
-        let result = Self::post_sign(&server, path, &order_json)
-            .await
-            .with_context(|| {
-                format!(
-                    "new_order: server={:?} / path={:?} / order_json={:?}",
-                    server, path, order_json
-                )
-            })?;
-
-        let r = serde_json::from_value::<BybitOrderRestResponse>(result.body)
-            .with_context(|| format!("parse error in new_order "))?;
-
-        let is_maker = order_type.is_maker();
-
-        let mut order = Order::default();
-
-        order.category = category;
-        order.symbol = symbol;
-        order.create_time = msec_to_microsec(result.time);
-        order.status = OrderStatus::New;
-        order.order_id = r.order_id;
-        order.client_order_id = r.order_link_id;
-        order.order_side = side;
-        order.order_type = order_type;
-        order.order_price = if order_type == OrderType::Market {
-            dec![0.0]
-        } else {
-            price.unwrap()
+        let side = match side {
+            OrderSide::Buy => "buy",
+            OrderSide::Sell => "sell",
+            _ => return Err(anyhow!("unknown order side {:?}", side)),
         };
-        order.order_size = size;
-        order.remain_size = size;
-        order.update_time = msec_to_microsec(result.time);
-        order.is_maker = is_maker;
 
-        order.update_balance(&config);
-
-        return Ok(vec![order]);
-        */
+        let mut order = client
+            .place_order(&config.trade_symbol, size, price, side, type_)
+            .await?;
+        order.update_balance(config);
 
-        todo!()
+        Ok(vec![order])
     }
 
     async fn cancel_order(&self, config: &MarketConfig, order_id: &str) -> anyhow::Result<Order> {
-        /*
-        let server = &self.server_config;
-
-        let category = config.trade_category.clone();
-        let message = CancelOrderMessage {
-            category: category.clone(),
-            symbol: config.trade_symbol.clone(),
-            order_id: order_id.to_string(),
-        };
-
-        let message_json = serde_json::to_string(&message)?;
-        let path = "/v5/order/cancel";
-        let result = Self::post_sign(&server, path, &message_json)
-            .await
-            .with_context(|| {
-                format!(
-                    "cancel_order: server={:?} / path={:?} / message_json={:?}",
-                    server, path, message_json
-                )
-            })?;
-
-        let r = serde_json::from_value::<BybitOrderRestResponse>(result.body)?;
-
-        let mut order = Order::default();
-
-        order.category = category;
-        order.symbol = config.trade_symbol.clone();
-        order.create_time = msec_to_microsec(result.time);
-        order.status = OrderStatus::Canceled;
-        order.order_id = r.order_id;
-        order.client_order_id = r.order_link_id;
-        order.order_side = OrderSide::Unknown;
-        order.order_type = OrderType::Limit;
-        order.update_time = msec_to_microsec(result.time);
-        order.is_maker = true;
+        let client = BitbankPrivateClient::new(&self.server_config);
 
+        let mut order = client.cancel_order(&config.trade_symbol, order_id).await?;
         order.update_balance(config);
 
-        return Ok(order);
-        */
-        todo!()
+        Ok(order)
     }
 
     async fn open_orders(&self, config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
-        /*
-        let server = &self.server_config;
-
-        let query_string = format!(
-            "category={}&symbol={}&limit=50",
-            config.trade_category, config.trade_symbol
-        );
-
-        let path = "/v5/order/realtime";
-
-        let result = Self::get_sign(&server, path, &query_string)
-            .await
-            .with_context(|| {
-                format!(
-                    "open_orders: server={:?} / path={:?} / query_string={:?}",
-                    server, path, query_string
-                )
-            })?;
-
-        log::debug!("result.body={:?}", result.body);
-        if result.body.is_null() {
-            return Ok(vec![]);
-        }
-
-        let response = serde_json::from_value::<BybitMultiOrderStatus>(result.body)
-            .with_context(|| format!("order status parse error"))?;
-
-        let mut orders: Vec<Order> = response.into();
+        let client = BitbankPrivateClient::new(&self.server_config);
 
+        let mut orders = client.get_active_orders(&config.trade_symbol).await?;
         for o in orders.iter_mut() {
             o.update_balance(config);
         }
 
         Ok(orders)
-        */
-        todo!()
     }
 
     async fn get_account(&self) -> anyhow::Result<AccountCoins> {
-        /* 
-        let server = &self.server_config;
-
-        let path = "/v5/account/wallet-balance";
-        // TODO: implement otherthn accountType=UNIFIED(e.g.inverse)
-        let query_string = format!("accountType=UNIFIED");
-        //let query_string = format!("accountType=UNIFIED");
-
-        let response = Self::get_sign(&server, path, &query_string)
-            .await
-            .with_context(|| {
-                format!(
-                    "get_account error: {}/{}/{}",
-                    &server.get_rest_server(),
-                    path,
-                    &query_string
-                )
-            })?;
+        let client = BitbankPrivateClient::new(&self.server_config);
 
-        ensure!(
-            response.is_success(),
-            format!(
-                "return_code = {}, msg={}",
-                response.is_success(),
-                response.return_message
-            )
-        );
-
-        let account_status = serde_json::from_value::<BybitAccountResponse>(response.body)?;
-        let coins: AccountCoins = account_status.into();
-
-        Ok(coins)
-        */
-        todo!()
+        client.get_assets().await
     }
 
     fn history_web_url(&self, config: &MarketConfig, date: MicroSec) -> String {
@@ -467,13 +334,14 @@ impl RestApi for BitbankRestApi {
     }
 
 
-    async fn web_archive_to_parquet<F>(
+    /// アーカイブはcsvではなくtransactionsのjsonなので、TradeBufferでDataFrameにする。
+    /// parquetへの保存はRestApi::web_archive_to_parquet(このDataFrameを保存する)に任せる。
+    async fn web_archive_to_df<F>(
         &self,
         config: &MarketConfig,
-        parquet_file: &PathBuf,
         date: MicroSec,
         f: F,
-    ) -> anyhow::Result<i64>
+    ) -> anyhow::Result<DataFrame>
     where
         F: FnMut(i64, i64),
     {
@@ -500,12 +368,10 @@ impl RestApi for BitbankRestApi {
                     buffer.push_trade(&t.into())
                 }
 
-                let mut df = buffer.to_dataframe();
+                let df = buffer.to_dataframe();
+                log::debug!("archive df shape={:?}", df.shape());
 
-                let rec = df_to_parquet(&mut df, &parquet_file)?;
-                log::debug!("done {} [rec]", rec);
-    
-                return Ok(rec)
+                return Ok(df)
             }
         }
 
@@ -676,7 +542,7 @@ impl BitbankApiClient {
         let mut headers = HeaderMap::new();
         headers.insert(USER_AGENT, HeaderValue::from_static("Bitbank Rust API Client"));
 
-        let client = reqwest::Client::builder()
+        let client = http_client_builder()
             .default_headers(headers)
             .build()
             .unwrap();
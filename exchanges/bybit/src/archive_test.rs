@@ -179,7 +179,7 @@ mod archive_test {
         let api = BybitRestApi::new(&server_config);
 
 
-        archive.download(&api, 4, false, true).await?;
+        archive.download(&api, 4, false, true, None).await?;
         log::debug!(
             "start={:?}({:?})",
             archive.start_time(),
@@ -193,7 +193,7 @@ mod archive_test {
 
         log::debug!("download with cache");
 
-        archive.download(&api, 7, false, true).await?;
+        archive.download(&api, 7, false, true, None).await?;
         log::debug!(
             "start={:?}({:?})",
             archive.start_time(),
@@ -216,7 +216,7 @@ mod archive_test {
         let server_config = BybitServerConfig::new(true);
         let api = BybitRestApi::new(&server_config);
 
-        archive.download(&api, 2, false, true).await?;
+        archive.download(&api, 2, false, true, None).await?;
 
         log::debug!(
             "start={:?}({:?})",
@@ -277,5 +277,4 @@ mod archive_test {
 
         Ok(())
     }
-
 }
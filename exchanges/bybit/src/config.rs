@@ -5,7 +5,7 @@ use pyo3::prelude::*;
 use rust_decimal_macros::dec;
 use serde_derive::{Deserialize, Serialize};
 
-use rbot_lib::common::{FeeType, MarketConfig, ExchangeConfig};
+use rbot_lib::common::{FeeType, MarketConfig, ExchangeConfig, OrderSide};
 
 use crate::BYBIT;
 
@@ -48,9 +48,76 @@ impl BybitServerConfig {
 }
 
 
+/// linear perpetualのポジションモード
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PositionMode {
+    OneWay,
+    HedgeBothSide,
+}
+
+impl Default for PositionMode {
+    fn default() -> Self {
+        PositionMode::OneWay
+    }
+}
+
+impl PositionMode {
+    /// `/v5/position/switch-mode`のmode値(0: one-way, 3: both side)
+    pub fn to_bybit_mode(&self) -> i64 {
+        match self {
+            PositionMode::OneWay => 0,
+            PositionMode::HedgeBothSide => 3,
+        }
+    }
+
+    /// 注文時のpositionIdx. 明示的に指定された場合はそれを優先する。
+    pub fn position_idx(&self, side: OrderSide, position_idx: Option<u8>) -> Option<u8> {
+        if position_idx.is_some() {
+            return position_idx;
+        }
+
+        match self {
+            PositionMode::OneWay => None,
+            PositionMode::HedgeBothSide => match side {
+                OrderSide::Buy => Some(1),
+                OrderSide::Sell => Some(2),
+                OrderSide::Unknown => None,
+            },
+        }
+    }
+}
+
+/// spotのマージン取引設定。指定時はspotの注文にisLeverage=1を付ける。
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpotMarginConfig {
+    #[pyo3(get, set)]
+    pub leverage: u8,
+}
+
+#[pymethods]
+impl SpotMarginConfig {
+    #[new]
+    pub fn new(leverage: u8) -> Self {
+        SpotMarginConfig { leverage }
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("SpotMarginConfig(leverage={})", self.leverage)
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 #[pyclass]
-pub struct BybitConfig {}
+pub struct BybitConfig {
+    /// 口座のlinearのポジションモード。取引所側の設定は変えないので、切り替えるときはBybit.set_position_modeを使う。
+    #[pyo3(get, set)]
+    pub position_mode: PositionMode,
+    /// spotのマージン取引(Noneは現物のみ)。Bybit.open_marketで開いたマーケットの注文に使う。
+    #[pyo3(get, set)]
+    pub spot_margin: Option<SpotMarginConfig>,
+}
 
 // 取引ペアーの制限については以下を参照
 // https://www.bybit.com/ja-JP/announcement-info/transact-parameters/
@@ -59,7 +126,10 @@ pub struct BybitConfig {}
 impl BybitConfig {
     #[new]
     pub fn new() -> Self {
-        return BybitConfig {};
+        return BybitConfig {
+            position_mode: PositionMode::default(),
+            spot_margin: None,
+        };
     }
 
 
@@ -8,5 +8,103 @@ mod archive_test;
 
 pub use config::*;
 pub use market::*;
+pub use message::FeeTier;
+
+use anyhow::anyhow;
+use rbot_lib::common::{Channel, ChannelType, MarketConfig};
 
 pub const BYBIT_BOARD_DEPTH: u32 = 200;
+/// orderbook.{depth}.{symbol}で購読できるdepth
+pub const BYBIT_SPOT_BOARD_DEPTHS: [u32; 3] = [1, 50, 200];
+pub const BYBIT_DERIVATIVE_BOARD_DEPTHS: [u32; 4] = [1, 50, 200, 500];
+pub const BYBIT_CHECKSUM_DEPTH: usize = 25;
+
+pub fn bybit_board_depth(config: &MarketConfig) -> anyhow::Result<u32> {
+    if config.trade_category == "spot" {
+        config.resolve_board_depth(&BYBIT_SPOT_BOARD_DEPTHS, BYBIT_BOARD_DEPTH)
+    } else {
+        config.resolve_board_depth(&BYBIT_DERIVATIVE_BOARD_DEPTHS, BYBIT_BOARD_DEPTH)
+    }
+}
+
+/// MarketConfig.channelsからtopic名を作る。未設定の場合はpublicTrade + orderbook + tickers。
+/// Depthのparamは板の深さ(空はboard_depth)、BookTickerはtickersを購読する。
+/// klineはメッセージを解釈できないため購読しない(エラーにする)。
+pub fn bybit_ws_channels(config: &MarketConfig) -> anyhow::Result<Vec<String>> {
+    let channels = if config.channels.is_empty() {
+        vec![Channel::trade(), Channel::depth(""), Channel::book_ticker()]
+    } else {
+        config.channels.clone()
+    };
+
+    let symbol = &config.trade_symbol;
+
+    channels
+        .iter()
+        .map(|channel| match channel.channel_type {
+            ChannelType::Trade => Ok(format!("publicTrade.{}", symbol)),
+            ChannelType::Depth => {
+                let depth = if channel.param.is_empty() {
+                    bybit_board_depth(config)?
+                } else {
+                    let supported: &[u32] = if config.trade_category == "spot" {
+                        &BYBIT_SPOT_BOARD_DEPTHS
+                    } else {
+                        &BYBIT_DERIVATIVE_BOARD_DEPTHS
+                    };
+
+                    match channel.param.parse::<u32>() {
+                        Ok(depth) if supported.contains(&depth) => depth,
+                        _ => {
+                            return Err(anyhow!(
+                                "unsupported depth {} for bybit {} (supported={:?})",
+                                channel.param,
+                                config.trade_category,
+                                supported
+                            ))
+                        }
+                    }
+                };
+
+                Ok(format!("orderbook.{}.{}", depth, symbol))
+            }
+            ChannelType::BookTicker => Ok(format!("tickers.{}", symbol)),
+            ChannelType::Kline => Err(anyhow!(
+                "unsupported channel {:?} for bybit (supported=Trade, Depth, BookTicker)",
+                channel.channel_type
+            )),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod bybit_channel_test {
+    use super::*;
+
+    #[test]
+    fn test_bybit_ws_channels() -> anyhow::Result<()> {
+        let mut config = MarketConfig::default();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = "ETHUSDT".to_string();
+
+        assert_eq!(
+            bybit_ws_channels(&config)?,
+            vec!["publicTrade.ETHUSDT", "orderbook.200.ETHUSDT", "tickers.ETHUSDT"]
+        );
+
+        config.channels = vec![Channel::trade(), Channel::depth("500")];
+        assert_eq!(bybit_ws_channels(&config)?, vec!["publicTrade.ETHUSDT", "orderbook.500.ETHUSDT"]);
+
+        // 解釈できないtopicは購読しない
+        config.channels = vec![Channel::trade(), Channel::kline("15")];
+        assert!(bybit_ws_channels(&config).is_err());
+
+        config.channels = vec![Channel::trade(), Channel::depth("500")];
+
+        // spotは500を購読できない
+        config.trade_category = "spot".to_string();
+        assert!(bybit_ws_channels(&config).is_err());
+
+        Ok(())
+    }
+}
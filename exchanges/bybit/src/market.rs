@@ -12,25 +12,33 @@ use futures::StreamExt;
 use polars::export::num::FromPrimitive;
 use pyo3::ffi::getter;
 
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RwLock};
 
 use std::thread::sleep;
 use std::time::Duration;
 
 use rbot_lib::common::{
-    convert_klines_to_trades, flush_log, time_string, to_naive_datetime, AccountCoins, AccountPair,
-    BoardItem, BoardTransfer, LogStatus, MarketConfig, MarketMessage, MarketStream, MicroSec,
-    MultiMarketMessage, Order, OrderBook, OrderBookRaw, OrderSide, OrderStatus, OrderType,
-    ExchangeConfig, Trade, DAYS, FLOOR_DAY, HHMM, MARKET_HUB, NOW, SEC,
+    convert_klines_to_trades, extract_time, flush_log, time_string, to_naive_datetime, AccountCoins, AccountPair,
+    Channel, Coin, to_py_err,
+    BoardEventStream, BoardItem, BoardTransfer, LogStatus, MarketConfig, MarketMessage, MarketStream, MicroSec,
+    MultiMarketMessage, Order, OrderBook, OrderBookRaw, OrderBookResync, OrderSide, OrderStatus, OrderType,
+    ExchangeConfig, KlineInterval, PyProgressCallback, TickerInfo, TimeInForce, Trade, DAYS, FLOOR_DAY, HHMM, MARKET_HUB, NOW, SEC,
 };
 
-use rbot_lib::db::{db_full_path, TradeArchive, TradeDataFrame, TradeDb, KEY};
-use rbot_lib::net::{latest_archive_date, BroadcastMessage, RestApi, RestPage, UdpSender, WebSocketClient};
+use rbot_lib::db::{
+    convert_timems_to_datetime, db_full_path, BalanceDb, BalanceRecorder, BALANCE_SNAPSHOT_INTERVAL_SEC, CacheStats, ColumnStyle, CsvSchema, Ohlcvb, RangeStats, SpreadLogger, TradeArchive, TradeDataFrame, TradeSummary, TradeDb, KEY,
+    SPREAD_LOG_DEFAULT_INTERVAL_MS, TimeFormat, GAP_REPORT_ALLOW_SIZE,
+};
+use rbot_lib::net::{
+    download_to_dataframe, latest_archive_date, make_py_raw_message_hook, BroadcastMessage, RawMessageHook, RestApi, WsStateHandle,
+    RestPage, UdpSender, WebSocketClient, stop_stream_task, stream_stop_signal, STREAM_STOP_TIMEOUT_SEC,
+};
 
-use rbot_market::{extract_or_generate_config, MarketImpl};
-use rbot_market::{MarketInterface, OrderInterface, OrderInterfaceImpl};
+use rbot_market::{extract_or_generate_config, HealthStatus, MarketImpl, OhlcvStream, TradeStream, TRADE_DEDUP_WINDOW};
+use rbot_market::{measure_clock_skew, MarketInterface, OrderInterface, OrderInterfaceImpl};
 
-use crate::{market, BYBIT_BOARD_DEPTH};
+use crate::{bybit_board_depth, bybit_ws_channels, market, BYBIT_CHECKSUM_DEPTH};
 use crate::message::BybitUserWsMessage;
 
 use crate::rest::BybitRestApi;
@@ -38,12 +46,16 @@ use crate::ws::{BybitPrivateWsClient, BybitPublicWsClient, BybitWsOpMessage};
 
 use pyo3::prelude::*;
 use pyo3_polars::PyDataFrame;
+use pyo3_polars::PyLazyFrame;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
 use super::config::BybitServerConfig;
+use super::config::PositionMode;
+use super::config::SpotMarginConfig;
 
 use super::message::BybitOrderStatus;
+use super::message::{closed_pnl_to_df, FeeTier};
 use super::message::{BybitAccountInformation, BybitPublicWsMessage};
 
 use anyhow::Context;
@@ -54,6 +66,9 @@ use anyhow::anyhow;
 use tokio::task::JoinHandle;
 
 pub const BYBIT: &str = "BYBIT";
+/// spotマージン取引で指定できるレバレッジの範囲
+pub const SPOT_MARGIN_MIN_LEVERAGE: u8 = 2;
+pub const SPOT_MARGIN_MAX_LEVERAGE: u8 = 10;
 
 #[pyclass]
 pub struct Bybit {
@@ -61,24 +76,48 @@ pub struct Bybit {
     enable_order: bool,
     server_config: ExchangeConfig,
     user_handler: Option<JoinHandle<()>>,
+    user_stop: Option<tokio::sync::watch::Sender<bool>>,
     api: BybitRestApi,
+    config: BybitConfig,
+    raw_message_hook: Option<RawMessageHook>,
+    /// "category/symbol"ごとの手数料
+    fee_tiers: HashMap<String, FeeTier>,
 }
 
 #[pymethods]
 impl Bybit {
     #[new]
-    #[pyo3(signature = (production=false))]
-    pub fn new(production: bool) -> Self {
+    #[pyo3(signature = (production=false, config=None))]
+    pub fn new(production: bool, config: Option<BybitConfig>) -> Self {
         let server_config = BybitServerConfig::new(production);
-        let api = BybitRestApi::new(&server_config);
+        let config = config.unwrap_or_else(BybitConfig::new);
+        let mut api = BybitRestApi::new(&server_config);
+        api.set_position_mode(config.position_mode);
+        api.set_spot_margin(config.spot_margin);
 
-        return Bybit {
+        let mut bybit = Bybit {
             production: production,
             enable_order: false,
             server_config: server_config,
             user_handler: None,
+            user_stop: None,
             api: api,
+            config: config,
+            raw_message_hook: None,
+            fee_tiers: HashMap::new(),
         };
+
+        if bybit.has_credentials() {
+            if let Err(e) = BLOCK_ON(async { measure_clock_skew(&bybit.api).await }) {
+                log::warn!("failed to get server time: {:?}", e);
+            }
+
+            if let Err(e) = bybit.refresh_fee_tier() {
+                log::warn!("failed to get fee tier, use default fee: {:?}", e);
+            }
+        }
+
+        bybit
     }
 
     #[getter]
@@ -89,7 +128,48 @@ impl Bybit {
     pub fn open_market(&self, config: &PyAny) -> anyhow::Result<BybitMarket> {
         let config = extract_or_generate_config(&self.server_config.get_exchange_name(), config)?;
 
-        return Ok(BybitMarket::new(&self.server_config, &config));
+        Ok(self.open_market_config(config))
+    }
+
+    #[getter]
+    pub fn get_config(&self) -> BybitConfig {
+        self.config.clone()
+    }
+
+    /// 現在のVIPレベルでの手数料。取得済みでなければAPIに問い合わせる。
+    pub fn get_fee_tier(&self, market_config: &MarketConfig) -> anyhow::Result<FeeTier> {
+        if let Some(fee) = self.fee_tiers.get(&Self::fee_tier_key(market_config)) {
+            return Ok(fee.clone());
+        }
+
+        let fees = BLOCK_ON(async {
+            self.api
+                .fee_rate(&market_config.trade_category, &market_config.trade_symbol)
+                .await
+        })?;
+
+        fees.into_iter()
+            .find(|f| f.symbol == market_config.trade_symbol)
+            .ok_or_else(|| anyhow!("fee tier not found: {}", market_config.trade_symbol))
+    }
+
+    /// VIPレベルが変わったときに呼ぶ。linear/inverseの全銘柄の手数料を取り直す。
+    /// spotは全銘柄の取得ができないため、get_fee_tierで個別に問い合わせる。
+    pub fn refresh_fee_tier(&mut self) -> anyhow::Result<()> {
+        let mut fee_tiers: HashMap<String, FeeTier> = HashMap::new();
+
+        for category in ["linear", "inverse"] {
+            let fees = BLOCK_ON(async { self.api.fee_rate(category, "").await })?;
+
+            for fee in fees {
+                fee_tiers.insert(format!("{}/{}", category, fee.symbol), fee);
+            }
+        }
+
+        log::debug!("fee tier refreshed: {} symbols", fee_tiers.len());
+        self.fee_tiers = fee_tiers;
+
+        Ok(())
     }
 
     //--- OrderInterfaceImpl ----
@@ -103,6 +183,8 @@ impl Bybit {
         self.get_enable_order_feature()
     }
 
+    /// dry_run=Trueの場合は送信せず、検証済みのOrder(status=ServerWait)を返す
+    #[pyo3(signature = (market_config, side, price, size, client_order_id=None, position_idx=None, time_in_force=None, reduce_only=false, dry_run=false))]
     pub fn limit_order(
         &self,
         market_config: &MarketConfig,
@@ -110,46 +192,262 @@ impl Bybit {
         price: Decimal,
         size: Decimal,
         client_order_id: Option<&str>,
-    ) -> anyhow::Result<Vec<Order>> {
-        BLOCK_ON(async {
-            OrderInterfaceImpl::limit_order(self, market_config, side, price, size, client_order_id)
+        position_idx: Option<u8>,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
+        dry_run: bool,
+    ) -> PyResult<Vec<Order>> {
+        if dry_run {
+            let mut order = BLOCK_ON(async {
+                OrderInterfaceImpl::validate_order(
+                    self,
+                    market_config,
+                    side,
+                    price,
+                    size,
+                    OrderType::Limit,
+                    client_order_id,
+                    time_in_force.unwrap_or_default(),
+                    reduce_only,
+                )
                 .await
-        })
+            }).map_err(to_py_err)?;
+            order.position_idx = position_idx.unwrap_or(0) as i64;
+
+            return Ok(vec![order]);
+        }
+
+        BLOCK_ON(async {
+            OrderInterfaceImpl::limit_order_with_position_idx(
+                self,
+                market_config,
+                side,
+                price,
+                size,
+                client_order_id,
+                position_idx,
+                time_in_force,
+                reduce_only,
+            )
+            .await
+        }).map_err(to_py_err)
     }
 
+    /// dry_run=Trueの場合は送信せず、検証済みのOrder(status=ServerWait)を返す
+    #[pyo3(signature = (market_config, side, size, client_order_id=None, position_idx=None, reduce_only=false, dry_run=false))]
     pub fn market_order(
         &self,
         market_config: &MarketConfig,
         side: &str,
         size: Decimal,
         client_order_id: Option<&str>,
-    ) -> anyhow::Result<Vec<Order>> {
+        position_idx: Option<u8>,
+        reduce_only: bool,
+        dry_run: bool,
+    ) -> PyResult<Vec<Order>> {
+        if dry_run {
+            let mut order = BLOCK_ON(async {
+                OrderInterfaceImpl::validate_order(
+                    self,
+                    market_config,
+                    side,
+                    dec![0.0],
+                    size,
+                    OrderType::Market,
+                    client_order_id,
+                    TimeInForce::GTC,
+                    reduce_only,
+                )
+                .await
+            }).map_err(to_py_err)?;
+            order.position_idx = position_idx.unwrap_or(0) as i64;
+
+            return Ok(vec![order]);
+        }
+
         BLOCK_ON(async {
-            OrderInterfaceImpl::market_order(self, market_config, side, size, client_order_id).await
-        })
+            OrderInterfaceImpl::market_order_with_position_idx(
+                self,
+                market_config,
+                side,
+                size,
+                client_order_id,
+                position_idx,
+                reduce_only,
+            )
+            .await
+        }).map_err(to_py_err)
+    }
+
+    #[getter]
+    pub fn get_position_mode(&self) -> PositionMode {
+        self.api.get_position_mode()
+    }
+
+    /// switch position mode(one-way / hedge) of linear contracts settled in `coin`.
+    #[pyo3(signature = (mode, coin="USDT"))]
+    pub fn set_position_mode(&mut self, mode: PositionMode, coin: &str) -> anyhow::Result<()> {
+        BLOCK_ON(async { self.api.switch_position_mode(coin, mode).await })?;
+        self.config.position_mode = mode;
+        self.api.set_position_mode(mode);
+
+        Ok(())
+    }
+
+    #[getter]
+    pub fn get_spot_margin(&self) -> Option<SpotMarginConfig> {
+        self.config.spot_margin
+    }
+
+    /// spotのマージン取引を有効にする。以後spotの注文はisLeverage=1で送信される。
+    /// 有効にする前に開いたマーケットには反映されないので、open_marketで開き直すこと。
+    pub fn enable_spot_margin(&mut self, leverage: u8) -> PyResult<()> {
+        if leverage < SPOT_MARGIN_MIN_LEVERAGE || SPOT_MARGIN_MAX_LEVERAGE < leverage {
+            return Err(to_py_err(anyhow!(
+                "spot margin leverage must be {}..={} (got {})",
+                SPOT_MARGIN_MIN_LEVERAGE,
+                SPOT_MARGIN_MAX_LEVERAGE,
+                leverage
+            )));
+        }
+
+        let spot_margin = Some(SpotMarginConfig::new(leverage));
+        BLOCK_ON(async { self.api.switch_spot_margin_mode(spot_margin).await }).map_err(to_py_err)?;
+        self.set_spot_margin_config(spot_margin);
+
+        Ok(())
+    }
+
+    pub fn disable_spot_margin(&mut self) -> PyResult<()> {
+        BLOCK_ON(async { self.api.switch_spot_margin_mode(None).await }).map_err(to_py_err)?;
+        self.set_spot_margin_config(None);
+
+        Ok(())
     }
 
     pub fn cancel_order(
         &self,
         market_config: &MarketConfig,
         order_id: &str,
-    ) -> anyhow::Result<Order> {
-        BLOCK_ON(async { OrderInterfaceImpl::cancel_order(self, market_config, order_id).await })
+    ) -> PyResult<Order> {
+        BLOCK_ON(async { OrderInterfaceImpl::cancel_order(self, market_config, order_id).await }).map_err(to_py_err)
+    }
+
+    pub fn get_open_orders(&self, market_config: &MarketConfig) -> PyResult<Vec<Order>> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_open_orders(self, market_config).await }).map_err(to_py_err)
+    }
+
+    pub fn get_order_status(&self, market_config: &MarketConfig, order_id: &str) -> PyResult<Order> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_order_status(self, market_config, order_id).await }).map_err(to_py_err)
+    }
+
+    /// 未約定の注文をページングしてすべて返す(page_limitは1ページの件数、最大50)
+    #[pyo3(signature = (market_config, page_limit=50))]
+    pub fn get_all_open_orders(
+        &self,
+        market_config: &MarketConfig,
+        page_limit: usize,
+    ) -> anyhow::Result<Vec<Order>> {
+        BLOCK_ON(async { self.api.all_open_orders(market_config, page_limit).await })
+    }
+
+    /// 確定損益の履歴をDataFrameで返す(end_time=0は現在まで)
+    #[pyo3(signature = (symbol, start_time, end_time=0, category="linear"))]
+    pub fn closed_pnl(
+        &self,
+        symbol: &str,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        category: &str,
+    ) -> anyhow::Result<PyDataFrame> {
+        let pnl = BLOCK_ON(async {
+            self.api
+                .closed_pnl(category, symbol, start_time, end_time)
+                .await
+        })?;
+
+        Ok(PyDataFrame(closed_pnl_to_df(&pnl)?))
     }
 
-    pub fn get_open_orders(&self, market_config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
-        BLOCK_ON(async { OrderInterfaceImpl::get_open_orders(self, market_config).await })
+    /// 指値注文をキャンセルせずに変更する（板の順番を保つ）
+    #[pyo3(signature = (market_config, order_id, new_price, new_qty=None))]
+    pub fn amend_order(
+        &self,
+        market_config: &MarketConfig,
+        order_id: &str,
+        new_price: Decimal,
+        new_qty: Option<Decimal>,
+    ) -> PyResult<Order> {
+        BLOCK_ON(async {
+            self.async_amend_order(market_config, order_id, new_price, new_qty)
+                .await
+        }).map_err(to_py_err)
+    }
+
+    /// client_order_idが`agent_id`で始まる既存のbid/askを変更し、無ければ新規に発注する。
+    /// 他のagentの注文には触れない。戻り値は(bid, ask)
+    pub fn replace_quote(
+        &self,
+        market_config: &MarketConfig,
+        agent_id: &str,
+        bid_price: Decimal,
+        ask_price: Decimal,
+        size: Decimal,
+    ) -> anyhow::Result<(Order, Order)> {
+        BLOCK_ON(async {
+            self.async_replace_quote(market_config, agent_id, bid_price, ask_price, size)
+                .await
+        })
     }
 
     #[getter]
-    pub fn get_account(&self) -> anyhow::Result<AccountCoins> {
-        BLOCK_ON(async { OrderInterfaceImpl::get_account(self).await })
+    pub fn get_account(&self) -> PyResult<AccountCoins> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_account(self).await }).map_err(to_py_err)
+    }
+
+    pub fn balances(&self) -> anyhow::Result<Vec<Coin>> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_balances(self).await })
+    }
+
+    /// user streamで受け取った残高の履歴(timestamp, coin, equity, free, locked)
+    #[pyo3(signature = (start_time=0, end_time=0))]
+    pub fn balance_history_df(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<PyDataFrame> {
+        let db = BalanceDb::open(BYBIT, self.server_config.is_production())?;
+
+        Ok(PyDataFrame(db.select_df(start_time, end_time)?))
+    }
+
+    /// アーカイブを1日ずつダウンロードしてDataFrameとして返す(DBには保存しない)
+    #[pyo3(signature = (config, start_time, end_time=0))]
+    pub fn download_to_dataframe(&self, config: &PyAny, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<PyDataFrame> {
+        let config = extract_or_generate_config(&self.server_config.get_exchange_name(), config)?;
+
+        let mut df = BLOCK_ON(async {
+            download_to_dataframe(&self.api, &config, start_time, end_time).await
+        })?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
     }
 
     pub fn open_user_stream(&mut self) -> anyhow::Result<()> {
         BLOCK_ON(async { OrderInterfaceImpl::async_start_user_stream(self).await })
     }
 
+    /// user streamを停止し、タスクの終了を待つ
+    pub fn stop_user_stream(&mut self) {
+        let stop = self.user_stop.take();
+        let handle = self.user_handler.take();
+
+        BLOCK_ON(async { stop_stream_task(stop, handle, STREAM_STOP_TIMEOUT_SEC).await })
+    }
+
+    /// set callback(str) for raw user stream frames. must be set before open_user_stream.
+    #[pyo3(signature = (handler=None))]
+    pub fn on_raw_message(&mut self, handler: Option<PyObject>) {
+        self.raw_message_hook = handler.map(make_py_raw_message_hook);
+    }
+
     pub fn __str__(&self) -> String {
         format!(
             "{{production: {}, enable_order: {}, server_config: {:?} }}",
@@ -158,6 +456,171 @@ impl Bybit {
     }
 }
 
+impl Bybit {
+    /// 手数料とBybitConfigを反映したマーケットを開く
+    fn open_market_config(&self, mut config: MarketConfig) -> BybitMarket {
+        if let Some(fee) = self.fee_tiers.get(&Self::fee_tier_key(&config)) {
+            fee.apply(&mut config);
+        }
+
+        let mut market = BybitMarket::new(&self.server_config, &config);
+        market.api.set_position_mode(self.config.position_mode);
+        market.api.set_spot_margin(self.config.spot_margin);
+
+        market
+    }
+
+    fn set_spot_margin_config(&mut self, spot_margin: Option<SpotMarginConfig>) {
+        self.config.spot_margin = spot_margin;
+        self.api.set_spot_margin(spot_margin);
+    }
+
+    fn has_credentials(&self) -> bool {
+        self.server_config.get_api_key().extract() != ""
+            && self.server_config.get_api_secret().extract() != ""
+    }
+
+    fn fee_tier_key(config: &MarketConfig) -> String {
+        format!("{}/{}", config.trade_category, config.trade_symbol)
+    }
+
+    async fn async_amend_order(
+        &self,
+        market_config: &MarketConfig,
+        order_id: &str,
+        price: Decimal,
+        qty: Option<Decimal>,
+    ) -> anyhow::Result<Order> {
+        let open_orders = OrderInterfaceImpl::get_open_orders(self, market_config).await?;
+        let current = open_orders
+            .iter()
+            .find(|o| o.order_id == order_id)
+            .ok_or_else(|| anyhow!("amend_order: open order not found: {}", order_id))?;
+
+        self.async_amend_current(market_config, current, price, qty)
+            .await
+    }
+
+    /// 有効な注文`current`を変更する。side等は`current`から引き継ぐ。
+    async fn async_amend_current(
+        &self,
+        market_config: &MarketConfig,
+        current: &Order,
+        price: Decimal,
+        qty: Option<Decimal>,
+    ) -> anyhow::Result<Order> {
+        if !self.get_enable_order_feature() {
+            log::error!("Order feature is disabled.");
+            return Err(anyhow!("Order feature is disabled, you can enable exchange property 'enable_order_with_my_own_risk' to True"));
+        }
+
+        let price = market_config.round_price(price)?;
+        let qty = match qty {
+            Some(qty) => Some(market_config.round_size(qty)?),
+            None => None,
+        };
+
+        self.api
+            .amend_order(market_config, current, Some(price), qty)
+            .await
+    }
+
+    async fn async_quote(
+        &self,
+        market_config: &MarketConfig,
+        agent_id: &str,
+        side: OrderSide,
+        price: Decimal,
+        size: Decimal,
+        current: Option<&Order>,
+    ) -> anyhow::Result<Order> {
+        match current {
+            Some(current) => {
+                self.async_amend_current(market_config, current, price, Some(size))
+                    .await
+            }
+            None => {
+                let client_order_id = quote_order_id(agent_id, side, NOW())?;
+
+                let orders = OrderInterfaceImpl::limit_order(
+                    self,
+                    market_config,
+                    &side.to_string(),
+                    price,
+                    size,
+                    Some(&client_order_id),
+                    None,
+                    false,
+                )
+                .await?;
+
+                orders
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("replace_quote: no order returned for {:?}", side))
+            }
+        }
+    }
+
+    /// bid/askを並行して更新する
+    async fn async_replace_quote(
+        &self,
+        market_config: &MarketConfig,
+        agent_id: &str,
+        bid_price: Decimal,
+        ask_price: Decimal,
+        size: Decimal,
+    ) -> anyhow::Result<(Order, Order)> {
+        let open_orders = OrderInterfaceImpl::get_open_orders(self, market_config).await?;
+
+        let bid = self.async_quote(
+            market_config,
+            agent_id,
+            OrderSide::Buy,
+            bid_price,
+            size,
+            find_my_quote(&open_orders, OrderSide::Buy, agent_id),
+        );
+        let ask = self.async_quote(
+            market_config,
+            agent_id,
+            OrderSide::Sell,
+            ask_price,
+            size,
+            find_my_quote(&open_orders, OrderSide::Sell, agent_id),
+        );
+
+        tokio::try_join!(bid, ask)
+    }
+}
+
+/// Bybitのorder_link_idの最大長
+const MAX_ORDER_LINK_ID_LEN: usize = 36;
+
+/// `agent_id`の注文のうち、`side`の指値注文(quote)を探す
+fn find_my_quote<'a>(orders: &'a [Order], side: OrderSide, agent_id: &str) -> Option<&'a Order> {
+    orders
+        .iter()
+        .find(|o| o.order_side == side && o.order_type == OrderType::Limit && o.is_my_order(agent_id))
+}
+
+/// replace_quoteで新規発注するときのclient_order_id。`agent_id`で始まるのでis_my_orderで見分けられる。
+fn quote_order_id(agent_id: &str, side: OrderSide, now: MicroSec) -> anyhow::Result<String> {
+    let side = if side == OrderSide::Buy { "b" } else { "s" };
+    let order_id = format!("{}-q{}{}", agent_id, side, now);
+
+    if order_id.len() > MAX_ORDER_LINK_ID_LEN {
+        return Err(anyhow!(
+            "order id is too long ({} > {}): {}",
+            order_id.len(),
+            MAX_ORDER_LINK_ID_LEN,
+            order_id
+        ));
+    }
+
+    Ok(order_id)
+}
+
 impl OrderInterfaceImpl<BybitRestApi> for Bybit {
     fn get_restapi(&self) -> &BybitRestApi {
         &self.api
@@ -174,15 +637,53 @@ impl OrderInterfaceImpl<BybitRestApi> for Bybit {
     async fn async_start_user_stream(&mut self) -> anyhow::Result<()> {
         let exchange_name = BYBIT.to_string();
         let server_config = self.server_config.clone();
+        let raw_message_hook = self.raw_message_hook.clone();
+
+        // wallet更新と定期的な残高をbalance_historyへ記録する(開けなくてもstreamは続ける)
+        let balance_recorder = BalanceRecorder::open(&exchange_name, server_config.is_production())
+            .map_err(|e| log::error!("balance db open error {:?}", e))
+            .ok();
+        let snapshot_api = BybitRestApi::new(&server_config);
+
+        let (stop_tx, mut stop_rx) = stream_stop_signal();
+        self.user_stop = Some(stop_tx);
 
         self.user_handler = Some(tokio::task::spawn(async move {
             let mut ws = BybitPrivateWsClient::new(&server_config).await;
+            ws.set_raw_message_hook(raw_message_hook);
             ws.connect().await;
 
             let mut market_channel = MARKET_HUB.open_channel();
             let mut ws_stream = Box::pin(ws.open_stream().await);
 
-            while let Some(message) = ws_stream.next().await {
+            let mut balance_snapshot =
+                tokio::time::interval(Duration::from_secs(BALANCE_SNAPSHOT_INTERVAL_SEC));
+
+            loop {
+                let message = tokio::select! {
+                    _ = stop_rx.changed() => {
+                        log::info!("user stream stopped");
+                        break;
+                    }
+                    _ = balance_snapshot.tick(), if balance_recorder.is_some() => {
+                        match snapshot_api.get_account().await {
+                            Ok(coins) => {
+                                if let Err(e) = balance_recorder.as_ref().unwrap().record(NOW(), coins).await {
+                                    log::error!("balance history insert error {:?}", e);
+                                }
+                            }
+                            Err(e) => log::warn!("balance snapshot error {:?}", e),
+                        }
+                        continue;
+                    }
+                    message = ws_stream.next() => message,
+                };
+
+                let message = match message {
+                    Some(m) => m,
+                    None => break,
+                };
+
                 if message.is_err() {
                     log::error!("Error in ws_stream.recv: {:?}", message);
                     continue;
@@ -202,6 +703,12 @@ impl OrderInterfaceImpl<BybitRestApi> for Bybit {
                         }
                     }
                     MultiMarketMessage::Account(account) => {
+                        if let Some(recorder) = balance_recorder.as_ref() {
+                            if let Err(e) = recorder.record(NOW(), account.clone()).await {
+                                log::error!("balance history insert error {:?}", e);
+                            }
+                        }
+
                         market_channel.send(BroadcastMessage {
                             exchange: exchange_name.clone(),
                             category: "".to_string(),
@@ -209,6 +716,26 @@ impl OrderInterfaceImpl<BybitRestApi> for Bybit {
                             msg: MarketMessage::Account(account.clone()),
                         });
                     }
+                    MultiMarketMessage::Fill(fills) => {
+                        for f in fills {
+                            market_channel.send(BroadcastMessage {
+                                exchange: exchange_name.clone(),
+                                category: "".to_string(),
+                                symbol: f.symbol.clone(),
+                                msg: MarketMessage::Fill(f),
+                            });
+                        }
+                    }
+                    MultiMarketMessage::PositionUpdate(positions) => {
+                        for p in positions {
+                            market_channel.send(BroadcastMessage {
+                                exchange: exchange_name.clone(),
+                                category: p.category.clone(),
+                                symbol: p.symbol.clone(),
+                                msg: MarketMessage::PositionUpdate(p),
+                            });
+                        }
+                    }
                     _ => {
                         log::info!("User stream message: {:?}", message);
                     }
@@ -227,7 +754,12 @@ pub struct BybitMarket {
     pub config: MarketConfig,
     pub db: Arc<Mutex<TradeDataFrame>>,
     pub board: Arc<RwLock<OrderBook>>,
+    pub ticker: Arc<RwLock<TickerInfo>>,
     pub public_handler: Option<tokio::task::JoinHandle<()>>,
+    public_stop: Option<tokio::sync::watch::Sender<bool>>,
+    raw_message_hook: Option<RawMessageHook>,
+    spread_logger: Option<SpreadLogger>,
+    ws_state: WsStateHandle,
 }
 
 #[pymethods]
@@ -262,12 +794,31 @@ impl BybitMarket {
         MarketImpl::get_db_info(self)
     }
 
+    #[pyo3(signature = (ndays=2))]
+    fn gap_report(&self, ndays: i64) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::gap_report(self, ndays)
+    }
+
+    fn range_stats(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<RangeStats> {
+        MarketImpl::range_stats(self, start_time, end_time)
+    }
+
+    fn count(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<u64> {
+        MarketImpl::count(self, start_time, end_time)
+    }
+
+    fn summary(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<TradeSummary> {
+        MarketImpl::summary(self, start_time, end_time)
+    }
+
+    #[pyo3(signature = (start_time, end_time, column_style=ColumnStyle::Default))]
     fn select_trades(
         &mut self,
         start_time: MicroSec,
         end_time: MicroSec,
+        column_style: ColumnStyle,
     ) -> anyhow::Result<PyDataFrame> {
-        MarketImpl::select_trades(self, start_time, end_time)
+        MarketImpl::select_trades(self, start_time, end_time, column_style)
     }
 
     fn _select_db_trades(
@@ -302,6 +853,15 @@ impl BybitMarket {
         MarketImpl::select_cache_ohlcv_df(self, start_time, end_time)
     }
 
+    fn select_large_trades(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::select_large_trades(self, start_time, end_time, min_size)
+    }
+
     fn ohlcvv(
         &mut self,
         start_time: MicroSec,
@@ -311,13 +871,66 @@ impl BybitMarket {
         MarketImpl::ohlcvv(self, start_time, end_time, window_sec)
     }
 
+    fn ohlcv_large(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::ohlcv_large(self, start_time, end_time, window_sec, min_size)
+    }
+
+    /// ohlcvの遅延評価版。filterなどを重ねてからcollect()する。
+    fn ohlcv_lazy(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+    ) -> anyhow::Result<PyLazyFrame> {
+        MarketImpl::ohlcv_lazy(self, start_time, end_time, window_sec)
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, column_style=ColumnStyle::Default, day_start_offset_sec=0))]
     fn ohlcv(
         &mut self,
         start_time: MicroSec,
         end_time: MicroSec,
         window_sec: i64,
+        column_style: ColumnStyle,
+        day_start_offset_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::ohlcv(
+            self,
+            start_time,
+            end_time,
+            window_sec,
+            column_style,
+            day_start_offset_sec,
+        )
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, absolute=false))]
+    fn cvd(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        absolute: bool,
     ) -> anyhow::Result<PyDataFrame> {
-        MarketImpl::ohlcv(self, start_time, end_time, window_sec)
+        MarketImpl::cvd(self, start_time, end_time, window_sec, absolute)
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, path, iso_time=true))]
+    fn ohlcv_csv(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        path: &str,
+        iso_time: bool,
+    ) -> anyhow::Result<i64> {
+        MarketImpl::ohlcv_csv(self, start_time, end_time, window_sec, path, iso_time)
     }
 
     fn vap(
@@ -329,10 +942,51 @@ impl BybitMarket {
         MarketImpl::vap(self, start_time, end_time, price_unit)
     }
 
+    fn prefetch(&mut self, start_time: MicroSec, end_time: MicroSec) {
+        BLOCK_ON(async { MarketImpl::prefetch(self, start_time, end_time) })
+    }
+
+    #[getter]
+    fn get_cache_hit_rate(&self) -> f64 {
+        MarketImpl::cache_hit_rate(self)
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        MarketImpl::cache_stats(self)
+    }
+
+    #[getter]
+    fn get_cache_max_rows(&self) -> usize {
+        MarketImpl::get_cache_max_rows(self)
+    }
+
+    /// キャッシュの最大行数（0は無制限）。超えた分は古いデータから捨てる。
+    #[setter]
+    fn set_cache_max_rows(&mut self, max_rows: usize) -> anyhow::Result<()> {
+        MarketImpl::set_cache_max_rows(self, max_rows)
+    }
+
     fn get_board_json(&self, size: usize) -> anyhow::Result<String> {
         MarketImpl::get_board_json(self, size)
     }
 
+    /// 以降の板の更新ごとのBoardDiff(add/cancel/modify)を受け取る
+    fn board_events(&self) -> BoardEventStream {
+        MarketImpl::board_events(self)
+    }
+
+    /// tickers streamで受信した最新のmark price(market stream開始前は0)
+    #[getter]
+    pub fn get_mark_price(&self) -> Decimal {
+        self.ticker.read().unwrap().mark_price
+    }
+
+    /// 最新のmark price, index price, 予想funding rate
+    #[getter]
+    pub fn get_ticker(&self) -> TickerInfo {
+        self.ticker.read().unwrap().clone()
+    }
+
     #[getter]
     fn get_board(&mut self) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
         BLOCK_ON(async {
@@ -345,6 +999,61 @@ impl BybitMarket {
         MarketImpl::get_board_vec(self)
     }
 
+    /// 板をshape(2, depth, 2)のnumpy配列で返す(薄い板は0埋め)
+    fn get_board_tensor(&self, py: Python, depth: usize) -> PyObject {
+        MarketImpl::get_board_tensor(self, py, depth)
+    }
+
+    fn get_board_grouped(&self, group: Decimal) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
+        MarketImpl::get_board_grouped(self, group)
+    }
+
+    /// `async for trade in market.trade_stream():`
+    #[pyo3(signature = (dedup_window=TRADE_DEDUP_WINDOW))]
+    fn trade_stream(&self, dedup_window: usize) -> anyhow::Result<TradeStream> {
+        MarketImpl::trade_stream(self, dedup_window)
+    }
+
+    /// `async for bar in market.subscribe_ohlcv(60):`
+    #[pyo3(signature = (window_sec, dedup_window=TRADE_DEDUP_WINDOW))]
+    fn subscribe_ohlcv(&self, window_sec: i64, dedup_window: usize) -> anyhow::Result<OhlcvStream> {
+        MarketImpl::subscribe_ohlcv(self, window_sec, dedup_window)
+    }
+
+    #[getter]
+    fn get_db_thread_running(&self) -> bool {
+        MarketImpl::is_db_thread_running(self)
+    }
+
+    fn restart_db_thread(&mut self) -> anyhow::Result<()> {
+        MarketImpl::restart_db_thread(self)
+    }
+
+    #[pyo3(signature = (path, *, time_col=0, price_col=1, size_col=2, side_col=None, id_col=None, time_format=TimeFormat::UnixMilliseconds, has_header=true))]
+    fn import_csv(
+        &mut self,
+        path: &str,
+        time_col: usize,
+        price_col: usize,
+        size_col: usize,
+        side_col: Option<usize>,
+        id_col: Option<usize>,
+        time_format: TimeFormat,
+        has_header: bool,
+    ) -> anyhow::Result<u64> {
+        let schema = CsvSchema {
+            time_col,
+            time_format,
+            price_col,
+            size_col,
+            side_col,
+            id_col,
+            has_header,
+        };
+
+        MarketImpl::import_csv(self, path, schema)
+    }
+
     #[getter]
     fn get_edge_price(&mut self) -> anyhow::Result<(Decimal, Decimal)> {
         BLOCK_ON(async {
@@ -352,11 +1061,90 @@ impl BybitMarket {
         })
     }
 
+    fn server_time(&self) -> anyhow::Result<MicroSec> {
+        BLOCK_ON(async { MarketImpl::async_server_time(self).await })
+    }
+
+    /// 取引所のサーバ時刻 - ローカル時刻(usec)。1秒以上ずれている場合は警告をログに出す。
+    /// 測った値は署名付きリクエストのtimestampの補正に使う。
+    fn clock_skew(&self) -> anyhow::Result<MicroSec> {
+        BLOCK_ON(async { MarketImpl::async_clock_skew(self).await })
+    }
+
+    /// 取引所からtick size, 最小数量, 契約乗数を取得してconfigを更新する(結果はキャッシュされる)
+    #[pyo3(signature = (force=false))]
+    fn fetch_instrument_info(&mut self, force: bool) -> anyhow::Result<MarketConfig> {
+        let config = BLOCK_ON(async { MarketImpl::async_fetch_instrument_info(self, force).await })?;
+        self.config = config.clone();
+
+        Ok(config)
+    }
+
+    /// 発注せずにREST/WebSocketの接続とAPIキーの権限を確認する(ライブ実行前のチェック用)
+    fn health_check(&self) -> anyhow::Result<HealthStatus> {
+        let ws_url = BybitPublicWsClient::public_url(&self.server_config, &self.config);
+        BLOCK_ON(async { MarketImpl::async_health_check(self, &ws_url).await })
+    }
+
+    /// 板の最良気配(best bid/ask, mid, spread)をinterval_msごとにspread_logへ記録する
+    #[pyo3(signature = (interval_ms=SPREAD_LOG_DEFAULT_INTERVAL_MS))]
+    fn start_spread_logging(&mut self, interval_ms: u64) -> anyhow::Result<()> {
+        BLOCK_ON(async { MarketImpl::async_start_spread_logging(self, interval_ms).await })
+    }
+
+    fn stop_spread_logging(&mut self) {
+        BLOCK_ON(async { MarketImpl::async_stop_spread_logging(self).await })
+    }
+
+    #[pyo3(signature = (start_time=0, end_time=0))]
+    fn get_spread_history(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::get_spread_history(self, start_time, end_time)
+    }
+
+    /// OHLCVにspread(best_ask - best_bid)を加えた足。_repr_html_でspreadの幅を描く。
+    #[pyo3(signature = (start_time, end_time, window_sec=60))]
+    fn ohlcvb(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<Ohlcvb> {
+        MarketImpl::ohlcvb(self, start_time, end_time, window_sec)
+    }
+
+    /// 直近ndays分のDBの空白区間[(start, end)]
+    #[pyo3(signature = (ndays, allow_size=GAP_REPORT_ALLOW_SIZE))]
+    fn gap_chunks(&self, ndays: i64, allow_size: MicroSec) -> anyhow::Result<Vec<(MicroSec, MicroSec)>> {
+        MarketImpl::gap_chunks(self, ndays, allow_size)
+    }
+
+    /// Kyle's lambda(window_sec幅の足ごとの価格変化 ~ 符号付き出来高 の回帰係数)
+    fn kyles_lambda(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<f64> {
+        MarketImpl::kyles_lambda(self, start_time, end_time, window_sec)
+    }
+
+    /// step_secごとのKyle's lambda
+    fn rolling_kyles_lambda(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        step_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::rolling_kyles_lambda(self, start_time, end_time, window_sec, step_sec)
+    }
+
+    /// 約定代金がthresholdに達するごとに区切った足(dollar bar)
+    #[pyo3(signature = (start_time, end_time, threshold=Decimal::from(1_000_000)))]
+    fn dollar_bars(&mut self, start_time: MicroSec, end_time: MicroSec, threshold: Decimal) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::dollar_bars(self, start_time, end_time, threshold)
+    }
+
     fn _repr_html_(&self) -> String {
         MarketImpl::_repr_html_(self)
     }
 
-    #[pyo3(signature = (ndays, *, connect_ws=false, force=false, force_archive=false, force_recent=false, verbose=false))]
+    /// progress_callback(event, url, value, total) receives download progress instead of console output.
+    #[pyo3(signature = (ndays, *, connect_ws=false, force=false, force_archive=false, force_recent=false, verbose=false, progress_callback=None))]
     fn download(
         &mut self,
         ndays: i64,
@@ -365,6 +1153,7 @@ impl BybitMarket {
         force_archive: bool,
         force_recent: bool,
         verbose: bool,
+        progress_callback: Option<PyObject>,
     ) -> anyhow::Result<()> {
         BLOCK_ON(async {
             MarketImpl::async_download::<BybitPublicWsClient>(
@@ -375,14 +1164,66 @@ impl BybitMarket {
                 force_archive,
                 force_recent,
                 verbose,
+                PyProgressCallback::boxed(progress_callback),
             )
             .await
         })
     }
 
-    #[pyo3(signature = (ndays, force=false, verbose=false))]
-    fn _download_archive(&mut self, ndays: i64, force: bool, verbose: bool) -> anyhow::Result<i64> {
-        BLOCK_ON(async { MarketImpl::async_download_archive(self, ndays, force, verbose).await })
+    /// 約定アーカイブより古い期間を4本足から合成した約定(LogStatus::KlineFill)で埋める。
+    /// 4本足1本をopen, high, low, closeの4件にする。保存した件数を返す。
+    #[pyo3(signature = (start_time, end_time, interval=KlineInterval::Min1))]
+    fn download_kline_fill(&mut self, start_time: &PyAny, end_time: &PyAny, interval: KlineInterval) -> anyhow::Result<i64> {
+        let start_time = extract_time(start_time)?;
+        let end_time = extract_time(end_time)?;
+
+        BLOCK_ON(async { self.async_download_kline_fill(start_time, end_time, interval).await })
+    }
+
+    /// start_time〜end_timeの日のアーカイブをダウンロードする。時刻はMicroSecかISO8601の文字列("2024-08-23")。
+    #[pyo3(signature = (start_time, end_time, *, force=false, verbose=false, progress_callback=None))]
+    fn download_range(
+        &mut self,
+        start_time: &PyAny,
+        end_time: &PyAny,
+        force: bool,
+        verbose: bool,
+        progress_callback: Option<PyObject>,
+    ) -> anyhow::Result<i64> {
+        let start_time = extract_time(start_time)?;
+        let end_time = extract_time(end_time)?;
+
+        BLOCK_ON(async {
+            MarketImpl::async_download_archive_range(
+                self,
+                start_time,
+                end_time,
+                force,
+                verbose,
+                PyProgressCallback::boxed(progress_callback),
+            )
+            .await
+        })
+    }
+
+    #[pyo3(signature = (ndays, force=false, verbose=false, progress_callback=None))]
+    fn _download_archive(
+        &mut self,
+        ndays: i64,
+        force: bool,
+        verbose: bool,
+        progress_callback: Option<PyObject>,
+    ) -> anyhow::Result<i64> {
+        BLOCK_ON(async {
+            MarketImpl::async_download_archive(
+                self,
+                ndays,
+                force,
+                verbose,
+                PyProgressCallback::boxed(progress_callback),
+            )
+            .await
+        })
     }
 
     fn _download_realtime(
@@ -399,12 +1240,15 @@ impl BybitMarket {
         })
     }
 
+    /// speed: 0.0は待たずに流す。1.0で実時間、10.0で10倍速で再生する
+    #[pyo3(signature = (time_from, time_to, speed=0.0))]
     fn open_backtest_channel(
         &mut self,
         time_from: MicroSec,
         time_to: MicroSec,
+        speed: f64,
     ) -> anyhow::Result<(MicroSec, MicroSec, MarketStream)> {
-        MarketImpl::open_backtest_channel(self, time_from, time_to)
+        MarketImpl::open_backtest_channel_timed(self, time_from, time_to, speed)
     }
 
     fn vaccum(&self) -> anyhow::Result<()> {
@@ -449,14 +1293,86 @@ impl BybitMarket {
         })
     }
 
+    /// market streamを停止し、タスクの終了を待つ
+    fn stop_market_stream(&mut self) {
+        let stop = self.public_stop.take();
+        let handle = self.public_handler.take();
+
+        BLOCK_ON(async { stop_stream_task(stop, handle, STREAM_STOP_TIMEOUT_SEC).await })
+    }
+
     fn open_market_stream(&mut self) -> anyhow::Result<()> {
         BLOCK_ON (async {
             self.async_start_market_stream().await
         })
     }
+
+    /// set callback(str) for raw market stream frames. must be set before open_market_stream.
+    #[pyo3(signature = (handler=None))]
+    fn on_raw_message(&mut self, handler: Option<PyObject>) {
+        self.raw_message_hook = handler.map(make_py_raw_message_hook);
+    }
+
+    /// WSで購読するチャンネルを設定する(空はデフォルト)。open_market_streamの前に呼ぶ。
+    fn set_subscriptions(&mut self, channels: Vec<Channel>) -> anyhow::Result<()> {
+        let mut config = self.config.clone();
+        config.channels = channels;
+        bybit_ws_channels(&config)?;
+
+        self.config.channels = config.channels;
+
+        Ok(())
+    }
+
+    /// WebSocketの接続状態(Disconnected, Connecting, Subscribed, Receiving, Reconnecting(n)など)
+    #[getter]
+    fn get_ws_state(&self) -> String {
+        self.ws_state.get().to_string()
+    }
+
+    /// market streamの接続を張り直す(次のメッセージ受信時に再接続する)
+    fn force_reconnect(&self) {
+        log::info!("force reconnect: {}", self.config.trade_symbol);
+        self.ws_state.request_reconnect();
+    }
 }
 
 impl BybitMarket {
+    pub async fn async_download_kline_fill(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        interval: KlineInterval,
+    ) -> anyhow::Result<i64> {
+        let mut page = RestPage::New;
+        let mut rec = 0;
+
+        loop {
+            let (klines, next_page) = self
+                .api
+                .get_klines_with_interval(&self.config, start_time, end_time, interval.sec(), &page)
+                .await?;
+
+            if klines.is_empty() {
+                break;
+            }
+
+            let trades: Vec<Trade> = klines
+                .iter()
+                .flat_map(|k| k.extract_to_trades_with_status(interval.sec(), LogStatus::KlineFill))
+                .collect();
+
+            rec += self.db.lock().unwrap().insert_records(&trades)?;
+
+            if next_page == RestPage::Done {
+                break;
+            }
+            page = next_page;
+        }
+
+        Ok(rec)
+    }
+
     pub async fn async_new(
         server_config: &ExchangeConfig,
         config: &MarketConfig,
@@ -464,6 +1380,8 @@ impl BybitMarket {
         let db = TradeDataFrame::get(config, server_config.is_production())
             .with_context(|| format!("Error in TradeTable::open: {:?}", config))?;
 
+        let depth = bybit_board_depth(config)?;
+
         // let public_ws = BybitPublicWsClient::new(&server_config, &config).await;
 
         let mut market = BybitMarket {
@@ -471,14 +1389,61 @@ impl BybitMarket {
             api: BybitRestApi::new(server_config),
             config: config.clone(),
             db: db,
-            board: Arc::new(RwLock::new(OrderBook::new(&config, BYBIT_BOARD_DEPTH))),
+            board: Arc::new(RwLock::new(OrderBook::new(&config, depth))),
+            ticker: Arc::new(RwLock::new(TickerInfo::default())),
             public_handler: None,
+            public_stop: None,
+            raw_message_hook: None,
+            spread_logger: None,
+            ws_state: WsStateHandle::default(),
         };
 
         Ok(market)
     }
 }
 
+/// 板の差分を適用し、チェックサムが一致しなければRESTのスナップショットで板を取り直す。
+/// 取り直しはresyncのbackoff間隔をあけて行う。取り直しを行った場合はtrueを返す。
+async fn apply_orderbook_delta(
+    api: &BybitRestApi,
+    config: &MarketConfig,
+    orderbook: &Arc<RwLock<OrderBook>>,
+    board: &BoardTransfer,
+    resync: &mut OrderBookResync,
+) -> bool {
+    let verified = {
+        let mut b = orderbook.write().unwrap();
+        b.update_and_verify(board, BYBIT_CHECKSUM_DEPTH)
+    };
+
+    if verified {
+        if board.checksum.is_some() {
+            resync.on_verified();
+        }
+        return false;
+    }
+
+    let now = NOW();
+    if !resync.can_resync(now) {
+        log::debug!("orderbook checksum mismatch, wait for resync backoff");
+        return false;
+    }
+    resync.on_resync(now);
+
+    log::warn!("orderbook checksum mismatch, refresh board");
+    match api.get_board_snapshot(config).await {
+        Ok(snapshot) => {
+            let mut b = orderbook.write().unwrap();
+            b.update(&snapshot);
+        }
+        Err(e) => {
+            log::error!("Error in refresh board: {:?}", e);
+        }
+    }
+
+    true
+}
+
 impl MarketImpl<BybitRestApi> for BybitMarket {
     fn get_restapi(&self) -> &BybitRestApi {
         &self.api
@@ -509,13 +1474,18 @@ impl MarketImpl<BybitRestApi> for BybitMarket {
         }?;
 
         let orderbook = self.board.clone();
+        let ticker_cache = self.ticker.clone();
 
         let server_config = self.server_config.clone();
         let config = self.config.clone();
 
         let hub_channel = MARKET_HUB.open_channel();
 
+        bybit_ws_channels(&config)?;
+
         let mut public_ws = BybitPublicWsClient::new(&server_config, &config).await;
+        public_ws.set_raw_message_hook(self.raw_message_hook.clone());
+        public_ws.set_state_handle(self.ws_state.clone());
 
         let exchange_name = config.exchange_name.clone();
         let trade_category = config.trade_category.clone();
@@ -525,15 +1495,28 @@ impl MarketImpl<BybitRestApi> for BybitMarket {
 
         let _ = self.async_refresh_order_book().await;
 
+        let snapshot_api = BybitRestApi::new(&server_config);
+        let mut resync = OrderBookResync::new();
+
+        let (stop_tx, mut stop_rx) = stream_stop_signal();
+        self.public_stop = Some(stop_tx);
+
         self.public_handler = Some(tokio::task::spawn(async move {
             let ws_stream = public_ws.open_stream().await;
             let mut ws_stream = Box::pin(ws_stream);
 
             loop {
-                let message = ws_stream.next().await;
+                let message = tokio::select! {
+                    _ = stop_rx.changed() => {
+                        log::info!("market stream stopped");
+                        break;
+                    }
+                    message = ws_stream.next() => message,
+                };
+
                 if message.is_none() {
-                    log::error!("Error in ws_stream.recv: {:?}", message);
-                    continue;
+                    log::error!("market stream closed");
+                    break;
                 }
 
                 let message = message.unwrap();
@@ -567,8 +1550,24 @@ impl MarketImpl<BybitRestApi> for BybitMarket {
                         }
                     }
                     MultiMarketMessage::Orderbook(board) => {
-                        let mut b = orderbook.write().unwrap();
-                        b.update(&board);
+                        apply_orderbook_delta(&snapshot_api, &config, &orderbook, &board, &mut resync).await;
+                    }
+                    MultiMarketMessage::Ticker(ticker) => {
+                        let ticker = {
+                            let mut cache = ticker_cache.write().unwrap();
+                            cache.merge(&ticker);
+                            cache.clone()
+                        };
+
+                        let r = hub_channel.send(BroadcastMessage {
+                            exchange: exchange_name.clone(),
+                            category: trade_category.clone(),
+                            symbol: trade_symbol.clone(),
+                            msg: MarketMessage::Ticker(ticker),
+                        });
+                        if r.is_err() {
+                            log::error!("Error in hub_channel.send: {:?}", r);
+                        }
                     }
                     MultiMarketMessage::Control(control) => {
                         // TODO: alert or recovery.
@@ -590,6 +1589,10 @@ impl MarketImpl<BybitRestApi> for BybitMarket {
         self.board.clone()
     }
 
+    fn get_spread_logger(&mut self) -> &mut Option<SpreadLogger> {
+        &mut self.spread_logger
+    }
+
     async fn async_download_range(
         &mut self,
         time_from: MicroSec,
@@ -612,53 +1615,150 @@ mod bybit_test {
     #[test]
     fn test_create() {
         init_debug_log();
-        let mut bybit = Bybit::new(false);
+        let mut bybit = Bybit::new(false, None);
         assert_eq!(bybit.get_enable_order_feature(), false);
 
         bybit.set_enable_order_feature(true);
         assert_eq!(bybit.get_enable_order_feature(), true);
     }
 
+    #[test]
+    fn test_open_market_position_mode() -> anyhow::Result<()> {
+        use super::PositionMode;
+        use rbot_lib::common::MarketConfig;
+
+        let mut config = BybitConfig::new();
+        config.position_mode = PositionMode::HedgeBothSide;
+        let bybit = Bybit::new(false, Some(config));
+        assert_eq!(bybit.get_position_mode(), PositionMode::HedgeBothSide);
+
+        let mut market_config = MarketConfig::default();
+        market_config.exchange_name = "BYBIT".to_string();
+        market_config.trade_category = "linear".to_string();
+        market_config.trade_symbol = "BTCUSDT".to_string();
+
+        let market = bybit.open_market_config(market_config);
+        assert_eq!(market.api.get_position_mode(), PositionMode::HedgeBothSide);
+
+        Ok(())
+    }
+
+    #[test]
+        fn test_open_market_spot_margin() -> anyhow::Result<()> {
+        use super::SpotMarginConfig;
+        use rbot_lib::common::{MarketConfig, OrderSide, OrderType, TimeInForce};
+
+        let mut config = BybitConfig::new();
+        config.spot_margin = Some(SpotMarginConfig::new(3));
+        let bybit = Bybit::new(false, Some(config));
+
+        let mut market_config = MarketConfig::default();
+        market_config.exchange_name = "BYBIT".to_string();
+        market_config.trade_category = "spot".to_string();
+        market_config.trade_symbol = "BTCUSDT".to_string();
+
+        // Bybitの設定が開いたマーケットの注文に反映される
+        let market = bybit.open_market_config(market_config.clone());
+        assert_eq!(market.api.get_spot_margin().unwrap().leverage, 3);
+
+        let order = market.api.make_order_request(
+            &market_config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["isLeverage"], "1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_my_quote() {
+        use super::find_my_quote;
+        use rbot_lib::common::{Order, OrderSide, OrderType};
+
+        let order = |id: &str, client_id: &str, side: OrderSide, order_type: OrderType| {
+            let mut o = Order::default();
+            o.order_id = id.to_string();
+            o.client_order_id = client_id.to_string();
+            o.order_side = side;
+            o.order_type = order_type;
+            o
+        };
+
+        let orders = vec![
+            order("1", "other-qb1", OrderSide::Buy, OrderType::Limit),
+            order("2", "mm-qb1", OrderSide::Buy, OrderType::Market),
+            order("3", "mm-qb2", OrderSide::Buy, OrderType::Limit),
+            order("4", "other-qs1", OrderSide::Sell, OrderType::Limit),
+        ];
+
+        assert_eq!(find_my_quote(&orders, OrderSide::Buy, "mm").unwrap().order_id, "3");
+        assert!(find_my_quote(&orders, OrderSide::Sell, "mm").is_none());
+        assert_eq!(find_my_quote(&orders, OrderSide::Sell, "other").unwrap().order_id, "4");
+    }
+
+    #[test]
+    fn test_quote_order_id() {
+        use super::quote_order_id;
+        use rbot_lib::common::{Order, OrderSide};
+
+        let id = quote_order_id("mm", OrderSide::Sell, 1704541422547000).unwrap();
+        assert_eq!(id, "mm-qs1704541422547000");
+
+        let mut order = Order::default();
+        order.client_order_id = id;
+        assert!(order.is_my_order("mm"));
+
+        assert!(quote_order_id("a-very-long-agent-identifier", OrderSide::Buy, 1704541422547000).is_err());
+    }
+
     #[test]
     fn test_limit_order() {
         init_debug_log();
-        let mut bybit = Bybit::new(false);
+        let mut bybit = Bybit::new(false, None);
         let config = BybitConfig::BTCUSDT();
 
-        let rec = bybit.limit_order(&config, "Buy", dec![45000.0], dec![0.001], None);
+        let rec = bybit.limit_order(&config, "Buy", dec![45000.0], dec![0.001], None, None, None, false, false);
         println!("{:?}", rec);
         assert!(rec.is_err()); // first enable flag.
 
         bybit.set_enable_order_with_my_own_risk(true);
-        let rec = bybit.limit_order(&config, "Buy", dec![45000.0], dec![0.001], None);
+        let rec = bybit.limit_order(&config, "Buy", dec![45000.0], dec![0.001], None, None, None, false, false);
         println!("{:?}", rec);
         assert!(rec.is_ok()); // first enable flag.
     }
 
     #[test]
     fn test_market_order() {
-        let mut bybit = Bybit::new(false);
+        let mut bybit = Bybit::new(false, None);
         let config = BybitConfig::BTCUSDT();
 
         init_debug_log();
 
-        let rec = bybit.market_order(&config, "Buy", dec![0.001], None);
+        let rec = bybit.market_order(&config, "Buy", dec![0.001], None, None, false, false);
         println!("{:?}", rec);
         assert!(rec.is_err()); // first enable flag.
 
         bybit.set_enable_order_with_my_own_risk(true);
-        let rec = bybit.market_order(&config, "Buy", dec![0.001], None);
+        let rec = bybit.market_order(&config, "Buy", dec![0.001], None, None, false, false);
         println!("{:?}", rec);
         assert!(rec.is_ok()); // first enable flag.
     }
 
     #[test]
     fn test_cancel_order() -> anyhow::Result<()> {
-        let mut bybit = Bybit::new(false);
+        let mut bybit = Bybit::new(false, None);
         let config = BybitConfig::BTCUSDT();
 
         bybit.set_enable_order_with_my_own_risk(true);
-        let rec = bybit.limit_order(&config, "Buy", dec![45000.0], dec![0.001], None)?;
+        let rec = bybit.limit_order(&config, "Buy", dec![45000.0], dec![0.001], None, None, None, false, false)?;
 
         let order_id = rec[0].order_id.clone();
 
@@ -670,7 +1770,7 @@ mod bybit_test {
 
     #[test]
     fn test_get_open_orders() -> anyhow::Result<()> {
-        let mut bybit = Bybit::new(false);
+        let mut bybit = Bybit::new(false, None);
         let config = BybitConfig::BTCUSDT();
 
         let rec = bybit.get_open_orders(&config)?;
@@ -681,7 +1781,7 @@ mod bybit_test {
 
     #[test]
     fn test_get_account() {
-        let mut bybit = Bybit::new(false);
+        let mut bybit = Bybit::new(false, None);
         let config = BybitConfig::BTCUSDT();
 
         let rec = bybit.get_account();
@@ -718,7 +1818,7 @@ mod market_test {
         init_debug_log();
         let mut market = BybitMarket::new(&server_config, &market_config);
 
-        let rec = market._download_archive(3, false, true);
+        let rec = market._download_archive(3, false, true, None);
         assert!(rec.is_ok());
     }
 
@@ -755,7 +1855,7 @@ mod market_test {
 
         init_debug_log();
 
-        let mut server = Bybit::new(false);
+        let mut server = Bybit::new(false, None);
 
         assert_eq!(server.get_enable_order_with_my_own_risk(), false);
 
@@ -774,7 +1874,7 @@ mod market_test {
 
         let mut market = BybitMarket::new(&server_config, &market_config);
 
-        let ohlcv = market.ohlcv(0, 0, 60);
+        let ohlcv = market.ohlcv(0, 0, 60, ColumnStyle::Default, 0);
         println!("{:?}", ohlcv);
 
         let ohlcvv = market.ohlcvv(0, 0, 60);
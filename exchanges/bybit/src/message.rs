@@ -7,7 +7,11 @@ use std::collections::HashMap;
 
 use std::str::FromStr;
 
-use pyo3::pyclass;
+use polars::frame::DataFrame;
+use polars::prelude::NamedFrom as _;
+use polars::series::Series;
+use pyo3::{pyclass, pymethods};
+use rust_decimal::prelude::ToPrimitive as _;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use serde::Deserializer;
@@ -15,9 +19,10 @@ use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 
 use rbot_lib::common::{
-    msec_to_microsec, string_to_decimal, string_to_i64, time_string, AccountCoins, AccountPair,
-    Board, BoardTransfer, Coin, ControlMessage, Kline, LogStatus, MarketConfig, MarketMessage,
-    MicroSec, MultiMarketMessage, Order, OrderBookRaw, OrderSide, OrderStatus, OrderType, Trade,
+    collect_trades, msec_to_microsec, normalize_timestamp, string_to_decimal, string_to_decimal_opt, string_to_i64, time_string, AccountCoins, AccountPair,
+    Board, BoardTransfer, Coin, ControlMessage, FeeType, Fill, Kline, LogStatus, MarketConfig, MarketError, MarketMessage,
+    MicroSec, MultiMarketMessage, Order, OrderBookRaw, OrderSide, OrderStatus, OrderType,
+    PositionInfo, TickerInfo, TickerUpdate, TimestampUnit, Trade,
 };
 
 use crate::Bybit;
@@ -25,7 +30,7 @@ use crate::Bybit;
 pub type BybitTimestamp = i64;
 
 pub fn bybit_timestamp_to_microsec(timestamp: BybitTimestamp) -> MicroSec {
-    timestamp * 1000
+    normalize_timestamp(timestamp, TimestampUnit::Milliseconds)
 }
 
 pub fn microsec_to_bybit_timestamp(timestamp: MicroSec) -> BybitTimestamp {
@@ -82,6 +87,18 @@ impl BybitRestResponse {
     pub fn is_success(&self) -> bool {
         self.return_code == 0
     }
+
+    /// retCodeの分類 https://bybit-exchange.github.io/docs/v5/error
+    pub fn to_market_error(&self) -> MarketError {
+        let msg = self.return_message.clone();
+
+        match self.return_code {
+            10003 | 10004 | 10005 | 10007 | 10009 | 10010 | 33004 => MarketError::Auth(msg),
+            10006 | 10018 => MarketError::RateLimited(msg),
+            110001 | 170213 => MarketError::NotFound(msg),
+            code => MarketError::Exchange { code, msg },
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -100,6 +117,8 @@ pub struct BybitRestBoard {
 impl Into<BoardTransfer> for BybitRestBoard {
     fn into(self) -> BoardTransfer {
         let mut bt = BoardTransfer::new();
+        // RESTの板は全体のスナップショットなので、適用時に古い板を消す
+        bt.snapshot = true;
 
         for bid in self.bids.iter() {
             bt.insert_bid(bid);
@@ -354,21 +373,23 @@ pub struct BybitTradeResponse {
     pub trades: Vec<BybitTrade>,
 }
 
+impl BybitTrade {
+    pub fn to_trade(&self) -> anyhow::Result<Trade> {
+        Trade::new(
+            msec_to_microsec(self.time),
+            OrderSide::from(&self.side),
+            self.price,
+            self.size,
+            LogStatus::UnFix,
+            &self.exec_id,
+        )
+        .validate()
+    }
+}
+
 impl Into<Vec<Trade>> for BybitTradeResponse {
     fn into(self) -> Vec<Trade> {
-        let mut trades = Vec::new();
-
-        for trade in self.trades.iter() {
-            let t = Trade::new(
-                msec_to_microsec(trade.time),
-                OrderSide::from(&trade.side),
-                trade.price,
-                trade.size,
-                LogStatus::UnFix,
-                &trade.exec_id,
-            );
-            trades.push(t);
-        }
+        let (trades, _skipped) = collect_trades(self.trades.iter(), |trade| trade.to_trade());
 
         trades
     }
@@ -481,6 +502,15 @@ pub struct BybitOrderStatus {
     pub createdTime: BybitTimestamp,
     #[serde(deserialize_with = "string_to_i64")]
     pub updatedTime: BybitTimestamp,
+    #[serde(default)]
+    pub feeCurrency: String, // spot only
+}
+
+impl BybitOrderStatus {
+    /// spotマージン取引(借入あり)の注文か
+    pub fn is_margin_order(&self) -> bool {
+        self.isLeverage == "1"
+    }
 }
 
 impl Into<Order> for &BybitOrderStatus {
@@ -505,7 +535,7 @@ impl Into<Order> for &BybitOrderStatus {
             execute_size: self.cumExecQty,
             quote_vol: self.price * self.qty,
             commission: self.cumExecFee,
-            commission_asset: "".to_string(),
+            commission_asset: self.feeCurrency.clone(),
             is_maker: order_type.is_maker(),
             message: "".to_string(),    // DUMMY value
             commission_home: dec![0.0], // DUMMY value
@@ -523,10 +553,227 @@ impl Into<Order> for &BybitOrderStatus {
             profit: dec![0.0],
             fee: dec![0.0],
             total_profit: dec![0.0],
+            position_idx: self.positionIdx,
+            reduce_only: self.reduceOnly,
         }
     }
 }
 
+/// /v5/position/closed-pnl のlistの1件
+/// {"symbol":"BTCUSDT","orderType":"Market","leverage":"10","updatedTime":"1724371260000","side":"Sell","orderId":"a1",
+///  "closedPnl":"12.5","avgEntryPrice":"64000","qty":"0.01","cumEntryValue":"640","createdTime":"1724371200000",
+///  "orderPrice":"63000","closedSize":"0.01","avgExitPrice":"65250","execType":"Trade","fillCount":"2","cumExitValue":"652.5"}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitClosedPnl {
+    pub symbol: String,
+    pub orderId: String,
+    pub side: String,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub qty: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub orderPrice: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub avgEntryPrice: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub avgExitPrice: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub closedSize: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub cumEntryValue: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub cumExitValue: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub closedPnl: Decimal,
+    #[serde(deserialize_with = "string_to_i64")]
+    pub fillCount: i64,
+    #[serde(deserialize_with = "string_to_i64")]
+    pub createdTime: BybitTimestamp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitClosedPnlResponse {
+    #[serde(default)]
+    pub category: String,
+    pub list: Vec<BybitClosedPnl>,
+    #[serde(default)]
+    pub nextPageCursor: String,
+}
+
+pub fn closed_pnl_to_df(pnl: &Vec<BybitClosedPnl>) -> anyhow::Result<DataFrame> {
+    let f = |v: &Decimal| v.to_f64().unwrap_or(0.0);
+
+    let df = DataFrame::new(vec![
+        Series::new("symbol", pnl.iter().map(|p| p.symbol.clone()).collect::<Vec<_>>()),
+        Series::new("order_id", pnl.iter().map(|p| p.orderId.clone()).collect::<Vec<_>>()),
+        Series::new("side", pnl.iter().map(|p| p.side.clone()).collect::<Vec<_>>()),
+        Series::new("qty", pnl.iter().map(|p| f(&p.qty)).collect::<Vec<_>>()),
+        Series::new("order_price", pnl.iter().map(|p| f(&p.orderPrice)).collect::<Vec<_>>()),
+        Series::new("avg_entry_price", pnl.iter().map(|p| f(&p.avgEntryPrice)).collect::<Vec<_>>()),
+        Series::new("avg_exit_price", pnl.iter().map(|p| f(&p.avgExitPrice)).collect::<Vec<_>>()),
+        Series::new("closed_size", pnl.iter().map(|p| f(&p.closedSize)).collect::<Vec<_>>()),
+        Series::new("cum_entry_value", pnl.iter().map(|p| f(&p.cumEntryValue)).collect::<Vec<_>>()),
+        Series::new("cum_exit_value", pnl.iter().map(|p| f(&p.cumExitValue)).collect::<Vec<_>>()),
+        Series::new("closed_pnl", pnl.iter().map(|p| f(&p.closedPnl)).collect::<Vec<_>>()),
+        Series::new("fill_count", pnl.iter().map(|p| p.fillCount).collect::<Vec<_>>()),
+        Series::new(
+            "created_time",
+            pnl.iter().map(|p| bybit_timestamp_to_microsec(p.createdTime)).collect::<Vec<_>>(),
+        ),
+    ])?;
+
+    Ok(df)
+}
+
+/// /v5/market/time
+/// {"timeSecond":"1688639403","timeNano":"1688639403423213947"}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitServerTime {
+    #[serde(rename = "timeSecond", deserialize_with = "string_to_i64")]
+    pub time_second: i64,
+    #[serde(rename = "timeNano", deserialize_with = "string_to_i64")]
+    pub time_nano: i64,
+}
+
+impl BybitServerTime {
+    pub fn to_microsec(&self) -> MicroSec {
+        self.time_nano / 1_000
+    }
+}
+
+/// /v5/user/query-api
+/// {"id":"13770661","apiKey":"xxxxx","readOnly":0,"permissions":{"ContractTrade":["Order","Position"],"Spot":["SpotTrade"],...},...}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BybitApiKeyInfo {
+    #[serde(rename = "readOnly", default)]
+    pub read_only: i64,
+    #[serde(default)]
+    pub permissions: std::collections::BTreeMap<String, Vec<String>>,
+}
+
+impl BybitApiKeyInfo {
+    /// "ContractTrade.Order"の形式。読み取り専用キーは"ReadOnly"を含む。
+    pub fn permission_list(&self) -> Vec<String> {
+        let mut permissions: Vec<String> = vec![];
+
+        if self.read_only == 1 {
+            permissions.push("ReadOnly".to_string());
+        }
+
+        for (group, items) in self.permissions.iter() {
+            for item in items {
+                permissions.push(format!("{}.{}", group, item));
+            }
+        }
+
+        permissions
+    }
+}
+
+/// /v5/account/fee-rate のlistの1件
+/// {"symbol":"BTCUSDT","baseCoin":"","takerFeeRate":"0.00055","makerFeeRate":"0.0002","makerMarkupRate":"","takerMarkupRate":""}
+/// markupはRPI対象の銘柄のみ値が入り、それ以外は空文字か項目自体がない。
+#[pyclass]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeTier {
+    #[pyo3(get)]
+    pub symbol: String,
+    #[pyo3(get)]
+    #[serde(rename = "makerFeeRate", deserialize_with = "string_to_decimal")]
+    pub maker_fee_rate: Decimal,
+    #[pyo3(get)]
+    #[serde(rename = "takerFeeRate", deserialize_with = "string_to_decimal")]
+    pub taker_fee_rate: Decimal,
+    #[pyo3(get)]
+    #[serde(rename = "makerMarkupRate", default, deserialize_with = "string_to_decimal")]
+    pub maker_markup_rate: Decimal,
+    #[pyo3(get)]
+    #[serde(rename = "takerMarkupRate", default, deserialize_with = "string_to_decimal")]
+    pub taker_markup_rate: Decimal,
+}
+
+impl FeeTier {
+    /// MarketConfigの手数料を上書きする。spotは買いが外貨、売りが円貨(quote)建てで引かれる。
+    pub fn apply(&self, config: &mut MarketConfig) {
+        config.maker_fee = self.maker_fee_rate;
+        config.taker_fee = self.taker_fee_rate;
+        config.fee_type = if config.trade_category == "spot" {
+            FeeType::Both
+        } else {
+            FeeType::Home
+        };
+    }
+}
+
+#[pymethods]
+impl FeeTier {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "{{symbol: {}, maker_fee_rate: {}, taker_fee_rate: {}, maker_markup_rate: {}, taker_markup_rate: {}}}",
+            self.symbol,
+            self.maker_fee_rate,
+            self.taker_fee_rate,
+            self.maker_markup_rate,
+            self.taker_markup_rate
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitFeeRateResponse {
+    pub list: Vec<FeeTier>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BybitPriceFilter {
+    #[serde(rename = "tickSize", deserialize_with = "string_to_decimal")]
+    pub tick_size: Decimal,
+}
+
+/// spotはqtyStepの代わりにbasePrecisionが入る
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BybitLotSizeFilter {
+    #[serde(rename = "qtyStep", default, deserialize_with = "string_to_decimal")]
+    pub qty_step: Decimal,
+    #[serde(rename = "basePrecision", default, deserialize_with = "string_to_decimal")]
+    pub base_precision: Decimal,
+    #[serde(rename = "minOrderQty", deserialize_with = "string_to_decimal")]
+    pub min_order_qty: Decimal,
+}
+
+/// /v5/market/instruments-info のlistの1件
+/// {"symbol":"BTCUSDT","contractType":"LinearPerpetual","priceFilter":{"tickSize":"0.10",...},"lotSizeFilter":{"qtyStep":"0.001","minOrderQty":"0.001",...},...}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BybitInstrumentInfo {
+    pub symbol: String,
+    #[serde(rename = "priceFilter")]
+    pub price_filter: BybitPriceFilter,
+    #[serde(rename = "lotSizeFilter")]
+    pub lot_size_filter: BybitLotSizeFilter,
+}
+
+impl BybitInstrumentInfo {
+    /// Bybitの数量はlinearは外貨、inverseはUSD建てなので契約乗数は常に1
+    pub fn apply(&self, config: &mut MarketConfig) {
+        let size_unit = if self.lot_size_filter.qty_step != dec![0.0] {
+            self.lot_size_filter.qty_step
+        } else {
+            self.lot_size_filter.base_precision
+        };
+
+        config.set_instrument(
+            self.price_filter.tick_size,
+            size_unit,
+            self.lot_size_filter.min_order_qty,
+            dec![1.0],
+        );
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitInstrumentsResponse {
+    pub list: Vec<BybitInstrumentInfo>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[pyclass]
 pub struct BybitMultiOrderStatus {
@@ -563,6 +810,7 @@ pub enum BybitPublicWsMessage {
     Pong(BybitWsPongReply),
     Trade(BybitWsTradeMessage),
     Orderbook(BybitWsOrderbookMessage),
+    Ticker(BybitWsTickerTopic),
 }
 
 impl From<String> for BybitPublicWsMessage {
@@ -576,19 +824,8 @@ impl Into<MultiMarketMessage> for BybitPublicWsMessage {
     fn into(self) -> MultiMarketMessage {
         match self {
             BybitPublicWsMessage::Trade(trade) => {
-                let mut trades: Vec<Trade> = vec![];
-
-                for trade in trade.data.iter() {
-                    let t = Trade::new(
-                        msec_to_microsec(trade.timestamp),
-                        OrderSide::from(&trade.side),
-                        trade.price,
-                        trade.size,
-                        LogStatus::UnFix,
-                        &trade.trade_id,
-                    );
-                    trades.push(t);
-                }
+                let (trades, _skipped) = collect_trades(trade.data.iter(), |trade| trade.to_trade());
+
                 return MultiMarketMessage::Trade(trades);
             }
             BybitPublicWsMessage::Orderbook(orderbook) => {
@@ -596,6 +833,9 @@ impl Into<MultiMarketMessage> for BybitPublicWsMessage {
 
                 return MultiMarketMessage::Orderbook(board);
             }
+            BybitPublicWsMessage::Ticker(ticker) => {
+                return MultiMarketMessage::Ticker(ticker.into());
+            }
             BybitPublicWsMessage::Status(status) => {
                 return MultiMarketMessage::Control(ControlMessage {
                     status: status.success,
@@ -685,6 +925,20 @@ pub struct BybitWsTrade {
     pub is_block_trade: bool,
 }
 
+impl BybitWsTrade {
+    pub fn to_trade(&self) -> anyhow::Result<Trade> {
+        Trade::new(
+            msec_to_microsec(self.timestamp),
+            OrderSide::from(&self.side),
+            self.price,
+            self.size,
+            LogStatus::UnFix,
+            &self.trade_id,
+        )
+        .validate()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BybitWsOrderbook {
     #[serde(rename = "s")]
@@ -699,6 +953,8 @@ pub struct BybitWsOrderbook {
     pub sequence: i64,
     #[serde(rename = "cts")]
     pub create_timestamp: Option<BybitTimestamp>,
+    #[serde(default)]
+    pub checksum: Option<i64>,
 }
 
 /*
@@ -743,6 +999,7 @@ impl Into<BoardTransfer> for BybitWsOrderbook {
         let mut bt = BoardTransfer::new();
 
         bt.last_update_id = self.update_id as u64;
+        bt.checksum = self.checksum;
 
         for bid in self.bids.iter() {
             bt.insert_bid(bid);
@@ -786,12 +1043,66 @@ impl Into<BoardTransfer> for BybitWsOrderbookMessage {
     }
 }
 
+/// tickers.{symbol}
+/// linearはsnapshotの後、変化した項目だけのdeltaが送られる(含まれない項目はNone)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitWsTickerTopic {
+    #[serde(rename = "topic")]
+    pub topic: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    #[serde(rename = "data")]
+    pub data: BybitWsTickerMessage,
+    #[serde(rename = "ts")]
+    pub timestamp: BybitTimestamp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitWsTickerMessage {
+    #[serde(rename = "symbol")]
+    pub symbol: String,
+    #[serde(rename = "markPrice", default, deserialize_with = "string_to_decimal_opt")]
+    pub mark_price: Option<Decimal>,
+    #[serde(rename = "indexPrice", default, deserialize_with = "string_to_decimal_opt")]
+    pub index_price: Option<Decimal>,
+    #[serde(rename = "fundingRate", default, deserialize_with = "string_to_decimal_opt")]
+    pub predicted_funding_rate: Option<Decimal>,
+    #[serde(rename = "nextFundingTime", default, deserialize_with = "string_to_i64")]
+    pub next_funding_time: BybitTimestamp,
+}
+
+impl Into<TickerUpdate> for BybitWsTickerTopic {
+    fn into(self) -> TickerUpdate {
+        TickerUpdate {
+            symbol: self.data.symbol,
+            time: bybit_timestamp_to_microsec(self.timestamp),
+            mark_price: self.data.mark_price,
+            index_price: self.data.index_price,
+            predicted_funding_rate: self.data.predicted_funding_rate,
+            next_funding_time: if self.data.next_funding_time == 0 {
+                None
+            } else {
+                Some(bybit_timestamp_to_microsec(self.data.next_funding_time))
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum BybitUserWsMessage {
     status(BybitWsStatus),
     pong(BybitWsPongReply),
     message(BybitUserMessage),
+    ping(BybitWsPing),
+}
+
+/// サーバからのheartbeat {"op":"ping"}。20秒以内に{"op":"pong"}を返さないと切断される。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitWsPing {
+    pub op: String,
+    #[serde(default)]
+    pub req_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -815,6 +1126,65 @@ pub enum BybitUserMessage {
         creationTime: BybitTimestamp,
         data: Vec<BybitExecution>,
     },
+    position {
+        id: String,
+        //topic: String,
+        creationTime: BybitTimestamp,
+        data: Vec<BybitPositionUpdate>,
+    },
+}
+
+/*
+{"id":"1003076014fb7eedb-c7e6-45d6-a8c1-270f0169171a","topic":"position","creationTime":1697682317044,
+ "data":[{"positionIdx":0,"tradeMode":0,"riskId":1,"riskLimitValue":"2000000","symbol":"BTCUSDT","side":"","size":"0","entryPrice":"0",
+ "leverage":"10","positionValue":"0","positionBalance":"0","markPrice":"28184.5","positionIM":"0","positionMM":"0","takeProfit":"0",
+ "stopLoss":"0","trailingStop":"0","unrealisedPnl":"0","cumRealisedPnl":"-25.06579337","sessionAvgPrice":"0","createdTime":"1694402496913",
+ "updatedTime":"1697682317038","tpslMode":"Full","liqPrice":"0","bustPrice":"","category":"linear","positionStatus":"Normal",
+ "adlRankIndicator":0,"autoAddMargin":0,"leverageSysUpdatedTime":"","mmrSysUpdatedTime":"","seq":8327597863,"isReduceOnly":false}]}
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BybitPositionUpdate {
+    #[serde(default)]
+    pub category: String,
+    pub symbol: String,
+    pub side: String, // "" when no position
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub size: Decimal,
+    #[serde(alias = "entryPrice", deserialize_with = "string_to_decimal")]
+    pub avgPrice: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub positionValue: Decimal,
+    #[serde(default, deserialize_with = "string_to_decimal")]
+    pub leavesValue: Decimal,
+    pub riskId: i64,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub leverage: Decimal,
+    #[serde(default)]
+    pub positionIdx: i64,
+    #[serde(default, deserialize_with = "string_to_i64")]
+    pub updatedTime: BybitTimestamp,
+}
+
+impl Into<PositionInfo> for &BybitPositionUpdate {
+    fn into(self) -> PositionInfo {
+        let side = if self.side == "" {
+            OrderSide::Unknown
+        } else {
+            OrderSide::from(self.side.as_str())
+        };
+
+        PositionInfo {
+            category: self.category.clone(),
+            symbol: self.symbol.clone(),
+            side: side,
+            size: self.size,
+            avg_price: self.avgPrice,
+            position_value: self.positionValue,
+            leverage: self.leverage,
+            position_idx: self.positionIdx,
+            update_time: bybit_timestamp_to_microsec(self.updatedTime),
+        }
+    }
 }
 
 /*
@@ -876,6 +1246,27 @@ pub struct BybitExecution {
     #[serde(deserialize_with = "string_to_decimal")]
     pub feeRate: Decimal,
     pub seq: i64,
+    #[serde(default)]
+    pub feeCurrency: String, // spot only
+}
+
+impl Into<Fill> for &BybitExecution {
+    fn into(self) -> Fill {
+        Fill {
+            time: bybit_timestamp_to_microsec(self.execTime),
+            symbol: self.symbol.clone(),
+            order_id: self.orderId.clone(),
+            client_order_id: self.orderLinkId.clone(),
+            exec_id: self.execId.clone(),
+            side: OrderSide::from(&self.side),
+            price: self.execPrice,
+            qty: self.execQty,
+            fee: self.execFee,
+            fee_rate: self.feeRate,
+            fee_asset: self.feeCurrency.clone(),
+            is_maker: self.isMaker,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -976,6 +1367,7 @@ impl Into<AccountCoins> for BybitAccountStatus {
     }
 }
 
+/// spotマージン取引で借り入れている分はlockedに含める。
 pub fn convert_coin_to_account_status(
     config: &MarketConfig,
     coins: Vec<BybitAccountCoin>,
@@ -986,11 +1378,11 @@ pub fn convert_coin_to_account_status(
         if coin.coin == config.home_currency {
             account.home.volume = coin.equity;
             account.home.free = coin.availableToWithdraw;
-            account.home.locked = coin.locked;
+            account.home.locked = coin.locked + coin.borrowAmount;
         } else if coin.coin == config.foreign_currency {
             account.foreign.volume = coin.equity;
             account.foreign.free = coin.availableToWithdraw;
-            account.foreign.locked = coin.locked;
+            account.foreign.locked = coin.locked + coin.borrowAmount;
         }
     }
 
@@ -1080,7 +1472,7 @@ impl Into<AccountCoins> for BybitAccountResponse {
 #[allow(unused_variables)]
 mod bybit_message_test {
     use super::*;
-    use rbot_lib::common::init_debug_log;
+    use rbot_lib::common::{init_debug_log, BoardTransfer, MultiMarketMessage};
 
     use crate::message::{
         BybitAccountStatus, BybitExecution, BybitMultiOrderStatus, BybitRestResponse,
@@ -1099,6 +1491,26 @@ mod bybit_message_test {
         let result = serde_json::from_str::<BybitRestResponse>(&message);
         println!("{:?}", result);
         assert!(result.is_ok());
+
+        let board: BybitRestBoard = serde_json::from_value(result.unwrap().body).unwrap();
+        let transfer: BoardTransfer = board.into();
+        assert!(transfer.snapshot);
+        assert_eq!(transfer.bids.len(), 5);
+        assert_eq!(transfer.asks.len(), 5);
+    }
+
+    #[test]
+    fn test_bybit_ws_orderbook_checksum() {
+        let message = r#"{"topic":"orderbook.50.BTCUSDT","ts":1703430557896,"type":"delta","data":{"s":"BTCUSDT","b":[["43728.19","0.5"]],"a":[["43736.01","0.525"]],"u":5179080,"seq":19967461033,"checksum":-1234567},"cts":1703430557847}"#;
+        let message = serde_json::from_str::<BybitPublicWsMessage>(message).unwrap();
+
+        match message.into() {
+            MultiMarketMessage::Orderbook(board) => {
+                assert_eq!(board.checksum, Some(-1234567));
+                assert!(!board.snapshot);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
     }
 
     #[test]
@@ -1350,6 +1762,23 @@ mod bybit_message_test {
         println!("{:?}", execution);
     }
 
+    #[test]
+    fn test_bybit_execution_into_fill() {
+        let message = r#"{"category":"linear","symbol":"BTCUSDT","closedSize":"0","execFee":"0.02285465","execId":"2800474f-1e3d-571e-9cc8-46e3bcb82699","execPrice":"41553.9","execQty":"0.001","execType":"Trade","execValue":"41.5539","feeRate":"0.00055","tradeIv":"","markIv":"","blockTradeId":"","markPrice":"41547.63","indexPrice":"","underlyingPrice":"","leavesQty":"0","orderId":"e4385ca4-59cf-4ef8-aa34-61b7ad99ae84","orderLinkId":"SkeltonAgentlp9qlB-0001","orderPrice":"43607.8","orderQty":"0.001","orderType":"Market","stopOrderType":"UNKNOWN","side":"Buy","execTime":"1705761437503","isLeverage":"0","isMaker":false,"seq":8883610598,"marketUnit":"","createType":"CreateByUser"}"#;
+
+        let execution = serde_json::from_str::<BybitExecution>(message).unwrap();
+        let fill: Fill = (&execution).into();
+
+        assert_eq!(fill.time, 1705761437503000);
+        assert_eq!(fill.exec_id, "2800474f-1e3d-571e-9cc8-46e3bcb82699");
+        assert_eq!(fill.order_id, "e4385ca4-59cf-4ef8-aa34-61b7ad99ae84");
+        assert_eq!(fill.side, OrderSide::Buy);
+        assert_eq!(fill.get_qty(), 0.001);
+        assert_eq!(fill.get_fee(), 0.02285465);
+        assert!(!fill.is_maker);
+        assert!(fill.is_my_fill("SkeltonAgent"));
+    }
+
     #[test]
     fn test_bybit_order_and_execution() {
         let message = r#"
@@ -1413,6 +1842,119 @@ mod bybit_message_test {
         assert!(response.is_ok());
     }
 
+    #[test]
+    fn test_account_into_coins_with_blank_fields() -> anyhow::Result<()> {
+        const MESSAGE: &str = r#"
+        {"list":[{"coin":[
+            {"availableToBorrow":"","bonus":"0","accruedInterest":"0","availableToWithdraw":"10196.98720872","totalOrderIM":"12.1254","equity":"11667.94862481","totalPositionMM":"49.37769736","usdValue":"11671.04063119","unrealisedPnl":"1470.96141609","collateralSwitch":true,"spotHedgingQty":"0","borrowAmount":"0.000000000000000000","totalPositionIM":"903.04125483","walletBalance":"10196.98720872","cumRealisedPnl":"196.98720872","locked":"0","marginCollateral":true,"coin":"USDT"},
+            {"availableToBorrow":"","bonus":"","accruedInterest":"","availableToWithdraw":"","totalOrderIM":"","equity":"","totalPositionMM":"","usdValue":"","unrealisedPnl":"","collateralSwitch":false,"spotHedgingQty":"0","borrowAmount":"","totalPositionIM":"","walletBalance":"","cumRealisedPnl":"","locked":"","marginCollateral":true,"coin":"BTC"}
+        ]}]}
+        "#;
+
+        let response = serde_json::from_str::<BybitAccountResponse>(MESSAGE)?;
+        let coins: AccountCoins = response.into();
+
+        assert_eq!(coins.coins.len(), 2);
+        assert_eq!(coins.coins[0].symbol, "USDT");
+        assert_eq!(coins.coins[0].get_equity(), 11667.94862481);
+        assert_eq!(coins.coins[0].get_available(), 10196.98720872);
+
+        assert_eq!(coins.coins[1].symbol, "BTC");
+        assert_eq!(coins.coins[1].volume, dec![0.0]);
+        assert_eq!(coins.coins[1].free, dec![0.0]);
+        assert_eq!(coins.coins[1].locked, dec![0.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_position_message() {
+        const M: &str = r#"{"id":"1003076014fb7eedb-c7e6-45d6-a8c1-270f0169171a","topic":"position","creationTime":1697682317044,"data":[{"positionIdx":0,"tradeMode":0,"riskId":1,"riskLimitValue":"2000000","symbol":"BTCUSDT","side":"Buy","size":"0.01","entryPrice":"28184.5","leverage":"10","positionValue":"281.845","positionBalance":"0","markPrice":"28184.5","positionIM":"0","positionMM":"0","takeProfit":"0","stopLoss":"0","trailingStop":"0","unrealisedPnl":"0","cumRealisedPnl":"-25.06579337","sessionAvgPrice":"0","createdTime":"1694402496913","updatedTime":"1697682317038","tpslMode":"Full","liqPrice":"0","bustPrice":"","category":"linear","positionStatus":"Normal","adlRankIndicator":0,"autoAddMargin":0,"leverageSysUpdatedTime":"","mmrSysUpdatedTime":"","seq":8327597863,"isReduceOnly":false}]}"#;
+
+        let message = serde_json::from_str::<BybitUserWsMessage>(M);
+        println!("{:?}", message);
+        assert!(message.is_ok());
+
+        if let BybitUserWsMessage::message(BybitUserMessage::position { data, .. }) =
+            message.unwrap()
+        {
+            let position: PositionInfo = (&data[0]).into();
+            println!("{:?}", position);
+
+            assert_eq!(position.symbol, "BTCUSDT");
+            assert_eq!(position.side, OrderSide::Buy);
+            assert_eq!(position.size, dec![0.01]);
+            assert_eq!(position.avg_price, dec![28184.5]);
+            assert_eq!(position.leverage, dec![10]);
+            assert_eq!(position.position_idx, 0);
+            assert_eq!(position.update_time, 1697682317038_000);
+        } else {
+            assert!(false, "not a position message");
+        }
+    }
+
+    #[test]
+    fn test_parse_ticker_message() {
+        const SNAPSHOT: &str = r#"{"topic":"tickers.BTCUSDT","type":"snapshot","data":{"symbol":"BTCUSDT","tickDirection":"PlusTick","price24hPcnt":"0.017103","lastPrice":"17216.00","prevPrice24h":"16926.50","highPrice24h":"17281.50","lowPrice24h":"16915.00","prevPrice1h":"17238.00","markPrice":"17217.33","indexPrice":"17227.36","openInterest":"68744.761","openInterestValue":"1183601235.91","turnover24h":"1570383121.943499","volume24h":"91705.276","nextFundingTime":"1673280000000","fundingRate":"-0.000212","bid1Price":"17215.50","bid1Size":"84.489","ask1Price":"17216.00","ask1Size":"83.020"},"cs":24987956059,"ts":1673272861686}"#;
+        const DELTA: &str = r#"{"topic":"tickers.BTCUSDT","type":"delta","data":{"symbol":"BTCUSDT","markPrice":"17218.01","bid1Price":"17215.50"},"cs":24987956060,"ts":1673272861786}"#;
+
+        let message = serde_json::from_str::<BybitPublicWsMessage>(SNAPSHOT).unwrap();
+        let snapshot = match message.into() {
+            MultiMarketMessage::Ticker(ticker) => ticker,
+            m => panic!("not a ticker message {:?}", m),
+        };
+
+        let mut ticker = TickerInfo::default();
+        ticker.merge(&snapshot);
+
+        assert_eq!(ticker.symbol, "BTCUSDT");
+        assert_eq!(ticker.time, 1673272861686_000);
+        assert_eq!(ticker.mark_price, dec![17217.33]);
+        assert_eq!(ticker.index_price, dec![17227.36]);
+        assert_eq!(ticker.predicted_funding_rate, dec![-0.000212]);
+        assert_eq!(ticker.next_funding_time, 1673280000000_000);
+
+        let message = serde_json::from_str::<BybitPublicWsMessage>(DELTA).unwrap();
+        let delta: TickerUpdate = match message.into() {
+            MultiMarketMessage::Ticker(ticker) => ticker,
+            m => panic!("not a ticker message {:?}", m),
+        };
+        assert_eq!(delta.index_price, None);
+        assert_eq!(delta.predicted_funding_rate, None);
+
+        ticker.merge(&delta);
+        assert_eq!(ticker.mark_price, dec![17218.01]);
+        assert_eq!(ticker.index_price, dec![17227.36]);
+        assert_eq!(ticker.predicted_funding_rate, dec![-0.000212]);
+        assert_eq!(ticker.time, 1673272861786_000);
+
+        // funding rateが0になったdeltaは値として反映する
+        const ZERO_RATE: &str = r#"{"topic":"tickers.BTCUSDT","type":"delta","data":{"symbol":"BTCUSDT","fundingRate":"0"},"cs":24987956061,"ts":1673272861886}"#;
+        let message = serde_json::from_str::<BybitPublicWsMessage>(ZERO_RATE).unwrap();
+        let delta: TickerUpdate = match message.into() {
+            MultiMarketMessage::Ticker(ticker) => ticker,
+            m => panic!("not a ticker message {:?}", m),
+        };
+        assert_eq!(delta.predicted_funding_rate, Some(dec![0]));
+
+        ticker.merge(&delta);
+        assert_eq!(ticker.predicted_funding_rate, dec![0]);
+        assert_eq!(ticker.mark_price, dec![17218.01]);
+    }
+
+    #[test]
+    fn test_parse_position_update_avg_price() {
+        const M: &str = r#"{"symbol":"BTCUSDT","side":"","size":"0","avgPrice":"0","positionValue":"0","leavesValue":"0","riskId":1,"leverage":"10"}"#;
+
+        let position = serde_json::from_str::<BybitPositionUpdate>(M);
+        println!("{:?}", position);
+        assert!(position.is_ok());
+
+        let position: PositionInfo = (&position.unwrap()).into();
+        assert_eq!(position.side, OrderSide::Unknown);
+        assert_eq!(position.get_position(), dec![0]);
+    }
+
     #[test]
     fn test_parse_coin() {
         const M: &str = r#"{"availableToBorrow":"","bonus":"0","accruedInterest":"0","availableToWithdraw":"10196.98720872","totalOrderIM":"12.1254","equity":"11667.94862481","totalPositionMM":"49.37769736","usdValue":"11671.04063119","unrealisedPnl":"1470.96141609","collateralSwitch":true,"spotHedgingQty":"0","borrowAmount":"0.000000000000000000","totalPositionIM":"903.04125483","walletBalance":"10196.98720872","cumRealisedPnl":"196.98720872","locked":"0","marginCollateral":true,"coin":"USDT"}"#;
@@ -1423,6 +1965,44 @@ mod bybit_message_test {
         assert!(coins.is_ok());
     }
 
+    #[test]
+    fn test_parse_fee_rate() {
+        const M: &str = r#"{"list":[{"symbol":"BTCUSDT","baseCoin":"","takerFeeRate":"0.00055","makerFeeRate":"0.0002","makerMarkupRate":"","takerMarkupRate":""},{"symbol":"ETHUSDT","takerFeeRate":"0.0004","makerFeeRate":"-0.0001","makerMarkupRate":"0.00005","takerMarkupRate":"0.0001"},{"symbol":"SOLUSDT","takerFeeRate":"0.00055","makerFeeRate":"0.0002"}]}"#;
+
+        let fee = serde_json::from_str::<BybitFeeRateResponse>(M).unwrap();
+        assert_eq!(fee.list.len(), 3);
+
+        // markupが空文字
+        assert_eq!(fee.list[0].symbol, "BTCUSDT");
+        assert_eq!(fee.list[0].maker_fee_rate, dec![0.0002]);
+        assert_eq!(fee.list[0].taker_fee_rate, dec![0.00055]);
+        assert_eq!(fee.list[0].maker_markup_rate, dec![0]);
+        assert_eq!(fee.list[0].taker_markup_rate, dec![0]);
+
+        // makerはリベート(負の値)
+        assert_eq!(fee.list[1].maker_fee_rate, dec![-0.0001]);
+        assert_eq!(fee.list[1].maker_markup_rate, dec![0.00005]);
+
+        // markupの項目自体がない
+        assert_eq!(fee.list[2].taker_markup_rate, dec![0]);
+
+        let mut config = MarketConfig::default();
+        config.trade_category = "spot".to_string();
+        fee.list[1].apply(&mut config);
+        assert_eq!(config.maker_fee, dec![-0.0001]);
+        assert_eq!(config.taker_fee, dec![0.0004]);
+        assert_eq!(config.fee_type, FeeType::Both);
+    }
+
+    #[test]
+    fn test_server_time() {
+        let message = r#"{"timeSecond":"1688639403","timeNano":"1688639403423213947"}"#;
+        let time: BybitServerTime = serde_json::from_str(message).unwrap();
+
+        assert_eq!(time.time_second, 1688639403);
+        assert_eq!(time.to_microsec(), 1688639403423213);
+    }
+
     fn test_parse() {
         let message: &str = r#"
         {"retCode":0,"retMsg":"OK","result":{"list":[{"totalEquity":"11745.04972951","accountIMRate":"0.0779","totalMarginBalance":"11745.04972951","totalInitialMargin":"916.00037165","accountType":"UNIFIED","totalAvailableBalance":"10829.04935785","accountMMRate":"0.0042","totalPerpUPL":"1543.8483218","totalWalletBalance":"10201.2014077","accountLTV":"0","totalMaintenanceMargin":"50.14874128","coin":[{"availableToBorrow":"","bonus":"0","accruedInterest":"0","availableToWithdraw":"10191.91657171","totalOrderIM":"12.1254","equity":"11734.3597278","totalPositionMM":"49.37769736","usdValue":"11745.04972951","unrealisedPnl":"1542.44315609","collateralSwitch":true,"spotHedgingQty":"0","borrowAmount":"0.000000000000000000","totalPositionIM":"903.04125483","walletBalance":"10191.91657171","cumRealisedPnl":"191.91657171","locked":"0","marginCollateral":true,"coin":"USDT"},{"availableToBorrow":"","bonus":"","accruedInterest":"","availableToWithdraw":"","totalOrderIM":"","equity":"","totalPositionMM":"","usdValue":"","unrealisedPnl":"","collateralSwitch":false,"spotHedgingQty":"0","borrowAmount":"","totalPositionIM":"","walletBalance":"","cumRealisedPnl":"","locked":"","marginCollateral":true,"coin":"BTC"}]}]},"retExtInfo":{},"time":1708051591009}        
@@ -45,20 +45,23 @@ use anyhow::Context;
 use anyhow::Result;
 
 use rbot_lib::common::{
-    hmac_sign, msec_to_microsec, MarketConfig, MicroSec, Order, OrderSide, OrderStatus, OrderType,
-    ExchangeConfig, Trade, NOW,
+    hmac_sign, msec_to_microsec, DAYS, MarketConfig, MarketError, MicroSec, Order, OrderSide, OrderStatus, OrderType,
+    ExchangeConfig, TimeInForce, Trade, NOW, SERVER_NOW,
 };
 
 use rbot_lib::net::{rest_get, rest_post, RestApi};
 
 use crate::message::convert_coin_to_account_status;
 use crate::message::microsec_to_bybit_timestamp;
+use crate::message::{BybitClosedPnl, BybitClosedPnlResponse, BybitFeeRateResponse, BybitApiKeyInfo, BybitInstrumentsResponse, BybitServerTime, FeeTier};
 use crate::message::BybitAccountCoin;
 use crate::message::BybitAccountResponse;
 use crate::message::BybitAccountStatus;
-use crate::BYBIT_BOARD_DEPTH;
+use crate::bybit_board_depth;
 
 use super::config::BybitServerConfig;
+use super::config::PositionMode;
+use super::config::SpotMarginConfig;
 use super::message::BybitKlinesResponse;
 use super::message::BybitMultiOrderStatus;
 use super::message::BybitRestBoard;
@@ -77,6 +80,20 @@ struct BybitOrderRequest<'a> {
     pub order_link_id: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "positionIdx")]
+    pub position_idx: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: Option<String>,
+    // closeOnTriggerは条件付き注文(triggerPrice)の発動時にのみ効くので送らない。
+    // 条件付き注文は出さないため、ポジションを反転させない指定はreduceOnlyで足りる。
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(rename = "reduceOnly")]
+    pub reduce_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "isLeverage")]
+    pub is_leverage: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,6 +109,24 @@ struct BybitMultiOrderRestResponse {
     pub list: Vec<BybitOrderRestResponse>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SwitchPositionModeMessage {
+    category: String,
+    coin: String,
+    mode: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SwitchSpotMarginModeMessage {
+    #[serde(rename = "spotMarginMode")]
+    spot_margin_mode: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SetSpotMarginLeverageMessage {
+    leverage: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CancelOrderMessage {
     category: String,
@@ -100,16 +135,457 @@ struct CancelOrderMessage {
     order_id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AmendOrderMessage {
+    category: String,
+    symbol: String,
+    #[serde(rename = "orderId")]
+    order_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qty: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price: Option<Decimal>,
+}
+
+/// closed-pnlのstartTime〜endTimeの最大日数
+const CLOSED_PNL_MAX_DAYS: i64 = 7;
+const CLOSED_PNL_PAGE_LIMIT: i64 = 100;
+/// /v5/order/realtime の1ページの最大件数
+const OPEN_ORDERS_PAGE_LIMIT: usize = 50;
+
 pub struct BybitRestApi {
     server_config: ExchangeConfig,
+    position_mode: PositionMode,
+    spot_margin: Option<SpotMarginConfig>,
 }
 
 impl BybitRestApi {
+    /// interval_sec幅の4本足(1分〜1日)
+    pub async fn get_klines_with_interval(
+        &self,
+        config: &MarketConfig,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        interval_sec: i64,
+        page: &RestPage,
+    ) -> anyhow::Result<(Vec<Kline>, RestPage)> {
+        let start_time = FLOOR_SEC(start_time, interval_sec);
+        // 終わり時間は、TICKの範囲にふくまれていれば全体がかえってくる。
+        let end_time = FLOOR_SEC(end_time, interval_sec);
+
+        println!("kline start_time {:?} / end_time {:?}", time_string(start_time), time_string(end_time));
+
+        if start_time == end_time {
+            return Ok((vec![], RestPage::Done));
+        }
+
+        if *page == RestPage::Done {
+            return Err(anyhow!("call with RestPage::Done"));
+        }
+
+        if start_time == 0 || (end_time == 0) {
+            return Err(anyhow!(
+                "end_time({}) or start_time({}) is zero",
+                end_time,
+                start_time
+            ));
+        }
+
+        let end_time = if let RestPage::Time(t) = page {
+            t.clone() - 1           // 次のTick全体がかえってくるのをさける。
+        }
+        else {
+            end_time
+        };
+
+        let path = "/v5/market/kline";
+
+        // 分単位、1日は"D"
+        let klines_width = if interval_sec == 24 * 60 * 60 {
+            "D".to_string()
+        } else {
+            (interval_sec / 60).to_string()
+        };
+
+        let params = format!(
+            "category={}&symbol={}&interval={}&start={}&end={}&limit={}",
+            config.trade_category.as_str(),
+            config.trade_symbol.as_str(),
+            klines_width,
+            microsec_to_bybit_timestamp(start_time),
+            microsec_to_bybit_timestamp(end_time),
+            1000 // max records.
+        );
+
+        let r = Self::get(&self.server_config, path, &params).await;
+
+        if r.is_err() {
+            let r = r.unwrap_err();
+            return Err(r);
+        }
+
+        let message = r.unwrap().body;
+
+        let result = serde_json::from_value::<BybitKlinesResponse>(message)
+            .with_context(|| format!("parse error in try_get_trade_klines"))?;
+
+        let mut klines: Vec<Kline> = result.into();
+        klines.reverse();
+
+        let len = klines.len();
+
+        let page = if len == 0 || klines[0].timestamp <= start_time {
+            RestPage::Done
+        }
+        else {
+            RestPage::Time(klines[0].timestamp)
+        };
+        
+        return Ok((klines, page))
+    }
+
     pub fn new(server_config: &ExchangeConfig) -> Self {
         Self {
             server_config: server_config.clone(),
+            position_mode: PositionMode::default(),
+            spot_margin: None,
         }
     }
+
+    pub fn get_position_mode(&self) -> PositionMode {
+        self.position_mode
+    }
+
+    pub fn set_position_mode(&mut self, position_mode: PositionMode) {
+        self.position_mode = position_mode;
+    }
+
+    /// switch position mode of linear contracts settled in `coin`.
+    pub async fn switch_position_mode(
+        &self,
+        coin: &str,
+        position_mode: PositionMode,
+    ) -> anyhow::Result<()> {
+        let server = &self.server_config;
+
+        let message = SwitchPositionModeMessage {
+            category: "linear".to_string(),
+            coin: coin.to_string(),
+            mode: position_mode.to_bybit_mode(),
+        };
+
+        let message_json = serde_json::to_string(&message)?;
+        let path = "/v5/position/switch-mode";
+
+        Self::post_sign(&server, path, &message_json)
+            .await
+            .with_context(|| {
+                format!(
+                    "switch_position_mode: server={:?} / path={:?} / message_json={:?}",
+                    server, path, message_json
+                )
+            })?;
+
+        Ok(())
+    }
+
+    pub fn get_spot_margin(&self) -> Option<SpotMarginConfig> {
+        self.spot_margin
+    }
+
+    pub fn set_spot_margin(&mut self, spot_margin: Option<SpotMarginConfig>) {
+        self.spot_margin = spot_margin;
+    }
+
+    /// spotのマージン取引を有効/無効にし、有効時はレバレッジを設定する。
+    pub async fn switch_spot_margin_mode(
+        &self,
+        spot_margin: Option<SpotMarginConfig>,
+    ) -> anyhow::Result<()> {
+        let server = &self.server_config;
+
+        let message = SwitchSpotMarginModeMessage {
+            spot_margin_mode: if spot_margin.is_some() { "1" } else { "0" }.to_string(),
+        };
+
+        let message_json = serde_json::to_string(&message)?;
+        let path = "/v5/spot-margin-trade/switch-mode";
+
+        Self::post_sign(&server, path, &message_json)
+            .await
+            .with_context(|| {
+                format!(
+                    "switch_spot_margin_mode: server={:?} / path={:?} / message_json={:?}",
+                    server, path, message_json
+                )
+            })?;
+
+        let spot_margin = match spot_margin {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        let message = SetSpotMarginLeverageMessage {
+            leverage: spot_margin.leverage.to_string(),
+        };
+
+        let message_json = serde_json::to_string(&message)?;
+        let path = "/v5/spot-margin-trade/set-leverage";
+
+        Self::post_sign(&server, path, &message_json)
+            .await
+            .with_context(|| {
+                format!(
+                    "set_spot_margin_leverage: server={:?} / path={:?} / message_json={:?}",
+                    server, path, message_json
+                )
+            })?;
+
+        Ok(())
+    }
+
+    /// 確定損益の履歴(/v5/position/closed-pnl)。
+    /// 1回の問い合わせは7日以内なので期間を分割し、それぞれをnextPageCursorでページングする。
+    pub async fn closed_pnl(
+        &self,
+        category: &str,
+        symbol: &str,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<Vec<BybitClosedPnl>> {
+        let server = &self.server_config;
+        let path = "/v5/position/closed-pnl";
+
+        let end_time = if end_time == 0 { NOW() } else { end_time };
+
+        let mut result: Vec<BybitClosedPnl> = vec![];
+        let mut window_start = start_time;
+
+        while window_start < end_time {
+            let window_end = (window_start + DAYS(CLOSED_PNL_MAX_DAYS)).min(end_time);
+            let mut cursor = "".to_string();
+
+            loop {
+                let mut query_string = format!(
+                    "category={}&symbol={}&startTime={}&endTime={}&limit={}",
+                    category,
+                    symbol,
+                    microsec_to_bybit_timestamp(window_start),
+                    microsec_to_bybit_timestamp(window_end),
+                    CLOSED_PNL_PAGE_LIMIT
+                );
+                if cursor != "" {
+                    query_string += &format!("&cursor={}", cursor);
+                }
+
+                let response = Self::get_sign(&server, path, &query_string)
+                    .await
+                    .with_context(|| {
+                        format!("closed_pnl: path={:?} / query_string={:?}", path, query_string)
+                    })?;
+
+                let mut page = serde_json::from_value::<BybitClosedPnlResponse>(response.body)
+                    .with_context(|| format!("closed pnl parse error"))?;
+
+                let l = page.list.len();
+                result.append(&mut page.list);
+
+                if l == 0 || page.nextPageCursor == "" || page.nextPageCursor == cursor {
+                    break;
+                }
+                cursor = page.nextPageCursor;
+            }
+
+            window_start = window_end;
+        }
+
+        Ok(result)
+    }
+
+    /// 未約定の注文(/v5/order/realtime)をnextPageCursorが空になるまでページングしてすべて返す。
+    /// page_limitは1ページの件数(最大50)。
+    pub async fn all_open_orders(
+        &self,
+        config: &MarketConfig,
+        page_limit: usize,
+    ) -> anyhow::Result<Vec<Order>> {
+        let server = &self.server_config;
+        let path = "/v5/order/realtime";
+        let page_limit = page_limit.clamp(1, OPEN_ORDERS_PAGE_LIMIT);
+
+        let mut orders: Vec<Order> = vec![];
+        let mut cursor = "".to_string();
+
+        loop {
+            let mut query_string = format!(
+                "category={}&symbol={}&limit={}",
+                config.trade_category, config.trade_symbol, page_limit
+            );
+            if cursor != "" {
+                query_string += &format!("&cursor={}", cursor);
+            }
+
+            let result = Self::get_sign(&server, path, &query_string)
+                .await
+                .with_context(|| {
+                    format!(
+                        "open_orders: server={:?} / path={:?} / query_string={:?}",
+                        server, path, query_string
+                    )
+                })?;
+
+            log::debug!("result.body={:?}", result.body);
+            if result.body.is_null() {
+                break;
+            }
+
+            let response = serde_json::from_value::<BybitMultiOrderStatus>(result.body)
+                .with_context(|| format!("order status parse error"))?;
+
+            let next_cursor = response.nextPageCursor.clone();
+            let mut page: Vec<Order> = response.into();
+            let l = page.len();
+            orders.append(&mut page);
+
+            if l == 0 || next_cursor == "" || next_cursor == cursor {
+                break;
+            }
+            cursor = next_cursor;
+        }
+
+        for o in orders.iter_mut() {
+            o.update_balance(config);
+        }
+
+        Ok(orders)
+    }
+
+    /// アカウントのVIPレベルに応じた手数料(/v5/account/fee-rate)。
+    /// symbolが空文字の場合はcategoryの全銘柄を返す(spotは全銘柄取得不可)。
+    pub async fn fee_rate(&self, category: &str, symbol: &str) -> anyhow::Result<Vec<FeeTier>> {
+        let server = &self.server_config;
+        let path = "/v5/account/fee-rate";
+
+        let mut query_string = format!("category={}", category);
+        if symbol != "" {
+            query_string += &format!("&symbol={}", symbol);
+        }
+
+        let response = Self::get_sign(&server, path, &query_string)
+            .await
+            .with_context(|| format!("fee_rate: path={:?} / query_string={:?}", path, query_string))?;
+
+        ensure!(
+            response.is_success(),
+            format!("fee_rate error: code={}, msg={}", response.return_code, response.return_message)
+        );
+
+        let fee = serde_json::from_value::<BybitFeeRateResponse>(response.body)
+            .with_context(|| format!("fee rate parse error"))?;
+
+        Ok(fee.list)
+    }
+
+    pub(crate) fn make_order_request<'a>(
+        &self,
+        config: &MarketConfig,
+        side: OrderSide,
+        price: Option<Decimal>,
+        size: Decimal,
+        order_type: OrderType,
+        client_order_id: Option<&'a str>,
+        position_idx: Option<u8>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
+    ) -> BybitOrderRequest<'a> {
+        // 成行注文ではtimeInForceを送らない(取引所側でIOCになる)
+        let time_in_force = if order_type == OrderType::Market {
+            None
+        } else {
+            Some(time_in_force.to_string())
+        };
+
+        // spotのマージン取引時は借入を許可する
+        let is_leverage = if config.trade_category == "spot" && self.spot_margin.is_some() {
+            Some("1".to_string())
+        } else {
+            None
+        };
+
+        BybitOrderRequest {
+            category: config.trade_category.clone(),
+            symbol: config.trade_symbol.clone(),
+            side: side.to_string(),
+            order_type: order_type.to_string(),
+            qty: size,
+            order_link_id: client_order_id,
+            price: price,
+            position_idx: self.position_mode.position_idx(side, position_idx),
+            time_in_force: time_in_force,
+            reduce_only: reduce_only,
+            is_leverage: is_leverage,
+        }
+    }
+
+    fn make_amend_request(
+        &self,
+        config: &MarketConfig,
+        order_id: &str,
+        price: Option<Decimal>,
+        qty: Option<Decimal>,
+    ) -> AmendOrderMessage {
+        AmendOrderMessage {
+            category: config.trade_category.clone(),
+            symbol: config.trade_symbol.clone(),
+            order_id: order_id.to_string(),
+            qty: qty,
+            price: price,
+        }
+    }
+
+    /// 有効な指値注文`current`の価格・数量を変更する（キャンセルせずに板の順番を保つ）。
+    /// qtyは約定済みを含めた注文数量。side, order_type, create_timeは元の注文を引き継ぐ。
+    pub async fn amend_order(
+        &self,
+        config: &MarketConfig,
+        current: &Order,
+        price: Option<Decimal>,
+        qty: Option<Decimal>,
+    ) -> anyhow::Result<Order> {
+        let server = &self.server_config;
+
+        let message = self.make_amend_request(config, &current.order_id, price, qty);
+
+        let message_json = serde_json::to_string(&message)?;
+        let path = "/v5/order/amend";
+        let result = Self::post_sign(&server, path, &message_json)
+            .await
+            .with_context(|| {
+                format!(
+                    "amend_order: server={:?} / path={:?} / message_json={:?}",
+                    server, path, message_json
+                )
+            })?;
+
+        let r = serde_json::from_value::<BybitOrderRestResponse>(result.body)?;
+
+        let mut order = Order::default();
+
+        order.category = config.trade_category.clone();
+        order.symbol = config.trade_symbol.clone();
+        order.create_time = current.create_time;
+        order.status = OrderStatus::Amended;
+        order.order_id = r.order_id;
+        order.client_order_id = r.order_link_id;
+        order.order_side = current.order_side;
+        order.order_type = current.order_type;
+        order.order_price = price.unwrap_or(current.order_price);
+        order.order_size = qty.unwrap_or(current.order_size);
+        order.update_time = msec_to_microsec(result.time);
+        order.is_maker = true;
+
+        return Ok(order);
+    }
 }
 
 impl RestApi for BybitRestApi {
@@ -126,7 +602,7 @@ impl RestApi for BybitRestApi {
             "category={}&symbol={}&limit={}",
             config.trade_category.as_str(),
             config.trade_symbol.as_str(),
-            BYBIT_BOARD_DEPTH
+            bybit_board_depth(config)?
         );
 
         let r = Self::get(server, path, &params).await.with_context(|| {
@@ -188,75 +664,9 @@ impl RestApi for BybitRestApi {
         end_time: MicroSec,
         page: &RestPage,
     ) -> anyhow::Result<(Vec<Kline>, RestPage)> {
-        let start_time = FLOOR_SEC(start_time, self.klines_width());
-        // 終わり時間は、TICKの範囲にふくまれていれば全体がかえってくる。
-        let end_time = FLOOR_SEC(end_time, self.klines_width());
-
-        println!("kline start_time {:?} / end_time {:?}", time_string(start_time), time_string(end_time));
-
-        if start_time == end_time {
-            return Ok((vec![], RestPage::Done));
-        }
-
-        if *page == RestPage::Done {
-            return Err(anyhow!("call with RestPage::Done"));
-        }
-
-        if start_time == 0 || (end_time == 0) {
-            return Err(anyhow!(
-                "end_time({}) or start_time({}) is zero",
-                end_time,
-                start_time
-            ));
-        }
-
-        let end_time = if let RestPage::Time(t) = page {
-            t.clone() - 1           // 次のTick全体がかえってくるのをさける。
-        }
-        else {
-            end_time
-        };
-
-        let path = "/v5/market/kline";
-
-        let klines_width = self.klines_width() / 60;        // convert to min
-
-        let params = format!(
-            "category={}&symbol={}&interval={}&start={}&end={}&limit={}", // 1 min
-            config.trade_category.as_str(),
-            config.trade_symbol.as_str(),
-            klines_width, // interval 1 min.
-            microsec_to_bybit_timestamp(start_time),
-            microsec_to_bybit_timestamp(end_time),
-            1000 // max records.
-        );
-
-        let r = Self::get(&self.server_config, path, &params).await;
-
-        if r.is_err() {
-            let r = r.unwrap_err();
-            return Err(r);
-        }
-
-        let message = r.unwrap().body;
-
-        let result = serde_json::from_value::<BybitKlinesResponse>(message)
-            .with_context(|| format!("parse error in try_get_trade_klines"))?;
-
-        let mut klines: Vec<Kline> = result.into();
-        klines.reverse();
-
-        let len = klines.len();
-
-        let page = if len == 0 || klines[0].timestamp <= start_time {
-            RestPage::Done
-        }
-        else {
-            RestPage::Time((klines[0].timestamp))
-        };
-        
-        return Ok((klines, page))
-      }
+        self.get_klines_with_interval(config, start_time, end_time, self.klines_width(), page)
+            .await
+    }
 
     fn klines_width(&self) -> i64 {
         60
@@ -270,6 +680,34 @@ impl RestApi for BybitRestApi {
         size: Decimal,
         order_type: OrderType,
         client_order_id: Option<&str>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
+    ) -> anyhow::Result<Vec<Order>> {
+        self.new_order_with_position_idx(
+            config,
+            side,
+            price,
+            size,
+            order_type,
+            client_order_id,
+            None,
+            time_in_force,
+            reduce_only,
+        )
+        .await
+    }
+
+    async fn new_order_with_position_idx(
+        &self,
+        config: &MarketConfig,
+        side: OrderSide,
+        price: Decimal, // when order_type is Market, this value is ignored.
+        size: Decimal,
+        order_type: OrderType,
+        client_order_id: Option<&str>,
+        position_idx: Option<u8>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>> {
         let server = &self.server_config;
 
@@ -282,15 +720,18 @@ impl RestApi for BybitRestApi {
             Some(price)
         };
 
-        let order = BybitOrderRequest {
-            category: category.clone(),
-            symbol: symbol.clone(),
-            side: side.to_string(),
-            order_type: order_type.to_string(),
-            qty: size,
-            order_link_id: client_order_id,
-            price: price,
-        };
+        let order = self.make_order_request(
+            config,
+            side,
+            price,
+            size,
+            order_type,
+            client_order_id,
+            position_idx,
+            time_in_force,
+            reduce_only,
+        );
+        let position_idx = order.position_idx;
 
         let order_json = serde_json::to_string(&order)?;
         log::debug!("order_json={}", order_json);
@@ -330,6 +771,8 @@ impl RestApi for BybitRestApi {
         order.remain_size = size;
         order.update_time = msec_to_microsec(result.time);
         order.is_maker = is_maker;
+        order.position_idx = position_idx.unwrap_or(0) as i64;
+        order.reduce_only = reduce_only;
 
         order.update_balance(&config);
 
@@ -378,39 +821,38 @@ impl RestApi for BybitRestApi {
     }
 
     async fn open_orders(&self, config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
-        let server = &self.server_config;
+        self.all_open_orders(config, OPEN_ORDERS_PAGE_LIMIT).await
+    }
 
+    /// https://bybit-exchange.github.io/docs/v5/order/order-list
+    async fn get_order_status(&self, config: &MarketConfig, order_id: &str) -> anyhow::Result<Order> {
+        let server = &self.server_config;
+        let path = "/v5/order/history";
         let query_string = format!(
-            "category={}&symbol={}&limit=50",
-            config.trade_category, config.trade_symbol
+            "category={}&symbol={}&orderId={}",
+            config.trade_category, config.trade_symbol, order_id
         );
 
-        let path = "/v5/order/realtime";
-
         let result = Self::get_sign(&server, path, &query_string)
             .await
-            .with_context(|| {
-                format!(
-                    "open_orders: server={:?} / path={:?} / query_string={:?}",
-                    server, path, query_string
-                )
-            })?;
+            .with_context(|| format!("get_order_status: path={:?} / query_string={:?}", path, query_string))?;
 
-        log::debug!("result.body={:?}", result.body);
-        if result.body.is_null() {
-            return Ok(vec![]);
-        }
+        ensure!(
+            result.is_success(),
+            format!("get_order_status error: code={}, msg={}", result.return_code, result.return_message)
+        );
 
         let response = serde_json::from_value::<BybitMultiOrderStatus>(result.body)
             .with_context(|| format!("order status parse error"))?;
+        let orders: Vec<Order> = response.into();
 
-        let mut orders: Vec<Order> = response.into();
-
-        for o in orders.iter_mut() {
-            o.update_balance(config);
-        }
+        let mut order = orders
+            .into_iter()
+            .find(|o| o.order_id == order_id)
+            .ok_or_else(|| anyhow!("order not found in history: {}", order_id))?;
+        order.update_balance(config);
 
-        Ok(orders)
+        Ok(order)
     }
 
     async fn get_account(&self) -> anyhow::Result<AccountCoins> {
@@ -447,6 +889,67 @@ impl RestApi for BybitRestApi {
         Ok(coins)
     }
 
+    /// https://bybit-exchange.github.io/docs/v5/market/time
+    async fn get_server_time(&self) -> anyhow::Result<MicroSec> {
+        let response = Self::get(&self.server_config, "/v5/market/time", "")
+            .await
+            .with_context(|| format!("get_server_time error"))?;
+
+        ensure!(
+            response.is_success(),
+            format!("get_server_time error: code={}, msg={}", response.return_code, response.return_message)
+        );
+
+        let time = serde_json::from_value::<BybitServerTime>(response.body)?;
+
+        Ok(time.to_microsec())
+    }
+
+    async fn get_api_permissions(&self) -> anyhow::Result<Vec<String>> {
+        let path = "/v5/user/query-api";
+
+        let response = Self::get_sign(&self.server_config, path, "")
+            .await
+            .with_context(|| format!("get_api_permissions error"))?;
+
+        ensure!(
+            response.is_success(),
+            format!("get_api_permissions error: code={}, msg={}", response.return_code, response.return_message)
+        );
+
+        let info = serde_json::from_value::<BybitApiKeyInfo>(response.body)?;
+
+        Ok(info.permission_list())
+    }
+
+    async fn get_instrument_info(&self, config: &MarketConfig) -> anyhow::Result<MarketConfig> {
+        let path = "/v5/market/instruments-info";
+        let query_string = format!("category={}&symbol={}", config.trade_category, config.trade_symbol);
+
+        let response = Self::get(&self.server_config, path, &query_string)
+            .await
+            .with_context(|| format!("get_instrument_info: path={:?} / query_string={:?}", path, query_string))?;
+
+        ensure!(
+            response.is_success(),
+            format!("get_instrument_info error: code={}, msg={}", response.return_code, response.return_message)
+        );
+
+        let instruments = serde_json::from_value::<BybitInstrumentsResponse>(response.body)
+            .with_context(|| format!("instruments-info parse error"))?;
+
+        let info = instruments
+            .list
+            .iter()
+            .find(|i| i.symbol == config.trade_symbol)
+            .ok_or_else(|| anyhow!("instrument not found: {}", config.trade_symbol))?;
+
+        let mut config = config.clone();
+        info.apply(&mut config);
+
+        Ok(config)
+    }
+
     fn history_web_url(&self, config: &MarketConfig, date: MicroSec) -> String {
         let web_base = self.server_config.get_historical_web_base();
 
@@ -483,13 +986,30 @@ impl RestApi for BybitRestApi {
         let mut size = df.column("size")?.clone();
         size.rename(KEY::size);
 
-        let df = DataFrame::new(vec![timestamp, side, price, size, id])?;
+        // tickDirectionはアグレッサー判定(KEY::tick_direction)として末尾に残す
+        let tick_direction: Vec<Option<i64>> = df
+            .column("tickDirection")?
+            .str()?
+            .into_iter()
+            .map(|t| t.and_then(bybit_tick_direction))
+            .collect();
+        let tick_direction = Series::new(KEY::tick_direction, tick_direction);
 
+        let df = DataFrame::new(vec![timestamp, side, price, size, id, tick_direction])?;
 
         Ok(df)
     }
 }
 
+/// PlusTick/ZeroPlusTickは買い(1)、MinusTick/ZeroMinusTickは売り(-1)
+fn bybit_tick_direction(tick: &str) -> Option<i64> {
+    match tick {
+        "PlusTick" | "ZeroPlusTick" => Some(1),
+        "MinusTick" | "ZeroMinusTick" => Some(-1),
+        _ => None,
+    }
+}
+
 impl BybitRestApi {
     async fn get(
         server: &ExchangeConfig,
@@ -500,6 +1020,7 @@ impl BybitRestApi {
 
         let response = rest_get(&server.get_public_api(), &query, vec![], None, None)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| format!("rest_get error: {}/{}", &server.get_public_api(), &query))?;
 
         Self::parse_rest_response(response)
@@ -510,7 +1031,7 @@ impl BybitRestApi {
         path: &str,
         query_string: &str,
     ) -> anyhow::Result<BybitRestResponse> {
-        let timestamp = format!("{}", NOW() / 1_000);
+        let timestamp = format!("{}", SERVER_NOW(&server.get_exchange_name()) / 1_000);
         let api_key = server.get_api_key().extract();
         let api_secret = server.get_api_secret().extract();
         let recv_window = "5000";
@@ -533,6 +1054,7 @@ impl BybitRestApi {
 
         let result = rest_get(&server.get_public_api(), path, headers, Some(query_string), None)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| {
                 format!(
                     "get_sign error: {}/{}/{}",
@@ -548,7 +1070,7 @@ impl BybitRestApi {
         path: &str,
         body: &str,
     ) -> anyhow::Result<BybitRestResponse> {
-        let timestamp = format!("{}", NOW() / 1_000);
+        let timestamp = format!("{}", SERVER_NOW(&server.get_exchange_name()) / 1_000);
         let api_key = server.get_api_key().extract();
         let api_secret = server.get_api_secret().extract();
         let recv_window = "5000";
@@ -566,6 +1088,7 @@ impl BybitRestApi {
 
         let response = rest_post(&server.get_public_api(), path, headers, &body)
             .await
+            .map_err(MarketError::forbidden_as_rate_limited)
             .with_context(|| format!("post_sign error {}/{}", server.get_public_api(), path))?;
 
         Self::parse_rest_response(response)
@@ -589,10 +1112,10 @@ impl BybitRestApi {
         let result = from_str::<BybitRestResponse>(&response)
             .with_context(|| format!("parse error in parse_rest_response: {:?}", response))?;
 
-        ensure!(
-            result.is_success(),
-            format!("parse rest response error = {}", result.return_message)
-        );
+        if !result.is_success() {
+            return Err(anyhow::Error::new(result.to_market_error())
+                .context(format!("parse rest response error = {}", result.return_message)));
+        }
 
         return Ok(result);
     }
@@ -605,14 +1128,253 @@ impl BybitRestApi {
 #[allow(unused_variables)]
 mod bybit_rest_test {
     use super::*;
-    use rbot_lib::common::{init_log, DAYS};
+    use rbot_lib::common::{init_log, MarketError, DAYS};
+    use rbot_lib::net::mock::{request_line, MockHttpServer};
     use std::{any, thread::sleep, time::Duration};
 
+    fn closed_pnl_item(order_id: &str) -> String {
+        format!(
+            r#"{{"symbol":"BTCUSDT","orderType":"Market","leverage":"10","updatedTime":"1724371260000","side":"Sell","orderId":"{}","closedPnl":"12.5","avgEntryPrice":"64000","qty":"0.01","cumEntryValue":"640","createdTime":"1724371200000","orderPrice":"63000","closedSize":"0.01","avgExitPrice":"65250","execType":"Trade","fillCount":"2","cumExitValue":"652.5"}}"#,
+            order_id
+        )
+    }
+
+    /// cursorなしの問い合わせには2件とnextPageCursor=page2、cursor=page2には1件と空のcursorを返す
+    fn closed_pnl_handler() -> impl FnMut(&str) -> String + Send + 'static {
+        let mut order_no = 0;
+
+        move |request| {
+            let (count, next_cursor) = if request_line(request).contains("cursor=page2") {
+                (1, "")
+            } else {
+                (2, "page2")
+            };
+
+            let list: Vec<String> = (0..count)
+                .map(|_| {
+                    order_no += 1;
+                    closed_pnl_item(&format!("order-{}", order_no))
+                })
+                .collect();
+
+            format!(
+                r#"{{"retCode":0,"retMsg":"OK","result":{{"nextPageCursor":"{}","category":"linear","list":[{}]}},"retExtInfo":{{}},"time":1724371300000}}"#,
+                next_cursor,
+                list.join(",")
+            )
+        }
+    }
+
+    fn open_order_item(order_id: &str) -> String {
+        format!(
+            r#"{{"symbol":"BTCUSDT","orderType":"Limit","orderLinkId":"","slLimitPrice":"0","orderId":"{}","cancelType":"UNKNOWN","avgPrice":"","stopOrderType":"","lastPriceOnCreated":"43634.9","orderStatus":"New","createType":"CreateByUser","takeProfit":"","cumExecValue":"0","tpslMode":"","smpType":"None","triggerDirection":0,"blockTradeId":"","isLeverage":"","rejectReason":"EC_NoError","price":"40000","orderIv":"","createdTime":"1704539202055","tpTriggerBy":"","positionIdx":0,"timeInForce":"GTC","leavesValue":"40","updatedTime":"1704539202058","side":"Buy","smpGroup":0,"triggerPrice":"","tpLimitPrice":"0","cumExecFee":"0","leavesQty":"0.001","slTriggerBy":"","closeOnTrigger":false,"placeType":"","cumExecQty":"0","reduceOnly":false,"qty":"0.001","stopLoss":"","marketUnit":"","smpOrderId":"","triggerBy":""}}"#,
+            order_id
+        )
+    }
+
+    /// cursorなし => page2 => page3 => 空 の3ページ(各2件)を返す
+    fn open_orders_handler(request: &str) -> String {
+        let line = request_line(request);
+
+        let (page, next_cursor) = if line.contains("cursor=page3") {
+            (3, "")
+        } else if line.contains("cursor=page2") {
+            (2, "page3")
+        } else {
+            (1, "page2")
+        };
+
+        let list: Vec<String> = (0..2)
+            .map(|i| open_order_item(&format!("order-{}-{}", page, i)))
+            .collect();
+
+        format!(
+            r#"{{"retCode":0,"retMsg":"OK","result":{{"nextPageCursor":"{}","category":"linear","list":[{}]}},"retExtInfo":{{}},"time":1704541422547}}"#,
+            next_cursor,
+            list.join(",")
+        )
+    }
+
+    #[tokio::test]
+    async fn test_health_check() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(|request| {
+            let line = request_line(request);
+            if line.contains("/v5/market/time") {
+                let now = rbot_lib::common::NOW();
+                format!(
+                    r#"{{"retCode":0,"retMsg":"OK","result":{{"timeSecond":"{}","timeNano":"{}"}},"retExtInfo":{{}},"time":{}}}"#,
+                    now / 1_000_000,
+                    now * 1_000,
+                    now / 1_000
+                )
+            } else {
+                r#"{"retCode":0,"retMsg":"","result":{"id":"13770661","note":"test","apiKey":"xxxxx","readOnly":0,"secret":"","permissions":{"ContractTrade":["Order","Position"],"Spot":["SpotTrade"],"Wallet":[]},"ips":["*"],"type":1},"retExtInfo":{},"time":1697525990798}"#.to_string()
+            }
+        })
+        .await;
+        let url = mock.url();
+
+        let server = ExchangeConfig::new("bybit", false, &url, &url, "ws://localhost", "ws://localhost", "");
+        let api = BybitRestApi::new(&server);
+
+        // WebSocketは接続できないので0
+        let status = rbot_market::health_check(&api, "ws://127.0.0.1:1").await?;
+
+        assert!(status.rest_latency_ms < 5_000);
+        assert!(status.server_time_delta_ms.abs() < 5_000);
+        assert_eq!(status.ws_latency_ms, 0);
+        assert!(status.api_key_valid);
+        assert_eq!(
+            status.permissions,
+            vec!["ContractTrade.Order", "ContractTrade.Position", "Spot.SpotTrade"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rest_error() {
+        let response = r#"{"retCode":10006,"retMsg":"Too many visits!","result":{},"retExtInfo":{},"time":1672712468011}"#;
+        let error = BybitRestApi::parse_rest_response(response.to_string()).unwrap_err();
+        assert_eq!(
+            MarketError::find(&error),
+            Some(&MarketError::RateLimited("Too many visits!".to_string()))
+        );
+
+        let response = r#"{"retCode":110007,"retMsg":"ab not enough for new order","result":{},"retExtInfo":{},"time":1672712468011}"#;
+        let error = BybitRestApi::parse_rest_response(response.to_string()).unwrap_err();
+        assert_eq!(
+            MarketError::find(&error),
+            Some(&MarketError::Exchange { code: 110007, msg: "ab not enough for new order".to_string() })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_instrument_info() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(|request| {
+            let line = request_line(request);
+            if line.contains("category=spot") {
+                r#"{"retCode":0,"retMsg":"OK","result":{"category":"spot","list":[{"symbol":"BTCUSDT","baseCoin":"BTC","quoteCoin":"USDT","status":"Trading","lotSizeFilter":{"basePrecision":"0.000001","quotePrecision":"0.00000001","minOrderQty":"0.000048","maxOrderQty":"71.73956243","minOrderAmt":"1","maxOrderAmt":"2000000"},"priceFilter":{"tickSize":"0.01"}}]},"retExtInfo":{},"time":1672712468011}"#.to_string()
+            } else {
+                r#"{"retCode":0,"retMsg":"","result":{"category":"linear","list":[{"symbol":"BTCUSDT","contractType":"LinearPerpetual","status":"Trading","baseCoin":"BTC","quoteCoin":"USDT","priceScale":"2","priceFilter":{"minPrice":"0.10","maxPrice":"199999.80","tickSize":"0.10"},"lotSizeFilter":{"maxOrderQty":"100.000","minOrderQty":"0.001","qtyStep":"0.001","postOnlyMaxOrderQty":"1000.000"}}],"nextPageCursor":""},"retExtInfo":{},"time":1672712495660}"#.to_string()
+            }
+        })
+        .await;
+        let url = mock.url();
+
+        let server = ExchangeConfig::new("bybit", false, &url, &url, "ws://localhost", "ws://localhost", "");
+        let api = BybitRestApi::new(&server);
+
+        let mut config = MarketConfig::default();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = "BTCUSDT".to_string();
+
+        let linear = api.get_instrument_info(&config).await?;
+        assert_eq!(linear.price_unit, dec![0.1]);
+        assert_eq!(linear.size_unit, dec![0.001]);
+        assert_eq!(linear.min_size, dec![0.001]);
+        assert_eq!(linear.contract_size, dec![1]);
+
+        // spotはqtyStepが無いのでbasePrecisionを使う
+        config.trade_category = "spot".to_string();
+        let spot = api.get_instrument_info(&config).await?;
+        assert_eq!(spot.price_unit, dec![0.01]);
+        assert_eq!(spot.size_unit, dec![0.000001]);
+        assert_eq!(spot.min_size, dec![0.000048]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_orders_pagination() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(open_orders_handler).await;
+        let url = mock.url();
+        let server = ExchangeConfig::new("bybit", false, &url, &url, "ws://localhost", "ws://localhost", "");
+        let api = BybitRestApi::new(&server);
+
+        let mut config = MarketConfig::default();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = "BTCUSDT".to_string();
+
+        let orders = api.all_open_orders(&config, 100).await?;
+
+        let ids: Vec<String> = orders.iter().map(|o| o.order_id.clone()).collect();
+        assert_eq!(
+            ids,
+            vec!["order-1-0", "order-1-1", "order-2-0", "order-2-1", "order-3-0", "order-3-1"]
+        );
+
+        let log = mock.request_lines();
+        assert_eq!(log.len(), 3);
+        assert!(log[0].contains("limit=50")); // 最大50に丸める
+        assert!(!log[0].contains("cursor="));
+        assert!(log[1].contains("cursor=page2"));
+        assert!(log[2].contains("cursor=page3"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_closed_pnl_pagination() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(closed_pnl_handler()).await;
+        let url = mock.url();
+        let server = ExchangeConfig::new("bybit", false, &url, &url, "ws://localhost", "ws://localhost", "");
+        let api = BybitRestApi::new(&server);
+
+        // 10日 => 7日 + 3日の2区間、それぞれ2ページ
+        let start = 1724371200_000_000;
+        let pnl = api.closed_pnl("linear", "BTCUSDT", start, start + DAYS(10)).await?;
+
+        assert_eq!(pnl.len(), 6);
+        assert_eq!(pnl[0].orderId, "order-1");
+        assert_eq!(pnl[2].orderId, "order-3");
+        assert_eq!(pnl[0].closedPnl, dec![12.5]);
+
+        let log = mock.request_lines();
+        assert_eq!(log.len(), 4);
+        assert!(!log[0].contains("cursor="));
+        assert!(log[1].contains("cursor=page2"));
+        assert!(log[2].contains(&format!("startTime={}", (start + DAYS(7)) / 1_000)));
+        assert!(!log[2].contains("cursor="));
+
+        let df = crate::message::closed_pnl_to_df(&pnl)?;
+        assert_eq!(df.shape(), (6, 13));
+        assert_eq!(df.get_column_names()[0], "symbol");
+        assert_eq!(df.column("created_time")?.i64()?.get(0), Some(1724371200_000_000));
+
+        Ok(())
+    }
+
     use crate::config::BybitConfig;
     use pyo3::ffi::Py_Initialize;
     use rbot_lib::common::{init_debug_log, time_string, HHMM};
     use rust_decimal_macros::dec;
 
+    #[test]
+    fn test_logdf_to_archivedf_tick_direction() -> anyhow::Result<()> {
+        let server_config = BybitServerConfig::new(false);
+        let api = BybitRestApi::new(&server_config);
+
+        // timestamp,symbol,side,size,price,tickDirection,trdMatchID,...
+        let df = polars::df![
+            "timestamp" => [1724371200.1234, 1724371200.5, 1724371201.0],
+            "symbol" => ["BTCUSDT", "BTCUSDT", "BTCUSDT"],
+            "side" => ["Buy", "Sell", "Sell"],
+            "size" => [0.01, 0.02, 0.03],
+            "price" => [64000.0, 63999.5, 63999.5],
+            "tickDirection" => ["PlusTick", "MinusTick", "ZeroMinusTick"],
+            "trdMatchID" => ["a", "b", "c"]
+        ]?;
+
+        let archive_df = api.logdf_to_archivedf(&df)?;
+        assert_eq!(archive_df.get_column_names()[5], KEY::tick_direction);
+
+        let tick: Vec<Option<i64>> = archive_df.column(KEY::tick_direction)?.i64()?.into_iter().collect();
+        assert_eq!(tick, vec![Some(1), Some(-1), Some(-1)]);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn get_board_snapshot_test() -> anyhow::Result<()> {
         let server_config = BybitServerConfig::new(false);
@@ -703,6 +1465,264 @@ mod bybit_rest_test {
 
 
 
+    #[test]
+    fn test_order_request_position_idx() -> anyhow::Result<()> {
+        let server_config = BybitServerConfig::new(false);
+        let config = BybitConfig::BTCUSDT();
+        let mut api = BybitRestApi::new(&server_config);
+
+        // one-way mode: positionIdx is omitted
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert!(json.get("positionIdx").is_none());
+
+        // hedge mode: buy => 1, sell => 2
+        api.set_position_mode(PositionMode::HedgeBothSide);
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["positionIdx"], 1);
+
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Sell,
+            None,
+            dec![0.001],
+            OrderType::Market,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["positionIdx"], 2);
+
+        // explicit position_idx wins
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Sell,
+            None,
+            dec![0.001],
+            OrderType::Market,
+            None,
+            Some(1),
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["positionIdx"], 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_amend_request() -> anyhow::Result<()> {
+        let server_config = BybitServerConfig::new(false);
+        let config = BybitConfig::BTCUSDT();
+        let api = BybitRestApi::new(&server_config);
+
+        let message = api.make_amend_request(
+            &config,
+            "order-1",
+            Some(dec![40000.5]),
+            Some(dec![0.002]),
+        );
+        let json = serde_json::to_value(&message)?;
+        assert_eq!(json["category"], config.trade_category.as_str());
+        assert_eq!(json["symbol"], config.trade_symbol.as_str());
+        assert_eq!(json["orderId"], "order-1");
+        assert_eq!(json["price"], serde_json::to_value(dec![40000.5])?);
+        assert_eq!(json["qty"], serde_json::to_value(dec![0.002])?);
+
+        // 数量を変えない場合はqtyを送らない
+        let message = api.make_amend_request(&config, "order-1", Some(dec![40000.5]), None);
+        let json = serde_json::to_value(&message)?;
+        assert!(json.get("qty").is_none());
+        assert_eq!(json.as_object().unwrap().len(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_amend_order_keeps_side() -> anyhow::Result<()> {
+        let mock = MockHttpServer::start(|_request| {
+            r#"{"retCode":0,"retMsg":"OK","result":{"orderId":"order-1","orderLinkId":"agent-1"},"retExtInfo":{},"time":1704541422547}"#.to_string()
+        })
+        .await;
+        let url = mock.url();
+
+        let server = ExchangeConfig::new("bybit", false, &url, &url, "ws://localhost", "ws://localhost", "");
+        let api = BybitRestApi::new(&server);
+
+        let mut config = MarketConfig::default();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = "BTCUSDT".to_string();
+
+        let mut current = Order::default();
+        current.order_id = "order-1".to_string();
+        current.order_side = OrderSide::Sell;
+        current.order_type = OrderType::Limit;
+        current.order_price = dec![40000];
+        current.order_size = dec![0.002];
+        current.create_time = 1704541400000000;
+
+        let order = api.amend_order(&config, &current, Some(dec![40100]), None).await?;
+
+        assert_eq!(order.status, OrderStatus::Amended);
+        assert_eq!(order.order_side, OrderSide::Sell);
+        assert_eq!(order.order_type, OrderType::Limit);
+        assert_eq!(order.order_price, dec![40100]);
+        assert_eq!(order.order_size, dec![0.002]);
+        assert_eq!(order.create_time, current.create_time);
+        assert_eq!(order.client_order_id, "agent-1");
+
+        assert!(mock.request_lines()[0].starts_with("POST /v5/order/amend "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_order_request_time_in_force() -> anyhow::Result<()> {
+        let server_config = BybitServerConfig::new(false);
+        let config = BybitConfig::BTCUSDT();
+        let api = BybitRestApi::new(&server_config);
+
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::PostOnly,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["timeInForce"], "PostOnly");
+
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::IOC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["timeInForce"], "IOC");
+
+        // 成行ではtimeInForceを送らない
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Sell,
+            None,
+            dec![0.001],
+            OrderType::Market,
+            None,
+            None,
+            TimeInForce::FOK,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert!(json.get("timeInForce").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_order_request_spot_margin() -> anyhow::Result<()> {
+        let server_config = BybitServerConfig::new(false);
+        let mut config = BybitConfig::BTCUSDT();
+        config.trade_category = "spot".to_string();
+        let mut api = BybitRestApi::new(&server_config);
+
+        // マージン無効時はisLeverageを送らない
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert!(json.get("isLeverage").is_none());
+
+        api.set_spot_margin(Some(SpotMarginConfig::new(3)));
+
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["isLeverage"], "1");
+
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Sell,
+            None,
+            dec![0.001],
+            OrderType::Market,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert_eq!(json["isLeverage"], "1");
+
+        // linearには付けない
+        let config = BybitConfig::BTCUSDT();
+        let order = api.make_order_request(
+            &config,
+            OrderSide::Buy,
+            Some(dec![40000.0]),
+            dec![0.001],
+            OrderType::Limit,
+            None,
+            None,
+            TimeInForce::GTC,
+            false,
+        );
+        let json = serde_json::to_value(&order)?;
+        assert!(json.get("isLeverage").is_none());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_new_limit_order() {
         let server_config = BybitServerConfig::new(false);
@@ -717,6 +1737,8 @@ mod bybit_rest_test {
                 dec![0.001],
                 OrderType::Limit,
                 None,
+                TimeInForce::GTC,
+                false,
             )
             .await;
 
@@ -738,6 +1760,8 @@ mod bybit_rest_test {
                 dec![0.001],
                 OrderType::Market,
                 None,
+                TimeInForce::GTC,
+                false,
             )
             .await;
 
@@ -759,6 +1783,8 @@ mod bybit_rest_test {
                 dec![0.001],
                 OrderType::Limit,
                 None,
+                TimeInForce::GTC,
+                false,
             )
             .await;
 
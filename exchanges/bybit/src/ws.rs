@@ -1,5 +1,8 @@
 #![allow(unused)]
 use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_stream::stream;
 use futures::Stream;
@@ -9,6 +12,8 @@ use rbot_lib::common::AccountPair;
 use rbot_lib::common::ControlMessage;
 use rbot_lib::common::MarketMessage;
 use rbot_lib::common::Order;
+use rbot_lib::common::Fill;
+use rbot_lib::common::PositionInfo;
 use rbot_lib::common::MARKET_HUB;
 use rbot_lib::net::BroadcastMessage;
 use rbot_lib::net::ReceiveMessage;
@@ -16,10 +21,11 @@ use rbot_lib::net::WebSocketClient;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
-use rbot_lib::common::{hmac_sign, MarketConfig, MultiMarketMessage, ExchangeConfig, NOW};
+use rbot_lib::common::{hmac_sign, time_string, MarketConfig, MicroSec, MultiMarketMessage, ExchangeConfig, NOW, SEC};
 
-use rbot_lib::net::{AutoConnectClient, WsOpMessage};
+use rbot_lib::net::{AutoConnectClient, RawMessageHook, WsOpMessage, WsStateHandle};
 use tokio::task::JoinHandle;
+use tokio::time::sleep;
 
 use crate::message::convert_coin_to_account_status;
 use crate::message::merge_order_and_execution;
@@ -29,6 +35,7 @@ use crate::message::BybitPublicWsMessage;
 use crate::message::BybitUserMessage;
 use crate::message::BybitUserWsMessage;
 use crate::BybitConfig;
+use crate::{bybit_board_depth, bybit_ws_channels, BYBIT_BOARD_DEPTH};
 
 use super::config::BybitServerConfig;
 
@@ -36,6 +43,12 @@ const PING_INTERVAL_SEC: i64 = 30; // 30 sec
 const SWITCH_INTERVAL_SEC: i64 = 60 * 3; // 3min for test
 const SYNC_WAIT_RECORDS: i64 = 0; // no overlap
 
+/// private streamの認証は30分で切れるため、25分毎に同じ接続で再認証する
+pub const AUTH_REFRESH_INTERVAL_SEC: u64 = 60 * 25;
+/// サーバのpingが途絶えたと判断するまでの時間
+pub const HEARTBEAT_TIMEOUT_SEC: i64 = 60;
+const PONG_MESSAGE: &str = r#"{"op":"pong"}"#;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BybitWsOpMessage {
     pub op: String,
@@ -98,10 +111,16 @@ impl WebSocketClient for BybitPublicWsClient {
             None,
         );
 
-        public_ws.subscribe(&vec![
-            format!("publicTrade.{}", &config.trade_symbol),
-            format!("orderbook.200.{}", &config.trade_symbol)
-        ]).await;
+        let channels = bybit_ws_channels(config).unwrap_or_else(|e| {
+            log::error!("{:?}, use default channels (depth={})", e, BYBIT_BOARD_DEPTH);
+            vec![
+                format!("publicTrade.{}", &config.trade_symbol),
+                format!("orderbook.{}.{}", BYBIT_BOARD_DEPTH, &config.trade_symbol),
+                format!("tickers.{}", &config.trade_symbol),
+            ]
+        });
+
+        public_ws.subscribe(&channels).await;
 
         Self {
             ws: public_ws,
@@ -150,10 +169,18 @@ impl WebSocketClient for BybitPublicWsClient {
             }
         }
     }
+
+    fn set_raw_message_hook(&mut self, hook: Option<RawMessageHook>) {
+        self.ws.set_raw_message_hook(hook);
+    }
+
+    fn set_state_handle(&mut self, state: WsStateHandle) {
+        self.ws.set_state_handle(state);
+    }
 }
     
 impl BybitPublicWsClient {
-    fn public_url(server: &ExchangeConfig, config: &MarketConfig) -> String {
+    pub fn public_url(server: &ExchangeConfig, config: &MarketConfig) -> String {
         format!(
             "{}/{}",
             server.get_public_ws_server(),
@@ -178,7 +205,23 @@ impl BybitPublicWsClient {
 }
 
 pub struct BybitPrivateWsClient {
+    server: ExchangeConfig,
     ws: AutoConnectClient<BybitWsOpMessage>,
+    /// 最後にサーバから{"op":"ping"}を受け取った時刻(0は未受信)
+    last_ping_time: Arc<AtomicI64>,
+    auth_handler: Option<JoinHandle<()>>,
+    heartbeat_handler: Option<JoinHandle<()>>,
+}
+
+impl Drop for BybitPrivateWsClient {
+    fn drop(&mut self) {
+        if let Some(h) = self.auth_handler.take() {
+            h.abort();
+        }
+        if let Some(h) = self.heartbeat_handler.take() {
+            h.abort();
+        }
+    }
 }
 
 impl BybitPrivateWsClient {
@@ -201,10 +244,72 @@ impl BybitPrivateWsClient {
                 "execution".to_string(),
                 "order".to_string(),
                 "wallet".to_string(),
+                "position".to_string(),
             ])
             .await;
 
-        Self { ws: private_ws }
+        Self {
+            server: server.clone(),
+            ws: private_ws,
+            last_ping_time: Arc::new(AtomicI64::new(0)),
+            auth_handler: None,
+            heartbeat_handler: None,
+        }
+    }
+
+    /// 接続を切らずに新しい署名でauthを送り直す
+    pub async fn refresh_auth(&mut self) -> anyhow::Result<()> {
+        self.ws
+            .writer()
+            .send_text(&Self::make_auth_message(&self.server))
+            .await
+    }
+
+    pub fn last_ping_time(&self) -> MicroSec {
+        self.last_ping_time.load(Ordering::Relaxed)
+    }
+
+    /// interval毎にrefresh_authするタスクを開始する
+    pub fn start_auth_refresh(&mut self, interval: Duration) {
+        let writer = self.ws.writer();
+        let server = self.server.clone();
+
+        if let Some(h) = self.auth_handler.take() {
+            h.abort();
+        }
+
+        self.auth_handler = Some(tokio::task::spawn(async move {
+            loop {
+                sleep(interval).await;
+
+                let r = writer.send_text(&Self::make_auth_message(&server)).await;
+                log::info!("refresh private ws auth");
+                if r.is_err() {
+                    log::error!("Failed to refresh auth: {:?}", r);
+                }
+            }
+        }));
+    }
+
+    /// サーバからのpingがHEARTBEAT_TIMEOUT_SEC以上途絶えていないか監視する。
+    /// pongの返信はopen_streamの中で受信時にすぐ行う。
+    pub fn start_heartbeat(&mut self) {
+        let last_ping_time = self.last_ping_time.clone();
+
+        if let Some(h) = self.heartbeat_handler.take() {
+            h.abort();
+        }
+
+        self.heartbeat_handler = Some(tokio::task::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(HEARTBEAT_TIMEOUT_SEC as u64)).await;
+
+                let last = last_ping_time.load(Ordering::Relaxed);
+                if last != 0 && last + SEC(HEARTBEAT_TIMEOUT_SEC) < NOW() {
+                    log::warn!("no ping from server since {}", time_string(last));
+                }
+            }
+        }));
     }
 
     fn make_auth_message(server: &ExchangeConfig) -> String {
@@ -222,13 +327,23 @@ impl BybitPrivateWsClient {
         message.to_string()
     }
 
+    pub fn set_raw_message_hook(&mut self, hook: Option<RawMessageHook>) {
+        self.ws.set_raw_message_hook(hook);
+    }
+
     pub async fn connect(&mut self) {
-        self.ws.connect().await
+        self.ws.connect().await;
+
+        self.start_auth_refresh(Duration::from_secs(AUTH_REFRESH_INTERVAL_SEC));
+        self.start_heartbeat();
     }
 
     pub async fn open_stream<'a>(
         &'a mut self,
     ) -> impl Stream<Item = Result<MultiMarketMessage, String>> + 'a {
+        let writer = self.ws.writer();
+        let last_ping_time = self.last_ping_time.clone();
+
         let mut s = Box::pin(self.ws.open_stream().await);
 
         stream! {
@@ -257,6 +372,19 @@ impl BybitPrivateWsClient {
                                         BybitUserWsMessage::pong(p) => {
                                             log::debug!("pong message: {:?}", p);
                                         }
+                                        BybitUserWsMessage::ping(p) => {
+                                            if p.op == "ping" {
+                                                last_ping_time.store(NOW(), Ordering::Relaxed);
+
+                                                let r = writer.send_text(PONG_MESSAGE).await;
+                                                if r.is_err() {
+                                                    log::error!("Failed to send pong: {:?}", r);
+                                                }
+                                            }
+                                            else {
+                                                log::debug!("unknown op message: {:?}", p);
+                                            }
+                                        }
                                         BybitUserWsMessage::message(m) => {
                                             match m {
                                                 BybitUserMessage::order {
@@ -282,6 +410,9 @@ impl BybitPrivateWsClient {
                                                     mut data,
                                                 } => {
                                                     println!("{}", serde_json::to_string(&data).unwrap().to_string());
+                                                    let fills: Vec<Fill> = data.iter().map(|e| e.into()).collect();
+                                                    yield Ok(MultiMarketMessage::Fill(fills));
+
                                                     if last_executions.len() == 0 {
                                                         last_executions.append(&mut data);
                                                     }
@@ -305,6 +436,14 @@ impl BybitPrivateWsClient {
                                                     }
                                                     yield Ok(MultiMarketMessage::Account(coins));
                                                 }
+                                                BybitUserMessage::position {
+                                                    id,
+                                                    creationTime,
+                                                    data,
+                                                } => {
+                                                    let positions: Vec<PositionInfo> = data.iter().map(|p| p.into()).collect();
+                                                    yield Ok(MultiMarketMessage::PositionUpdate(positions));
+                                                }
                                             }
                                         }
                                     }
@@ -351,6 +490,91 @@ mod bybit_ws_test {
 
     use super::BybitPrivateWsClient;
 
+    use std::time::Duration;
+
+    use futures::SinkExt;
+    use rbot_lib::common::ExchangeConfig;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    /// authに成功を返し、subscribeの後に{"op":"ping"}を送るwebsocketサーバ。
+    /// 受け取ったtextはすべてchannelへ流す。
+    async fn start_mock_ws_server() -> (String, UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (tx, rx) = unbounded_channel();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(socket).await.unwrap();
+
+            let mut ping_sent = false;
+            while let Some(Ok(message)) = ws.next().await {
+                if let Message::Text(text) = message {
+                    if text.contains("\"auth\"") && !ping_sent {
+                        ws.send(Message::Text(
+                            r#"{"success":true,"ret_msg":"","op":"auth","conn_id":"mock"}"#.to_string(),
+                        ))
+                        .await
+                        .unwrap();
+                    } else if text.contains("\"subscribe\"") {
+                        ws.send(Message::Text(r#"{"op":"ping"}"#.to_string()))
+                            .await
+                            .unwrap();
+                        ping_sent = true;
+                    }
+
+                    let _ = tx.send(text);
+                }
+            }
+        });
+
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_bybit_user_ws_pong_and_refresh_auth() -> anyhow::Result<()> {
+        let (url, mut received) = start_mock_ws_server().await;
+        let server = ExchangeConfig::new("bybit", false, "http://localhost", "http://localhost", &url, &url, "");
+
+        let mut ws = BybitPrivateWsClient::new(&server).await;
+        ws.connect().await;
+        assert_eq!(ws.last_ping_time(), 0);
+
+        {
+            let mut stream = Box::pin(ws.open_stream().await);
+
+            let pong = tokio::time::timeout(Duration::from_secs(1), async {
+                loop {
+                    tokio::select! {
+                        text = received.recv() => {
+                            let text = text.unwrap();
+                            if text.contains("pong") {
+                                return text;
+                            }
+                        }
+                        _ = stream.next() => {}
+                    }
+                }
+            })
+            .await?;
+
+            assert_eq!(pong, r#"{"op":"pong"}"#);
+        }
+
+        assert!(0 < ws.last_ping_time());
+
+        // 同じ接続で再認証する
+        ws.refresh_auth().await?;
+
+        let auth = tokio::time::timeout(Duration::from_secs(1), received.recv())
+            .await?
+            .unwrap();
+        assert!(auth.contains("\"op\":\"auth\""));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_bybit_public_ws() {
         init_debug_log();
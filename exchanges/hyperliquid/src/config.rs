@@ -0,0 +1,115 @@
+#![allow(non_snake_case)]
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use pyo3::{pyclass, pymethods};
+
+use rbot_lib::common::{ExchangeConfig, MarketConfig};
+
+use crate::HYPERLIQUID;
+
+/// see https://hyperliquid.gitbook.io/hyperliquid-docs/for-developers/api
+
+#[derive(Clone, Debug)]
+#[pyclass]
+pub struct HyperliquidServerConfig {
+}
+
+impl HyperliquidServerConfig {
+    pub fn new(production: bool) -> ExchangeConfig {
+        let rest_server = if production {
+            "https://api.hyperliquid.xyz"
+        } else {
+            "https://api.hyperliquid-testnet.xyz"
+        };
+
+        let ws_server = if production {
+            "wss://api.hyperliquid.xyz/ws"
+        } else {
+            "wss://api.hyperliquid-testnet.xyz/ws"
+        };
+
+        // 日次の約定アーカイブは公開されていない
+        ExchangeConfig::new(
+            HYPERLIQUID,
+            production,
+            rest_server,
+            rest_server,
+            ws_server,
+            ws_server,
+            "",
+        )
+    }
+}
+
+/// 発注用の認証情報。wallet_addressは残高・注文照会の対象、private_keyは署名に使う鍵(API walletでも可)。
+/// wallet_addressが空の場合はprivate_keyのアドレスを使う。
+#[derive(Clone)]
+#[pyclass]
+pub struct HyperliquidConfig {
+    #[pyo3(get, set)]
+    pub wallet_address: String,
+    #[pyo3(set)]
+    pub private_key: String,
+    #[pyo3(get, set)]
+    pub is_mainnet: bool,
+}
+
+#[pymethods]
+impl HyperliquidConfig {
+    #[new]
+    #[pyo3(signature = (wallet_address="", private_key="", is_mainnet=true))]
+    pub fn new(wallet_address: &str, private_key: &str, is_mainnet: bool) -> Self {
+        return HyperliquidConfig {
+            wallet_address: wallet_address.to_string(),
+            private_key: private_key.to_string(),
+            is_mainnet: is_mainnet,
+        };
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "{{wallet_address: {}, private_key: {}, is_mainnet: {}}}",
+            self.wallet_address,
+            if self.private_key.is_empty() { "" } else { "*****" },
+            self.is_mainnet
+        )
+    }
+
+    #[classattr]
+    pub fn BTC() -> MarketConfig {
+        ExchangeConfig::open_exchange_market("hyperliquid", "BTC/USDC:USDC").unwrap()
+    }
+
+    #[classattr]
+    pub fn ETH() -> MarketConfig {
+        ExchangeConfig::open_exchange_market("hyperliquid", "ETH/USDC:USDC").unwrap()
+    }
+
+    #[classattr]
+    pub fn SOL() -> MarketConfig {
+        ExchangeConfig::open_exchange_market("hyperliquid", "SOL/USDC:USDC").unwrap()
+    }
+}
+
+#[cfg(test)]
+mod hyperliquid_config_test {
+    use super::*;
+
+    #[test]
+    fn test_perp_config() {
+        let config = HyperliquidConfig::BTC();
+
+        assert_eq!(config.trade_category, "linear");
+        assert_eq!(config.trade_symbol, "BTC");
+
+        let server = HyperliquidServerConfig::new(true);
+        assert_eq!(server.get_public_ws_server(), "wss://api.hyperliquid.xyz/ws");
+    }
+
+    #[test]
+    fn test_credential_config() {
+        let config = HyperliquidConfig::new("0xabc", "0x01", false);
+        assert!(!config.__repr__().contains("0x01"));
+        assert!(!config.is_mainnet);
+    }
+}
@@ -0,0 +1,25 @@
+mod config;
+mod rest;
+mod message;
+mod ws;
+mod market;
+mod signer;
+
+pub use config::*;
+pub use rest::*;
+pub use message::*;
+pub use ws::*;
+pub use market::*;
+pub use signer::*;
+
+/// ローカルに保持する板の深さ。l2Bookは各サイド最大20件のスナップショットで配信される。
+pub const HYPERLIQUID_BOARD_DEPTH: u32 = 200;
+
+/// 購読時に深さを指定できないため、board_depthはローカルの板を切り詰める幅としてのみ使う。
+pub fn hyperliquid_board_depth(config: &rbot_lib::common::MarketConfig) -> u32 {
+    if config.board_depth == 0 {
+        HYPERLIQUID_BOARD_DEPTH
+    } else {
+        config.board_depth
+    }
+}
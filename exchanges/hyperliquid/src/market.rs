@@ -0,0 +1,822 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{anyhow, Context};
+use futures::StreamExt;
+use pyo3_polars::PyDataFrame;
+use pyo3_polars::PyLazyFrame;
+use rbot_blockon::BLOCK_ON;
+use rbot_lib::common::BoardItem;
+use rbot_lib::common::{AccountCoins, Coin, Order, OrderType, TimeInForce};
+use rbot_lib::common::MarketConfig;
+use rbot_lib::common::to_py_err;
+use rbot_lib::common::BoardEventStream;
+use rbot_lib::common::MarketMessage;
+use rbot_lib::common::MarketStream;
+use rbot_lib::common::MicroSec;
+use rbot_lib::common::MultiMarketMessage;
+use rbot_lib::common::OrderBook;
+use rbot_lib::common::MARKET_HUB;
+use rbot_lib::common::{ExchangeConfig, DAYS, FLOOR_DAY, NOW};
+use rbot_lib::db::{
+    CacheStats, ColumnStyle, CsvSchema, Ohlcvb, SpreadLogger, TradeDataFrame, SPREAD_LOG_DEFAULT_INTERVAL_MS, TimeFormat, GAP_REPORT_ALLOW_SIZE,
+};
+use rbot_lib::net::{
+    make_py_raw_message_hook, stop_stream_task, stream_stop_signal, BroadcastMessage,
+    RawMessageHook, WebSocketClient as _, WsStateHandle, STREAM_STOP_TIMEOUT_SEC,
+};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use tokio::task::JoinHandle;
+
+use rbot_market::{extract_or_generate_config, MarketImpl, OrderInterfaceImpl, OhlcvStream, TradeStream, TRADE_DEDUP_WINDOW};
+
+use crate::HyperliquidConfig;
+use crate::HyperliquidPublicWsClient;
+use crate::HyperliquidRestApi;
+use crate::HyperliquidServerConfig;
+use crate::hyperliquid_board_depth;
+
+use pyo3::prelude::*;
+
+pub const HYPERLIQUID: &str = "HYPERLIQUID";
+
+/// 発注にはHyperliquidConfigでウォレットのアドレスと秘密鍵を指定する。
+/// 指定しない場合は環境変数のAPI_KEY(アドレス)/API_SECRET(秘密鍵)を使う。
+#[pyclass]
+pub struct Hyperliquid {
+    production: bool,
+    enable_order: bool,
+    server_config: ExchangeConfig,
+    api: HyperliquidRestApi,
+}
+
+#[pymethods]
+impl Hyperliquid {
+    #[new]
+    #[pyo3(signature = (production=false, config=None))]
+    pub fn new(production: bool, config: Option<HyperliquidConfig>) -> anyhow::Result<Self> {
+        let (production, api) = match config {
+            Some(config) if !config.private_key.is_empty() => {
+                let server_config = HyperliquidServerConfig::new(config.is_mainnet);
+                let api = HyperliquidRestApi::with_credentials(
+                    &server_config,
+                    &config.wallet_address,
+                    &config.private_key,
+                    config.is_mainnet,
+                )?;
+
+                (config.is_mainnet, api)
+            }
+            _ => (
+                production,
+                HyperliquidRestApi::new(&HyperliquidServerConfig::new(production)),
+            ),
+        };
+
+        Ok(Self {
+            production: production,
+            enable_order: false,
+            server_config: HyperliquidServerConfig::new(production),
+            api: api,
+        })
+    }
+
+    #[getter]
+    fn get_production(&self) -> bool {
+        self.server_config.is_production()
+    }
+
+    #[getter]
+    fn get_wallet_address(&self) -> String {
+        self.api.get_wallet_address()
+    }
+
+    pub fn open_market(&self, config: &PyAny) -> anyhow::Result<HyperliquidMarket> {
+        let config = extract_or_generate_config(&self.server_config.get_exchange_name(), config)?;
+
+        Ok(HyperliquidMarket::new(&self.server_config, &config))
+    }
+
+    //--- OrderInterfaceImpl ----
+    #[setter]
+    pub fn set_enable_order_with_my_own_risk(&mut self, enable_order: bool) {
+        self.set_enable_order_feature(enable_order);
+    }
+
+    #[getter]
+    pub fn get_enable_order_with_my_own_risk(&self) -> bool {
+        self.get_enable_order_feature()
+    }
+
+    /// FOKは未対応。PostOnlyはALOとして発注する。
+    #[pyo3(signature = (market_config, side, price, size, client_order_id=None, time_in_force=None, reduce_only=false, dry_run=false))]
+    pub fn limit_order(
+        &self,
+        market_config: &MarketConfig,
+        side: &str,
+        price: Decimal,
+        size: Decimal,
+        client_order_id: Option<&str>,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
+        dry_run: bool,
+    ) -> PyResult<Vec<Order>> {
+        if dry_run {
+            return Ok(vec![BLOCK_ON(async {
+                OrderInterfaceImpl::validate_order(
+                    self,
+                    market_config,
+                    side,
+                    price,
+                    size,
+                    OrderType::Limit,
+                    client_order_id,
+                    time_in_force.unwrap_or_default(),
+                    reduce_only,
+                )
+                .await
+            }).map_err(to_py_err)?]);
+        }
+
+        BLOCK_ON(async {
+            OrderInterfaceImpl::limit_order(
+                self,
+                market_config,
+                side,
+                price,
+                size,
+                client_order_id,
+                time_in_force,
+                reduce_only,
+            )
+            .await
+        }).map_err(to_py_err)
+    }
+
+    /// 成行注文はないため、板の最良値から5%不利な価格のIOC指値で発注する。
+    #[pyo3(signature = (market_config, side, size, client_order_id=None, reduce_only=false, dry_run=false))]
+    pub fn market_order(
+        &self,
+        market_config: &MarketConfig,
+        side: &str,
+        size: Decimal,
+        client_order_id: Option<&str>,
+        reduce_only: bool,
+        dry_run: bool,
+    ) -> PyResult<Vec<Order>> {
+        if dry_run {
+            return Ok(vec![BLOCK_ON(async {
+                OrderInterfaceImpl::validate_order(
+                    self,
+                    market_config,
+                    side,
+                    dec![0.0],
+                    size,
+                    OrderType::Market,
+                    client_order_id,
+                    TimeInForce::GTC,
+                    reduce_only,
+                )
+                .await
+            }).map_err(to_py_err)?]);
+        }
+
+        BLOCK_ON(async {
+            OrderInterfaceImpl::market_order(
+                self,
+                market_config,
+                side,
+                size,
+                client_order_id,
+                reduce_only,
+            )
+            .await
+        }).map_err(to_py_err)
+    }
+
+    pub fn cancel_order(
+        &self,
+        market_config: &MarketConfig,
+        order_id: &str,
+    ) -> PyResult<Order> {
+        BLOCK_ON(async { OrderInterfaceImpl::cancel_order(self, market_config, order_id).await }).map_err(to_py_err)
+    }
+
+    pub fn get_open_orders(&self, market_config: &MarketConfig) -> PyResult<Vec<Order>> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_open_orders(self, market_config).await }).map_err(to_py_err)
+    }
+
+    pub fn get_order_status(&self, market_config: &MarketConfig, order_id: &str) -> PyResult<Order> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_order_status(self, market_config, order_id).await }).map_err(to_py_err)
+    }
+
+    #[getter]
+    pub fn get_account(&self) -> PyResult<AccountCoins> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_account(self).await }).map_err(to_py_err)
+    }
+
+    pub fn balances(&self) -> anyhow::Result<Vec<Coin>> {
+        BLOCK_ON(async { OrderInterfaceImpl::get_balances(self).await })
+    }
+
+    pub fn __str__(&self) -> String {
+        format!(
+            "{{production: {}, server_config: {:?} }}",
+            self.production, self.server_config
+        )
+    }
+}
+
+impl OrderInterfaceImpl<HyperliquidRestApi> for Hyperliquid {
+    fn get_restapi(&self) -> &HyperliquidRestApi {
+        &self.api
+    }
+
+    fn set_enable_order_feature(&mut self, enable_order: bool) {
+        self.enable_order = enable_order;
+    }
+
+    fn get_enable_order_feature(&self) -> bool {
+        self.enable_order
+    }
+
+    /// 約定・注文の更新はuserEventsの購読が必要(未対応)
+    async fn async_start_user_stream(&mut self) -> anyhow::Result<()> {
+        Err(anyhow!("user stream is not supported in hyperliquid"))
+    }
+}
+
+#[pyclass]
+pub struct HyperliquidMarket {
+    server_config: ExchangeConfig,
+    config: MarketConfig,
+    api: HyperliquidRestApi,
+    pub db: Arc<Mutex<TradeDataFrame>>,
+    pub board: Arc<RwLock<OrderBook>>,
+    pub public_handler: Option<JoinHandle<()>>,
+    public_stop: Option<tokio::sync::watch::Sender<bool>>,
+    raw_message_hook: Option<RawMessageHook>,
+    spread_logger: Option<SpreadLogger>,
+    ws_state: WsStateHandle,
+}
+
+#[pymethods]
+impl HyperliquidMarket {
+    #[new]
+    pub fn new(server_config: &ExchangeConfig, config: &MarketConfig) -> Self {
+        log::debug!("open market HyperliquidMarket::new");
+        BLOCK_ON(async { Self::async_new(server_config, config).await.unwrap() })
+    }
+
+    #[getter]
+    fn get_config(&self) -> MarketConfig {
+        MarketImpl::get_config(self)
+    }
+
+    #[getter]
+    fn get_start_time(&mut self) -> MicroSec {
+        MarketImpl::start_time(self)
+    }
+
+    #[getter]
+    fn get_end_time(&mut self) -> MicroSec {
+        MarketImpl::end_time(self)
+    }
+
+    #[getter]
+    fn get_archive_info(&self) -> anyhow::Result<(MicroSec, MicroSec)> {
+        MarketImpl::get_archive_info(self)
+    }
+
+    #[getter]
+    fn get_db_info(&self) -> anyhow::Result<(MicroSec, MicroSec)> {
+        MarketImpl::get_db_info(self)
+    }
+
+    #[pyo3(signature = (start_time, end_time, column_style=ColumnStyle::Default))]
+    fn select_trades(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        column_style: ColumnStyle,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::select_trades(self, start_time, end_time, column_style)
+    }
+
+    fn _select_db_trades(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::select_db_trades(self, start_time, end_time)
+    }
+
+    fn select_large_trades(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::select_large_trades(self, start_time, end_time, min_size)
+    }
+
+    fn ohlcvv(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::ohlcvv(self, start_time, end_time, window_sec)
+    }
+
+    fn ohlcv_large(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::ohlcv_large(self, start_time, end_time, window_sec, min_size)
+    }
+
+    /// ohlcvの遅延評価版。filterなどを重ねてからcollect()する。
+    fn ohlcv_lazy(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+    ) -> anyhow::Result<PyLazyFrame> {
+        MarketImpl::ohlcv_lazy(self, start_time, end_time, window_sec)
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, column_style=ColumnStyle::Default, day_start_offset_sec=0))]
+    fn ohlcv(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        column_style: ColumnStyle,
+        day_start_offset_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::ohlcv(
+            self,
+            start_time,
+            end_time,
+            window_sec,
+            column_style,
+            day_start_offset_sec,
+        )
+    }
+
+    #[pyo3(signature = (start_time, end_time, window_sec, absolute=false))]
+    fn cvd(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        absolute: bool,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::cvd(self, start_time, end_time, window_sec, absolute)
+    }
+
+    fn vap(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        price_unit: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::vap(self, start_time, end_time, price_unit)
+    }
+
+    #[getter]
+    fn get_cache_hit_rate(&self) -> f64 {
+        MarketImpl::cache_hit_rate(self)
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        MarketImpl::cache_stats(self)
+    }
+
+    #[getter]
+    fn get_cache_max_rows(&self) -> usize {
+        MarketImpl::get_cache_max_rows(self)
+    }
+
+    /// キャッシュの最大行数（0は無制限）。超えた分は古いデータから捨てる。
+    #[setter]
+    fn set_cache_max_rows(&mut self, max_rows: usize) -> anyhow::Result<()> {
+        MarketImpl::set_cache_max_rows(self, max_rows)
+    }
+
+    fn get_board_json(&self, size: usize) -> anyhow::Result<String> {
+        MarketImpl::get_board_json(self, size)
+    }
+
+    /// 以降の板の更新ごとのBoardDiff(add/cancel/modify)を受け取る
+    fn board_events(&self) -> BoardEventStream {
+        MarketImpl::board_events(self)
+    }
+
+    #[getter]
+    fn get_board(&mut self) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
+        BLOCK_ON(async { MarketImpl::async_get_board(self).await })
+    }
+
+    #[getter]
+    fn get_board_vec(&self) -> anyhow::Result<(Vec<BoardItem>, Vec<BoardItem>)> {
+        MarketImpl::get_board_vec(self)
+    }
+
+    /// 板をshape(2, depth, 2)のnumpy配列で返す(薄い板は0埋め)
+    fn get_board_tensor(&self, py: Python, depth: usize) -> PyObject {
+        MarketImpl::get_board_tensor(self, py, depth)
+    }
+
+    fn get_board_grouped(&self, group: Decimal) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
+        MarketImpl::get_board_grouped(self, group)
+    }
+
+    /// `async for trade in market.trade_stream():`
+    #[pyo3(signature = (dedup_window=TRADE_DEDUP_WINDOW))]
+    fn trade_stream(&self, dedup_window: usize) -> anyhow::Result<TradeStream> {
+        MarketImpl::trade_stream(self, dedup_window)
+    }
+
+    /// `async for bar in market.subscribe_ohlcv(60):`
+    #[pyo3(signature = (window_sec, dedup_window=TRADE_DEDUP_WINDOW))]
+    fn subscribe_ohlcv(&self, window_sec: i64, dedup_window: usize) -> anyhow::Result<OhlcvStream> {
+        MarketImpl::subscribe_ohlcv(self, window_sec, dedup_window)
+    }
+
+    #[getter]
+    fn get_db_thread_running(&self) -> bool {
+        MarketImpl::is_db_thread_running(self)
+    }
+
+    fn restart_db_thread(&mut self) -> anyhow::Result<()> {
+        MarketImpl::restart_db_thread(self)
+    }
+
+    #[pyo3(signature = (path, *, time_col=0, price_col=1, size_col=2, side_col=None, id_col=None, time_format=TimeFormat::UnixMilliseconds, has_header=true))]
+    fn import_csv(
+        &mut self,
+        path: &str,
+        time_col: usize,
+        price_col: usize,
+        size_col: usize,
+        side_col: Option<usize>,
+        id_col: Option<usize>,
+        time_format: TimeFormat,
+        has_header: bool,
+    ) -> anyhow::Result<u64> {
+        let schema = CsvSchema {
+            time_col,
+            time_format,
+            price_col,
+            size_col,
+            side_col,
+            id_col,
+            has_header,
+        };
+
+        MarketImpl::import_csv(self, path, schema)
+    }
+
+    #[getter]
+    fn get_edge_price(&mut self) -> anyhow::Result<(Decimal, Decimal)> {
+        BLOCK_ON(async { MarketImpl::async_get_edge_price(self).await })
+    }
+
+    /// 板の最良気配(best bid/ask, mid, spread)をinterval_msごとにspread_logへ記録する
+    #[pyo3(signature = (interval_ms=SPREAD_LOG_DEFAULT_INTERVAL_MS))]
+    fn start_spread_logging(&mut self, interval_ms: u64) -> anyhow::Result<()> {
+        BLOCK_ON(async { MarketImpl::async_start_spread_logging(self, interval_ms).await })
+    }
+
+    fn stop_spread_logging(&mut self) {
+        BLOCK_ON(async { MarketImpl::async_stop_spread_logging(self).await })
+    }
+
+    #[pyo3(signature = (start_time=0, end_time=0))]
+    fn get_spread_history(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::get_spread_history(self, start_time, end_time)
+    }
+
+    /// OHLCVにspread(best_ask - best_bid)を加えた足。_repr_html_でspreadの幅を描く。
+    #[pyo3(signature = (start_time, end_time, window_sec=60))]
+    fn ohlcvb(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<Ohlcvb> {
+        MarketImpl::ohlcvb(self, start_time, end_time, window_sec)
+    }
+
+    /// 直近ndays分のDBの空白区間[(start, end)]
+    #[pyo3(signature = (ndays, allow_size=GAP_REPORT_ALLOW_SIZE))]
+    fn gap_chunks(&self, ndays: i64, allow_size: MicroSec) -> anyhow::Result<Vec<(MicroSec, MicroSec)>> {
+        MarketImpl::gap_chunks(self, ndays, allow_size)
+    }
+
+    /// Kyle's lambda(window_sec幅の足ごとの価格変化 ~ 符号付き出来高 の回帰係数)
+    fn kyles_lambda(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<f64> {
+        MarketImpl::kyles_lambda(self, start_time, end_time, window_sec)
+    }
+
+    /// step_secごとのKyle's lambda
+    fn rolling_kyles_lambda(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        step_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::rolling_kyles_lambda(self, start_time, end_time, window_sec, step_sec)
+    }
+
+    /// 約定代金がthresholdに達するごとに区切った足(dollar bar)
+    #[pyo3(signature = (start_time, end_time, threshold=Decimal::from(1_000_000)))]
+    fn dollar_bars(&mut self, start_time: MicroSec, end_time: MicroSec, threshold: Decimal) -> anyhow::Result<PyDataFrame> {
+        MarketImpl::dollar_bars(self, start_time, end_time, threshold)
+    }
+
+    fn _repr_html_(&self) -> String {
+        MarketImpl::_repr_html_(self)
+    }
+
+    /// 日次アーカイブがないため、ndays分は1分足から生成した仮想約定で埋め、直近は約定APIとWSで取得する。
+    #[pyo3(signature = (ndays, *, connect_ws=false, verbose=false))]
+    fn download(&mut self, ndays: i64, connect_ws: bool, verbose: bool) -> anyhow::Result<()> {
+        BLOCK_ON(async {
+            if 0 < ndays {
+                MarketImpl::_async_download_range_virtual(
+                    self,
+                    FLOOR_DAY(NOW() - DAYS(ndays)),
+                    FLOOR_DAY(NOW() - DAYS(1)),
+                    verbose,
+                )
+                .await?;
+            }
+
+            MarketImpl::async_download_realtime::<HyperliquidPublicWsClient>(
+                self, connect_ws, false, verbose,
+            )
+            .await
+        })
+    }
+
+    /// speed: 0.0は待たずに流す。1.0で実時間、10.0で10倍速で再生する
+    #[pyo3(signature = (time_from, time_to, speed=0.0))]
+    fn open_backtest_channel(
+        &mut self,
+        time_from: MicroSec,
+        time_to: MicroSec,
+        speed: f64,
+    ) -> anyhow::Result<(MicroSec, MicroSec, MarketStream)> {
+        MarketImpl::open_backtest_channel_timed(self, time_from, time_to, speed)
+    }
+
+    /// market streamを停止し、タスクの終了を待つ
+    fn stop_market_stream(&mut self) {
+        let stop = self.public_stop.take();
+        let handle = self.public_handler.take();
+
+        BLOCK_ON(async { stop_stream_task(stop, handle, STREAM_STOP_TIMEOUT_SEC).await })
+    }
+
+    fn open_market_stream(&mut self) -> anyhow::Result<()> {
+        BLOCK_ON(async { self.async_start_market_stream().await })
+    }
+
+    /// set callback(str) for raw market stream frames. must be set before open_market_stream.
+    #[pyo3(signature = (handler=None))]
+    fn on_raw_message(&mut self, handler: Option<PyObject>) {
+        self.raw_message_hook = handler.map(make_py_raw_message_hook);
+    }
+
+    /// WebSocketの接続状態(Disconnected, Connecting, Subscribed, Receiving, Reconnecting(n)など)
+    #[getter]
+    fn get_ws_state(&self) -> String {
+        self.ws_state.get().to_string()
+    }
+
+    /// market streamの接続を張り直す(次のメッセージ受信時に再接続する)
+    fn force_reconnect(&self) {
+        log::info!("force reconnect: {}", self.config.trade_symbol);
+        self.ws_state.request_reconnect();
+    }
+
+    fn vaccum(&self) -> anyhow::Result<()> {
+        let lock = self.db.lock().unwrap();
+
+        lock.vacuum()
+    }
+
+    #[pyo3(signature = (verbose=false))]
+    fn _download_latest(&mut self, verbose: bool) -> anyhow::Result<(i64, i64)> {
+        BLOCK_ON(async { MarketImpl::async_download_latest(self, verbose).await })
+    }
+
+    fn _download_range(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        verbose: bool,
+    ) -> anyhow::Result<i64> {
+        BLOCK_ON(async {
+            MarketImpl::async_download_range(self, start_time, end_time, verbose).await
+        })
+    }
+}
+
+impl MarketImpl<HyperliquidRestApi> for HyperliquidMarket {
+    fn get_restapi(&self) -> &HyperliquidRestApi {
+        &self.api
+    }
+
+    fn get_config(&self) -> MarketConfig {
+        self.config.clone()
+    }
+
+    fn get_db(&self) -> Arc<Mutex<TradeDataFrame>> {
+        self.db.clone()
+    }
+
+    fn get_history_web_base_url(&self) -> String {
+        self.server_config.get_historical_web_base()
+    }
+
+    async fn async_start_market_stream(&mut self) -> anyhow::Result<()> {
+        if self.public_handler.is_some() {
+            log::info!("market stream is already running.");
+            return Ok(());
+        }
+
+        let db_channel = {
+            let mut lock = self.db.lock().unwrap();
+            lock.open_channel()
+        }?;
+
+        let orderbook = self.board.clone();
+
+        let server_config = self.server_config.clone();
+        let config = self.config.clone();
+
+        let hub_channel = MARKET_HUB.open_channel();
+
+        let mut public_ws = HyperliquidPublicWsClient::new(&server_config, &config).await;
+        public_ws.set_raw_message_hook(self.raw_message_hook.clone());
+        public_ws.set_state_handle(self.ws_state.clone());
+
+        let exchange_name = config.exchange_name.clone();
+        let trade_category = config.trade_category.clone();
+        let trade_symbol = config.trade_symbol.clone();
+
+        let _ = self.async_refresh_order_book().await;
+
+        let (stop_tx, mut stop_rx) = stream_stop_signal();
+        self.public_stop = Some(stop_tx);
+
+        self.public_handler = Some(tokio::task::spawn(async move {
+            let ws_stream = public_ws.open_stream().await;
+            let mut ws_stream = Box::pin(ws_stream);
+
+            loop {
+                let message = tokio::select! {
+                    _ = stop_rx.changed() => {
+                        log::info!("market stream stopped");
+                        break;
+                    }
+                    message = ws_stream.next() => message,
+                };
+
+                if message.is_none() {
+                    log::error!("market stream closed");
+                    break;
+                }
+
+                let message = message.unwrap();
+
+                if message.is_err() {
+                    log::error!("Error in ws_stream.recv: {:?}", message);
+                    continue;
+                }
+
+                let messages = message.unwrap();
+
+                match messages {
+                    MultiMarketMessage::Trade(trade) => {
+                        log::debug!("Trade: {:?}", trade);
+                        let r = db_channel.send(trade.clone());
+
+                        if r.is_err() {
+                            log::error!("Error in db_channel.send: {:?}", r);
+                        }
+
+                        for message in trade {
+                            let r = hub_channel.send(BroadcastMessage {
+                                exchange: exchange_name.clone(),
+                                category: trade_category.clone(),
+                                symbol: trade_symbol.clone(),
+                                msg: MarketMessage::Trade(message),
+                            });
+                            if r.is_err() {
+                                log::error!("Error in hub_channel.send: {:?}", r);
+                            }
+                        }
+                    }
+                    MultiMarketMessage::Orderbook(board) => {
+                        // l2Bookは毎回スナップショット
+                        let mut b = orderbook.write().unwrap();
+                        b.update(&board);
+                    }
+                    MultiMarketMessage::Control(control) => {
+                        if control.status == false {
+                            log::error!("Control message: {:?}", control);
+                        }
+                    }
+                    _ => {
+                        log::info!("Market stream message: {:?}", messages);
+                    }
+                }
+            }
+        }));
+
+        Ok(())
+    }
+
+    fn get_order_book(&self) -> Arc<RwLock<OrderBook>> {
+        self.board.clone()
+    }
+
+    fn get_spread_logger(&mut self) -> &mut Option<SpreadLogger> {
+        &mut self.spread_logger
+    }
+
+    /// 約定履歴のAPIがないため、直近以外は1分足から仮想約定を生成する
+    async fn async_download_range(
+        &mut self,
+        time_from: MicroSec,
+        time_to: MicroSec,
+        verbose: bool,
+    ) -> anyhow::Result<i64> {
+        let time_from = if time_from == 0 || time_from < NOW() - DAYS(2) {
+            FLOOR_DAY(NOW() - DAYS(1))
+        } else {
+            time_from
+        };
+
+        self._async_download_range_virtual(time_from, time_to, verbose)
+            .await
+    }
+}
+
+impl HyperliquidMarket {
+    async fn async_new(
+        server_config: &ExchangeConfig,
+        config: &MarketConfig,
+    ) -> anyhow::Result<Self> {
+        let db = TradeDataFrame::get(config, server_config.is_production())
+            .with_context(|| format!("Error in TradeTable::open: {:?}", config))?;
+
+        let market = HyperliquidMarket {
+            server_config: server_config.clone(),
+            api: HyperliquidRestApi::new(server_config),
+            config: config.clone(),
+            db: db,
+            board: Arc::new(RwLock::new(OrderBook::new(
+                &config,
+                hyperliquid_board_depth(config),
+            ))),
+            public_handler: None,
+            public_stop: None,
+            raw_message_hook: None,
+            spread_logger: None,
+            ws_state: WsStateHandle::default(),
+        };
+
+        Ok(market)
+    }
+}
+
+#[cfg(test)]
+mod hyperliquid_market_test {
+    use rbot_lib::common::init_debug_log;
+
+    use crate::HyperliquidConfig;
+
+    #[test]
+    fn test_download_latest() {
+        init_debug_log();
+        use super::*;
+        let server = HyperliquidServerConfig::new(true);
+        let market_config = HyperliquidConfig::BTC();
+
+        let mut market = HyperliquidMarket::new(&server, &market_config);
+
+        let rec = market._download_latest(true).unwrap();
+        assert!(rec.0 > 0);
+    }
+}
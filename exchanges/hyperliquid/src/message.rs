@@ -0,0 +1,475 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+#![allow(non_snake_case)]
+
+use rbot_lib::common::{
+    collect_trades, msec_to_microsec, string_to_decimal, AccountCoins, BoardItem, BoardTransfer, Coin,
+    ControlMessage, Kline, LogStatus, MultiMarketMessage, Order, OrderSide, OrderStatus,
+    OrderType, Trade,
+};
+use rust_decimal::Decimal;
+use serde_derive::{Deserialize, Serialize};
+
+/// {"coin":"BTC","side":"B","px":"64123.0","sz":"0.0012","time":1724371200123,"hash":"0x..","tid":123456}
+/// sideはtakerの向き(B=買い, A=売り)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidTrade {
+    pub coin: String,
+    pub side: String,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub px: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub sz: Decimal,
+    pub time: i64,
+    pub tid: u64,
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+impl HyperliquidTrade {
+    pub fn to_trade(&self) -> anyhow::Result<Trade> {
+        Trade {
+            time: msec_to_microsec(self.time),
+            price: self.px,
+            size: self.sz,
+            order_side: match self.side.as_str() {
+                "B" => OrderSide::Buy,
+                "A" => OrderSide::Sell,
+                _ => OrderSide::Unknown,
+            },
+            status: LogStatus::UnFix,
+            id: self.tid.to_string(),
+        }
+        .validate()
+    }
+}
+
+/// {"px":"64123.0","sz":"1.2","n":3}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidLevel {
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub px: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub sz: Decimal,
+    pub n: i64,
+}
+
+/// levels[0]がbid、levels[1]がask。毎回スナップショットで送られる。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidL2Book {
+    pub coin: String,
+    pub time: i64,
+    pub levels: Vec<Vec<HyperliquidLevel>>,
+}
+
+impl Into<BoardTransfer> for HyperliquidL2Book {
+    fn into(self) -> BoardTransfer {
+        let mut board = BoardTransfer::new();
+
+        board.last_update_time = msec_to_microsec(self.time);
+        board.snapshot = true;
+
+        let mut levels = self.levels.into_iter();
+
+        board.bids = levels
+            .next()
+            .unwrap_or_default()
+            .iter()
+            .map(|l| BoardItem { price: l.px, size: l.sz })
+            .collect();
+        board.asks = levels
+            .next()
+            .unwrap_or_default()
+            .iter()
+            .map(|l| BoardItem { price: l.px, size: l.sz })
+            .collect();
+
+        board
+    }
+}
+
+/// candleSnapshotの1本
+/// {"t":1724371200000,"T":1724371259999,"s":"BTC","i":"1m","o":"64100.0","c":"64120.0","h":"64130.0","l":"64090.0","v":"12.3","n":120}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidCandle {
+    pub t: i64,
+    pub T: i64,
+    pub s: String,
+    pub i: String,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub o: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub c: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub h: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub l: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub v: Decimal,
+    pub n: i64,
+}
+
+impl Into<Kline> for HyperliquidCandle {
+    fn into(self) -> Kline {
+        Kline::new(msec_to_microsec(self.t), self.o, self.h, self.l, self.c, self.v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "channel", content = "data")]
+pub enum HyperliquidPublicWsMessage {
+    #[serde(rename = "trades")]
+    Trades(Vec<HyperliquidTrade>),
+    #[serde(rename = "l2Book")]
+    L2Book(HyperliquidL2Book),
+    #[serde(rename = "subscriptionResponse")]
+    SubscriptionResponse(serde_json::Value),
+    #[serde(rename = "pong")]
+    Pong,
+}
+
+impl Into<MultiMarketMessage> for HyperliquidPublicWsMessage {
+    fn into(self) -> MultiMarketMessage {
+        match self {
+            HyperliquidPublicWsMessage::Trades(trades) => {
+                let (trades, _skipped) = collect_trades(trades.iter(), |t| t.to_trade());
+
+                MultiMarketMessage::Trade(trades)
+            }
+            HyperliquidPublicWsMessage::L2Book(book) => MultiMarketMessage::Orderbook(book.into()),
+            HyperliquidPublicWsMessage::SubscriptionResponse(m) => {
+                MultiMarketMessage::Control(ControlMessage {
+                    status: true,
+                    operation: "subscribe".to_string(),
+                    message: m.to_string(),
+                })
+            }
+            HyperliquidPublicWsMessage::Pong => MultiMarketMessage::Control(ControlMessage {
+                status: true,
+                operation: "pong".to_string(),
+                message: "".to_string(),
+            }),
+        }
+    }
+}
+
+/*------------ /exchange ------------------------*/
+
+/// actionはmsgpackでハッシュをとるため、フィールドの順番を変えてはいけない。
+/// {"type":"order","orders":[{"a":0,"b":true,"p":"64000","s":"0.001","r":false,"t":{"limit":{"tif":"Gtc"}}}],"grouping":"na"}
+#[derive(Debug, Clone, Serialize)]
+pub struct HyperliquidOrderAction {
+    #[serde(rename = "type")]
+    pub action_type: String,
+    pub orders: Vec<HyperliquidOrderWire>,
+    pub grouping: String,
+}
+
+impl HyperliquidOrderAction {
+    pub fn new(orders: Vec<HyperliquidOrderWire>) -> Self {
+        Self {
+            action_type: "order".to_string(),
+            orders,
+            grouping: "na".to_string(),
+        }
+    }
+}
+
+/// a: asset index, b: is_buy, p: price, s: size, r: reduce_only, t: order type, c: cloid
+#[derive(Debug, Clone, Serialize)]
+pub struct HyperliquidOrderWire {
+    pub a: u32,
+    pub b: bool,
+    pub p: String,
+    pub s: String,
+    pub r: bool,
+    pub t: HyperliquidOrderTypeWire,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HyperliquidOrderTypeWire {
+    pub limit: HyperliquidLimit,
+}
+
+/// tif: Gtc, Ioc, Alo(post only)
+#[derive(Debug, Clone, Serialize)]
+pub struct HyperliquidLimit {
+    pub tif: String,
+}
+
+/// {"type":"cancel","cancels":[{"a":0,"o":77738308}]}
+#[derive(Debug, Clone, Serialize)]
+pub struct HyperliquidCancelAction {
+    #[serde(rename = "type")]
+    pub action_type: String,
+    pub cancels: Vec<HyperliquidCancelWire>,
+}
+
+impl HyperliquidCancelAction {
+    pub fn new(cancels: Vec<HyperliquidCancelWire>) -> Self {
+        Self {
+            action_type: "cancel".to_string(),
+            cancels,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HyperliquidCancelWire {
+    pub a: u32,
+    pub o: u64,
+}
+
+/// {"status":"ok","response":{"type":"order","data":{"statuses":[...]}}}
+/// エラーの場合は {"status":"err","response":"message"}
+#[derive(Debug, Clone, Deserialize)]
+pub struct HyperliquidExchangeResponse {
+    pub status: String,
+    pub response: serde_json::Value,
+}
+
+impl HyperliquidExchangeResponse {
+    pub fn statuses(&self) -> anyhow::Result<Vec<HyperliquidOrderStatus>> {
+        if self.status != "ok" {
+            return Err(anyhow::anyhow!("exchange error: {}", self.response));
+        }
+
+        let statuses = self
+            .response
+            .pointer("/data/statuses")
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(serde_json::from_value(statuses)?)
+    }
+}
+
+/// {"resting":{"oid":77738308}} / {"filled":{"totalSz":"0.02","avgPx":"1891.4","oid":77747314}} / {"error":"..."} / "success"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HyperliquidOrderStatus {
+    Resting(HyperliquidResting),
+    Filled(HyperliquidFilled),
+    Error(String),
+    Success,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HyperliquidResting {
+    pub oid: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HyperliquidFilled {
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub totalSz: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub avgPx: Decimal,
+    pub oid: u64,
+}
+
+/// POST /info {"type":"openOrders","user":"0x.."}
+/// {"coin":"BTC","limitPx":"29792.0","oid":91490942,"side":"A","sz":"0.0","origSz":"5.0","timestamp":1681247412573}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidOpenOrder {
+    pub coin: String,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub limitPx: Decimal,
+    pub oid: u64,
+    pub side: String,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub sz: Decimal,
+    #[serde(default, deserialize_with = "string_to_decimal")]
+    pub origSz: Decimal,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub cloid: Option<String>,
+}
+
+impl HyperliquidOpenOrder {
+    pub fn to_order(&self, category: &str) -> Order {
+        let side = if self.side == "B" { OrderSide::Buy } else { OrderSide::Sell };
+        let size = if self.origSz.is_zero() { self.sz } else { self.origSz };
+
+        let mut order = Order::new(
+            category,
+            &self.coin,
+            msec_to_microsec(self.timestamp),
+            &self.oid.to_string(),
+            self.cloid.as_deref().unwrap_or_default(),
+            side,
+            OrderType::Limit,
+            if self.sz < size { OrderStatus::PartiallyFilled } else { OrderStatus::New },
+            self.limitPx,
+            size,
+        );
+        order.remain_size = self.sz;
+
+        order
+    }
+}
+
+/// POST /info {"type":"clearinghouseState","user":"0x.."}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidClearinghouseState {
+    pub marginSummary: HyperliquidMarginSummary,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub withdrawable: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidMarginSummary {
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub accountValue: Decimal,
+    #[serde(deserialize_with = "string_to_decimal")]
+    pub totalMarginUsed: Decimal,
+}
+
+/// 証拠金はUSDCのみ
+impl Into<AccountCoins> for HyperliquidClearinghouseState {
+    fn into(self) -> AccountCoins {
+        let mut coins = AccountCoins::new();
+        coins.push(Coin {
+            symbol: "USDC".to_string(),
+            volume: self.marginSummary.accountValue,
+            free: self.withdrawable,
+            locked: self.marginSummary.totalMarginUsed,
+        });
+
+        coins
+    }
+}
+
+/// POST /info {"type":"meta"} のuniverse。配列の順番がasset indexになる。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidMeta {
+    pub universe: Vec<HyperliquidAssetInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperliquidAssetInfo {
+    pub name: String,
+    pub szDecimals: u32,
+}
+
+#[cfg(test)]
+mod hyperliquid_message_test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const TRADES: &str = r#"{"channel":"trades","data":[{"coin":"BTC","side":"A","px":"64123.0","sz":"0.0012","time":1724371200123,"hash":"0xabc","tid":900001,"users":["0x1","0x2"]}]}"#;
+    const L2BOOK: &str = r#"{"channel":"l2Book","data":{"coin":"BTC","time":1724371200500,"levels":[[{"px":"64120.0","sz":"1.5","n":3},{"px":"64119.0","sz":"0.2","n":1}],[{"px":"64121.0","sz":"0.7","n":2}]]}}"#;
+    const SUBSCRIBED: &str = r#"{"channel":"subscriptionResponse","data":{"method":"subscribe","subscription":{"type":"trades","coin":"BTC"}}}"#;
+    const PONG: &str = r#"{"channel":"pong"}"#;
+
+    #[test]
+    fn test_ws_trades() {
+        let message: HyperliquidPublicWsMessage = serde_json::from_str(TRADES).unwrap();
+
+        match message.into() {
+            MultiMarketMessage::Trade(trades) => {
+                assert_eq!(trades.len(), 1);
+                assert_eq!(trades[0].order_side, OrderSide::Sell);
+                assert_eq!(trades[0].price, dec![64123.0]);
+                assert_eq!(trades[0].time, 1724371200123_000);
+                assert_eq!(trades[0].id, "900001");
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_ws_l2book() {
+        let message: HyperliquidPublicWsMessage = serde_json::from_str(L2BOOK).unwrap();
+
+        match message.into() {
+            MultiMarketMessage::Orderbook(board) => {
+                assert!(board.snapshot);
+                assert_eq!(board.bids.len(), 2);
+                assert_eq!(board.asks.len(), 1);
+                assert_eq!(board.asks[0].price, dec![64121.0]);
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_ws_control() {
+        let message: HyperliquidPublicWsMessage = serde_json::from_str(SUBSCRIBED).unwrap();
+        assert!(matches!(message.into(), MultiMarketMessage::Control(_)));
+
+        let message: HyperliquidPublicWsMessage = serde_json::from_str(PONG).unwrap();
+        assert!(matches!(message.into(), MultiMarketMessage::Control(_)));
+    }
+
+    #[test]
+    fn test_order_response() -> anyhow::Result<()> {
+        let response: HyperliquidExchangeResponse = serde_json::from_str(
+            r#"{"status":"ok","response":{"type":"order","data":{"statuses":[{"resting":{"oid":77738308}},{"filled":{"totalSz":"0.02","avgPx":"1891.4","oid":77747314}},{"error":"Order must have minimum value of $10."}]}}}"#,
+        )?;
+
+        let statuses = response.statuses()?;
+        assert!(matches!(statuses[0], HyperliquidOrderStatus::Resting(HyperliquidResting { oid: 77738308 })));
+        match &statuses[1] {
+            HyperliquidOrderStatus::Filled(f) => assert_eq!(f.avgPx, dec![1891.4]),
+            s => panic!("unexpected status {:?}", s),
+        }
+        assert!(matches!(statuses[2], HyperliquidOrderStatus::Error(_)));
+
+        let response: HyperliquidExchangeResponse = serde_json::from_str(
+            r#"{"status":"ok","response":{"type":"cancel","data":{"statuses":["success"]}}}"#,
+        )?;
+        assert!(matches!(response.statuses()?[0], HyperliquidOrderStatus::Success));
+
+        let response: HyperliquidExchangeResponse =
+            serde_json::from_str(r#"{"status":"err","response":"User or API Wallet does not exist."}"#)?;
+        assert!(response.statuses().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_order() -> anyhow::Result<()> {
+        let orders: Vec<HyperliquidOpenOrder> = serde_json::from_str(
+            r#"[{"coin":"BTC","limitPx":"29792.0","oid":91490942,"side":"A","sz":"2.0","origSz":"5.0","timestamp":1681247412573}]"#,
+        )?;
+
+        let order = orders[0].to_order("linear");
+        assert_eq!(order.order_id, "91490942");
+        assert_eq!(order.order_side, OrderSide::Sell);
+        assert_eq!(order.order_size, dec![5.0]);
+        assert_eq!(order.remain_size, dec![2.0]);
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clearinghouse_state() -> anyhow::Result<()> {
+        let state: HyperliquidClearinghouseState = serde_json::from_str(
+            r#"{"assetPositions":[],"marginSummary":{"accountValue":"13104.51","totalNtlPos":"0.0","totalRawUsd":"13104.51","totalMarginUsed":"100.0"},"withdrawable":"13004.51","time":1708622398623}"#,
+        )?;
+
+        let account: AccountCoins = state.into();
+        assert_eq!(account.coins[0].symbol, "USDC");
+        assert_eq!(account.coins[0].volume, dec![13104.51]);
+        assert_eq!(account.coins[0].free, dec![13004.51]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_candle() {
+        let candle: HyperliquidCandle = serde_json::from_str(
+            r#"{"t":1724371200000,"T":1724371259999,"s":"BTC","i":"1m","o":"64100.0","c":"64120.0","h":"64130.0","l":"64090.0","v":"12.3","n":120}"#,
+        )
+        .unwrap();
+
+        let kline: Kline = candle.into();
+        assert_eq!(kline.timestamp, 1724371200000_000);
+        assert_eq!(kline.high, dec![64130.0]);
+        assert_eq!(kline.volume, dec![12.3]);
+    }
+}
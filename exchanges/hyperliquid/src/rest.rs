@@ -0,0 +1,612 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context};
+use polars::frame::DataFrame;
+use rbot_lib::{
+    common::{
+        collect_trades, AccountCoins, BoardTransfer, ExchangeConfig, Kline, MarketConfig, MicroSec, Order,
+        OrderSide, OrderStatus, OrderType, TimeInForce, Trade, FLOOR_SEC, NOW,
+    },
+    net::{rest_post, RestApi, RestPage},
+};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde_json::{json, Value};
+
+use crate::{
+    keccak256, HyperliquidCancelAction, HyperliquidCancelWire, HyperliquidCandle,
+    HyperliquidClearinghouseState, HyperliquidExchangeResponse, HyperliquidL2Book,
+    HyperliquidLimit, HyperliquidMeta, HyperliquidOpenOrder, HyperliquidOrderAction,
+    HyperliquidOrderStatus, HyperliquidOrderTypeWire, HyperliquidOrderWire, HyperliquidSigner,
+    HyperliquidTrade,
+};
+
+/// candleSnapshotで一度に返る最大本数
+const MAX_CANDLES: i64 = 5000;
+
+/// 成行注文はIOCの指値で出す。板の最良値からこの割合だけ不利な価格を指定する。
+const MARKET_ORDER_SLIPPAGE: Decimal = dec![0.05];
+
+/// 価格は有効数字5桁まで
+const PRICE_SIGNIFICANT_FIGURES: u32 = 5;
+
+#[derive(Clone, Debug)]
+pub struct HyperliquidRestApi {
+    server_config: ExchangeConfig,
+    /// 残高・注文照会に使うアカウントのアドレス(API walletで署名する場合もメインのアドレス)
+    wallet_address: String,
+    signer: Option<HyperliquidSigner>,
+    assets: Arc<Mutex<HashMap<String, u32>>>,
+    last_nonce: Arc<AtomicU64>,
+}
+
+impl HyperliquidRestApi {
+    /// api_keyをウォレットアドレス、api_secretを秘密鍵として使う
+    pub fn new(server_config: &ExchangeConfig) -> Self {
+        let private_key = server_config.get_api_secret().extract();
+
+        let signer = if private_key.is_empty() {
+            None
+        } else {
+            match HyperliquidSigner::new(&private_key, server_config.is_production()) {
+                Ok(signer) => Some(signer),
+                Err(e) => {
+                    log::warn!("invalid hyperliquid private key: {:?}", e);
+                    None
+                }
+            }
+        };
+
+        let mut wallet_address = server_config.get_api_key().extract();
+        if wallet_address.is_empty() {
+            if let Some(signer) = &signer {
+                wallet_address = signer.address();
+            }
+        }
+
+        Self {
+            server_config: server_config.clone(),
+            wallet_address,
+            signer,
+            assets: Arc::new(Mutex::new(HashMap::new())),
+            last_nonce: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// wallet_addressが空の場合は秘密鍵のアドレスを使う
+    pub fn with_credentials(
+        server_config: &ExchangeConfig,
+        wallet_address: &str,
+        private_key: &str,
+        is_mainnet: bool,
+    ) -> anyhow::Result<Self> {
+        let signer = HyperliquidSigner::new(private_key, is_mainnet)?;
+
+        let wallet_address = if wallet_address.is_empty() {
+            signer.address()
+        } else {
+            wallet_address.to_lowercase()
+        };
+
+        Ok(Self {
+            server_config: server_config.clone(),
+            wallet_address,
+            signer: Some(signer),
+            assets: Arc::new(Mutex::new(HashMap::new())),
+            last_nonce: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn get_wallet_address(&self) -> String {
+        self.wallet_address.clone()
+    }
+
+    /// 公開情報はすべて POST /info にJSONのtypeを指定して取得する
+    async fn post_info(&self, body: Value) -> anyhow::Result<Value> {
+        let server = self.server_config.get_public_api();
+
+        let response = rest_post(
+            &server,
+            "/info",
+            vec![("Content-Type", "application/json")],
+            &body.to_string(),
+        )
+        .await
+        .with_context(|| format!("post_info error {:?}", body))?;
+
+        let value: Value = serde_json::from_str(&response)
+            .with_context(|| format!("post_info parse error {}", response))?;
+
+        Ok(value)
+    }
+
+    /// 発注・取消は POST /exchange に署名付きのactionを送る
+    async fn post_exchange<T: serde::Serialize>(
+        &self,
+        action: &T,
+    ) -> anyhow::Result<HyperliquidExchangeResponse> {
+        let signer = self
+            .signer
+            .as_ref()
+            .ok_or_else(|| anyhow!("hyperliquid private key is not set"))?;
+
+        let nonce = self.next_nonce();
+        let signature = signer.sign_action(action, nonce, None)?;
+
+        let body = json!({
+            "action": action,
+            "nonce": nonce,
+            "signature": signature,
+            "vaultAddress": null,
+        });
+
+        let server = self.server_config.get_private_api();
+        let response = rest_post(
+            &server,
+            "/exchange",
+            vec![("Content-Type", "application/json")],
+            &body.to_string(),
+        )
+        .await
+        .with_context(|| format!("post_exchange error {}", body))?;
+
+        serde_json::from_str(&response)
+            .with_context(|| format!("post_exchange parse error {}", response))
+    }
+
+    /// nonceはmsec。同じmsecに複数送る場合も重複しないよう単調増加させる。
+    fn next_nonce(&self) -> u64 {
+        let now = (NOW() / 1_000) as u64;
+
+        let mut last = self.last_nonce.load(Ordering::SeqCst);
+        loop {
+            let nonce = if last < now { now } else { last + 1 };
+
+            match self
+                .last_nonce
+                .compare_exchange(last, nonce, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return nonce,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    /// metaのuniverseの順番がasset index
+    pub async fn asset_index(&self, coin: &str) -> anyhow::Result<u32> {
+        if let Some(index) = self.assets.lock().unwrap().get(coin) {
+            return Ok(*index);
+        }
+
+        let message = self
+            .post_info(json!({"type": "meta"}))
+            .await
+            .with_context(|| format!("get meta error"))?;
+        let meta: HyperliquidMeta = serde_json::from_value(message)?;
+
+        let mut assets = self.assets.lock().unwrap();
+        for (i, asset) in meta.universe.iter().enumerate() {
+            assets.insert(asset.name.clone(), i as u32);
+        }
+
+        assets
+            .get(coin)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown asset {}", coin))
+    }
+
+    /// https://hyperliquid.gitbook.io/hyperliquid-docs/for-developers/api/exchange-endpoint#place-an-order
+    pub async fn place_order(
+        &self,
+        side: OrderSide,
+        asset: u32,
+        limit_px: Decimal,
+        sz: Decimal,
+        order_type: HyperliquidOrderTypeWire,
+        reduce_only: bool,
+        cloid: Option<String>,
+    ) -> anyhow::Result<HyperliquidOrderStatus> {
+        let action = HyperliquidOrderAction::new(vec![HyperliquidOrderWire {
+            a: asset,
+            b: side == OrderSide::Buy,
+            p: to_wire(limit_px),
+            s: to_wire(sz),
+            r: reduce_only,
+            t: order_type,
+            c: cloid,
+        }]);
+
+        let response = self.post_exchange(&action).await?;
+
+        response
+            .statuses()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("empty order response"))
+    }
+
+    /// 成行注文の価格。板の最良値からMARKET_ORDER_SLIPPAGEだけ不利な価格。
+    async fn market_price(&self, config: &MarketConfig, side: OrderSide) -> anyhow::Result<Decimal> {
+        let board = self.get_board_snapshot(config).await?;
+
+        let price = if side == OrderSide::Buy {
+            let best = board.asks.first().ok_or_else(|| anyhow!("no ask in board"))?;
+            best.price * (dec![1] + MARKET_ORDER_SLIPPAGE)
+        } else {
+            let best = board.bids.first().ok_or_else(|| anyhow!("no bid in board"))?;
+            best.price * (dec![1] - MARKET_ORDER_SLIPPAGE)
+        };
+
+        let price = price.round_sf(PRICE_SIGNIFICANT_FIGURES).unwrap_or(price);
+
+        config.round_price(price)
+    }
+}
+
+/// 数値は末尾の0を除いた文字列で送る(署名対象になるため表記を揃える)
+pub fn to_wire(value: Decimal) -> String {
+    value.round_dp(8).normalize().to_string()
+}
+
+/// cloidは0x付き32桁の16進。それ以外の文字列はハッシュから作る。
+pub fn to_cloid(client_order_id: &str) -> String {
+    let hex = client_order_id.trim_start_matches("0x");
+
+    if client_order_id.starts_with("0x") && hex.len() == 32 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return client_order_id.to_lowercase();
+    }
+
+    format!("0x{}", hex::encode(&keccak256(client_order_id.as_bytes())[..16]))
+}
+
+fn tif_wire(order_type: OrderType, time_in_force: TimeInForce) -> anyhow::Result<String> {
+    if order_type == OrderType::Market {
+        return Ok("Ioc".to_string());
+    }
+
+    match time_in_force {
+        TimeInForce::GTC => Ok("Gtc".to_string()),
+        TimeInForce::IOC => Ok("Ioc".to_string()),
+        TimeInForce::PostOnly => Ok("Alo".to_string()),
+        TimeInForce::FOK => Err(anyhow!("FOK is not supported in hyperliquid")),
+    }
+}
+
+fn status_to_order(
+    config: &MarketConfig,
+    status: HyperliquidOrderStatus,
+    side: OrderSide,
+    order_type: OrderType,
+    price: Decimal,
+    size: Decimal,
+    client_order_id: &str,
+) -> anyhow::Result<Order> {
+    let (oid, order_status) = match &status {
+        HyperliquidOrderStatus::Resting(r) => (r.oid, OrderStatus::New),
+        HyperliquidOrderStatus::Filled(f) if f.totalSz < size => (f.oid, OrderStatus::PartiallyFilled),
+        HyperliquidOrderStatus::Filled(f) => (f.oid, OrderStatus::Filled),
+        HyperliquidOrderStatus::Error(e) => return Err(anyhow!("order rejected: {}", e)),
+        HyperliquidOrderStatus::Success => return Err(anyhow!("unexpected order status {:?}", status)),
+    };
+
+    let mut order = Order::new(
+        &config.trade_category,
+        &config.trade_symbol,
+        NOW(),
+        &oid.to_string(),
+        client_order_id,
+        side,
+        order_type,
+        order_status,
+        price,
+        size,
+    );
+
+    if let HyperliquidOrderStatus::Filled(f) = status {
+        order.execute_price = f.avgPx;
+        order.execute_size = f.totalSz;
+        order.remain_size = size - f.totalSz;
+    }
+
+    Ok(order)
+}
+
+impl RestApi for HyperliquidRestApi {
+    fn get_exchange(&self) -> ExchangeConfig {
+        self.server_config.clone()
+    }
+
+    /// https://hyperliquid.gitbook.io/hyperliquid-docs/for-developers/api/info-endpoint#l2-book-snapshot
+    async fn get_board_snapshot(&self, config: &MarketConfig) -> anyhow::Result<BoardTransfer> {
+        let message = self
+            .post_info(json!({"type": "l2Book", "coin": config.trade_symbol}))
+            .await
+            .with_context(|| format!("get_board_snapshot error"))?;
+
+        let board: HyperliquidL2Book = serde_json::from_value(message)?;
+
+        Ok(board.into())
+    }
+
+    async fn get_recent_trades(&self, config: &MarketConfig) -> anyhow::Result<Vec<Trade>> {
+        let message = self
+            .post_info(json!({"type": "recentTrades", "coin": config.trade_symbol}))
+            .await
+            .with_context(|| format!("get_recent_trades error"))?;
+
+        let trades: Vec<HyperliquidTrade> = serde_json::from_value(message)?;
+
+        let (trades, _skipped) = collect_trades(trades.iter(), |t| t.to_trade());
+
+        Ok(trades)
+    }
+
+    /// 約定履歴をさかのぼるAPIはないため、直近の約定のみを返す
+    async fn get_trades(
+        &self,
+        config: &MarketConfig,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        page: &RestPage,
+    ) -> anyhow::Result<(Vec<Trade>, RestPage)> {
+        if *page == RestPage::Done {
+            return Err(anyhow!("called with RestPage::Done"));
+        }
+
+        let trades = self
+            .get_recent_trades(config)
+            .await?
+            .into_iter()
+            .filter(|t| start_time <= t.time && (end_time == 0 || t.time < end_time))
+            .collect();
+
+        Ok((trades, RestPage::Done))
+    }
+
+    /// https://hyperliquid.gitbook.io/hyperliquid-docs/for-developers/api/info-endpoint#candle-snapshot
+    /// 古い順に返るので、RestPage::Timeには次の開始時刻を入れる
+    async fn get_klines(
+        &self,
+        config: &MarketConfig,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        page: &RestPage,
+    ) -> anyhow::Result<(Vec<Kline>, RestPage)> {
+        if *page == RestPage::Done {
+            return Err(anyhow!("call with RestPage::Done"));
+        }
+
+        if start_time == 0 || end_time == 0 {
+            return Err(anyhow!(
+                "end_time({}) or start_time({}) is zero",
+                end_time,
+                start_time
+            ));
+        }
+
+        let start_time = if let RestPage::Time(t) = page {
+            *t
+        } else {
+            FLOOR_SEC(start_time, self.klines_width())
+        };
+
+        if end_time <= start_time {
+            return Ok((vec![], RestPage::Done));
+        }
+
+        let message = self
+            .post_info(json!({
+                "type": "candleSnapshot",
+                "req": {
+                    "coin": config.trade_symbol,
+                    "interval": "1m",
+                    "startTime": start_time / 1_000,
+                    "endTime": end_time / 1_000,
+                }
+            }))
+            .await
+            .with_context(|| format!("get_klines error"))?;
+
+        let candles: Vec<HyperliquidCandle> = serde_json::from_value(message)?;
+
+        let klines: Vec<Kline> = candles
+            .into_iter()
+            .map(|c| c.into())
+            .filter(|k: &Kline| start_time <= k.timestamp && k.timestamp < end_time)
+            .collect();
+
+        if klines.len() < MAX_CANDLES as usize {
+            return Ok((klines, RestPage::Done));
+        }
+
+        let next_time = klines.last().unwrap().timestamp + self.klines_width() * 1_000_000;
+
+        Ok((klines, RestPage::Time(next_time)))
+    }
+
+    fn klines_width(&self) -> i64 {
+        60
+    }
+
+    /// 成行注文はMARKET_ORDER_SLIPPAGEを付けたIOCの指値で出す
+    async fn new_order(
+        &self,
+        config: &MarketConfig,
+        side: OrderSide,
+        price: Decimal,
+        size: Decimal,
+        order_type: OrderType,
+        client_order_id: Option<&str>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
+    ) -> anyhow::Result<Vec<Order>> {
+        let asset = self.asset_index(&config.trade_symbol).await?;
+        let tif = tif_wire(order_type, time_in_force)?;
+
+        let limit_px = if order_type == OrderType::Market {
+            self.market_price(config, side).await?
+        } else {
+            price
+        };
+
+        let status = self
+            .place_order(
+                side,
+                asset,
+                limit_px,
+                size,
+                HyperliquidOrderTypeWire {
+                    limit: HyperliquidLimit { tif },
+                },
+                reduce_only,
+                client_order_id.map(to_cloid),
+            )
+            .await
+            .with_context(|| format!("new_order error {:?} {} {} {}", side, config.trade_symbol, limit_px, size))?;
+
+        let order = status_to_order(
+            config,
+            status,
+            side,
+            order_type,
+            limit_px,
+            size,
+            client_order_id.unwrap_or_default(),
+        )?;
+
+        Ok(vec![order])
+    }
+
+    /// https://hyperliquid.gitbook.io/hyperliquid-docs/for-developers/api/exchange-endpoint#cancel-order-s
+    async fn cancel_order(&self, config: &MarketConfig, order_id: &str) -> anyhow::Result<Order> {
+        let asset = self.asset_index(&config.trade_symbol).await?;
+        let oid: u64 = order_id
+            .parse()
+            .with_context(|| format!("invalid order id {}", order_id))?;
+
+        let action = HyperliquidCancelAction::new(vec![HyperliquidCancelWire { a: asset, o: oid }]);
+        let response = self.post_exchange(&action).await?;
+
+        match response.statuses()?.into_iter().next() {
+            Some(HyperliquidOrderStatus::Success) => Ok(Order::new(
+                &config.trade_category,
+                &config.trade_symbol,
+                NOW(),
+                order_id,
+                "",
+                OrderSide::Unknown,
+                OrderType::Limit,
+                OrderStatus::Canceled,
+                dec![0.0],
+                dec![0.0],
+            )),
+            Some(HyperliquidOrderStatus::Error(e)) => Err(anyhow!("cancel_order error {}: {}", order_id, e)),
+            status => Err(anyhow!("unexpected cancel response {:?}", status)),
+        }
+    }
+
+    async fn open_orders(&self, config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
+        let message = self
+            .post_info(json!({"type": "openOrders", "user": self.wallet_address}))
+            .await
+            .with_context(|| format!("open_orders error"))?;
+
+        let orders: Vec<HyperliquidOpenOrder> = serde_json::from_value(message)?;
+
+        Ok(orders
+            .iter()
+            .filter(|o| o.coin == config.trade_symbol)
+            .map(|o| o.to_order(&config.trade_category))
+            .collect())
+    }
+
+    async fn get_account(&self) -> anyhow::Result<AccountCoins> {
+        let message = self
+            .post_info(json!({"type": "clearinghouseState", "user": self.wallet_address}))
+            .await
+            .with_context(|| format!("get_account error"))?;
+
+        let state: HyperliquidClearinghouseState = serde_json::from_value(message)?;
+
+        Ok(state.into())
+    }
+
+    /// 日次アーカイブは公開されていない
+    fn history_web_url(&self, _config: &MarketConfig, _date: MicroSec) -> String {
+        "".to_string()
+    }
+
+    async fn has_web_archive(&self, _config: &MarketConfig, _date: MicroSec) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn logdf_to_archivedf(&self, _df: &DataFrame) -> anyhow::Result<DataFrame> {
+        Err(anyhow!("web archive is not available in hyperliquid"))
+    }
+}
+
+#[cfg(test)]
+mod hyperliquid_rest_test {
+    use super::*;
+    use crate::{HyperliquidConfig, HyperliquidServerConfig};
+    use rbot_lib::common::{init_debug_log, NOW, SEC};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_to_wire() {
+        assert_eq!(to_wire(dec![64000.0]), "64000");
+        assert_eq!(to_wire(dec![0.00100]), "0.001");
+        assert_eq!(to_wire(dec![1.123456789]), "1.12345679");
+    }
+
+    #[test]
+    fn test_to_cloid() {
+        let cloid = "0x1234567890abcdef1234567890abcdef";
+        assert_eq!(to_cloid(cloid), cloid);
+
+        let cloid = to_cloid("my-order-1");
+        assert_eq!(cloid.len(), 34);
+        assert_eq!(cloid, to_cloid("my-order-1"));
+    }
+
+    #[test]
+    fn test_nonce() {
+        let api = HyperliquidRestApi::new(&HyperliquidServerConfig::new(false));
+
+        let n1 = api.next_nonce();
+        let n2 = api.next_nonce();
+        assert!(n1 < n2);
+    }
+
+    #[tokio::test]
+    async fn test_get_board_snapshot() -> anyhow::Result<()> {
+        init_debug_log();
+        let api = HyperliquidRestApi::new(&HyperliquidServerConfig::new(true));
+
+        let board = api.get_board_snapshot(&HyperliquidConfig::BTC()).await?;
+        assert!(board.snapshot);
+        assert!(0 < board.bids.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_klines() -> anyhow::Result<()> {
+        init_debug_log();
+        let api = HyperliquidRestApi::new(&HyperliquidServerConfig::new(true));
+
+        let now = NOW();
+        let (klines, page) = api
+            .get_klines(&HyperliquidConfig::BTC(), now - SEC(60 * 10), now, &RestPage::New)
+            .await?;
+
+        assert!(0 < klines.len());
+        assert_eq!(page, RestPage::Done);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use anyhow::{anyhow, Context};
+use k256::ecdsa::SigningKey;
+use serde_derive::Serialize;
+use sha3::{Digest, Keccak256};
+
+/// see https://hyperliquid.gitbook.io/hyperliquid-docs/for-developers/api/signing
+/// /exchangeのactionはmsgpackにnonceとvault addressを付けたもののハッシュ(connectionId)を
+/// EIP-712のAgent(string source,bytes32 connectionId)として署名する。
+const EIP712_DOMAIN_TYPE: &str =
+    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+const AGENT_TYPE: &str = "Agent(string source,bytes32 connectionId)";
+
+const DOMAIN_NAME: &str = "Exchange";
+const DOMAIN_VERSION: &str = "1";
+const DOMAIN_CHAIN_ID: u64 = 1337;
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// {"r":"0x..","s":"0x..","v":27}
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HyperliquidSignature {
+    pub r: String,
+    pub s: String,
+    pub v: u8,
+}
+
+#[derive(Clone)]
+pub struct HyperliquidSigner {
+    key: SigningKey,
+    is_mainnet: bool,
+}
+
+impl std::fmt::Debug for HyperliquidSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HyperliquidSigner({}, mainnet={})", self.address(), self.is_mainnet)
+    }
+}
+
+impl HyperliquidSigner {
+    /// private_keyは0x付き/なしの16進文字列
+    pub fn new(private_key: &str, is_mainnet: bool) -> anyhow::Result<Self> {
+        let bytes = hex::decode(private_key.trim().trim_start_matches("0x"))
+            .with_context(|| "private key must be hex string")?;
+
+        let key = SigningKey::from_slice(&bytes).map_err(|e| anyhow!("invalid private key {:?}", e))?;
+
+        Ok(Self { key, is_mainnet })
+    }
+
+    pub fn is_mainnet(&self) -> bool {
+        self.is_mainnet
+    }
+
+    /// 署名に使う鍵のアドレス(0x付き小文字)
+    pub fn address(&self) -> String {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let hash = keccak256(&point.as_bytes()[1..]);
+
+        format!("0x{}", hex::encode(&hash[12..]))
+    }
+
+    pub fn sign_action<T: serde::Serialize>(
+        &self,
+        action: &T,
+        nonce: u64,
+        vault_address: Option<&str>,
+    ) -> anyhow::Result<HyperliquidSignature> {
+        let connection_id = action_hash(action, nonce, vault_address)?;
+        let digest = agent_digest(&connection_id, self.is_mainnet);
+
+        self.sign_digest(&digest)
+    }
+
+    pub fn sign_digest(&self, digest: &[u8; 32]) -> anyhow::Result<HyperliquidSignature> {
+        let (signature, recovery_id) = self
+            .key
+            .sign_prehash_recoverable(digest)
+            .map_err(|e| anyhow!("sign error {:?}", e))?;
+
+        Ok(HyperliquidSignature {
+            r: format!("0x{}", hex::encode(signature.r().to_bytes())),
+            s: format!("0x{}", hex::encode(signature.s().to_bytes())),
+            v: 27 + recovery_id.to_byte(),
+        })
+    }
+}
+
+/// keccak256(msgpack(action) + nonce(big endian) + vault address)
+pub fn action_hash<T: serde::Serialize>(
+    action: &T,
+    nonce: u64,
+    vault_address: Option<&str>,
+) -> anyhow::Result<[u8; 32]> {
+    let mut data = rmp_serde::to_vec_named(action)?;
+    data.extend_from_slice(&nonce.to_be_bytes());
+
+    match vault_address {
+        Some(address) => {
+            data.push(1);
+            data.extend(hex::decode(address.trim_start_matches("0x"))?);
+        }
+        None => data.push(0),
+    }
+
+    Ok(keccak256(&data))
+}
+
+pub fn domain_separator() -> [u8; 32] {
+    let mut chain_id = [0u8; 32];
+    chain_id[24..].copy_from_slice(&DOMAIN_CHAIN_ID.to_be_bytes());
+
+    let mut data = vec![];
+    data.extend(keccak256(EIP712_DOMAIN_TYPE.as_bytes()));
+    data.extend(keccak256(DOMAIN_NAME.as_bytes()));
+    data.extend(keccak256(DOMAIN_VERSION.as_bytes()));
+    data.extend(chain_id);
+    data.extend([0u8; 32]); // verifyingContract = 0x0
+
+    keccak256(&data)
+}
+
+/// source: mainnet="a", testnet="b"
+pub fn agent_struct_hash(connection_id: &[u8; 32], is_mainnet: bool) -> [u8; 32] {
+    let source = if is_mainnet { "a" } else { "b" };
+
+    let mut data = vec![];
+    data.extend(keccak256(AGENT_TYPE.as_bytes()));
+    data.extend(keccak256(source.as_bytes()));
+    data.extend(connection_id);
+
+    keccak256(&data)
+}
+
+/// EIP-712の署名対象 keccak256("\x19\x01" + domainSeparator + structHash)
+pub fn agent_digest(connection_id: &[u8; 32], is_mainnet: bool) -> [u8; 32] {
+    let mut data = vec![0x19, 0x01];
+    data.extend(domain_separator());
+    data.extend(agent_struct_hash(connection_id, is_mainnet));
+
+    keccak256(&data)
+}
+
+#[cfg(test)]
+mod hyperliquid_signer_test {
+    use super::*;
+    use crate::{HyperliquidLimit, HyperliquidOrderAction, HyperliquidOrderTypeWire, HyperliquidOrderWire};
+    use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+
+    fn order_action() -> HyperliquidOrderAction {
+        HyperliquidOrderAction::new(vec![HyperliquidOrderWire {
+            a: 0,
+            b: true,
+            p: "64000".to_string(),
+            s: "0.001".to_string(),
+            r: false,
+            t: HyperliquidOrderTypeWire {
+                limit: HyperliquidLimit { tif: "Gtc".to_string() },
+            },
+            c: None,
+        }])
+    }
+
+    #[test]
+    fn test_msgpack() -> anyhow::Result<()> {
+        let bytes = rmp_serde::to_vec_named(&order_action())?;
+
+        assert_eq!(
+            hex::encode(bytes),
+            "83a474797065a56f72646572a66f72646572739186a16100a162c3a170a53634303030a173a5302e303031a172c2a17481a56c696d697481a3746966a3477463a867726f7570696e67a26e61"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eip712_hash() -> anyhow::Result<()> {
+        assert_eq!(
+            hex::encode(keccak256(EIP712_DOMAIN_TYPE.as_bytes())),
+            "8b73c3c69bb8fe3d512ecc4cf759cc79239f7b179b0ffacaa9a75d522b39400f"
+        );
+        assert_eq!(
+            hex::encode(domain_separator()),
+            "d79297fcdf2ffcd4ae223d01edaa2ba214ff8f401d7c9300d995d17c82aa4040"
+        );
+
+        let connection_id = action_hash(&order_action(), 1724371200000, None)?;
+        assert_eq!(
+            hex::encode(connection_id),
+            "e1734c4f8528c65b1df340c77e75d9bdf16f8cd5d32125ba297fe693e238fcb7"
+        );
+        assert_eq!(
+            hex::encode(agent_struct_hash(&connection_id, true)),
+            "6c34ac7d41f524be10be5b84083e79db4b26761e0147d405f55b62dbcb358cfa"
+        );
+        assert_eq!(
+            hex::encode(agent_digest(&connection_id, true)),
+            "73312a5736aed2ec80253293f09fa39640d7c39e7a2c0eb4154156a85db09f2b"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign() -> anyhow::Result<()> {
+        let signer = HyperliquidSigner::new(
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+            true,
+        )?;
+        assert_eq!(signer.address(), "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf");
+
+        let digest = agent_digest(&action_hash(&order_action(), 1724371200000, None)?, true);
+        let signature = signer.sign_digest(&digest)?;
+        assert!(signature.v == 27 || signature.v == 28);
+
+        // 署名から復元した鍵が署名者と一致する
+        let r = hex::decode(signature.r.trim_start_matches("0x"))?;
+        let s = hex::decode(signature.s.trim_start_matches("0x"))?;
+        let sig = Signature::from_scalars(
+            <[u8; 32]>::try_from(r.as_slice())?,
+            <[u8; 32]>::try_from(s.as_slice())?,
+        )?;
+        let recovery_id = RecoveryId::from_byte(signature.v - 27).unwrap();
+        let key = VerifyingKey::recover_from_prehash(&digest, &sig, recovery_id)?;
+        assert_eq!(&key, signer.key.verifying_key());
+
+        Ok(())
+    }
+}
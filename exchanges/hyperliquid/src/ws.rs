@@ -0,0 +1,170 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use futures::Stream;
+use futures::StreamExt;
+use rbot_lib::net::ReceiveMessage;
+use rbot_lib::net::WebSocketClient;
+use tokio::task::JoinHandle;
+
+use async_stream::stream;
+
+use rbot_lib::{
+    common::{ExchangeConfig, MarketConfig, MultiMarketMessage},
+    net::{AutoConnectClient, RawMessageHook, WsOpMessage, WsStateHandle},
+};
+
+use crate::HyperliquidPublicWsMessage;
+
+use serde_json::{json, Value};
+
+use anyhow::anyhow;
+
+/// https://hyperliquid.gitbook.io/hyperliquid-docs/for-developers/api/websocket
+/// 60秒メッセージがないと切断されるため、アプリケーションレベルのpingを送る
+pub const PING_INTERVAL_SEC: i64 = 30;
+pub const SWITCH_INTERVAL_SEC: i64 = 60 * 60 * 12; // 12 hours
+const SYNC_WAIT_RECORDS_FOR_PUBLIC: i64 = 3;
+
+/// paramsにはsubscriptionのJSON({"type":"trades","coin":"BTC"})を入れる。
+/// 1メッセージで1つしか購読できないので、paramsごとにメッセージを作る。
+#[derive(Debug, Clone)]
+pub struct HyperliquidWsOpMessage {
+    params: Vec<String>,
+}
+
+impl WsOpMessage for HyperliquidWsOpMessage {
+    fn new() -> Self {
+        HyperliquidWsOpMessage { params: vec![] }
+    }
+
+    fn add_params(&mut self, params: &Vec<String>) {
+        log::debug!("add_params: {:?} / {:?}", self.params, params);
+        self.params.extend(params.clone());
+    }
+
+    fn make_message(&self) -> Vec<String> {
+        self.params
+            .iter()
+            .filter_map(|p| serde_json::from_str::<Value>(p).ok())
+            .map(|subscription| {
+                json!({"method": "subscribe", "subscription": subscription}).to_string()
+            })
+            .collect()
+    }
+
+    fn to_string(&self) -> String {
+        if self.params.len() == 0 {
+            return "".to_string();
+        } else {
+            return self.make_message().join("\n");
+        }
+    }
+
+    fn get_ping_message() -> String {
+        r#"{"method":"ping"}"#.to_string()
+    }
+}
+
+pub struct HyperliquidPublicWsClient {
+    ws: AutoConnectClient<HyperliquidWsOpMessage>,
+    _handler: Option<JoinHandle<()>>,
+}
+
+impl WebSocketClient for HyperliquidPublicWsClient {
+    async fn new(server: &ExchangeConfig, config: &MarketConfig) -> Self {
+        let mut public_ws = AutoConnectClient::new(
+            server,
+            config,
+            &server.get_public_ws_server(),
+            PING_INTERVAL_SEC,
+            SWITCH_INTERVAL_SEC,
+            SYNC_WAIT_RECORDS_FOR_PUBLIC,
+            None,
+            None,
+        );
+
+        // l2Bookは深さを指定できず、毎回全体のスナップショットが送られる
+        public_ws
+            .subscribe(&vec![
+                json!({"type": "trades", "coin": config.trade_symbol}).to_string(),
+                json!({"type": "l2Book", "coin": config.trade_symbol}).to_string(),
+            ])
+            .await;
+
+        Self {
+            ws: public_ws,
+            _handler: None,
+        }
+    }
+
+    async fn open_stream<'a>(
+        &'a mut self,
+    ) -> impl Stream<Item = Result<MultiMarketMessage, String>> + 'a + Send {
+        let mut s = Box::pin(self.ws.open_stream().await);
+
+        stream! {
+            while let Some(message) = s.next().await {
+                match message {
+                    Ok(m) => {
+                        if let ReceiveMessage::Text(m) = m {
+                            match Self::parse_message(m) {
+                                Err(e) => {
+                                    log::warn!("Parse Error: {:?}", e);
+                                    continue;
+                                }
+                                Ok(m) => {
+                                    yield Ok(m.into());
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Receive Error: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_raw_message_hook(&mut self, hook: Option<RawMessageHook>) {
+        self.ws.set_raw_message_hook(hook);
+    }
+
+    fn set_state_handle(&mut self, state: WsStateHandle) {
+        self.ws.set_state_handle(state);
+    }
+}
+
+impl HyperliquidPublicWsClient {
+    fn parse_message(message: String) -> anyhow::Result<HyperliquidPublicWsMessage> {
+        let m = serde_json::from_str::<HyperliquidPublicWsMessage>(&message);
+
+        if m.is_err() {
+            log::warn!("Error in serde_json::from_str: {:?}", message);
+            return Err(anyhow!("Error in serde_json::from_str: {:?}", message));
+        }
+
+        Ok(m.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod hyperliquid_ws_test {
+    use super::*;
+
+    #[test]
+    fn test_subscribe_message() {
+        let mut message = HyperliquidWsOpMessage::new();
+        assert_eq!(message.to_string(), "");
+
+        message.add_params(&vec![r#"{"type":"trades","coin":"BTC"}"#.to_string()]);
+        message.add_params(&vec![r#"{"type":"l2Book","coin":"BTC"}"#.to_string()]);
+
+        let messages = message.make_message();
+        assert_eq!(messages.len(), 2);
+
+        let m: Value = serde_json::from_str(&messages[1]).unwrap();
+        assert_eq!(m["method"], "subscribe");
+        assert_eq!(m["subscription"]["type"], "l2Book");
+    }
+}
@@ -14,22 +14,69 @@ pub fn BLOCK_ON<F: Future>(f: F) -> F::Output {
     result
 }
 
+/// BLOCK_ON_TIMEOUTでtimeout_sec以内にFutureが完了しなかった
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    pub timeout_sec: u64,
+}
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timeout ({}[sec])", self.timeout_sec)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// タイムアウトした場合はpanicせずにErr(TimeoutError)を返す
 #[allow(non_snake_case)]
-pub fn BLOCK_ON_TIMEOUT<F>(timeout_sec: u64, f: F) -> F::Output
+pub fn BLOCK_ON_TIMEOUT<F>(timeout_sec: u64, f: F) -> Result<F::Output, TimeoutError>
 where
     F: Future,
 {
     log::debug!("BLOCK_ON_TIMEOUT: (timeout={})", timeout_sec);
 
-    let result = RUNTIME.block_on(async {
+    RUNTIME.block_on(async {
         let duration = Duration::from_secs(timeout_sec);
 
-        match timeout(duration, f).await {
-            Ok(result) => result,
-            Err(_) => panic!("Timeout"),
-        }        
-    });
+        timeout(duration, f).await.map_err(|_| {
+            log::warn!("BLOCK_ON_TIMEOUT: timeout {}[sec]", timeout_sec);
+            TimeoutError { timeout_sec }
+        })
+    })
+}
 
-    result
+/// タイムアウトした場合はdefaultを返す
+#[allow(non_snake_case)]
+pub fn BLOCK_ON_TIMEOUT_OR_DEFAULT<F>(timeout_sec: u64, default: F::Output, f: F) -> F::Output
+where
+    F: Future,
+{
+    BLOCK_ON_TIMEOUT(timeout_sec, f).unwrap_or(default)
 }
 
+#[cfg(test)]
+mod blockon_test {
+    use super::*;
+
+    #[test]
+    fn test_block_on_timeout() {
+        let r = BLOCK_ON_TIMEOUT(1, async { 1 });
+        assert_eq!(r, Ok(1));
+
+        let r = BLOCK_ON_TIMEOUT(1, async {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            1
+        });
+        assert_eq!(r, Err(TimeoutError { timeout_sec: 1 }));
+    }
+
+    #[test]
+    fn test_block_on_timeout_or_default() {
+        let r = BLOCK_ON_TIMEOUT_OR_DEFAULT(1, -1, async {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            1
+        });
+        assert_eq!(r, -1);
+    }
+}
@@ -1,14 +1,80 @@
 use std::{
     borrow::BorrowMut,
     io::{self, Write as _},
+    sync::RwLock,
 };
 
+use anyhow::anyhow;
 use pyo3::{
+    pyclass, pyfunction,
     types::{IntoPyDict as _, PyAnyMethods as _, PyModule},
     Bound, Py, PyAny, Python,
 };
 
-use super::{calc_class, is_notebook, MarketConfig};
+use super::{calc_class, env_rbot_progress, is_notebook, MarketConfig};
+
+/// 進捗の出力先。環境変数RBOT_PROGRESS(terminal/quiet/json)かset_progress_modeで選ぶ。
+///  - Terminal: tqdmのバーを表示する
+///  - Quiet: 何も出力しない
+///  - Json: ダウンロードした日ごとに{"day":..,"total":..,"rec":..}を1行stderrへ出力する
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    Terminal,
+    Quiet,
+    Json,
+}
+
+impl TryFrom<&str> for ProgressMode {
+    type Error = anyhow::Error;
+
+    fn try_from(mode: &str) -> anyhow::Result<Self> {
+        match mode.to_lowercase().as_str() {
+            "terminal" => Ok(ProgressMode::Terminal),
+            "quiet" => Ok(ProgressMode::Quiet),
+            "json" => Ok(ProgressMode::Json),
+            _ => Err(anyhow!("unknown progress mode {:?}", mode)),
+        }
+    }
+}
+
+static PROGRESS_MODE: RwLock<Option<ProgressMode>> = RwLock::new(None);
+
+/// set_progress_modeで設定した値、なければ環境変数の値(既定はTerminal)
+pub fn progress_mode() -> ProgressMode {
+    if let Some(mode) = *PROGRESS_MODE.read().unwrap() {
+        return mode;
+    }
+
+    match env_rbot_progress() {
+        Ok(mode) => ProgressMode::try_from(mode.as_str()).unwrap_or_else(|e| {
+            log::warn!("{:?}, use terminal", e);
+            ProgressMode::Terminal
+        }),
+        Err(_) => ProgressMode::Terminal,
+    }
+}
+
+#[pyfunction]
+pub fn set_progress_mode(mode: ProgressMode) {
+    *PROGRESS_MODE.write().unwrap() = Some(mode);
+}
+
+#[pyfunction]
+pub fn get_progress_mode() -> ProgressMode {
+    progress_mode()
+}
+
+fn progress_json_line(day: i64, total: i64, rec: i64) -> String {
+    format!(r#"{{"day":{},"total":{},"rec":{}}}"#, day, total, rec)
+}
+
+/// Jsonモードの時だけ進捗を1行stderrへ出力する
+pub fn emit_progress_json(day: i64, total: i64, rec: i64) {
+    if progress_mode() == ProgressMode::Json {
+        eprintln!("{}", progress_json_line(day, total, rec));
+    }
+}
 
 
 const PY_TQDM_PYTHON: &str = r#"
@@ -21,6 +87,72 @@ from tqdm.notebook import tqdm
 
 const PY_BAR: &str = include_str!("./bar.py");
 
+/// ダウンロードの進捗通知
+pub trait ProgressCallback: Send {
+    fn on_file_start(&self, url: &str);
+    fn on_file_done(&self, url: &str, records: i64);
+    fn on_total_progress(&self, done: usize, total: usize);
+}
+
+/// 進捗をstderrへ出力する
+pub struct ConsoleProgressCallback;
+
+impl ProgressCallback for ConsoleProgressCallback {
+    fn on_file_start(&self, url: &str) {
+        eprintln!("download start: {}", url);
+    }
+
+    fn on_file_done(&self, url: &str, records: i64) {
+        eprintln!("download done: {} {}[rec]", url, records);
+    }
+
+    fn on_total_progress(&self, done: usize, total: usize) {
+        eprintln!("progress: {}/{}", done, total);
+    }
+}
+
+/// Pythonのcallableへ進捗を通知する。
+/// callback(event, url, value, total)の形で呼ばれる。
+///  - ("file_start", url, 0, 0)
+///  - ("file_done", url, records, 0)
+///  - ("progress", "", done, total)
+pub struct PyProgressCallback {
+    handler: Py<PyAny>,
+}
+
+impl PyProgressCallback {
+    pub fn new(handler: Py<PyAny>) -> Self {
+        Self { handler }
+    }
+
+    pub fn boxed(handler: Option<Py<PyAny>>) -> Option<Box<dyn ProgressCallback>> {
+        handler.map(|h| Box::new(Self::new(h)) as Box<dyn ProgressCallback>)
+    }
+
+    fn call(&self, event: &str, url: &str, value: i64, total: i64) {
+        Python::with_gil(|py| {
+            let r = self.handler.call1(py, (event, url, value, total));
+            if r.is_err() {
+                log::error!("progress callback error: {:?}", r.err());
+            }
+        });
+    }
+}
+
+impl ProgressCallback for PyProgressCallback {
+    fn on_file_start(&self, url: &str) {
+        self.call("file_start", url, 0, 0);
+    }
+
+    fn on_file_done(&self, url: &str, records: i64) {
+        self.call("file_done", url, records, 0);
+    }
+
+    fn on_total_progress(&self, done: usize, total: usize) {
+        self.call("progress", "", done as i64, total as i64);
+    }
+}
+
 
 
 pub struct PyRestBar {
@@ -43,6 +175,12 @@ impl PyRestBar {
     }
 
     pub fn init(&mut self, total_duration: i64, enable: bool, verbose_print: bool) {
+        if progress_mode() != ProgressMode::Terminal {
+            self.enable = false;
+            self.verbose_print = false;
+            return;
+        }
+
         let py_script = if is_notebook() {
             format!("{}{}", PY_TQDM_NOTEBOOK, PY_BAR)
         } else {
@@ -135,6 +273,17 @@ impl PyFileBar {
     }
 
     pub fn init(&mut self, total_files: i64, enable: bool, verbose_print: bool) {
+        self.init_with_mode(total_files, enable, verbose_print, progress_mode());
+    }
+
+    /// 進捗の出力先を明示して初期化する(Terminal以外はバーを作らない)
+    pub fn init_with_mode(&mut self, total_files: i64, enable: bool, verbose_print: bool, mode: ProgressMode) {
+        if mode != ProgressMode::Terminal {
+            self.enable = false;
+            self.verbose_print = false;
+            return;
+        }
+
         let py_script = if is_notebook() {
             format!("{}{}", PY_TQDM_NOTEBOOK, PY_BAR)
         } else {
@@ -251,6 +400,12 @@ impl PyRunningBar {
     }
 
     pub fn init(&mut self, total_duration: i64, has_bar: bool, enable: bool, verbose_print: bool) {
+        if progress_mode() != ProgressMode::Terminal {
+            self.enable = false;
+            self.verbose_print = false;
+            return;
+        }
+
         let py_script = if is_notebook() {
             format!("{}{}", PY_TQDM_NOTEBOOK, PY_BAR)
         } else {
@@ -345,7 +500,23 @@ mod test_bar {
 
     use crate::common::DAYS;
 
-    use super::{PyFileBar, PyRestBar, PyRunningBar};
+    use super::{progress_json_line, PyFileBar, PyRestBar, PyRunningBar};
+    use super::ProgressMode;
+
+    #[test]
+    fn test_progress_mode() {
+        assert_eq!(ProgressMode::try_from("JSON").unwrap(), ProgressMode::Json);
+        assert_eq!(ProgressMode::try_from("quiet").unwrap(), ProgressMode::Quiet);
+        assert!(ProgressMode::try_from("tty").is_err());
+
+        assert_eq!(progress_json_line(3, 10, 12345), r#"{"day":3,"total":10,"rec":12345}"#);
+
+        // Quietではtqdmを作らず、printも出力しない(グローバルの設定は変えない)
+        let mut bar = PyFileBar::new();
+        bar.init_with_mode(10, true, true, ProgressMode::Quiet);
+        assert!(!bar.enable);
+        assert!(!bar.verbose_print);
+    }
 
     #[test]
     fn test_py_restbar() {
@@ -1,6 +1,21 @@
-use rust_decimal::{prelude::FromPrimitive, Decimal};
+use anyhow::anyhow;
+use polars::prelude::{DataFrame, NamedFrom, Series};
+use pyo3::{pyclass, pyfunction, pymethods};
+use pyo3_polars::PyDataFrame;
+use rust_decimal::{prelude::FromPrimitive, prelude::ToPrimitive, Decimal};
+use rust_decimal_macros::dec;
 use super::format_number;
-use super::MarketConfig;
+use super::{time_string, MarketConfig, MicroSec, Trade};
+
+use crate::db::KEY;
+
+pub const REGIME: &str = "regime";
+
+/// ADXがこの値以上ならトレンド、未満ならレンジ
+pub const ADX_TREND_THRESHOLD: f64 = 25.0;
+
+/// R/S解析で使う最小の区間長
+const HURST_MIN_CHUNK: usize = 8;
 
 pub fn calc_class(config: &MarketConfig, profit: f64, duration_min: i64) -> String {
     if duration_min < 1 {
@@ -65,6 +80,349 @@ pub fn calc_class(config: &MarketConfig, profit: f64, duration_min: i64) -> Stri
         );
 }
 
+/// Wilderの平滑化(初期値はwindow本の単純平均)。window本に満たない間はNaN。
+fn wilder_smooth(values: &[f64], window: usize) -> Vec<f64> {
+    let mut result = vec![f64::NAN; values.len()];
+
+    if window == 0 || values.len() < window {
+        return result;
+    }
+
+    let mut avg = values[..window].iter().sum::<f64>() / window as f64;
+    result[window - 1] = avg;
+
+    for i in window..values.len() {
+        avg = (avg * (window - 1) as f64 + values[i]) / window as f64;
+        result[i] = avg;
+    }
+
+    result
+}
+
+/// (ADX, +DI, -DI)。先頭の計算できない行はNaN。
+pub fn calc_adx(high: &[f64], low: &[f64], close: &[f64], window: usize) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+    let len = close.len();
+
+    let mut tr = vec![0.0; len];
+    let mut plus_dm = vec![0.0; len];
+    let mut minus_dm = vec![0.0; len];
+
+    for i in 1..len {
+        tr[i] = (high[i] - low[i])
+            .max((high[i] - close[i - 1]).abs())
+            .max((low[i] - close[i - 1]).abs());
+
+        let up = high[i] - high[i - 1];
+        let down = low[i - 1] - low[i];
+
+        if up > down && up > 0.0 {
+            plus_dm[i] = up;
+        }
+        if down > up && down > 0.0 {
+            minus_dm[i] = down;
+        }
+    }
+
+    // 1本目は前日比がないので除いて平滑化する
+    let skip = len.min(1);
+    let tr = wilder_smooth(&tr[skip..], window);
+    let plus_dm = wilder_smooth(&plus_dm[skip..], window);
+    let minus_dm = wilder_smooth(&minus_dm[skip..], window);
+
+    let mut plus_di = vec![f64::NAN; len];
+    let mut minus_di = vec![f64::NAN; len];
+    let mut dx = vec![];
+
+    for i in 0..tr.len() {
+        if tr[i].is_nan() {
+            continue;
+        }
+
+        let (p, m) = if tr[i] == 0.0 {
+            (0.0, 0.0)
+        } else {
+            (100.0 * plus_dm[i] / tr[i], 100.0 * minus_dm[i] / tr[i])
+        };
+
+        plus_di[i + skip] = p;
+        minus_di[i + skip] = m;
+        dx.push(if p + m == 0.0 { 0.0 } else { 100.0 * (p - m).abs() / (p + m) });
+    }
+
+    let mut adx = vec![f64::NAN; len];
+    let first_dx = len - dx.len();
+    for (i, v) in wilder_smooth(&dx, window).into_iter().enumerate() {
+        adx[first_dx + i] = v;
+    }
+
+    (adx, plus_di, minus_di)
+}
+
+/// ohlcvにregime列(i8)を追加する。
+/// ADXがADX_TREND_THRESHOLD以上なら+DI/-DIの大きい方向(+1:上昇, -1:下降)、それ以外は0(レンジ)。
+/// ADXの計算に必要な足(window * 2)が揃うまでは0。
+pub fn detect_regime(df: &DataFrame, window: usize) -> anyhow::Result<DataFrame> {
+    let high: Vec<f64> = df.column(KEY::high)?.f64()?.into_iter().map(|v| v.unwrap_or(f64::NAN)).collect();
+    let low: Vec<f64> = df.column(KEY::low)?.f64()?.into_iter().map(|v| v.unwrap_or(f64::NAN)).collect();
+    let close: Vec<f64> = df.column(KEY::close)?.f64()?.into_iter().map(|v| v.unwrap_or(f64::NAN)).collect();
+
+    let (adx, plus_di, minus_di) = calc_adx(&high, &low, &close, window);
+
+    let regime: Vec<i8> = (0..adx.len())
+        .map(|i| {
+            if adx[i].is_nan() || adx[i] < ADX_TREND_THRESHOLD {
+                0
+            } else if plus_di[i] > minus_di[i] {
+                1
+            } else if plus_di[i] < minus_di[i] {
+                -1
+            } else {
+                0
+            }
+        })
+        .collect();
+
+    let mut df = df.clone();
+    df.with_column(Series::new(REGIME, regime))?;
+
+    Ok(df)
+}
+
+/// 価格の差分をR/S解析したHurst指数。
+/// 0.5より大きければトレンド(持続性)、小さければ平均回帰。データが足りない場合はNaN。
+pub fn hurst_exponent(prices: &[f64]) -> f64 {
+    let increments: Vec<f64> = prices.windows(2).map(|w| w[1] - w[0]).collect();
+    let len = increments.len();
+
+    let mut log_n = vec![];
+    let mut log_rs = vec![];
+
+    let mut n = HURST_MIN_CHUNK;
+    while n <= len / 2 {
+        let mut rs_sum = 0.0;
+        let mut rs_count = 0;
+
+        for chunk in increments.chunks_exact(n) {
+            let mean = chunk.iter().sum::<f64>() / n as f64;
+
+            let mut cum = 0.0;
+            let mut max = f64::MIN;
+            let mut min = f64::MAX;
+            for v in chunk {
+                cum += v - mean;
+                max = max.max(cum);
+                min = min.min(cum);
+            }
+
+            let std = (chunk.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n as f64).sqrt();
+
+            if 0.0 < std {
+                rs_sum += (max - min) / std;
+                rs_count += 1;
+            }
+        }
+
+        if rs_count != 0 {
+            log_n.push((n as f64).ln());
+            log_rs.push((rs_sum / rs_count as f64).ln());
+        }
+
+        n *= 2;
+    }
+
+    if log_n.len() < 2 {
+        return f64::NAN;
+    }
+
+    // log(R/S) = H * log(n) + c の傾き
+    let mean_x = log_n.iter().sum::<f64>() / log_n.len() as f64;
+    let mean_y = log_rs.iter().sum::<f64>() / log_rs.len() as f64;
+
+    let cov: f64 = log_n.iter().zip(&log_rs).map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let var: f64 = log_n.iter().map(|x| (x - mean_x).powi(2)).sum();
+
+    cov / var
+}
+
+#[pyfunction]
+#[pyo3(name = "detect_regime", signature = (df, window=14))]
+pub fn py_detect_regime(df: PyDataFrame, window: usize) -> anyhow::Result<PyDataFrame> {
+    let df = detect_regime(&df.0, window)?;
+
+    Ok(PyDataFrame(df))
+}
+
+#[pyfunction]
+#[pyo3(name = "hurst_exponent")]
+pub fn py_hurst_exponent(prices: Vec<f64>) -> f64 {
+    hurst_exponent(&prices)
+}
+
+/// LiveOHLCV(時間足)とTickBarAggregator(件数/代金/出来高足)が出力する足。closed=falseは更新中の足。
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OhlcvBar {
+    #[pyo3(get)]
+    pub timestamp: MicroSec,
+    #[pyo3(get)]
+    pub open: Decimal,
+    #[pyo3(get)]
+    pub high: Decimal,
+    #[pyo3(get)]
+    pub low: Decimal,
+    #[pyo3(get)]
+    pub close: Decimal,
+    #[pyo3(get)]
+    pub volume: Decimal,
+    #[pyo3(get)]
+    pub count: i64,
+    #[pyo3(get)]
+    pub closed: bool,
+}
+
+impl OhlcvBar {
+    pub fn new(timestamp: MicroSec, trade: &Trade) -> Self {
+        Self {
+            timestamp,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.size,
+            count: 1,
+            closed: false,
+        }
+    }
+
+    pub fn update(&mut self, trade: &Trade) {
+        if self.high < trade.price {
+            self.high = trade.price;
+        }
+        if trade.price < self.low {
+            self.low = trade.price;
+        }
+        self.close = trade.price;
+        self.volume += trade.size;
+        self.count += 1;
+    }
+}
+
+#[pymethods]
+impl OhlcvBar {
+    fn __repr__(&self) -> String {
+        format!(
+            "{{timestamp: {}, open: {}, high: {}, low: {}, close: {}, volume: {}, count: {}, closed: {}}}",
+            time_string(self.timestamp),
+            self.open,
+            self.high,
+            self.low,
+            self.close,
+            self.volume,
+            self.count,
+            self.closed
+        )
+    }
+}
+
+/// 代替足の区切り方と閾値
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarType {
+    /// 約定件数
+    TickBar(u64),
+    /// 約定代金(price * size)
+    DollarBar(Decimal),
+    /// 出来高(size)
+    VolumeBar(Decimal),
+}
+
+impl BarType {
+    pub fn threshold(&self) -> Decimal {
+        match self {
+            BarType::TickBar(count) => Decimal::from(*count),
+            BarType::DollarBar(amount) => *amount,
+            BarType::VolumeBar(volume) => *volume,
+        }
+    }
+
+    fn measure(&self, trade: &Trade) -> Decimal {
+        match self {
+            BarType::TickBar(_) => dec![1.0],
+            BarType::DollarBar(_) => trade.price * trade.size,
+            BarType::VolumeBar(_) => trade.size,
+        }
+    }
+}
+
+/// 時間ではなく約定件数/代金/出来高の累積で区切った足を作る。
+/// 1件の約定は分割せず、閾値に達した約定を含めて足を確定する。足のtimestampは最初の約定時刻。
+#[derive(Debug, Clone)]
+pub struct TickBarAggregator {
+    pub threshold: Decimal,
+    pub bar_type: BarType,
+    current: Option<OhlcvBar>,
+    accumulated: Decimal,
+}
+
+impl TickBarAggregator {
+    pub fn new(bar_type: BarType) -> anyhow::Result<Self> {
+        let threshold = bar_type.threshold();
+
+        if threshold <= dec![0.0] {
+            return Err(anyhow!("threshold must be positive: {:?}", bar_type));
+        }
+
+        Ok(Self {
+            threshold,
+            bar_type,
+            current: None,
+            accumulated: dec![0.0],
+        })
+    }
+
+    /// 更新中(未確定)の足
+    pub fn current(&self) -> Option<OhlcvBar> {
+        self.current.clone()
+    }
+
+    /// 累積が閾値に達したら確定した足(closed=true)を返す
+    pub fn push(&mut self, trade: &Trade) -> Option<OhlcvBar> {
+        match self.current.as_mut() {
+            Some(bar) => bar.update(trade),
+            None => self.current = Some(OhlcvBar::new(trade.time, trade)),
+        }
+
+        self.accumulated += self.bar_type.measure(trade);
+
+        if self.accumulated < self.threshold {
+            return None;
+        }
+
+        self.accumulated = dec![0.0];
+
+        let mut bar = self.current.take().unwrap();
+        bar.closed = true;
+
+        Some(bar)
+    }
+}
+
+/// 足の列をDataFrame(timestamp, open, high, low, close, volume, count)にする
+pub fn ohlcv_bars_df(bars: &[OhlcvBar]) -> anyhow::Result<DataFrame> {
+    let to_f64 = |v: Decimal| v.to_f64().unwrap_or_default();
+
+    let df = DataFrame::new(vec![
+        Series::new(KEY::timestamp, bars.iter().map(|b| b.timestamp).collect::<Vec<i64>>()),
+        Series::new(KEY::open, bars.iter().map(|b| to_f64(b.open)).collect::<Vec<f64>>()),
+        Series::new(KEY::high, bars.iter().map(|b| to_f64(b.high)).collect::<Vec<f64>>()),
+        Series::new(KEY::low, bars.iter().map(|b| to_f64(b.low)).collect::<Vec<f64>>()),
+        Series::new(KEY::close, bars.iter().map(|b| to_f64(b.close)).collect::<Vec<f64>>()),
+        Series::new(KEY::volume, bars.iter().map(|b| to_f64(b.volume)).collect::<Vec<f64>>()),
+        Series::new(KEY::count, bars.iter().map(|b| b.count).collect::<Vec<i64>>()),
+    ])?;
+
+    Ok(df)
+}
+
 #[cfg(test)]
 mod class_calc_test {
 
@@ -86,4 +444,161 @@ mod class_calc_test {
         log::debug!("{}", calc_class(&config, -100.0, 1));
         log::debug!("{}", calc_class(&config, 100.0, 1));
     }
+
+    fn trade(time: i64, price: i64, size: i64) -> crate::common::Trade {
+        use crate::common::{LogStatus, OrderSide, Trade};
+        use rust_decimal::Decimal;
+
+        Trade::new(time, OrderSide::Buy, Decimal::from(price), Decimal::from(size), LogStatus::UnFix, "")
+    }
+
+    #[test]
+    fn test_tick_bar() {
+        use crate::common::{BarType, TickBarAggregator};
+
+        let mut aggregator = TickBarAggregator::new(BarType::TickBar(3)).unwrap();
+
+        let mut bars = vec![];
+        for i in 0..7 {
+            if let Some(bar) = aggregator.push(&trade(i, 100 + i, 1)) {
+                bars.push((i, bar));
+            }
+        }
+
+        // 3件目, 6件目で確定
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[0].0, 2);
+        assert_eq!(bars[0].1.timestamp, 0);
+        assert_eq!(bars[0].1.open, rust_decimal_macros::dec![100]);
+        assert_eq!(bars[0].1.close, rust_decimal_macros::dec![102]);
+        assert_eq!(bars[0].1.count, 3);
+        assert!(bars[0].1.closed);
+        assert_eq!(bars[1].0, 5);
+        assert_eq!(bars[1].1.timestamp, 3);
+
+        let current = aggregator.current().unwrap();
+        assert_eq!(current.count, 1);
+        assert!(!current.closed);
+
+        assert!(TickBarAggregator::new(BarType::TickBar(0)).is_err());
+    }
+
+    #[test]
+    fn test_dollar_and_volume_bar() {
+        use crate::common::{ohlcv_bars_df, BarType, TickBarAggregator};
+        use rust_decimal_macros::dec;
+
+        let mut aggregator = TickBarAggregator::new(BarType::DollarBar(dec![1000])).unwrap();
+
+        // 400 + 599 = 999 では確定しない
+        assert!(aggregator.push(&trade(1, 100, 4)).is_none());
+        assert!(aggregator.push(&trade(2, 599, 1)).is_none());
+        // ちょうど閾値に達した約定で確定
+        let bar = aggregator.push(&trade(3, 1, 1)).unwrap();
+        assert_eq!(bar.count, 3);
+        assert_eq!(bar.high, dec![599]);
+        assert_eq!(bar.low, dec![1]);
+        assert_eq!(bar.volume, dec![6]);
+
+        // 1件で閾値を超える約定はその1件で確定
+        let bar = aggregator.push(&trade(4, 100, 20)).unwrap();
+        assert_eq!(bar.count, 1);
+        assert!(aggregator.current().is_none());
+
+        let mut aggregator = TickBarAggregator::new(BarType::VolumeBar(dec![2])).unwrap();
+        let bars: Vec<_> = [1, 1, 3, 1]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, size)| aggregator.push(&trade(i as i64, 100, *size)))
+            .collect();
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[0].volume, dec![2]);
+        assert_eq!(bars[1].volume, dec![3]);
+
+        let df = ohlcv_bars_df(&bars).unwrap();
+        assert_eq!(df.shape(), (2, 7));
+    }
+
+    fn make_ohlcv(close: &[f64]) -> polars::prelude::DataFrame {
+        use polars::prelude::{DataFrame, NamedFrom, Series};
+        use crate::db::KEY;
+
+        let high: Vec<f64> = close.iter().map(|c| c + 0.5).collect();
+        let low: Vec<f64> = close.iter().map(|c| c - 0.5).collect();
+
+        DataFrame::new(vec![
+            Series::new(KEY::open, close.to_vec()),
+            Series::new(KEY::high, high),
+            Series::new(KEY::low, low),
+            Series::new(KEY::close, close.to_vec()),
+        ])
+        .unwrap()
+    }
+
+    fn regimes(close: &[f64]) -> Vec<i8> {
+        let df = crate::common::detect_regime(&make_ohlcv(close), 14).unwrap();
+
+        df.column(crate::common::REGIME).unwrap().i8().unwrap().into_no_null_iter().collect()
+    }
+
+    #[test]
+    fn test_detect_regime() {
+        // 直線的な上昇・下降はトレンド
+        let up: Vec<f64> = (0..100).map(|i| 100.0 + i as f64).collect();
+        let r = regimes(&up);
+        assert_eq!(r.len(), 100);
+        assert_eq!(r[0], 0); // ADXが計算できるまでは0
+        assert_eq!(r[99], 1);
+
+        let down: Vec<f64> = (0..100).map(|i| 200.0 - i as f64).collect();
+        assert_eq!(regimes(&down)[99], -1);
+
+        // 上下を繰り返すだけならレンジ
+        let range: Vec<f64> = (0..100).map(|i| if i % 2 == 0 { 100.0 } else { 101.0 }).collect();
+        assert_eq!(regimes(&range)[99], 0);
+    }
+
+    /// 再現性のある乱数(-0.5..0.5)
+    fn random_walk_increments(count: usize) -> Vec<f64> {
+        let mut seed: u64 = 12345;
+        (0..count)
+            .map(|_| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (seed >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+            })
+            .collect()
+    }
+
+    fn to_prices(increments: &[f64]) -> Vec<f64> {
+        let mut prices = vec![100.0];
+        for d in increments {
+            prices.push(prices.last().unwrap() + d);
+        }
+        prices
+    }
+
+    #[test]
+    fn test_hurst_exponent() {
+        use crate::common::hurst_exponent;
+
+        let noise = random_walk_increments(4096);
+
+        let h = hurst_exponent(&to_prices(&noise));
+        assert!(0.4 < h && h < 0.65, "random walk {}", h);
+
+        // 増分に自己相関があればトレンド
+        let mut d = 0.0;
+        let persistent: Vec<f64> = noise.iter().map(|n| { d = 0.9 * d + n; d }).collect();
+        let h = hurst_exponent(&to_prices(&persistent));
+        assert!(0.65 < h, "persistent {}", h);
+
+        // 交互に上下するのは平均回帰
+        let alternate: Vec<f64> = (0..4096).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }).collect();
+        let h = hurst_exponent(&to_prices(&alternate));
+        assert!(h < 0.1, "alternate {}", h);
+
+        assert!(hurst_exponent(&[1.0, 2.0, 3.0]).is_nan());
+    }
 }
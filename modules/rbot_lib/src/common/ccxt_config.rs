@@ -123,6 +123,7 @@ pub fn get_market_config(exchange_name: &str, symbol: &str) -> anyhow::Result<Ma
         market.maker_fee,
         market.taker_fee,
         fee_type,
+        0,
     ))
 }
 
@@ -140,6 +141,33 @@ pub fn list_exchange() -> anyhow::Result<Vec<String>> {
     return Ok(exchange_list);
 }
 
+/// unified symbol("BTC/USDT:USDT")から取引所のシンボル("BTCUSDT")を引く
+pub fn exchange_symbol(exchange_name: &str, unified_symbol: &str) -> anyhow::Result<String> {
+    Ok(get_market_json(exchange_name, unified_symbol)?.trade_symbol)
+}
+
+/// 取引所のカテゴリとシンボルからunified symbolを引く
+pub fn unified_symbol(
+    exchange_name: &str,
+    trade_category: &str,
+    trade_symbol: &str,
+) -> anyhow::Result<String> {
+    let exchange_config = get_exchange_config(exchange_name)?;
+
+    for market in exchange_config.markets {
+        if market.trade_category == trade_category && market.trade_symbol == trade_symbol {
+            return Ok(market.symbol);
+        }
+    }
+
+    Err(anyhow!(
+        "not found market ({}/{}) in exchange({})",
+        trade_category,
+        trade_symbol,
+        exchange_name
+    ))
+}
+
 pub fn list_symbols(exchange_name: &str) -> anyhow::Result<Vec<String>> {
     let exchange_config = get_exchange_config(exchange_name)?;
 
@@ -8,6 +8,7 @@ use pyo3::pymethods;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
+use super::order::Fill;
 use super::order::Order;
 use super::order::Trade;
 use super::AccountCoins;
@@ -15,6 +16,9 @@ use super::AccountPair;
 use super::BoardTransfer;
 use super::MarketConfig;
 use super::OrderBookRaw;
+use super::PositionInfo;
+use super::TickerInfo;
+use super::TickerUpdate;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ControlMessage {
@@ -27,8 +31,13 @@ pub struct ControlMessage {
 pub enum MarketMessage {
     Trade(Trade),
     Order(Order),
+    /// 照合で取引所から取得した注文(execute_sizeは累計)。差分はSession側で計算する。
+    ReconciledOrder(Order),
     Account(AccountCoins),
     Orderbook(OrderBookRaw),
+    PositionUpdate(PositionInfo),
+    Fill(Fill),
+    Ticker(TickerInfo),
     Control(ControlMessage),
     Message(String),
     ErrorMessage(String)
@@ -65,6 +74,18 @@ impl MarketMessage {
         MarketMessage::Account(account)
     }
 
+    pub fn from_position(position: PositionInfo) -> Self {
+        MarketMessage::PositionUpdate(position)
+    }
+
+    pub fn from_fill(fill: Fill) -> Self {
+        MarketMessage::Fill(fill)
+    }
+
+    pub fn from_ticker(ticker: TickerInfo) -> Self {
+        MarketMessage::Ticker(ticker)
+    }
+
     pub fn from_orderbook(orderbook: OrderBookRaw) -> Self {
         MarketMessage::Orderbook(orderbook)
     }
@@ -94,6 +115,9 @@ pub enum MultiMarketMessage {
     Order(Vec<Order>),
     Account(AccountCoins),
     Orderbook(BoardTransfer),
+    PositionUpdate(Vec<PositionInfo>),
+    Fill(Vec<Fill>),
+    Ticker(TickerUpdate),
     Message(String),
     Control(ControlMessage),
 }
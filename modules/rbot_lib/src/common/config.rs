@@ -1,8 +1,13 @@
 // Copyright(c) 2022-4. yasstake. All rights reserved.
 // ABSOLUTELY NO WARRANTY.
 
-use super::{env_api_key, env_api_secret, get_market_config, get_server_config, list_exchange, list_symbols, SecretString};
-use anyhow::anyhow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use super::{env_api_key, env_api_secret, get_market_config, get_server_config, list_exchange, list_symbols, to_mask_string, MarketError, SecretString, Symbol};
+use anyhow::{anyhow, Context as _};
+use once_cell::sync::Lazy;
 use pyo3::{pyclass, pymethods, types::PyAnyMethods as _, Bound, PyAny, PyResult};
 use rusqlite::ffi::SQLITE_LIMIT_FUNCTION_ARG;
 use rust_decimal::{prelude::FromPrimitive, Decimal};
@@ -22,6 +27,9 @@ pub struct ExchangeConfig {
     history_web_base: String,
     api_key: SecretString,
     api_secret: SecretString,
+    /// 設定ファイルで定義したマーケット(空の場合はccxtの定義を使う)
+    #[serde(default)]
+    markets: Vec<MarketConfig>,
 }
 
 #[pymethods]
@@ -39,7 +47,8 @@ impl ExchangeConfig {
             private_ws:private_ws.to_string(),
             history_web_base: history_web_base.to_string(),
             api_key: SecretString::new(&env_api_key(exchange_name, production)),
-            api_secret: SecretString::new(&env_api_secret(exchange_name, production))
+            api_secret: SecretString::new(&env_api_secret(exchange_name, production)),
+            markets: vec![],
         }
     }
 
@@ -49,6 +58,17 @@ impl ExchangeConfig {
         get_server_config(exchange_name, production)
     }
 
+    /// TOML/JSONの設定ファイルから読み込む
+    #[staticmethod]
+    pub fn load(path: &str) -> anyhow::Result<ExchangeConfig> {
+        Self::from_file(Path::new(path))
+    }
+
+    /// TOML/JSONの設定ファイルへ保存する(API KEYはマスクされる)
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        self.to_file(Path::new(path))
+    }
+
     #[staticmethod]
     pub fn open_exchange_market(exchange_name: &str, symbol: &str) -> anyhow::Result<MarketConfig> {
         get_market_config(exchange_name, symbol)
@@ -66,6 +86,10 @@ impl ExchangeConfig {
 
     #[getter]
     pub fn get_markets(&self) -> anyhow::Result<Vec<MarketConfig>> {
+        if !self.markets.is_empty() {
+            return Ok(self.markets.clone());
+        }
+
         let mut markets: Vec<MarketConfig> = vec![];
 
         let symbols = list_symbols(&self.exchange_name)?;
@@ -78,9 +102,25 @@ impl ExchangeConfig {
     }
 
     pub fn open_market(&self, symbol: &str) -> anyhow::Result<MarketConfig>{
+        if let Some(market) = self.find_market(symbol) {
+            return Ok(market);
+        }
+
         get_market_config(&self.exchange_name, symbol)
     }
 
+    pub fn open_symbol_market(&self, symbol: &Symbol) -> anyhow::Result<MarketConfig> {
+        get_market_config(&self.exchange_name, &symbol.get_unified())
+    }
+
+    pub fn to_exchange_symbol(&self, symbol: &Symbol) -> anyhow::Result<String> {
+        symbol.to_exchange_symbol(&self.exchange_name)
+    }
+
+    pub fn from_exchange_symbol(&self, trade_category: &str, trade_symbol: &str) -> anyhow::Result<Symbol> {
+        Symbol::from_exchange_symbol(&self.exchange_name, trade_category, trade_symbol)
+    }
+
     pub fn get_exchange_name(&self) -> String {
         self.exchange_name.to_string()
     }
@@ -123,6 +163,172 @@ impl ExchangeConfig {
     }
 }
 
+impl ExchangeConfig {
+    /// 拡張子(.toml/.json)で形式を判定して設定ファイルを読み込む。
+    /// api_key/api_secretの`${VAR}`は環境変数で置き換え、未設定の場合は従来通り環境変数(.env)から読み込む。
+    pub fn from_file(path: &Path) -> anyhow::Result<ExchangeConfig> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read config error {:?}", path))?;
+
+        let file: ExchangeConfigFile = match ConfigFormat::from_path(path)? {
+            ConfigFormat::Toml => toml::from_str(&text)?,
+            ConfigFormat::Json => serde_json::from_str(&text)?,
+        };
+
+        file.into_config()
+    }
+
+    pub fn to_file(&self, path: &Path) -> anyhow::Result<()> {
+        let file = ExchangeConfigFile::from_config(self);
+
+        let text = match ConfigFormat::from_path(path)? {
+            ConfigFormat::Toml => toml::to_string_pretty(&file)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(&file)?,
+        };
+
+        std::fs::write(path, text).with_context(|| format!("write config error {:?}", path))?;
+
+        Ok(())
+    }
+
+    fn find_market(&self, symbol: &str) -> Option<MarketConfig> {
+        self.markets
+            .iter()
+            .find(|m| m.trade_symbol == symbol || m.unified_symbol == symbol)
+            .cloned()
+    }
+}
+
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match ext.as_str() {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(anyhow!("unknown config format {:?} (.toml or .json)", path)),
+        }
+    }
+}
+
+/// 設定ファイルの形式。endpointを省略した場合はccxtの定義を使う。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExchangeConfigFile {
+    exchange_name: String,
+    #[serde(default = "default_production")]
+    production: bool,
+    #[serde(default)]
+    public_api: String,
+    #[serde(default)]
+    private_api: String,
+    #[serde(default)]
+    public_ws: String,
+    #[serde(default)]
+    private_ws: String,
+    #[serde(default)]
+    history_web_base: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", serialize_with = "to_mask_string")]
+    api_key: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", serialize_with = "to_mask_string")]
+    api_secret: String,
+    #[serde(default)]
+    markets: Vec<MarketConfig>,
+}
+
+fn default_production() -> bool {
+    true
+}
+
+impl ExchangeConfigFile {
+    fn from_config(config: &ExchangeConfig) -> Self {
+        Self {
+            exchange_name: config.exchange_name.clone(),
+            production: config.production,
+            public_api: config.public_api.clone(),
+            private_api: config.private_api.clone(),
+            public_ws: config.public_ws.clone(),
+            private_ws: config.private_ws.clone(),
+            history_web_base: config.history_web_base.clone(),
+            api_key: config.api_key.extract(),
+            api_secret: config.api_secret.extract(),
+            markets: config.markets.clone(),
+        }
+    }
+
+    fn into_config(self) -> anyhow::Result<ExchangeConfig> {
+        let mut config = match get_server_config(&self.exchange_name, self.production) {
+            Ok(config) => config,
+            Err(_) => ExchangeConfig::new(&self.exchange_name, self.production, "", "", "", "", ""),
+        };
+
+        set_if_not_empty(&mut config.public_api, &self.public_api)?;
+        set_if_not_empty(&mut config.private_api, &self.private_api)?;
+        set_if_not_empty(&mut config.public_ws, &self.public_ws)?;
+        set_if_not_empty(&mut config.private_ws, &self.private_ws)?;
+        set_if_not_empty(&mut config.history_web_base, &self.history_web_base)?;
+
+        if let Some(key) = secret_value(&self.api_key)? {
+            config.api_key = SecretString::new(&key);
+        }
+        if let Some(secret) = secret_value(&self.api_secret)? {
+            config.api_secret = SecretString::new(&secret);
+        }
+
+        config.markets = self.markets;
+
+        Ok(config)
+    }
+}
+
+fn set_if_not_empty(field: &mut String, value: &str) -> anyhow::Result<()> {
+    if !value.is_empty() {
+        *field = expand_env(value)?;
+    }
+
+    Ok(())
+}
+
+/// to_fileでマスクされた値は無効として扱う
+fn secret_value(value: &str) -> anyhow::Result<Option<String>> {
+    if value.is_empty() || value.contains("****") || value == "--- NO KEY ---" {
+        return Ok(None);
+    }
+
+    Ok(Some(expand_env(value)?))
+}
+
+/// `${VAR}`を環境変数の値で置き換える
+pub fn expand_env(value: &str) -> anyhow::Result<String> {
+    let mut result = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unclosed ${{ in {:?}", value))?;
+
+        let name = &rest[start + 2..start + end];
+        let var = std::env::var(name)
+            .with_context(|| format!("environment variable [{}] is not set", name))?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&var);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
 #[pyclass]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum FeeType {
@@ -131,6 +337,68 @@ pub enum FeeType {
     Both,
 }
 
+/// WSで購読するストリームの種類
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ChannelType {
+    Trade,
+    Depth,
+    BookTicker,
+    Kline,
+}
+
+/// WSの購読チャンネル。取引所ごとのトピック名への変換と検証は各取引所のクレートで行う。
+/// paramはDepthなら更新間隔("100ms")か板の深さ("200")、Klineなら足の間隔("1m")。空は取引所のデフォルト。
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Channel {
+    #[pyo3(get)]
+    pub channel_type: ChannelType,
+    #[pyo3(get)]
+    pub param: String,
+}
+
+#[pymethods]
+impl Channel {
+    #[new]
+    #[pyo3(signature = (channel_type, param=""))]
+    pub fn new(channel_type: ChannelType, param: &str) -> Self {
+        Self {
+            channel_type,
+            param: param.to_string(),
+        }
+    }
+
+    #[staticmethod]
+    pub fn trade() -> Self {
+        Self::new(ChannelType::Trade, "")
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (param=""))]
+    pub fn depth(param: &str) -> Self {
+        Self::new(ChannelType::Depth, param)
+    }
+
+    #[staticmethod]
+    pub fn book_ticker() -> Self {
+        Self::new(ChannelType::BookTicker, "")
+    }
+
+    #[staticmethod]
+    pub fn kline(interval: &str) -> Self {
+        Self::new(ChannelType::Kline, interval)
+    }
+
+    pub fn __repr__(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
 #[pyclass]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct MarketConfig {
@@ -169,6 +437,25 @@ pub struct MarketConfig {
 
     #[pyo3(set)]
     pub market_order_price_slip: Decimal,
+
+    /// 板の深さ（0は取引所のデフォルト）。WSの購読とRESTのスナップショットに使う。
+    /// BinanceはWSの購読には使わず、RESTのスナップショットとローカルの板の切り詰めだけに使う。
+    #[pyo3(set, get)]
+    #[serde(default)]
+    pub board_depth: u32,
+
+    /// 1契約あたりの数量(先物の乗数)。現物は1。
+    #[serde(default = "default_contract_size")]
+    pub contract_size: Decimal,
+
+    /// WSで購読するチャンネル。空の場合は取引所ごとのデフォルト(trade+depthなど)。
+    #[pyo3(get)]
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+}
+
+fn default_contract_size() -> Decimal {
+    dec![1.0]
 }
 
 fn round(unit: Decimal, value: Decimal) -> anyhow::Result<Decimal> {
@@ -184,12 +471,13 @@ fn round(unit: Decimal, value: Decimal) -> anyhow::Result<Decimal> {
             unit,
             v
         );
-        return Err(anyhow!(
+        return Err(MarketError::invalid_order(&format!(
             "Price or size becomes zero value= {} / unit= {} => {}",
             value,
             unit,
             v
-        ));
+        ))
+        .into());
     }
     Ok(v)
 }
@@ -212,13 +500,14 @@ impl MarketConfig {
         let size = round(self.size_unit, size)?;
 
         if self.min_size != dec![0.0] && size < self.min_size {
-            return Err(anyhow!("below min size size={}, min_size={}", size, self.min_size));
+            return Err(MarketError::invalid_order(&format!("below min size size={}, min_size={}", size, self.min_size)).into());
         }
 
         Ok(size)
     }
 
     #[new]
+    #[pyo3(signature = (unified_symbol, exchange_name, trade_category, trade_symbol, foreign_currency, home_currency, quote_currency, settle_currency, price_unit, size_unit, min_size, maker_fee, taker_fee, fee_type, order_book_depth=0))]
     pub fn new(
         unified_symbol: &str,
 
@@ -238,6 +527,7 @@ impl MarketConfig {
         maker_fee: f64,
         taker_fee: f64,
         fee_type: FeeType,
+        order_book_depth: u32,
     ) -> Self {
         let maker_fee = Decimal::from_f64(maker_fee).unwrap();
         let taker_fee = Decimal::from_f64(taker_fee).unwrap();
@@ -262,7 +552,10 @@ impl MarketConfig {
             foreign_currency:foreign_currency.to_string(),
             quote_currency:quote_currency.to_string(),
             settle_currency:settle_currency.to_string(), 
-            market_order_price_slip: price_unit * dec![2.0]
+            market_order_price_slip: price_unit * dec![2.0],
+            board_depth: order_book_depth,
+            contract_size: default_contract_size(),
+            channels: vec![],
         }
     }
 
@@ -286,6 +579,27 @@ impl MarketConfig {
         self.size_unit.clone()
     }
 
+    #[getter]
+    pub fn get_min_size(&self) -> Decimal {
+        self.min_size.clone()
+    }
+
+    #[getter]
+    pub fn get_contract_size(&self) -> Decimal {
+        self.contract_size.clone()
+    }
+
+    /// board_depthの別名(コンストラクタの引数名と同じ)
+    #[getter]
+    pub fn get_order_book_depth(&self) -> u32 {
+        self.board_depth
+    }
+
+    #[setter]
+    pub fn set_order_book_depth(&mut self, depth: u32) {
+        self.board_depth = depth;
+    }
+
     #[setter]
     pub fn set_maker_fee(&mut self, fee: f64) {
         self.maker_fee = Decimal::from_f64(fee).unwrap();
@@ -306,6 +620,32 @@ impl MarketConfig {
         self.taker_fee.clone()
     }
 
+    #[getter]
+    pub fn get_symbol(&self) -> anyhow::Result<Symbol> {
+        Symbol::from_unified(&self.unified_symbol)
+    }
+
+    /// インバース（base建て決済）の先物か
+    pub fn is_inverse(&self) -> bool {
+        self.trade_category == "inverse"
+    }
+
+    /// 数量sizeをpriceで評価した想定元本(絶対値)。
+    /// linear/spotはquote建て(size * contract_size * price)、
+    /// inverseは数量がUSD建ての契約数なのでbase建て(size * contract_size / price)。
+    pub fn notional(&self, size: Decimal, price: Decimal) -> Decimal {
+        let size = size.abs() * self.contract_size;
+
+        if self.is_inverse() {
+            if price == dec![0.0] {
+                return dec![0.0];
+            }
+            size / price
+        } else {
+            size * price
+        }
+    }
+
     pub fn key_string(&self, production: bool) -> String {
         if production {
             format!(
@@ -328,6 +668,78 @@ impl MarketConfig {
     }
 }
 
+impl MarketConfig {
+    /// board_depthを取引所がサポートする値(昇順)に合わせる。
+    /// 0はdefault、サポート外の値は一つ上の段階へ切り上げ、最大値を超える場合はエラー。
+    pub fn resolve_board_depth(&self, supported: &[u32], default: u32) -> anyhow::Result<u32> {
+        if self.board_depth == 0 {
+            return Ok(default);
+        }
+
+        match supported.iter().find(|d| self.board_depth <= **d) {
+            Some(depth) => {
+                if *depth != self.board_depth {
+                    log::warn!(
+                        "board_depth {} is not supported by {}, use {} (supported={:?})",
+                        self.board_depth,
+                        self.exchange_name,
+                        depth,
+                        supported
+                    );
+                }
+                Ok(*depth)
+            }
+            None => Err(anyhow!(
+                "board_depth {} is not supported by {} (supported={:?})",
+                self.board_depth,
+                self.exchange_name,
+                supported
+            )),
+        }
+    }
+}
+
+impl MarketConfig {
+    /// 取引所から取得したtick size, 数量単位, 最小数量, 契約乗数で上書きする
+    pub fn set_instrument(&mut self, price_unit: Decimal, size_unit: Decimal, min_size: Decimal, contract_size: Decimal) {
+        self.price_unit = price_unit.normalize();
+        self.size_unit = size_unit.normalize();
+        self.min_size = min_size.normalize();
+        self.contract_size = contract_size.normalize();
+        self.market_order_price_slip = self.price_unit * dec![2.0];
+    }
+}
+
+/// 取引所から取得した銘柄情報のキャッシュ(key_stringごと)
+static INSTRUMENT_CACHE: Lazy<RwLock<HashMap<String, MarketConfig>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub fn set_instrument_cache(production: bool, config: &MarketConfig) {
+    INSTRUMENT_CACHE
+        .write()
+        .unwrap()
+        .insert(config.key_string(production), config.clone());
+}
+
+pub fn get_instrument_cache(production: bool, config: &MarketConfig) -> Option<MarketConfig> {
+    INSTRUMENT_CACHE
+        .read()
+        .unwrap()
+        .get(&config.key_string(production))
+        .cloned()
+}
+
+/// キャッシュ済みの銘柄情報があればtick size等をそれで置き換える(手数料等はそのまま)。
+pub fn apply_instrument_cache(production: bool, config: &MarketConfig) -> MarketConfig {
+    let mut config = config.clone();
+
+    if let Some(cached) = get_instrument_cache(production, &config) {
+        config.set_instrument(cached.price_unit, cached.size_unit, cached.min_size, cached.contract_size);
+    }
+
+    config
+}
+
 impl Default for MarketConfig {
     fn default() -> Self {
         MarketConfig::new(
@@ -345,6 +757,7 @@ impl Default for MarketConfig {
             0.0,
             0.0,
             FeeType::Home,
+            0,
         )
     }
 }
@@ -355,7 +768,7 @@ mod test_market_config {
 
     use crate::common::init_debug_log;
 
-    use super::MarketConfig;
+    use super::{apply_instrument_cache, set_instrument_cache, MarketConfig};
 
     #[test]
     fn round_price() -> anyhow::Result<()> {
@@ -401,4 +814,147 @@ mod test_market_config {
         config.set_size_unit(1.23);
         assert_eq!(config.get_size_unit(), dec![1.23]);
     }
+
+    #[test]
+    fn test_resolve_board_depth() {
+        let mut config = MarketConfig::default();
+        let supported = [1, 50, 200];
+
+        assert_eq!(config.resolve_board_depth(&supported, 200).unwrap(), 200);
+
+        config.board_depth = 1;
+        assert_eq!(config.resolve_board_depth(&supported, 200).unwrap(), 1);
+
+        config.board_depth = 20;
+        assert_eq!(config.resolve_board_depth(&supported, 200).unwrap(), 50);
+
+        config.board_depth = 500;
+        assert!(config.resolve_board_depth(&supported, 200).is_err());
+    }
+
+    #[test]
+    fn test_notional() {
+        let mut config = MarketConfig::default();
+        config.trade_category = "linear".to_string();
+        config.contract_size = dec![1.0];
+        assert_eq!(config.notional(dec![-0.5], dec![60000.0]), dec![30000.0]);
+
+        config.contract_size = dec![0.01];
+        assert_eq!(config.notional(dec![2.0], dec![3000.0]), dec![60.0]);
+
+        // inverseは数量(USD)を価格で割ってbase建て
+        config.trade_category = "inverse".to_string();
+        config.contract_size = dec![1.0];
+        assert!(config.is_inverse());
+        assert_eq!(config.notional(dec![30000.0], dec![60000.0]), dec![0.5]);
+        assert_eq!(config.notional(dec![30000.0], dec![0.0]), dec![0.0]);
+    }
+
+    #[test]
+    fn test_instrument_cache() -> anyhow::Result<()> {
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "INSTRUMENT_CACHE_TEST".to_string();
+        config.maker_fee = dec![0.0002];
+
+        // キャッシュが無ければそのまま
+        assert_eq!(apply_instrument_cache(true, &config), config);
+
+        let mut fetched = config.clone();
+        fetched.set_instrument(dec![0.10], dec![0.001], dec![0.005], dec![1.0]);
+        set_instrument_cache(true, &fetched);
+
+        let applied = apply_instrument_cache(true, &config);
+        assert_eq!(applied.price_unit, dec![0.1]);
+        assert_eq!(applied.size_unit, dec![0.001]);
+        assert_eq!(applied.maker_fee, dec![0.0002]);
+        assert!(applied.round_size(dec![0.004]).is_err());
+        assert_eq!(applied.round_price(dec![100.17])?, dec![100.1]);
+
+        // testnetのキャッシュは別
+        assert_eq!(apply_instrument_cache(false, &config), config);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_exchange_config {
+    use std::io::Write as _;
+
+    use rust_decimal_macros::dec;
+
+    use super::{expand_env, ExchangeConfig};
+
+    #[test]
+    fn test_expand_env() -> anyhow::Result<()> {
+        std::env::set_var("RBOT_EXPAND_TEST", "abc");
+
+        assert_eq!(expand_env("${RBOT_EXPAND_TEST}")?, "abc");
+        assert_eq!(expand_env("x-${RBOT_EXPAND_TEST}-y")?, "x-abc-y");
+        assert_eq!(expand_env("plain")?, "plain");
+        assert!(expand_env("${RBOT_EXPAND_NOT_DEFINED}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_toml() -> anyhow::Result<()> {
+        std::env::set_var("RBOT_CONFIG_TEST_KEY", "test-api-key");
+        std::env::set_var("RBOT_CONFIG_TEST_SECRET", "test-api-secret");
+
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile()?;
+        write!(
+            file,
+            r#"
+exchange_name = "MYEXCHANGE"
+production = false
+public_api = "https://api.example.com"
+public_ws = "wss://ws.example.com"
+api_key = "${{RBOT_CONFIG_TEST_KEY}}"
+api_secret = "${{RBOT_CONFIG_TEST_SECRET}}"
+
+[[markets]]
+unified_symbol = "BTC/USDT:USDT"
+exchange_name = "MYEXCHANGE"
+trade_category = "linear"
+trade_symbol = "BTCUSDT"
+fee_type = "Home"
+home_currency = "USDT"
+foreign_currency = "BTC"
+quote_currency = "USDT"
+settle_currency = "USDT"
+price_unit = "0.1"
+size_unit = "0.001"
+min_size = "0.001"
+maker_fee = "0.0001"
+taker_fee = "0.0006"
+market_order_price_slip = "0.2"
+"#
+        )?;
+
+        let config = ExchangeConfig::from_file(file.path())?;
+        assert_eq!(config.get_exchange_name(), "MYEXCHANGE");
+        assert!(!config.is_production());
+        assert_eq!(config.get_public_api(), "https://api.example.com");
+        assert_eq!(config.get_api_key().extract(), "test-api-key");
+        assert_eq!(config.get_api_secret().extract(), "test-api-secret");
+
+        let market = config.open_market("BTCUSDT")?;
+        assert_eq!(market.price_unit, dec![0.1]);
+        assert_eq!(market.size_unit, dec![0.001]);
+
+        // 保存したファイルにはAPI KEYが残らない
+        let json = tempfile::Builder::new().suffix(".json").tempfile()?;
+        config.to_file(json.path())?;
+
+        let text = std::fs::read_to_string(json.path())?;
+        assert!(!text.contains("test-api-key"));
+        assert!(!text.contains("test-api-secret"));
+
+        let reload = ExchangeConfig::from_file(json.path())?;
+        assert_eq!(reload.get_public_api(), "https://api.example.com");
+        assert_eq!(reload.open_market("BTCUSDT")?, market);
+
+        Ok(())
+    }
 }
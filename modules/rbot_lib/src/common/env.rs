@@ -21,6 +21,11 @@ pub fn env_rbot_db_root() -> Result<String, VarError> {
     std::env::var("RBOT_DB_ROOT")
 }
 
+/// Get the progress output mode(terminal/quiet/json).
+pub fn env_rbot_progress() -> Result<String, VarError> {
+    std::env::var("RBOT_PROGRESS")
+}
+
 const RBOT_ENV_DIR: &str = ".rusty-bot";
 const API_KEY: &str = "API_KEY";
 const API_SECRET: &str = "API_SECRET";
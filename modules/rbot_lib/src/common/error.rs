@@ -0,0 +1,152 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::PyErr;
+
+/// 取引所APIのエラーの分類。anyhow::Errorに包んで返し、Pythonへ渡す時に例外クラスへ変換する。
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum MarketError {
+    #[error("network error: {0}")]
+    Network(String),
+    #[error("auth error: {0}")]
+    Auth(String),
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+    #[error("invalid order: {reason}")]
+    InvalidOrder { reason: String },
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("exchange error: code={code}, msg={msg}")]
+    Exchange { code: i64, msg: String },
+}
+
+impl MarketError {
+    pub fn invalid_order(reason: &str) -> Self {
+        MarketError::InvalidOrder {
+            reason: reason.to_string(),
+        }
+    }
+
+    /// HTTPステータスコードから分類する(200以外)。
+    /// 403は取引所によって意味が違う(認証エラー、IPのレート制限、WAFの制限など)ので
+    /// ここでは分類せずExchange{code: 403}とし、取引所ごとに付け替える。
+    pub fn from_http_status(status: u16, body: &str) -> Self {
+        match status {
+            401 => MarketError::Auth(body.to_string()),
+            404 => MarketError::NotFound(body.to_string()),
+            418 | 429 => MarketError::RateLimited(body.to_string()),
+            _ => MarketError::Exchange {
+                code: status as i64,
+                msg: body.to_string(),
+            },
+        }
+    }
+
+    /// 403をレート制限として返す取引所(BybitはIPのレート制限、BinanceはWAFの制限)向け。
+    /// RESTの403エラーをRateLimitedに付け替える。それ以外のエラーはそのまま返す。
+    pub fn forbidden_as_rate_limited(error: anyhow::Error) -> anyhow::Error {
+        match MarketError::find(&error) {
+            Some(MarketError::Exchange { code: 403, msg }) => {
+                let rate_limited = MarketError::RateLimited(msg.clone());
+                anyhow::Error::new(rate_limited).context(error.to_string())
+            }
+            _ => error,
+        }
+    }
+
+    /// errorのchainからMarketErrorを探す
+    pub fn find(error: &anyhow::Error) -> Option<&MarketError> {
+        error.chain().find_map(|e| e.downcast_ref::<MarketError>())
+    }
+}
+
+/// Pythonの例外クラス。`except rbot.RateLimited:`のように使い、全てMarketErrorを継承する。
+pub mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyException;
+
+    create_exception!(rbot, MarketError, PyException);
+    create_exception!(rbot, NetworkError, MarketError);
+    create_exception!(rbot, AuthError, MarketError);
+    create_exception!(rbot, RateLimited, MarketError);
+    create_exception!(rbot, InvalidOrder, MarketError);
+    create_exception!(rbot, NotFound, MarketError);
+    create_exception!(rbot, ExchangeError, MarketError);
+}
+
+impl From<MarketError> for PyErr {
+    fn from(error: MarketError) -> Self {
+        let message = error.to_string();
+
+        match error {
+            MarketError::Network(_) => exceptions::NetworkError::new_err(message),
+            MarketError::Auth(_) => exceptions::AuthError::new_err(message),
+            MarketError::RateLimited(_) => exceptions::RateLimited::new_err(message),
+            MarketError::InvalidOrder { .. } => exceptions::InvalidOrder::new_err(message),
+            MarketError::NotFound(_) => exceptions::NotFound::new_err(message),
+            MarketError::Exchange { code, msg } => exceptions::ExchangeError::new_err((message, code, msg)),
+        }
+    }
+}
+
+/// anyhow::ErrorをPythonの例外へ変換する。MarketErrorを含まない場合はRuntimeError。
+pub fn to_py_err(error: anyhow::Error) -> PyErr {
+    match MarketError::find(&error) {
+        Some(market_error) => {
+            let py_err: PyErr = market_error.clone().into();
+            log::debug!("{:?}", error);
+            py_err
+        }
+        None => PyRuntimeError::new_err(format!("{:?}", error)),
+    }
+}
+
+#[cfg(test)]
+mod error_test {
+    use super::*;
+    use anyhow::Context as _;
+
+    #[test]
+    fn test_find_market_error() {
+        let error = anyhow::Error::new(MarketError::RateLimited("too many visits".to_string()))
+            .context("get_sign error");
+
+        assert_eq!(
+            MarketError::find(&error),
+            Some(&MarketError::RateLimited("too many visits".to_string()))
+        );
+
+        let result: anyhow::Result<()> = Err(anyhow::anyhow!("other error"));
+        let error = result.context("context").unwrap_err();
+        assert_eq!(MarketError::find(&error), None);
+    }
+
+    #[test]
+    fn test_from_http_status() {
+        assert!(matches!(MarketError::from_http_status(401, ""), MarketError::Auth(_)));
+        assert!(matches!(MarketError::from_http_status(403, ""), MarketError::Exchange { code: 403, .. }));
+        assert!(matches!(MarketError::from_http_status(429, ""), MarketError::RateLimited(_)));
+        assert!(matches!(MarketError::from_http_status(404, ""), MarketError::NotFound(_)));
+        assert_eq!(
+            MarketError::from_http_status(500, "error"),
+            MarketError::Exchange { code: 500, msg: "error".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_forbidden_as_rate_limited() {
+        let error = anyhow::Error::new(MarketError::from_http_status(403, "access denied")).context("rest_get error");
+        let error = MarketError::forbidden_as_rate_limited(error);
+        assert_eq!(
+            MarketError::find(&error),
+            Some(&MarketError::RateLimited("access denied".to_string()))
+        );
+        assert!(format!("{:?}", error).contains("rest_get error"));
+
+        // 403以外はそのまま
+        let error = anyhow::Error::new(MarketError::from_http_status(401, "invalid key"));
+        let error = MarketError::forbidden_as_rate_limited(error);
+        assert_eq!(MarketError::find(&error), Some(&MarketError::Auth("invalid key".to_string())));
+    }
+}
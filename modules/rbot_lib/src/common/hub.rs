@@ -87,7 +87,7 @@ impl MarketHub {
                         let market_message = msg.msg.clone();
     
                         match market_message {
-                            MarketMessage::Order(ref order) => {
+                            MarketMessage::Order(ref order) | MarketMessage::ReconciledOrder(ref order) => {
                                 if order.is_my_order(&agent_id) {
                                     let r = tx.send(market_message.clone());
                                     if r.is_err() {
@@ -157,7 +157,7 @@ impl MarketHub {
 
                 if msg.filter(exchange, category, symbol) {
                     match msg.msg {
-                        MarketMessage::Order(ref order) => {
+                        MarketMessage::Order(ref order) | MarketMessage::ReconciledOrder(ref order) => {
                             if order.is_my_order(agent_id) {
                                 yield Ok(msg.msg);
                             }
@@ -1,13 +1,21 @@
 // Copyright(c) 2022-4. yasstake. All rights reserved.
 // ABSOLUTELY NO WARRANTY.
 
+use std::fs::{File, OpenOptions};
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Once;
+use anyhow::anyhow;
 use pyo3::{pyfunction, PyErr};
-use env_logger::Env; 
+use env_logger::{Env, Target};
 
 static INIT: Once = Once::new();
 
+/// init_file_logのデフォルトのファイルサイズ上限(10MB)
+pub const LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// init_file_logのデフォルトの世代数
+pub const LOG_FILE_MAX_FILES: usize = 5;
+
 #[pyfunction]
 /// Initializes the logger with a warning level filter.
 pub fn init_log() {
@@ -24,6 +32,105 @@ pub fn init_debug_log() {
     });
 }
 
+#[pyfunction]
+#[pyo3(signature = (path, level="info", max_size=LOG_FILE_MAX_SIZE, max_files=LOG_FILE_MAX_FILES))]
+/// stderrに加えてpathへログを書き出す。max_sizeを超えるとpath.1, path.2...へ順に退避し、
+/// max_files世代より古いものは削除する。RUST_LOGが設定されていればlevelより優先する。
+pub fn init_file_log(path: PathBuf, level: &str, max_size: u64, max_files: usize) -> anyhow::Result<()> {
+    if INIT.is_completed() {
+        return Err(anyhow!("logger is already initialized"));
+    }
+
+    let writer = RotatingFileWriter::open(&path, max_size, max_files)?;
+
+    INIT.call_once(|| {
+        env_logger::Builder::from_env(Env::default().default_filter_or(level))
+            .target(Target::Pipe(Box::new(writer)))
+            .init();
+    });
+
+    Ok(())
+}
+
+/// stderrとファイルの両方に書き、ファイルがmax_sizeを超えたらローテーションする
+pub struct RotatingFileWriter {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFileWriter {
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated_path(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _ = std::io::stderr().write_all(buf);
+
+        if 0 < self.size && self.max_size < self.size + buf.len() as u64 {
+            self.rotate()?;
+        }
+
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let _ = std::io::stderr().flush();
+        self.file.flush()
+    }
+}
+
 pub fn flush_log() {
     let _ = std::io::stdout().flush();
     let _ = std::io::stderr().flush();
@@ -92,4 +199,30 @@ mod test_common_mod {
         init_log();
         flush_log();
     }
+
+    #[test]
+    fn test_rotating_file_writer() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("log/rbot.log");
+
+        let mut writer = RotatingFileWriter::open(&path, 10, 2)?;
+        for line in ["line-1\n", "line-2\n", "line-3\n", "line-4\n"] {
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.flush()?;
+
+        // 1行ごとに上限を超えるので、最新+2世代だけ残る
+        assert_eq!(std::fs::read_to_string(&path)?, "line-4\n");
+        assert_eq!(std::fs::read_to_string(writer.rotated_path(1))?, "line-3\n");
+        assert_eq!(std::fs::read_to_string(writer.rotated_path(2))?, "line-2\n");
+        assert!(!writer.rotated_path(3).exists());
+
+        // 既存のファイルに追記し、サイズを引き継ぐ
+        let mut writer = RotatingFileWriter::open(&path, 100, 2)?;
+        writer.write_all(b"line-5\n")?;
+        writer.flush()?;
+        assert_eq!(std::fs::read_to_string(&path)?, "line-4\nline-5\n");
+
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -16,6 +16,9 @@ mod bar;
 mod calc_class;
 mod text_message;
 mod ccxt_config;
+mod symbol;
+mod error;
+pub mod patterns;
 
 pub use time::*;
 pub use order::*;
@@ -31,5 +34,8 @@ pub use bar::*;
 pub use calc_class::*;
 pub use text_message::*;
 pub use ccxt_config::*;
+pub use symbol::*;
+pub use error::*;
+pub use patterns::*;
 
 
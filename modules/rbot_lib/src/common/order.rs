@@ -54,10 +54,14 @@ pub enum OrderStatus {
     #[strum(ascii_case_insensitive)]
     Canceled, // ユーザによるキャンセル
     #[strum(ascii_case_insensitive)]
+    Amended, // 価格・数量の変更（注文は有効なまま）
+    #[strum(ascii_case_insensitive)]
     Rejected, // システムからの拒否（指値範囲外、数量不足など）
     #[strum(ascii_case_insensitive)]
     Error, // エラー
     #[strum(ascii_case_insensitive)]
+    ServerWait, // 未送信（dry_runで検証のみ行った注文）
+    #[strum(ascii_case_insensitive)]
     Unknown, // その他未定義状態
 }
 
@@ -138,6 +142,12 @@ where
     Ok(string_to_side(&s))
 }
 
+/// client_order_idがagent_idの発行したものか。
+/// Session.set_order_id_prefixを使うとidは`{prefix}-{agent_id}...`になるので、`-`の後ろも見る。
+pub fn is_agent_order_id(client_order_id: &str, agent_id: &str) -> bool {
+    client_order_id.starts_with(agent_id) || client_order_id.contains(&format!("-{}", agent_id))
+}
+
 pub fn string_to_side(side: &str) -> OrderSide {
     match side.to_uppercase().as_str() {
         "BUY" | "B" => OrderSide::Buy,
@@ -252,6 +262,63 @@ impl From<&String> for OrderType {
     }
 }
 
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Display, Serialize, Deserialize)]
+/// 指値注文の有効期限(time in force)
+pub enum TimeInForce {
+    GTC,      // Good Till Cancel
+    IOC,      // Immediate Or Cancel（約定しなかった残りはキャンセル）
+    FOK,      // Fill Or Kill（全量約定できなければキャンセル）
+    PostOnly, // Maker注文のみ（Takerになる場合はキャンセル）
+}
+
+impl Default for TimeInForce {
+    fn default() -> Self {
+        TimeInForce::GTC
+    }
+}
+
+#[pymethods]
+impl TimeInForce {
+    pub fn to_string(&self) -> String {
+        match self {
+            TimeInForce::GTC => "GTC".to_string(),
+            TimeInForce::IOC => "IOC".to_string(),
+            TimeInForce::FOK => "FOK".to_string(),
+            TimeInForce::PostOnly => "PostOnly".to_string(),
+        }
+    }
+
+    pub fn __str__(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn __repr__(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn __eq__(&self, other: &str) -> bool {
+        let other = TimeInForce::from(other);
+
+        *self == other
+    }
+}
+
+impl From<&str> for TimeInForce {
+    fn from(tif: &str) -> Self {
+        match tif.to_uppercase().as_str() {
+            "GTC" => TimeInForce::GTC,
+            "IOC" => TimeInForce::IOC,
+            "FOK" => TimeInForce::FOK,
+            "POSTONLY" | "PO" | "GTX" | "LIMIT_MAKER" => TimeInForce::PostOnly,
+            _ => {
+                log::error!("Unknown time in force: {:?}, use GTC", tif);
+                TimeInForce::GTC
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Display, Serialize, Deserialize)]
 #[pyclass]
 pub enum LogStatus {
@@ -261,6 +328,8 @@ pub enum LogStatus {
     FixArchiveBlock,   // アーカイブから取得されたデータ
     ExpireControlForce, // 削除指示（アーカイブ意外は強制削除）
     ExpireControl,      // 削除指示(通常：WSデータのみ削除)
+    Aggregate,          // 同一価格・同一方向の連続した約定をまとめたデータ
+    KlineFill,          // アーカイブより古い期間を4本足で埋めたデータ(手数料は計算しない)
     Unknown,            // 未知のステータス / 未確定のステータス
 }
 
@@ -279,6 +348,8 @@ impl From<&str> for LogStatus {
             "A" => LogStatus::FixArchiveBlock,
             "XX" => LogStatus::ExpireControlForce,
             "X" => LogStatus::ExpireControl,
+            "AG" => LogStatus::Aggregate,
+            "K" => LogStatus::KlineFill,
             _ => {
                 log::error!("Unknown log status: {:?}", status);
                 LogStatus::Unknown
@@ -296,6 +367,8 @@ impl LogStatus {
             LogStatus::FixArchiveBlock => "A".to_string(),
             LogStatus::ExpireControlForce => "XX".to_string(),
             LogStatus::ExpireControl => "X".to_string(),
+            LogStatus::Aggregate => "AG".to_string(),
+            LogStatus::KlineFill => "K".to_string(),
             LogStatus::Unknown => "???".to_string(),
         }
     }
@@ -427,6 +500,15 @@ impl Trade {
     }
 
 
+    /// 時刻・価格・サイズが0以下の約定(壊れた行を既定値で埋めたもの)をエラーにする
+    pub fn validate(self) -> anyhow::Result<Trade> {
+        if self.time <= 0 || self.price <= Decimal::ZERO || self.size <= Decimal::ZERO {
+            return Err(anyhow::anyhow!("invalid trade {:?}", self));
+        }
+
+        Ok(self)
+    }
+
     pub fn csv_header() -> String {
         format!(
             "{},{},{},{},{}\n",
@@ -553,6 +635,18 @@ impl Coin {
         return self.locked.to_f64().unwrap();
     }
 
+    /// 総額(volumeと同じ)
+    #[getter]
+    pub fn get_equity(&self) -> f64 {
+        return self.get_volume();
+    }
+
+    /// 利用可能額(freeと同じ)
+    #[getter]
+    pub fn get_available(&self) -> f64 {
+        return self.get_free();
+    }
+
     pub fn __repr__(&self) -> String {
         serde_json::to_string(&self).unwrap()
     }
@@ -745,6 +839,305 @@ impl AccountPair {
     }
 }
 
+/// position status reported by exchange(user stream).
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PositionInfo {
+    #[pyo3(get)]
+    pub category: String,
+    #[pyo3(get)]
+    pub symbol: String,
+    #[pyo3(get)]
+    pub side: OrderSide,
+    #[pyo3(get)]
+    pub size: Decimal,
+    #[pyo3(get)]
+    pub avg_price: Decimal,
+    #[pyo3(get)]
+    pub position_value: Decimal,
+    #[pyo3(get)]
+    pub leverage: Decimal,
+    /// ヘッジモードのポジション(0: one-way, 1: 買い, 2: 売り)
+    #[pyo3(get)]
+    #[serde(default)]
+    pub position_idx: i64,
+    #[pyo3(get)]
+    pub update_time: MicroSec,
+}
+
+/// mark price / index price / funding rate reported by exchange(public stream).
+#[pyclass]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TickerInfo {
+    #[pyo3(get)]
+    pub symbol: String,
+    #[pyo3(get)]
+    pub time: MicroSec,
+    #[pyo3(get)]
+    pub mark_price: Decimal,
+    #[pyo3(get)]
+    pub index_price: Decimal,
+    #[pyo3(get)]
+    pub predicted_funding_rate: Decimal,
+    #[pyo3(get)]
+    pub next_funding_time: MicroSec,
+}
+
+/// TickerInfoの差分(snapshot/delta)。メッセージに含まれない項目はNone。
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TickerUpdate {
+    pub symbol: String,
+    pub time: MicroSec,
+    pub mark_price: Option<Decimal>,
+    pub index_price: Option<Decimal>,
+    pub predicted_funding_rate: Option<Decimal>,
+    pub next_funding_time: Option<MicroSec>,
+}
+
+impl TickerInfo {
+    /// 差分を反映する。Noneの項目は変更しない(funding rateの0は値として反映する)。
+    pub fn merge(&mut self, update: &TickerUpdate) {
+        if update.symbol != "" {
+            self.symbol = update.symbol.clone();
+        }
+        if update.time != 0 {
+            self.time = update.time;
+        }
+        if let Some(mark_price) = update.mark_price {
+            self.mark_price = mark_price;
+        }
+        if let Some(index_price) = update.index_price {
+            self.index_price = index_price;
+        }
+        if let Some(rate) = update.predicted_funding_rate {
+            self.predicted_funding_rate = rate;
+        }
+        if let Some(next_funding_time) = update.next_funding_time {
+            self.next_funding_time = next_funding_time;
+        }
+    }
+}
+
+#[pymethods]
+impl TickerInfo {
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+
+    pub fn __repr__(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+impl Default for PositionInfo {
+    fn default() -> Self {
+        PositionInfo {
+            category: "".to_string(),
+            symbol: "".to_string(),
+            side: OrderSide::Unknown,
+            size: dec![0.0],
+            avg_price: dec![0.0],
+            position_value: dec![0.0],
+            leverage: dec![0.0],
+            position_idx: 0,
+            update_time: 0,
+        }
+    }
+}
+
+#[pymethods]
+impl PositionInfo {
+    /// signed position size(Sell is minus)
+    #[getter]
+    pub fn get_position(&self) -> Decimal {
+        if self.side == OrderSide::Sell {
+            -self.size
+        } else {
+            self.size
+        }
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+
+    pub fn __repr__(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+/// 約定1件分の明細(手数料の内訳つき)
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Fill {
+    #[pyo3(get)]
+    pub time: MicroSec,
+    #[pyo3(get)]
+    pub symbol: String,
+    #[pyo3(get)]
+    pub order_id: String,
+    #[pyo3(get)]
+    pub client_order_id: String,
+    #[pyo3(get)]
+    pub exec_id: String,
+    #[pyo3(get)]
+    pub side: OrderSide,
+    pub price: Decimal,
+    pub qty: Decimal,
+    pub fee: Decimal,
+    pub fee_rate: Decimal,
+    #[pyo3(get)]
+    pub fee_asset: String,
+    #[pyo3(get)]
+    pub is_maker: bool,
+}
+
+impl Default for Fill {
+    fn default() -> Self {
+        Fill {
+            time: 0,
+            symbol: "".to_string(),
+            order_id: "".to_string(),
+            client_order_id: "".to_string(),
+            exec_id: "".to_string(),
+            side: OrderSide::Unknown,
+            price: dec![0.0],
+            qty: dec![0.0],
+            fee: dec![0.0],
+            fee_rate: dec![0.0],
+            fee_asset: "".to_string(),
+            is_maker: false,
+        }
+    }
+}
+
+impl Fill {
+    /// BackTest/Dry用。約定したダミーオーダーからMarketConfigの手数料で約定明細を作る。
+    pub fn from_dummy_order(order: &Order, config: &MarketConfig) -> Self {
+        let fee_rate = if order.is_maker {
+            config.maker_fee
+        } else {
+            config.taker_fee
+        };
+
+        let quote_vol = order.execute_price * order.execute_size;
+
+        let fee_in_foreign = match config.fee_type {
+            FeeType::Home => false,
+            FeeType::Foreign => true,
+            FeeType::Both => order.order_side == OrderSide::Sell,
+        };
+
+        let (fee, fee_asset) = if fee_in_foreign {
+            (order.execute_size * fee_rate, config.foreign_currency.clone())
+        } else {
+            (quote_vol * fee_rate, config.home_currency.clone())
+        };
+
+        Fill {
+            time: order.update_time,
+            symbol: order.symbol.clone(),
+            order_id: order.order_id.clone(),
+            client_order_id: order.client_order_id.clone(),
+            exec_id: order.transaction_id.clone(),
+            side: order.order_side,
+            price: order.execute_price,
+            qty: order.execute_size,
+            fee: fee,
+            fee_rate: fee_rate,
+            fee_asset: fee_asset,
+            is_maker: order.is_maker,
+        }
+    }
+}
+
+#[pymethods]
+impl Fill {
+    #[getter]
+    pub fn get_price(&self) -> f64 {
+        self.price.to_f64().unwrap()
+    }
+
+    #[getter]
+    pub fn get_qty(&self) -> f64 {
+        self.qty.to_f64().unwrap()
+    }
+
+    #[getter]
+    pub fn get_fee(&self) -> f64 {
+        self.fee.to_f64().unwrap()
+    }
+
+    #[getter]
+    pub fn get_fee_rate(&self) -> f64 {
+        self.fee_rate.to_f64().unwrap()
+    }
+
+    pub fn is_my_fill(&self, agent_id: &str) -> bool {
+        is_agent_order_id(&self.client_order_id, agent_id)
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+
+    pub fn __repr__(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+pub fn fillvec_to_dataframe(fills: &Vec<Fill>) -> DataFrame {
+    let mut time = Vec::<MicroSec>::new();
+    let mut symbol = Vec::<String>::new();
+    let mut order_id = Vec::<String>::new();
+    let mut client_order_id = Vec::<String>::new();
+    let mut exec_id = Vec::<String>::new();
+    let mut side = Vec::<String>::new();
+    let mut price = Vec::<f64>::new();
+    let mut qty = Vec::<f64>::new();
+    let mut fee = Vec::<f64>::new();
+    let mut fee_rate = Vec::<f64>::new();
+    let mut fee_asset = Vec::<String>::new();
+    let mut is_maker = Vec::<bool>::new();
+
+    for fill in fills {
+        time.push(fill.time);
+        symbol.push(fill.symbol.clone());
+        order_id.push(fill.order_id.clone());
+        client_order_id.push(fill.client_order_id.clone());
+        exec_id.push(fill.exec_id.clone());
+        side.push(fill.side.to_string());
+        price.push(fill.price.to_f64().unwrap());
+        qty.push(fill.qty.to_f64().unwrap());
+        fee.push(fill.fee.to_f64().unwrap());
+        fee_rate.push(fill.fee_rate.to_f64().unwrap());
+        fee_asset.push(fill.fee_asset.clone());
+        is_maker.push(fill.is_maker);
+    }
+
+    let mut df = DataFrame::new(vec![
+        Series::new("time", time),
+        Series::new("symbol", symbol),
+        Series::new("order_id", order_id),
+        Series::new("client_order_id", client_order_id),
+        Series::new("exec_id", exec_id),
+        Series::new("side", side),
+        Series::new("price", price),
+        Series::new("qty", qty),
+        Series::new("fee", fee),
+        Series::new("fee_rate", fee_rate),
+        Series::new("fee_asset", fee_asset),
+        Series::new("is_maker", is_maker),
+    ])
+    .unwrap();
+
+    let t = df.column("time").unwrap().i64().unwrap().clone();
+    let date_time = t.into_datetime(TimeUnit::Microseconds, None);
+    let df = df.with_column(date_time).unwrap();
+
+    return df.clone();
+}
+
 #[pyclass]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Order {
@@ -805,6 +1198,12 @@ pub struct Order {
     pub profit: Decimal,
     pub fee: Decimal,
     pub total_profit: Decimal,
+    #[pyo3(get)]
+    #[serde(default)]
+    pub position_idx: i64, // Bybit: 0=one-way, 1=hedge buy side, 2=hedge sell side
+    #[pyo3(get)]
+    #[serde(default)]
+    pub reduce_only: bool, // ポジションを減らす方向にのみ約定する
 
     pub log_id: i64,
 }
@@ -860,6 +1259,8 @@ impl Order {
             profit: dec![0.0],
             fee: dec![0.0],
             total_profit: dec![0.0],
+            position_idx: 0,
+            reduce_only: false,
         }
     }
 
@@ -872,7 +1273,7 @@ impl Order {
     }
 
     pub fn is_my_order(&self, agent_id: &str) -> bool {
-        return self.client_order_id.starts_with(agent_id);
+        is_agent_order_id(&self.client_order_id, agent_id)
     }
 
     pub fn update(&mut self, order: &Order) {
@@ -1141,7 +1542,7 @@ pub fn ordervec_to_dataframe(orders: Vec<Order>) -> DataFrame {
 impl Order {
     pub fn update_balance(&mut self, config: &MarketConfig) {
         match self.status {
-            OrderStatus::New => {
+            OrderStatus::New | OrderStatus::Amended => {
                 self.update_balance_new(config);
             }
             OrderStatus::PartiallyFilled | OrderStatus::Filled => {
@@ -1215,9 +1616,35 @@ impl Order {
         } else if commission_asset == config.home_currency {
             self.commission_home = commission;
             self.commission_foreign = dec![0.0];
-        } else {
+        } else if commission_asset == config.foreign_currency {
             self.commission_home = dec![0.0];
             self.commission_foreign = commission;
+        } else {
+            // BNBなど第三の通貨で払った手数料はhome/foreignの残高に影響しない
+            self.commission_home = dec![0.0];
+            self.commission_foreign = dec![0.0];
+        }
+    }
+
+    /// home/foreign以外の通貨で手数料を払ったか
+    pub fn is_third_asset_commission(&self, config: &MarketConfig) -> bool {
+        self.commission_asset != ""
+            && self.commission_asset != config.home_currency
+            && self.commission_asset != config.foreign_currency
+    }
+
+    /// 手数料をquote(home)通貨に換算する。第三の通貨の場合はasset_price(quote建ての価格)が必要。
+    pub fn commission_in_quote(
+        &self,
+        config: &MarketConfig,
+        asset_price: Option<Decimal>,
+    ) -> Option<Decimal> {
+        if self.commission_asset == "" || self.commission_asset == config.home_currency {
+            Some(self.commission)
+        } else if self.commission_asset == config.foreign_currency {
+            Some(self.commission * self.execute_price)
+        } else {
+            asset_price.map(|price| self.commission * price)
         }
     }
 
@@ -1364,6 +1791,8 @@ impl Default for Order {
             profit: dec![0.0],
             fee: dec![0.0],
             total_profit: dec![0.0],
+            position_idx: 0,
+            reduce_only: false,
         }
     }
 }
@@ -1399,6 +1828,11 @@ impl Kline {
 
     /// OHLCをTradeに4分割する。
     pub fn extract_to_trades(&self, window_sec: i64) -> Vec<Trade> {
+        self.extract_to_trades_with_status(window_sec, LogStatus::Virtual)
+    }
+
+    /// open, high, low, closeの順に4件の約定へ展開する
+    pub fn extract_to_trades_with_status(&self, window_sec: i64, status: LogStatus) -> Vec<Trade> {
         let mut trades = Vec::new();
 
         let vol = self.volume / Decimal::from(4);
@@ -1411,7 +1845,7 @@ impl Kline {
             OrderSide::Buy,
             self.open,
             vol,
-            LogStatus::Virtual,
+            status,
             &format!("KLINE{}-{}", self.timestamp, 0),
         );
         trades.push(t);
@@ -1422,7 +1856,7 @@ impl Kline {
             OrderSide::Buy,
             self.high,
             vol,
-            LogStatus::Virtual,
+            status,
             &format!("KLINE{}-{}", self.timestamp, 1),
         );
         trades.push(t);
@@ -1432,7 +1866,7 @@ impl Kline {
             OrderSide::Sell,
             self.low,
             vol,
-            LogStatus::Virtual,
+            status,
             &format!("KLINE{}-{}", self.timestamp, 2),
         );
         trades.push(t);
@@ -1442,7 +1876,7 @@ impl Kline {
             OrderSide::Sell,
             self.close,
             remain_vol,
-            LogStatus::Virtual,
+            status,
             &format!("KLINE{}-{}", self.timestamp, 3),
         );
         trades.push(t);
@@ -1452,6 +1886,71 @@ impl Kline {
 }
 
 
+/// 4本足の幅
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KlineInterval {
+    Min1,
+    Min3,
+    Min5,
+    Min15,
+    Min30,
+    Hour1,
+    Hour2,
+    Hour4,
+    Hour6,
+    Hour12,
+    Day1,
+}
+
+impl KlineInterval {
+    pub fn sec(&self) -> i64 {
+        match self {
+            KlineInterval::Min1 => 60,
+            KlineInterval::Min3 => 3 * 60,
+            KlineInterval::Min5 => 5 * 60,
+            KlineInterval::Min15 => 15 * 60,
+            KlineInterval::Min30 => 30 * 60,
+            KlineInterval::Hour1 => 60 * 60,
+            KlineInterval::Hour2 => 2 * 60 * 60,
+            KlineInterval::Hour4 => 4 * 60 * 60,
+            KlineInterval::Hour6 => 6 * 60 * 60,
+            KlineInterval::Hour12 => 12 * 60 * 60,
+            KlineInterval::Day1 => 24 * 60 * 60,
+        }
+    }
+}
+
+/// 変換に失敗したレコードのうちログへ出す件数
+const INVALID_TRADE_LOG_LIMIT: usize = 5;
+
+/// recordsをfでTradeに変換する。変換できなかったレコードは捨て、その件数を返す(最初の数件はログに出す)。
+pub fn collect_trades<T, F>(records: impl IntoIterator<Item = T>, f: F) -> (Vec<Trade>, usize)
+where
+    F: Fn(&T) -> anyhow::Result<Trade>,
+{
+    let mut trades = vec![];
+    let mut skipped = 0;
+
+    for rec in records {
+        match f(&rec) {
+            Ok(trade) => trades.push(trade),
+            Err(e) => {
+                if skipped < INVALID_TRADE_LOG_LIMIT {
+                    log::warn!("skip invalid trade record: {:?}", e);
+                }
+                skipped += 1;
+            }
+        }
+    }
+
+    if 0 < skipped {
+        log::warn!("{} invalid trade records are skipped", skipped);
+    }
+
+    (trades, skipped)
+}
+
 pub fn convert_klines_to_trades(klines: Vec<Kline>, window_sec: i64) -> Vec<Trade> {
     let mut trades = Vec::new();
     for kline in klines {
@@ -1476,6 +1975,42 @@ mod order_tests {
         config
     }
 
+    #[test]
+    fn test_is_agent_order_id() {
+        assert!(is_agent_order_id("session-abc0001", "session"));
+        assert!(is_agent_order_id("mm-01-session-abc0001", "session"));
+        assert!(!is_agent_order_id("other-abc0001", "session"));
+    }
+
+    #[test]
+    fn test_collect_trades_skip_invalid() {
+        let records = vec![
+            StringRecord::from(vec!["1668816000029000", "Buy", "16681.46", "0.00298", "1"]),
+            StringRecord::from(vec!["1668816000053000", "Buy", "16681.43"]), // 途中で切れた行
+            StringRecord::from(vec!["1668816000075000", "Sell", "0", "0.00299", "3"]),
+            StringRecord::from(vec!["1668816000080000", "Sell", "16681.00", "0.00299", "4"]),
+        ];
+
+        let (trades, skipped) = collect_trades(records, |rec| {
+            let field = |i: usize| rec.get(i).ok_or_else(|| anyhow::anyhow!("column {} not found", i));
+
+            Trade::new(
+                field(0)?.parse()?,
+                OrderSide::from(field(1)?),
+                Decimal::from_str(field(2)?)?,
+                Decimal::from_str(field(3)?)?,
+                LogStatus::FixArchiveBlock,
+                field(4)?,
+            )
+            .validate()
+        });
+
+        assert_eq!(skipped, 2);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].id, "1");
+        assert_eq!(trades[1].id, "4");
+    }
+
     fn create_order() -> Order {
         let mut order = Order::new(
             "",
@@ -1494,6 +2029,89 @@ mod order_tests {
     }
 
 
+    #[test]
+    fn test_kline_fill_trades() {
+        let kline = Kline::new(SEC(60), dec![100.0], dec![110.0], dec![90.0], dec![105.0], dec![2.0]);
+        let trades = kline.extract_to_trades_with_status(KlineInterval::Min1.sec(), LogStatus::KlineFill);
+
+        assert_eq!(trades.len(), 4);
+        assert_eq!(
+            trades.iter().map(|t| t.price).collect::<Vec<_>>(),
+            vec![dec![100.0], dec![110.0], dec![90.0], dec![105.0]]
+        );
+        assert_eq!(
+            trades.iter().map(|t| t.time).collect::<Vec<_>>(),
+            vec![SEC(60), SEC(75), SEC(90), SEC(105)]
+        );
+        assert_eq!(trades.iter().map(|t| t.size).sum::<Decimal>(), dec![2.0]);
+        assert!(trades.iter().all(|t| t.status == LogStatus::KlineFill));
+
+        assert_eq!(LogStatus::from("K"), LogStatus::KlineFill);
+        assert_eq!(LogStatus::KlineFill.to_string(), "K");
+    }
+
+    #[test]
+    fn test_commission_in_third_asset() {
+        let mut config = MarketConfig::default();
+        config.home_currency = "USDT".to_string();
+        config.foreign_currency = "BTC".to_string();
+        config.fee_type = FeeType::Home;
+
+        let mut order = create_order();
+        order.status = OrderStatus::Filled;
+        order.execute_price = dec![60000.0];
+        order.execute_size = dec![0.01];
+        order.commission = dec![0.002];
+        order.commission_asset = "BNB".to_string();
+
+        order.update_balance(&config);
+        assert!(order.is_third_asset_commission(&config));
+        assert_eq!(order.commission_home, dec![0.0]);
+        assert_eq!(order.commission_foreign, dec![0.0]);
+
+        // BNB=500USDT
+        assert_eq!(order.commission_in_quote(&config, Some(dec![500.0])), Some(dec![1.0]));
+        assert_eq!(order.commission_in_quote(&config, None), None);
+
+        order.commission_asset = "BTC".to_string();
+        assert!(!order.is_third_asset_commission(&config));
+        assert_eq!(order.commission_in_quote(&config, None), Some(dec![120.0]));
+    }
+
+    #[test]
+    fn test_fill_from_dummy_order() {
+        let mut config = MarketConfig::default();
+        config.home_currency = "USDT".to_string();
+        config.foreign_currency = "BTC".to_string();
+        config.fee_type = FeeType::Home;
+        config.maker_fee = dec![0.001];
+        config.taker_fee = dec![0.002];
+
+        let mut order = create_order();
+        order.status = OrderStatus::Filled;
+        order.is_maker = true;
+        order.execute_price = dec![1000.0];
+        order.execute_size = dec![0.5];
+        order.transaction_id = "T-0001".to_string();
+
+        let fill = Fill::from_dummy_order(&order, &config);
+        assert_eq!(fill.price, dec![1000.0]);
+        assert_eq!(fill.qty, dec![0.5]);
+        assert_eq!(fill.fee, dec![0.5]);
+        assert_eq!(fill.fee_asset, "USDT");
+        assert_eq!(fill.exec_id, "T-0001");
+        assert!(fill.is_maker);
+
+        config.fee_type = FeeType::Foreign;
+        order.is_maker = false;
+        let fill = Fill::from_dummy_order(&order, &config);
+        assert_eq!(fill.fee, dec![0.001]);
+        assert_eq!(fill.fee_asset, "BTC");
+
+        let df = fillvec_to_dataframe(&vec![fill]);
+        assert_eq!(df.shape(), (1, 12));
+    }
+
     #[test]
     fn test_order_status_from_str() {
         let status = OrderStatus::from_str("neW").unwrap_or(OrderStatus::Unknown);
@@ -1504,6 +2122,7 @@ mod order_tests {
         assert!(OrderStatus::PartiallyFilled.__eq__("PartiallyFilled"));
         assert!(OrderStatus::Filled.__eq__("Filled"));
         assert!(OrderStatus::Canceled.__eq__("Canceled"));
+        assert!(OrderStatus::Amended.__eq__("amended"));
         assert!(OrderStatus::Rejected.__eq__("Rejected"));
         assert!(OrderStatus::Error.__eq__("error"));
         assert!(OrderStatus::Unknown.__eq__("???"));
@@ -7,12 +7,15 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use ndarray::Array3;
+use numpy::IntoPyArray;
 use once_cell::sync::Lazy;
 use polars::{
     prelude::{DataFrame, NamedFrom},
     series::Series,
 };
-use pyo3::{pyclass, pyfunction};
+use pyo3::{pyclass, pyfunction, pymethods, PyObject, Python};
 use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
 
@@ -28,6 +31,10 @@ use super::{
 
 static ALL_BOARD: Lazy<Mutex<OrderBookList>> = Lazy::new(|| Mutex::new(OrderBookList::new()));
 
+/// チェックサム不一致で板を取り直す間隔の初期値と上限。失敗が続くと倍々に延ばす。
+pub const ORDERBOOK_RESYNC_BACKOFF_MS: i64 = 500;
+pub const ORDERBOOK_RESYNC_BACKOFF_MAX_MS: i64 = 30_000;
+
 pub struct OrderBookList {
     books: HashMap<String, Arc<Mutex<OrderBookRaw>>>,
 }
@@ -156,6 +163,9 @@ pub struct BoardTransfer {
     pub bids: Vec<BoardItem>,
     pub asks: Vec<BoardItem>,
     pub snapshot: bool,
+    /// 取引所から送られてくる板のチェックサム（提供されない場合はNone）
+    #[serde(default)]
+    pub checksum: Option<i64>,
 }
 
 impl BoardTransfer {
@@ -167,6 +177,7 @@ impl BoardTransfer {
             bids: vec![],
             asks: vec![],
             snapshot: false,
+            checksum: None,
         }
     }
 
@@ -177,7 +188,8 @@ impl BoardTransfer {
             last_update_id: order_book.last_update_id,
             bids: order_book.bids.get(),
             asks: order_book.asks.get(),
-            snapshot: true
+            snapshot: true,
+            checksum: None,
         }
     }
 
@@ -231,6 +243,126 @@ impl BoardTransfer {
     }
 }
 
+/// 板の価格レベルの変化の種類
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BoardEventType {
+    Add,    // 新しい価格レベル
+    Cancel, // 価格レベルが消えた
+    Modify, // サイズが変わった
+}
+
+/// 価格レベルごとの変化。deltaはsize - prev_size。
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BoardLevelDiff {
+    #[pyo3(get)]
+    pub side: OrderSide,
+    #[pyo3(get)]
+    pub price: Decimal,
+    #[pyo3(get)]
+    pub prev_size: Decimal,
+    #[pyo3(get)]
+    pub size: Decimal,
+    #[pyo3(get)]
+    pub delta: Decimal,
+    #[pyo3(get)]
+    pub event: BoardEventType,
+}
+
+impl BoardLevelDiff {
+    pub fn new(side: OrderSide, price: Decimal, prev_size: Decimal, size: Decimal) -> Self {
+        let event = if prev_size == dec![0.0] {
+            BoardEventType::Add
+        } else if size == dec![0.0] {
+            BoardEventType::Cancel
+        } else {
+            BoardEventType::Modify
+        };
+
+        Self {
+            side,
+            price,
+            prev_size,
+            size,
+            delta: size - prev_size,
+            event,
+        }
+    }
+}
+
+/// 2つの板のスナップショットの差分
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct BoardDiff {
+    #[pyo3(get)]
+    pub time: MicroSec,
+    #[pyo3(get)]
+    pub added: Vec<BoardLevelDiff>,
+    #[pyo3(get)]
+    pub removed: Vec<BoardLevelDiff>,
+    #[pyo3(get)]
+    pub changed: Vec<BoardLevelDiff>,
+}
+
+impl BoardDiff {
+    pub fn new(time: MicroSec) -> Self {
+        Self {
+            time,
+            ..Default::default()
+        }
+    }
+}
+
+#[pymethods]
+impl BoardDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn __len__(&self) -> usize {
+        self.added.len() + self.removed.len() + self.changed.len()
+    }
+
+    pub fn __repr__(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+/// 板の更新ごとのBoardDiffを受け取るストリーム
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct BoardEventStream {
+    receiver: Receiver<BoardDiff>,
+}
+
+#[pymethods]
+impl BoardEventStream {
+    /// 次のBoardDiffを待つ。timeout_sec以内に来なければNone。
+    #[pyo3(signature = (timeout_sec=None))]
+    pub fn recv(&self, py: Python, timeout_sec: Option<f64>) -> anyhow::Result<Option<BoardDiff>> {
+        py.allow_threads(|| match timeout_sec {
+            Some(sec) => match self
+                .receiver
+                .recv_timeout(std::time::Duration::from_secs_f64(sec))
+            {
+                Ok(diff) => Ok(Some(diff)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            None => Ok(Some(self.receiver.recv()?)),
+        })
+    }
+
+    pub fn __iter__(slf: pyo3::PyRef<'_, Self>) -> pyo3::PyRef<'_, Self> {
+        slf
+    }
+
+    pub fn __next__(&self, py: Python) -> Option<BoardDiff> {
+        py.allow_threads(|| self.receiver.recv().ok())
+    }
+}
+
 /// 板上の1行を表す。（価格＆数量）
 #[pyclass]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -309,7 +441,7 @@ impl Board {
         let mut vec = self.get();
 
         if self.max_depth != 0 && self.max_depth < vec.len() as u32 {
-            log::info!("board depth over. remove items.");
+            log::debug!("board depth over. remove items.");
             let not_valid = vec.split_off(self.max_depth as usize);
             for item in not_valid {
                 self.board.remove(&item.price);
@@ -322,6 +454,57 @@ impl Board {
         self.board.clear();
     }
 
+    /// previousからの価格レベルごとの変化。sideはbidsならBuy、asksならSell。
+    pub fn diff(&self, previous: &Board, side: OrderSide) -> Vec<BoardLevelDiff> {
+        let mut diffs: Vec<BoardLevelDiff> = vec![];
+
+        for (price, size) in self.board.iter() {
+            match previous.board.get(price) {
+                None => diffs.push(BoardLevelDiff::new(side, *price, dec![0.0], *size)),
+                Some(prev_size) if prev_size != size => {
+                    diffs.push(BoardLevelDiff::new(side, *price, *prev_size, *size))
+                }
+                _ => {}
+            }
+        }
+
+        for (price, prev_size) in previous.board.iter() {
+            if !self.board.contains_key(price) {
+                diffs.push(BoardLevelDiff::new(side, *price, *prev_size, dec![0.0]));
+            }
+        }
+
+        if self.asc {
+            diffs.sort_by(|a, b| a.price.cmp(&b.price));
+        } else {
+            diffs.sort_by(|a, b| b.price.cmp(&a.price));
+        }
+
+        diffs
+    }
+
+    /// groupの幅で価格をまとめた板を返す。
+    /// bidは切り捨て、askは切り上げでまとめるため、まとめた後も板が交差しない。
+    pub fn grouped(&self, group: Decimal) -> anyhow::Result<Board> {
+        if group <= dec![0.0] {
+            return Err(anyhow::anyhow!("group must be positive: {}", group));
+        }
+
+        let mut grouped = Board::new(0, self.asc);
+
+        for (price, size) in self.board.iter() {
+            let bucket = if self.asc {
+                (price / group).ceil() * group
+            } else {
+                (price / group).floor() * group
+            };
+
+            *grouped.board.entry(bucket.normalize()).or_insert(dec![0.0]) += size;
+        }
+
+        Ok(grouped)
+    }
+
     pub fn to_dataframe(&mut self) -> anyhow::Result<DataFrame> {
         let board = self.get();
 
@@ -423,6 +606,97 @@ impl OrderBookRaw {
         self.bids.clip_depth();
         self.asks.clip_depth();
     }
+
+    /// previousからの変化をadd/cancel/modifyに分類して返す
+    pub fn diff(&self, previous: &OrderBookRaw) -> BoardDiff {
+        let mut diff = BoardDiff::new(self.last_update_time);
+
+        let levels = self
+            .bids
+            .diff(&previous.bids, OrderSide::Buy)
+            .into_iter()
+            .chain(self.asks.diff(&previous.asks, OrderSide::Sell));
+
+        for level in levels {
+            match level.event {
+                BoardEventType::Add => diff.added.push(level),
+                BoardEventType::Cancel => diff.removed.push(level),
+                BoardEventType::Modify => diff.changed.push(level),
+            }
+        }
+
+        diff
+    }
+
+    /// 上位depth件の板から"bid_price:bid_size:ask_price:ask_size:..."を作りCRC32をとる
+    /// (OKXのドキュメントにある形式。片側が足りない場合はある方だけを続ける)。
+    /// 価格・数量は取引所が送ってきた文字列のまま使う必要がある。Decimalは文字列の桁数を保持するので
+    /// to_stringで元の表記に戻る。
+    pub fn calc_checksum(&self, depth: usize) -> u32 {
+        let bids = self.bids.get();
+        let asks = self.asks.get();
+
+        let mut items: Vec<String> = vec![];
+
+        for i in 0..depth {
+            if let Some(bid) = bids.get(i) {
+                items.push(bid.price.to_string());
+                items.push(bid.size.to_string());
+            }
+
+            if let Some(ask) = asks.get(i) {
+                items.push(ask.price.to_string());
+                items.push(ask.size.to_string());
+            }
+        }
+
+        let mut crc = flate2::Crc::new();
+        crc.update(items.join(":").as_bytes());
+
+        crc.sum()
+    }
+
+    /// 取引所によって符号付き/符号なしの両方があるため、どちらでも一致すればOKとする。
+    pub fn verify_checksum(&self, expected: i64, depth: usize) -> bool {
+        let checksum = self.calc_checksum(depth);
+
+        checksum as i64 == expected || checksum as i32 as i64 == expected
+    }
+}
+
+/// チェックサム不一致による板の取り直しの間隔を管理する。
+/// 取り直しても一致しない状態が続く場合にRESTを連打しないよう、間隔を倍々に延ばす。
+#[derive(Debug, Clone, Default)]
+pub struct OrderBookResync {
+    attempts: u32,
+    next_time: MicroSec,
+}
+
+impl OrderBookResync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 今取り直してよいか
+    pub fn can_resync(&self, now: MicroSec) -> bool {
+        self.next_time <= now
+    }
+
+    /// 取り直したら呼ぶ。次に取り直せるのはbackoff後。
+    pub fn on_resync(&mut self, now: MicroSec) {
+        let backoff = ORDERBOOK_RESYNC_BACKOFF_MS
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(ORDERBOOK_RESYNC_BACKOFF_MAX_MS);
+
+        self.attempts += 1;
+        self.next_time = now + backoff * 1_000;
+    }
+
+    /// チェックサムが一致したら呼ぶ。
+    pub fn on_verified(&mut self) {
+        self.attempts = 0;
+        self.next_time = 0;
+    }
 }
 
 #[pyclass]
@@ -432,6 +706,7 @@ pub struct OrderBook {
     category: String,
     symbol: String,
     board: Arc<Mutex<OrderBookRaw>>,
+    event_sender: Option<Sender<BoardDiff>>,
 }
 
 impl OrderBook {
@@ -450,9 +725,14 @@ impl OrderBook {
             category: category,
             symbol: symbol,
             board: board,
+            event_sender: None,
         }
     }
 
+    pub fn get_symbol(&self) -> String {
+        self.symbol.clone()
+    }
+
     pub fn get_first_update_id(&self) -> u64 {
         self.board.lock().unwrap().first_update_id
     }
@@ -486,6 +766,7 @@ impl OrderBook {
             category: category,
             symbol: symbol,
             board: board,
+            event_sender: None,
         })
     }
 
@@ -511,6 +792,31 @@ impl OrderBook {
         Ok((bids, asks))
     }
 
+    /// 板を(side, level, [price, size])の固定shape(2, depth, 2)の配列にする。
+    /// side 0がbids(高い順)、1がasks(安い順)。depthに満たない部分は0で埋め、超える分は捨てる。
+    pub fn get_board_tensor(&self, depth: usize) -> Array3<f64> {
+        let (bids, asks) = {
+            let board = self.board.lock().unwrap();
+            (board.bids.get(), board.asks.get())
+        };
+
+        let mut tensor = Array3::<f64>::zeros((2, depth, 2));
+
+        for (side, items) in [bids, asks].iter().enumerate() {
+            for (level, item) in items.iter().take(depth).enumerate() {
+                tensor[[side, level, 0]] = item.price.to_f64().unwrap_or_default();
+                tensor[[side, level, 1]] = item.size.to_f64().unwrap_or_default();
+            }
+        }
+
+        tensor
+    }
+
+    /// get_board_tensorをnumpyの配列で返す
+    pub fn get_board_pyarray(&self, py: Python, depth: usize) -> PyObject {
+        self.get_board_tensor(depth).into_pyarray_bound(py).into_any().unbind()
+    }
+
     pub fn get_board(&self) -> anyhow::Result<(DataFrame, DataFrame)> {
         let mut board = self.board.lock().unwrap();
         let bids = board.get_bids_dataframe()?;
@@ -518,6 +824,14 @@ impl OrderBook {
         Ok((bids, asks))
     }
 
+    /// groupの幅でまとめた板を(bids, asks)のDataFrameで返す
+    pub fn get_board_grouped(&self, group: Decimal) -> anyhow::Result<(DataFrame, DataFrame)> {
+        let board = self.board.lock().unwrap();
+        let bids = board.bids.grouped(group)?.to_dataframe()?;
+        let asks = board.asks.grouped(group)?.to_dataframe()?;
+        Ok((bids, asks))
+    }
+
     pub fn get_json(&self, size: usize) -> anyhow::Result<String> {
         let board = self.board.lock().unwrap();
         let mut bids = board.bids.get();
@@ -542,10 +856,38 @@ impl OrderBook {
     }
 
     pub fn update(&mut self, board_transfer: &BoardTransfer) {
-        self.board
-            .lock()
-            .unwrap()
-            .update(board_transfer);
+        let mut board = self.board.lock().unwrap();
+        let previous = self.event_sender.as_ref().map(|_| board.clone());
+
+        board.update(board_transfer);
+        Self::send_event(&mut self.event_sender, previous, &board);
+    }
+
+    /// 差分を適用した後、チェックサムがあれば検証する。
+    /// 不一致の場合はfalseを返すので、呼び出し側で板を取り直すこと。
+    pub fn update_and_verify(&mut self, board_transfer: &BoardTransfer, depth: usize) -> bool {
+        let mut board = self.board.lock().unwrap();
+        let previous = self.event_sender.as_ref().map(|_| board.clone());
+
+        board.update(board_transfer);
+        Self::send_event(&mut self.event_sender, previous, &board);
+
+        match board_transfer.checksum {
+            Some(checksum) => {
+                if board.verify_checksum(checksum, depth) {
+                    true
+                } else {
+                    log::warn!(
+                        "orderbook checksum mismatch: expected={} / actual={} (update_id={})",
+                        checksum,
+                        board.calc_checksum(depth),
+                        board_transfer.last_update_id
+                    );
+                    false
+                }
+            }
+            None => true,
+        }
     }
 
     pub fn dry_market_order(
@@ -621,6 +963,99 @@ impl OrderBook {
 
         Ok(orders)
     }
+
+    /// 指値`price`で即時に約定可能な数量（板の反対側で指値に届く範囲の合計）
+    pub fn fillable_size(&self, side: OrderSide, price: Decimal) -> Decimal {
+        let board = self.board.lock().unwrap();
+
+        let items = if side == OrderSide::Buy {
+            board.asks.get()
+        } else {
+            board.bids.get()
+        };
+
+        items
+            .iter()
+            .take_while(|item| {
+                if side == OrderSide::Buy {
+                    item.price <= price
+                } else {
+                    price <= item.price
+                }
+            })
+            .map(|item| item.size)
+            .sum()
+    }
+
+    /// 指値`price`で`size`を即時に約定させたときの平均約定価格（板を上から食っていく）
+    /// 指値に届く板がなければNone
+    pub fn fill_price(&self, side: OrderSide, price: Decimal, size: Decimal) -> Option<Decimal> {
+        let board = self.board.lock().unwrap();
+
+        let items = if side == OrderSide::Buy {
+            board.asks.get()
+        } else {
+            board.bids.get()
+        };
+
+        let mut remain_size = size;
+        let mut filled_size = dec![0.0];
+        let mut quote_vol = dec![0.0];
+
+        for item in items.iter() {
+            let crossed = if side == OrderSide::Buy {
+                item.price <= price
+            } else {
+                price <= item.price
+            };
+
+            if !crossed || remain_size <= dec![0.0] {
+                break;
+            }
+
+            let execute_size = item.size.min(remain_size);
+            filled_size += execute_size;
+            quote_vol += item.price * execute_size;
+            remain_size -= execute_size;
+        }
+
+        if filled_size == dec![0.0] {
+            return None;
+        }
+
+        Some(quote_vol / filled_size)
+    }
+}
+
+impl OrderBook {
+    /// 以降の板の更新ごとにBoardDiffを受け取るチャネルを開く(開きなおすと前のチャネルは閉じる)
+    pub fn open_event_channel(&mut self) -> BoardEventStream {
+        let (sender, receiver) = unbounded();
+        self.event_sender = Some(sender);
+
+        BoardEventStream { receiver }
+    }
+
+    fn send_event(
+        event_sender: &mut Option<Sender<BoardDiff>>,
+        previous: Option<OrderBookRaw>,
+        board: &OrderBookRaw,
+    ) {
+        let (sender, previous) = match (event_sender.as_ref(), previous) {
+            (Some(sender), Some(previous)) => (sender, previous),
+            _ => return,
+        };
+
+        let diff = board.diff(&previous);
+        if diff.is_empty() {
+            return;
+        }
+
+        if sender.send(diff).is_err() {
+            log::debug!("board event channel closed");
+            *event_sender = None;
+        }
+    }
 }
 
 impl Drop for OrderBook {
@@ -645,6 +1080,55 @@ impl Drop for OrderBook {
 mod board_test {
     use super::*;
 
+    #[test]
+    fn test_board_diff() {
+        let mut previous = OrderBookRaw::new(0);
+        let mut transfer = BoardTransfer::new();
+        transfer.snapshot = true;
+        transfer.insert_bid(&(dec![100.0], dec![1.0]));
+        transfer.insert_bid(&(dec![99.0], dec![2.0]));
+        transfer.insert_ask(&(dec![101.0], dec![1.0]));
+        previous.update(&transfer);
+
+        let mut current = previous.clone();
+        let mut transfer = BoardTransfer::new();
+        transfer.last_update_time = 10;
+        transfer.insert_bid(&(dec![100.0], dec![3.0])); // modify
+        transfer.insert_bid(&(dec![99.0], dec![0.0])); // cancel
+        transfer.insert_ask(&(dec![102.0], dec![5.0])); // add
+        current.update(&transfer);
+
+        let diff = current.diff(&previous);
+        assert_eq!(diff.time, 10);
+        assert_eq!(diff.__len__(), 3);
+
+        assert_eq!(diff.added, vec![BoardLevelDiff::new(OrderSide::Sell, dec![102.0], dec![0.0], dec![5.0])]);
+        assert_eq!(diff.removed[0].price, dec![99.0]);
+        assert_eq!(diff.removed[0].delta, dec![-2.0]);
+        assert_eq!(diff.removed[0].event, BoardEventType::Cancel);
+        assert_eq!(diff.changed[0].side, OrderSide::Buy);
+        assert_eq!(diff.changed[0].delta, dec![2.0]);
+
+        assert!(current.diff(&current).is_empty());
+    }
+
+    #[test]
+    fn test_board_event_channel() {
+        let config = MarketConfig::default();
+        let mut book = OrderBook::new(&config, 0);
+        let stream = book.open_event_channel();
+
+        let mut transfer = BoardTransfer::new();
+        transfer.insert_bid(&(dec![100.0], dec![1.0]));
+        book.update(&transfer);
+        // 変化がない更新はイベントにならない
+        book.update(&transfer);
+
+        let diff = stream.receiver.try_recv().unwrap();
+        assert_eq!(diff.added.len(), 1);
+        assert!(stream.receiver.try_recv().is_err());
+    }
+
     #[test]
     fn test_board_set() {
 
@@ -712,7 +1196,8 @@ mod board_test {
                     size: dec![0.01],
                 },
             ],
-            snapshot: true
+            snapshot: true,
+            checksum: None,
         };
 
         b.update(&board_transfer);
@@ -728,4 +1213,236 @@ mod board_test {
         let t2 = BoardTransfer::from_vec(vec);
         println!("{:?}", t2);
     }
+
+    #[test]
+    fn test_board_max_depth() {
+        let mut b = OrderBookRaw::new(5);
+
+        let mut transfer = BoardTransfer::new();
+        transfer.snapshot = true;
+        for i in 0..10 {
+            transfer.insert_bid(&(dec![100.0] - Decimal::from(i), dec![1.0]));
+            transfer.insert_ask(&(dec![101.0] + Decimal::from(i), dec![1.0]));
+        }
+        b.update(&transfer);
+
+        assert_eq!(b.get_bids().len(), 5);
+        assert_eq!(b.get_asks().len(), 5);
+        assert_eq!(b.get_edge_price().unwrap(), (dec![100.0], dec![101.0]));
+
+        // 差分で外側の板が増えても5件を超えない
+        let mut transfer = BoardTransfer::new();
+        for i in 0..10 {
+            transfer.insert_bid(&(dec![99.5] - Decimal::from(i), dec![2.0]));
+            transfer.insert_ask(&(dec![101.5] + Decimal::from(i), dec![2.0]));
+        }
+        b.update(&transfer);
+
+        assert_eq!(b.get_bids().len(), 5);
+        assert_eq!(b.get_asks().len(), 5);
+        assert_eq!(b.get_bids()[1].price, dec![99.5]);
+        assert_eq!(b.get_asks()[1].price, dec![101.5]);
+    }
+
+    #[test]
+    fn test_board_grouped() {
+        let mut b = OrderBookRaw::new(0);
+
+        let mut transfer = BoardTransfer::new();
+        transfer.snapshot = true;
+        transfer.insert_bid(&(dec![100.4], dec![1.0]));
+        transfer.insert_bid(&(dec![100.1], dec![0.5]));
+        transfer.insert_bid(&(dec![99.9], dec![2.0]));
+        transfer.insert_ask(&(dec![100.5], dec![0.3]));
+        transfer.insert_ask(&(dec![100.9], dec![0.2]));
+        transfer.insert_ask(&(dec![101.0], dec![1.0]));
+        transfer.insert_ask(&(dec![101.2], dec![4.0]));
+        b.update(&transfer);
+
+        let bids = b.bids.grouped(dec![1.0]).unwrap().get();
+        assert_eq!(bids.len(), 2);
+        assert_eq!(bids[0].price, dec![100]);
+        assert_eq!(bids[0].size, dec![1.5]);
+        assert_eq!(bids[1].price, dec![99]);
+        assert_eq!(bids[1].size, dec![2.0]);
+
+        let asks = b.asks.grouped(dec![1.0]).unwrap().get();
+        assert_eq!(asks.len(), 2);
+        assert_eq!(asks[0].price, dec![101]);
+        assert_eq!(asks[0].size, dec![1.5]);
+        assert_eq!(asks[1].price, dec![102]);
+        assert_eq!(asks[1].size, dec![4.0]);
+
+        assert!(b.bids.grouped(dec![0.0]).is_err());
+    }
+
+    #[test]
+    fn test_board_tensor() {
+        let config = MarketConfig::default();
+        let book = OrderBook::new(&config, 0);
+
+        let mut transfer = BoardTransfer::new();
+        transfer.snapshot = true;
+        transfer.insert_bid(&(dec![100.0], dec![1.0]));
+        transfer.insert_bid(&(dec![99.0], dec![2.0]));
+        transfer.insert_bid(&(dec![98.0], dec![3.0]));
+        transfer.insert_ask(&(dec![101.0], dec![0.5]));
+        book.board.lock().unwrap().update(&transfer);
+
+        let tensor = book.get_board_tensor(2);
+        assert_eq!(tensor.shape(), &[2, 2, 2]);
+        // bidsは高い順、depthを超えた分は捨てる
+        assert_eq!(tensor[[0, 0, 0]], 100.0);
+        assert_eq!(tensor[[0, 0, 1]], 1.0);
+        assert_eq!(tensor[[0, 1, 0]], 99.0);
+        assert_eq!(tensor[[0, 1, 1]], 2.0);
+        // asksが足りない分は0埋め
+        assert_eq!(tensor[[1, 0, 0]], 101.0);
+        assert_eq!(tensor[[1, 0, 1]], 0.5);
+        assert_eq!(tensor[[1, 1, 0]], 0.0);
+        assert_eq!(tensor[[1, 1, 1]], 0.0);
+
+        assert_eq!(book.get_board_tensor(0).shape(), &[2, 0, 2]);
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let mut b = OrderBookRaw::new(0);
+
+        let mut transfer = BoardTransfer::new();
+        transfer.snapshot = true;
+        transfer.insert_bid(&(dec![10.5], dec![1.2]));
+        transfer.insert_bid(&(dec![10.0], dec![0.5]));
+        transfer.insert_ask(&(dec![11.0], dec![0.3]));
+        transfer.insert_ask(&(dec![11.5], dec![2.0]));
+        b.update(&transfer);
+
+        let mut crc = flate2::Crc::new();
+        crc.update("10.5:1.2:11.0:0.3:10.0:0.5:11.5:2.0".as_bytes());
+        let expected = crc.sum();
+
+        assert_eq!(b.calc_checksum(25), expected);
+        assert!(b.verify_checksum(expected as i64, 25));
+        assert!(b.verify_checksum(expected as i32 as i64, 25));
+
+        // depthより深い板はチェックサムに含めない
+        assert_eq!(b.calc_checksum(1), {
+            let mut crc = flate2::Crc::new();
+            crc.update("10.5:1.2:11.0:0.3".as_bytes());
+            crc.sum()
+        });
+
+        // 差分を適用して板がずれたら検出できる
+        let mut delta = BoardTransfer::new();
+        delta.insert_bid(&(dec![10.0], dec![0.0]));
+        b.update(&delta);
+        assert!(!b.verify_checksum(expected as i64, 25));
+    }
+
+    #[test]
+    fn test_checksum_documented_example() {
+        // OKXのドキュメントの例: "3366.1:7:3366.8:9:3366:6:3368:8"
+        let message = r#"{"bids":[["3366.1","7"],["3366","6"]],"asks":[["3366.8","9"],["3368","8"]]}"#;
+        let levels: HashMap<String, Vec<(String, String)>> = serde_json::from_str(message).unwrap();
+
+        let mut transfer = BoardTransfer::new();
+        transfer.snapshot = true;
+        for (price, size) in levels["bids"].iter() {
+            transfer.insert_bid(&(Decimal::from_str(price).unwrap(), Decimal::from_str(size).unwrap()));
+        }
+        for (price, size) in levels["asks"].iter() {
+            transfer.insert_ask(&(Decimal::from_str(price).unwrap(), Decimal::from_str(size).unwrap()));
+        }
+
+        let mut b = OrderBookRaw::new(0);
+        b.update(&transfer);
+
+        // 符号付き32bitで送られてくる
+        assert!(b.verify_checksum(-1881014294, 25));
+    }
+
+    #[test]
+    fn test_resync_backoff() {
+        let mut resync = OrderBookResync::new();
+        assert!(resync.can_resync(0));
+
+        resync.on_resync(0);
+        assert!(!resync.can_resync(ORDERBOOK_RESYNC_BACKOFF_MS * 1_000 - 1));
+        assert!(resync.can_resync(ORDERBOOK_RESYNC_BACKOFF_MS * 1_000));
+
+        // 続けて失敗すると間隔が倍になる
+        resync.on_resync(0);
+        assert!(!resync.can_resync(ORDERBOOK_RESYNC_BACKOFF_MS * 2_000 - 1));
+
+        for _ in 0..20 {
+            resync.on_resync(0);
+        }
+        assert!(resync.can_resync(ORDERBOOK_RESYNC_BACKOFF_MAX_MS * 1_000));
+
+        resync.on_verified();
+        assert!(resync.can_resync(0));
+    }
+
+    #[test]
+    fn test_update_and_verify() {
+        let config = MarketConfig::default();
+        let mut book = OrderBook::new(&config, 0);
+
+        let mut snapshot = BoardTransfer::new();
+        snapshot.snapshot = true;
+        snapshot.insert_bid(&(dec![10.0], dec![0.5]));
+        snapshot.insert_ask(&(dec![11.0], dec![0.3]));
+        book.update(&snapshot);
+
+        // チェックサムなしの差分は検証しない
+        let mut delta = BoardTransfer::new();
+        delta.insert_bid(&(dec![10.5], dec![1.2]));
+        assert!(book.update_and_verify(&delta, 25));
+
+        let mut crc = flate2::Crc::new();
+        crc.update("10.5:1.2:11.0:0.3:10.0:0.5".as_bytes());
+        let expected = crc.sum() as i64;
+
+        let mut delta = BoardTransfer::new();
+        delta.insert_bid(&(dec![10.5], dec![1.2]));
+        delta.checksum = Some(expected);
+        assert!(book.update_and_verify(&delta, 25));
+
+        // 取りこぼした差分があると不一致になる
+        let mut delta = BoardTransfer::new();
+        delta.insert_ask(&(dec![11.5], dec![2.0]));
+        delta.checksum = Some(expected);
+        assert!(!book.update_and_verify(&delta, 25));
+
+        // スナップショットで取り直すと古い板は残らない
+        book.update(&snapshot);
+        let board = book.board.lock().unwrap();
+        assert_eq!(board.get_bids().len(), 1);
+        assert_eq!(board.get_asks().len(), 1);
+    }
+
+    #[test]
+    fn test_fill_price() {
+        let config = MarketConfig::default();
+        let mut book = OrderBook::new(&config, 0);
+
+        let mut snapshot = BoardTransfer::new();
+        snapshot.snapshot = true;
+        snapshot.insert_bid(&(dec![99.0], dec![1.0]));
+        snapshot.insert_bid(&(dec![98.0], dec![1.0]));
+        snapshot.insert_ask(&(dec![101.0], dec![1.0]));
+        snapshot.insert_ask(&(dec![102.0], dec![1.0]));
+        book.update(&snapshot);
+
+        // 最良気配だけで足りる場合は最良気配で約定
+        assert_eq!(book.fill_price(OrderSide::Buy, dec![105.0], dec![0.5]), Some(dec![101.0]));
+        // 2段目まで食う場合は平均価格
+        assert_eq!(book.fill_price(OrderSide::Buy, dec![105.0], dec![2.0]), Some(dec![101.5]));
+        // 指値より先の板は食わない
+        assert_eq!(book.fill_price(OrderSide::Buy, dec![101.0], dec![2.0]), Some(dec![101.0]));
+        assert_eq!(book.fill_price(OrderSide::Sell, dec![98.0], dec![2.0]), Some(dec![98.5]));
+        // 届かない指値は約定しない
+        assert_eq!(book.fill_price(OrderSide::Buy, dec![100.0], dec![1.0]), None);
+        assert_eq!(book.fill_price(OrderSide::Sell, dec![100.0], dec![1.0]), None);
+    }
 }
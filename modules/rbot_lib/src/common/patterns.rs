@@ -0,0 +1,277 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use polars::lazy::prelude::{col, lit, when, Expr, IntoLazy};
+use polars::prelude::DataFrame;
+use pyo3::pyfunction;
+use pyo3_polars::PyDataFrame;
+
+use crate::db::KEY;
+
+#[allow(non_upper_case_globals)]
+#[allow(non_snake_case)]
+pub mod PATTERN {
+    pub const doji: &str = "doji";
+    pub const hammer: &str = "hammer";
+    pub const shooting_star: &str = "shooting_star";
+    pub const engulfing_bullish: &str = "engulfing_bullish";
+    pub const engulfing_bearish: &str = "engulfing_bearish";
+    pub const morning_star: &str = "morning_star";
+    pub const evening_star: &str = "evening_star";
+
+    pub const ALL: [&str; 7] = [
+        doji,
+        hammer,
+        shooting_star,
+        engulfing_bullish,
+        engulfing_bearish,
+        morning_star,
+        evening_star,
+    ];
+}
+
+/// パターン判定に必要な足の本数（morning/evening starの3本）
+pub const PATTERN_LOOKBACK_BARS: i64 = 3;
+
+/// 実体が値幅に占める割合がこれ以下ならdoji
+const DOJI_BODY_RATIO: f64 = 0.1;
+/// hammer/shooting starのヒゲは実体のこの倍数以上
+const SHADOW_BODY_RATIO: f64 = 2.0;
+/// star足(morning/evening starの中央の足)の実体は1本目の実体のこの割合以下
+const STAR_BODY_RATIO: f64 = 0.3;
+
+/// n本前の値（n=0は当該足）
+fn prev(name: &str, n: i64) -> Expr {
+    if n == 0 {
+        col(name)
+    } else {
+        col(name).shift(lit(n))
+    }
+}
+
+fn body(n: i64) -> Expr {
+    let diff = prev(KEY::close, n) - prev(KEY::open, n);
+    when(diff.clone().lt(lit(0.0)))
+        .then(lit(0.0) - diff.clone())
+        .otherwise(diff)
+}
+
+fn range(n: i64) -> Expr {
+    prev(KEY::high, n) - prev(KEY::low, n)
+}
+
+fn body_top(n: i64) -> Expr {
+    when(prev(KEY::open, n).gt(prev(KEY::close, n)))
+        .then(prev(KEY::open, n))
+        .otherwise(prev(KEY::close, n))
+}
+
+fn body_bottom(n: i64) -> Expr {
+    when(prev(KEY::open, n).lt(prev(KEY::close, n)))
+        .then(prev(KEY::open, n))
+        .otherwise(prev(KEY::close, n))
+}
+
+fn upper_shadow(n: i64) -> Expr {
+    prev(KEY::high, n) - body_top(n)
+}
+
+fn lower_shadow(n: i64) -> Expr {
+    body_bottom(n) - prev(KEY::low, n)
+}
+
+fn is_bullish(n: i64) -> Expr {
+    prev(KEY::close, n).gt(prev(KEY::open, n))
+}
+
+fn is_bearish(n: i64) -> Expr {
+    prev(KEY::close, n).lt(prev(KEY::open, n))
+}
+
+fn body_mid(n: i64) -> Expr {
+    (prev(KEY::open, n) + prev(KEY::close, n)) / lit(2.0)
+}
+
+fn doji() -> Expr {
+    range(0)
+        .gt(lit(0.0))
+        .and(body(0).lt_eq(range(0) * lit(DOJI_BODY_RATIO)))
+}
+
+fn hammer() -> Expr {
+    body(0)
+        .gt(lit(0.0))
+        .and(lower_shadow(0).gt_eq(body(0) * lit(SHADOW_BODY_RATIO)))
+        .and(upper_shadow(0).lt_eq(body(0)))
+}
+
+fn shooting_star() -> Expr {
+    body(0)
+        .gt(lit(0.0))
+        .and(upper_shadow(0).gt_eq(body(0) * lit(SHADOW_BODY_RATIO)))
+        .and(lower_shadow(0).lt_eq(body(0)))
+}
+
+fn engulfing_bullish() -> Expr {
+    is_bearish(1)
+        .and(is_bullish(0))
+        .and(prev(KEY::open, 0).lt_eq(prev(KEY::close, 1)))
+        .and(prev(KEY::close, 0).gt_eq(prev(KEY::open, 1)))
+        .and(body(0).gt(body(1)))
+}
+
+fn engulfing_bearish() -> Expr {
+    is_bullish(1)
+        .and(is_bearish(0))
+        .and(prev(KEY::open, 0).gt_eq(prev(KEY::close, 1)))
+        .and(prev(KEY::close, 0).lt_eq(prev(KEY::open, 1)))
+        .and(body(0).gt(body(1)))
+}
+
+fn morning_star() -> Expr {
+    is_bearish(2)
+        .and(body(1).lt_eq(body(2) * lit(STAR_BODY_RATIO)))
+        .and(body_top(1).lt_eq(prev(KEY::close, 2)))
+        .and(is_bullish(0))
+        .and(prev(KEY::close, 0).gt(body_mid(2)))
+}
+
+fn evening_star() -> Expr {
+    is_bullish(2)
+        .and(body(1).lt_eq(body(2) * lit(STAR_BODY_RATIO)))
+        .and(body_bottom(1).gt_eq(prev(KEY::close, 2)))
+        .and(is_bearish(0))
+        .and(prev(KEY::close, 0).lt(body_mid(2)))
+}
+
+/// ohlcv(open, high, low, close)にローソク足パターンのbool列を追加する。
+/// 判定に必要な過去の足が足りない先頭行はfalseになる。
+pub fn detect_patterns(df: &DataFrame) -> anyhow::Result<DataFrame> {
+    let patterns = [
+        (PATTERN::doji, doji()),
+        (PATTERN::hammer, hammer()),
+        (PATTERN::shooting_star, shooting_star()),
+        (PATTERN::engulfing_bullish, engulfing_bullish()),
+        (PATTERN::engulfing_bearish, engulfing_bearish()),
+        (PATTERN::morning_star, morning_star()),
+        (PATTERN::evening_star, evening_star()),
+    ];
+
+    let exprs: Vec<Expr> = patterns
+        .into_iter()
+        .map(|(name, expr)| expr.fill_null(lit(false)).alias(name))
+        .collect();
+
+    let df = df.clone().lazy().with_columns(exprs).collect()?;
+
+    Ok(df)
+}
+
+/// dfの最終行で成立しているパターン名を返す
+pub fn last_patterns(df: &DataFrame) -> anyhow::Result<Vec<String>> {
+    let mut fired = vec![];
+
+    if df.height() == 0 {
+        return Ok(fired);
+    }
+
+    let last = df.height() - 1;
+
+    for name in PATTERN::ALL {
+        if df.column(name)?.bool()?.get(last) == Some(true) {
+            fired.push(name.to_string());
+        }
+    }
+
+    Ok(fired)
+}
+
+#[pyfunction]
+#[pyo3(name = "detect_patterns")]
+pub fn py_detect_patterns(df: PyDataFrame) -> anyhow::Result<PyDataFrame> {
+    let df = detect_patterns(&df.0)?;
+
+    Ok(PyDataFrame(df))
+}
+
+#[cfg(test)]
+mod patterns_test {
+    use super::*;
+    use polars::prelude::NamedFrom;
+    use polars::series::Series;
+
+    fn make_ohlcv(bars: &[(f64, f64, f64, f64)]) -> DataFrame {
+        let open: Vec<f64> = bars.iter().map(|b| b.0).collect();
+        let high: Vec<f64> = bars.iter().map(|b| b.1).collect();
+        let low: Vec<f64> = bars.iter().map(|b| b.2).collect();
+        let close: Vec<f64> = bars.iter().map(|b| b.3).collect();
+
+        DataFrame::new(vec![
+            Series::new(KEY::open, open),
+            Series::new(KEY::high, high),
+            Series::new(KEY::low, low),
+            Series::new(KEY::close, close),
+        ])
+        .unwrap()
+    }
+
+    fn fired(bars: &[(f64, f64, f64, f64)]) -> Vec<String> {
+        let df = detect_patterns(&make_ohlcv(bars)).unwrap();
+        last_patterns(&df).unwrap()
+    }
+
+    #[test]
+    fn test_doji() {
+        assert_eq!(fired(&[(100.0, 105.0, 95.0, 100.5)]), vec![PATTERN::doji]);
+        assert!(fired(&[(100.0, 105.0, 95.0, 104.0)]).is_empty());
+    }
+
+    #[test]
+    fn test_hammer() {
+        // 長い下ヒゲ、上ヒゲなし
+        assert_eq!(fired(&[(100.0, 102.0, 90.0, 102.0)]), vec![PATTERN::hammer]);
+    }
+
+    #[test]
+    fn test_shooting_star() {
+        // 長い上ヒゲ、下ヒゲなし
+        assert_eq!(fired(&[(102.0, 112.0, 100.0, 100.0)]), vec![PATTERN::shooting_star]);
+    }
+
+    #[test]
+    fn test_engulfing() {
+        // 陰線を次の陽線が包む
+        let r = fired(&[(105.0, 106.0, 99.0, 100.0), (99.0, 108.0, 98.0, 107.0)]);
+        assert_eq!(r, vec![PATTERN::engulfing_bullish]);
+
+        // 陽線を次の陰線が包む
+        let r = fired(&[(100.0, 106.0, 99.0, 105.0), (106.0, 107.0, 97.0, 98.0)]);
+        assert_eq!(r, vec![PATTERN::engulfing_bearish]);
+
+        // 1本だけでは判定しない
+        let df = detect_patterns(&make_ohlcv(&[(99.0, 108.0, 98.0, 107.0)])).unwrap();
+        assert_eq!(df.column(PATTERN::engulfing_bullish).unwrap().bool().unwrap().get(0), Some(false));
+    }
+
+    #[test]
+    fn test_morning_star() {
+        let r = fired(&[
+            (110.0, 111.0, 99.0, 100.0), // 大陰線
+            (99.0, 100.0, 97.0, 98.5),   // 下に離れた小さな足
+            (99.0, 109.0, 98.5, 108.0),  // 1本目の実体の半分以上を戻す陽線
+        ]);
+        assert!(r.contains(&PATTERN::morning_star.to_string()));
+        assert!(!r.contains(&PATTERN::evening_star.to_string()));
+    }
+
+    #[test]
+    fn test_evening_star() {
+        let r = fired(&[
+            (100.0, 111.0, 99.0, 110.0), // 大陽線
+            (111.0, 113.0, 110.0, 111.5), // 上に離れた小さな足
+            (111.0, 111.5, 101.0, 102.0), // 1本目の実体の半分以上を押し戻す陰線
+        ]);
+        assert!(r.contains(&PATTERN::evening_star.to_string()));
+        assert!(!r.contains(&PATTERN::morning_star.to_string()));
+    }
+}
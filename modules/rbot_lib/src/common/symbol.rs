@@ -0,0 +1,198 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use anyhow::anyhow;
+use pyo3::{pyclass, pymethods};
+use serde_derive::{Deserialize, Serialize};
+use strum_macros::Display;
+
+use super::{exchange_symbol, unified_symbol};
+
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+/// 市場の種類（取引所のtrade_categoryに対応）
+pub enum SymbolKind {
+    Spot,
+    Linear,  // 先物・無期限（quote建て決済）
+    Inverse, // インバース（base建て決済）
+}
+
+#[pymethods]
+impl SymbolKind {
+    pub fn to_string(&self) -> String {
+        match self {
+            SymbolKind::Spot => "spot".to_string(),
+            SymbolKind::Linear => "linear".to_string(),
+            SymbolKind::Inverse => "inverse".to_string(),
+        }
+    }
+
+    pub fn __str__(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn __repr__(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn __eq__(&self, other: &str) -> bool {
+        match SymbolKind::try_from(other) {
+            Ok(kind) => *self == kind,
+            Err(_) => false,
+        }
+    }
+}
+
+impl TryFrom<&str> for SymbolKind {
+    type Error = anyhow::Error;
+
+    fn try_from(kind: &str) -> anyhow::Result<Self> {
+        match kind.to_lowercase().as_str() {
+            "spot" => Ok(SymbolKind::Spot),
+            "linear" => Ok(SymbolKind::Linear),
+            "inverse" => Ok(SymbolKind::Inverse),
+            _ => Err(anyhow!("unknown symbol kind {:?}", kind)),
+        }
+    }
+}
+
+/// 取引所に依存しないシンボル。取引所のシンボルとはExchangeConfigを通して変換する。
+/// unified表記はccxtと同じ(spot "BTC/USDT", linear "BTC/USDT:USDT", inverse "BTC/USD:BTC")
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Symbol {
+    #[pyo3(get)]
+    pub base: String,
+    #[pyo3(get)]
+    pub quote: String,
+    #[pyo3(get)]
+    pub kind: SymbolKind,
+}
+
+#[pymethods]
+impl Symbol {
+    #[new]
+    pub fn new(base: &str, quote: &str, kind: SymbolKind) -> Self {
+        Self {
+            base: base.to_uppercase(),
+            quote: quote.to_uppercase(),
+            kind,
+        }
+    }
+
+    #[staticmethod]
+    pub fn from_unified(unified: &str) -> anyhow::Result<Self> {
+        let unified = unified.to_uppercase();
+
+        let (pair, settle) = match unified.split_once(':') {
+            Some((pair, settle)) => (pair, Some(settle)),
+            None => (unified.as_str(), None),
+        };
+
+        let (base, quote) = pair
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid unified symbol {:?}", unified))?;
+
+        let kind = match settle {
+            None => SymbolKind::Spot,
+            Some(settle) if settle == base => SymbolKind::Inverse,
+            Some(_) => SymbolKind::Linear,
+        };
+
+        Ok(Self::new(base, quote, kind))
+    }
+
+    #[getter]
+    pub fn get_unified(&self) -> String {
+        match self.kind {
+            SymbolKind::Spot => format!("{}/{}", self.base, self.quote),
+            _ => format!("{}/{}:{}", self.base, self.quote, self.get_settle()),
+        }
+    }
+
+    /// 決済通貨（spotはquote）
+    #[getter]
+    pub fn get_settle(&self) -> String {
+        match self.kind {
+            SymbolKind::Inverse => self.base.clone(),
+            _ => self.quote.clone(),
+        }
+    }
+
+    pub fn to_exchange_symbol(&self, exchange_name: &str) -> anyhow::Result<String> {
+        exchange_symbol(exchange_name, &self.get_unified())
+    }
+
+    #[staticmethod]
+    pub fn from_exchange_symbol(
+        exchange_name: &str,
+        category: &str,
+        trade_symbol: &str,
+    ) -> anyhow::Result<Self> {
+        Self::from_unified(&unified_symbol(exchange_name, category, trade_symbol)?)
+    }
+
+    pub fn __str__(&self) -> String {
+        self.get_unified()
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("Symbol({})", self.get_unified())
+    }
+
+    pub fn __eq__(&self, other: &Symbol) -> bool {
+        self == other
+    }
+}
+
+#[cfg(test)]
+mod symbol_test {
+    use super::*;
+
+    #[test]
+    fn test_unified() -> anyhow::Result<()> {
+        let s = Symbol::new("btc", "usdt", SymbolKind::Spot);
+        assert_eq!(s.get_unified(), "BTC/USDT");
+        assert_eq!(Symbol::from_unified("BTC/USDT")?, s);
+
+        let s = Symbol::from_unified("BTC/USDT:USDT")?;
+        assert_eq!(s, Symbol::new("BTC", "USDT", SymbolKind::Linear));
+
+        let s = Symbol::from_unified("BTC/USD:BTC")?;
+        assert_eq!(s, Symbol::new("BTC", "USD", SymbolKind::Inverse));
+        assert_eq!(s.get_settle(), "BTC");
+
+        assert!(Symbol::from_unified("BTCUSDT").is_err());
+
+        Ok(())
+    }
+
+    /// 取引所ごとに exchange symbol -> Symbol -> exchange symbol が一致すること
+    #[test]
+    fn test_exchange_round_trip() -> anyhow::Result<()> {
+        let cases = [
+            ("bybit", Symbol::new("BTC", "USDT", SymbolKind::Spot), "spot", "BTCUSDT"),
+            ("bybit", Symbol::new("BTC", "USDT", SymbolKind::Linear), "linear", "BTCUSDT"),
+            ("bybit", Symbol::new("BTC", "USDC", SymbolKind::Linear), "linear", "BTCPERP"),
+            ("bybit", Symbol::new("BTC", "USD", SymbolKind::Inverse), "inverse", "BTCUSD"),
+            ("binance", Symbol::new("BTC", "USDT", SymbolKind::Spot), "spot", "BTCUSDT"),
+            ("binance", Symbol::new("BTC", "USD", SymbolKind::Inverse), "inverse", "BTCUSD_PERP"),
+            ("bitflyer", Symbol::new("BTC", "JPY", SymbolKind::Linear), "linear", "FX_BTC_JPY"),
+            ("bitbank", Symbol::new("BTC", "JPY", SymbolKind::Spot), "spot", "btc_jpy"),
+            ("hyperliquid", Symbol::new("BTC", "USDC", SymbolKind::Linear), "linear", "BTC"),
+        ];
+
+        for (exchange, symbol, category, trade_symbol) in cases {
+            assert_eq!(symbol.to_exchange_symbol(exchange)?, trade_symbol, "{}", exchange);
+
+            let back = Symbol::from_exchange_symbol(exchange, category, trade_symbol)?;
+            assert_eq!(back, symbol, "{}", exchange);
+            assert_eq!(back.to_exchange_symbol(exchange)?, trade_symbol, "{}", exchange);
+        }
+
+        // 同じBTCUSDTでもspotとlinearは別の市場
+        assert!(Symbol::from_exchange_symbol("bybit", "inverse", "BTCUSDT").is_err());
+
+        Ok(())
+    }
+}
@@ -3,12 +3,16 @@
 
 #![allow(non_snake_case)]
 
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::RwLock;
 
 use chrono::{DateTime, Datelike as _, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use pyo3::prelude::*;
 
 use anyhow::anyhow;
+use once_cell::sync::Lazy;
 
 pub const MICRO_SECOND: i64 = 1_000_000;
 pub const NANO_SECOND: i64 = 1_000_000_000;
@@ -20,6 +24,42 @@ pub fn msec_to_microsec(t: i64) -> MicroSec {
     return (t as i64) * 1_000;
 }
 
+/// 取引所の生のタイムスタンプの単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampUnit {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+}
+
+pub fn normalize_timestamp(raw: i64, unit: TimestampUnit) -> MicroSec {
+    match unit {
+        TimestampUnit::Seconds => raw * MICRO_SECOND,
+        TimestampUnit::Milliseconds => raw * 1_000,
+        TimestampUnit::Microseconds => raw,
+    }
+}
+
+/// 桁数から単位を推定する。現在時刻は秒で10桁、msで13桁、μsで16桁になる。
+///  < 10^11 (秒なら西暦5138年まで) => Seconds
+///  < 10^14 (msなら西暦5138年まで) => Milliseconds
+pub fn infer_timestamp_unit(value: i64) -> TimestampUnit {
+    let value = value.abs();
+
+    if value < 100_000_000_000 {
+        TimestampUnit::Seconds
+    } else if value < 100_000_000_000_000 {
+        TimestampUnit::Milliseconds
+    } else {
+        TimestampUnit::Microseconds
+    }
+}
+
+/// 単位を推定してMicroSecへ変換する
+pub fn auto_timestamp(raw: i64) -> MicroSec {
+    normalize_timestamp(raw, infer_timestamp_unit(raw))
+}
+
 pub fn microsec_to_sec(t: MicroSec) -> i64 {
     t / MICRO_SECOND
 }
@@ -66,30 +106,51 @@ pub fn CEIL(microsecond: MicroSec, unit_sec: i64) -> MicroSec {
     return floor;
 }
 
+thread_local! {
+    /// 表示に使うタイムゾーン(UTCからの秒数)。0はUTC。
+    static TZ_OFFSET_SEC: Cell<i64> = Cell::new(0);
+}
+
+/// time_string等の表示とLOCAL_HHMMに使うタイムゾーンを設定する(例 JSTは9*3600)。
+/// NOW()などの時刻は常にUTCのまま。
+#[pyfunction]
+pub fn set_local_timezone(offset_sec: i64) {
+    TZ_OFFSET_SEC.with(|tz| tz.set(offset_sec));
+}
+
+#[pyfunction]
+pub fn get_local_timezone() -> i64 {
+    TZ_OFFSET_SEC.with(|tz| tz.get())
+}
+
+fn to_local_datetime(t: MicroSec) -> DateTime<Utc> {
+    to_naive_datetime(t + SEC(get_local_timezone()))
+}
+
 #[pyfunction]
 pub fn time_string(t: MicroSec) -> String {
-    let datetime = to_naive_datetime(t);
+    let datetime = to_local_datetime(t);
 
     return datetime.format("%Y-%m-%dT%H:%M:%S%.6f").to_string();
 }
 
 #[pyfunction]
 pub fn short_time_string(t: MicroSec) -> String {
-    let datetime = to_naive_datetime(t);
+    let datetime = to_local_datetime(t);
 
     return datetime.format("%Y-%m-%dT%H:%M:%S").to_string();
 }
 
 #[pyfunction]
 pub fn hour_string(t: MicroSec) -> String {
-    let datetime = to_naive_datetime(t);
+    let datetime = to_local_datetime(t);
 
     return datetime.format("%H").to_string();
 }
 
 #[pyfunction]
 pub fn min_string(t: MicroSec) -> String {
-    let datetime = to_naive_datetime(t);
+    let datetime = to_local_datetime(t);
 
     return datetime.format("%M").to_string();
 }
@@ -104,7 +165,7 @@ pub fn date_string(t: MicroSec) -> String {
 
 #[pyfunction]
 pub fn date_time_string(t: MicroSec) -> String {
-    let datetime = to_naive_datetime(t);
+    let datetime = to_local_datetime(t);
 
     return datetime.format("%Y/%m/%dT%H:%M").to_string();
 }
@@ -136,6 +197,40 @@ pub fn parse_time(t: &str) -> MicroSec {
     return datetime.unwrap().timestamp_micros();
 }
 
+/// ISO8601の日付・時刻をMicroSecへ変換する。
+/// "2024-08-23", "20240823", "2024-08-23T12:00:00", "2024-08-23T12:00:00+09:00" を受け付け、
+/// タイムゾーンがない場合はUTCとみなす。
+pub fn parse_iso_time(t: &str) -> anyhow::Result<MicroSec> {
+    let t = t.trim();
+
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(t) {
+        return Ok(datetime.timestamp_micros());
+    }
+
+    if let Ok(datetime) = NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Ok(datetime.and_utc().timestamp_micros());
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(t, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_micros());
+    }
+
+    parse_date(t).map_err(|_| anyhow!("unknown time format {:?}", t))
+}
+
+/// pythonから渡された時刻(MicroSecのint、またはISO8601の文字列)をMicroSecへ変換する
+pub fn extract_time(value: &PyAny) -> anyhow::Result<MicroSec> {
+    if let Ok(t) = value.extract::<MicroSec>() {
+        return Ok(t);
+    }
+
+    let t: &str = value
+        .extract()
+        .map_err(|_| anyhow!("time must be int(MicroSec) or ISO8601 str: {:?}", value))?;
+
+    parse_iso_time(t)
+}
+
 #[pyfunction]
 pub fn DAYS(days: i64) -> MicroSec {
     return (24 * 60 * 60 * MICRO_SECOND * days) as MicroSec;
@@ -151,6 +246,12 @@ pub fn HHMM(hh: i64, mm: i64) -> MicroSec {
     return ((hh * 60 * 60) * MICRO_SECOND + MIN(mm)) as MicroSec;
 }
 
+/// ローカル時刻(set_local_timezone)の0時からの経過時間。UTCの0時を基準に加算して使う。
+#[pyfunction]
+pub fn LOCAL_HHMM(hh: i64, mm: i64) -> MicroSec {
+    return HHMM(hh, mm) - SEC(get_local_timezone());
+}
+
 #[pyfunction]
 pub fn MIN(min: i64) -> MicroSec {
     return min * MICRO_SECOND * 60;
@@ -183,11 +284,65 @@ pub fn NOW() -> MicroSec {
     return Utc::now().timestamp_micros();
 }
 
+/// 取引所ごとの時差(サーバ時刻 - ローカル時刻)
+static SERVER_TIME_OFFSET: Lazy<RwLock<HashMap<String, MicroSec>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// ローカル時刻と取引所の時刻の差がこれを超えたら警告する
+pub const CLOCK_SKEW_WARN_THRESHOLD: MicroSec = 1_000_000;
+
+pub fn set_server_time_offset(exchange_name: &str, offset: MicroSec) {
+    SERVER_TIME_OFFSET
+        .write()
+        .unwrap()
+        .insert(exchange_name.to_string(), offset);
+}
+
+pub fn get_server_time_offset(exchange_name: &str) -> MicroSec {
+    SERVER_TIME_OFFSET
+        .read()
+        .unwrap()
+        .get(exchange_name)
+        .cloned()
+        .unwrap_or(0)
+}
+
+/// 取引所の時刻に合わせた現在時刻。署名付きリクエストのtimestampに使う。
+pub fn SERVER_NOW(exchange_name: &str) -> MicroSec {
+    NOW() + get_server_time_offset(exchange_name)
+}
+
 #[cfg(test)]
 mod time_test {
     use crate::common::init_debug_log;
 
     use super::*;
+
+    #[test]
+    fn test_normalize_timestamp() {
+        // 2024-08-23T00:00:00Z
+        let t: MicroSec = 1724371200_000_000;
+
+        let sec = 1724371200;
+        let msec = 1724371200_000;
+        let usec = 1724371200_000_000;
+
+        assert_eq!(infer_timestamp_unit(sec), TimestampUnit::Seconds);
+        assert_eq!(infer_timestamp_unit(msec), TimestampUnit::Milliseconds);
+        assert_eq!(infer_timestamp_unit(usec), TimestampUnit::Microseconds);
+
+        assert_eq!(normalize_timestamp(sec, TimestampUnit::Seconds), t);
+        assert_eq!(normalize_timestamp(msec, TimestampUnit::Milliseconds), t);
+        assert_eq!(normalize_timestamp(usec, TimestampUnit::Microseconds), t);
+
+        assert_eq!(auto_timestamp(sec), t);
+        assert_eq!(auto_timestamp(msec), t);
+        assert_eq!(auto_timestamp(usec), t);
+
+        // msの端数は残る
+        assert_eq!(auto_timestamp(1724371200_123), t + 123_000);
+        assert_eq!(auto_timestamp(0), 0);
+    }
     #[test]
     fn test_floor() {
         assert_eq!(
@@ -221,6 +376,31 @@ mod time_test {
         assert_eq!(short_time_string(0), "1970-01-01T00:00:00");
     }
 
+    #[test]
+    fn test_local_timezone() {
+        let now = NOW();
+        let utc = time_string(now + SEC(9 * 3600));
+
+        set_local_timezone(9 * 3600);
+        assert_eq!(get_local_timezone(), 9 * 3600);
+        assert_eq!(time_string(now), utc);
+        assert_eq!(short_time_string(0), "1970-01-01T09:00:00");
+        assert_eq!(hour_string(0), "09");
+        // JSTの9:00はUTCの0:00
+        assert_eq!(LOCAL_HHMM(9, 0), 0);
+        assert_eq!(FLOOR_DAY(now) + LOCAL_HHMM(10, 30), FLOOR_DAY(now) + SEC(90 * 60));
+        // HHMMは常にUTC
+        assert_eq!(HHMM(9, 0), SEC(9 * 3600));
+
+        // 別スレッドはUTCのまま
+        std::thread::spawn(|| assert_eq!(time_string(0), "1970-01-01T00:00:00.000000"))
+            .join()
+            .unwrap();
+
+        set_local_timezone(0);
+        assert_eq!(short_time_string(0), "1970-01-01T00:00:00");
+    }
+
     // https://rust-lang-nursery.github.io/rust-cookbook/datetime/parse.html
     #[test]
     fn test_parse_time() {
@@ -231,6 +411,21 @@ mod time_test {
         assert_eq!(1_000_001, parse_time("1970-01-01T00:00:01.000001+00:00"));
     }
 
+    #[test]
+    fn test_parse_iso_time() -> anyhow::Result<()> {
+        let day = parse_time("2024-08-23T00:00:00.000000+00:00");
+
+        assert_eq!(parse_iso_time("2024-08-23")?, day);
+        assert_eq!(parse_iso_time("20240823")?, day);
+        assert_eq!(parse_iso_time("2024-08-23T01:00:00")?, day + HHMM(1, 0));
+        assert_eq!(parse_iso_time("2024-08-23T09:00:00+09:00")?, day);
+        assert_eq!(parse_iso_time("2024-08-23T00:00:00.000001Z")?, day + 1);
+
+        assert!(parse_iso_time("23/08/2024").is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_days() {
         assert_eq!(DAYS(1), parse_time("1970-01-02T00:00:00.000000+00:00"));
@@ -279,4 +474,15 @@ mod time_test {
         assert_eq!(mm, 1);
         assert_eq!(dd, 1);
     }
+
+    #[test]
+    fn test_server_time_offset() {
+        assert_eq!(get_server_time_offset("TEST_EXCHANGE"), 0);
+
+        set_server_time_offset("TEST_EXCHANGE", -SEC(2));
+        assert_eq!(get_server_time_offset("TEST_EXCHANGE"), -SEC(2));
+
+        let t = SERVER_NOW("TEST_EXCHANGE");
+        assert!(NOW() - SEC(3) < t && t < NOW() - SEC(1));
+    }
 }
@@ -12,7 +12,7 @@ use serde::{de, Deserialize as _, Deserializer, Serialize, Serializer};
 use serde_derive::Deserialize;
 use serde_json::Value;
 use sha2::Sha256;
-use std::{fmt, io::Write, ops::Deref};
+use std::{fmt, io::Write, ops::Deref, str::FromStr};
 use anyhow::anyhow;
 
 use super::env_rbot_db_root;
@@ -111,12 +111,35 @@ where
         return Ok(Decimal::from_f64(0.0).unwrap());
     }
 
+    // f64を経由すると精度が落ちるので、まずDecimalとして直接パースする
+    if let Ok(num) = Decimal::from_str(&s) {
+        return Ok(num);
+    }
+
+    // "1e-8"などの指数表記はf64経由で変換する
     match s.parse::<f64>() {
-        Ok(num) => Ok(Decimal::from_f64(num).unwrap()),
+        Ok(num) => Decimal::from_f64(num)
+            .ok_or_else(|| de::Error::custom(format!("Failed to convert into Decimal {}", s))),
         Err(_) => Err(de::Error::custom(format!("Failed to parse f64 {}", s))),
     }
 }
 
+/// 項目が無い、または""の場合はNone(0と区別する)
+pub fn string_to_decimal_opt<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = Option::<String>::deserialize(deserializer)?;
+
+    match s {
+        Some(s) if s != "" => {
+            let num = string_to_decimal(de::IntoDeserializer::<D::Error>::into_deserializer(s))?;
+            Ok(Some(num))
+        }
+        _ => Ok(None),
+    }
+}
+
 pub fn string_to_i64<'de, D>(deserializer: D) -> Result<i64, D::Error>
 where
     D: Deserializer<'de>,
@@ -188,7 +211,36 @@ pub fn format_number(num: i64) -> String {
 
 #[cfg(test)]
 mod test_utils {
-    use crate::common::format_number;
+    use crate::common::{format_number, string_to_decimal};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize)]
+    struct DecimalValue {
+        #[serde(deserialize_with = "string_to_decimal")]
+        value: Decimal,
+    }
+
+    fn parse_decimal(s: &str) -> Decimal {
+        let json = format!(r#"{{"value": "{}"}}"#, s);
+        serde_json::from_str::<DecimalValue>(&json).unwrap().value
+    }
+
+    #[test]
+    fn test_string_to_decimal() {
+        // f64を経由しないので桁落ちしない
+        assert_eq!(parse_decimal("29741.123456789"), dec![29741.123456789]);
+        assert_eq!(parse_decimal("29741.123456789").to_string(), "29741.123456789");
+        assert_eq!(parse_decimal("0.00000001"), dec![0.00000001]);
+        assert_eq!(parse_decimal("-12.50"), dec![-12.50]);
+        assert_eq!(parse_decimal(""), dec![0.0]);
+        // 指数表記はf64にフォールバック
+        assert_eq!(parse_decimal("1e-5"), dec![0.00001]);
+
+        let r = serde_json::from_str::<DecimalValue>(r#"{"value": "abc"}"#);
+        assert!(r.is_err());
+    }
 
     #[test]
     fn test_format_number() {
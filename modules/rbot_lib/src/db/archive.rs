@@ -1,20 +1,19 @@
 use crate::{
     common::{
-        date_string, parse_date, time_string, MarketConfig, MicroSec, OrderSide, PyFileBar, Trade,
-        DAYS, FLOOR_DAY, MIN, NOW, TODAY,
+        date_string, parse_date, time_string, MarketConfig, MarketError, MicroSec, OrderSide, ProgressCallback,
+        MarketMessage, PyFileBar, TimeChunk, emit_progress_json, Trade, DAYS, FLOOR_DAY, MIN, NOW, TODAY,
     },
-    db::{append_df, csv_to_df, df_to_parquet, parquet_to_df, KEY},
-    net::{check_exist, RestApi},
+    db::{append_df, avro_to_df, csv_to_df, df_to_avro, df_to_parquet, parquet_to_df, AvroTradeReader, TradeDb, KEY},
+    net::{check_exist, http_client, is_timeout_error, RestApi},
 };
 use anyhow::{anyhow, Context};
 use arrow::temporal_conversions::MICROSECONDS;
 use futures::StreamExt;
 use parquet::{file::reader::SerializedFileReader, record::RowAccessor};
-use reqwest::Client;
 use rust_decimal::{prelude::FromPrimitive, Decimal};
 use tokio::io::{AsyncWriteExt as _, BufWriter};
 // Import the `anyhow` crate and the `Result` type.
-use super::{db_path_root, select_df_lazy};
+use super::{db_path_root, select_df_lazy, spawn_timed_trade_reader, spawn_trade_reader, TRADE_STREAM_CHANNEL_SIZE};
 use polars::lazy::{
     dsl::{col, lit},
     frame::IntoLazy,
@@ -30,7 +29,28 @@ use std::{
 };
 use tempfile::tempdir;
 
-const EXTENSION: &str = "parquet";
+/// ローカルに保存するアーカイブファイルの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Parquet,
+    /// db::avroの固定スキーマ(tick_directionは保存されない)
+    Avro,
+}
+
+impl Default for ArchiveFormat {
+    fn default() -> Self {
+        ArchiveFormat::Parquet
+    }
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Parquet => "parquet",
+            ArchiveFormat::Avro => "avro",
+        }
+    }
+}
 
 const ARCHIVE_CHECK_INTERVAL: MicroSec = 10 * 60 * MICROSECONDS;
 
@@ -53,6 +73,7 @@ const ARCHIVE_CHECK_INTERVAL: MicroSec = 10 * 60 * MICROSECONDS;
 pub struct TradeArchive {
     config: MarketConfig,
     production: bool,
+    format: ArchiveFormat,
     last_archive_check_time: MicroSec,
     latest_archive_date: MicroSec,
     start_time: MicroSec,
@@ -66,6 +87,7 @@ impl Clone for TradeArchive {
         let mut archive = Self {
             config: self.config.clone(),
             production: self.production.clone(),
+            format: self.format,
             last_archive_check_time: self.last_archive_check_time.clone(),
             latest_archive_date: self.latest_archive_date.clone(),
             start_time: self.start_time.clone(),
@@ -87,6 +109,7 @@ impl TradeArchive {
         let mut my = Self {
             config: config.clone(),
             production: production,
+            format: ArchiveFormat::default(),
             last_archive_check_time: 0,
             latest_archive_date: 0,
             start_time: 0,
@@ -113,6 +136,16 @@ impl TradeArchive {
         self.start_time
     }
 
+    pub fn get_format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    /// 保存形式を切り替える。形式ごとにファイルが別なので、切り替え後に期間を調べ直す。
+    pub fn set_format(&mut self, format: ArchiveFormat) -> anyhow::Result<()> {
+        self.format = format;
+        self.analyze()
+    }
+
     pub fn end_time(&mut self) -> MicroSec {
         let _ = self.update_end_time();
         self.end_time
@@ -128,66 +161,135 @@ impl TradeArchive {
     }
 
     /// download historical data from the web and store csv in the Archive directory
+    /// if `progress` is given, progress is reported to it instead of the console bar.
     pub async fn download<T>(
         &mut self,
         api: &T,
         ndays: i64,
         force: bool,
         verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
     ) -> anyhow::Result<i64>
     where
         T: RestApi,
     {
-        let mut date = FLOOR_DAY(NOW());
-        let mut bar = PyFileBar::new();
+        let today = FLOOR_DAY(NOW());
+        let days: Vec<MicroSec> = (0..ndays).map(|i| today - DAYS(i)).collect();
 
-        if verbose {
-            bar.print(&format!(
+        if verbose && progress.is_none() {
+            PyFileBar::new().print(&format!(
                 "downloading web archvie from [{}]days before. force=[{}]",
                 ndays, force
             ));
         }
 
-        let mut count = 0;
-        let mut total_files = -1;
+        self.download_days(api, &days, force, verbose, progress).await
+    }
+
+    /// download archives of the days between start_time and end_time(exclusive).
+    pub async fn download_range<T>(
+        &mut self,
+        api: &T,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        force: bool,
+        verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
+    ) -> anyhow::Result<i64>
+    where
+        T: RestApi,
+    {
+        if end_time <= start_time {
+            return Err(anyhow!(
+                "invalid range {} - {}",
+                time_string(start_time),
+                time_string(end_time)
+            ));
+        }
+
+        let chunk = TimeChunk {
+            start: start_time,
+            end: end_time,
+        };
+        let mut days = TradeDb::time_chunks_to_days(&vec![chunk]);
+        days.reverse(); // same order as download(newest first)
+
+        if verbose && progress.is_none() {
+            PyFileBar::new().print(&format!(
+                "downloading web archvie from [{}] to [{}]. force=[{}]",
+                date_string(start_time),
+                date_string(end_time),
+                force
+            ));
+        }
+
+        self.download_days(api, &days, force, verbose, progress).await
+    }
 
-        for i in 0..ndays {
+    async fn download_days<T>(
+        &mut self,
+        api: &T,
+        days: &Vec<MicroSec>,
+        force: bool,
+        verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
+    ) -> anyhow::Result<i64>
+    where
+        T: RestApi,
+    {
+        let verbose = verbose && progress.is_none();
+
+        // 取得済み・未公開の日を除いた件数を進捗の全体とする
+        let mut download_days = vec![];
+        for &date in days.iter() {
             if force
                 || (!self.has_local_archive(date) && date < self.latest_archive_date(api).await?)
             {
-                if total_files == -1 {
-                    total_files = ndays - i;
+                download_days.push(date);
+            }
+        }
+        let total_files = download_days.len() as i64;
 
-                    if verbose {
-                        bar.init(total_files, true, true);
-                        bar.set_total_files(total_files);
-                    }
-                }
+        let mut bar = PyFileBar::new();
+        if verbose && 0 < total_files {
+            bar.init(total_files, true, true);
+            bar.set_total_files(total_files);
+        }
 
-                let url = api.history_web_url(&self.config, date);
-                bar.next_file(&url, 10_000);
-                bar.print(&url);
+        let mut count = 0;
+        let mut done_files: usize = 0;
 
-                let mut file_size = 0;
+        for date in download_days {
+            let url = api.history_web_url(&self.config, date);
+            bar.next_file(&url, 10_000);
+            bar.print(&url);
 
-                count += self
-                    .web_archive_to_parquet(api, date, force, verbose, |count, content_len| {
-                        if verbose {
-                            if file_size == 0 {
-                                bar.set_file_size(content_len);
-                            }
-                            file_size = content_len;
+            if let Some(p) = &progress {
+                p.on_file_start(&url);
+            }
 
-                            bar.set_file_progress(count);
+            let mut file_size = 0;
+
+            let file_count = self
+                .web_archive_to_parquet(api, date, force, verbose, |count, content_len| {
+                    if verbose {
+                        if file_size == 0 {
+                            bar.set_file_size(content_len);
                         }
-                    })
-                    .await?;
-            } else {
-                if verbose {
-                    // text_bar.set_message(format!("skip download [{}]", date_time_string(date)));
-                }
+                        file_size = content_len;
+
+                        bar.set_file_progress(count);
+                    }
+                })
+                .await?;
+            count += file_count;
+            done_files += 1;
+
+            if let Some(p) = &progress {
+                p.on_file_done(&url, file_count);
+                p.on_total_progress(done_files, total_files as usize);
             }
-            date -= DAYS(1);
+            emit_progress_json(done_files as i64, total_files, file_count);
         }
 
         self.analyze()?;
@@ -253,13 +355,16 @@ impl TradeArchive {
 
     /// generate 0 row empty archive(stored in disk) df
     pub fn load_archive_df(&self, date: MicroSec) -> anyhow::Result<DataFrame> {
-        let parquet_file = self.file_path(date);
+        let archive_file = self.file_path(date);
         log::debug!(
             "read archive file into DataFrame {} ({})",
             date_string(date),
             date
         );
-        let df = parquet_to_df(&parquet_file)?;
+        let df = match self.format {
+            ArchiveFormat::Parquet => parquet_to_df(&archive_file)?,
+            ArchiveFormat::Avro => avro_to_df(&archive_file)?,
+        };
 
         Ok(df)
     }
@@ -287,6 +392,29 @@ impl TradeArchive {
         Ok(df)
     }
 
+    /// size >= min_sizeの約定のみを返す。日ごとに絞り込んでから結合するのでメモリを節約できる。
+    pub fn fetch_large_trades_df(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: f64,
+    ) -> anyhow::Result<DataFrame> {
+        let dates = self.select_dates(start_time, end_time)?;
+
+        let mut df = Self::make_empty_cachedf();
+
+        for date in dates {
+            let new_df = self.load_cache_df(date)?;
+            let new_df = select_df_lazy(&new_df, start_time, end_time)
+                .filter(col(KEY::size).gt_eq(lit(min_size)))
+                .collect()?;
+
+            df = append_df(&df, &new_df)?;
+        }
+
+        Ok(df)
+    }
+
     /// load from parquet file and retrive as cachedf.
     pub fn load_cache_df(&mut self, date: MicroSec) -> anyhow::Result<DataFrame> {
         let date = FLOOR_DAY(date);
@@ -339,6 +467,36 @@ impl TradeArchive {
         Ok(count)
     }
 
+    /// foreachの結果をbuffer_sizeのchannelへ流す
+    pub fn stream_to_channel(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        buffer_size: usize,
+    ) -> tokio::sync::mpsc::Receiver<MarketMessage> {
+        let mut archive = self.clone();
+
+        spawn_trade_reader(buffer_size, move |send| {
+            archive.foreach(start_time, end_time, &mut |trade| send(trade))?;
+            Ok(())
+        })
+    }
+
+    /// stream_to_channelと同じだが、Tradeの時刻間隔をspeedで割った実時間をあけて流す(0は待たない)
+    pub fn select_stream_timed(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        speed: f64,
+    ) -> tokio::sync::mpsc::Receiver<MarketMessage> {
+        let mut archive = self.clone();
+
+        spawn_timed_trade_reader(TRADE_STREAM_CHANNEL_SIZE, speed, move |send| {
+            archive.foreach(start_time, end_time, &mut |trade| send(trade))?;
+            Ok(())
+        })
+    }
+
     /// execite f for each rec(trade) specifed a date.
     pub fn foreach_paquet<F>(&self, date: MicroSec, f: &mut F) -> anyhow::Result<i64>
     where
@@ -347,6 +505,16 @@ impl TradeArchive {
         let mut count = 0;
 
         let file = self.file_path(date);
+
+        if self.format == ArchiveFormat::Avro {
+            for trade in AvroTradeReader::read_trades(&file)? {
+                f(&trade)?;
+                count += 1;
+            }
+
+            return Ok(count);
+        }
+
         let file = File::open(file)?;
         let reader = SerializedFileReader::new(file)?;
 
@@ -510,7 +678,7 @@ impl TradeArchive {
                     let ent = ent.path();
                     let path = ent.file_name().unwrap();
                     let path_str = path.to_str().unwrap();
-                    if path_str.ends_with(EXTENSION) {
+                    if path_str.ends_with(self.format.extension()) {
                         log::debug!("entry= {:?}", path_str);
                         if let Ok(date) = self.file_date(&PathBuf::from_str(&path_str)?) {
                             dates.push(date);
@@ -547,7 +715,12 @@ impl TradeArchive {
         let date = FLOOR_DAY(date);
         let date = date_string(date);
 
-        let archive_name = format!("{}-{}.{}", self.config.trade_symbol, date, EXTENSION);
+        let archive_name = format!(
+            "{}-{}.{}",
+            self.config.trade_symbol,
+            date,
+            self.format.extension()
+        );
 
         let archive_path = archive_directory.join(archive_name);
 
@@ -603,9 +776,23 @@ impl TradeArchive {
             log::debug!("force download");
         }
 
-        let parquet_file = self.file_path(date);
+        let archive_file = self.file_path(date);
 
-        api.web_archive_to_parquet::<F>(&self.config, &parquet_file, date, f).await
+        match self.format {
+            ArchiveFormat::Parquet => {
+                api.web_archive_to_parquet::<F>(&self.config, &archive_file, date, f).await
+            }
+            ArchiveFormat::Avro => {
+                // 取引所ごとの変換はparquetまでなので、一時ファイルを経由してAvroにする
+                let tmp_dir = tempdir().with_context(|| "create tmp dir error")?;
+                let parquet_file = tmp_dir.path().join("archive.parquet");
+
+                api.web_archive_to_parquet::<F>(&self.config, &parquet_file, date, f).await?;
+
+                let df = parquet_to_df(&parquet_file)?;
+                df_to_avro(&df, &archive_file)
+            }
+        }
 
 
         /*
@@ -645,6 +832,10 @@ impl TradeArchive {
 
 const BUFFER_SIZE: usize = 8 * 1024 * 1024;
 
+/// タイムアウトしたダウンロードをやり直す回数
+pub const DOWNLOAD_RETRY: usize = 2;
+
+/// urlをtmp_dirにダウンロードする。タイムアウトした場合はDOWNLOAD_RETRY回までやり直す。
 pub async fn log_download_tmp<F>(
     url: &str,
     tmp_dir: &Path,
@@ -653,7 +844,34 @@ pub async fn log_download_tmp<F>(
 where
     F: FnMut(i64, i64),
 {
-    let client = Client::new();
+    let mut retry = 0;
+
+    loop {
+        match download_tmp(url, tmp_dir, &mut progress).await {
+            Ok(path) => return Ok(path),
+            Err(e) if retry < DOWNLOAD_RETRY && is_timeout_error(&e) => {
+                retry += 1;
+                log::warn!("download timeout, retry({}/{}) {}: {:?}", retry, DOWNLOAD_RETRY, url, e);
+            }
+            Err(e) => {
+                if is_timeout_error(&e) {
+                    return Err(anyhow::Error::new(MarketError::Network(format!("timeout: {}", url))).context(e));
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+async fn download_tmp<F>(
+    url: &str,
+    tmp_dir: &Path,
+    progress: &mut F,
+) -> anyhow::Result<PathBuf>
+where
+    F: FnMut(i64, i64),
+{
+    let client = http_client()?;
 
     let response = client
         .get(url)
@@ -730,11 +948,237 @@ where
 
 #[cfg(test)]
 mod archive_test {
-    use std::{path::PathBuf, str::FromStr};
+    use std::{
+        path::PathBuf,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
+
+    use anyhow::anyhow;
+    use polars::df;
+    use polars::prelude::{DataFrame, NamedFrom};
+    use rust_decimal::Decimal;
+
+    use crate::{
+        common::{
+            init_debug_log, AccountCoins, ExchangeConfig, Kline, MarketConfig, MicroSec, Order,
+            OrderSide, OrderType, ProgressCallback, TimeInForce, DAYS, FLOOR_DAY, NOW,
+        },
+        db::{db_path_root, df_to_avro, df_to_parquet, TradeArchive, KEY},
+        net::{
+            mock::{request_line, MockHttpServer},
+            RestApi, RestPage,
+        },
+    };
+
+    use super::{log_download_tmp, ArchiveFormat};
+
+    /// `{url}/{date}.csv`のアーカイブをモックサーバから取得するRestApi
+    struct MockArchiveApi {
+        url: String,
+    }
 
-    use crate::{common::{init_debug_log, NOW}, db::TradeArchive};
+    impl RestApi for MockArchiveApi {
+        fn get_exchange(&self) -> ExchangeConfig {
+            ExchangeConfig::new("TEST", false, "", "", "", "", "")
+        }
 
-    use super::log_download_tmp;
+        async fn get_klines(
+            &self,
+            _config: &MarketConfig,
+            _start_time: MicroSec,
+            _end_time: MicroSec,
+            _page: &RestPage,
+        ) -> anyhow::Result<(Vec<Kline>, RestPage)> {
+            Err(anyhow!("not supported"))
+        }
+
+        fn klines_width(&self) -> i64 {
+            60
+        }
+
+        async fn new_order(
+            &self,
+            _config: &MarketConfig,
+            _side: OrderSide,
+            _price: Decimal,
+            _size: Decimal,
+            _order_type: OrderType,
+            _client_order_id: Option<&str>,
+            _time_in_force: TimeInForce,
+            _reduce_only: bool,
+        ) -> anyhow::Result<Vec<Order>> {
+            Err(anyhow!("not supported"))
+        }
+
+        async fn cancel_order(&self, _config: &MarketConfig, _order_id: &str) -> anyhow::Result<Order> {
+            Err(anyhow!("not supported"))
+        }
+
+        async fn open_orders(&self, _config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
+            Err(anyhow!("not supported"))
+        }
+
+        async fn get_account(&self) -> anyhow::Result<AccountCoins> {
+            Err(anyhow!("not supported"))
+        }
+
+        fn history_web_url(&self, _config: &MarketConfig, date: MicroSec) -> String {
+            format!("{}/{}.csv", self.url, date)
+        }
+
+        fn logdf_to_archivedf(&self, df: &DataFrame) -> anyhow::Result<DataFrame> {
+            Ok(df.clone())
+        }
+    }
+
+    struct RecordingCallback {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ProgressCallback for RecordingCallback {
+        fn on_file_start(&self, url: &str) {
+            self.calls.lock().unwrap().push(format!("start {}", url));
+        }
+
+        fn on_file_done(&self, url: &str, _records: i64) {
+            self.calls.lock().unwrap().push(format!("done {}", url));
+        }
+
+        fn on_total_progress(&self, done: usize, total: usize) {
+            self.calls.lock().unwrap().push(format!("progress {}/{}", done, total));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_progress_callback() -> anyhow::Result<()> {
+        init_debug_log();
+
+        // "GET /{date}.csv HTTP/1.1"の日付で1行だけのcsvを返す(HEADも200)
+        let server = MockHttpServer::start(|request| {
+            let path = request_line(request).split(' ').nth(1).unwrap_or("");
+            let date: MicroSec = path
+                .trim_start_matches('/')
+                .trim_end_matches(".csv")
+                .parse()
+                .unwrap_or(0);
+            format!("timestamp,order_side,price,size,id\n{},Buy,100.0,1.0,a\n", date + 1)
+        })
+        .await;
+        let api = MockArchiveApi { url: server.url() };
+
+        let mut config = MarketConfig::default();
+        config.exchange_name = "TEST".to_string();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = format!("PROGRESS{}", std::process::id());
+
+        let root = db_path_root(&config.exchange_name, &config.trade_category, &config.trade_symbol, false);
+
+        let today = FLOOR_DAY(NOW());
+
+        let result = async {
+            let mut archive = TradeArchive::new(&config, false);
+
+            // 昨日分は取得済み
+            let mut local_df = df![
+                KEY::timestamp => [today - DAYS(1) + 1],
+                KEY::order_side => ["Buy"],
+                KEY::price => [100.0],
+                KEY::size => [1.0],
+                KEY::id => ["a"]
+            ]?;
+            df_to_parquet(&mut local_df, &archive.file_path(today - DAYS(1)))?;
+
+            let calls = Arc::new(Mutex::new(vec![]));
+            let callback = RecordingCallback {
+                calls: calls.clone(),
+            };
+
+            archive
+                .download(&api, 4, false, false, Some(Box::new(callback)))
+                .await?;
+
+            // 当日(未公開)と取得済みの昨日は全体の件数に含めない
+            let url2 = api.history_web_url(&config, today - DAYS(2));
+            let url3 = api.history_web_url(&config, today - DAYS(3));
+
+            let calls = calls.lock().unwrap().clone();
+            assert_eq!(
+                calls,
+                vec![
+                    format!("start {}", url2),
+                    format!("done {}", url2),
+                    "progress 1/2".to_string(),
+                    format!("start {}", url3),
+                    format!("done {}", url3),
+                    "progress 2/2".to_string(),
+                ]
+            );
+
+            assert!(archive.has_local_archive(today - DAYS(2)));
+            assert!(archive.has_local_archive(today - DAYS(3)));
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        let _ = std::fs::remove_dir_all(&root);
+
+        result
+    }
+
+    #[test]
+    fn test_avro_archive_format() -> anyhow::Result<()> {
+        let mut config = MarketConfig::default();
+        config.exchange_name = "TEST".to_string();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = format!("AVRO{}", std::process::id());
+
+        let root = db_path_root(&config.exchange_name, &config.trade_category, &config.trade_symbol, false);
+
+        let day = DAYS(19000);
+        let archive_df = df![
+            KEY::timestamp => [day + 1, day + 2],
+            KEY::order_side => ["Buy", "Sell"],
+            KEY::price => [100.0, 101.0],
+            KEY::size => [1.0, 2.0],
+            KEY::id => ["a", "b"]
+        ]?;
+
+        let result = (|| -> anyhow::Result<()> {
+            let mut archive = TradeArchive::new(&config, false);
+            assert_eq!(archive.get_format(), ArchiveFormat::Parquet);
+
+            archive.set_format(ArchiveFormat::Avro)?;
+            let path = archive.file_path(day);
+            assert_eq!(path.extension().unwrap(), "avro");
+            df_to_avro(&archive_df, &path)?;
+
+            archive.set_format(ArchiveFormat::Avro)?;
+            assert_eq!(archive.list_dates()?, vec![day]);
+            assert_eq!(archive.start_time(), day);
+
+            assert_eq!(archive.load_archive_df(day)?, archive_df);
+
+            let mut ids = vec![];
+            let count = archive.foreach(day, day + DAYS(1), &mut |trade| {
+                ids.push(trade.id.clone());
+                Ok(())
+            })?;
+            assert_eq!(count, 2);
+            assert_eq!(ids, vec!["a", "b"]);
+
+            // parquetのアーカイブとしては見えない
+            archive.set_format(ArchiveFormat::Parquet)?;
+            assert!(archive.list_dates()?.is_empty());
+
+            Ok(())
+        })();
+
+        let _ = std::fs::remove_dir_all(&root);
+
+        result
+    }
 
     #[tokio::test]
     async fn test_download() -> anyhow::Result<()> {
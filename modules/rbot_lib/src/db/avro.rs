@@ -0,0 +1,256 @@
+// Copyright(c) 2022-2024. yasstake. All rights reserved.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use anyhow::anyhow;
+use apache_avro::{from_value, Reader, Schema, Writer};
+use once_cell::sync::Lazy;
+use polars::prelude::{DataFrame, NamedFrom};
+use polars::series::Series;
+use rust_decimal::{prelude::FromPrimitive, prelude::ToPrimitive, Decimal};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::common::{LogStatus, MicroSec, OrderSide, Trade};
+use crate::db::KEY;
+
+const TRADE_SCHEMA: &str = r#"
+{
+    "type": "record",
+    "name": "trade",
+    "fields": [
+        {"name": "time_stamp", "type": "long"},
+        {"name": "order_side", "type": "string"},
+        {"name": "price", "type": "double"},
+        {"name": "size", "type": "double"},
+        {"name": "status", "type": "string"},
+        {"name": "id", "type": "string"}
+    ]
+}
+"#;
+
+static AVRO_TRADE_SCHEMA: Lazy<Schema> = Lazy::new(|| Schema::parse_str(TRADE_SCHEMA).unwrap());
+
+/// Avroファイルに保存する1レコード（Tradeと1:1対応）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AvroTrade {
+    time_stamp: i64,
+    order_side: String,
+    price: f64,
+    size: f64,
+    status: String,
+    id: String,
+}
+
+impl From<&Trade> for AvroTrade {
+    fn from(trade: &Trade) -> Self {
+        AvroTrade {
+            time_stamp: trade.time,
+            order_side: trade.order_side.to_string(),
+            price: trade.price.to_f64().unwrap_or_default(),
+            size: trade.size.to_f64().unwrap_or_default(),
+            status: trade.status.to_string(),
+            id: trade.id.clone(),
+        }
+    }
+}
+
+impl AvroTrade {
+    fn to_trade(&self) -> anyhow::Result<Trade> {
+        let price = Decimal::from_f64(self.price)
+            .ok_or_else(|| anyhow!("invalid price {}", self.price))?;
+        let size =
+            Decimal::from_f64(self.size).ok_or_else(|| anyhow!("invalid size {}", self.size))?;
+
+        Ok(Trade::new(
+            self.time_stamp,
+            OrderSide::from(self.order_side.as_str()),
+            price,
+            size,
+            LogStatus::from(self.status.as_str()),
+            &self.id,
+        ))
+    }
+}
+
+/// TradeをAvro形式で書き出す。
+/// ファイルはclose()またはdrop時にflushされる。
+pub struct AvroTradeWriter {
+    writer: Writer<'static, BufWriter<File>>,
+}
+
+impl AvroTradeWriter {
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let file = File::create(path.as_ref())?;
+        let writer = Writer::new(&AVRO_TRADE_SCHEMA, BufWriter::new(file));
+
+        Ok(Self { writer })
+    }
+
+    pub fn write_trades(&mut self, trades: &[Trade]) -> anyhow::Result<usize> {
+        for trade in trades {
+            self.writer.append_ser(AvroTrade::from(trade))?;
+        }
+        self.writer.flush()?;
+
+        Ok(trades.len())
+    }
+
+    pub fn close(self) -> anyhow::Result<()> {
+        let mut inner = self.writer.into_inner()?;
+        std::io::Write::flush(&mut inner)?;
+
+        Ok(())
+    }
+}
+
+pub struct AvroTradeReader {}
+
+impl AvroTradeReader {
+    pub fn read_trades<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Trade>> {
+        let file = File::open(path.as_ref())?;
+        let reader = Reader::with_schema(&AVRO_TRADE_SCHEMA, BufReader::new(file))?;
+
+        let mut trades: Vec<Trade> = vec![];
+        for value in reader {
+            let rec: AvroTrade = from_value(&value?)?;
+            trades.push(rec.to_trade()?);
+        }
+
+        Ok(trades)
+    }
+}
+
+/// archive形式のDataFrame(timestamp, order_side, price, size, id)をAvroファイルへ書き出す。
+/// スキーマは固定のため、tick_directionなどそれ以外の列は保存しない。
+pub fn df_to_avro<P: AsRef<Path>>(df: &DataFrame, path: P) -> anyhow::Result<i64> {
+    let timestamp = df.column(KEY::timestamp)?.i64()?;
+    let order_side = df.column(KEY::order_side)?.str()?;
+    let price = df.column(KEY::price)?.f64()?;
+    let size = df.column(KEY::size)?.f64()?;
+    let id = df.column(KEY::id)?.str()?;
+
+    let file = File::create(path.as_ref())?;
+    let mut writer = Writer::new(&AVRO_TRADE_SCHEMA, BufWriter::new(file));
+
+    let status = LogStatus::FixArchiveBlock.to_string();
+
+    let rows = timestamp
+        .into_iter()
+        .zip(order_side.into_iter())
+        .zip(price.into_iter())
+        .zip(size.into_iter())
+        .zip(id.into_iter());
+
+    for ((((timestamp, order_side), price), size), id) in rows {
+        writer.append_ser(AvroTrade {
+            time_stamp: timestamp.unwrap_or_default(),
+            order_side: order_side.unwrap_or_default().to_string(),
+            price: price.unwrap_or_default(),
+            size: size.unwrap_or_default(),
+            status: status.clone(),
+            id: id.unwrap_or_default().to_string(),
+        })?;
+    }
+
+    let mut inner = writer.into_inner()?;
+    std::io::Write::flush(&mut inner)?;
+
+    Ok(df.height() as i64)
+}
+
+/// df_to_avroで書き出したファイルをarchive形式のDataFrameとして読み込む。
+pub fn avro_to_df<P: AsRef<Path>>(path: P) -> anyhow::Result<DataFrame> {
+    let file = File::open(path.as_ref())?;
+    let reader = Reader::with_schema(&AVRO_TRADE_SCHEMA, BufReader::new(file))?;
+
+    let mut timestamp: Vec<MicroSec> = vec![];
+    let mut order_side: Vec<String> = vec![];
+    let mut price: Vec<f64> = vec![];
+    let mut size: Vec<f64> = vec![];
+    let mut id: Vec<String> = vec![];
+
+    for value in reader {
+        let rec: AvroTrade = from_value(&value?)?;
+
+        timestamp.push(rec.time_stamp);
+        order_side.push(rec.order_side);
+        price.push(rec.price);
+        size.push(rec.size);
+        id.push(rec.id);
+    }
+
+    let df = DataFrame::new(vec![
+        Series::new(KEY::timestamp, timestamp),
+        Series::new(KEY::order_side, order_side),
+        Series::new(KEY::price, price),
+        Series::new(KEY::size, size),
+        Series::new(KEY::id, id),
+    ])?;
+
+    Ok(df)
+}
+
+#[cfg(test)]
+mod avro_test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trades.avro");
+
+        let trades: Vec<Trade> = (0..1000)
+            .map(|i| {
+                Trade::new(
+                    1_700_000_000_000_000 + i,
+                    if i % 2 == 0 { OrderSide::Buy } else { OrderSide::Sell },
+                    dec![25000.5] + Decimal::from(i),
+                    dec![0.001],
+                    if i % 3 == 0 { LogStatus::FixArchiveBlock } else { LogStatus::UnFix },
+                    &format!("id-{}", i),
+                )
+            })
+            .collect();
+
+        let mut writer = AvroTradeWriter::new(&path)?;
+        assert_eq!(writer.write_trades(&trades)?, 1000);
+        writer.close()?;
+
+        let read = AvroTradeReader::read_trades(&path)?;
+        assert_eq!(read, trades);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_df_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("archive.avro");
+
+        let df = DataFrame::new(vec![
+            Series::new(KEY::timestamp, vec![1_000_000i64, 2_000_000, 3_000_000]),
+            Series::new(KEY::order_side, vec!["Buy", "Sell", "Buy"]),
+            Series::new(KEY::price, vec![100.0, 100.5, 101.0]),
+            Series::new(KEY::size, vec![0.1, 0.2, 0.3]),
+            Series::new(KEY::id, vec!["a", "b", "c"]),
+        ])?;
+
+        assert_eq!(df_to_avro(&df, &path)?, 3);
+
+        let read = avro_to_df(&path)?;
+        assert_eq!(read, df);
+
+        // Tradeとしても読める(アーカイブ由来のstatusになる)
+        let trades = AvroTradeReader::read_trades(&path)?;
+        assert_eq!(trades.len(), 3);
+        assert_eq!(trades[1].order_side, OrderSide::Sell);
+        assert_eq!(trades[1].status, LogStatus::FixArchiveBlock);
+
+        Ok(())
+    }
+}
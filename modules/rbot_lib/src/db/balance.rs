@@ -0,0 +1,242 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use polars::prelude::DataFrame;
+use polars::prelude::NamedFrom;
+use polars::series::Series;
+use rusqlite::{params, params_from_iter, Connection};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+use crate::common::{AccountCoins, MicroSec};
+use crate::db::KEY;
+
+use super::{convert_timems_to_datetime, db_full_path};
+
+const BALANCE_DB_CATEGORY: &str = "account";
+const BALANCE_DB_SYMBOL: &str = "balance";
+
+/// user streamの残高通知とは別に、REST APIで残高を取り直して記録する間隔
+pub const BALANCE_SNAPSHOT_INTERVAL_SEC: u64 = 300;
+
+/// ある時刻のコインごとの残高
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceRecord {
+    pub timestamp: MicroSec,
+    pub coin: String,
+    pub equity: Decimal,
+    pub free: Decimal,
+    pub locked: Decimal,
+}
+
+/// balance_historyテーブル（取引所ごとに1ファイル）
+pub struct BalanceDb {
+    connection: Connection,
+}
+
+impl BalanceDb {
+    pub fn open(exchange_name: &str, production: bool) -> anyhow::Result<Self> {
+        let db_path = db_full_path(exchange_name, BALANCE_DB_CATEGORY, BALANCE_DB_SYMBOL, production);
+
+        Self::open_path(&db_path)
+    }
+
+    pub fn open_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("balance db open error {:?}", path))?;
+
+        let db = Self { connection };
+        db.create_table_if_not_exists()?;
+
+        Ok(db)
+    }
+
+    fn create_table_if_not_exists(&self) -> anyhow::Result<()> {
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS balance_history (
+            timestamp   INTEGER,
+            coin        TEXT,
+            equity      NUMBER,
+            free        NUMBER,
+            locked      NUMBER,
+            primary key (timestamp, coin)
+        )",
+            (),
+        )?;
+
+        Ok(())
+    }
+
+    /// AccountCoinsの全コインをtimestamp時点の残高として記録する
+    pub fn insert_coins(&mut self, timestamp: MicroSec, coins: &AccountCoins) -> anyhow::Result<usize> {
+        let tx = self.connection.transaction()?;
+        let mut count = 0;
+
+        for coin in coins.coins.iter() {
+            count += tx.execute(
+                "insert or replace into balance_history (timestamp, coin, equity, free, locked)
+                    values (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    timestamp,
+                    coin.symbol,
+                    coin.volume.to_f64().unwrap_or(0.0),
+                    coin.free.to_f64().unwrap_or(0.0),
+                    coin.locked.to_f64().unwrap_or(0.0),
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(count)
+    }
+
+    /// 0以下の時刻は制限なしとして扱う
+    pub fn select(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<Vec<BalanceRecord>> {
+        let mut param: Vec<i64> = vec![];
+        let mut sql = "select timestamp, coin, equity, free, locked from balance_history".to_string();
+
+        if 0 < start_time {
+            sql += " where ?1 <= timestamp";
+            param.push(start_time);
+        }
+
+        if 0 < end_time {
+            sql += if 0 < start_time { " and" } else { " where" };
+            sql += &format!(" timestamp < ?{}", param.len() + 1);
+            param.push(end_time);
+        }
+
+        sql += " order by timestamp, coin";
+
+        let mut statement = self.connection.prepare(&sql)?;
+        let records = statement
+            .query_map(params_from_iter(param.iter()), |row| {
+                Ok(BalanceRecord {
+                    timestamp: row.get_unwrap(0),
+                    coin: row.get_unwrap(1),
+                    equity: Decimal::from_f64(row.get_unwrap(2)).unwrap_or_default(),
+                    free: Decimal::from_f64(row.get_unwrap(3)).unwrap_or_default(),
+                    locked: Decimal::from_f64(row.get_unwrap(4)).unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(records)
+    }
+
+    /// columns: timestamp, coin, equity, free, locked
+    pub fn select_df(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<DataFrame> {
+        let records = self.select(start_time, end_time)?;
+
+        let timestamp: Vec<i64> = records.iter().map(|r| r.timestamp).collect();
+        let coin: Vec<String> = records.iter().map(|r| r.coin.clone()).collect();
+        let equity: Vec<f64> = records.iter().map(|r| r.equity.to_f64().unwrap()).collect();
+        let free: Vec<f64> = records.iter().map(|r| r.free.to_f64().unwrap()).collect();
+        let locked: Vec<f64> = records.iter().map(|r| r.locked.to_f64().unwrap()).collect();
+
+        let mut df = DataFrame::new(vec![
+            Series::new(KEY::timestamp, timestamp),
+            Series::new("coin", coin),
+            Series::new("equity", equity),
+            Series::new("free", free),
+            Series::new("locked", locked),
+        ])?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(df)
+    }
+}
+
+/// 非同期タスクからbalance_historyへ記録するハンドル。
+/// rusqliteの書き込みはtokioのworkerを塞がないようspawn_blockingで行う。
+#[derive(Clone)]
+pub struct BalanceRecorder {
+    db: Arc<Mutex<BalanceDb>>,
+}
+
+impl BalanceRecorder {
+    pub fn new(db: BalanceDb) -> Self {
+        Self {
+            db: Arc::new(Mutex::new(db)),
+        }
+    }
+
+    pub fn open(exchange_name: &str, production: bool) -> anyhow::Result<Self> {
+        Ok(Self::new(BalanceDb::open(exchange_name, production)?))
+    }
+
+    pub async fn record(&self, timestamp: MicroSec, coins: AccountCoins) -> anyhow::Result<usize> {
+        let db = self.db.clone();
+
+        tokio::task::spawn_blocking(move || db.lock().unwrap().insert_coins(timestamp, &coins)).await?
+    }
+}
+
+#[cfg(test)]
+mod balance_test {
+    use super::*;
+    use crate::common::Coin;
+    use rust_decimal_macros::dec;
+
+    fn coin(symbol: &str, volume: Decimal, free: Decimal) -> Coin {
+        let mut coin = Coin::default();
+        coin.symbol = symbol.to_string();
+        coin.volume = volume;
+        coin.free = free;
+        coin.locked = volume - free;
+        coin
+    }
+
+    #[test]
+    fn test_balance_history() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("balance.db");
+        let mut db = BalanceDb::open_path(&path)?;
+
+        let mut coins = AccountCoins::new();
+        coins.push(coin("USDT", dec![1000.0], dec![900.0]));
+        coins.push(coin("BTC", dec![0.5], dec![0.5]));
+        assert_eq!(db.insert_coins(100, &coins)?, 2);
+
+        let mut coins = AccountCoins::new();
+        coins.push(coin("USDT", dec![1010.0], dec![1010.0]));
+        db.insert_coins(200, &coins)?;
+
+        let records = db.select(0, 0)?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].coin, "USDT");
+        assert_eq!(records[1].locked, dec![100.0]);
+
+        let records = db.select(150, 0)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].equity, dec![1010.0]);
+
+        let df = db.select_df(0, 0)?;
+        assert_eq!(df.shape(), (3, 5));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_balance_recorder() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("balance.db");
+        let recorder = BalanceRecorder::new(BalanceDb::open_path(&path)?);
+
+        let mut coins = AccountCoins::new();
+        coins.push(coin("USDT", dec![1000.0], dec![900.0]));
+        assert_eq!(recorder.record(100, coins.clone()).await?, 1);
+        assert_eq!(recorder.clone().record(200, coins).await?, 1);
+
+        let records = BalanceDb::open_path(&path)?.select(0, 0)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].timestamp, 200);
+
+        Ok(())
+    }
+}
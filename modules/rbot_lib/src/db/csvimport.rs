@@ -0,0 +1,187 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use csv::{ReaderBuilder, StringRecord};
+use pyo3::pyclass;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use sha2::{Digest, Sha256};
+
+use crate::common::{parse_iso_time, LogStatus, MicroSec, OrderSide, Trade};
+
+/// CSVの時刻カラムの形式
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeFormat {
+    UnixSeconds,
+    #[default]
+    UnixMilliseconds,
+    ISO8601,
+}
+
+impl TimeFormat {
+    pub fn parse(&self, value: &str) -> anyhow::Result<MicroSec> {
+        let value = value.trim();
+
+        let unit = match self {
+            TimeFormat::UnixSeconds => dec![1_000_000],
+            TimeFormat::UnixMilliseconds => dec![1_000],
+            TimeFormat::ISO8601 => return parse_iso_time(value),
+        };
+
+        // 小数点以下のある時刻(1724371200.123など)もあるのでDecimalで変換する
+        let t = Decimal::from_str(value).with_context(|| format!("invalid time {:?}", value))?;
+
+        Ok((t * unit).trunc().try_into()?)
+    }
+}
+
+/// 取引所のUIなどからダウンロードした約定履歴CSVのカラム配置
+/// side_colがNoneの場合はsizeの符号で売買を判定する(マイナスがSell)
+/// id_colがNoneの場合は時刻・価格・サイズのハッシュをIDとする
+#[derive(Debug, Clone)]
+pub struct CsvSchema {
+    pub time_col: usize,
+    pub time_format: TimeFormat,
+    pub price_col: usize,
+    pub size_col: usize,
+    pub side_col: Option<usize>,
+    pub id_col: Option<usize>,
+    pub has_header: bool,
+}
+
+impl Default for CsvSchema {
+    fn default() -> Self {
+        Self {
+            time_col: 0,
+            time_format: TimeFormat::default(),
+            price_col: 1,
+            size_col: 2,
+            side_col: None,
+            id_col: None,
+            has_header: true,
+        }
+    }
+}
+
+impl CsvSchema {
+    pub fn parse_record(&self, rec: &StringRecord) -> anyhow::Result<Trade> {
+        let time = self.time_format.parse(Self::field(rec, self.time_col)?)?;
+        let price = Self::decimal(Self::field(rec, self.price_col)?)?;
+        let mut size = Self::decimal(Self::field(rec, self.size_col)?)?;
+
+        let order_side = match self.side_col {
+            Some(col) => OrderSide::from(Self::field(rec, col)?),
+            None => OrderSide::from_buy_side(size.is_sign_positive()),
+        };
+        size = size.abs();
+
+        let id = match self.id_col {
+            Some(col) => Self::field(rec, col)?.to_string(),
+            None => synthetic_trade_id(time, price, size),
+        };
+
+        Ok(Trade::new(
+            time,
+            order_side,
+            price,
+            size,
+            LogStatus::FixArchiveBlock,
+            &id,
+        ))
+    }
+
+    fn field(rec: &StringRecord, col: usize) -> anyhow::Result<&str> {
+        rec.get(col)
+            .map(|s| s.trim())
+            .ok_or_else(|| anyhow!("column {} not found in {:?}", col, rec))
+    }
+
+    fn decimal(value: &str) -> anyhow::Result<Decimal> {
+        Decimal::from_str(value)
+            .or_else(|_| Decimal::from_scientific(value))
+            .with_context(|| format!("invalid number {:?}", value))
+    }
+}
+
+/// 時刻・価格・サイズから作るID(同じ行を2回importしても同じIDになる)
+pub fn synthetic_trade_id(time: MicroSec, price: Decimal, size: Decimal) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{},{},{}", time, price.normalize(), size.normalize()));
+
+    hex::encode(&hasher.finalize()[..8])
+}
+
+/// CSVを読み込んで時刻順に並べたTradeを返す
+pub fn read_trades_csv(path: &Path, schema: &CsvSchema) -> anyhow::Result<Vec<Trade>> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(schema.has_header)
+        .flexible(true)
+        .from_path(path)
+        .with_context(|| format!("open csv error {:?}", path))?;
+
+    let mut trades = vec![];
+
+    for (i, rec) in reader.records().enumerate() {
+        let rec = rec?;
+        let trade = schema
+            .parse_record(&rec)
+            .with_context(|| format!("{:?} line {}", path, i + 1))?;
+
+        trades.push(trade);
+    }
+
+    trades.sort_by(|a, b| a.time.cmp(&b.time));
+
+    Ok(trades)
+}
+
+#[cfg(test)]
+mod csvimport_test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_time_format() -> anyhow::Result<()> {
+        assert_eq!(TimeFormat::UnixSeconds.parse("1724371200")?, 1724371200_000_000);
+        assert_eq!(TimeFormat::UnixSeconds.parse("1724371200.5")?, 1724371200_500_000);
+        assert_eq!(TimeFormat::UnixMilliseconds.parse("1724371200123")?, 1724371200_123_000);
+        assert_eq!(
+            TimeFormat::ISO8601.parse("2024-08-23T00:00:00Z")?,
+            1724371200_000_000
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_trades_csv() -> anyhow::Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "time,price,size")?;
+        writeln!(file, "1724371201000,60001.5,-0.2")?;
+        writeln!(file, "1724371200000,60000,0.1")?;
+
+        let trades = read_trades_csv(file.path(), &CsvSchema::default())?;
+        assert_eq!(trades.len(), 2);
+
+        assert_eq!(trades[0].time, 1724371200_000_000);
+        assert_eq!(trades[0].order_side, OrderSide::Buy);
+        assert_eq!(trades[0].price, dec![60000]);
+        assert_eq!(trades[0].size, dec![0.1]);
+
+        assert_eq!(trades[1].order_side, OrderSide::Sell);
+        assert_eq!(trades[1].size, dec![0.2]);
+
+        assert_eq!(
+            trades[0].id,
+            synthetic_trade_id(1724371200_000_000, dec![60000], dec![0.1])
+        );
+        assert_ne!(trades[0].id, trades[1].id);
+
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@ use std::io::{BufReader, Cursor, Read};
 use std::path::{Path, PathBuf};
 
 use crate::common::{OrderSide, Trade};
-use crate::common::{time_string, MicroSec, SEC};
+use crate::common::{time_string, MicroSec, FLOOR_SEC, SEC};
 use csv::ReaderBuilder;
 use flate2::read::GzDecoder;
 use polars::prelude::DataFrame;
@@ -17,10 +17,12 @@ use polars::prelude::SortMultipleOptions;
 
 use polars::lazy::frame::pivot::pivot;
 use polars::lazy::prelude::IntoLazy;
-use polars::lazy::prelude::{col, LazyFrame};
+use polars::lazy::prelude::{col, lit, when, Expr, LazyFrame, NULL};
+use polars::prelude::DataType;
 use polars::time::ClosedWindow;
 
 use anyhow::anyhow;
+use pyo3::pyclass;
 
 #[allow(non_upper_case_globals)]
 #[allow(non_snake_case)]
@@ -41,17 +43,297 @@ pub mod KEY {
     pub const low: &str = "low";
     pub const close: &str = "close";
     pub const volume: &str = "volume";
-    #[allow(unused)]
+    /// 売りの出来高。ohlcv/ohlcvvともにtick_direction(-1)で判定する。
     pub const sell_volume: &str = "sell_volume";
-    #[allow(unused)]
+    /// 売り(taker側のorder_side)の約定数
     pub const sell_count: &str = "sell_count";
-    #[allow(unused)]
+    /// 買いの出来高。ohlcv/ohlcvvともにtick_direction(1)で判定する。
     pub const buy_volume: &str = "buy_volume";
-    #[allow(unused)]
+    /// 買い(taker側のorder_side)の約定数
     pub const buy_count: &str = "buy_count";
     pub const start_time: &str = "start_time";
     pub const end_time: &str = "end_time";
     pub const count: &str = "count";
+    pub const tick_direction: &str = "tick_direction";
+    pub const delta: &str = "delta";
+    pub const cvd: &str = "cvd";
+    pub const lambda: &str = "lambda";
+
+    // for large trade
+    pub const large_volume: &str = "large_volume";
+    pub const large_count: &str = "large_count";
+    pub const large_buy_volume: &str = "large_buy_volume";
+    pub const large_sell_volume: &str = "large_sell_volume";
+}
+
+fn is_buy() -> Expr {
+    col(KEY::order_side).eq(lit(OrderSide::Buy.to_string()))
+}
+
+fn is_sell() -> Expr {
+    col(KEY::order_side).eq(lit(OrderSide::Sell.to_string()))
+}
+
+fn is_tick_buy() -> Expr {
+    col(KEY::tick_direction).eq(lit(1i64))
+}
+
+fn is_tick_sell() -> Expr {
+    col(KEY::tick_direction).eq(lit(-1i64))
+}
+
+/// tick ruleによるアグレッサー判定（買い:1, 売り:-1）。
+/// 価格が上がれば1、下がれば-1、変化なしは直前の値を引き継ぐ。
+/// 判定できない先頭部分はorder_side（taker側）で代用する。
+fn tick_rule() -> Expr {
+    let side = when(is_buy()).then(lit(1i64)).otherwise(lit(-1i64));
+    let diff = col(KEY::price) - col(KEY::price).shift(lit(1));
+
+    when(diff.clone().gt(lit(0.0)))
+        .then(lit(1i64))
+        .when(diff.lt(lit(0.0)))
+        .then(lit(-1i64))
+        .otherwise(lit(NULL).cast(DataType::Int64))
+        .forward_fill(None)
+        .fill_null(side)
+}
+
+/// KEY::tick_directionの式。取引所のアーカイブ由来の値(BybitのtickDirection)があればそれを使い、
+/// ない行(DBの約定など)はtick ruleで補う。
+fn tick_direction_expr(df: &DataFrame) -> Expr {
+    let tick = if df.get_column_names().contains(&KEY::tick_direction) {
+        col(KEY::tick_direction)
+            .cast(DataType::Int64)
+            .fill_null(tick_rule())
+    } else {
+        tick_rule()
+    };
+
+    tick.alias(KEY::tick_direction)
+}
+
+/// アグレッサー判定（買い:1, 売り:-1）をKEY::tick_directionとして追加する。
+pub fn tick_direction_df(df: &DataFrame) -> anyhow::Result<DataFrame> {
+    let df = df
+        .clone()
+        .lazy()
+        .with_column(tick_direction_expr(df))
+        .collect()?;
+
+    Ok(df)
+}
+
+/// buy_volume/sell_volume付きのohlcvから足ごとのdelta(買い-売り)と累積値cvdを計算する。
+/// cvdは入力dfの先頭を0として累積する。
+pub fn cvd_df(ohlcv: &DataFrame) -> anyhow::Result<DataFrame> {
+    let delta = col(KEY::buy_volume) - col(KEY::sell_volume);
+
+    let df = ohlcv
+        .clone()
+        .lazy()
+        .select([
+            col(KEY::timestamp),
+            col(KEY::buy_volume),
+            col(KEY::sell_volume),
+            delta.clone().alias(KEY::delta),
+            delta.cum_sum(false).alias(KEY::cvd),
+        ])
+        .collect()?;
+
+    Ok(df)
+}
+
+/// 最小二乗法によるy = a + b * xの傾きb
+fn ols_slope(x: &[f64], y: &[f64]) -> Option<f64> {
+    let n = x.len();
+    if n < 2 || n != y.len() {
+        return None;
+    }
+
+    let mean_x = x.iter().sum::<f64>() / n as f64;
+    let mean_y = y.iter().sum::<f64>() / n as f64;
+
+    let mut cov = 0.0;
+    let mut var = 0.0;
+    for i in 0..n {
+        cov += (x[i] - mean_x) * (y[i] - mean_y);
+        var += (x[i] - mean_x) * (x[i] - mean_x);
+    }
+
+    if var == 0.0 {
+        return None;
+    }
+
+    Some(cov / var)
+}
+
+/// window_sec幅の足ごとの(timestamp, 符号付き出来高(買い-売り), 前の足からの終値の変化)。
+/// 最初の足は始値からの変化とする。
+fn price_impact_samples(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    window_sec: i64,
+) -> anyhow::Result<(Vec<MicroSec>, Vec<f64>, Vec<f64>)> {
+    let ohlcv = ohlcv_df(df, start_time, end_time, window_sec)?;
+
+    let timestamp = ohlcv.column(KEY::timestamp)?.i64()?;
+    let open = ohlcv.column(KEY::open)?.f64()?;
+    let close = ohlcv.column(KEY::close)?.f64()?;
+    let buy_volume = ohlcv.column(KEY::buy_volume)?.f64()?;
+    let sell_volume = ohlcv.column(KEY::sell_volume)?.f64()?;
+
+    let mut times = vec![];
+    let mut signed_volume = vec![];
+    let mut delta_price = vec![];
+    let mut last_close: Option<f64> = None;
+
+    for i in 0..ohlcv.height() {
+        let (t, o, c) = match (timestamp.get(i), open.get(i), close.get(i)) {
+            (Some(t), Some(o), Some(c)) => (t, o, c),
+            _ => continue,
+        };
+
+        let v = buy_volume.get(i).unwrap_or(0.0) - sell_volume.get(i).unwrap_or(0.0);
+
+        times.push(t);
+        signed_volume.push(v);
+        delta_price.push(c - last_close.unwrap_or(o));
+        last_close = Some(c);
+    }
+
+    Ok((times, signed_volume, delta_price))
+}
+
+/// Kyle's lambda(出来高あたりの恒久的な価格インパクト)。
+/// window_sec幅の足ごとの価格変化を符号付き出来高(Buy=+, Sell=-)で回帰した傾き。
+pub fn kyles_lambda_df(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    window_sec: i64,
+) -> anyhow::Result<f64> {
+    let (_, signed_volume, delta_price) = price_impact_samples(df, start_time, end_time, window_sec)?;
+
+    ols_slope(&signed_volume, &delta_price).ok_or_else(|| {
+        anyhow!(
+            "not enough data to estimate lambda: {} windows ({} - {})",
+            signed_volume.len(),
+            time_string(start_time),
+            time_string(end_time)
+        )
+    })
+}
+
+/// step_secごとにKyle's lambdaを推定する(各区間内のwindow_sec幅の足で回帰)。
+/// 推定できない区間のlambdaはnull。
+pub fn rolling_kyles_lambda_df(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    window_sec: i64,
+    step_sec: i64,
+) -> anyhow::Result<DataFrame> {
+    if step_sec < window_sec * 2 {
+        return Err(anyhow!(
+            "step_sec({}) must be at least twice window_sec({})",
+            step_sec,
+            window_sec
+        ));
+    }
+
+    let (times, signed_volume, delta_price) = price_impact_samples(df, start_time, end_time, window_sec)?;
+
+    let mut timestamp: Vec<MicroSec> = vec![];
+    let mut lambda: Vec<Option<f64>> = vec![];
+
+    let mut begin = 0;
+    while begin < times.len() {
+        let step = FLOOR_SEC(times[begin], step_sec);
+
+        let mut end = begin;
+        while end < times.len() && FLOOR_SEC(times[end], step_sec) == step {
+            end += 1;
+        }
+
+        timestamp.push(step);
+        lambda.push(ols_slope(&signed_volume[begin..end], &delta_price[begin..end]));
+
+        begin = end;
+    }
+
+    let df = DataFrame::new(vec![
+        Series::new(KEY::timestamp, timestamp),
+        Series::new(KEY::lambda, lambda),
+    ])?;
+
+    Ok(df)
+}
+
+/// DataFrameのカラム名の命名規則
+/// Default: KEYの名前(timestamp, open, high, low, close, volume...)
+/// Short:   timestamp, o, h, l, c, v などの短縮名
+#[pyclass]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColumnStyle {
+    #[default]
+    Default,
+    Short,
+}
+
+impl ColumnStyle {
+    pub fn column_name(&self, key: &'static str) -> &'static str {
+        match self {
+            ColumnStyle::Default => key,
+            ColumnStyle::Short => match key {
+                KEY::price => "p",
+                KEY::size => "s",
+                KEY::order_side => "side",
+                KEY::open => "o",
+                KEY::high => "h",
+                KEY::low => "l",
+                KEY::close => "c",
+                KEY::volume => "v",
+                KEY::sell_volume => "sv",
+                KEY::sell_count => "sn",
+                KEY::buy_volume => "bv",
+                KEY::buy_count => "bn",
+                KEY::count => "n",
+                _ => key,
+            },
+        }
+    }
+}
+
+const STYLED_COLUMNS: [&str; 13] = [
+    KEY::price,
+    KEY::size,
+    KEY::order_side,
+    KEY::open,
+    KEY::high,
+    KEY::low,
+    KEY::close,
+    KEY::volume,
+    KEY::sell_volume,
+    KEY::sell_count,
+    KEY::buy_volume,
+    KEY::buy_count,
+    KEY::count,
+];
+
+/// カラム名をstyleに合わせて変更する（データのコピーは発生しない）
+pub fn apply_column_style(df: &mut DataFrame, style: ColumnStyle) -> anyhow::Result<()> {
+    if style == ColumnStyle::Default {
+        return Ok(());
+    }
+
+    for key in STYLED_COLUMNS {
+        if df.get_column_index(key).is_some() {
+            df.rename(key, style.column_name(key))?;
+        }
+    }
+
+    Ok(())
 }
 
 /// Convert DataFrame to Parquet format and save it to the specified path.
@@ -154,6 +436,36 @@ pub fn csv_to_df(source_path: &PathBuf) -> anyhow::Result<DataFrame> {
     //let lazy = LazyCsvReader::new(source_path).with_has_header(has_header).finish()?;
 }
 
+/// skipした行のうちログへ出す件数
+const INVALID_ROW_LOG_LIMIT: usize = 5;
+
+/// アーカイブのDataFrame(timestamp, price, size)から、欠けた行や0以下の価格・サイズの行を除く。
+/// 壊れた行が0円の約定としてOHLCVに混ざらないようにする。除いた行数も返す。
+pub fn drop_invalid_trade_rows(df: &DataFrame) -> anyhow::Result<(DataFrame, usize)> {
+    let valid = col(KEY::timestamp)
+        .is_not_null()
+        .and(col(KEY::price).is_not_null())
+        .and(col(KEY::size).is_not_null())
+        .and(col(KEY::price).gt(lit(0)))
+        .and(col(KEY::size).gt(lit(0)));
+
+    let valid_df = df.clone().lazy().filter(valid.clone()).collect()?;
+    let skipped = df.height() - valid_df.height();
+
+    if 0 < skipped {
+        let invalid_df = df
+            .clone()
+            .lazy()
+            .filter(valid.not().or(col(KEY::price).is_null()).or(col(KEY::size).is_null()))
+            .limit(INVALID_ROW_LOG_LIMIT as u32)
+            .collect()?;
+
+        log::warn!("skip {} invalid rows: {:?}", skipped, invalid_df);
+    }
+
+    Ok((valid_df, skipped))
+}
+
 /*
 /// Cutoff start_time to end_time(not include)
 pub fn select_df(df: &DataFrame, start_time: MicroSec, end_time: MicroSec) -> DataFrame {
@@ -244,10 +556,13 @@ pub fn end_time_df(df: &DataFrame) -> Option<MicroSec> {
 
 /// append df2 after df1
 pub fn append_df(df1: &DataFrame, df2: &DataFrame) -> anyhow::Result<DataFrame> {
-    if df1.shape().1 != df2.shape().1 {
-        println!("shape mismatch df1={:?}, df2={:?}", df1.shape(), df2.shape());
-        println!("{:?}", df1);
-        println!("{:?}", df2);
+    if df1.get_column_names() != df2.get_column_names() {
+        log::debug!("column mismatch df1={:?}, df2={:?}", df1.get_column_names(), df2.get_column_names());
+
+        let df1_aligned = align_columns(df1, df2)?;
+        let df2_aligned = align_columns(df2, &df1_aligned)?;
+
+        return Ok(df1_aligned.vstack(&df2_aligned)?);
     }
 
     let df = df1.vstack(df2)?;
@@ -255,6 +570,27 @@ pub fn append_df(df1: &DataFrame, df2: &DataFrame) -> anyhow::Result<DataFrame>
     Ok(df)
 }
 
+/// likeにあってdfにないカラム(アーカイブのみにあるtick_directionなど)をnullで追加し、likeのカラム順に並べる。
+/// dfにだけあるカラムは末尾に残す。
+fn align_columns(df: &DataFrame, like: &DataFrame) -> anyhow::Result<DataFrame> {
+    let mut df = df.clone();
+
+    for column in like.get_columns() {
+        if !df.get_column_names().contains(&column.name()) {
+            df.with_column(Series::full_null(column.name(), df.height(), column.dtype()))?;
+        }
+    }
+
+    let mut order: Vec<String> = like.get_column_names().iter().map(|c| c.to_string()).collect();
+    for name in df.get_column_names() {
+        if !order.iter().any(|c| c == name) {
+            order.push(name.to_string());
+        }
+    }
+
+    Ok(df.select(order)?)
+}
+
 /// merge 2 dataframe, if overlap df2 on df1, df1 will be trimmed(overwritten by df2)
 pub fn merge_df(df1: &DataFrame, df2: &DataFrame) -> anyhow::Result<DataFrame> {
     log::debug!("merge df1={:?}  df2={:?}", df1.shape(), df2.shape());
@@ -296,12 +632,52 @@ pub fn merge_df(df1: &DataFrame, df2: &DataFrame) -> anyhow::Result<DataFrame> {
     Ok(df)
 }
 
+/// offsetを[0, time_window)に正規化する
+pub fn window_offset(time_window: i64, offset_sec: i64) -> i64 {
+    if time_window <= 0 {
+        return 0;
+    }
+
+    offset_sec.rem_euclid(time_window)
+}
+
 pub fn ohlcv_df(
     df: &DataFrame,
     start_time: MicroSec,
     end_time: MicroSec,
     time_window: i64,
 ) -> anyhow::Result<DataFrame> {
+    ohlcv_df_with_offset(df, start_time, end_time, time_window, 0)
+}
+
+/// 足の区切りをepochからoffset_sec(秒)ずらす。日足を取引所の日付の切り替え(funding/清算)に合わせるときに使う。
+pub fn ohlcv_df_with_offset(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    time_window: i64,
+    offset_sec: i64,
+) -> anyhow::Result<DataFrame> {
+    let result = ohlcv_lazy_df_with_offset(df, start_time, end_time, time_window, offset_sec).collect();
+
+    match result {
+        Ok(dataframe) => return Ok(dataframe),
+        Err(e) => {
+            log::error!("Polars error {}", e.to_string());
+            println!("Polars error {}", e.to_string());
+            return Ok(make_empty_ohlcv());
+        }
+    }
+}
+
+/// ohlcv_df_with_offsetの遅延評価版。collectするまで集計しない。
+pub fn ohlcv_lazy_df_with_offset(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    time_window: i64,
+    offset_sec: i64,
+) -> LazyFrame {
     log::debug!(
         "ohlcv_df, from={} / to={}",
         time_string(start_time),
@@ -310,14 +686,14 @@ pub fn ohlcv_df(
 
     if df.shape().0 == 0 {
         log::debug!("empty ohlc");
-        return Ok(make_empty_ohlcv());
+        return make_empty_ohlcv().lazy();
     }
 
     let option = DynamicGroupOptions {
         index_column: KEY::timestamp.into(),
         every: Duration::new(SEC(time_window)), // グループ間隔
         period: Duration::new(SEC(time_window)), // データ取得の幅（グループ間隔と同じでOK)
-        offset: Duration::parse("0m"),
+        offset: Duration::new(SEC(window_offset(time_window, offset_sec))),
         // truncate: true,                    // タイムスタンプを切り下げてまとめる。
         include_boundaries: false, // データの下限と上限を結果に含めるかどうか？(falseでOK)
         closed_window: ClosedWindow::Left, // t <=  x  < t+1       開始時間はWindowに含まれる。終了は含まれない(CloseWindow::Left)。
@@ -325,7 +701,8 @@ pub fn ohlcv_df(
         ..Default::default()
     };
 
-    let df = select_df_lazy(df, start_time, end_time);
+    let tick_direction = tick_direction_expr(df);
+    let df = select_df_lazy(df, start_time, end_time).with_column(tick_direction);
 
     let result = df
         .group_by_dynamic(col(KEY::timestamp), [], option)
@@ -336,6 +713,10 @@ pub fn ohlcv_df(
             col(KEY::price).last().alias(KEY::close),
             col(KEY::size).sum().alias(KEY::volume),
             col(KEY::price).count().alias(KEY::count),
+            col(KEY::size).filter(is_tick_buy()).sum().alias(KEY::buy_volume),
+            col(KEY::size).filter(is_tick_sell()).sum().alias(KEY::sell_volume),
+            col(KEY::price).filter(is_buy()).count().alias(KEY::buy_count),
+            col(KEY::price).filter(is_sell()).count().alias(KEY::sell_count),
         ])
         .sort(
             vec![(KEY::timestamp).to_string()],
@@ -345,17 +726,82 @@ pub fn ohlcv_df(
                 maintain_order: true,
                 multithreaded: true,
             },
-        )
-        .collect();
+        );
 
-    match result {
-        Ok(dataframe) => return Ok(dataframe),
-        Err(e) => {
-            log::error!("Polars error {}", e.to_string());
-            println!("Polars error {}", e.to_string());
-            return Ok(make_empty_ohlcv());
-        }
+    result
+}
+
+/// ohlcvに加えて、size >= min_sizeの約定だけを集計したlarge_*カラムを持つDataFrameを返す
+pub fn ohlcv_large_df(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    time_window: i64,
+    min_size: f64,
+) -> anyhow::Result<DataFrame> {
+    log::debug!(
+        "ohlcv_large_df, from={} / to={} / min_size={}",
+        time_string(start_time),
+        time_string(end_time),
+        min_size
+    );
+
+    if df.shape().0 == 0 {
+        log::debug!("empty ohlc");
+        return Ok(make_empty_ohlcv_large());
     }
+
+    let option = DynamicGroupOptions {
+        index_column: KEY::timestamp.into(),
+        every: Duration::new(SEC(time_window)),
+        period: Duration::new(SEC(time_window)),
+        offset: Duration::parse("0m"),
+        include_boundaries: false,
+        closed_window: ClosedWindow::Left,
+        ..Default::default()
+    };
+
+    let is_large = || col(KEY::size).gt_eq(lit(min_size));
+
+    let tick_direction = tick_direction_expr(df);
+    let df = select_df_lazy(df, start_time, end_time).with_column(tick_direction);
+
+    let result = df
+        .group_by_dynamic(col(KEY::timestamp), [], option)
+        .agg([
+            col(KEY::price).first().alias(KEY::open),
+            col(KEY::price).max().alias(KEY::high),
+            col(KEY::price).min().alias(KEY::low),
+            col(KEY::price).last().alias(KEY::close),
+            col(KEY::size).sum().alias(KEY::volume),
+            col(KEY::price).count().alias(KEY::count),
+            col(KEY::size).filter(is_tick_buy()).sum().alias(KEY::buy_volume),
+            col(KEY::size).filter(is_tick_sell()).sum().alias(KEY::sell_volume),
+            col(KEY::price).filter(is_buy()).count().alias(KEY::buy_count),
+            col(KEY::price).filter(is_sell()).count().alias(KEY::sell_count),
+            col(KEY::size).filter(is_large()).sum().alias(KEY::large_volume),
+            col(KEY::price).filter(is_large()).count().alias(KEY::large_count),
+            col(KEY::size)
+                .filter(is_large().and(is_tick_buy()))
+                .sum()
+                .alias(KEY::large_buy_volume),
+            col(KEY::size)
+                .filter(is_large().and(is_tick_sell()))
+                .sum()
+                .alias(KEY::large_sell_volume),
+        ])
+        .sort(
+            vec![(KEY::timestamp).to_string()],
+            SortMultipleOptions {
+                descending: vec![false],
+                nulls_last: vec![false],
+                maintain_order: true,
+                multithreaded: true,
+            },
+        )
+        .collect()?;
+
+    Ok(result)
 }
 
 pub fn ohlcvv_df(
@@ -386,7 +832,8 @@ pub fn ohlcvv_df(
         ..Default::default()
     };
 
-    let df = select_df_lazy(df, start_time, end_time);
+    let tick_direction = tick_direction_expr(df);
+    let df = select_df_lazy(df, start_time, end_time).with_column(tick_direction);
 
     let result = df
         .group_by_dynamic(col(KEY::timestamp), [col(KEY::order_side)], option)
@@ -399,6 +846,8 @@ pub fn ohlcvv_df(
             col(KEY::price).count().alias(KEY::count),
             col(KEY::timestamp).min().alias(KEY::start_time),
             col(KEY::timestamp).max().alias(KEY::end_time),
+            col(KEY::size).filter(is_tick_buy()).sum().alias(KEY::buy_volume),
+            col(KEY::size).filter(is_tick_sell()).sum().alias(KEY::sell_volume),
         ])
         .sort(
             vec![KEY::timestamp.to_string()],
@@ -427,6 +876,36 @@ pub fn ohlcv_from_ohlcvv_df(
     end_time: MicroSec,
     time_window: i64,
 ) -> anyhow::Result<DataFrame> {
+    ohlcv_from_ohlcvv_df_with_offset(df, start_time, end_time, time_window, 0)
+}
+
+pub fn ohlcv_from_ohlcvv_df_with_offset(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    time_window: i64,
+    offset_sec: i64,
+) -> anyhow::Result<DataFrame> {
+    let result = ohlcv_from_ohlcvv_lazy_df_with_offset(df, start_time, end_time, time_window, offset_sec).collect();
+
+    match result {
+        Ok(dataframe) => return Ok(dataframe),
+        Err(e) => {
+            log::error!("Polars error {}", e.to_string());
+            println!("Polars error {}", e.to_string());
+            return Ok(make_empty_ohlcv());
+        }
+    }
+}
+
+/// ohlcv_from_ohlcvv_df_with_offsetの遅延評価版
+pub fn ohlcv_from_ohlcvv_lazy_df_with_offset(
+    df: &DataFrame,
+    start_time: MicroSec,
+    end_time: MicroSec,
+    time_window: i64,
+    offset_sec: i64,
+) -> LazyFrame {
     log::debug!(
         "ohlc {:?} -> {:?}",
         time_string(start_time),
@@ -435,14 +914,14 @@ pub fn ohlcv_from_ohlcvv_df(
 
     if df.shape().0 == 0 {
         log::debug!("empty ohlc");
-        return Ok(make_empty_ohlcv());
+        return make_empty_ohlcv().lazy();
     }
 
     let option = DynamicGroupOptions {
         index_column: KEY::timestamp.into(),
         every: Duration::new(SEC(time_window)), // グループ間隔
         period: Duration::new(SEC(time_window)), // データ取得の幅（グループ間隔と同じでOK)
-        offset: Duration::parse("0m"),
+        offset: Duration::new(SEC(window_offset(time_window, offset_sec))),
         // truncate: true,                    // タイムスタンプを切り下げてまとめる。
         include_boundaries: false, // データの下限と上限を結果に含めるかどうか？(falseでOK)
         closed_window: ClosedWindow::Left, // t <=  x  < t+1       開始時間はWindowに含まれる。終了は含まれない(CloseWindow::Left)。
@@ -482,6 +961,10 @@ pub fn ohlcv_from_ohlcvv_df(
                 .alias(KEY::close),
             col(KEY::volume).sum().alias(KEY::volume),
             col(KEY::count).sum().alias(KEY::count),
+            col(KEY::buy_volume).sum().alias(KEY::buy_volume),
+            col(KEY::sell_volume).sum().alias(KEY::sell_volume),
+            col(KEY::count).filter(is_buy()).sum().alias(KEY::buy_count),
+            col(KEY::count).filter(is_sell()).sum().alias(KEY::sell_count),
         ])
         .sort(
             vec![(KEY::timestamp).to_string()],
@@ -491,17 +974,9 @@ pub fn ohlcv_from_ohlcvv_df(
                 maintain_order: true,
                 multithreaded: true,
             },
-        )
-        .collect();
+        );
 
-    match result {
-        Ok(dataframe) => return Ok(dataframe),
-        Err(e) => {
-            log::error!("Polars error {}", e.to_string());
-            println!("Polars error {}", e.to_string());
-            return Ok(make_empty_ohlcv());
-        }
-    }
+    result
 }
 
 pub fn ohlcvv_from_ohlcvv_df(
@@ -540,6 +1015,8 @@ pub fn ohlcvv_from_ohlcvv_df(
             col(KEY::count).sum().alias(KEY::count),
             col(KEY::start_time).min().alias(KEY::start_time),
             col(KEY::end_time).max().alias(KEY::end_time),
+            col(KEY::buy_volume).sum().alias(KEY::buy_volume),
+            col(KEY::sell_volume).sum().alias(KEY::sell_volume),
         ])
         .sort(
             vec![(KEY::timestamp).to_string()],
@@ -705,9 +1182,12 @@ pub fn make_empty_ohlcvv() -> DataFrame {
     let count = Series::new(KEY::count, Vec::<i64>::new());
     let start_time = Series::new(KEY::start_time, Vec::<MicroSec>::new());
     let end_time = Series::new(KEY::end_time, Vec::<MicroSec>::new());
+    let buy_vol = Series::new(KEY::buy_volume, Vec::<f64>::new());
+    let sell_vol = Series::new(KEY::sell_volume, Vec::<f64>::new());
 
     let df = DataFrame::new(vec![
-        time, order_side, open, high, low, close, vol, count, start_time, end_time,
+        time, order_side, open, high, low, close, vol, count, start_time, end_time, buy_vol,
+        sell_vol,
     ])
     .unwrap();
 
@@ -722,12 +1202,30 @@ pub fn make_empty_ohlcv() -> DataFrame {
     let close = Series::new(KEY::close, Vec::<f64>::new());
     let vol = Series::new(KEY::volume, Vec::<f64>::new());
     let count = Series::new(KEY::count, Vec::<i64>::new());
+    let buy_vol = Series::new(KEY::buy_volume, Vec::<f64>::new());
+    let sell_vol = Series::new(KEY::sell_volume, Vec::<f64>::new());
+    let buy_count = Series::new(KEY::buy_count, Vec::<i64>::new());
+    let sell_count = Series::new(KEY::sell_count, Vec::<i64>::new());
 
-    let df = DataFrame::new(vec![time, open, high, low, close, vol, count]).unwrap();
+    let df = DataFrame::new(vec![
+        time, open, high, low, close, vol, count, buy_vol, sell_vol, buy_count, sell_count,
+    ])
+    .unwrap();
 
     return df;
 }
 
+pub fn make_empty_ohlcv_large() -> DataFrame {
+    let mut df = make_empty_ohlcv();
+
+    df.with_column(Series::new(KEY::large_volume, Vec::<f64>::new())).unwrap();
+    df.with_column(Series::new(KEY::large_count, Vec::<i64>::new())).unwrap();
+    df.with_column(Series::new(KEY::large_buy_volume, Vec::<f64>::new())).unwrap();
+    df.with_column(Series::new(KEY::large_sell_volume, Vec::<f64>::new())).unwrap();
+
+    df
+}
+
 pub trait AsDynamicGroupOptions {
     fn as_dynamic_group_options(&self) -> &DynamicGroupOptions;
 }
@@ -746,6 +1244,49 @@ pub fn convert_timems_to_datetime(df: &mut DataFrame) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// write ohlcv df into csv file with header.
+/// time column is iso string(time_string) when iso_time is true, otherwise raw microsec.
+/// returns number of rows written.
+pub fn ohlcv_df_to_csv(df: &DataFrame, path: &Path, iso_time: bool) -> anyhow::Result<i64> {
+    let mut writer = csv::Writer::from_path(path)?;
+
+    writer.write_record(&[
+        if iso_time { "time" } else { KEY::timestamp },
+        KEY::open,
+        KEY::high,
+        KEY::low,
+        KEY::close,
+        KEY::volume,
+    ])?;
+
+    let timestamp = df.column(KEY::timestamp)?.i64()?;
+    let open = df.column(KEY::open)?.f64()?;
+    let high = df.column(KEY::high)?.f64()?;
+    let low = df.column(KEY::low)?.f64()?;
+    let close = df.column(KEY::close)?.f64()?;
+    let volume = df.column(KEY::volume)?.f64()?;
+
+    let mut count = 0;
+    for i in 0..df.height() {
+        let t = timestamp.get(i).unwrap_or_default();
+        let t = if iso_time { time_string(t) } else { t.to_string() };
+
+        writer.write_record(&[
+            t,
+            open.get(i).unwrap_or_default().to_string(),
+            high.get(i).unwrap_or_default().to_string(),
+            low.get(i).unwrap_or_default().to_string(),
+            close.get(i).unwrap_or_default().to_string(),
+            volume.get(i).unwrap_or_default().to_string(),
+        ])?;
+        count += 1;
+    }
+
+    writer.flush()?;
+
+    Ok(count)
+}
+
 use tokio::time::error::Elapsed;
 use ::zip::ZipArchive;
 use polars::prelude::*;
@@ -756,6 +1297,338 @@ mod test_df {
     use super::*;
     use crate::common::{init_debug_log, DAYS};
 
+    #[test]
+    fn test_drop_invalid_trade_rows() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("archive.csv");
+        let mut file = File::create(&path)?;
+        writeln!(file, "timestamp,price,size,order_side,id")?;
+        writeln!(file, "1668816000029,16681.46,0.00298,Buy,1")?;
+        writeln!(file, "1668816000053,16681.43")?; // 途中で切れた行
+        writeln!(file, "1668816000075,16681.00,0.00299,Sell,3")?;
+        drop(file);
+
+        let df = csv_to_df(&path)?;
+        assert_eq!(df.height(), 3);
+
+        let (df, skipped) = drop_invalid_trade_rows(&df)?;
+        assert_eq!(skipped, 1);
+        assert_eq!(df.height(), 2);
+
+        let prices: Vec<Option<f64>> = df.column(KEY::price)?.f64()?.into_iter().collect();
+        assert_eq!(prices, vec![Some(16681.46), Some(16681.0)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tick_direction_df() -> anyhow::Result<()> {
+        let df = df![
+            KEY::timestamp => [1, 2, 3, 4, 5],
+            KEY::price => [100.0, 101.0, 101.0, 100.0, 100.0],
+            KEY::size => [1.0, 1.0, 1.0, 1.0, 1.0],
+            KEY::order_side => ["Sell", "Buy", "Sell", "Buy", "Sell"]
+        ]?;
+
+        let df = tick_direction_df(&df)?;
+        let tick: Vec<Option<i64>> = df.column(KEY::tick_direction)?.i64()?.into_iter().collect();
+        assert_eq!(tick, vec![Some(-1), Some(1), Some(1), Some(-1), Some(-1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tick_direction_df_with_archive_column() -> anyhow::Result<()> {
+        // アーカイブ由来のtick_directionを優先し、nullの行だけtick ruleで補う
+        let df = df![
+            KEY::timestamp => [1i64, 2, 3, 4],
+            KEY::price => [100.0, 101.0, 101.0, 100.0],
+            KEY::size => [1.0, 1.0, 1.0, 1.0],
+            KEY::order_side => ["Sell", "Buy", "Sell", "Buy"],
+            KEY::tick_direction => [Some(1i64), Some(-1), None, None]
+        ]?;
+
+        let df = tick_direction_df(&df)?;
+        let tick: Vec<Option<i64>> = df.column(KEY::tick_direction)?.i64()?.into_iter().collect();
+        assert_eq!(tick, vec![Some(1), Some(-1), Some(1), Some(-1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ohlcvv_tick_direction_volume() -> anyhow::Result<()> {
+        let df = df![
+            KEY::timestamp => [0i64, 1, 2, 3],
+            KEY::price => [100.0, 101.0, 102.0, 101.0],
+            KEY::size => [1.0, 2.0, 3.0, 4.0],
+            KEY::order_side => ["Buy", "Buy", "Buy", "Buy"],
+            KEY::tick_direction => [1i64, -1, 1, -1]
+        ]?;
+
+        let ohlcvv = ohlcvv_df(&df, 0, 0, 60)?;
+        assert_eq!(ohlcvv.shape().0, 1);
+        assert_eq!(ohlcvv.column(KEY::buy_volume)?.f64()?.get(0), Some(4.0));
+        assert_eq!(ohlcvv.column(KEY::sell_volume)?.f64()?.get(0), Some(6.0));
+
+        let ohlcvv2 = ohlcvv_from_ohlcvv_df(&ohlcvv, 0, 0, 120)?;
+        assert_eq!(ohlcvv2.column(KEY::buy_volume)?.f64()?.get(0), Some(4.0));
+        assert_eq!(ohlcvv2.column(KEY::sell_volume)?.f64()?.get(0), Some(6.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_df_align_columns() -> anyhow::Result<()> {
+        let db_df = df![
+            KEY::timestamp => [1i64],
+            KEY::order_side => ["Buy"],
+            KEY::price => [100.0],
+            KEY::size => [1.0],
+            KEY::id => ["a"]
+        ]?;
+        let archive_df = df![
+            KEY::timestamp => [2i64],
+            KEY::order_side => ["Sell"],
+            KEY::price => [99.0],
+            KEY::size => [2.0],
+            KEY::id => ["b"],
+            KEY::tick_direction => [-1i64]
+        ]?;
+
+        let df = append_df(&db_df, &archive_df)?;
+        assert_eq!(df.shape(), (2, 6));
+        let tick: Vec<Option<i64>> = df.column(KEY::tick_direction)?.i64()?.into_iter().collect();
+        assert_eq!(tick, vec![None, Some(-1)]);
+
+        let df = append_df(&archive_df, &db_df)?;
+        assert_eq!(df.get_column_names(), archive_df.get_column_names());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ohlcv_buy_sell_volume() -> anyhow::Result<()> {
+        let df = df![
+            KEY::timestamp => [0i64, 1, 2, 3],
+            KEY::price => [100.0, 99.0, 102.0, 101.0],
+            KEY::size => [1.0, 2.0, 3.0, 4.0],
+            KEY::order_side => ["Buy", "Sell", "Buy", "Sell"]
+        ]?;
+
+        let ohlcv = ohlcv_df(&df, 0, 0, 60)?;
+        assert_eq!(ohlcv.shape().0, 1);
+        assert_eq!(ohlcv.column(KEY::buy_volume)?.f64()?.get(0), Some(4.0));
+        assert_eq!(ohlcv.column(KEY::sell_volume)?.f64()?.get(0), Some(6.0));
+
+        // アーカイブのtick_directionがorder_sideと食い違っても、
+        // 約定から直接作ったohlcvとohlcvv経由のohlcvは同じbuy/sell出来高になる
+        let df = df![
+            KEY::timestamp => [0i64, 1, 2, 3],
+            KEY::price => [100.0, 101.0, 102.0, 101.0],
+            KEY::size => [1.0, 2.0, 3.0, 4.0],
+            KEY::order_side => ["Buy", "Buy", "Sell", "Sell"],
+            KEY::tick_direction => [1i64, -1, 1, -1]
+        ]?;
+
+        let ohlcv = ohlcv_df(&df, 0, 0, 60)?;
+        let ohlcvv = ohlcvv_df(&df, 0, 0, 60)?;
+        let from_ohlcvv = ohlcv_from_ohlcvv_df(&ohlcvv, 0, 0, 60)?;
+
+        for key in [KEY::buy_volume, KEY::sell_volume] {
+            assert!(ohlcv.column(key)?.equals(from_ohlcvv.column(key)?));
+        }
+        assert_eq!(ohlcv.column(KEY::buy_volume)?.f64()?.get(0), Some(4.0));
+        assert_eq!(ohlcv.column(KEY::sell_volume)?.f64()?.get(0), Some(6.0));
+
+        Ok(())
+    }
+
+    /// 価格 += lambda * 符号付き出来高 + ノイズ のランダムウォーク(1秒に1約定)
+    fn impact_random_walk(lambda: f64, n: usize) -> anyhow::Result<DataFrame> {
+        let mut seed: u64 = 12345;
+        let mut rand = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+
+        let mut price = 10000.0;
+        let mut timestamp = vec![];
+        let mut prices = vec![];
+        let mut sizes = vec![];
+        let mut sides = vec![];
+
+        for i in 0..n {
+            let is_buy = rand() < 0.5;
+            let size = 0.5 + rand();
+            let noise = (rand() - 0.5) * 0.1;
+
+            price += lambda * if is_buy { size } else { -size } + noise;
+
+            timestamp.push(SEC(i as i64));
+            prices.push(price);
+            sizes.push(size);
+            sides.push(if is_buy { "Buy" } else { "Sell" });
+        }
+
+        Ok(df![
+            KEY::timestamp => timestamp,
+            KEY::price => prices,
+            KEY::size => sizes,
+            KEY::order_side => sides
+        ]?)
+    }
+
+    #[test]
+    fn test_kyles_lambda() -> anyhow::Result<()> {
+        let df = impact_random_walk(0.5, 10_000)?;
+
+        let lambda = kyles_lambda_df(&df, 0, 0, 10)?;
+        assert!((lambda - 0.5).abs() < 0.05, "lambda={}", lambda);
+
+        // 1区間だけでは推定できない
+        let one = impact_random_walk(0.5, 5)?;
+        assert!(kyles_lambda_df(&one, 0, 0, 10).is_err());
+
+        let rolling = rolling_kyles_lambda_df(&df, 0, 0, 10, 1000)?;
+        assert_eq!(rolling.shape(), (10, 2));
+        assert_eq!(rolling.column(KEY::timestamp)?.i64()?.get(1), Some(SEC(1000)));
+        for l in rolling.column(KEY::lambda)?.f64()?.into_iter() {
+            let l = l.unwrap();
+            assert!((l - 0.5).abs() < 0.05, "lambda={}", l);
+        }
+
+        assert!(rolling_kyles_lambda_df(&df, 0, 0, 10, 10).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ohlcv_df_with_offset() -> anyhow::Result<()> {
+        // 2024-08-23 00:00:00 UTCから1時間ごとに48時間分
+        let base = 1724371200_000_000i64;
+        let timestamps: Vec<i64> = (0..48).map(|h| base + SEC(60 * 60 * h)).collect();
+        let n = timestamps.len();
+
+        let df = df![
+            KEY::timestamp => timestamps,
+            KEY::price => vec![100.0; n],
+            KEY::size => vec![1.0; n],
+            KEY::order_side => vec!["Buy"; n]
+        ]?;
+
+        let day = 60 * 60 * 24;
+
+        let ohlcv = ohlcv_df(&df, 0, 0, day)?;
+        assert_eq!(ohlcv.column(KEY::timestamp)?.i64()?.get(0), Some(base));
+
+        // 8時始まりの日足
+        let offset = 60 * 60 * 8;
+        let ohlcv = ohlcv_df_with_offset(&df, 0, 0, day, offset)?;
+        let t: Vec<Option<i64>> = ohlcv.column(KEY::timestamp)?.i64()?.into_iter().collect();
+        assert_eq!(
+            t,
+            vec![Some(base - SEC(day - offset)), Some(base + SEC(offset)), Some(base + SEC(day + offset))]
+        );
+        // 00:00-07:00の8本
+        assert_eq!(ohlcv.column(KEY::volume)?.f64()?.get(0), Some(8.0));
+        assert_eq!(ohlcv.column(KEY::volume)?.f64()?.get(1), Some(24.0));
+
+        // 負のoffsetは1日から引いた値と同じ
+        let ohlcv2 = ohlcv_df_with_offset(&df, 0, 0, day, offset - day)?;
+        assert_eq!(ohlcv, ohlcv2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ohlcv_lazy_df() -> anyhow::Result<()> {
+        let df = df![
+            KEY::timestamp => [0i64, SEC(30), SEC(60), SEC(90), SEC(120)],
+            KEY::price => [100.0, 101.0, 102.0, 103.0, 104.0],
+            KEY::size => [1.0, 1.0, 1.0, 1.0, 1.0],
+            KEY::order_side => ["Buy", "Sell", "Buy", "Sell", "Buy"]
+        ]?;
+
+        let lazy = ohlcv_lazy_df_with_offset(&df, 0, 0, 60, 0);
+        assert_eq!(lazy.clone().collect()?, ohlcv_df(&df, 0, 0, 60)?);
+
+        // collect前にフィルタを重ねられる
+        let filtered = lazy.filter(col(KEY::close).gt(lit(102.0))).collect()?;
+        assert_eq!(filtered.height(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cvd_df() -> anyhow::Result<()> {
+        let df = df![
+            KEY::timestamp => [0i64, SEC(60), SEC(120), SEC(180)],
+            KEY::price => [100.0, 99.0, 102.0, 101.0],
+            KEY::size => [1.0, 2.0, 3.0, 4.0],
+            KEY::order_side => ["Buy", "Sell", "Buy", "Sell"]
+        ]?;
+
+        let ohlcv = ohlcv_df(&df, 0, 0, 60)?;
+        let cvd = cvd_df(&ohlcv)?;
+        let delta: Vec<Option<f64>> = cvd.column(KEY::delta)?.f64()?.into_iter().collect();
+        let sum: Vec<Option<f64>> = cvd.column(KEY::cvd)?.f64()?.into_iter().collect();
+        assert_eq!(delta, vec![Some(1.0), Some(-2.0), Some(3.0), Some(-4.0)]);
+        assert_eq!(sum, vec![Some(1.0), Some(-1.0), Some(2.0), Some(-2.0)]);
+
+        // sub range starts from 0
+        let ohlcv = ohlcv_df(&df, SEC(120), 0, 60)?;
+        let cvd = cvd_df(&ohlcv)?;
+        let sum: Vec<Option<f64>> = cvd.column(KEY::cvd)?.f64()?.into_iter().collect();
+        assert_eq!(sum, vec![Some(3.0), Some(-1.0)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ohlcv_large_df() -> anyhow::Result<()> {
+        let df = df![
+            KEY::timestamp => [0i64, 1, 2, SEC(60)],
+            KEY::price => [100.0, 99.0, 102.0, 101.0],
+            KEY::size => [1.0, 5.0, 6.0, 0.5],
+            KEY::order_side => ["Buy", "Sell", "Buy", "Sell"]
+        ]?;
+
+        let ohlcv = ohlcv_large_df(&df, 0, 0, 60, 5.0)?;
+        assert_eq!(ohlcv.shape().0, 2);
+        assert_eq!(ohlcv.column(KEY::volume)?.f64()?.get(0), Some(12.0));
+        assert_eq!(ohlcv.column(KEY::large_volume)?.f64()?.get(0), Some(11.0));
+        assert_eq!(ohlcv.column(KEY::large_buy_volume)?.f64()?.get(0), Some(6.0));
+        assert_eq!(ohlcv.column(KEY::large_sell_volume)?.f64()?.get(0), Some(5.0));
+        assert_eq!(ohlcv.column(KEY::large_volume)?.f64()?.get(1), Some(0.0));
+
+        let empty = ohlcv_large_df(&make_empty_ohlcv(), 0, 0, 60, 5.0)?;
+        assert_eq!(empty.shape().0, 0);
+        assert!(empty.column(KEY::large_count).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_column_style() -> anyhow::Result<()> {
+        let mut df = make_empty_ohlcv();
+        apply_column_style(&mut df, ColumnStyle::Default)?;
+        assert_eq!(df, make_empty_ohlcv());
+
+        apply_column_style(&mut df, ColumnStyle::Short)?;
+        let names: Vec<&str> = df.get_column_names();
+        assert_eq!(names[0], KEY::timestamp);
+        assert!(names.contains(&"o"));
+        assert!(names.contains(&"h"));
+        assert!(names.contains(&"l"));
+        assert!(names.contains(&"c"));
+        assert!(names.contains(&"v"));
+        assert!(!names.contains(&KEY::open));
+
+        Ok(())
+    }
+
     #[test]
     fn test_merge_and_append_df() -> anyhow::Result<()> {
         init_debug_log();
@@ -878,12 +1751,12 @@ mod test_df {
         println!("{:?}", groupby);
         let df = make_empty_ohlcv();
         assert_eq!(df.height(), 0);
-        assert_eq!(df.width(), 7);
+        assert_eq!(df.width(), 11);
     }
 
     fn make_ohlcv_df() -> DataFrame {
         let df = df!(
-            KEY::order_side => &[false, true, false, true],
+            KEY::order_side => &["Sell", "Buy", "Sell", "Buy"],
             KEY::timestamp => &[DAYS(1), DAYS(2), DAYS(3), DAYS(3)],
             KEY::open => &[1.0, 2.0, 3.0, 4.0],
             KEY::high => &[1.0, 2.0, 3.0, 4.0],
@@ -892,7 +1765,9 @@ mod test_df {
             KEY::volume => &[1.0, 2.0, 3.0, 4.0],
             KEY::count => &[1, 2, 3, 4],
             KEY::start_time => &[DAYS(1), DAYS(2), DAYS(3), DAYS(3)],
-            KEY::end_time => &[DAYS(2), DAYS(3), DAYS(4), DAYS(4)]
+            KEY::end_time => &[DAYS(2), DAYS(3), DAYS(4), DAYS(4)],
+            KEY::buy_volume => &[0.0, 2.0, 0.0, 4.0],
+            KEY::sell_volume => &[1.0, 0.0, 3.0, 0.0]
         );
 
         return df
@@ -915,7 +1790,7 @@ mod test_df {
 
         println!("{:?}", ohlc);
         assert_eq!(ohlc.shape().0, 4);
-        assert_eq!(ohlc.shape().1, 10);
+        assert_eq!(ohlc.shape().1, 12);
     }
 
     #[test]
@@ -938,7 +1813,7 @@ mod test_df {
         println!("{:?}", ohlcv2);
 
         assert_eq!(ohlcv2.shape().0, 3);
-        assert_eq!(ohlcv2.shape().1, 7);
+        assert_eq!(ohlcv2.shape().1, 11);
 
         Ok(())
     }
@@ -948,7 +1823,7 @@ mod test_df {
         let r = make_empty_ohlcvv();
 
         println!("{:?}", r);
-        assert_eq!(r.shape(), (0, 10));
+        assert_eq!(r.shape(), (0, 12));
     }
 
     #[test]
@@ -956,7 +1831,7 @@ mod test_df {
         let r = make_empty_ohlcv();
 
         println!("{:?}", r);
-        assert_eq!(r.shape(), (0, 7));
+        assert_eq!(r.shape(), (0, 11));
     }
 
     #[test]
@@ -964,7 +1839,7 @@ mod test_df {
         let r = make_empty_ohlcvv();
         let r2 = ohlcvv_df(&r, 0, 0, 10)?;
         println!("{:?}", r2);
-        assert_eq!(r2.shape(), (0, 10));
+        assert_eq!(r2.shape(), (0, 12));
 
         Ok(())
     }
@@ -974,7 +1849,7 @@ mod test_df {
         let r = make_empty_ohlcvv();
         let r2 = ohlcv_df(&r, 0, 0, 10)?;
         println!("{:?}", r2);
-        assert_eq!(r2.shape(), (0, 7));
+        assert_eq!(r2.shape(), (0, 11));
 
         Ok(())
     }
@@ -1079,4 +1954,35 @@ mod test_df {
 
         println!("{:?}", ohlcv);
     }
+
+    #[test]
+    fn test_ohlcv_df_to_csv() -> anyhow::Result<()> {
+        let mut trade_buffer = TradeBuffer::new();
+
+        for i in 0..1000 {
+            trade_buffer.push(
+                i * 1_000_000,
+                "id".to_string(),
+                &OrderSide::Buy,
+                (i * 2) as f64,
+                1.0,
+            );
+        }
+
+        let df = trade_buffer.to_dataframe();
+        let ohlcv = ohlcv_df(&df, 0, 0, 60)?;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ohlcv.csv");
+
+        let rec = ohlcv_df_to_csv(&ohlcv, &path, true)?;
+        assert_eq!(rec, ohlcv.height() as i64);
+
+        let text = std::fs::read_to_string(&path)?;
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("time,open,high,low,close,volume"));
+        println!("{}", text);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,262 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use pyo3::{pyclass, pymethods};
+use rusqlite::{params, Connection};
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::common::{MarketConfig, MicroSec, FLOOR_SEC, SEC};
+
+use super::db_full_path;
+
+/// Bybitのlinear perpetualは8時間ごと(00:00, 08:00, 16:00 UTC)にfundingを精算する
+pub const FUNDING_INTERVAL_SEC: i64 = 8 * 60 * 60;
+
+/// データがない時刻のfunding rate(0.01% / 8h)
+pub const DEFAULT_FUNDING_RATE: Decimal = dec![0.0001];
+
+/// funding_rateテーブル（tradesと同じsqliteファイルに保存する）
+pub struct FundingRateDb {
+    connection: Connection,
+}
+
+impl FundingRateDb {
+    pub fn open(config: &MarketConfig, production: bool) -> anyhow::Result<Self> {
+        let db_path = db_full_path(
+            &config.exchange_name,
+            &config.trade_category,
+            &config.trade_symbol,
+            production,
+        );
+
+        Self::open_path(&db_path)
+    }
+
+    pub fn open_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("funding rate db open error {:?}", path))?;
+
+        let db = Self { connection };
+        db.create_table_if_not_exists()?;
+
+        Ok(db)
+    }
+
+    fn create_table_if_not_exists(&self) -> anyhow::Result<()> {
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS funding_rate (
+            timestamp   INTEGER primary key,
+            rate        NUMBER
+        )",
+            (),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn insert(&self, timestamp: MicroSec, rate: Decimal) -> anyhow::Result<usize> {
+        let count = self.connection.execute(
+            "insert or replace into funding_rate (timestamp, rate) values (?1, ?2)",
+            params![timestamp, rate.to_f64().unwrap_or(0.0)],
+        )?;
+
+        Ok(count)
+    }
+
+    pub fn select_all(&self) -> anyhow::Result<Vec<(MicroSec, Decimal)>> {
+        let mut statement = self
+            .connection
+            .prepare("select timestamp, rate from funding_rate order by timestamp")?;
+
+        let records = statement
+            .query_map([], |row| {
+                let rate: f64 = row.get_unwrap(1);
+                Ok((row.get_unwrap(0), Decimal::from_f64(rate).unwrap_or_default()))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(records)
+    }
+}
+
+/// 精算時刻ごとのfunding rate。データがない時刻はdefault_rateを使う。
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct FundingRateTable {
+    rates: BTreeMap<MicroSec, Decimal>,
+    #[pyo3(get, set)]
+    pub default_rate: Decimal,
+}
+
+#[pymethods]
+impl FundingRateTable {
+    #[new]
+    #[pyo3(signature = (default_rate=DEFAULT_FUNDING_RATE))]
+    pub fn new(default_rate: Decimal) -> Self {
+        Self {
+            rates: BTreeMap::new(),
+            default_rate,
+        }
+    }
+
+    /// funding_rateテーブルから読み込む
+    #[staticmethod]
+    #[pyo3(signature = (market_config, production=false, default_rate=DEFAULT_FUNDING_RATE))]
+    pub fn load(
+        market_config: &MarketConfig,
+        production: bool,
+        default_rate: Decimal,
+    ) -> anyhow::Result<Self> {
+        let db = FundingRateDb::open(market_config, production)?;
+
+        let mut table = Self::new(default_rate);
+        for (timestamp, rate) in db.select_all()? {
+            table.add(timestamp, rate);
+        }
+
+        Ok(table)
+    }
+
+    #[pyo3(signature = (market_config, production=false))]
+    pub fn save(&self, market_config: &MarketConfig, production: bool) -> anyhow::Result<()> {
+        let db = FundingRateDb::open(market_config, production)?;
+
+        for (timestamp, rate) in self.rates.iter() {
+            db.insert(*timestamp, *rate)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, timestamp: MicroSec, rate: Decimal) {
+        self.rates.insert(timestamp, rate);
+    }
+
+    /// timestampの精算に適用されるrate。直前FUNDING_INTERVAL_SEC以内に記録がなければdefault_rate。
+    pub fn rate_at(&self, timestamp: MicroSec) -> Decimal {
+        match self.rates.range(..=timestamp).next_back() {
+            Some((t, rate)) if timestamp - t < SEC(FUNDING_INTERVAL_SEC) => *rate,
+            _ => self.default_rate,
+        }
+    }
+
+    pub fn __len__(&self) -> usize {
+        self.rates.len()
+    }
+}
+
+/// 精算額。正の値はポジションの保有者が支払う(rateが正ならlongが支払い、shortが受け取る)。
+pub fn funding_payment(position: Decimal, mark_price: Decimal, rate: Decimal) -> Decimal {
+    position * mark_price * rate
+}
+
+/// バックテストの時間の進みに合わせて精算時刻を通知する
+#[derive(Debug, Clone)]
+pub struct FundingSimulator {
+    table: FundingRateTable,
+    next_funding_time: MicroSec,
+}
+
+impl FundingSimulator {
+    pub fn new(table: FundingRateTable) -> Self {
+        Self {
+            table,
+            next_funding_time: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.next_funding_time = 0;
+    }
+
+    /// timeまでに過ぎた精算時刻と、その時刻のrateを返す。最初の呼び出しでは次の精算時刻を決めるだけ。
+    pub fn due(&mut self, time: MicroSec) -> Vec<(MicroSec, Decimal)> {
+        let interval = SEC(FUNDING_INTERVAL_SEC);
+
+        if self.next_funding_time == 0 {
+            self.next_funding_time = FLOOR_SEC(time, FUNDING_INTERVAL_SEC) + interval;
+            return vec![];
+        }
+
+        let mut due = vec![];
+        while self.next_funding_time <= time {
+            due.push((self.next_funding_time, self.table.rate_at(self.next_funding_time)));
+            self.next_funding_time += interval;
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod funding_test {
+    use super::*;
+    use crate::common::HHMM;
+
+    const BASE: MicroSec = 1724371200_000_000; // 2024-08-23 00:00:00 UTC
+
+    #[test]
+    fn test_rate_at() {
+        let mut table = FundingRateTable::new(DEFAULT_FUNDING_RATE);
+        table.add(BASE + HHMM(8, 0), dec![0.0003]);
+        table.add(BASE + HHMM(16, 0), dec![-0.0002]);
+
+        assert_eq!(table.rate_at(BASE), DEFAULT_FUNDING_RATE);
+        assert_eq!(table.rate_at(BASE + HHMM(8, 0)), dec![0.0003]);
+        assert_eq!(table.rate_at(BASE + HHMM(16, 0)), dec![-0.0002]);
+        // 記録の8時間後以降はデータなし
+        assert_eq!(table.rate_at(BASE + HHMM(24, 0)), DEFAULT_FUNDING_RATE);
+    }
+
+    #[test]
+    fn test_funding_24h_long() {
+        // 00:30から1時間ごとに約定があり、1BTCのlongを60000で持ち続ける
+        let mut simulator = FundingSimulator::new(FundingRateTable::new(DEFAULT_FUNDING_RATE));
+        let position = dec![1.0];
+        let mark_price = dec![60000.0];
+
+        let mut paid = dec![0.0];
+        let mut count = 0;
+        for h in 0..24 {
+            for (_time, rate) in simulator.due(BASE + HHMM(h, 30)) {
+                paid += funding_payment(position, mark_price, rate);
+                count += 1;
+            }
+        }
+
+        // 08:00, 16:00の2回(24:00は範囲外)
+        assert_eq!(count, 2);
+        assert_eq!(paid, dec![12.0]);
+
+        // 翌日00:30で3回目
+        let due = simulator.due(BASE + HHMM(24, 30));
+        assert_eq!(due, vec![(BASE + HHMM(24, 0), DEFAULT_FUNDING_RATE)]);
+    }
+
+    #[test]
+    fn test_funding_short_receives() {
+        assert_eq!(funding_payment(dec![-2.0], dec![100.0], dec![0.0001]), dec![-0.02]);
+        // 負のrateではshortが支払う
+        assert_eq!(funding_payment(dec![-2.0], dec![100.0], dec![-0.0001]), dec![0.02]);
+    }
+
+    #[test]
+    fn test_funding_rate_db() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = FundingRateDb::open_path(&dir.path().join("funding.db"))?;
+
+        db.insert(BASE, dec![0.0001])?;
+        db.insert(BASE + HHMM(8, 0), dec![-0.00025])?;
+
+        let records = db.select_all()?;
+        assert_eq!(records, vec![(BASE, dec![0.0001]), (BASE + HHMM(8, 0), dec![-0.00025])]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use anyhow::anyhow;
+use polars::frame::DataFrame;
+use polars::prelude::{ChunkAgg as _, NamedFrom as _};
+use polars::series::Series;
+use pyo3::{pyclass, pymethods};
+use pyo3_polars::PyDataFrame;
+
+use crate::common::{time_string, MicroSec, OrderSide};
+
+use super::{end_time_df, select_df, start_time_df, KEY};
+
+/// 価格×時間の約定量のヒストグラム(time_bins × price_bins)
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct HeatMap {
+    #[pyo3(get)]
+    pub start_time: MicroSec,
+    #[pyo3(get)]
+    pub end_time: MicroSec,
+    #[pyo3(get)]
+    pub price_min: f64,
+    #[pyo3(get)]
+    pub price_max: f64,
+    #[pyo3(get)]
+    pub time_bins: usize,
+    #[pyo3(get)]
+    pub price_bins: usize,
+    /// [time_bin * price_bins + price_bin]
+    buy_volume: Vec<f64>,
+    sell_volume: Vec<f64>,
+}
+
+#[pymethods]
+impl HeatMap {
+    #[getter]
+    pub fn get_df(&self) -> anyhow::Result<PyDataFrame> {
+        Ok(PyDataFrame(self.to_dataframe()?))
+    }
+
+    pub fn _repr_html_(&self) -> String {
+        let max_volume = self
+            .buy_volume
+            .iter()
+            .zip(self.sell_volume.iter())
+            .map(|(b, s)| b + s)
+            .fold(0.0, f64::max);
+
+        let mut html = format!(
+            "<table style=\"border-collapse:collapse\"><caption>{} - {} / price {} - {}</caption>",
+            time_string(self.start_time),
+            time_string(self.end_time),
+            self.price_min,
+            self.price_max
+        );
+
+        // 高い価格を上に表示する
+        for p in (0..self.price_bins).rev() {
+            html += "<tr>";
+            for t in 0..self.time_bins {
+                let buy = self.buy_volume[self.index(t, p)];
+                let sell = self.sell_volume[self.index(t, p)];
+
+                let alpha = if max_volume == 0.0 {
+                    0.0
+                } else {
+                    (buy + sell) / max_volume
+                };
+                let color = if sell < buy { "0,160,0" } else { "200,0,0" };
+
+                html += &format!(
+                    "<td title=\"buy {} / sell {}\" style=\"width:6px;height:6px;padding:0;background:rgba({},{:.3})\"></td>",
+                    buy, sell, color, alpha
+                );
+            }
+            html += "</tr>";
+        }
+        html += "</table>";
+
+        html
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "HeatMap({}x{}, {} - {})",
+            self.time_bins,
+            self.price_bins,
+            time_string(self.start_time),
+            time_string(self.end_time)
+        )
+    }
+}
+
+impl HeatMap {
+    /// trade dfのstart_time〜end_time(0は全範囲)を集計する
+    pub fn from_df(
+        df: &DataFrame,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        time_bins: usize,
+        price_bins: usize,
+    ) -> anyhow::Result<Self> {
+        if time_bins == 0 || price_bins == 0 {
+            return Err(anyhow!(
+                "bins must be positive time_bins={} price_bins={}",
+                time_bins,
+                price_bins
+            ));
+        }
+
+        let df = select_df(df, start_time, end_time);
+
+        let start_time = if start_time == 0 {
+            start_time_df(&df).unwrap_or(0)
+        } else {
+            start_time
+        };
+        let end_time = if end_time == 0 {
+            end_time_df(&df).unwrap_or(0) + 1
+        } else {
+            end_time
+        };
+
+        let price = df.column(KEY::price)?.f64()?;
+        let price_min = price.min().unwrap_or(0.0);
+        let price_max = price.max().unwrap_or(0.0);
+
+        let mut heat_map = Self {
+            start_time,
+            end_time,
+            price_min,
+            price_max,
+            time_bins,
+            price_bins,
+            buy_volume: vec![0.0; time_bins * price_bins],
+            sell_volume: vec![0.0; time_bins * price_bins],
+        };
+
+        let timestamp = df.column(KEY::timestamp)?.i64()?;
+        let size = df.column(KEY::size)?.f64()?;
+        let order_side = df.column(KEY::order_side)?.str()?;
+        let buy = OrderSide::Buy.to_string();
+
+        for i in 0..df.height() {
+            if let (Some(t), Some(p), Some(s), Some(side)) =
+                (timestamp.get(i), price.get(i), size.get(i), order_side.get(i))
+            {
+                heat_map.add(t, p, s, side == buy);
+            }
+        }
+
+        Ok(heat_map)
+    }
+
+    fn index(&self, time_bin: usize, price_bin: usize) -> usize {
+        time_bin * self.price_bins + price_bin
+    }
+
+    fn time_bin(&self, time: MicroSec) -> Option<usize> {
+        if time < self.start_time || self.end_time <= time {
+            return None;
+        }
+
+        let width = (self.end_time - self.start_time) as i128;
+        let bin = ((time - self.start_time) as i128 * self.time_bins as i128 / width) as usize;
+
+        Some(bin.min(self.time_bins - 1))
+    }
+
+    fn price_bin(&self, price: f64) -> usize {
+        if self.price_max <= self.price_min {
+            return 0;
+        }
+
+        let bin = ((price - self.price_min) / (self.price_max - self.price_min)
+            * self.price_bins as f64) as usize;
+
+        // price_maxは最上段に含める
+        bin.min(self.price_bins - 1)
+    }
+
+    pub fn add(&mut self, time: MicroSec, price: f64, size: f64, is_buy: bool) {
+        let time_bin = match self.time_bin(time) {
+            Some(bin) => bin,
+            None => return,
+        };
+        let index = self.index(time_bin, self.price_bin(price));
+
+        if is_buy {
+            self.buy_volume[index] += size;
+        } else {
+            self.sell_volume[index] += size;
+        }
+    }
+
+    /// time_bin, price_bin, buy_volume, sell_volumeの全セル(time_bins × price_bins行)
+    pub fn to_dataframe(&self) -> anyhow::Result<DataFrame> {
+        let cells = self.time_bins * self.price_bins;
+
+        let time_bin: Vec<i64> = (0..cells).map(|i| (i / self.price_bins) as i64).collect();
+        let price_bin: Vec<i64> = (0..cells).map(|i| (i % self.price_bins) as i64).collect();
+
+        let df = DataFrame::new(vec![
+            Series::new("time_bin", time_bin),
+            Series::new("price_bin", price_bin),
+            Series::new(KEY::buy_volume, self.buy_volume.clone()),
+            Series::new(KEY::sell_volume, self.sell_volume.clone()),
+        ])?;
+
+        Ok(df)
+    }
+}
+
+#[cfg(test)]
+mod heatmap_test {
+    use super::*;
+    use crate::common::{LogStatus, Trade, SEC};
+    use crate::db::TradeBuffer;
+    use rust_decimal::prelude::FromPrimitive as _;
+    use rust_decimal::Decimal;
+
+    fn make_df() -> DataFrame {
+        let mut buffer = TradeBuffer::new();
+
+        // (sec, price, size, side)
+        let trades = [
+            (0, 100.0, 1.0, OrderSide::Buy),
+            (1, 100.0, 2.0, OrderSide::Sell),
+            (5, 110.0, 3.0, OrderSide::Buy),
+            (9, 120.0, 4.0, OrderSide::Sell),
+        ];
+
+        for (i, (sec, price, size, side)) in trades.iter().enumerate() {
+            buffer.push_trades(vec![Trade::new(
+                SEC(*sec),
+                *side,
+                Decimal::from_f64(*price).unwrap(),
+                Decimal::from_f64(*size).unwrap(),
+                LogStatus::FixArchiveBlock,
+                &i.to_string(),
+            )]);
+        }
+
+        buffer.to_dataframe()
+    }
+
+    #[test]
+    fn test_heat_map_cells() -> anyhow::Result<()> {
+        let heat_map = HeatMap::from_df(&make_df(), SEC(0), SEC(10), 2, 2)?;
+
+        assert_eq!(heat_map.price_min, 100.0);
+        assert_eq!(heat_map.price_max, 120.0);
+
+        let df = heat_map.to_dataframe()?;
+        assert_eq!(df.height(), 4);
+
+        let buy: Vec<f64> = df.column(KEY::buy_volume)?.f64()?.into_iter().flatten().collect();
+        let sell: Vec<f64> = df.column(KEY::sell_volume)?.f64()?.into_iter().flatten().collect();
+
+        // [t0p0, t0p1, t1p0, t1p1]
+        assert_eq!(buy, vec![1.0, 0.0, 0.0, 3.0]);
+        assert_eq!(sell, vec![2.0, 0.0, 0.0, 4.0]);
+
+        // 範囲外の約定は数えない
+        let heat_map = HeatMap::from_df(&make_df(), SEC(0), SEC(5), 1, 1)?;
+        let df = heat_map.to_dataframe()?;
+        assert_eq!(df.column(KEY::buy_volume)?.f64()?.get(0), Some(1.0));
+        assert_eq!(df.column(KEY::sell_volume)?.f64()?.get(0), Some(2.0));
+
+        assert!(HeatMap::from_df(&make_df(), 0, 0, 0, 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_heat_map_html() -> anyhow::Result<()> {
+        let heat_map = HeatMap::from_df(&make_df(), 0, 0, 3, 4)?;
+        let html = heat_map._repr_html_();
+
+        assert_eq!(html.matches("<tr>").count(), 4);
+        assert_eq!(html.matches("<td").count(), 12);
+
+        Ok(())
+    }
+}
@@ -5,11 +5,29 @@ pub mod df;
 pub mod fs;
 pub mod archive;
 pub mod tradedf;
+pub mod avro;
+pub mod spread;
+pub mod stream;
+pub mod heatmap;
+pub mod csvimport;
+pub mod funding;
+pub mod queue;
+pub mod balance;
+pub mod ohlcvb;
 
 pub use sqlite::*;
 pub use df::*;
 pub use fs::*;
 pub use archive::*;
 pub use tradedf::*;
+pub use avro::*;
+pub use spread::*;
+pub use stream::*;
+pub use heatmap::*;
+pub use csvimport::*;
+pub use funding::*;
+pub use queue::*;
+pub use balance::*;
+pub use ohlcvb::*;
 
 
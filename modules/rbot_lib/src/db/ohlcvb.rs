@@ -0,0 +1,344 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use anyhow::anyhow;
+use polars::frame::DataFrame;
+use polars::prelude::NamedFrom as _;
+use polars::series::Series;
+use pyo3::{pyclass, pymethods};
+use pyo3_polars::PyDataFrame;
+use rust_decimal::prelude::ToPrimitive as _;
+
+use crate::common::{time_string, MicroSec, SEC};
+
+use super::{convert_timems_to_datetime, SpreadRecord, KEY};
+
+pub const OPEN_SPREAD: &str = "open_spread";
+pub const CLOSE_SPREAD: &str = "close_spread";
+pub const MIN_SPREAD: &str = "min_spread";
+pub const MAX_SPREAD: &str = "max_spread";
+
+/// OHLCVに足の期間中のspread(best_ask - best_bid)を加えた足。
+/// spread_logの記録が始まる前の足はspreadがNone。
+#[derive(Debug, Clone, PartialEq)]
+pub struct OhlcvbBar {
+    pub timestamp: MicroSec,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub open_spread: Option<f64>,
+    pub close_spread: Option<f64>,
+    pub min_spread: Option<f64>,
+    pub max_spread: Option<f64>,
+}
+
+/// ohlcv(timestamp=足の開始時刻)にspread_logを重ねる。spreadsは時刻順。
+/// 足の開始時点のspreadは直前の記録を引き継ぐ(板の状態は次の記録まで続くとみなす)。
+pub fn ohlcvb_bars(ohlcv: &DataFrame, spreads: &[SpreadRecord], window_sec: i64) -> anyhow::Result<Vec<OhlcvbBar>> {
+    let timestamp = ohlcv.column(KEY::timestamp)?.i64()?;
+    let open = ohlcv.column(KEY::open)?.f64()?;
+    let high = ohlcv.column(KEY::high)?.f64()?;
+    let low = ohlcv.column(KEY::low)?.f64()?;
+    let close = ohlcv.column(KEY::close)?.f64()?;
+    let volume = ohlcv.column(KEY::volume)?.f64()?;
+
+    let spread = |r: &SpreadRecord| (r.best_ask - r.best_bid).to_f64().unwrap_or(0.0);
+
+    let mut bars = vec![];
+    let mut current: Option<f64> = None;
+    let mut i = 0;
+
+    for row in 0..ohlcv.height() {
+        let start = timestamp.get(row).unwrap_or(0);
+        let end = start + SEC(window_sec);
+
+        // 足の開始前の記録
+        while i < spreads.len() && spreads[i].timestamp < start {
+            current = Some(spread(&spreads[i]));
+            i += 1;
+        }
+
+        let open_spread = current.or_else(|| spreads.get(i).filter(|r| r.timestamp < end).map(spread));
+        let mut min_spread = open_spread;
+        let mut max_spread = open_spread;
+
+        while i < spreads.len() && spreads[i].timestamp < end {
+            let s = spread(&spreads[i]);
+            min_spread = Some(min_spread.map_or(s, |m| m.min(s)));
+            max_spread = Some(max_spread.map_or(s, |m| m.max(s)));
+            current = Some(s);
+            i += 1;
+        }
+
+        bars.push(OhlcvbBar {
+            timestamp: start,
+            open: open.get(row).unwrap_or(0.0),
+            high: high.get(row).unwrap_or(0.0),
+            low: low.get(row).unwrap_or(0.0),
+            close: close.get(row).unwrap_or(0.0),
+            volume: volume.get(row).unwrap_or(0.0),
+            open_spread,
+            close_spread: current,
+            min_spread,
+            max_spread,
+        });
+    }
+
+    Ok(bars)
+}
+
+/// columns: timestamp, open, high, low, close, volume, open_spread, close_spread, min_spread, max_spread
+pub fn ohlcvb_df(ohlcv: &DataFrame, spreads: &[SpreadRecord], window_sec: i64) -> anyhow::Result<DataFrame> {
+    if spreads.is_empty() {
+        return Err(anyhow!("no spread log in range, start spread logging first"));
+    }
+
+    let bars = ohlcvb_bars(ohlcv, spreads, window_sec)?;
+
+    let df = DataFrame::new(vec![
+        Series::new(KEY::timestamp, bars.iter().map(|b| b.timestamp).collect::<Vec<_>>()),
+        Series::new(KEY::open, bars.iter().map(|b| b.open).collect::<Vec<_>>()),
+        Series::new(KEY::high, bars.iter().map(|b| b.high).collect::<Vec<_>>()),
+        Series::new(KEY::low, bars.iter().map(|b| b.low).collect::<Vec<_>>()),
+        Series::new(KEY::close, bars.iter().map(|b| b.close).collect::<Vec<_>>()),
+        Series::new(KEY::volume, bars.iter().map(|b| b.volume).collect::<Vec<_>>()),
+        Series::new(OPEN_SPREAD, bars.iter().map(|b| b.open_spread).collect::<Vec<_>>()),
+        Series::new(CLOSE_SPREAD, bars.iter().map(|b| b.close_spread).collect::<Vec<_>>()),
+        Series::new(MIN_SPREAD, bars.iter().map(|b| b.min_spread).collect::<Vec<_>>()),
+        Series::new(MAX_SPREAD, bars.iter().map(|b| b.max_spread).collect::<Vec<_>>()),
+    ])?;
+
+    Ok(df)
+}
+
+/// ohlcvb_dfの結果。Jupyterでは_repr_html_で終値とspreadの幅を描く。
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct Ohlcvb {
+    #[pyo3(get)]
+    pub start_time: MicroSec,
+    #[pyo3(get)]
+    pub end_time: MicroSec,
+    #[pyo3(get)]
+    pub window_sec: i64,
+    df: DataFrame,
+}
+
+const CHART_WIDTH: f64 = 600.0;
+const PRICE_HEIGHT: f64 = 120.0;
+const SPREAD_HEIGHT: f64 = 60.0;
+
+#[pymethods]
+impl Ohlcvb {
+    #[getter]
+    pub fn get_df(&self) -> anyhow::Result<PyDataFrame> {
+        let mut df = self.df.clone();
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
+    }
+
+    /// 上段は高値-安値と終値、下段はmin_spread〜max_spreadを塗りつぶしてclose_spreadを線で描く
+    pub fn _repr_html_(&self) -> String {
+        let caption = format!(
+            "<div>{} - {} / {}[sec] x {}</div>",
+            time_string(self.start_time),
+            time_string(self.end_time),
+            self.window_sec,
+            self.df.height()
+        );
+
+        let bars = match self.bars() {
+            Ok(bars) if !bars.is_empty() => bars,
+            _ => return caption,
+        };
+
+        let step = CHART_WIDTH / bars.len() as f64;
+        let x = |i: usize| step * (i as f64 + 0.5);
+
+        let price_min = bars.iter().map(|b| b.low).fold(f64::MAX, f64::min);
+        let price_max = bars.iter().map(|b| b.high).fold(f64::MIN, f64::max);
+        let price_y = |p: f64| scale(p, price_min, price_max, PRICE_HEIGHT);
+
+        let spread_max = bars.iter().filter_map(|b| b.max_spread).fold(0.0, f64::max);
+        let spread_y = |s: f64| PRICE_HEIGHT + scale(s, 0.0, spread_max, SPREAD_HEIGHT);
+
+        let mut svg = format!(
+            "<svg width=\"{}\" height=\"{}\" style=\"background:#fff\">",
+            CHART_WIDTH,
+            PRICE_HEIGHT + SPREAD_HEIGHT
+        );
+
+        let mut close_line = vec![];
+        for (i, b) in bars.iter().enumerate() {
+            svg += &format!(
+                "<line x1=\"{x:.1}\" y1=\"{:.1}\" x2=\"{x:.1}\" y2=\"{:.1}\" stroke=\"#bbb\"/>",
+                price_y(b.high),
+                price_y(b.low),
+                x = x(i)
+            );
+            close_line.push(format!("{:.1},{:.1}", x(i), price_y(b.close)));
+        }
+        svg += &format!(
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"#1f77b4\"/>",
+            close_line.join(" ")
+        );
+
+        // spreadのある足だけで帯を作る
+        let spread_bars: Vec<(usize, &OhlcvbBar)> = bars.iter().enumerate().filter(|(_, b)| b.max_spread.is_some()).collect();
+        if !spread_bars.is_empty() {
+            let upper = spread_bars.iter().map(|(i, b)| format!("{:.1},{:.1}", x(*i), spread_y(b.max_spread.unwrap())));
+            let lower = spread_bars.iter().rev().map(|(i, b)| format!("{:.1},{:.1}", x(*i), spread_y(b.min_spread.unwrap())));
+            svg += &format!(
+                "<polygon points=\"{}\" fill=\"rgba(255,127,14,0.3)\" stroke=\"none\"/>",
+                upper.chain(lower).collect::<Vec<_>>().join(" ")
+            );
+
+            let close_spread: Vec<String> = spread_bars
+                .iter()
+                .map(|(i, b)| format!("{:.1},{:.1}", x(*i), spread_y(b.close_spread.unwrap())))
+                .collect();
+            svg += &format!(
+                "<polyline points=\"{}\" fill=\"none\" stroke=\"#ff7f0e\"/>",
+                close_spread.join(" ")
+            );
+        }
+
+        svg += "</svg>";
+
+        caption + &svg
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "Ohlcvb({} bars, {} - {})",
+            self.df.height(),
+            time_string(self.start_time),
+            time_string(self.end_time)
+        )
+    }
+}
+
+impl Ohlcvb {
+    pub fn new(start_time: MicroSec, end_time: MicroSec, window_sec: i64, df: DataFrame) -> Self {
+        Self {
+            start_time,
+            end_time,
+            window_sec,
+            df,
+        }
+    }
+
+    pub fn df(&self) -> &DataFrame {
+        &self.df
+    }
+
+    fn bars(&self) -> anyhow::Result<Vec<OhlcvbBar>> {
+        let timestamp = self.df.column(KEY::timestamp)?.i64()?;
+        let column = |name: &str| -> anyhow::Result<Vec<Option<f64>>> {
+            Ok(self.df.column(name)?.f64()?.into_iter().collect())
+        };
+
+        let (open, high, low, close, volume) = (
+            column(KEY::open)?,
+            column(KEY::high)?,
+            column(KEY::low)?,
+            column(KEY::close)?,
+            column(KEY::volume)?,
+        );
+        let (open_spread, close_spread, min_spread, max_spread) = (
+            column(OPEN_SPREAD)?,
+            column(CLOSE_SPREAD)?,
+            column(MIN_SPREAD)?,
+            column(MAX_SPREAD)?,
+        );
+
+        Ok((0..self.df.height())
+            .map(|i| OhlcvbBar {
+                timestamp: timestamp.get(i).unwrap_or(0),
+                open: open[i].unwrap_or(0.0),
+                high: high[i].unwrap_or(0.0),
+                low: low[i].unwrap_or(0.0),
+                close: close[i].unwrap_or(0.0),
+                volume: volume[i].unwrap_or(0.0),
+                open_spread: open_spread[i],
+                close_spread: close_spread[i],
+                min_spread: min_spread[i],
+                max_spread: max_spread[i],
+            })
+            .collect())
+    }
+}
+
+/// 値をheightの高さに収める(上が大きい値)
+fn scale(v: f64, min: f64, max: f64, height: f64) -> f64 {
+    if max <= min {
+        return height / 2.0;
+    }
+
+    height - (v - min) / (max - min) * height
+}
+
+#[cfg(test)]
+mod ohlcvb_test {
+    use super::*;
+    use crate::db::SpreadDb;
+    use rust_decimal_macros::dec;
+
+    fn ohlcv(timestamps: Vec<MicroSec>) -> DataFrame {
+        let n = timestamps.len();
+        DataFrame::new(vec![
+            Series::new(KEY::timestamp, timestamps),
+            Series::new(KEY::open, vec![100.0; n]),
+            Series::new(KEY::high, vec![110.0; n]),
+            Series::new(KEY::low, vec![90.0; n]),
+            Series::new(KEY::close, vec![105.0; n]),
+            Series::new(KEY::volume, vec![1.0; n]),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ohlcvb_df() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = SpreadDb::open_path(&dir.path().join("spread.db"))?;
+
+        // 1本目: spread 1 -> 3 -> 2, 2本目: 記録なし, 3本目: 0.5
+        db.insert(&SpreadRecord::new(SEC(60), dec![100.0], dec![101.0]))?;
+        db.insert(&SpreadRecord::new(SEC(70), dec![100.0], dec![103.0]))?;
+        db.insert(&SpreadRecord::new(SEC(110), dec![100.0], dec![102.0]))?;
+        db.insert(&SpreadRecord::new(SEC(190), dec![100.0], dec![100.5]))?;
+        let spreads = db.select(0, 0)?;
+
+        let df = ohlcvb_df(&ohlcv(vec![SEC(0), SEC(60), SEC(120), SEC(180)]), &spreads, 60)?;
+        assert_eq!(df.shape(), (4, 10));
+
+        // 記録が始まる前の足
+        assert_eq!(df.column(OPEN_SPREAD)?.f64()?.get(0), None);
+        assert_eq!(df.column(MAX_SPREAD)?.f64()?.get(0), None);
+
+        assert_eq!(df.column(OPEN_SPREAD)?.f64()?.get(1), Some(1.0));
+        assert_eq!(df.column(CLOSE_SPREAD)?.f64()?.get(1), Some(2.0));
+        assert_eq!(df.column(MIN_SPREAD)?.f64()?.get(1), Some(1.0));
+        assert_eq!(df.column(MAX_SPREAD)?.f64()?.get(1), Some(3.0));
+
+        // 記録のない足は直前のspreadを引き継ぐ
+        assert_eq!(df.column(OPEN_SPREAD)?.f64()?.get(2), Some(2.0));
+        assert_eq!(df.column(CLOSE_SPREAD)?.f64()?.get(2), Some(2.0));
+
+        assert_eq!(df.column(OPEN_SPREAD)?.f64()?.get(3), Some(2.0));
+        assert_eq!(df.column(CLOSE_SPREAD)?.f64()?.get(3), Some(0.5));
+        assert_eq!(df.column(MIN_SPREAD)?.f64()?.get(3), Some(0.5));
+
+        let ohlcvb = Ohlcvb::new(0, SEC(240), 60, df);
+        assert!(ohlcvb._repr_html_().contains("<polygon"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ohlcvb_df_requires_spread_log() {
+        assert!(ohlcvb_df(&ohlcv(vec![SEC(0)]), &[], 60).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::common::MarketConfig;
+
+static DOWNLOAD_QUEUE: Lazy<DownloadQueue> = Lazy::new(DownloadQueue::new);
+
+/// 同じ(exchange, category, symbol)のダウンロードを同時に1つだけ実行する。
+/// 実行中に呼ばれた場合は先のダウンロードの終了を待つ。待った場合(DownloadSlot.waited)は
+/// 呼び出し側で必要な範囲がすでにあるかを確認し、あればダウンロードしない。
+#[derive(Debug, Default)]
+pub struct DownloadQueue {
+    running: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl DownloadQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn global() -> &'static DownloadQueue {
+        &DOWNLOAD_QUEUE
+    }
+
+    pub fn key(config: &MarketConfig) -> String {
+        format!(
+            "{}/{}/{}",
+            config.exchange_name, config.trade_category, config.trade_symbol
+        )
+    }
+
+    /// keyのダウンロード枠を取る。実行中のダウンロードがあれば終わるまで待つ(waited=true)。
+    /// 枠は戻り値をdropすると解放される。
+    pub async fn acquire(&self, key: &str) -> DownloadSlot {
+        let slot = {
+            let mut running = self.running.lock().await;
+            running
+                .entry(key.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(())))
+                .clone()
+        };
+
+        match slot.clone().try_lock_owned() {
+            Ok(guard) => DownloadSlot {
+                _guard: guard,
+                waited: false,
+            },
+            Err(_) => {
+                log::debug!("download of {} is in progress, wait", key);
+                DownloadSlot {
+                    _guard: slot.lock_owned().await,
+                    waited: true,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DownloadSlot {
+    _guard: OwnedMutexGuard<()>,
+    /// 他のダウンロードの終了を待った
+    pub waited: bool,
+}
+
+#[cfg(test)]
+mod queue_test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_download_queue() {
+        let queue = Arc::new(DownloadQueue::new());
+        let downloaded = Arc::new(AtomicBool::new(false));
+
+        let first = queue.acquire("BYBIT/linear/BTCUSDT").await;
+        assert!(!first.waited);
+
+        // 実行中のダウンロードがあれば終わるまで待つ
+        let handle = {
+            let queue = queue.clone();
+            let downloaded = downloaded.clone();
+
+            tokio::spawn(async move {
+                let slot = queue.acquire("BYBIT/linear/BTCUSDT").await;
+                (slot.waited, downloaded.load(Ordering::SeqCst))
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!handle.is_finished());
+
+        downloaded.store(true, Ordering::SeqCst);
+        drop(first);
+
+        // 待った側は先のダウンロードの結果が見える
+        assert_eq!(handle.await.unwrap(), (true, true));
+
+        // 別のsymbolは待たない
+        let other = queue.acquire("BYBIT/linear/ETHUSDT").await;
+        assert!(!other.waited);
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use polars::prelude::DataFrame;
+use polars::prelude::NamedFrom;
+use polars::series::Series;
+use rusqlite::{params, params_from_iter, Connection};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use tokio::task::JoinHandle;
+
+use crate::common::{MarketConfig, MicroSec, NOW};
+use crate::db::KEY;
+use crate::net::{stop_stream_task, stream_stop_signal, STREAM_STOP_TIMEOUT_SEC};
+
+use super::db_full_path;
+
+pub const SPREAD_LOG_DEFAULT_INTERVAL_MS: u64 = 100;
+
+/// 最良気配(best bid/ask)のスナップショット
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpreadRecord {
+    pub timestamp: MicroSec,
+    pub best_bid: Decimal,
+    pub best_ask: Decimal,
+    pub mid_price: Decimal,
+    pub spread_bps: f64,
+}
+
+impl SpreadRecord {
+    pub fn new(timestamp: MicroSec, best_bid: Decimal, best_ask: Decimal) -> Self {
+        let mid_price = (best_bid + best_ask) / dec![2.0];
+
+        let spread_bps = if mid_price == dec![0.0] {
+            0.0
+        } else {
+            ((best_ask - best_bid) / mid_price * dec![10000.0])
+                .to_f64()
+                .unwrap_or(0.0)
+        };
+
+        Self {
+            timestamp,
+            best_bid,
+            best_ask,
+            mid_price,
+            spread_bps,
+        }
+    }
+}
+
+/// spread_logテーブル（tradesと同じsqliteファイルに保存する）
+pub struct SpreadDb {
+    connection: Connection,
+}
+
+impl SpreadDb {
+    pub fn open(config: &MarketConfig, production: bool) -> anyhow::Result<Self> {
+        let db_path = db_full_path(
+            &config.exchange_name,
+            &config.trade_category,
+            &config.trade_symbol,
+            production,
+        );
+
+        Self::open_path(&db_path)
+    }
+
+    pub fn open_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("spread db open error {:?}", path))?;
+
+        let db = Self { connection };
+        db.create_table_if_not_exists()?;
+
+        Ok(db)
+    }
+
+    fn create_table_if_not_exists(&self) -> anyhow::Result<()> {
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS spread_log (
+            timestamp   INTEGER primary key,
+            best_bid    NUMBER,
+            best_ask    NUMBER,
+            mid_price   NUMBER,
+            spread_bps  NUMBER
+        )",
+            (),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn insert(&self, record: &SpreadRecord) -> anyhow::Result<usize> {
+        let count = self.connection.execute(
+            "insert or replace into spread_log (timestamp, best_bid, best_ask, mid_price, spread_bps)
+                values (?1, ?2, ?3, ?4, ?5)",
+            params![
+                record.timestamp,
+                record.best_bid.to_f64().unwrap_or(0.0),
+                record.best_ask.to_f64().unwrap_or(0.0),
+                record.mid_price.to_f64().unwrap_or(0.0),
+                record.spread_bps,
+            ],
+        )?;
+
+        Ok(count)
+    }
+
+    /// 0以下の時刻は制限なしとして扱う
+    pub fn select(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<Vec<SpreadRecord>> {
+        let mut param: Vec<i64> = vec![];
+        let mut sql =
+            "select timestamp, best_bid, best_ask, mid_price, spread_bps from spread_log"
+                .to_string();
+
+        if 0 < start_time {
+            sql += " where ?1 <= timestamp";
+            param.push(start_time);
+        }
+
+        if 0 < end_time {
+            sql += if 0 < start_time { " and" } else { " where" };
+            sql += &format!(" timestamp < ?{}", param.len() + 1);
+            param.push(end_time);
+        }
+
+        sql += " order by timestamp";
+
+        let mut statement = self.connection.prepare(&sql)?;
+        let records = statement
+            .query_map(params_from_iter(param.iter()), |row| {
+                Ok(SpreadRecord {
+                    timestamp: row.get_unwrap(0),
+                    best_bid: Decimal::from_f64(row.get_unwrap(1)).unwrap_or_default(),
+                    best_ask: Decimal::from_f64(row.get_unwrap(2)).unwrap_or_default(),
+                    mid_price: Decimal::from_f64(row.get_unwrap(3)).unwrap_or_default(),
+                    spread_bps: row.get_unwrap(4),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(records)
+    }
+
+    pub fn select_df(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<DataFrame> {
+        let records = self.select(start_time, end_time)?;
+
+        let timestamp: Vec<i64> = records.iter().map(|r| r.timestamp).collect();
+        let best_bid: Vec<f64> = records.iter().map(|r| r.best_bid.to_f64().unwrap()).collect();
+        let best_ask: Vec<f64> = records.iter().map(|r| r.best_ask.to_f64().unwrap()).collect();
+        let mid_price: Vec<f64> = records.iter().map(|r| r.mid_price.to_f64().unwrap()).collect();
+        let spread_bps: Vec<f64> = records.iter().map(|r| r.spread_bps).collect();
+
+        let df = DataFrame::new(vec![
+            Series::new(KEY::timestamp, timestamp),
+            Series::new("best_bid", best_bid),
+            Series::new("best_ask", best_ask),
+            Series::new("mid_price", mid_price),
+            Series::new("spread_bps", spread_bps),
+        ])?;
+
+        Ok(df)
+    }
+}
+
+/// 一定間隔で最良気配をspread_logへ記録するバックグラウンドタスク。
+/// rusqliteの書き込みはtokioのworkerを塞がないようspawn_blockingで行う。
+#[derive(Debug)]
+pub struct SpreadLogger {
+    pub interval_ms: u64,
+    stop: Option<tokio::sync::watch::Sender<bool>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SpreadLogger {
+    /// `edge_price`は(best_bid, best_ask)を返す。板が空の場合はErrを返せばその回は記録しない。
+    pub fn start<F>(db: SpreadDb, interval_ms: u64, edge_price: F) -> Self
+    where
+        F: Fn() -> anyhow::Result<(Decimal, Decimal)> + Send + 'static,
+    {
+        Self::start_with_clock(db, interval_ms, edge_price, NOW)
+    }
+
+    /// startと同じだが、記録する時刻を`clock`から取る
+    pub fn start_with_clock<F, C>(db: SpreadDb, interval_ms: u64, edge_price: F, clock: C) -> Self
+    where
+        F: Fn() -> anyhow::Result<(Decimal, Decimal)> + Send + 'static,
+        C: Fn() -> MicroSec + Send + 'static,
+    {
+        let (stop_tx, mut stop_rx) = stream_stop_signal();
+        let db = Arc::new(Mutex::new(db));
+
+        let handle = tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(interval_ms));
+
+            loop {
+                tokio::select! {
+                    _ = stop_rx.changed() => {
+                        log::debug!("spread logger stopped");
+                        break;
+                    }
+                    _ = interval.tick() => {
+                        let (bid, ask) = match edge_price() {
+                            Ok(edge) => edge,
+                            Err(e) => {
+                                log::debug!("spread logger skip: {:?}", e);
+                                continue;
+                            }
+                        };
+
+                        let record = SpreadRecord::new(clock(), bid, ask);
+                        let db = db.clone();
+                        let result =
+                            tokio::task::spawn_blocking(move || db.lock().unwrap().insert(&record))
+                                .await;
+
+                        match result {
+                            Ok(Ok(_)) => {}
+                            Ok(Err(e)) => log::error!("spread log insert error {:?}", e),
+                            Err(e) => log::error!("spread log insert task error {:?}", e),
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            interval_ms,
+            stop: Some(stop_tx),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        match &self.handle {
+            Some(h) => !h.is_finished(),
+            None => false,
+        }
+    }
+
+    pub async fn stop(&mut self) {
+        stop_stream_task(self.stop.take(), self.handle.take(), STREAM_STOP_TIMEOUT_SEC).await;
+    }
+}
+
+#[cfg(test)]
+mod spread_test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_spread_record() {
+        let r = SpreadRecord::new(1, dec![99.0], dec![101.0]);
+        assert_eq!(r.mid_price, dec![100.0]);
+        assert_eq!(r.spread_bps, 200.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_spread_logger_rate() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("spread.db");
+
+        // 板の更新列を模擬する(3回目までは板が空)
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let edge_price = move || {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            if n < 3 {
+                return Err(anyhow::anyhow!("board has no data"));
+            }
+            let bid = Decimal::from(100 + n as i64);
+            Ok((bid, bid + dec![1.0]))
+        };
+
+        // tokioの時刻は止めてあるので、sleepで進めた分だけtickする
+        let start: MicroSec = 1_700_000_000_000_000;
+        let origin = tokio::time::Instant::now();
+        let clock = move || start + origin.elapsed().as_micros() as MicroSec;
+
+        let mut logger = SpreadLogger::start_with_clock(SpreadDb::open_path(&path)?, 50, edge_price, clock);
+        assert!(logger.is_running());
+
+        tokio::time::sleep(Duration::from_millis(1025)).await;
+        logger.stop().await;
+        assert!(!logger.is_running());
+
+        // 50ms間隔で1025ms => 0, 50, ..., 1000msの21回(初回tickは即時)
+        let sampled = count.load(Ordering::SeqCst);
+        assert_eq!(sampled, 21);
+
+        let db = SpreadDb::open_path(&path)?;
+        let records = db.select(start, 0)?;
+        assert_eq!(records.len(), 18);
+        assert_eq!(records[0].best_bid, dec![103.0]);
+        assert_eq!(records[0].timestamp, start + 150_000);
+        assert_eq!(records[17].best_bid, dec![120.0]);
+        assert_eq!(records[17].timestamp, start + 1_000_000);
+        assert!(records.windows(2).all(|w| w[1].timestamp - w[0].timestamp == 50_000));
+
+        let df = db.select_df(0, 0)?;
+        assert_eq!(df.shape().0, records.len());
+
+        Ok(())
+    }
+}
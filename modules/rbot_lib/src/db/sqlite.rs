@@ -5,33 +5,127 @@ use anyhow::Context;
 //use anyhow::Result;
 
 use polars::prelude::DataFrame;
+use polars::prelude::DataType;
+use polars::prelude::NamedFrom;
+use polars::prelude::Series;
+use polars::prelude::TimeUnit;
+use pyo3::{pyclass, pymethods};
 use rusqlite::params_from_iter;
 use rusqlite::{params, Connection, Transaction};
 use rust_decimal::prelude::FromPrimitive;
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::path::PathBuf;
-use tokio::task::spawn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
 use tokio::task::JoinHandle;
 
 use crate::common::MarketConfig;
+use crate::common::MarketMessage;
 use crate::common::TimeChunk;
 use crate::common::FLOOR_DAY;
 
 use crossbeam_channel::unbounded;
+use crossbeam_channel::Receiver;
+use crossbeam_channel::RecvTimeoutError;
 use crossbeam_channel::Sender;
 
 use crate::common::LogStatus;
 use crate::common::OrderSide;
 use crate::common::Trade;
 use crate::common::SEC;
-use crate::common::{time_string, MicroSec, CEIL, DAYS, FLOOR_SEC, NOW};
+use crate::common::{date_string, time_string, MicroSec, CEIL, DAYS, FLOOR_SEC, NOW};
 use crate::db::df::TradeBuffer;
 
 use super::db_full_path;
+use super::{read_trades_csv, CsvSchema};
+use super::SpreadDb;
+use super::{spawn_trade_reader, trade_stream, TRADE_STREAM_CHANNEL_SIZE};
 use super::OHLCV_WINDOW_SEC;
 
+/// 書き込みスレッドが停止要求を確認する間隔
+const DB_THREAD_POLL_MS: u64 = 500;
+
+/// import_csvで1トランザクションに書き込む件数
+const IMPORT_CHUNK_SIZE: usize = 10_000;
+
+/// この時間以上約定が空いている日は確定(manifest登録)しない
+const MANIFEST_MAX_GAP: MicroSec = 10 * 60 * 1_000_000;
+
+/// gap_reportでこれより短い空白は無視する
+pub const GAP_REPORT_ALLOW_SIZE: MicroSec = 60 * 1_000_000;
+
+/// gap_reportで欠損件数の推定に使う、空白の前後の約定を数える幅
+const GAP_REPORT_RATE_WINDOW: MicroSec = 10 * 60 * 1_000_000;
+
+/// 2010-01-01T00:00:00Z
+pub const TIMESTAMP_VALID_FROM: MicroSec = 1_262_304_000_000_000;
+/// 2030-01-01T00:00:00Z
+pub const TIMESTAMP_VALID_TO: MicroSec = 1_893_456_000_000_000;
+
+/// start_time <= timestamp < end_time の約定の集計。約定がない場合は価格類が0。
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RangeStats {
+    #[pyo3(get)]
+    pub start_time: MicroSec,
+    #[pyo3(get)]
+    pub end_time: MicroSec,
+    #[pyo3(get)]
+    pub count: i64,
+    #[pyo3(get)]
+    pub min_price: Decimal,
+    #[pyo3(get)]
+    pub max_price: Decimal,
+    #[pyo3(get)]
+    pub mean_price: Decimal,
+    #[pyo3(get)]
+    pub volume: Decimal,
+    #[pyo3(get)]
+    pub vwap: Decimal,
+}
+
+#[pymethods]
+impl RangeStats {
+    pub fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// start_time <= timestamp < end_time の約定の件数内訳。
+/// fixはアーカイブ由来(A)、unfixはWebSocket/REST由来(U, Us)。約定がない場合、時刻は0。
+#[pyclass]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TradeSummary {
+    #[pyo3(get)]
+    pub total_count: u64,
+    #[pyo3(get)]
+    pub buy_count: u64,
+    #[pyo3(get)]
+    pub sell_count: u64,
+    #[pyo3(get)]
+    pub fix_count: u64,
+    #[pyo3(get)]
+    pub unfix_count: u64,
+    #[pyo3(get)]
+    pub first_time: MicroSec,
+    #[pyo3(get)]
+    pub last_time: MicroSec,
+    #[pyo3(get)]
+    pub total_volume: Decimal,
+}
+
+#[pymethods]
+impl TradeSummary {
+    pub fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
 pub fn ohlcv_floor_fix_time(t: MicroSec, unit_sec: i64) -> MicroSec {
     return FLOOR_SEC(t, unit_sec);
 }
@@ -52,7 +146,74 @@ pub struct TradeDb {
     first_ws_message: bool,
 
     tx: Option<Sender<Vec<Trade>>>,
+    /// 書き込みスレッドが落ちても送信側がエラーにならないよう、受信側を保持しておく
+    rx: Option<Receiver<Vec<Trade>>>,
     handle: Option<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+}
+
+/// compress_ticksの結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionStats {
+    pub input_count: i64,
+    pub output_count: i64,
+    pub ratio: f64,
+}
+
+impl CompressionStats {
+    fn new(input_count: i64, output_count: i64) -> Self {
+        let ratio = if input_count == 0 {
+            1.0
+        } else {
+            output_count as f64 / input_count as f64
+        };
+
+        Self {
+            input_count,
+            output_count,
+            ratio,
+        }
+    }
+}
+
+/// "1d 02:03:04"の形式(1日未満は"02:03:04")
+fn duration_string(width: MicroSec) -> String {
+    let sec = width / 1_000_000;
+    let (days, sec) = (sec / 86_400, sec % 86_400);
+    let hms = format!("{:02}:{:02}:{:02}", sec / 3_600, (sec % 3_600) / 60, sec % 60);
+
+    if days == 0 {
+        hms
+    } else {
+        format!("{}d {}", days, hms)
+    }
+}
+
+/// 複数マーケットの空白区間の一覧(exchange, symbol, gap_start, gap_end, duration)
+pub fn gap_chunks_df(gaps: &Vec<(String, String, TimeChunk)>) -> anyhow::Result<DataFrame> {
+    let mut exchanges: Vec<String> = vec![];
+    let mut symbols: Vec<String> = vec![];
+    let mut starts: Vec<MicroSec> = vec![];
+    let mut ends: Vec<MicroSec> = vec![];
+    let mut duration: Vec<String> = vec![];
+
+    for (exchange, symbol, c) in gaps.iter() {
+        exchanges.push(exchange.clone());
+        symbols.push(symbol.clone());
+        starts.push(c.start);
+        ends.push(c.end);
+        duration.push(duration_string(c.end - c.start));
+    }
+
+    let df = DataFrame::new(vec![
+        Series::new("exchange", exchanges),
+        Series::new("symbol", symbols),
+        Series::new("gap_start", starts).cast(&DataType::Datetime(TimeUnit::Microseconds, None))?,
+        Series::new("gap_end", ends).cast(&DataType::Datetime(TimeUnit::Microseconds, None))?,
+        Series::new("duration", duration),
+    ])?;
+
+    Ok(df)
 }
 
 impl TradeDb {
@@ -79,6 +240,7 @@ impl TradeDb {
         let sql = r#"delete from trades where $1 <= timestamp and timestamp < $2"#;
 
         let result = tx.execute(sql, params![start_time, end_time])?;
+        Self::invalidate_manifest_tx(tx, start_time, end_time)?;
 
         Ok(result as i64)
     }
@@ -217,6 +379,20 @@ impl TradeDb {
         Ok(insert_len as i64)
     }
 
+    /// 取引所のUIなどからダウンロードした約定履歴CSVを取り込む。戻り値は取り込んだ件数。
+    pub fn import_csv(&mut self, path: &Path, schema: CsvSchema) -> anyhow::Result<u64> {
+        let trades = read_trades_csv(path, &schema)?;
+
+        let mut count: u64 = 0;
+        for chunk in trades.chunks(IMPORT_CHUNK_SIZE) {
+            count += self.insert_records(&chunk.to_vec())? as u64;
+        }
+
+        log::info!("import_csv {:?} {} records", path, count);
+
+        Ok(count)
+    }
+
     pub fn is_wal_mode(name: &str) -> anyhow::Result<bool> {
         let conn = Connection::open(name.to_string())?;
 
@@ -283,36 +459,87 @@ impl TradeDb {
 
             connection: conn,
             tx: None,
+            rx: None,
             handle: None,
+            stop: Arc::new(AtomicBool::new(false)),
         };
 
         if create_new {
             db.create_table_if_not_exists()?;
         }
+        db.create_manifest_table_if_not_exists()?;
 
         Ok(db)
     }
 
     pub fn open_channel(&mut self) -> anyhow::Result<Sender<Vec<Trade>>> {
-        // check if the thread is already started
-        // check self.tx is valid and return clone of self.tx
         log::debug!("start_thread");
-        if self.tx.is_some() {
+
+        if self.tx.is_none() || self.rx.is_none() {
+            let (tx, rx) = unbounded();
+            self.tx = Some(tx);
+            self.rx = Some(rx);
+        }
+
+        if self.is_running() {
             log::info!("DB Thread is already started, reuse tx");
-            return Ok(self.tx.clone().unwrap().clone());
+        } else {
+            if self.handle.is_some() {
+                log::error!("DB insert thread is not running, restart it");
+            }
+            self.spawn_insert_thread();
+        }
+
+        return Ok(self.tx.clone().unwrap());
+    }
+
+    /// 書き込みスレッドが動作中かどうか
+    pub fn is_running(&self) -> bool {
+        match &self.handle {
+            Some(handle) => !handle.is_finished(),
+            None => false,
         }
+    }
+
+    /// 書き込みスレッドを新しいDB接続で起動し直す。
+    /// channelはそのまま使うので、open_channelで取得済みのSenderも引き続き使える。
+    pub fn restart_thread(&mut self) -> anyhow::Result<()> {
+        // 動作中のスレッドはrecvのタイムアウトで停止フラグを見て終了する
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle = None;
 
-        let (tx, rx) = unbounded();
+        self.open_channel()?;
+
+        Ok(())
+    }
 
+    fn spawn_insert_thread(&mut self) {
         let config = self.config.clone();
         let production = self.production;
-        self.tx = Some(tx);
+        let rx = self.rx.clone().unwrap();
+
+        self.stop = Arc::new(AtomicBool::new(false));
+        let stop = self.stop.clone();
+
+        let handle = spawn_blocking(move || {
+            let mut db = match TradeDb::open(&config, production) {
+                Ok(db) => db,
+                Err(e) => {
+                    log::error!("DB insert thread: open error {:?}", e);
+                    return;
+                }
+            };
 
-        let handle = spawn(async move {
-            let mut db = TradeDb::open(&config, production).unwrap();
-            let rx = rx; // Move rx into the closure's environment
             loop {
-                match rx.recv() {
+                if stop.load(Ordering::SeqCst) {
+                    log::debug!("DB insert thread: stopped");
+                    break;
+                }
+
+                match rx.recv_timeout(std::time::Duration::from_millis(DB_THREAD_POLL_MS)) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        continue;
+                    }
                     Ok(mut trades) => {
                         if db.first_ws_message {
                             if trades.len() != 0
@@ -325,13 +552,40 @@ impl TradeDb {
                             }
                         }
 
-                        let result = db.insert_records(&trades);
+                        let mut result = db.insert_records(&trades);
+
+                        // 接続を開き直して一度だけやり直す
+                        if result.is_err() {
+                            log::error!("insert error, reopen db and retry {:?}", result);
+
+                            match TradeDb::open(&config, production) {
+                                Ok(new_db) => {
+                                    db.connection = new_db.connection;
+                                    result = db.insert_records(&trades);
+                                }
+                                Err(e) => {
+                                    log::error!("DB insert thread: reopen error {:?}", e);
+                                    return;
+                                }
+                            }
+                        }
 
                         if result.is_err() {
                             log::error!("insert error {:?}", result);
                             continue;
                         }
                         log::debug!("recv trades: {}", trades.len());
+
+                        // REST(download_latest)とWSの重複区間を掃除する
+                        if trades.len() != 0 && trades[0].status == LogStatus::UnFixStart {
+                            let start_time = trades[0].time;
+                            let end_time = trades[trades.len() - 1].time + 1;
+
+                            let r = db.dedup_by_time_and_price(start_time, end_time);
+                            if r.is_err() {
+                                log::error!("dedup error {:?}", r);
+                            }
+                        }
                     }
                     Err(e) => {
                         log::error!("recv error(sender program died?) {:?}", e);
@@ -342,8 +596,11 @@ impl TradeDb {
         });
 
         self.handle = Some(handle);
+    }
 
-        return Ok(self.tx.clone().unwrap());
+    /// 同じDBファイル上のspread_logテーブルを開く
+    pub fn open_spread_db(&self) -> anyhow::Result<SpreadDb> {
+        SpreadDb::open(&self.config, self.production)
     }
 
     /// check if database file is exsit
@@ -369,6 +626,147 @@ impl TradeDb {
             (),
         )?;
 
+        self.create_manifest_table_if_not_exists()?;
+
+        Ok(())
+    }
+
+    /// 確定済みの日(validate_by_dateがtrue)を記録するテーブル
+    fn create_manifest_table_if_not_exists(&self) -> anyhow::Result<()> {
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS manifest (
+            date        INTEGER primary key,
+            fixed_at    INTEGER
+        )",
+            (),
+        )?;
+
+        Ok(())
+    }
+
+    /// 全データとmanifestを削除する
+    pub fn drop_table(&mut self) -> anyhow::Result<()> {
+        log::warn!("drop table {:?}", self.config.trade_symbol);
+
+        let tx = self.begin_transaction()?;
+        tx.execute("delete from trades", ())?;
+        tx.execute("delete from manifest", ())?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// その日のデータが確定しているか確認する。確定している場合はmanifestへ記録し、次回以降はDBを走査しない。
+    /// 確定の条件: 過去の日で、未確定(U, Us, V)のレコードがなく、MANIFEST_MAX_GAP以上の空白がない。
+    pub fn validate_by_date(&self, date: MicroSec) -> anyhow::Result<bool> {
+        let date = FLOOR_DAY(date);
+
+        if self.is_fixed_day(date)? {
+            return Ok(true);
+        }
+
+        let end = date + DAYS(1);
+        if FLOOR_DAY(NOW()) < end {
+            return Ok(false);
+        }
+
+        let sql = r#"
+        select count(*), max(timestamp), sum(case when status in ('U', 'Us', 'V') then 1 else 0 end), max(gap) from (
+            select timestamp, status, timestamp - lag(timestamp, 1, $1) over (order by timestamp) gap
+            from trades where $1 <= timestamp and timestamp < $2)
+        "#;
+
+        let (count, last, unfix, max_gap) =
+            self.connection.query_row(sql, params![date, end], |row| {
+                let count: i64 = row.get(0)?;
+                let last: Option<i64> = row.get(1)?;
+                let unfix: Option<i64> = row.get(2)?;
+                let max_gap: Option<i64> = row.get(3)?;
+                Ok((count, last.unwrap_or(0), unfix.unwrap_or(0), max_gap.unwrap_or(0)))
+            })?;
+
+        if count == 0 || unfix != 0 || MANIFEST_MAX_GAP <= max_gap || MANIFEST_MAX_GAP <= end - last {
+            log::debug!(
+                "not fixed {} count={} unfix={} max_gap={} last={}",
+                date_string(date),
+                count,
+                unfix,
+                max_gap,
+                time_string(last)
+            );
+            return Ok(false);
+        }
+
+        self.connection.execute(
+            "insert or replace into manifest (date, fixed_at) values ($1, $2)",
+            params![date, NOW()],
+        )?;
+
+        Ok(true)
+    }
+
+    /// start_timeから続く確定済みの日を飛ばした時刻(確定済みでない日の始まり)。
+    /// 確定済みかはvalidate_by_dateで判定するので、manifestに未登録の日はここで登録される。
+    pub fn skip_fixed_days(&self, start_time: MicroSec, end_time: MicroSec) -> MicroSec {
+        let mut time = start_time;
+
+        while time < end_time {
+            let date = FLOOR_DAY(time);
+
+            match self.validate_by_date(date) {
+                Ok(true) => time = date + DAYS(1),
+                Ok(false) => break,
+                Err(e) => {
+                    log::warn!("manifest check error {}: {:?}", date_string(date), e);
+                    break;
+                }
+            }
+        }
+
+        time
+    }
+
+    pub fn is_fixed_day(&self, date: MicroSec) -> anyhow::Result<bool> {
+        let count: i64 = self.connection.query_row(
+            "select count(*) from manifest where date = $1",
+            params![FLOOR_DAY(date)],
+            |row| row.get(0),
+        )?;
+
+        Ok(count != 0)
+    }
+
+    pub fn fixed_days(&self) -> anyhow::Result<HashSet<MicroSec>> {
+        let mut statement = self.connection.prepare("select date from manifest")?;
+
+        let days = statement
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .collect::<Result<HashSet<MicroSec>, _>>()?;
+
+        Ok(days)
+    }
+
+    /// start_time〜end_timeを含む日をmanifestから外す(end_time=0は全て)
+    pub fn invalidate_manifest(&mut self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<()> {
+        let tx = self.begin_transaction()?;
+        Self::invalidate_manifest_tx(&tx, start_time, end_time)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn invalidate_manifest_tx(tx: &Transaction, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<()> {
+        let start_time = FLOOR_DAY(start_time);
+
+        if end_time == 0 {
+            tx.execute("delete from manifest where $1 <= date", params![start_time])?;
+        } else {
+            tx.execute(
+                "delete from manifest where $1 <= date and date < $2",
+                params![start_time, end_time],
+            )?;
+        }
+
         Ok(())
     }
 
@@ -466,6 +864,108 @@ impl TradeDb {
         Ok(())
     }
 
+    /// size >= min_sizeの約定だけをSQLで絞り込んで返す（時間の範囲はselectと同じ）
+    pub fn select_large<F>(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: f64,
+        mut f: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(&Trade) -> anyhow::Result<()>,
+    {
+        let mut param: Vec<rusqlite::types::Value> = vec![min_size.into()];
+
+        let mut sql =
+            "select timestamp, action, price, size, status, id from trades where $1 <= size"
+                .to_string();
+
+        if 0 < start_time {
+            param.push(start_time.into());
+            sql += &format!(" and ${} <= timestamp", param.len());
+        }
+
+        if 0 < end_time {
+            param.push(end_time.into());
+            sql += &format!(" and timestamp < ${}", param.len());
+        }
+
+        sql += " order by timestamp";
+
+        let mut statement = self.connection.prepare(&sql)?;
+
+        let _transaction_iter = statement
+            .query_map(params_from_iter(param.iter()), |row| {
+                let bs_str: String = row.get_unwrap(1);
+                let bs: OrderSide = bs_str.as_str().into();
+                let status_str: String = row.get_unwrap(4);
+                let status = LogStatus::from(status_str.as_str());
+
+                Ok(Trade {
+                    time: row.get_unwrap(0),
+                    price: Decimal::from_f64(row.get_unwrap(2)).unwrap(),
+                    size: Decimal::from_f64(row.get_unwrap(3)).unwrap(),
+                    order_side: bs,
+                    status: status,
+                    id: row.get_unwrap(5),
+                })
+            })
+            .with_context(|| format!("select large trade error"))?;
+
+        for trade in _transaction_iter {
+            match trade {
+                Ok(t) => f(&t)?,
+                Err(e) => {
+                    return Err(anyhow!("select_large query: SQL error {}", e));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn fetch_large_trades_df(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: f64,
+    ) -> anyhow::Result<DataFrame> {
+        let mut buffer = TradeBuffer::new();
+
+        self.select_large(start_time, end_time, min_size, |trade| {
+            buffer.push_trade(trade);
+
+            Ok(())
+        })?;
+
+        Ok(buffer.to_dataframe())
+    }
+
+    /// selectの結果をbuffer_sizeのchannelへ流す（別スレッドで別connectionを開いて読む）
+    pub fn stream_to_channel(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        buffer_size: usize,
+    ) -> tokio::sync::mpsc::Receiver<MarketMessage> {
+        let config = self.config.clone();
+        let production = self.production;
+
+        spawn_trade_reader(buffer_size, move |send| {
+            let db = TradeDb::open(&config, production)?;
+            db.select(start_time, end_time, send)
+        })
+    }
+
+    pub fn stream_trades(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> impl futures::Stream<Item = Trade> {
+        trade_stream(self.stream_to_channel(start_time, end_time, TRADE_STREAM_CHANNEL_SIZE))
+    }
+
     pub fn select_query(&mut self, sql: &str, param: Vec<i64>) -> anyhow::Result<Vec<Trade>> {
         let mut statement = self.connection.prepare(sql)?;
         let mut trades: Vec<Trade> = vec![];
@@ -523,6 +1023,38 @@ impl TradeDb {
         return time;
     }
 
+    /// 全レコードのtimestampが[2010-01-01, 2030-01-01)に入っているか確認する。
+    /// 単位の取り違えや0のままのtimestampを見つけるため。
+    pub fn validate_timestamp_range(&self) -> anyhow::Result<()> {
+        let sql = "select count(*), min(timestamp), max(timestamp) from trades where timestamp < $1 or $2 <= timestamp";
+
+        let (count, min, max) = self.connection.query_row(
+            sql,
+            params![TIMESTAMP_VALID_FROM, TIMESTAMP_VALID_TO],
+            |row| {
+                let count: i64 = row.get(0)?;
+                let min: Option<i64> = row.get(1)?;
+                let max: Option<i64> = row.get(2)?;
+                Ok((count, min.unwrap_or(0), max.unwrap_or(0)))
+            },
+        )?;
+
+        if count != 0 {
+            return Err(anyhow!(
+                "{} records out of timestamp range [{}, {}): min={}({}) max={}({})",
+                count,
+                time_string(TIMESTAMP_VALID_FROM),
+                time_string(TIMESTAMP_VALID_TO),
+                min,
+                time_string(min),
+                max,
+                time_string(max)
+            ));
+        }
+
+        Ok(())
+    }
+
     /// select max(end) timestamp in db
     /// returns 0 for no data.
     pub fn end_time(&self, search_from: MicroSec) -> MicroSec {
@@ -584,48 +1116,421 @@ impl TradeDb {
         None
     }
 
-    /// Find un-downloaded data time chunks.
-    pub fn select_gap_chunks(
-        &self,
+    /// [start_time, end_time)のレコードをtradesで置き換える（1トランザクション）
+    /// end_time=0は現在まで。
+    fn replace_records(
+        &mut self,
         start_time: MicroSec,
-        mut end_time: MicroSec,
-        allow_size: MicroSec,
-    ) -> anyhow::Result<Vec<TimeChunk>> {
-        if end_time == 0 {
-            end_time = NOW();
-        }
-
-        let mut chunk = self.find_time_chunk_from(start_time, end_time, allow_size);
-        log::debug!("chunk before {:?}", chunk);
-        // find in db
-        let mut c = self.select_time_chunks_in_db(start_time, end_time, allow_size)?;
-        chunk.append(&mut c);
-        log::debug!("chunk in db {:?}", chunk);
+        end_time: MicroSec,
+        trades: &Vec<Trade>,
+    ) -> anyhow::Result<i64> {
+        let end_time = if end_time == 0 { NOW() } else { end_time };
 
-        // find after db time
-        let mut c = self.find_time_chunk_to(start_time, end_time, allow_size);
-        chunk.append(&mut c);
-        log::debug!("chunk after {:?}", chunk);
+        let tx = self.begin_transaction()?;
+        Self::delete_date_force(&tx, start_time, end_time)?;
+        let count = Self::insert_transaction(&tx, trades)?;
+        tx.commit()?;
 
-        return Ok(chunk);
+        Ok(count)
     }
 
-    /// Find un-downloaded data chunks before db data.
-    /// If db has no data, returns []
-    pub fn find_time_chunk_from(
-        &self,
+    /// 連続する同一価格・同一方向の約定をsizeを合算した1レコード(LogStatus::Aggregate)にまとめる。
+    /// 元データは失われる（非可逆）。end_time=0は現在まで。
+    pub fn compress_ticks(
+        &mut self,
         start_time: MicroSec,
-        _end_time: MicroSec,
-        allow_size: MicroSec,
-    ) -> Vec<TimeChunk> {
-        let db_start_time = self.start_time(0);
+        end_time: MicroSec,
+    ) -> anyhow::Result<CompressionStats> {
+        let end_time = if end_time == 0 { NOW() } else { end_time };
 
-        if db_start_time == 0 {
-            return vec![];
-        }
+        let mut trades: Vec<Trade> = vec![];
 
-        if start_time + allow_size <= db_start_time {
-            log::debug!(
+        self.select(start_time, end_time, |trade| {
+            trades.push(trade.clone());
+            Ok(())
+        })?;
+
+        let compressed = Self::compress_trades(&trades);
+        let stats = CompressionStats::new(trades.len() as i64, compressed.len() as i64);
+
+        if stats.input_count == stats.output_count {
+            return Ok(stats);
+        }
+
+        log::debug!(
+            "compress {}-{} {:?}",
+            time_string(start_time),
+            time_string(end_time),
+            stats
+        );
+
+        self.replace_records(start_time, end_time, &compressed)?;
+
+        Ok(stats)
+    }
+
+    /// compress_ticksで失われた元データをtradesで戻す
+    pub fn restore_ticks(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        trades: &Vec<Trade>,
+    ) -> anyhow::Result<i64> {
+        self.replace_records(start_time, end_time, trades)
+    }
+
+    /// trades must be sorted by time.
+    fn compress_trades(trades: &Vec<Trade>) -> Vec<Trade> {
+        let mut compressed: Vec<Trade> = vec![];
+
+        for trade in trades {
+            if let Some(last) = compressed.last_mut() {
+                if last.status != LogStatus::UnFixStart
+                    && trade.status != LogStatus::UnFixStart
+                    && last.order_side == trade.order_side
+                    && last.price == trade.price
+                {
+                    last.size += trade.size;
+                    last.status = LogStatus::Aggregate;
+                    continue;
+                }
+            }
+
+            compressed.push(trade.clone());
+        }
+
+        compressed
+    }
+
+    /// REST/WSで重複したレコード(id違い)を削除する。
+    /// (timestamp, price, size, action)が同一でstatusが異なるものは確度の高いstatusを残す。
+    /// returns number of deleted records.
+    pub fn dedup_by_time_and_price(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<u64> {
+        let mut trades: Vec<Trade> = vec![];
+
+        self.select(start_time, end_time, |trade| {
+            trades.push(trade.clone());
+            Ok(())
+        })?;
+
+        let duplicate_ids = Self::find_duplicate_ids(&trades);
+
+        if duplicate_ids.len() == 0 {
+            return Ok(0);
+        }
+
+        log::debug!(
+            "dedup {}-{} delete {} recs",
+            time_string(start_time),
+            time_string(end_time),
+            duplicate_ids.len()
+        );
+
+        let tx = self.begin_transaction()?;
+        let mut count = 0;
+        for id in duplicate_ids {
+            count += tx.execute("delete from trades where id = ?1", params![id])?;
+        }
+        Self::invalidate_manifest_tx(&tx, start_time, end_time)?;
+        tx.commit()?;
+
+        Ok(count as u64)
+    }
+
+    /// trades must be sorted by time.
+    /// 同じ約定がstatus違い(UnFixとarchiveなど)で保存されたものだけを重複とみなす。
+    /// 同じstatusの行は別々の約定なので消さない。
+    fn find_duplicate_ids(trades: &Vec<Trade>) -> Vec<String> {
+        let mut duplicate_ids: Vec<String> = vec![];
+        let mut group: HashMap<(String, Decimal, Decimal), Vec<&Trade>> = HashMap::new();
+        let mut current_time: MicroSec = -1;
+
+        for trade in trades {
+            if trade.time != current_time {
+                for (_, same) in group.drain() {
+                    Self::collect_duplicate_ids(&same, &mut duplicate_ids);
+                }
+                current_time = trade.time;
+            }
+
+            let key = (trade.order_side.to_string(), trade.price, trade.size);
+            group.entry(key).or_default().push(trade);
+        }
+
+        for (_, same) in group.drain() {
+            Self::collect_duplicate_ids(&same, &mut duplicate_ids);
+        }
+
+        duplicate_ids
+    }
+
+    /// (timestamp, price, size, action)が同じ行のうち、最も確度の高いstatusの行は全て残し、
+    /// 他のstatusの行はその件数までを重複として削除する。
+    fn collect_duplicate_ids(same: &Vec<&Trade>, duplicate_ids: &mut Vec<String>) {
+        let best = match same.iter().map(|t| Self::status_certainty(&t.status)).max() {
+            Some(best) => best,
+            None => return,
+        };
+        let best_count = same
+            .iter()
+            .filter(|t| Self::status_certainty(&t.status) == best)
+            .count();
+
+        let mut removed: HashMap<i64, usize> = HashMap::new();
+        for trade in same {
+            let certainty = Self::status_certainty(&trade.status);
+            if certainty == best {
+                continue;
+            }
+
+            let count = removed.entry(certainty).or_insert(0);
+            if *count < best_count {
+                duplicate_ids.push(trade.id.clone());
+                *count += 1;
+            }
+        }
+    }
+
+    fn status_certainty(status: &LogStatus) -> i64 {
+        match status {
+            LogStatus::FixArchiveBlock => 4,
+            LogStatus::UnFixStart => 3, // keep WS start up marker.
+            LogStatus::UnFix => 2,
+            LogStatus::Virtual | LogStatus::KlineFill => 1,
+            _ => 0,
+        }
+    }
+
+    /// Find un-downloaded data time chunks.
+    pub fn select_gap_chunks(
+        &self,
+        start_time: MicroSec,
+        mut end_time: MicroSec,
+        allow_size: MicroSec,
+    ) -> anyhow::Result<Vec<TimeChunk>> {
+        if end_time == 0 {
+            end_time = NOW();
+        }
+
+        let mut chunk = self.find_time_chunk_from(start_time, end_time, allow_size);
+        log::debug!("chunk before {:?}", chunk);
+        // find in db
+        // 確定済みの日はダウンロード済みなので走査しない。
+        // 確定済みの日の最後の約定はMANIFEST_MAX_GAP以内にあるので、そこから走査すれば境界の空白も見つかる。
+        let scan_start = self.skip_fixed_days(start_time, end_time);
+        let scan_start = if scan_start == start_time {
+            start_time
+        } else {
+            scan_start - MANIFEST_MAX_GAP
+        };
+        let mut c = self.select_time_chunks_in_db(scan_start, end_time, allow_size)?;
+        chunk.append(&mut c);
+        log::debug!("chunk in db {:?}", chunk);
+
+        // find after db time
+        let mut c = self.find_time_chunk_to(start_time, end_time, allow_size);
+        chunk.append(&mut c);
+        log::debug!("chunk after {:?}", chunk);
+
+        return Ok(chunk);
+    }
+
+    /// start_time <= timestamp < end_time の約定件数
+    pub fn count_trades(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<i64> {
+        let sql = "select count(*) from trades where $1 <= timestamp and timestamp < $2";
+
+        let count = self
+            .connection
+            .query_row(sql, params![start_time, end_time], |row| row.get(0))
+            .with_context(|| format!("count_trades error"))?;
+
+        Ok(count)
+    }
+
+    /// count_tradesと同じ(select count(*))。
+    pub fn count_records(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<u64> {
+        Ok(self.count_trades(start_time, end_time)? as u64)
+    }
+
+    /// start_time <= timestamp < end_time で、statusが一致する約定件数
+    pub fn count_records_by_status(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        status: LogStatus,
+    ) -> anyhow::Result<u64> {
+        let sql = "select count(*) from trades where $1 <= timestamp and timestamp < $2 and status = $3";
+
+        let count: i64 = self
+            .connection
+            .query_row(sql, params![start_time, end_time, status.to_string()], |row| row.get(0))
+            .with_context(|| format!("count_records_by_status error"))?;
+
+        Ok(count as u64)
+    }
+
+    /// 件数の内訳、最初/最後の約定時刻、出来高を一回のSQLで集計する
+    pub fn summary(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<TradeSummary> {
+        let sql = "select count(*),
+                coalesce(sum(case when action = $3 then 1 else 0 end), 0),
+                coalesce(sum(case when action = $4 then 1 else 0 end), 0),
+                coalesce(sum(case when status = $5 then 1 else 0 end), 0),
+                coalesce(sum(case when status in ($6, $7) then 1 else 0 end), 0),
+                min(timestamp), max(timestamp), sum(size)
+            from trades where $1 <= timestamp and timestamp < $2";
+
+        let (total, buy, sell, fix, unfix, first, last, volume): (
+            i64,
+            i64,
+            i64,
+            i64,
+            i64,
+            Option<i64>,
+            Option<i64>,
+            Option<f64>,
+        ) = self
+            .connection
+            .query_row(
+                sql,
+                params![
+                    start_time,
+                    end_time,
+                    OrderSide::Buy.to_string(),
+                    OrderSide::Sell.to_string(),
+                    LogStatus::FixArchiveBlock.to_string(),
+                    LogStatus::UnFix.to_string(),
+                    LogStatus::UnFixStart.to_string()
+                ],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                        row.get(6)?,
+                        row.get(7)?,
+                    ))
+                },
+            )
+            .with_context(|| format!("summary error"))?;
+
+        Ok(TradeSummary {
+            total_count: total as u64,
+            buy_count: buy as u64,
+            sell_count: sell as u64,
+            fix_count: fix as u64,
+            unfix_count: unfix as u64,
+            first_time: first.unwrap_or_default(),
+            last_time: last.unwrap_or_default(),
+            total_volume: volume.and_then(Decimal::from_f64).unwrap_or_default(),
+        })
+    }
+
+    /// 約定を読み出さずにSQLで集計する(アーカイブのみの期間は含まれない)
+    pub fn range_stats(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<RangeStats> {
+        let sql = "select count(*), min(price), max(price), avg(price), sum(size), sum(price * size)
+            from trades where $1 <= timestamp and timestamp < $2";
+
+        let (count, min, max, mean, volume, amount): (
+            i64,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+            Option<f64>,
+        ) = self
+            .connection
+            .query_row(sql, params![start_time, end_time], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+            })
+            .with_context(|| format!("range_stats error"))?;
+
+        let to_decimal = |v: Option<f64>| v.and_then(Decimal::from_f64).unwrap_or_default();
+
+        let volume = to_decimal(volume);
+        let vwap = if volume.is_zero() {
+            dec![0.0]
+        } else {
+            to_decimal(amount) / volume
+        };
+
+        Ok(RangeStats {
+            start_time,
+            end_time,
+            count,
+            min_price: to_decimal(min),
+            max_price: to_decimal(max),
+            mean_price: to_decimal(mean),
+            volume,
+            vwap,
+        })
+    }
+
+    /// select_gap_chunksの結果に、空白の前後の約定頻度から推定した欠損件数を付けたDataFrame。
+    /// columns: start_time, end_time, duration_sec, duration, estimated_missing_trades
+    pub fn gap_report(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<DataFrame> {
+        let chunks = self.select_gap_chunks(start_time, end_time, GAP_REPORT_ALLOW_SIZE)?;
+
+        let mut starts: Vec<MicroSec> = vec![];
+        let mut ends: Vec<MicroSec> = vec![];
+        let mut duration_sec: Vec<f64> = vec![];
+        let mut duration: Vec<String> = vec![];
+        let mut estimated: Vec<i64> = vec![];
+
+        for c in chunks.iter() {
+            let before = self.count_trades(c.start - GAP_REPORT_RATE_WINDOW, c.start)?;
+            let after = self.count_trades(c.end + 1, c.end + 1 + GAP_REPORT_RATE_WINDOW)?;
+
+            // 前後のうちデータのある側だけで頻度を求める
+            let sides = (before != 0) as i64 + (after != 0) as i64;
+            let rate = if sides == 0 {
+                0.0
+            } else {
+                (before + after) as f64 / (sides * GAP_REPORT_RATE_WINDOW) as f64
+            };
+
+            let width = c.end - c.start;
+
+            starts.push(c.start);
+            ends.push(c.end);
+            duration_sec.push(width as f64 / 1_000_000.0);
+            duration.push(duration_string(width));
+            estimated.push((rate * width as f64).round() as i64);
+        }
+
+        let df = DataFrame::new(vec![
+            Series::new("start_time", starts).cast(&DataType::Datetime(TimeUnit::Microseconds, None))?,
+            Series::new("end_time", ends).cast(&DataType::Datetime(TimeUnit::Microseconds, None))?,
+            Series::new("duration_sec", duration_sec),
+            Series::new("duration", duration),
+            Series::new("estimated_missing_trades", estimated),
+        ])?;
+
+        Ok(df)
+    }
+
+    /// Find un-downloaded data chunks before db data.
+    /// If db has no data, returns []
+    pub fn find_time_chunk_from(
+        &self,
+        start_time: MicroSec,
+        _end_time: MicroSec,
+        allow_size: MicroSec,
+    ) -> Vec<TimeChunk> {
+        let db_start_time = self.start_time(0);
+
+        if db_start_time == 0 {
+            return vec![];
+        }
+
+        if start_time + allow_size <= db_start_time {
+            log::debug!(
                 "before db {:?}-{:?}",
                 time_string(start_time),
                 time_string(db_start_time)
@@ -696,9 +1601,10 @@ impl TradeDb {
                 let missing_width: MicroSec = row.get_unwrap(1);
 
                 log::debug!("{}- gap({})", time_string(start_time), missing_width);
+                // 空白はこの約定の直前
                 Ok(TimeChunk {
-                    start: start_time,
-                    end: start_time + missing_width,
+                    start: start_time - missing_width,
+                    end: start_time,
                 })
             })
             .with_context(|| format!("select_time_chunks_in_db error"))?;
@@ -798,14 +1704,638 @@ impl TradeDb {
             time_chunk
         };
 
-        let days_gap = Self::time_chunks_to_days(&time_gap);
+        let mut days_gap = Self::time_chunks_to_days(&time_gap);
         log::debug!("GAP TIME: {:?}", time_gap);
+
+        if !force {
+            // manifestに記録された確定済みの日はDBを見ずにスキップする
+            match self.fixed_days() {
+                Ok(fixed) => days_gap.retain(|d| {
+                    !fixed.contains(d) && !self.validate_by_date(*d).unwrap_or(false)
+                }),
+                Err(e) => log::warn!("manifest read error {:?}", e),
+            }
+        }
         log::debug!("GAP DAYS: {:?}", days_gap);
 
         return days_gap;
     }
 }
 
+
+#[cfg(test)]
+mod sqlite_test {
+    use rust_decimal_macros::dec;
+
+    use futures::StreamExt;
+
+    use crate::common::{auto_timestamp, init_debug_log, LogStatus, MarketConfig, MarketMessage, OrderSide, Trade};
+
+    use super::TradeDb;
+    use super::{duration_string, gap_chunks_df};
+    use crate::common::{MicroSec, TimeChunk, DAYS, HHMM, NOW, SEC};
+
+    #[test]
+    fn test_dedup_by_time_and_price() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "DEDUP_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        let trades = vec![
+            Trade::new(1_000, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, "ws-1"),
+            Trade::new(1_000, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFixStart, "rest-1"),
+            Trade::new(1_000, OrderSide::Sell, dec![10.0], dec![1.0], LogStatus::UnFix, "ws-2"),
+            Trade::new(2_000, OrderSide::Buy, dec![11.0], dec![2.0], LogStatus::UnFix, "ws-3"),
+            Trade::new(2_000, OrderSide::Buy, dec![11.0], dec![2.0], LogStatus::FixArchiveBlock, "arch-3"),
+            Trade::new(3_000, OrderSide::Buy, dec![11.0], dec![2.0], LogStatus::UnFix, "ws-4"),
+            // 同じstatusで時刻・価格・サイズが同じ行は別々の約定
+            Trade::new(4_000, OrderSide::Buy, dec![12.0], dec![1.0], LogStatus::UnFix, "ws-5"),
+            Trade::new(4_000, OrderSide::Buy, dec![12.0], dec![1.0], LogStatus::UnFix, "ws-6"),
+            // archiveに2件ある約定のうちWSで受けた1件だけが重複
+            Trade::new(5_000, OrderSide::Sell, dec![12.0], dec![1.0], LogStatus::FixArchiveBlock, "arch-7"),
+            Trade::new(5_000, OrderSide::Sell, dec![12.0], dec![1.0], LogStatus::FixArchiveBlock, "arch-8"),
+            Trade::new(5_000, OrderSide::Sell, dec![12.0], dec![1.0], LogStatus::UnFix, "ws-7"),
+        ];
+        db.insert_records(&trades)?;
+
+        let removed = db.dedup_by_time_and_price(0, 10_000)?;
+        assert_eq!(removed, 3);
+
+        let mut ids: Vec<String> = vec![];
+        db.select(0, 10_000, |t| {
+            ids.push(t.id.clone());
+            Ok(())
+        })?;
+        ids.sort();
+        assert_eq!(ids, vec!["arch-3", "arch-7", "arch-8", "rest-1", "ws-2", "ws-4", "ws-5", "ws-6"]);
+
+        // second pass has nothing to do.
+        assert_eq!(db.dedup_by_time_and_price(0, 10_000)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_stats() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "RANGE_STATS_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        let start = NOW() - HHMM(1, 0);
+        let trades = vec![
+            Trade::new(start, OrderSide::Buy, dec![100.0], dec![1.0], LogStatus::UnFix, "1"),
+            Trade::new(start + SEC(1), OrderSide::Sell, dec![110.0], dec![3.0], LogStatus::UnFix, "2"),
+            Trade::new(start + SEC(2), OrderSide::Buy, dec![90.0], dec![1.0], LogStatus::UnFix, "3"),
+            Trade::new(start + SEC(3), OrderSide::Buy, dec![200.0], dec![1.0], LogStatus::UnFix, "4"),
+        ];
+        db.insert_records(&trades)?;
+
+        // end_timeは含まない
+        let stats = db.range_stats(start, start + SEC(3))?;
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.min_price, dec![90.0]);
+        assert_eq!(stats.max_price, dec![110.0]);
+        assert_eq!(stats.mean_price, dec![100.0]);
+        assert_eq!(stats.volume, dec![5.0]);
+        assert_eq!(stats.vwap, dec![104.0]);
+
+        let stats = db.range_stats(start - SEC(10), start)?;
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.vwap, dec![0.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_records_and_summary() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "SUMMARY_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        let start = NOW() - HHMM(1, 0);
+        let trades = vec![
+            Trade::new(start, OrderSide::Buy, dec![100.0], dec![1.0], LogStatus::FixArchiveBlock, "1"),
+            Trade::new(start + SEC(1), OrderSide::Sell, dec![110.0], dec![3.0], LogStatus::FixArchiveBlock, "2"),
+            Trade::new(start + SEC(2), OrderSide::Buy, dec![90.0], dec![0.5], LogStatus::UnFixStart, "3"),
+            Trade::new(start + SEC(3), OrderSide::Buy, dec![95.0], dec![1.5], LogStatus::UnFix, "4"),
+            Trade::new(start + SEC(4), OrderSide::Sell, dec![200.0], dec![1.0], LogStatus::UnFix, "5"),
+        ];
+        db.insert_records(&trades)?;
+
+        // end_timeは含まない
+        let end = start + SEC(4);
+        assert_eq!(db.count_records(start, end)?, 4);
+        assert_eq!(db.count_records_by_status(start, end, LogStatus::FixArchiveBlock)?, 2);
+        assert_eq!(db.count_records_by_status(start, end, LogStatus::UnFix)?, 1);
+        assert_eq!(db.count_records_by_status(start, end, LogStatus::Virtual)?, 0);
+
+        let summary = db.summary(start, end)?;
+        assert_eq!(summary.total_count, 4);
+        assert_eq!(summary.buy_count, 3);
+        assert_eq!(summary.sell_count, 1);
+        assert_eq!(summary.fix_count, 2);
+        assert_eq!(summary.unfix_count, 2);
+        assert_eq!(summary.first_time, start);
+        assert_eq!(summary.last_time, start + SEC(3));
+        assert_eq!(summary.total_volume, dec![6.0]);
+
+        let summary = db.summary(start - SEC(10), start)?;
+        assert_eq!(summary, super::TradeSummary::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gap_report() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "GAP_REPORT_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        // 1秒に1件、途中に1時間の空白
+        let start = NOW() - HHMM(3, 0);
+        let gap_start = start + HHMM(1, 0);
+        let gap_end = gap_start + HHMM(1, 0);
+        let end = gap_end + HHMM(1, 0);
+
+        let mut trades = vec![];
+        for t in (start..=gap_start).step_by(1_000_000).chain((gap_end..end).step_by(1_000_000)) {
+            trades.push(Trade::new(t, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, &format!("{}", t)));
+        }
+        db.insert_records(&trades)?;
+
+        let report = db.gap_report(start, end)?;
+        println!("{:?}", report);
+
+        assert_eq!(report.shape().0, 1);
+        assert_eq!(report.column("duration_sec")?.f64()?.get(0), Some(3600.0));
+        assert_eq!(report.column("duration")?.str()?.get(0), Some("01:00:00"));
+
+        let estimated = report.column("estimated_missing_trades")?.i64()?.get(0).unwrap();
+        assert!((3590..=3610).contains(&estimated));
+
+        assert_eq!(duration_string(DAYS(1) + SEC(61)), "1d 00:01:01");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_time_chunks_in_db_range() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "TIME_CHUNK_RANGE_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        // 10秒に1件、gap_start〜gap_endの間は約定なし
+        let start = NOW() - HHMM(1, 0);
+        let gap_start = start + SEC(600);
+        let gap_end = gap_start + SEC(300);
+
+        let mut trades = vec![];
+        for t in (start..=gap_start).step_by(10_000_000).chain((gap_end..gap_end + SEC(600)).step_by(10_000_000)) {
+            trades.push(Trade::new(t, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, &format!("{}", t)));
+        }
+        db.insert_records(&trades)?;
+
+        // 空白を閉じた約定の後ろではなく、空白そのものを返す
+        let chunks = db.select_time_chunks_in_db(start - 1, 0, SEC(60))?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].start, gap_start);
+        assert_eq!(chunks[0].end, gap_end);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gap_chunks_df() -> anyhow::Result<()> {
+        let gaps = vec![
+            ("BYBIT".to_string(), "BTCUSDT".to_string(), TimeChunk { start: SEC(0), end: SEC(60) }),
+            ("BYBIT".to_string(), "BTCUSDT".to_string(), TimeChunk { start: SEC(120), end: SEC(120) + HHMM(1, 0) }),
+            ("BINANCE".to_string(), "ETHUSDT".to_string(), TimeChunk { start: SEC(0), end: DAYS(1) }),
+        ];
+
+        let df = gap_chunks_df(&gaps)?;
+
+        assert_eq!(df.shape(), (3, 5));
+        assert_eq!(df.column("exchange")?.str()?.get(2), Some("BINANCE"));
+        assert_eq!(df.column("symbol")?.str()?.get(0), Some("BTCUSDT"));
+        assert_eq!(df.column("duration")?.str()?.get(0), Some("00:01:00"));
+        assert_eq!(df.column("duration")?.str()?.get(1), Some("01:00:00"));
+        assert_eq!(df.column("duration")?.str()?.get(2), Some("1d 00:00:00"));
+
+        let empty = gap_chunks_df(&vec![])?;
+        assert_eq!(empty.shape(), (0, 5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gap_chunks_from_db() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.exchange_name = "BYBIT".to_string();
+        config.trade_symbol = "GAP_CHUNK_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        // 00:01:00から00:06:00まで約定がない
+        let base: MicroSec = 1724371200_000_000; // 2024-08-23 00:00:00 UTC
+        let mut trades = vec![];
+        for sec in (0..=60).step_by(10).chain((360..=420).step_by(10)) {
+            trades.push(Trade::new(base + SEC(sec), OrderSide::Buy, dec![10.0], dec![0.1], LogStatus::UnFix, &format!("g-{}", sec)));
+        }
+        db.insert_records(&trades)?;
+
+        let chunks = db.select_gap_chunks(base - SEC(1), base + SEC(420), SEC(30))?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].start, base + SEC(60));
+        assert_eq!(chunks[0].end, base + SEC(360));
+
+        let gaps: Vec<(String, String, TimeChunk)> = chunks
+            .into_iter()
+            .map(|c| (config.exchange_name.clone(), config.trade_symbol.clone(), c))
+            .collect();
+        let df = gap_chunks_df(&gaps)?;
+
+        assert_eq!(df.shape(), (1, 5));
+        assert_eq!(df.column("symbol")?.str()?.get(0), Some("GAP_CHUNK_TEST"));
+        assert_eq!(df.column("duration")?.str()?.get(0), Some("00:05:00"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_large() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "LARGE_TRADE_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        db.insert_records(&vec![
+            Trade::new(1_000, OrderSide::Buy, dec![10.0], dec![0.1], LogStatus::UnFix, "s-1"),
+            Trade::new(2_000, OrderSide::Sell, dec![10.0], dec![5.0], LogStatus::UnFix, "l-1"),
+            Trade::new(3_000, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, "l-2"),
+            Trade::new(4_000, OrderSide::Buy, dec![10.0], dec![9.0], LogStatus::UnFix, "l-3"),
+        ])?;
+
+        let mut ids: Vec<String> = vec![];
+        db.select_large(0, 4_000, 1.0, |t| {
+            ids.push(t.id.clone());
+            Ok(())
+        })?;
+        assert_eq!(ids, vec!["l-1", "l-2"]);
+
+        let df = db.fetch_large_trades_df(0, 0, 5.0)?;
+        assert_eq!(df.shape().0, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest() -> anyhow::Result<()> {
+        use crate::common::{DAYS, FLOOR_DAY, NOW, SEC};
+
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "MANIFEST_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.drop_table()?;
+
+        let day = FLOOR_DAY(NOW()) - DAYS(3);
+
+        // 1分ごとの確定データ
+        let trades: Vec<Trade> = (0..24 * 60)
+            .map(|m| {
+                Trade::new(
+                    day + SEC(m * 60),
+                    OrderSide::Buy,
+                    dec![10.0],
+                    dec![1.0],
+                    LogStatus::FixArchiveBlock,
+                    &format!("a-{}", m),
+                )
+            })
+            .collect();
+        db.insert_records(&trades)?;
+
+        assert!(!db.is_fixed_day(day)?);
+        assert!(db.validate_by_date(day)?);
+        assert!(db.is_fixed_day(day)?);
+        assert!(db.fixed_days()?.contains(&day));
+
+        // 未確定データのある日は登録しない
+        let next = day + DAYS(1);
+        db.insert_records(&vec![Trade::new(next, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, "u-1")])?;
+        assert!(!db.validate_by_date(next)?);
+
+        // 当日は確定しない
+        assert!(!db.validate_by_date(NOW())?);
+
+        db.invalidate_manifest(day, day + DAYS(1))?;
+        assert!(!db.is_fixed_day(day)?);
+
+        assert!(db.validate_by_date(day)?);
+        db.drop_table()?;
+        assert!(db.fixed_days()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gap_chunks_skip_fixed_days() -> anyhow::Result<()> {
+        use crate::common::{DAYS, FLOOR_DAY, NOW, SEC};
+
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "MANIFEST_GAP_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.drop_table()?;
+
+        let day = FLOOR_DAY(NOW()) - DAYS(5);
+        let next = day + DAYS(1);
+
+        // 1分ごと、どちらの日も途中に5分の空白。dayは確定データ、nextは未確定データ
+        let mut trades: Vec<Trade> = vec![];
+        for m in (0..24 * 60).filter(|m| !(600 < *m && *m < 605)) {
+            trades.push(Trade::new(day + SEC(m * 60), OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::FixArchiveBlock, &format!("a-{}", m)));
+            trades.push(Trade::new(next + SEC(m * 60), OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, &format!("u-{}", m)));
+        }
+        db.insert_records(&trades)?;
+
+        // 確定済みの日の空白は報告せず、manifestに登録される
+        let chunks = db.select_gap_chunks(day, next + DAYS(1), SEC(60))?;
+        assert!(db.is_fixed_day(day)?);
+        assert!(!db.is_fixed_day(next)?);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].start, next + SEC(600 * 60));
+        assert_eq!(chunks[0].end, next + SEC(605 * 60));
+
+        // 2回目はmanifestから判定する
+        assert_eq!(db.skip_fixed_days(day, next + DAYS(1)), next);
+
+        db.drop_table()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_csv() -> anyhow::Result<()> {
+        use crate::db::{CsvSchema, TimeFormat};
+        use std::io::Write;
+
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "IMPORT_CSV_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "1724371200,60000,0.1")?;
+        writeln!(file, "1724371201,60010,-0.5")?;
+        writeln!(file, "1724371202,60020,1.0")?;
+
+        let schema = CsvSchema {
+            time_format: TimeFormat::UnixSeconds,
+            has_header: false,
+            ..Default::default()
+        };
+
+        let count = db.import_csv(file.path(), schema)?;
+        assert_eq!(count, 3);
+
+        let mut trades: Vec<Trade> = vec![];
+        db.select(0, 0, |t| {
+            trades.push(t.clone());
+            Ok(())
+        })?;
+
+        assert_eq!(trades.len(), 3);
+        assert_eq!(trades[0].time, 1724371200_000_000);
+        assert_eq!(trades[1].order_side, OrderSide::Sell);
+        assert_eq!(trades[1].size, dec![0.5]);
+        assert_eq!(trades[2].price, dec![60020]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_thread() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "RESTART_THREAD_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        assert!(!db.is_running());
+
+        let tx = db.open_channel()?;
+        assert!(db.is_running());
+
+        tx.send(vec![Trade::new(1_000, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, "t-1")])?;
+
+        // 再起動後も、取得済みのSenderはそのまま使える
+        db.restart_thread()?;
+        assert!(db.is_running());
+
+        tx.send(vec![Trade::new(2_000, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, "t-2")])?;
+
+        tokio::time::sleep(std::time::Duration::from_millis(1_500)).await;
+
+        let mut ids: Vec<String> = vec![];
+        db.select(0, 0, |t| {
+            ids.push(t.id.clone());
+            Ok(())
+        })?;
+        assert_eq!(ids, vec!["t-1", "t-2"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_timestamp_range() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "TIMESTAMP_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        // 2024-08-23
+        let t = auto_timestamp(1724371200_000);
+        db.insert_records(&vec![
+            Trade::new(t, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, "ok-1"),
+        ])?;
+        assert!(db.validate_timestamp_range().is_ok());
+
+        // epoch 0のまま
+        db.insert_records(&vec![
+            Trade::new(0, OrderSide::Buy, dec![10.0], dec![1.0], LogStatus::UnFix, "bad-1"),
+        ])?;
+        assert!(db.validate_timestamp_range().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_ticks() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "COMPRESS_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        let mut trades: Vec<Trade> = (0..100)
+            .map(|i| {
+                Trade::new(1_000 + i, OrderSide::Buy, dec![10.0], dec![0.5], LogStatus::UnFix, &format!("t-{}", i))
+            })
+            .collect();
+        trades.push(Trade::new(2_000, OrderSide::Sell, dec![10.0], dec![1.0], LogStatus::UnFix, "t-sell"));
+        db.insert_records(&trades)?;
+
+        let stats = db.compress_ticks(0, 10_000)?;
+        assert_eq!(stats.input_count, 101);
+        assert_eq!(stats.output_count, 2);
+
+        let mut result: Vec<Trade> = vec![];
+        db.select(0, 10_000, |t| {
+            result.push(t.clone());
+            Ok(())
+        })?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].status, LogStatus::Aggregate);
+        assert_eq!(result[0].size, dec![50.0]);
+        assert_eq!(result[0].time, 1_000);
+        assert_eq!(result[1].id, "t-sell");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_ticks_open_end() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "COMPRESS_OPEN_END_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        let trades: Vec<Trade> = (0..10)
+            .map(|i| {
+                Trade::new(1_000 + i, OrderSide::Buy, dec![10.0], dec![0.5], LogStatus::UnFix, &format!("t-{}", i))
+            })
+            .collect();
+        db.insert_records(&trades)?;
+
+        // end_time=0は現在まで。元のレコードを残したまま追加しない
+        let stats = db.compress_ticks(0, 0)?;
+        assert_eq!(stats.input_count, 10);
+        assert_eq!(stats.output_count, 1);
+
+        let mut result: Vec<Trade> = vec![];
+        db.select(0, 0, |t| {
+            result.push(t.clone());
+            Ok(())
+        })?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].size, dec![5.0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_trades() -> anyhow::Result<()> {
+        init_debug_log();
+
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "STREAM_TEST".to_string();
+
+        let mut db = TradeDb::open(&config, false)?;
+        db.create_table_if_not_exists()?;
+        db.connection.execute("delete from trades", ())?;
+
+        let trades: Vec<Trade> = (0..10_000)
+            .map(|i| {
+                Trade::new(1_000 + i, OrderSide::Buy, dec![10.0], dec![0.5], LogStatus::UnFix, &format!("s-{}", i))
+            })
+            .collect();
+        db.insert_records(&trades)?;
+
+        let stream = db.stream_trades(0, 0);
+        futures::pin_mut!(stream);
+
+        let mut count = 0;
+        let mut last_time = 0;
+        while let Some(trade) = stream.next().await {
+            assert!(last_time < trade.time);
+            last_time = trade.time;
+            count += 1;
+        }
+        assert_eq!(count, 10_000);
+        assert_eq!(last_time, 1_000 + 9_999);
+
+        // 小さなbufferでも全件届く
+        let mut receiver = db.stream_to_channel(0, 0, 8);
+        let mut count = 0;
+        while let Some(message) = receiver.recv().await {
+            if let MarketMessage::Trade(_) = message {
+                count += 1;
+            }
+        }
+        assert_eq!(count, 10_000);
+
+        Ok(())
+    }
+}
+
 /*
 #[cfg(test)]
 mod test_transaction_table {
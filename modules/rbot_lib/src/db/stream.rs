@@ -0,0 +1,162 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use futures::Stream;
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::common::{MarketMessage, MicroSec, Trade};
+
+pub const TRADE_STREAM_CHANNEL_SIZE: usize = 4096;
+
+/// readerが読み出したTradeをbuffer_sizeのchannelへ流す。
+/// channelが一杯の間はreaderのスレッドが待たされ(backpressure)、受信側が閉じると読み出しを打ち切る。
+pub fn spawn_trade_reader<F>(buffer_size: usize, reader: F) -> Receiver<MarketMessage>
+where
+    F: FnOnce(&mut dyn FnMut(&Trade) -> anyhow::Result<()>) -> anyhow::Result<()>
+        + Send
+        + 'static,
+{
+    let (tx, rx) = channel(buffer_size.max(1));
+
+    std::thread::spawn(move || {
+        let mut send = |trade: &Trade| -> anyhow::Result<()> {
+            tx.blocking_send(MarketMessage::from_trade(trade.clone()))
+                .map_err(|_| anyhow!("trade stream receiver closed"))
+        };
+
+        if let Err(e) = reader(&mut send) {
+            log::debug!("trade reader finished: {:?}", e);
+        }
+    });
+
+    rx
+}
+
+/// MarketMessageのchannelからTradeだけを取り出すStream
+pub fn trade_stream(receiver: Receiver<MarketMessage>) -> impl Stream<Item = Trade> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await? {
+                MarketMessage::Trade(trade) => return Some((trade, receiver)),
+                _ => continue,
+            }
+        }
+    })
+}
+
+/// 過去データをspeed倍速で再生するためのタイミング調整。
+/// 最初のTradeの時刻を基準に、Tradeの時刻差/speedだけ経過するまで待つ(speedが0以下なら待たない)。
+#[derive(Debug)]
+pub struct ReplayPacer {
+    speed: f64,
+    origin: Option<(MicroSec, Instant)>,
+}
+
+impl ReplayPacer {
+    pub fn new(speed: f64) -> Self {
+        Self {
+            speed,
+            origin: None,
+        }
+    }
+
+    /// nowの時点でtimeのTradeを送るまでに待つ時間
+    pub fn delay(&mut self, time: MicroSec, now: Instant) -> Duration {
+        if self.speed <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        let (origin_time, origin_instant) = *self.origin.get_or_insert((time, now));
+
+        let elapsed = (time - origin_time).max(0) as f64 / self.speed;
+        let target = origin_instant + Duration::from_micros(elapsed as u64);
+
+        target.saturating_duration_since(now)
+    }
+
+    pub fn wait(&mut self, time: MicroSec) {
+        let delay = self.delay(time, Instant::now());
+
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// spawn_trade_readerと同じだが、Tradeの時刻間隔をspeedで割った実時間をあけて流す
+pub fn spawn_timed_trade_reader<F>(buffer_size: usize, speed: f64, reader: F) -> Receiver<MarketMessage>
+where
+    F: FnOnce(&mut dyn FnMut(&Trade) -> anyhow::Result<()>) -> anyhow::Result<()>
+        + Send
+        + 'static,
+{
+    spawn_trade_reader(buffer_size, move |send| {
+        let mut pacer = ReplayPacer::new(speed);
+
+        reader(&mut |trade: &Trade| {
+            pacer.wait(trade.time);
+            send(trade)
+        })
+    })
+}
+
+#[cfg(test)]
+mod stream_test {
+    use super::*;
+    use crate::common::{LogStatus, OrderSide, MICRO_SECOND};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_replay_pacer() {
+        let now = Instant::now();
+
+        let mut pacer = ReplayPacer::new(10.0);
+        assert_eq!(pacer.delay(1_000_000, now), Duration::ZERO);
+        assert_eq!(pacer.delay(2_000_000, now), Duration::from_millis(100));
+        // 遅れている場合は待たない
+        assert_eq!(
+            pacer.delay(2_000_000, now + Duration::from_millis(150)),
+            Duration::ZERO
+        );
+
+        let mut pacer = ReplayPacer::new(0.0);
+        pacer.delay(0, now);
+        assert_eq!(pacer.delay(MICRO_SECOND * 3600, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_spawn_timed_trade_reader() {
+        let trades: Vec<Trade> = (0..3)
+            .map(|i| {
+                Trade::new(
+                    i * 100_000, // 100ms
+                    OrderSide::Buy,
+                    dec![100.0],
+                    dec![1.0],
+                    LogStatus::FixArchiveBlock,
+                    "",
+                )
+            })
+            .collect();
+
+        let mut receiver = spawn_timed_trade_reader(16, 4.0, move |send| {
+            for trade in trades.iter() {
+                send(trade)?;
+            }
+            Ok(())
+        });
+
+        // 待ち時間の計算はtest_replay_pacerで確認する。ここでは全件が順に届くことを見る
+        let mut times = vec![];
+        while let Some(message) = receiver.blocking_recv() {
+            match message {
+                MarketMessage::Trade(trade) => times.push(trade.time),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+
+        assert_eq!(times, vec![0, 100_000, 200_000]);
+    }
+}
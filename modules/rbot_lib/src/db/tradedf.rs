@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    path::Path,
     sync::{Arc, Mutex},
 };
 
@@ -8,19 +9,24 @@ use crossbeam_channel::Sender;
 use once_cell::sync::Lazy;
 //use pyo3::sync::GILOnceCell;
 use polars::frame::DataFrame;
+use polars::lazy::prelude::{col, LazyFrame};
+use polars::prelude::{DataType, TimeUnit};
 use pyo3_polars::PyDataFrame;
 
+use pyo3::{pyclass, pymethods};
+use rust_decimal::{prelude::FromPrimitive, Decimal};
+
 use crate::{
-    common::{time_string, MarketConfig, MicroSec, Trade, DAYS, FLOOR_DAY, NOW},
+    common::{ohlcv_bars_df, time_string, BarType, LogStatus, MarketConfig, MarketMessage, MicroSec, OrderSide, ProgressCallback, TickBarAggregator, TimeChunk, Trade, DAYS, FLOOR_DAY, NOW, SEC},
     db::{
-        append_df, end_time_df, make_empty_ohlcvv, merge_df, ohlcv_start, ohlcvv_df,
-        start_time_df, TradeBuffer, select_df_lazy
+        append_df, end_time_df, make_empty_ohlcvv, merge_df, ohlcv_end, ohlcv_start, ohlcvv_df,
+        start_time_df, TradeBuffer, select_df_lazy, AvroTradeReader, AvroTradeWriter, KEY
     },
     net::RestApi,
 };
 
 use super::{
-    convert_timems_to_datetime, ohlcv_df, ohlcv_floor_fix_time, ohlcv_from_ohlcvv_df, ohlcvv_from_ohlcvv_df, vap_df, TradeArchive, TradeDb
+    convert_timems_to_datetime, cvd_df, kyles_lambda_df, rolling_kyles_lambda_df, ohlcv_df_with_offset, ohlcv_large_df, ohlcv_df_to_csv, ohlcv_floor_fix_time, ohlcv_from_ohlcvv_df_with_offset, ohlcv_from_ohlcvv_lazy_df_with_offset, ohlcv_lazy_df_with_offset, ohlcvv_from_ohlcvv_df, vap_df, trade_stream, CompressionStats, CsvSchema, HeatMap, ohlcvb_df, ArchiveFormat, RangeStats, SpreadDb, TradeArchive, TradeDb, TradeSummary, TRADE_STREAM_CHANNEL_SIZE
 };
 use anyhow::anyhow;
 
@@ -59,6 +65,120 @@ fn get_trade_dataframe_cache(
 }
 
 pub const OHLCV_WINDOW_SEC: i64 = 60; // min
+pub const LAZY_CACHE_MARGIN_SEC: i64 = 60 * 5; // lazyモード時に前後に余分に読み込む幅
+
+/// lazyモードで追加取得が必要な範囲を返す。
+/// キャッシュ済み範囲[cache_start, cache_end)と重ならない（または空の）場合は全範囲を返す。
+fn lazy_fetch_ranges(
+    cache_start: MicroSec,
+    cache_end: MicroSec,
+    start_time: MicroSec,
+    end_time: MicroSec,
+) -> Vec<(MicroSec, MicroSec)> {
+    if cache_end == 0 || end_time < cache_start || cache_end < start_time {
+        return vec![(start_time, end_time)];
+    }
+
+    let mut ranges = vec![];
+
+    if start_time < cache_start {
+        ranges.push((start_time, cache_start));
+    }
+
+    if cache_end < end_time {
+        ranges.push((cache_end, end_time));
+    }
+
+    ranges
+}
+
+/// cache_dfに保持する最大行数の既定値（0は無制限）
+pub const DEFAULT_CACHE_MAX_ROWS: usize = 10_000_000;
+
+/// cache_dfがmax_rowsを超えている場合、残す範囲[start, end)を返す(0はその側を削除しない)。
+/// keep=[start, end)の行(end=0は末尾まで)は削除せず、範囲外を古い方から、足りなければ新しい方から削除する。
+/// keepがNoneの場合は古い方から削除する。
+fn cache_eviction_range(
+    df: &DataFrame,
+    max_rows: usize,
+    keep: Option<(MicroSec, MicroSec)>,
+) -> anyhow::Result<Option<(MicroSec, MicroSec)>> {
+    let rows = df.height();
+
+    if max_rows == 0 || rows <= max_rows {
+        return Ok(None);
+    }
+
+    let timestamp: Vec<MicroSec> = df
+        .column(KEY::timestamp)?
+        .i64()?
+        .into_no_null_iter()
+        .collect();
+
+    let (keep_start, keep_end) = keep.unwrap_or((MicroSec::MAX, 0));
+    let before = timestamp.partition_point(|t| *t < keep_start);
+    let after = if keep_end == 0 {
+        0
+    } else {
+        rows - timestamp.partition_point(|t| *t < keep_end)
+    };
+
+    let over = rows - max_rows;
+    let drop_front = over.min(before);
+    let drop_back = (over - drop_front).min(after);
+
+    if drop_front + drop_back < over {
+        log::warn!(
+            "requested range {} -> {} has {} rows, exceeds cache_max_rows({}); keep all rows in the range",
+            time_string(keep_start),
+            time_string(keep_end),
+            rows - before - after,
+            max_rows
+        );
+    }
+
+    if drop_front == 0 && drop_back == 0 {
+        return Ok(None);
+    }
+
+    let start = if drop_front == 0 { 0 } else { timestamp[drop_front] };
+    let end = if drop_back == 0 { 0 } else { timestamp[rows - drop_back] };
+
+    Ok(Some((start, end)))
+}
+
+/// TradeDataFrameのキャッシュの状態
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheStats {
+    #[pyo3(get)]
+    pub rows: usize,
+    #[pyo3(get)]
+    pub ohlcvv_rows: usize,
+    #[pyo3(get)]
+    pub estimated_bytes: usize,
+    #[pyo3(get)]
+    pub max_rows: usize,
+    #[pyo3(get)]
+    pub start_time: MicroSec,
+    #[pyo3(get)]
+    pub end_time: MicroSec,
+    #[pyo3(get)]
+    pub hit: i64,
+    #[pyo3(get)]
+    pub miss: i64,
+    #[pyo3(get)]
+    pub evictions: i64,
+    #[pyo3(get)]
+    pub evicted_rows: i64,
+}
+
+#[pymethods]
+impl CacheStats {
+    pub fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
 
 pub struct TradeDataFrame {
     db: TradeDb,
@@ -66,6 +186,16 @@ pub struct TradeDataFrame {
 
     cache_df: DataFrame,
     cache_ohlcvv: DataFrame,
+
+    lazy: bool,
+    cache_start: MicroSec,
+    cache_end: MicroSec,
+    cache_hit: i64,
+    cache_miss: i64,
+
+    cache_max_rows: usize,
+    cache_evictions: i64,
+    cache_evicted_rows: i64,
 }
 
 impl TradeDataFrame {
@@ -106,10 +236,39 @@ impl TradeDataFrame {
         self.db.end_time(from_time)
     }
 
+    pub fn gap_report(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<DataFrame> {
+        self.db.gap_report(start_time, end_time)
+    }
+
+    pub fn select_gap_chunks(&self, start_time: MicroSec, end_time: MicroSec, allow_size: MicroSec) -> anyhow::Result<Vec<TimeChunk>> {
+        self.db.select_gap_chunks(start_time, end_time, allow_size)
+    }
+
+    pub fn range_stats(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<RangeStats> {
+        self.db.range_stats(start_time, end_time)
+    }
+
+    pub fn count_records(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<u64> {
+        self.db.count_records(start_time, end_time)
+    }
+
+    pub fn count_records_by_status(&self, start_time: MicroSec, end_time: MicroSec, status: LogStatus) -> anyhow::Result<u64> {
+        self.db.count_records_by_status(start_time, end_time, status)
+    }
+
+    pub fn summary(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<TradeSummary> {
+        self.db.summary(start_time, end_time)
+    }
+
     pub fn get_archive(&self) -> TradeArchive {
         self.archive.clone()
     }
 
+    /// ダウンロードしたアーカイブをローカルに保存する形式(デフォルトはparquet)
+    pub fn set_archive_format(&mut self, format: ArchiveFormat) -> anyhow::Result<()> {
+        self.archive.set_format(format)
+    }
+
     pub fn start_time(&self) -> MicroSec {
         let archive_start = self.get_archive_start_time();
 
@@ -177,17 +336,108 @@ impl TradeDataFrame {
         self.db.open_channel()
     }
 
+    pub fn is_db_thread_running(&self) -> bool {
+        self.db.is_running()
+    }
+
+    pub fn restart_db_thread(&mut self) -> anyhow::Result<()> {
+        self.db.restart_thread()
+    }
+
+    /// 約定履歴CSVをDBへ取り込む。取り込んだ範囲が古いキャッシュに残らないようキャッシュは捨てる。
+    pub fn import_csv(&mut self, path: &Path, schema: CsvSchema) -> anyhow::Result<u64> {
+        let count = self.db.import_csv(path, schema)?;
+        self.clear_cache_df();
+
+        Ok(count)
+    }
+
+    /// DBの全データ(とmanifest)を削除する
+    pub fn drop_table(&mut self) -> anyhow::Result<()> {
+        self.db.drop_table()?;
+        self.clear_cache_df();
+
+        Ok(())
+    }
+
+    pub fn open_spread_db(&self) -> anyhow::Result<SpreadDb> {
+        self.db.open_spread_db()
+    }
+
+    /// archiveのTradeをbuffer_sizeのchannelへ流す（バックテストの再生用）
+    pub fn stream_to_channel(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        buffer_size: usize,
+    ) -> tokio::sync::mpsc::Receiver<MarketMessage> {
+        self.archive.stream_to_channel(start_time, end_time, buffer_size)
+    }
+
+    /// archiveのTradeを実時間のspeed倍速で流す（0はstream_to_channelと同じく待たない）
+    pub fn select_stream_timed(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        speed: f64,
+    ) -> tokio::sync::mpsc::Receiver<MarketMessage> {
+        self.archive.select_stream_timed(start_time, end_time, speed)
+    }
+
+    pub fn stream_trades(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> impl futures::Stream<Item = Trade> {
+        trade_stream(self.stream_to_channel(start_time, end_time, TRADE_STREAM_CHANNEL_SIZE))
+    }
+
+    pub fn select_spread_df(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<DataFrame> {
+        self.db.open_spread_db()?.select_df(start_time, end_time)
+    }
+
+    /// ohlcvにspread_logから求めたspread(best_ask - best_bid)の始値/終値/最小/最大を加える
+    pub fn ohlcvb_df(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<DataFrame> {
+        let ohlcv = self._ohlcv_df(start_time, end_time, window_sec, 0)?;
+
+        // 足の開始前のspreadを引き継ぐため、1本分さかのぼって読む
+        let spreads = self
+            .db
+            .open_spread_db()?
+            .select(ohlcv_start(start_time) - SEC(window_sec), end_time)?;
+
+        ohlcvb_df(&ohlcv, &spreads, window_sec)
+    }
+
     pub async fn download_archive<T>(
         &mut self,
         api: &T,
         ndays: i64,
         force: bool,
         verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
+    ) -> anyhow::Result<i64>
+    where
+        T: RestApi,
+    {
+        self.archive.download(api, ndays, force, verbose, progress).await
+    }
+
+    pub async fn download_archive_range<T>(
+        &mut self,
+        api: &T,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        force: bool,
+        verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
     ) -> anyhow::Result<i64>
     where
         T: RestApi,
     {
-        self.archive.download(api, ndays, force, verbose).await
+        self.archive
+            .download_range(api, start_time, end_time, force, verbose, progress)
+            .await
     }
 
     pub fn select_cache_df(
@@ -232,6 +482,29 @@ impl TradeDataFrame {
         }
     }
 
+    /// size >= min_sizeの約定のみを返す。archiveは日ごと、DBはSQLで絞り込む。
+    pub fn fetch_large_trades_df(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: f64,
+    ) -> anyhow::Result<DataFrame> {
+        let archive_end = self.get_archive_end_time();
+
+        if start_time <= archive_end {
+            let df1 = self.archive.fetch_large_trades_df(start_time, end_time, min_size)?;
+
+            if archive_end <= end_time || end_time == 0 {
+                let df2 = self.db.fetch_large_trades_df(archive_end, end_time, min_size)?;
+                append_df(&df1, &df2)
+            } else {
+                Ok(df1)
+            }
+        } else {
+            self.db.fetch_large_trades_df(start_time, end_time, min_size)
+        }
+    }
+
     pub fn fetch_archive_df(
         &mut self,
         start_time: MicroSec,
@@ -253,6 +526,105 @@ impl TradeDataFrame {
         self.update_cache_df(0, 0, true)
     }
 
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// lazy=trueの場合、問い合わせのあった範囲（＋LAZY_CACHE_MARGIN_SEC）のみキャッシュへ読み込む
+    pub fn set_lazy(&mut self, lazy: bool) {
+        self.lazy = lazy;
+    }
+
+    /// update_cache_dfでキャッシュのみで済んだ割合
+    pub fn cache_hit_rate(&self) -> f64 {
+        let total = self.cache_hit + self.cache_miss;
+
+        if total == 0 {
+            return 0.0;
+        }
+
+        self.cache_hit as f64 / total as f64
+    }
+
+    pub fn get_cache_max_rows(&self) -> usize {
+        self.cache_max_rows
+    }
+
+    /// キャッシュの最大行数（0は無制限）。超えた分は古いデータから捨てる。
+    pub fn set_cache_max_rows(&mut self, max_rows: usize) -> anyhow::Result<()> {
+        self.cache_max_rows = max_rows;
+        self.enforce_cache_limit(None)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            rows: self.cache_df.height(),
+            ohlcvv_rows: self.cache_ohlcvv.height(),
+            estimated_bytes: self.cache_df.estimated_size() + self.cache_ohlcvv.estimated_size(),
+            max_rows: self.cache_max_rows,
+            start_time: self.cache_start,
+            end_time: self.cache_end,
+            hit: self.cache_hit,
+            miss: self.cache_miss,
+            evictions: self.cache_evictions,
+            evicted_rows: self.cache_evicted_rows,
+        }
+    }
+
+    /// cache_max_rowsを超えた分を削除する。keep(今回の問い合わせ範囲)内の行は削除しない。
+    fn enforce_cache_limit(&mut self, keep: Option<(MicroSec, MicroSec)>) -> anyhow::Result<()> {
+        let (cut_start, cut_end) = match cache_eviction_range(&self.cache_df, self.cache_max_rows, keep)? {
+            Some(cut) => cut,
+            None => return Ok(()),
+        };
+
+        let rows = self.cache_df.height();
+
+        self.cache_df = select_df_lazy(&self.cache_df, cut_start, cut_end).collect()?;
+        let ohlcv_cut_end = if cut_end == 0 { 0 } else { ohlcv_end(cut_end) };
+        self.cache_ohlcvv = select_df_lazy(&self.cache_ohlcvv, ohlcv_start(cut_start), ohlcv_cut_end).collect()?;
+
+        if self.cache_start < cut_start {
+            self.cache_start = cut_start;
+        }
+        if cut_end != 0 && (self.cache_end == 0 || cut_end < self.cache_end) {
+            self.cache_end = cut_end;
+        }
+
+        self.cache_evictions += 1;
+        self.cache_evicted_rows += (rows - self.cache_df.height()) as i64;
+
+        log::debug!(
+            "evict cache outside {} -> {} ({} rows)",
+            time_string(cut_start),
+            time_string(cut_end),
+            rows - self.cache_df.height()
+        );
+
+        Ok(())
+    }
+
+    /// 指定範囲のキャッシュをtokioのblockingタスクで先読みする（tokio runtime内で呼ぶこと）
+    pub fn prefetch(
+        db: &Arc<Mutex<Self>>,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> tokio::task::JoinHandle<()> {
+        let db = db.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut lock = db.lock().unwrap();
+            if let Err(e) = lock.update_cache_df(start_time, end_time, false) {
+                log::warn!(
+                    "prefetch error {}-{}: {:?}",
+                    time_string(start_time),
+                    time_string(end_time),
+                    e
+                );
+            }
+        })
+    }
+
     pub fn expire_cache_df(&mut self, forget_before: MicroSec) -> anyhow::Result<()>{
         let forget_before = FLOOR_DAY(forget_before); // expire by date.
         log::debug!("Expire cache {}", time_string(forget_before));
@@ -260,6 +632,10 @@ impl TradeDataFrame {
         self.cache_df = select_df_lazy(&self.cache_df, forget_before, 0).collect()?;
         self.cache_ohlcvv = select_df_lazy(&self.cache_ohlcvv, forget_before, 0).collect()?;
 
+        if self.cache_start < forget_before {
+            self.cache_start = forget_before;
+        }
+
         Ok(())
     }
 
@@ -276,12 +652,87 @@ impl TradeDataFrame {
         Ok(())
     }
 
+    fn clear_cache_df(&mut self) {
+        self.cache_df = TradeBuffer::new().to_dataframe();
+        self.cache_ohlcvv = make_empty_ohlcvv();
+        self.cache_start = 0;
+        self.cache_end = 0;
+    }
+
+    fn update_cache_df_lazy(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<()> {
+        let keep = (start_time, end_time);
+
+        let start_time = if start_time == 0 {
+            self.start_time()
+        } else {
+            start_time
+        };
+        let start_time = if start_time == 0 {
+            0
+        } else {
+            ohlcv_start(start_time - SEC(LAZY_CACHE_MARGIN_SEC)).max(0)
+        };
+
+        // 現在時刻を含む範囲は確定していないため、常に末尾を読み直す
+        let now = ohlcv_start(NOW());
+        let open_end = end_time == 0 || now < end_time;
+        let end_time = if open_end {
+            now
+        } else {
+            ohlcv_end(end_time + SEC(LAZY_CACHE_MARGIN_SEC)).min(now)
+        };
+
+        let mut ranges = lazy_fetch_ranges(self.cache_start, self.cache_end, start_time, end_time);
+
+        if open_end {
+            match ranges.last_mut() {
+                Some(last) if last.1 == end_time => last.1 = 0,
+                _ => ranges.push((self.cache_end.max(start_time), 0)),
+            }
+        }
+
+        if ranges.is_empty() {
+            self.cache_hit += 1;
+            return Ok(());
+        }
+        self.cache_miss += 1;
+
+        if self.cache_end == 0 || end_time < self.cache_start || self.cache_end < start_time {
+            self.clear_cache_df();
+        }
+
+        for (from, to) in ranges {
+            log::debug!("lazy cache load {} -> {}", time_string(from), time_string(to));
+            let df = self.fetch_cache_df(from, to)?;
+            self._update_cache_df(&df)?;
+        }
+
+        if self.cache_start == 0 || start_time < self.cache_start {
+            self.cache_start = start_time;
+        }
+        if self.cache_end < end_time {
+            self.cache_end = end_time;
+        }
+
+        self.enforce_cache_limit(Some(keep))
+    }
+
     pub fn update_cache_df(
         &mut self,
         start_time: MicroSec,
         end_time: MicroSec,
         force: bool
     ) -> anyhow::Result<()> {
+        if self.lazy && !force {
+            return self.update_cache_df_lazy(start_time, end_time);
+        }
+
+        let keep = (start_time, end_time);
+
         let start_time = if start_time != 0 {
             FLOOR_DAY(start_time - DAYS(1))
         }
@@ -328,7 +779,10 @@ impl TradeDataFrame {
             }
         };
 
-        Ok(())
+        self.cache_start = start_time_df(&self.cache_df).unwrap_or(0);
+        self.cache_end = end_time_df(&self.cache_df).unwrap_or(0);
+
+        self.enforce_cache_limit(Some(keep))
     }
 
     /*
@@ -508,16 +962,58 @@ impl TradeDataFrame {
         mut start_time: MicroSec,
         end_time: MicroSec,
         time_window_sec: i64,
+        offset_sec: i64,
     ) -> anyhow::Result<DataFrame> {
         start_time = ohlcv_start(start_time); // 開始tickは確定足、終了は未確定足もOK.
 
         self.update_cache_df(start_time, end_time, false)?;
 
-        if time_window_sec % OHLCV_WINDOW_SEC == 0 {
-            ohlcv_from_ohlcvv_df(&self.cache_ohlcvv, start_time, end_time, time_window_sec)
+        // 1分足のキャッシュから作れるのは区切りも分単位の場合のみ
+        if time_window_sec % OHLCV_WINDOW_SEC == 0 && offset_sec % OHLCV_WINDOW_SEC == 0 {
+            ohlcv_from_ohlcvv_df_with_offset(
+                &self.cache_ohlcvv,
+                start_time,
+                end_time,
+                time_window_sec,
+                offset_sec,
+            )
         } else {
-            ohlcv_df(&self.cache_df, start_time, end_time, time_window_sec)
+            ohlcv_df_with_offset(&self.cache_df, start_time, end_time, time_window_sec, offset_sec)
+        }
+    }
+
+    fn _cvd_df(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        time_window_sec: i64,
+        absolute: bool,
+    ) -> anyhow::Result<DataFrame> {
+        if !absolute {
+            let ohlcv = self._ohlcv_df(start_time, end_time, time_window_sec, 0)?;
+            return cvd_df(&ohlcv);
         }
+
+        // DB先頭からの累積値を求めるため全期間を計算してから切り出す
+        let ohlcv = self._ohlcv_df(0, end_time, time_window_sec, 0)?;
+        let df = cvd_df(&ohlcv)?;
+
+        Ok(select_df_lazy(&df, ohlcv_start(start_time), end_time).collect()?)
+    }
+
+    /// 足ごとのdelta(買い-売り)と累積出来高差分(cvd)
+    /// absolute=falseの場合、cvdはstart_timeを起点に0から累積する。
+    pub fn py_cvd_polars(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        absolute: bool,
+    ) -> anyhow::Result<PyDataFrame> {
+        let mut df = self._cvd_df(start_time, end_time, window_sec, absolute)?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
     }
 
     pub fn py_ohlcv_polars(
@@ -526,13 +1022,79 @@ impl TradeDataFrame {
         end_time: MicroSec,
         window_sec: i64,
     ) -> anyhow::Result<PyDataFrame> {
-        let mut df = self._ohlcv_df(start_time, end_time, window_sec)?;
+        self.py_ohlcv_polars_with_offset(start_time, end_time, window_sec, 0)
+    }
+
+    /// 足の区切りをday_start_offset_sec(秒)ずらしたohlcv(日足を取引所の日付に合わせる)
+    pub fn py_ohlcv_polars_with_offset(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        day_start_offset_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        let mut df = self._ohlcv_df(start_time, end_time, window_sec, day_start_offset_sec)?;
         convert_timems_to_datetime(&mut df)?;
         let df = PyDataFrame(df);
 
         return Ok(df);
     }
 
+    /// ohlcvの遅延評価版。キャッシュを参照するLazyFrameを返すので、
+    /// 集計はPython側でフィルタなどを重ねてcollectした時点で行う。
+    pub fn ohlcv_lazy(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+    ) -> anyhow::Result<LazyFrame> {
+        let start_time = ohlcv_start(start_time);
+
+        self.update_cache_df(start_time, end_time, false)?;
+
+        let lazy = if window_sec % OHLCV_WINDOW_SEC == 0 {
+            ohlcv_from_ohlcvv_lazy_df_with_offset(&self.cache_ohlcvv, start_time, end_time, window_sec, 0)
+        } else {
+            ohlcv_lazy_df_with_offset(&self.cache_df, start_time, end_time, window_sec, 0)
+        };
+
+        Ok(lazy.with_column(
+            col(KEY::timestamp).cast(DataType::Datetime(TimeUnit::Microseconds, None)),
+        ))
+    }
+
+    /// ohlcvにsize >= min_sizeの約定の出来高(large_*)を加えたもの
+    pub fn py_ohlcv_large_polars(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        min_size: f64,
+    ) -> anyhow::Result<PyDataFrame> {
+        let start_time = ohlcv_start(start_time);
+
+        self.update_cache_df(start_time, end_time, false)?;
+
+        let mut df = ohlcv_large_df(&self.cache_df, start_time, end_time, window_sec, min_size)?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
+    }
+
+    /// export ohlcv into csv file(with header). returns number of rows.
+    pub fn ohlcv_csv(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        path: &str,
+        iso_time: bool,
+    ) -> anyhow::Result<i64> {
+        let df = self._ohlcv_df(start_time, end_time, window_sec, 0)?;
+
+        ohlcv_df_to_csv(&df, Path::new(path), iso_time)
+    }
+
     pub fn py_vap(
         &mut self,
         start_time: MicroSec,
@@ -559,6 +1121,84 @@ impl TradeDataFrame {
         Ok(df)
     }
 
+    /// Kyle's lambda。window_sec幅の足ごとの価格変化を符号付き出来高で回帰した傾き。
+    pub fn kyles_lambda(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+    ) -> anyhow::Result<f64> {
+        self.update_cache_df(start_time, end_time, false)?;
+
+        kyles_lambda_df(&self.cache_df, start_time, end_time, window_sec)
+    }
+
+    /// step_secごとのKyle's lambda(timestamp, lambda)
+    pub fn rolling_kyles_lambda(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        step_sec: i64,
+    ) -> anyhow::Result<DataFrame> {
+        self.update_cache_df(start_time, end_time, false)?;
+
+        rolling_kyles_lambda_df(&self.cache_df, start_time, end_time, window_sec, step_sec)
+    }
+
+    /// 約定を時刻順にTickBarAggregatorへ流し、確定した足をDataFrameにする(未確定の最後の足は含まない)
+    pub fn tick_bars(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        bar_type: BarType,
+    ) -> anyhow::Result<DataFrame> {
+        let mut aggregator = TickBarAggregator::new(bar_type)?;
+
+        self.update_cache_df(start_time, end_time, false)?;
+        let df = select_df_lazy(&self.cache_df, start_time, end_time).collect()?;
+
+        let timestamp = df.column(KEY::timestamp)?.i64()?;
+        let price = df.column(KEY::price)?.f64()?;
+        let size = df.column(KEY::size)?.f64()?;
+        let order_side = df.column(KEY::order_side)?.str()?;
+
+        let mut bars = vec![];
+
+        for i in 0..df.height() {
+            if let (Some(t), Some(p), Some(s), Some(side)) =
+                (timestamp.get(i), price.get(i), size.get(i), order_side.get(i))
+            {
+                let trade = Trade::new(
+                    t,
+                    OrderSide::from(side),
+                    Decimal::from_f64(p).unwrap_or_default(),
+                    Decimal::from_f64(s).unwrap_or_default(),
+                    LogStatus::UnFix,
+                    "",
+                );
+
+                if let Some(bar) = aggregator.push(&trade) {
+                    bars.push(bar);
+                }
+            }
+        }
+
+        ohlcv_bars_df(&bars)
+    }
+
+    pub fn heat_map(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        time_bins: usize,
+        price_bins: usize,
+    ) -> anyhow::Result<HeatMap> {
+        self.update_cache_df(start_time, end_time, false)?;
+
+        HeatMap::from_df(&self.cache_df, start_time, end_time, time_bins, price_bins)
+    }
+
     pub fn info(&mut self) -> String {
         let min = self.start_time();
         let max = self.end_time();
@@ -628,6 +1268,88 @@ impl TradeDataFrame {
         return self.db.insert_records(trades);
     }
 
+    /// DBの[start_time, end_time)のTradeをAvroファイルへ書き出す（0を指定した場合は全件）
+    pub fn export_avro(
+        &mut self,
+        path: &str,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<i64> {
+        let mut trades: Vec<Trade> = vec![];
+        self.db.select(start_time, end_time, |trade| {
+            trades.push(trade.clone());
+            Ok(())
+        })?;
+
+        let mut writer = AvroTradeWriter::new(path)?;
+        let count = writer.write_trades(&trades)?;
+        writer.close()?;
+
+        Ok(count as i64)
+    }
+
+    /// export_avroで書き出したファイルをDBへ取り込む
+    pub fn import_avro(&mut self, path: &str) -> anyhow::Result<i64> {
+        let trades = AvroTradeReader::read_trades(path)?;
+        self.db.insert_records(&trades)
+    }
+
+    /// DBの[start_time, end_time)の連続する同一価格・同一方向の約定をまとめる（非可逆）
+    pub fn compress_ticks(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<CompressionStats> {
+        let stats = self.db.compress_ticks(start_time, end_time)?;
+        self.clear_cache_df();
+
+        Ok(stats)
+    }
+
+    /// compress_ticksでまとめたデータをアーカイブから復元する。end_time=0は現在時刻まで。
+    /// アーカイブに元データが無い場合はエラー。
+    pub fn decompress_ticks(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<i64> {
+        let end_time = if end_time == 0 { NOW() } else { end_time };
+
+        let archive_start = self.get_archive_start_time();
+        let archive_end = self.get_archive_end_time();
+
+        if archive_start == 0 || start_time < archive_start || archive_end < end_time {
+            return Err(anyhow!(
+                "original data is not archived {}-{} (archive {}-{})",
+                time_string(start_time),
+                time_string(end_time),
+                time_string(archive_start),
+                time_string(archive_end)
+            ));
+        }
+
+        let mut trades: Vec<Trade> = vec![];
+        self.archive.foreach(start_time, end_time, &mut |trade| {
+            if start_time <= trade.time && trade.time < end_time {
+                trades.push(trade.clone());
+            }
+            Ok(())
+        })?;
+
+        let count = self.db.restore_ticks(start_time, end_time, &trades)?;
+        self.clear_cache_df();
+
+        Ok(count)
+    }
+
+    pub fn dedup_by_time_and_price(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<u64> {
+        self.db.dedup_by_time_and_price(start_time, end_time)
+    }
+
     pub fn db_start_up_rec(&mut self) -> Option<Trade> {
         self.db.get_last_start_up_rec()
     }
@@ -654,6 +1376,187 @@ impl TradeDataFrame {
 
             cache_df: df,
             cache_ohlcvv: ohlcv,
+
+            lazy: true,
+            cache_start: 0,
+            cache_end: 0,
+            cache_hit: 0,
+            cache_miss: 0,
+
+            cache_max_rows: DEFAULT_CACHE_MAX_ROWS,
+            cache_evictions: 0,
+            cache_evicted_rows: 0,
         })
     }
 }
+
+#[cfg(test)]
+mod tradedf_test {
+    use super::*;
+    use crate::common::{LogStatus, OrderSide, MIN};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_lazy_fetch_ranges() {
+        let base = DAYS(19000);
+
+        // empty cache: load only requested range
+        let ranges = lazy_fetch_ranges(0, 0, base, base + MIN(10));
+        assert_eq!(ranges, vec![(base, base + MIN(10))]);
+
+        // inside cached range: nothing to load
+        let ranges = lazy_fetch_ranges(base, base + MIN(60), base + MIN(10), base + MIN(20));
+        assert!(ranges.is_empty());
+
+        // overlap: only missing head/tail
+        let ranges = lazy_fetch_ranges(base + MIN(10), base + MIN(20), base, base + MIN(30));
+        assert_eq!(
+            ranges,
+            vec![(base, base + MIN(10)), (base + MIN(20), base + MIN(30))]
+        );
+
+        // never outside the queried range
+        let ranges = lazy_fetch_ranges(base - DAYS(3), base - DAYS(2), base, base + MIN(5));
+        for (from, to) in ranges {
+            assert!(base <= from);
+            assert!(to <= base + MIN(5));
+        }
+    }
+
+    #[test]
+    fn test_cache_eviction_range() -> anyhow::Result<()> {
+        let mut buffer = TradeBuffer::new();
+        for i in 0..10 {
+            let trade = Trade::new(
+                SEC(i),
+                OrderSide::Buy,
+                dec![100.0],
+                dec![1.0],
+                LogStatus::FixArchiveBlock,
+                &format!("{}", i),
+            );
+            buffer.push_trades(vec![trade]);
+        }
+        let df = buffer.to_dataframe();
+
+        assert_eq!(cache_eviction_range(&df, 0, None)?, None);
+        assert_eq!(cache_eviction_range(&df, 10, None)?, None);
+
+        // keep latest 3 rows
+        let (start, end) = cache_eviction_range(&df, 3, None)?.unwrap();
+        assert_eq!((start, end), (SEC(7), 0));
+        assert_eq!(select_df_lazy(&df, start, end).collect()?.height(), 3);
+
+        // 問い合わせ範囲より前から削除し、足りなければ後ろから削除する
+        let (start, end) = cache_eviction_range(&df, 3, Some((SEC(2), SEC(5))))?.unwrap();
+        assert_eq!((start, end), (SEC(2), SEC(5)));
+        assert_eq!(select_df_lazy(&df, start, end).collect()?.height(), 3);
+
+        let (start, end) = cache_eviction_range(&df, 5, Some((SEC(4), SEC(6))))?.unwrap();
+        assert_eq!((start, end), (SEC(4), SEC(9)));
+        assert_eq!(select_df_lazy(&df, start, end).collect()?.height(), 5);
+
+        // 問い合わせ範囲だけで上限を超える場合は範囲内を削除しない
+        let (start, end) = cache_eviction_range(&df, 3, Some((SEC(1), SEC(8))))?.unwrap();
+        assert_eq!((start, end), (SEC(1), SEC(8)));
+        assert_eq!(select_df_lazy(&df, start, end).collect()?.height(), 7);
+
+        assert_eq!(cache_eviction_range(&df, 3, Some((0, 0)))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_ohlcvv_with_archive_tick_direction() -> anyhow::Result<()> {
+        use crate::db::{db_path_root, df_to_parquet};
+        use polars::prelude::*;
+
+        let mut config = MarketConfig::default();
+        config.exchange_name = "TEST".to_string();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = format!("TICK{}", std::process::id());
+
+        let root = db_path_root(&config.exchange_name, &config.trade_category, &config.trade_symbol, false);
+
+        let day = DAYS(19000);
+
+        // tick_directionはorder_sideと逆向きのものを含める。範囲外(1分, 60分)の約定も置く。
+        let mut archive_df = df![
+            KEY::timestamp => [day + MIN(1), day + MIN(11), day + MIN(12), day + MIN(12) + SEC(1), day + MIN(60)],
+            KEY::order_side => ["Buy", "Buy", "Buy", "Sell", "Sell"],
+            KEY::price => [100.0, 100.0, 101.0, 101.0, 102.0],
+            KEY::size => [9.0, 1.0, 2.0, 3.0, 9.0],
+            KEY::id => ["a", "b", "c", "d", "e"],
+            KEY::tick_direction => [1i64, -1, 1, 1, 1]
+        ]?;
+
+        let archive = TradeArchive::new(&config, false);
+        df_to_parquet(&mut archive_df, &archive.file_path(day))?;
+
+        let result = (|| -> anyhow::Result<()> {
+            let mut db = TradeDataFrame::open(&config, false)?;
+            assert!(db.is_lazy());
+
+            let ohlcvv = db.py_ohlcvv_polars(day + MIN(10), day + MIN(20), 60)?.0;
+
+            let buy_volume: f64 = ohlcvv.column(KEY::buy_volume)?.f64()?.sum().unwrap();
+            let sell_volume: f64 = ohlcvv.column(KEY::sell_volume)?.f64()?.sum().unwrap();
+            assert_eq!(buy_volume, 5.0);
+            assert_eq!(sell_volume, 1.0);
+
+            // lazyモードは問い合わせ範囲(+余白)外の約定を読み込まない
+            assert_eq!(db.cache_stats().rows, 3);
+
+            Ok(())
+        })();
+
+        let _ = std::fs::remove_dir_all(&root);
+
+        result
+    }
+
+    #[test]
+    fn test_decompress_ticks_open_end() -> anyhow::Result<()> {
+        use crate::db::{db_path_root, df_to_parquet};
+        use polars::prelude::*;
+
+        let mut config = MarketConfig::default();
+        config.exchange_name = "TEST".to_string();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = format!("DECOMP{}", std::process::id());
+
+        let root = db_path_root(&config.exchange_name, &config.trade_category, &config.trade_symbol, false);
+
+        let day = DAYS(19000);
+
+        let mut archive_df = df![
+            KEY::timestamp => [day + MIN(1), day + MIN(2)],
+            KEY::order_side => ["Buy", "Buy"],
+            KEY::price => [100.0, 100.0],
+            KEY::size => [1.0, 2.0],
+            KEY::id => ["a", "b"],
+            KEY::tick_direction => [1i64, 1]
+        ]?;
+
+        let archive = TradeArchive::new(&config, false);
+        df_to_parquet(&mut archive_df, &archive.file_path(day))?;
+
+        let result = (|| -> anyhow::Result<()> {
+            let mut db = TradeDataFrame::open(&config, false)?;
+            db.insert_records(&vec![
+                Trade::new(day + MIN(1), OrderSide::Buy, dec![100.0], dec![3.0], LogStatus::Aggregate, "a"),
+                Trade::new(NOW() - MIN(1), OrderSide::Sell, dec![100.0], dec![1.0], LogStatus::UnFix, "ws-1"),
+            ])?;
+
+            // end_time=0は現在時刻まで。アーカイブは当日までしか無いので何も消さずにエラーにする
+            assert!(db.decompress_ticks(day, 0).is_err());
+            assert_eq!(db.db.count_trades(day, NOW())?, 2);
+
+            Ok(())
+        })();
+
+        let _ = std::fs::remove_dir_all(&root);
+
+        result
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright(c) 2022-2024. yasstake. All rights reserved.
+
+//! テスト用のHTTPモックサーバ(`test-util` featureまたはrbot_lib自身のテストでのみ有効)
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// 127.0.0.1の空きポートで待ち受け、受け取ったリクエスト(ヘッダとbody)を記録して
+/// handler(request)の結果をJSONのbodyとして返すHTTPサーバ
+pub struct MockHttpServer {
+    url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockHttpServer {
+    pub async fn start<F>(mut handler: F) -> Self
+    where
+        F: FnMut(&str) -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = String::new();
+                let mut buf = vec![0u8; 4096];
+
+                // ヘッダとbodyが分かれて届くことがあるのでContent-Length分まで読む
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request += &String::from_utf8_lossy(&buf[..n]);
+
+                    if let Some((head, body)) = request.split_once("\r\n\r\n") {
+                        let length: usize = request_header(head, "content-length")
+                            .unwrap_or("0")
+                            .parse()
+                            .unwrap();
+                        if length <= body.len() {
+                            break;
+                        }
+                    }
+
+                    if n == 0 {
+                        break;
+                    }
+                }
+
+                let body = handler(&request);
+                received.lock().unwrap().push(request);
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// 受け取ったリクエスト全体(受信順)
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// 受け取ったリクエストの1行目("GET /path?query HTTP/1.1")
+    pub fn request_lines(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|r| request_line(r).to_string())
+            .collect()
+    }
+}
+
+/// リクエストの1行目
+pub fn request_line(request: &str) -> &str {
+    request.lines().next().unwrap_or("")
+}
+
+/// ヘッダの値(ヘッダ名の大文字小文字は区別しない)
+pub fn request_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .lines()
+        .take_while(|l| !l.is_empty())
+        .find_map(|l| {
+            l.split_once(": ")
+                .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v)
+        })
+}
+
+/// リクエストのbody
+pub fn request_body(request: &str) -> &str {
+    request.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or("")
+}
+
+#[cfg(test)]
+mod mock_test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_http_server() -> anyhow::Result<()> {
+        let server = MockHttpServer::start(|request| {
+            format!(r#"{{"path":"{}"}}"#, request_line(request).split(' ').nth(1).unwrap_or(""))
+        })
+        .await;
+
+        let client = reqwest::Client::new();
+
+        let body = client.get(format!("{}/a?x=1", server.url())).send().await?.text().await?;
+        assert_eq!(body, r#"{"path":"/a?x=1"}"#);
+
+        client
+            .post(format!("{}/b", server.url()))
+            .header("X-Test", "value")
+            .body(r#"{"k":"v"}"#)
+            .send()
+            .await?;
+
+        assert_eq!(server.request_lines(), vec!["GET /a?x=1 HTTP/1.1", "POST /b HTTP/1.1"]);
+
+        let request = &server.requests()[1];
+        assert_eq!(request_header(request, "x-test"), Some("value"));
+        assert_eq!(request_body(request), r#"{"k":"v"}"#);
+
+        Ok(())
+    }
+}
@@ -3,6 +3,8 @@ pub mod udp;
 pub mod rest;
 pub mod ws;
 pub mod ccxt;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 
 pub use udp::*;
 pub use rest::*;
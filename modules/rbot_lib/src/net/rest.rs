@@ -2,6 +2,8 @@
 // Abloultely no warranty.
 
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Context;
@@ -10,21 +12,27 @@ use parquet::format::PageType;
 use polars::lazy::frame::LazyFrame;
 use pyo3::Py;
 use pyo3::PyAny;
+use pyo3::pyfunction;
 use pyo3::Python;
 use reqwest::StatusCode;
 use tempfile::tempdir;
 
 // use crossbeam_channel::Receiver;
-use crate::common::time_string;
+use crate::common::{date_string, time_string, FLOOR_DAY, NOW};
 use crate::common::AccountCoins;
 use crate::common::ExchangeConfig;
 use crate::common::Kline;
+use crate::common::MarketError;
 use crate::common::{
-    BoardTransfer, MarketConfig, MicroSec, Order, OrderSide, OrderType, Trade, DAYS, TODAY,
+    BoardTransfer, MarketConfig, MicroSec, Order, OrderSide, OrderType, TimeInForce, Trade, DAYS, TODAY,
 };
+use crate::db::append_df;
 use crate::db::csv_to_df;
 use crate::db::df_to_parquet;
+use crate::db::drop_invalid_trade_rows;
 use crate::db::log_download_tmp;
+use crate::db::select_df_lazy;
+use crate::db::TradeArchive;
 use polars::frame::DataFrame;
 use reqwest::Method;
 use rust_decimal::Decimal;
@@ -159,12 +167,71 @@ pub trait RestApi {
         size: Decimal,
         order_type: OrderType,
         client_order_id: Option<&str>,
+        time_in_force: TimeInForce, // when order_type is Market, this value is ignored.
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>>;
+
+    /// ヘッジモードのポジション(position_idx)を指定して発注する。
+    /// 対応していない取引所でposition_idxを指定するとエラー。
+    async fn new_order_with_position_idx(
+        &self,
+        config: &MarketConfig,
+        side: OrderSide,
+        price: Decimal,
+        size: Decimal,
+        order_type: OrderType,
+        client_order_id: Option<&str>,
+        position_idx: Option<u8>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
+    ) -> anyhow::Result<Vec<Order>> {
+        if position_idx.is_some() {
+            return Err(MarketError::invalid_order(&format!(
+                "position_idx is not supported by {}",
+                self.get_exchange().get_exchange_name()
+            ))
+            .into());
+        }
+
+        self.new_order(
+            config,
+            side,
+            price,
+            size,
+            order_type,
+            client_order_id,
+            time_in_force,
+            reduce_only,
+        )
+        .await
+    }
+
     async fn cancel_order(&self, config: &MarketConfig, order_id: &str) -> anyhow::Result<Order>;
     async fn open_orders(&self, config: &MarketConfig) -> anyhow::Result<Vec<Order>>;
 
+    /// 注文IDで注文の現在の状態を問い合わせる(約定・キャンセル済みの注文も含む)。
+    /// execute_sizeは累計の約定量。
+    async fn get_order_status(&self, config: &MarketConfig, order_id: &str) -> anyhow::Result<Order> {
+        Err(anyhow!("get_order_status is not supported in {} ({})", self.get_exchange().get_exchange_name(), order_id))
+    }
+
     async fn get_account(&self) -> anyhow::Result<AccountCoins>;
 
+    /// 取引所のサーバ時刻
+    async fn get_server_time(&self) -> anyhow::Result<MicroSec> {
+        Err(anyhow!("get_server_time is not supported in {}", self.get_exchange().get_exchange_name()))
+    }
+
+    /// APIキーの権限。キーが無効な場合はエラー。
+    async fn get_api_permissions(&self) -> anyhow::Result<Vec<String>> {
+        Err(anyhow!("get_api_permissions is not supported in {}", self.get_exchange().get_exchange_name()))
+    }
+
+    /// 取引所の銘柄情報(tick size, 最小数量, 契約乗数)を反映したMarketConfig
+    async fn get_instrument_info(&self, config: &MarketConfig) -> anyhow::Result<MarketConfig> {
+        Err(anyhow!("get_instrument_info is not supported in {} ({})", self.get_exchange().get_exchange_name(), config.trade_symbol))
+    }
+
     fn history_web_url(&self, config: &MarketConfig, date: MicroSec) -> String;
     fn logdf_to_archivedf(&self, df: &DataFrame) -> anyhow::Result<DataFrame>;
 
@@ -187,6 +254,25 @@ pub trait RestApi {
         date: MicroSec,
         f: F,
     ) -> anyhow::Result<i64>
+    where
+        F: FnMut(i64, i64),
+    {
+        let mut archive_df = self.web_archive_to_df(config, date, f).await?;
+
+        log::debug!("store paquet");
+        let rec = df_to_parquet(&mut archive_df, &parquet_file)?;
+        log::debug!("done {} [rec]", rec);
+
+        Ok(rec)
+    }
+
+    /// 1日分のアーカイブをダウンロードしてarchive形式のDataFrameにする(不正な行は除く)
+    async fn web_archive_to_df<F>(
+        &self,
+        config: &MarketConfig,
+        date: MicroSec,
+        f: F,
+    ) -> anyhow::Result<DataFrame>
     where
         F: FnMut(i64, i64),
     {
@@ -207,23 +293,122 @@ pub trait RestApi {
             log::debug!("read log csv to df");
             let df = csv_to_df(&file_path)?;
 
-            let mut archive_df = self.logdf_to_archivedf(&df)?;
+            let archive_df = self.logdf_to_archivedf(&df)?;
             log::debug!("archive df shape={:?}", archive_df.shape());
 
-            log::debug!("store paquet");
-            let rec = df_to_parquet(&mut archive_df, &parquet_file)?;
-            log::debug!("done {} [rec]", rec);
+            let (archive_df, skipped) = drop_invalid_trade_rows(&archive_df)?;
+            if 0 < skipped {
+                log::warn!("{} invalid rows are skipped in {}", skipped, url);
+            }
 
-            return Ok(rec)
+            return Ok(archive_df);
         }
 
         Err(anyhow!("Unknown file type {:?}", file_path))
     }
+}
+
+
+/// [start_time, end_time)のアーカイブを1日ずつダウンロードしてDataFrameに連結する。
+/// SQLiteやparquetには保存しない。アーカイブのない日は飛ばす。end_timeが0の場合は現在まで。
+/// カラムはTradeTableのキャッシュ(TradeArchive::fetch_cachedf)と同じで、
+/// timestamp, order_side, price, size, idの後に取引所固有のカラム(tick_directionなど)が続く。
+pub async fn download_to_dataframe<T>(
+    api: &T,
+    config: &MarketConfig,
+    start_time: MicroSec,
+    end_time: MicroSec,
+) -> anyhow::Result<DataFrame>
+where
+    T: RestApi,
+{
+    let end_time = if end_time == 0 { NOW() } else { end_time };
+
+    let mut df = TradeArchive::make_empty_cachedf();
+    let mut found = false;
+    let mut date = FLOOR_DAY(start_time);
+
+    while date < end_time {
+        if !api.has_web_archive(config, date).await? {
+            log::info!("download_to_dataframe: no archive {}", date_string(date));
+            date += DAYS(1);
+            continue;
+        }
+
+        let day_df = api.web_archive_to_df(config, date, |_, _| {}).await?;
+        let day_df = select_df_lazy(&day_df, start_time, end_time).collect()?;
+        log::debug!("download_to_dataframe: {} {:?}", date_string(date), day_df.shape());
+
+        df = append_df(&df, &day_df)?;
+        found = true;
+
+        date += DAYS(1);
+    }
+
+    if !found {
+        return Err(anyhow!("no archive in {} - {}", time_string(start_time), time_string(end_time)));
+    }
+
+    Ok(df)
+}
+
+/// HTTP接続のタイムアウト(秒)のデフォルト
+pub const HTTP_CONNECT_TIMEOUT_SEC: u64 = 10;
+/// HTTPの読み込み(無通信)タイムアウト(秒)のデフォルト
+pub const HTTP_READ_TIMEOUT_SEC: u64 = 30;
+
+static CONNECT_TIMEOUT_SEC: AtomicU64 = AtomicU64::new(HTTP_CONNECT_TIMEOUT_SEC);
+static READ_TIMEOUT_SEC: AtomicU64 = AtomicU64::new(HTTP_READ_TIMEOUT_SEC);
+
+/// 以後に作るHTTPクライアントのタイムアウト(秒)を設定する。
+#[pyfunction]
+#[pyo3(signature = (connect_sec=HTTP_CONNECT_TIMEOUT_SEC, read_sec=HTTP_READ_TIMEOUT_SEC))]
+pub fn set_http_timeout(connect_sec: u64, read_sec: u64) {
+    CONNECT_TIMEOUT_SEC.store(connect_sec, Ordering::Relaxed);
+    READ_TIMEOUT_SEC.store(read_sec, Ordering::Relaxed);
+}
+
+/// (connect_sec, read_sec)
+#[pyfunction]
+pub fn get_http_timeout() -> (u64, u64) {
+    (
+        CONNECT_TIMEOUT_SEC.load(Ordering::Relaxed),
+        READ_TIMEOUT_SEC.load(Ordering::Relaxed),
+    )
+}
+
+/// タイムアウトを設定したreqwestのClientBuilder。各取引所のHTTPクライアントはこれから作る。
+pub fn http_client_builder() -> reqwest::ClientBuilder {
+    let (connect_sec, read_sec) = get_http_timeout();
 
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(connect_sec))
+        .read_timeout(Duration::from_secs(read_sec))
+}
 
+pub fn http_client() -> anyhow::Result<reqwest::Client> {
+    http_client_builder()
+        .build()
+        .with_context(|| "build http client error")
+}
 
+/// reqwestのエラーをMarketError::Networkにする(タイムアウトはメッセージで区別する)
+pub fn network_error(e: &reqwest::Error) -> MarketError {
+    if e.is_timeout() {
+        MarketError::Network(format!("timeout: {}", e))
+    } else {
+        MarketError::Network(e.to_string())
+    }
 }
 
+/// errorのchainにタイムアウトが含まれるか
+pub fn is_timeout_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .map(|e| e.is_timeout())
+            .unwrap_or(false)
+    })
+}
 
 pub async fn do_rest_request(
     method: Method,
@@ -231,7 +416,7 @@ pub async fn do_rest_request(
     headers: Vec<(&str, &str)>,
     body: &str,
 ) -> anyhow::Result<String> {
-    let client = reqwest::Client::new();
+    let client = http_client()?;
 
     let mut request_builder = client.request(method.clone(), url);
 
@@ -248,16 +433,22 @@ pub async fn do_rest_request(
         .header("User-Agent", "Mozilla/5.0")
         .header("Accept", "text/html");
 
-    let response = request_builder
-        .send()
-        .await
-        .with_context(|| format!("URL get error {url:}"))?;
+    let response = match request_builder.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(anyhow::Error::new(network_error(&e))
+                .context(format!("URL get error {url:}")));
+        }
+    };
 
     if response.status().as_str() == "200" {
-        let body = response
-            .text()
-            .await
-            .with_context(|| format!("response text error"))?;
+        let body = match response.text().await {
+            Ok(body) => body,
+            Err(e) => {
+                return Err(anyhow::Error::new(network_error(&e))
+                    .context(format!("response text error {url:}")));
+            }
+        };
 
         return Ok(body);
     }
@@ -269,12 +460,14 @@ pub async fn do_rest_request(
             log::error!("NOT FOUND url={}, {}", url, body);
             println!("NOT FOUND url={}, {}", url, body);
         },
-        StatusCode::FORBIDDEN |
         StatusCode::UNAUTHORIZED => {
             log::error!("AUTH ERROR url={}, {}", url, body);
             println!("AUTH ERROR url={}, {}", url, body);
             println!("Please check access key and token");
         }
+        StatusCode::FORBIDDEN => {
+            log::error!("FORBIDDEN (auth or access restriction) url={}, {}", url, body);
+        }
         _ => {
             let code = status.as_u16();
 
@@ -287,13 +480,15 @@ pub async fn do_rest_request(
         }
     }
 
-    Err(anyhow!(
+    let message = format!(
         "Response code = {} / download size {:?} / method({:?}) /  response body = {}",
         response.status().as_str(),
         response.content_length(),
         method,
         &body,
-    ))
+    );
+
+    Err(anyhow::Error::new(MarketError::from_http_status(status.as_u16(), &body)).context(message))
 }
 
 pub async fn rest_get(
@@ -350,7 +545,7 @@ pub async fn rest_put(
 }
 
 pub async fn check_exist(url: &str) -> anyhow::Result<bool> {
-    let client = reqwest::Client::new();
+    let client = http_client()?;
 
     let response = client
         .head(url)
@@ -436,4 +631,211 @@ mod test_exchange {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_read_timeout() -> anyhow::Result<()> {
+        use crate::common::MarketError;
+        use crate::net::{http_client_builder, is_timeout_error, network_error};
+        use std::time::Duration;
+
+        // 接続は受けるが応答しないサーバー
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let client = http_client_builder()
+            .read_timeout(Duration::from_millis(100))
+            .build()?;
+
+        let e = client
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(e.is_timeout());
+
+        match network_error(&e) {
+            MarketError::Network(message) => assert!(message.starts_with("timeout")),
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        assert!(is_timeout_error(&anyhow::Error::new(e).context("wrapped")));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod download_to_dataframe_test {
+    use super::*;
+    use crate::common::MIN;
+    use crate::db::{db_path_root, df_to_parquet, KEY};
+    use polars::df;
+    use polars::prelude::NamedFrom;
+    use rust_decimal_macros::dec;
+
+    const ARCHIVE_DAY: MicroSec = 19000 * 24 * 60 * 60 * 1_000_000;
+
+    /// ARCHIVE_DAYのアーカイブだけを返すRestApi(bybitと同じくtick_directionを含む)
+    struct ArchiveOnlyApi {}
+
+    fn archive_day_df() -> DataFrame {
+        df![
+            KEY::timestamp => [ARCHIVE_DAY + MIN(1), ARCHIVE_DAY + MIN(2), ARCHIVE_DAY + MIN(3)],
+            KEY::order_side => ["Buy", "Sell", "Buy"],
+            KEY::price => [100.0, 101.0, 102.0],
+            KEY::size => [1.0, 2.0, 3.0],
+            KEY::id => ["a", "b", "c"],
+            KEY::tick_direction => [1i64, -1, 1]
+        ]
+        .unwrap()
+    }
+
+    impl RestApi for ArchiveOnlyApi {
+        fn get_exchange(&self) -> ExchangeConfig {
+            ExchangeConfig::new("TEST", false, "", "", "", "", "")
+        }
+
+        async fn get_klines(
+            &self,
+            _config: &MarketConfig,
+            _start_time: MicroSec,
+            _end_time: MicroSec,
+            _page: &RestPage,
+        ) -> anyhow::Result<(Vec<Kline>, RestPage)> {
+            Err(anyhow!("not supported"))
+        }
+
+        fn klines_width(&self) -> i64 {
+            60
+        }
+
+        async fn new_order(
+            &self,
+            _config: &MarketConfig,
+            _side: OrderSide,
+            _price: Decimal,
+            _size: Decimal,
+            _order_type: OrderType,
+            _client_order_id: Option<&str>,
+            _time_in_force: TimeInForce,
+            _reduce_only: bool,
+        ) -> anyhow::Result<Vec<Order>> {
+            Err(anyhow!("not supported"))
+        }
+
+        async fn cancel_order(&self, _config: &MarketConfig, _order_id: &str) -> anyhow::Result<Order> {
+            Err(anyhow!("not supported"))
+        }
+
+        async fn open_orders(&self, _config: &MarketConfig) -> anyhow::Result<Vec<Order>> {
+            Err(anyhow!("not supported"))
+        }
+
+        async fn get_account(&self) -> anyhow::Result<AccountCoins> {
+            Err(anyhow!("not supported"))
+        }
+
+        fn history_web_url(&self, _config: &MarketConfig, _date: MicroSec) -> String {
+            "".to_string()
+        }
+
+        fn logdf_to_archivedf(&self, df: &DataFrame) -> anyhow::Result<DataFrame> {
+            Ok(df.clone())
+        }
+
+        async fn has_web_archive(&self, _config: &MarketConfig, date: MicroSec) -> anyhow::Result<bool> {
+            Ok(date == ARCHIVE_DAY)
+        }
+
+        async fn web_archive_to_df<F>(&self, _config: &MarketConfig, _date: MicroSec, _f: F) -> anyhow::Result<DataFrame>
+        where
+            F: FnMut(i64, i64),
+        {
+            Ok(archive_day_df())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_order_with_position_idx_unsupported() -> anyhow::Result<()> {
+        let config = MarketConfig::default();
+        let api = ArchiveOnlyApi {};
+
+        // position_idxに対応していない取引所では送信前にエラー
+        let e = api
+            .new_order_with_position_idx(
+                &config,
+                OrderSide::Buy,
+                dec![100.0],
+                dec![1.0],
+                OrderType::Limit,
+                None,
+                Some(1),
+                TimeInForce::GTC,
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("position_idx"));
+
+        // Noneならnew_orderと同じ
+        let e = api
+            .new_order_with_position_idx(
+                &config,
+                OrderSide::Buy,
+                dec![100.0],
+                dec![1.0],
+                OrderType::Limit,
+                None,
+                None,
+                TimeInForce::GTC,
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "not supported");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_to_dataframe_schema() -> anyhow::Result<()> {
+        let mut config = MarketConfig::default();
+        config.exchange_name = "TEST".to_string();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = format!("DL{}", std::process::id());
+
+        let root = db_path_root(&config.exchange_name, &config.trade_category, &config.trade_symbol, false);
+
+        let start_time = ARCHIVE_DAY - DAYS(1);
+        let end_time = ARCHIVE_DAY + MIN(3);
+
+        let df = download_to_dataframe(&ArchiveOnlyApi {}, &config, start_time, end_time).await?;
+
+        let result = (|| -> anyhow::Result<()> {
+            // 同じアーカイブをTradeTableのキャッシュとして読み込んだものと一致する
+            let mut archive = TradeArchive::new(&config, false);
+            df_to_parquet(&mut archive_day_df(), &archive.file_path(ARCHIVE_DAY))?;
+            archive.analyze()?;
+
+            let cache_df = archive.fetch_cachedf(start_time, end_time)?;
+
+            assert_eq!(df.schema(), cache_df.schema());
+            assert_eq!(df, cache_df);
+            assert_eq!(df.shape(), (2, 6));
+            assert_eq!(
+                df.get_column_names(),
+                vec![KEY::timestamp, KEY::order_side, KEY::price, KEY::size, KEY::id, KEY::tick_direction]
+            );
+
+            Ok(())
+        })();
+
+        let _ = std::fs::remove_dir_all(&root);
+
+        result
+    }
 }
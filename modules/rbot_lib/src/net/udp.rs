@@ -1,6 +1,8 @@
+use std::collections::HashMap;
 use std::mem::MaybeUninit;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use futures::Stream;
 
@@ -15,7 +17,230 @@ use serde_derive::Serialize;
 
 use async_stream::stream;
 
-use crate::common::{env_rbot_multicast_addr, env_rbot_multicast_port, MarketMessage};
+use crate::common::{env_rbot_multicast_addr, env_rbot_multicast_port, MarketMessage, NOW};
+
+/// UDPデータグラムの先頭につけるマジックナンバー
+pub const UDP_MAGIC: [u8; 2] = *b"RB";
+/// UDPメッセージのスキーマバージョン。BroadcastMessageの形式を変えたら上げる。
+pub const UDP_VERSION: u8 = 1;
+/// magic(2) + version(1) + type(1) + sender(4) + msg_id(4) + index(2) + count(2) + length(4)
+pub const UDP_HEADER_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpMessageType {
+    Text = 0,
+    Broadcast = 1,
+}
+
+impl TryFrom<u8> for UdpMessageType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(UdpMessageType::Text),
+            1 => Ok(UdpMessageType::Broadcast),
+            _ => Err(anyhow::anyhow!("unknown udp message type {}", value)),
+        }
+    }
+}
+
+/// 各データグラムのヘッダ。大きいメッセージはfrag_count個に分割して送る。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub version: u8,
+    pub msg_type: UdpMessageType,
+    pub sender: u32,
+    pub msg_id: u32,
+    pub frag_index: u16,
+    pub frag_count: u16,
+    pub length: u32,
+}
+
+impl UdpHeader {
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&UDP_MAGIC);
+        buf.push(self.version);
+        buf.push(self.msg_type as u8);
+        buf.extend_from_slice(&self.sender.to_be_bytes());
+        buf.extend_from_slice(&self.msg_id.to_be_bytes());
+        buf.extend_from_slice(&self.frag_index.to_be_bytes());
+        buf.extend_from_slice(&self.frag_count.to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+    }
+}
+
+/// payloadをヘッダ付きのデータグラムに分割する(1データグラムはUDP_SIZE以下)
+pub fn encode(
+    msg_type: UdpMessageType,
+    sender: u32,
+    msg_id: u32,
+    payload: &[u8],
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let chunk_size = UDP_SIZE - UDP_HEADER_SIZE;
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![payload]
+    } else {
+        payload.chunks(chunk_size).collect()
+    };
+
+    if chunks.len() > u16::MAX as usize {
+        return Err(anyhow::anyhow!(
+            "udp message too large ({} bytes)",
+            payload.len()
+        ));
+    }
+
+    let frag_count = chunks.len() as u16;
+
+    let datagrams = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let header = UdpHeader {
+                version: UDP_VERSION,
+                msg_type,
+                sender,
+                msg_id,
+                frag_index: i as u16,
+                frag_count,
+                length: chunk.len() as u32,
+            };
+
+            let mut buf = Vec::with_capacity(UDP_HEADER_SIZE + chunk.len());
+            header.write(&mut buf);
+            buf.extend_from_slice(chunk);
+            buf
+        })
+        .collect();
+
+    Ok(datagrams)
+}
+
+/// データグラムのヘッダを検証してpayloadを取り出す。
+/// マジックナンバーやバージョンが違う場合はエラー。
+pub fn decode(datagram: &[u8]) -> anyhow::Result<(UdpHeader, &[u8])> {
+    if datagram.len() < UDP_HEADER_SIZE || datagram[0..2] != UDP_MAGIC {
+        return Err(anyhow::anyhow!(
+            "not a rbot udp message ({} bytes)",
+            datagram.len()
+        ));
+    }
+
+    let version = datagram[2];
+    if version != UDP_VERSION {
+        return Err(anyhow::anyhow!(
+            "udp message version mismatch: received v{}, expected v{}. update rbot on both sender and receiver",
+            version,
+            UDP_VERSION
+        ));
+    }
+
+    let u16_at = |i: usize| u16::from_be_bytes([datagram[i], datagram[i + 1]]);
+    let u32_at = |i: usize| {
+        u32::from_be_bytes([datagram[i], datagram[i + 1], datagram[i + 2], datagram[i + 3]])
+    };
+
+    let header = UdpHeader {
+        version,
+        msg_type: UdpMessageType::try_from(datagram[3])?,
+        sender: u32_at(4),
+        msg_id: u32_at(8),
+        frag_index: u16_at(12),
+        frag_count: u16_at(14),
+        length: u32_at(16),
+    };
+
+    let payload = &datagram[UDP_HEADER_SIZE..];
+    if payload.len() != header.length as usize {
+        return Err(anyhow::anyhow!(
+            "udp payload length mismatch: header {}, actual {}",
+            header.length,
+            payload.len()
+        ));
+    }
+
+    if header.frag_count == 0 || header.frag_count <= header.frag_index {
+        return Err(anyhow::anyhow!("invalid udp fragment {:?}", header));
+    }
+
+    Ok((header, payload))
+}
+
+/// 組み立て中に保持する最大メッセージ数。超えたら古いものから捨てる。
+const MAX_PENDING_MESSAGES: usize = 64;
+
+#[derive(Debug)]
+struct PendingMessage {
+    msg_type: UdpMessageType,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    seq: u64,
+}
+
+/// 分割されたデータグラムを(sender, msg_id)ごとに組み立てる
+#[derive(Debug, Default)]
+pub struct UdpReassembler {
+    pending: HashMap<(u32, u32), PendingMessage>,
+    seq: u64,
+}
+
+impl UdpReassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// データグラムを追加し、メッセージが揃ったら(type, payload)を返す
+    pub fn push(&mut self, datagram: &[u8]) -> anyhow::Result<Option<(UdpMessageType, Vec<u8>)>> {
+        let (header, payload) = decode(datagram)?;
+
+        if header.frag_count == 1 {
+            return Ok(Some((header.msg_type, payload.to_vec())));
+        }
+
+        let key = (header.sender, header.msg_id);
+
+        if !self.pending.contains_key(&key) && MAX_PENDING_MESSAGES <= self.pending.len() {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, m)| m.seq)
+                .map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                log::warn!("drop incomplete udp message {:?}", oldest);
+                self.pending.remove(&oldest);
+            }
+        }
+
+        self.seq += 1;
+        let seq = self.seq;
+        let message = self.pending.entry(key).or_insert_with(|| PendingMessage {
+            msg_type: header.msg_type,
+            fragments: vec![None; header.frag_count as usize],
+            received: 0,
+            seq,
+        });
+
+        if message.fragments.len() != header.frag_count as usize {
+            self.pending.remove(&key);
+            return Err(anyhow::anyhow!("udp fragment count mismatch {:?}", header));
+        }
+
+        let slot = &mut message.fragments[header.frag_index as usize];
+        if slot.is_none() {
+            *slot = Some(payload.to_vec());
+            message.received += 1;
+        }
+
+        if message.received < message.fragments.len() {
+            return Ok(None);
+        }
+
+        let message = self.pending.remove(&key).unwrap();
+        let payload = message.fragments.into_iter().flatten().flatten().collect();
+
+        Ok(Some((message.msg_type, payload)))
+    }
+}
 
 #[pyclass]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,6 +269,8 @@ impl Into<MarketMessage> for BroadcastMessage {
 pub struct UdpSender {
     socket: Socket,
     multicast_addr: SockAddr,
+    sender_id: u32,
+    msg_id: AtomicU32,
 }
 
 impl UdpSender {
@@ -78,13 +305,28 @@ impl UdpSender {
         Self {
             socket: socket,
             multicast_addr: multicast_addr.into(),
+            sender_id: std::process::id() ^ (NOW() as u32),
+            msg_id: AtomicU32::new(0),
         }
     }
 
     pub fn send(&self, message: &str) -> Result<usize, std::io::Error> {
         log::debug!("UDP send: [{:?}], {}", &self.multicast_addr, message);
-        self.socket
-            .send_to(message.as_bytes(), &self.multicast_addr)
+        self.send_frames(UdpMessageType::Text, message.as_bytes())
+    }
+
+    /// ヘッダをつけて送る。UDP_SIZEを超える場合は分割して送る。
+    fn send_frames(&self, msg_type: UdpMessageType, payload: &[u8]) -> Result<usize, std::io::Error> {
+        let msg_id = self.msg_id.fetch_add(1, Ordering::Relaxed);
+        let datagrams = encode(msg_type, self.sender_id, msg_id, payload)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+
+        let mut size = 0;
+        for datagram in datagrams {
+            size += self.socket.send_to(&datagram, &self.multicast_addr)?;
+        }
+
+        Ok(size)
     }
 
     pub fn send_market_message(
@@ -110,7 +352,7 @@ impl UdpSender {
 
     pub fn send_message(&self, message: &BroadcastMessage) -> anyhow::Result<usize> {
         let msg = serde_json::to_string(message).unwrap();
-        let size = self.socket.send_to(msg.as_bytes(), &self.multicast_addr)?;
+        let size = self.send_frames(UdpMessageType::Broadcast, msg.as_bytes())?;
 
         Ok(size)
     }
@@ -122,6 +364,7 @@ const UDP_SIZE: usize = 4096;
 pub struct UdpReceiver {
     socket: Socket,
     buf: [MaybeUninit<u8>; UDP_SIZE],
+    reassembler: UdpReassembler,
 }
 
 impl UdpReceiver {
@@ -173,17 +416,30 @@ impl UdpReceiver {
         Self {
             socket: socket,
             buf: buf,
+            reassembler: UdpReassembler::new(),
         }
     }
 
+    /// 分割されたメッセージは揃うまで受信を続ける。
+    /// ヘッダが不正(バージョン違いなど)な場合はErrorKind::InvalidDataを返す。
     pub fn receive(&mut self) -> Result<String, std::io::Error> {
-        let (amt, _addr) = self.socket.recv_from(&mut self.buf)?;
+        loop {
+            let (amt, _addr) = self.socket.recv_from(&mut self.buf)?;
+
+            let msg = &self.buf[..amt];
+            let m = unsafe { std::mem::transmute::<_, &[u8]>(msg) };
 
-        let msg = &self.buf[..amt];
-        let m = unsafe { std::mem::transmute::<_, &[u8]>(msg) };
+            let payload = self
+                .reassembler
+                .push(m)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
 
-        let msg = std::str::from_utf8(m).unwrap();
-        Ok(msg.to_string())
+            if let Some((_msg_type, payload)) = payload {
+                let msg = String::from_utf8(payload)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                return Ok(msg);
+            }
+        }
     }
 
     pub fn receive_message(&mut self) -> Result<BroadcastMessage, std::io::Error> {
@@ -193,13 +449,7 @@ impl UdpReceiver {
     }
 
     pub async fn async_receive(&mut self) -> Result<String, std::io::Error> {
-        let (amt, _addr) = self.socket.recv_from(&mut self.buf)?;
-
-        let msg = &self.buf[..amt];
-        let m = unsafe { std::mem::transmute::<_, &[u8]>(msg) };
-
-        let msg = std::str::from_utf8(m).unwrap();
-        Ok(msg.to_string())
+        self.receive()
     }
 
     pub async fn async_receive_message(&mut self) -> Result<BroadcastMessage, std::io::Error> {
@@ -221,7 +471,7 @@ impl UdpReceiver {
 
                 if msg.filter(exchange, category, symbol) {
                     match msg.msg {
-                        MarketMessage::Order(ref order) => {
+                        MarketMessage::Order(ref order) | MarketMessage::ReconciledOrder(ref order) => {
                             if order.is_my_order(agent_id) {
                                 yield Ok(msg.msg);
                             }
@@ -260,7 +510,11 @@ impl UdpReceiver {
         std::thread::spawn(move || loop {
             let msg = udp.receive_message();
 
-            if msg.is_err() {
+            if let Err(e) = &msg {
+                if e.kind() == std::io::ErrorKind::InvalidData {
+                    log::warn!("open_channel: skip message {}", e);
+                    continue;
+                }
                 break;
             }
 
@@ -270,7 +524,7 @@ impl UdpReceiver {
                 let market_message = msg.msg.clone();
 
                 match market_message {
-                    MarketMessage::Order(ref order) => {
+                    MarketMessage::Order(ref order) | MarketMessage::ReconciledOrder(ref order) => {
                         if order.is_my_order(&agent_id) {
                             let r = tx.send(market_message.clone());
                             if r.is_err() {
@@ -296,10 +550,60 @@ impl UdpReceiver {
 
 #[cfg(test)]
 mod test_udp {
+    use super::*;
     use crate::common::init_debug_log;
     use futures::stream::StreamExt;
     use tokio::task::spawn;
 
+    #[test]
+    fn test_decode_header() -> anyhow::Result<()> {
+        let datagrams = encode(UdpMessageType::Text, 1, 2, b"hello")?;
+        assert_eq!(datagrams.len(), 1);
+
+        let (header, payload) = decode(&datagrams[0])?;
+        assert_eq!(header.version, UDP_VERSION);
+        assert_eq!(header.msg_type, UdpMessageType::Text);
+        assert_eq!(header.msg_id, 2);
+        assert_eq!(payload, b"hello");
+
+        // バージョン違い
+        let mut old = datagrams[0].clone();
+        old[2] = UDP_VERSION + 1;
+        let e = decode(&old).unwrap_err();
+        assert!(e.to_string().contains("version mismatch"));
+
+        // ヘッダなし(旧形式)
+        assert!(decode(b"{\"exchange\": \"BYBIT\"}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reassemble() -> anyhow::Result<()> {
+        let payload: Vec<u8> = (0..UDP_SIZE * 3).map(|i| (i % 251) as u8).collect();
+
+        let a = encode(UdpMessageType::Broadcast, 1, 10, &payload)?;
+        let b = encode(UdpMessageType::Broadcast, 2, 10, b"small")?;
+        assert_eq!(a.len(), 4);
+        assert!(a.iter().all(|d| d.len() <= UDP_SIZE));
+
+        let mut reassembler = UdpReassembler::new();
+
+        // 順不同、別senderのメッセージが混ざっても組み立てられる
+        assert!(reassembler.push(&a[3])?.is_none());
+        assert!(reassembler.push(&a[0])?.is_none());
+        let (_, small) = reassembler.push(&b[0])?.unwrap();
+        assert_eq!(small, b"small");
+        assert!(reassembler.push(&a[2])?.is_none());
+        assert!(reassembler.push(&a[0])?.is_none()); // 重複は無視
+        let (msg_type, r) = reassembler.push(&a[1])?.unwrap();
+
+        assert_eq!(msg_type, UdpMessageType::Broadcast);
+        assert_eq!(r, payload);
+
+        Ok(())
+    }
+
     #[test]
     fn send_test2() {
         let sender = super::UdpSender::open();
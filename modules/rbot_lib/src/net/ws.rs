@@ -10,13 +10,18 @@ use futures::StreamExt;
 use strum::Display;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio::sync::Notify;
 use tokio::sync::RwLock;
 use tokio::time::Duration;
 use tokio_tungstenite::WebSocketStream;
 //use tokio::task::JoinHandle;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use pyo3::PyObject;
+use pyo3::Python;
+
 use crate::common::MarketConfig;
 use crate::common::MultiMarketMessage;
 use crate::common::ExchangeConfig;
@@ -25,11 +30,85 @@ use crate::common::{MicroSec, MICRO_SECOND, NOW};
 use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_tungstenite::{connect_async, MaybeTlsStream};
 
+pub const STREAM_STOP_TIMEOUT_SEC: u64 = 5;
+
+/// spawnしたstreamタスクの停止シグナル。
+/// タスク側はReceiver::changed()を監視し、値の変更またはSenderのdropで終了する。
+pub fn stream_stop_signal() -> (
+    tokio::sync::watch::Sender<bool>,
+    tokio::sync::watch::Receiver<bool>,
+) {
+    tokio::sync::watch::channel(false)
+}
+
+/// 停止シグナルを送り、timeout_sec以内に終了しない場合はabortする
+pub async fn stop_stream_task(
+    stop: Option<tokio::sync::watch::Sender<bool>>,
+    handle: Option<tokio::task::JoinHandle<()>>,
+    timeout_sec: u64,
+) {
+    if let Some(stop) = stop {
+        let _ = stop.send(true);
+    }
+
+    let handle = match handle {
+        Some(h) => h,
+        None => return,
+    };
+
+    let abort_handle = handle.abort_handle();
+
+    if tokio::time::timeout(Duration::from_secs(timeout_sec), handle)
+        .await
+        .is_err()
+    {
+        log::warn!("stream task did not stop in {}[sec], abort", timeout_sec);
+        abort_handle.abort();
+    }
+}
+
+/// WebSocketの接続(ハンドシェイク)にかかった時間を測る。接続はすぐに閉じる。
+pub async fn measure_ws_latency(url: &str, timeout_sec: u64) -> anyhow::Result<MicroSec> {
+    let start = NOW();
+
+    let (mut ws, _) = tokio::time::timeout(Duration::from_secs(timeout_sec), connect_async(url))
+        .await
+        .map_err(|_| anyhow::anyhow!("websocket connect timeout {}", url))??;
+
+    let latency = NOW() - start;
+    let _ = ws.close(None).await;
+
+    Ok(latency)
+}
+
+/// callback invoked with every raw text frame before parsing.
+pub type RawMessageHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// wrap python callable(str) as RawMessageHook.
+pub fn make_py_raw_message_hook(handler: PyObject) -> RawMessageHook {
+    Arc::new(move |message: &str| {
+        Python::with_gil(|py| {
+            let r = handler.call1(py, (message,));
+            if r.is_err() {
+                log::error!("raw message hook error: {:?}", r.err().unwrap());
+            }
+        });
+    })
+}
+
 pub trait WebSocketClient {
     async fn new(server: &ExchangeConfig, config: &MarketConfig) -> Self;
     async fn open_stream<'a>(
         &'a mut self,
     ) -> impl Stream<Item = Result<MultiMarketMessage, String>> + Send + 'a;
+
+    fn set_raw_message_hook(&mut self, _hook: Option<RawMessageHook>) {
+        log::warn!("raw message hook is not supported");
+    }
+
+    fn set_state_handle(&mut self, _state: WsStateHandle) {
+        log::warn!("ws state is not supported");
+    }
 }
 
 pub trait WsOpMessage {
@@ -51,18 +130,117 @@ pub enum ReceiveMessage {
     Pong(Vec<u8>),
 }
 
+/// AutoConnectClientの接続状態
+#[derive(Debug, Clone, PartialEq)]
+pub enum WsState {
+    Disconnected,
+    Connecting,
+    Authenticating,
+    Subscribed,
+    Receiving,
+    /// 切断後の再接続(何回目か)
+    Reconnecting(u32),
+}
+
+impl Default for WsState {
+    fn default() -> Self {
+        WsState::Disconnected
+    }
+}
+
+impl std::fmt::Display for WsState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WsState::Reconnecting(attempt) => write!(f, "Reconnecting({})", attempt),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// AutoConnectClientの接続状態を別タスク(Pythonのmarketなど)から参照するハンドル。
+/// force_reconnectの要求もこれを通して伝える。
+#[derive(Debug, Clone, Default)]
+pub struct WsStateHandle {
+    current_state: Arc<std::sync::RwLock<WsState>>,
+    reconnect_requested: Arc<AtomicBool>,
+    reconnect_notify: Arc<Notify>,
+}
+
+impl WsStateHandle {
+    pub fn get(&self) -> WsState {
+        self.current_state.read().unwrap().clone()
+    }
+
+    /// 状態が変わった時だけinfoでログを出す
+    pub fn set(&self, url: &str, state: WsState) {
+        let mut current = self.current_state.write().unwrap();
+
+        if *current != state {
+            log::info!("ws state {} -> {} ({})", current, state, url);
+            *current = state;
+        }
+    }
+
+    /// 接続を張り直す。受信待ちの最中であればその受信を中断させる。
+    pub fn request_reconnect(&self) {
+        self.reconnect_requested.store(true, Ordering::SeqCst);
+        self.reconnect_notify.notify_one();
+    }
+
+    async fn reconnect_notified(&self) {
+        self.reconnect_notify.notified().await;
+    }
+
+    fn take_reconnect_request(&self) -> bool {
+        self.reconnect_requested.swap(false, Ordering::SeqCst)
+    }
+}
+
+type WsWriteStream = Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>;
+
+/// 接続中のwebsocketへの送信ハンドル。AutoConnectClientが再接続・切り替えのたびに差し替えるので、
+/// streamを読んでいる間も別タスクから現在の接続へメッセージを送れる。
+#[derive(Clone, Default)]
+pub struct WsWriter {
+    stream: Arc<std::sync::RwLock<Option<WsWriteStream>>>,
+}
+
+impl WsWriter {
+    fn set(&self, stream: Option<WsWriteStream>) {
+        *self.stream.write().unwrap() = stream;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.read().unwrap().is_some()
+    }
+
+    pub async fn send_text(&self, message: &str) -> anyhow::Result<()> {
+        let stream = self.stream.read().unwrap().clone();
+
+        let stream = stream.ok_or_else(|| anyhow::anyhow!("websocket is not connected"))?;
+
+        log::debug!("Sent message {:?}", message);
+        let mut stream = stream.lock().await;
+        stream.send(Message::Text(message.to_string())).await?;
+        stream.flush().await?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct SimpleWebsocket<U> {
     server: ExchangeConfig,
     config: MarketConfig,
     read_stream: Option<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
-    write_sream: Option<Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>>,
+    write_sream: Option<WsWriteStream>,
     url: String,
     subscribe_message: Arc<RwLock<U>>,
     init_fn: Option<fn(&ExchangeConfig) -> String>,
     url_generator: Option<fn(&ExchangeConfig, &MarketConfig) -> String>,
     ping_interval_sec: i64,
     ping_thread: Option<tokio::task::JoinHandle<()>>,
+    state: Option<WsStateHandle>,
 }
 
 impl<U> SimpleWebsocket<U>
@@ -90,6 +268,18 @@ where
             url_generator, // url generator  for reconnect(auth url, if this parameter is set url parameter is ignores)
             ping_interval_sec,
             ping_thread: None,
+            state: None,
+        }
+    }
+
+    /// 接続処理の途中経過(Authenticating/Subscribed)を通知する先
+    pub fn set_state_handle(&mut self, state: Option<WsStateHandle>) {
+        self.state = state;
+    }
+
+    fn set_state(&self, state: WsState) {
+        if let Some(handle) = self.state.as_ref() {
+            handle.set(&self.url, state);
         }
     }
 
@@ -126,6 +316,8 @@ where
         // self.connection = Some(socket);
 
         if self.init_fn.is_some() {
+            self.set_state(WsState::Authenticating);
+
             let message = (self.init_fn.as_ref().unwrap())(&self.server);
             log::debug!("init message: {}", message);
             self.send_text(message).await;
@@ -140,6 +332,7 @@ where
         if message != "" {
             self.send_text(message).await;
         }
+        self.set_state(WsState::Subscribed);
 
         self.ping_thread = Some(self.spawn_ping_task());
     }
@@ -173,6 +366,10 @@ where
         handle
     }
 
+    fn write_stream(&self) -> Option<WsWriteStream> {
+        self.write_sream.clone()
+    }
+
     pub async fn send_message(&mut self, message: Message) {
         let write_stream = self.write_sream.as_mut();
 
@@ -236,8 +433,14 @@ where
 
     pub async fn close(&mut self) {
         log::debug!(">>>Close connection<<<");
-        self.ping_thread.as_mut().unwrap().abort();
-        self.ping_thread = None;
+        if let Some(ping_thread) = self.ping_thread.take() {
+            ping_thread.abort();
+        }
+
+        // サーバからCloseを受信済みの場合はwrite streamがない
+        if self.write_sream.is_none() {
+            return;
+        }
 
         self.send_message(Message::Close(None)).await;
 
@@ -303,7 +506,6 @@ where
     }
 }
 
-#[derive(Debug)]
 pub struct AutoConnectClient<U> {
     server: ExchangeConfig,
     config: MarketConfig,
@@ -320,6 +522,22 @@ pub struct AutoConnectClient<U> {
     ping_interval: MicroSec,
     init_fn: Option<fn(&ExchangeConfig) -> String>,
     url_generator: Option<fn(&ExchangeConfig, &MarketConfig) -> String>,
+    raw_message_hook: Option<RawMessageHook>,
+    writer: WsWriter,
+    state: WsStateHandle,
+    reconnect_attempt: u32,
+}
+
+impl<U> std::fmt::Debug for AutoConnectClient<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AutoConnectClient")
+            .field("url", &self.url)
+            .field("last_connect_time", &self.last_connect_time)
+            .field("sync_mode", &self.sync_mode)
+            .field("state", &self.state.get())
+            .field("raw_message_hook", &self.raw_message_hook.is_some())
+            .finish()
+    }
 }
 
 impl<U> AutoConnectClient<U>
@@ -352,12 +570,73 @@ where
             url_generator: url_generator,
             server: server.clone(),
             config: config.clone(),
+            raw_message_hook: None,
+            writer: WsWriter::default(),
+            state: WsStateHandle::default(),
+            reconnect_attempt: 0,
         }
     }
 
+    /// 現在の接続状態
+    pub fn state(&self) -> WsState {
+        self.state.get()
+    }
+
+    /// 接続状態を参照するハンドル(clientをstreamに渡した後も有効)
+    pub fn state_handle(&self) -> WsStateHandle {
+        self.state.clone()
+    }
+
+    /// marketなどが保持しているハンドルに差し替える。connectの前に呼ぶ。
+    pub fn set_state_handle(&mut self, state: WsStateHandle) {
+        self.state = state;
+    }
+
+    /// ユーザの指示で再接続する。受信待ちを中断して現在の接続を閉じ、次の受信で張り直す。
+    pub fn force_reconnect(&self) {
+        log::info!("force reconnect requested: {}", self.url);
+        self.state.request_reconnect();
+    }
+
+    fn set_state(&self, state: WsState) {
+        self.state.set(&self.url, state);
+    }
+
+    /// 受信エラーまたは再接続要求で現在の接続を捨てる
+    async fn disconnect(&mut self) {
+        if let Some(client) = self.client.as_mut() {
+            client.close().await;
+        }
+        self.client = None;
+        self.writer.set(None);
+
+        self.reconnect_attempt += 1;
+        self.set_state(WsState::Disconnected);
+    }
+
+    /// 現在の接続へ送信するハンドル(再接続後も有効)
+    pub fn writer(&self) -> WsWriter {
+        self.writer.clone()
+    }
+
+    /// register a tap called for every received text frame(before parse).
+    pub fn on_raw_message(&mut self, hook: Box<dyn Fn(&str) + Send + Sync>) {
+        self.raw_message_hook = Some(Arc::from(hook));
+    }
+
+    pub fn set_raw_message_hook(&mut self, hook: Option<RawMessageHook>) {
+        self.raw_message_hook = hook;
+    }
+
     pub async fn connect(&mut self) {
         log::debug!("connect: {}", self.url);
 
+        if self.reconnect_attempt == 0 {
+            self.set_state(WsState::Connecting);
+        } else {
+            self.set_state(WsState::Reconnecting(self.reconnect_attempt));
+        }
+
         self.client = Some(SimpleWebsocket::new(
             &self.server,
             &self.config,
@@ -367,7 +646,9 @@ where
             self.init_fn,
             self.url_generator,
         ));
+        self.client.as_mut().unwrap().set_state_handle(Some(self.state.clone()));
         self.client.as_mut().unwrap().connect().await;
+        self.writer.set(self.client.as_ref().unwrap().write_stream());
         self.last_connect_time = NOW();
     }
 
@@ -393,6 +674,7 @@ where
         self.client.as_mut().unwrap().close().await;
         self.client = self.next_client.take();
         self.next_client = None;
+        self.writer.set(self.client.as_ref().and_then(|c| c.write_stream()));
 
         log::info!("------WS switched-{}-----", self.url);
     }
@@ -430,6 +712,11 @@ where
     }
 
     pub async fn receive_text(&mut self) -> Result<ReceiveMessage, String> {
+        if self.state.take_reconnect_request() && self.client.is_some() {
+            log::info!("force reconnect: {}", self.url);
+            self.disconnect().await;
+        }
+
         let client = self.client.as_mut();
         if client.is_none() {
             log::info!("Try reconnect");
@@ -495,8 +782,7 @@ where
                             self.url,
                             message.unwrap_err()
                         );
-                        self.client.as_mut().unwrap().close().await;
-                        self.client = None;
+                        self.disconnect().await;
 
                         self.last_message = "".to_string();
                         self.sync_mode = false;
@@ -549,17 +835,40 @@ where
             websocket = self.client.as_mut();
         }
 
-        let result = websocket.unwrap().receive_text().await;
+        let client = websocket.unwrap();
+        let state = self.state.clone();
+
+        // 受信待ちの間もforce_reconnectを受け付ける。
+        // 既に受信前に処理済みの要求の通知は読み捨てて受信を続ける。
+        let result = loop {
+            tokio::select! {
+                result = client.receive_text() => break result,
+                _ = state.reconnect_notified() => {
+                    if state.take_reconnect_request() {
+                        log::info!("force reconnect: {}", self.url);
+                        break Err("force reconnect".to_string());
+                    }
+                }
+            }
+        };
 
         match result {
             Ok(_) => {
+                if let Ok(ReceiveMessage::Text(text)) = &result {
+                    self.reconnect_attempt = 0;
+                    self.set_state(WsState::Receiving);
+
+                    if let Some(hook) = self.raw_message_hook.as_ref() {
+                        hook(text);
+                    }
+                }
+
                 return result;
             }
             Err(e) => {
                 log::debug!("recive error{}, try reconnect!!", e);
 
-                self.client.as_mut().unwrap().close().await;
-                self.client = None;
+                self.disconnect().await;
                 Err(e)
             }
         }
@@ -574,6 +883,190 @@ where
     }
 }
 
+#[cfg(test)]
+mod test_ws_state {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    #[derive(Debug, Clone)]
+    struct StateTestOpMessage {
+        args: Vec<String>,
+    }
+
+    impl WsOpMessage for StateTestOpMessage {
+        fn new() -> Self {
+            StateTestOpMessage { args: vec![] }
+        }
+
+        fn add_params(&mut self, params: &Vec<String>) {
+            self.args.extend(params.clone());
+        }
+
+        fn to_string(&self) -> String {
+            format!("subscribe:{}", self.args.join(","))
+        }
+
+        fn make_message(&self) -> Vec<String> {
+            vec![self.to_string()]
+        }
+    }
+
+    /// handshakeして購読メッセージを受け取る
+    async fn accept(stream: TcpStream) -> WebSocketStream<TcpStream> {
+        let mut ws = accept_async(stream).await.unwrap();
+
+        let message = ws.next().await.unwrap().unwrap();
+        assert_eq!(message, Message::Text("subscribe:trade".to_string()));
+
+        ws
+    }
+
+    async fn wait_state(handle: &WsStateHandle, state: WsState) {
+        for _ in 0..500 {
+            if handle.get() == state {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("state {} is not reached (current={})", state, handle.get());
+    }
+
+    #[test]
+    fn test_ws_state_display() {
+        assert_eq!(WsState::default().to_string(), "Disconnected");
+        assert_eq!(WsState::Subscribed.to_string(), "Subscribed");
+        assert_eq!(WsState::Reconnecting(2).to_string(), "Reconnecting(2)");
+    }
+
+    #[tokio::test]
+    async fn test_ws_state_transition() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (release_tx, release_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            // 1本目: 1件送って切断
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept(stream).await;
+            ws.send(Message::Text("m1".to_string())).await.unwrap();
+            ws.close(None).await.unwrap();
+
+            // 2本目: テスト側がReconnectingを確認するまでhandshakeを止める
+            let (stream, _) = listener.accept().await.unwrap();
+            release_rx.await.unwrap();
+            let mut ws2 = accept(stream).await;
+            ws2.send(Message::Text("m2".to_string())).await.unwrap();
+
+            // 3本目: force_reconnect後
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws3 = accept(stream).await;
+            ws3.send(Message::Text("m3".to_string())).await.unwrap();
+
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(ws2);
+        });
+
+        let server_config = ExchangeConfig::new("WSSTATE", false, "", "", &url, &url, "");
+        let mut ws: AutoConnectClient<StateTestOpMessage> = AutoConnectClient::new(
+            &server_config,
+            &MarketConfig::default(),
+            &url,
+            30,
+            3600,
+            0,
+            None,
+            None,
+        );
+        let handle = ws.state_handle();
+        assert_eq!(ws.state(), WsState::Disconnected);
+
+        ws.subscribe(&vec!["trade".to_string()]).await;
+        ws.connect().await;
+        assert_eq!(ws.state(), WsState::Subscribed);
+
+        assert_eq!(ws.receive_text().await, Ok(ReceiveMessage::Text("m1".to_string())));
+        assert_eq!(ws.state(), WsState::Receiving);
+
+        // サーバから切断
+        assert!(ws.receive_text().await.is_err());
+        assert_eq!(ws.state(), WsState::Disconnected);
+
+        // 次の受信で再接続する
+        let client = tokio::spawn(async move {
+            let message = ws.receive_text().await;
+            (ws, message)
+        });
+        wait_state(&handle, WsState::Reconnecting(1)).await;
+        release_tx.send(()).unwrap();
+
+        let (mut ws, message) = client.await.unwrap();
+        assert_eq!(message, Ok(ReceiveMessage::Text("m2".to_string())));
+        assert_eq!(ws.state(), WsState::Receiving);
+
+        // ユーザ指示の再接続
+        handle.request_reconnect();
+        assert_eq!(ws.receive_text().await, Ok(ReceiveMessage::Text("m3".to_string())));
+        assert_eq!(ws.state(), WsState::Receiving);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_force_reconnect_interrupts_receive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            // 1本目: 何も送らない
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws1 = accept(stream).await;
+
+            // 2本目: 再接続後
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws2 = accept(stream).await;
+            ws2.send(Message::Text("m1".to_string())).await.unwrap();
+
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(ws1);
+        });
+
+        let server_config = ExchangeConfig::new("WSSTATE", false, "", "", &url, &url, "");
+        let mut ws: AutoConnectClient<StateTestOpMessage> = AutoConnectClient::new(
+            &server_config,
+            &MarketConfig::default(),
+            &url,
+            30,
+            3600,
+            0,
+            None,
+            None,
+        );
+        let handle = ws.state_handle();
+        ws.subscribe(&vec!["trade".to_string()]).await;
+        ws.connect().await;
+
+        // 受信待ちの間に再接続を要求すると、その受信が中断される
+        let client = tokio::spawn(async move {
+            let message = ws.receive_text().await;
+            (ws, message)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!client.is_finished());
+
+        handle.request_reconnect();
+        let (mut ws, message) = client.await.unwrap();
+        assert_eq!(message, Err("force reconnect".to_string()));
+        assert_eq!(ws.state(), WsState::Disconnected);
+
+        // 次の受信で張り直した接続から受け取る
+        assert_eq!(ws.receive_text().await, Ok(ReceiveMessage::Text("m1".to_string())));
+        assert_eq!(ws.state(), WsState::Receiving);
+
+        server.abort();
+    }
+}
+
 #[allow(unused_imports)]
 #[cfg(test)]
 mod test_exchange_ws {
@@ -1099,3 +1592,39 @@ mod test_exchange_ws {
     }
     */
 }
+
+#[cfg(test)]
+mod test_stop_stream {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stop_stream_task() {
+        let (stop_tx, mut stop_rx) = stream_stop_signal();
+
+        let handle = tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = stop_rx.changed() => break,
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        });
+
+        let start = NOW();
+        stop_stream_task(Some(stop_tx), Some(handle), 5).await;
+        assert!(NOW() - start < 5 * MICRO_SECOND);
+
+        // a task that ignores the signal is aborted after timeout.
+        let (stop_tx, _stop_rx) = stream_stop_signal();
+        let handle = tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        let abort_handle = handle.abort_handle();
+
+        stop_stream_task(Some(stop_tx), Some(handle), 1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(abort_handle.is_finished());
+    }
+}
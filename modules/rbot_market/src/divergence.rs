@@ -0,0 +1,383 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use pyo3::{exceptions::PyStopAsyncIteration, pyclass, pymethods, Bound, Py, PyAny, PyRef, PyResult, Python};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use tokio::sync::{mpsc, Mutex};
+
+use rbot_lib::common::{time_string, MicroSec, OrderBook, NOW};
+
+/// 監視の間隔(ms)
+pub const DIVERGENCE_POLL_INTERVAL_MS: u64 = 100;
+const DIVERGENCE_STREAM_BUFFER_SIZE: usize = 1024;
+
+/// 仲値を返すもの。板(OrderBook)やpythonのmarketオブジェクト。
+pub trait MidPriceSource: Send + Sync {
+    fn mid_price(&self) -> anyhow::Result<Decimal>;
+}
+
+fn mid(bid: Decimal, ask: Decimal) -> anyhow::Result<Decimal> {
+    if bid <= dec![0.0] || ask <= dec![0.0] {
+        return Err(anyhow!("board is empty: bid={} ask={}", bid, ask));
+    }
+
+    Ok((bid + ask) / dec![2.0])
+}
+
+impl MidPriceSource for RwLock<OrderBook> {
+    fn mid_price(&self) -> anyhow::Result<Decimal> {
+        let (bid, ask) = self.read().unwrap().get_edge_price()?;
+
+        mid(bid, ask)
+    }
+}
+
+/// pythonのmarketオブジェクト。edge_price(bid, ask)から仲値を求める。
+pub struct PyMidPriceSource {
+    market: Py<PyAny>,
+}
+
+impl MidPriceSource for PyMidPriceSource {
+    fn mid_price(&self) -> anyhow::Result<Decimal> {
+        let (bid, ask) = Python::with_gil(|py| {
+            self.market
+                .getattr(py, "edge_price")?
+                .extract::<(Decimal, Decimal)>(py)
+        })?;
+
+        mid(bid, ask)
+    }
+}
+
+/// |a - b| / min(a, b) * 10000。価格が0以下の場合はNone。
+pub fn divergence_bps(price_a: Decimal, price_b: Decimal) -> Option<Decimal> {
+    let min = price_a.min(price_b);
+
+    if min <= dec![0.0] {
+        return None;
+    }
+
+    Some((price_a - price_b).abs() / min * dec![10000])
+}
+
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DivergenceEvent {
+    #[pyo3(get)]
+    pub time: MicroSec,
+    #[pyo3(get)]
+    pub price_a: Decimal,
+    #[pyo3(get)]
+    pub price_b: Decimal,
+    #[pyo3(get)]
+    pub divergence_bps: Decimal,
+}
+
+#[pymethods]
+impl DivergenceEvent {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "DivergenceEvent(time={}, price_a={}, price_b={}, divergence_bps={})",
+            time_string(self.time),
+            self.price_a,
+            self.price_b,
+            self.divergence_bps.round_dp(2)
+        )
+    }
+}
+
+/// 2つのマーケットの仲値を定期的に比べ、乖離がthreshold_bpsを超えたらDivergenceEventを流す。
+#[pyclass]
+pub struct PriceDivergenceMonitor {
+    market_a: Arc<dyn MidPriceSource>,
+    market_b: Arc<dyn MidPriceSource>,
+    #[pyo3(get)]
+    threshold_bps: Decimal,
+    #[pyo3(get, set)]
+    interval_ms: u64,
+    /// 実行中の監視スレッドの停止フラグ。startのたびに作り直すので、
+    /// stop直後にstartしても前のスレッドは自分のフラグを見て止まる。
+    running: std::sync::Mutex<Arc<AtomicBool>>,
+}
+
+impl PriceDivergenceMonitor {
+    pub fn new(
+        market_a: Arc<dyn MidPriceSource>,
+        market_b: Arc<dyn MidPriceSource>,
+        threshold_bps: Decimal,
+    ) -> Self {
+        Self {
+            market_a,
+            market_b,
+            threshold_bps,
+            interval_ms: DIVERGENCE_POLL_INTERVAL_MS,
+            running: std::sync::Mutex::new(Arc::new(AtomicBool::new(false))),
+        }
+    }
+
+    fn check_prices(
+        market_a: &Arc<dyn MidPriceSource>,
+        market_b: &Arc<dyn MidPriceSource>,
+        threshold_bps: Decimal,
+    ) -> anyhow::Result<Option<DivergenceEvent>> {
+        let price_a = market_a.mid_price()?;
+        let price_b = market_b.mid_price()?;
+
+        let divergence = match divergence_bps(price_a, price_b) {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+
+        if divergence <= threshold_bps {
+            return Ok(None);
+        }
+
+        Ok(Some(DivergenceEvent {
+            time: NOW(),
+            price_a,
+            price_b,
+            divergence_bps: divergence,
+        }))
+    }
+
+    /// 1回だけ比べる。
+    pub fn check(&self) -> anyhow::Result<Option<DivergenceEvent>> {
+        Self::check_prices(&self.market_a, &self.market_b, self.threshold_bps)
+    }
+
+    /// interval_msごとに監視するスレッドを起動する。
+    /// stopが呼ばれるか、Receiverが破棄されると終了する。
+    pub fn start(&self) -> anyhow::Result<mpsc::Receiver<DivergenceEvent>> {
+        let running = {
+            let mut current = self.running.lock().unwrap();
+            if current.load(Ordering::SeqCst) {
+                return Err(anyhow!("divergence monitor is already running"));
+            }
+
+            *current = Arc::new(AtomicBool::new(true));
+            current.clone()
+        };
+
+        let (tx, rx) = mpsc::channel(DIVERGENCE_STREAM_BUFFER_SIZE);
+
+        let market_a = self.market_a.clone();
+        let market_b = self.market_b.clone();
+        let threshold_bps = self.threshold_bps;
+        let interval = std::time::Duration::from_millis(self.interval_ms);
+
+        std::thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                if tx.is_closed() {
+                    log::debug!("divergence stream closed");
+                    break;
+                }
+
+                match Self::check_prices(&market_a, &market_b, threshold_bps) {
+                    Ok(Some(event)) => {
+                        if tx.blocking_send(event).is_err() {
+                            log::debug!("divergence stream closed");
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::debug!("divergence monitor: price not available {:?}", e);
+                    }
+                }
+
+                std::thread::sleep(interval);
+            }
+
+            running.store(false, Ordering::SeqCst);
+            log::debug!("divergence monitor stopped");
+        });
+
+        Ok(rx)
+    }
+}
+
+#[pymethods]
+impl PriceDivergenceMonitor {
+    #[new]
+    #[pyo3(signature = (market_a, market_b, threshold_bps=Decimal::from(20)))]
+    pub fn py_new(market_a: &Bound<PyAny>, market_b: &Bound<PyAny>, threshold_bps: Decimal) -> Self {
+        Self::new(
+            Arc::new(PyMidPriceSource { market: market_a.clone().unbind() }),
+            Arc::new(PyMidPriceSource { market: market_b.clone().unbind() }),
+            threshold_bps,
+        )
+    }
+
+    /// `async for event in monitor.start():`
+    #[pyo3(name = "start")]
+    pub fn py_start(&self) -> anyhow::Result<DivergenceStream> {
+        let rx = self.start()?;
+
+        Ok(DivergenceStream {
+            receiver: Arc::new(Mutex::new(rx)),
+        })
+    }
+
+    pub fn stop(&self) {
+        self.running.lock().unwrap().store(false, Ordering::SeqCst);
+    }
+
+    #[getter]
+    pub fn get_running(&self) -> bool {
+        self.running.lock().unwrap().load(Ordering::SeqCst)
+    }
+}
+
+#[pyclass]
+pub struct DivergenceStream {
+    receiver: Arc<Mutex<mpsc::Receiver<DivergenceEvent>>>,
+}
+
+#[pymethods]
+impl DivergenceStream {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let receiver = self.receiver.clone();
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            match receiver.lock().await.recv().await {
+                Some(event) => Ok(event),
+                None => Err(PyStopAsyncIteration::new_err("divergence stream closed")),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod divergence_test {
+    use super::*;
+
+    struct MockMarket {
+        price: std::sync::Mutex<Decimal>,
+    }
+
+    impl MockMarket {
+        fn new(price: Decimal) -> Arc<Self> {
+            Arc::new(Self {
+                price: std::sync::Mutex::new(price),
+            })
+        }
+
+        fn set(&self, price: Decimal) {
+            *self.price.lock().unwrap() = price;
+        }
+    }
+
+    impl MidPriceSource for MockMarket {
+        fn mid_price(&self) -> anyhow::Result<Decimal> {
+            Ok(*self.price.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_divergence_bps() {
+        assert_eq!(divergence_bps(dec![100], dec![101]), Some(dec![100]));
+        assert_eq!(divergence_bps(dec![101], dec![100]), Some(dec![100]));
+        assert_eq!(divergence_bps(dec![100], dec![100]), Some(dec![0]));
+        assert_eq!(divergence_bps(dec![0], dec![100]), None);
+    }
+
+    #[test]
+    fn test_check_threshold() -> anyhow::Result<()> {
+        let a = MockMarket::new(dec![10000]);
+        let b = MockMarket::new(dec![10000]);
+        let monitor = PriceDivergenceMonitor::new(a.clone(), b.clone(), dec![20]);
+
+        assert!(monitor.check()?.is_none());
+
+        // ちょうど20bpsでは出さない
+        b.set(dec![10020]);
+        assert!(monitor.check()?.is_none());
+
+        b.set(dec![10021]);
+        let event = monitor.check()?.unwrap();
+        assert_eq!(event.price_a, dec![10000]);
+        assert_eq!(event.price_b, dec![10021]);
+        assert_eq!(event.divergence_bps, dec![21]);
+
+        // 逆方向の乖離も検出する
+        a.set(dec![10021]);
+        b.set(dec![10000]);
+        assert!(monitor.check()?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_start() -> anyhow::Result<()> {
+        let a = MockMarket::new(dec![10000]);
+        let b = MockMarket::new(dec![10000]);
+        let mut monitor = PriceDivergenceMonitor::new(a.clone(), b.clone(), dec![20]);
+        monitor.interval_ms = 1;
+
+        let mut rx = monitor.start()?;
+        assert!(monitor.start().is_err());
+
+        b.set(dec![10050]);
+        let event = rx.blocking_recv().unwrap();
+        assert_eq!(event.divergence_bps, dec![50]);
+
+        monitor.stop();
+        while rx.blocking_recv().is_some() {}
+        assert!(!monitor.get_running());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart() -> anyhow::Result<()> {
+        let a = MockMarket::new(dec![10000]);
+        let b = MockMarket::new(dec![10050]);
+        let mut monitor = PriceDivergenceMonitor::new(a.clone(), b.clone(), dec![20]);
+        monitor.interval_ms = 1;
+
+        // stop直後にstartしても前のスレッドは止まり、新しいスレッドだけが動く
+        let mut old = monitor.start()?;
+        monitor.stop();
+        let mut rx = monitor.start()?;
+
+        while old.blocking_recv().is_some() {}
+        assert!(monitor.get_running());
+        assert!(rx.blocking_recv().is_some());
+
+        monitor.stop();
+        while rx.blocking_recv().is_some() {}
+        assert!(!monitor.get_running());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_receiver_dropped() -> anyhow::Result<()> {
+        let a = MockMarket::new(dec![10000]);
+        let b = MockMarket::new(dec![10000]);
+        let mut monitor = PriceDivergenceMonitor::new(a.clone(), b.clone(), dec![20]);
+        monitor.interval_ms = 1;
+
+        // 乖離がなくても受信側が破棄されたら止まる
+        let rx = monitor.start()?;
+        drop(rx);
+
+        for _ in 0..1000 {
+            if !monitor.get_running() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(!monitor.get_running());
+
+        Ok(())
+    }
+}
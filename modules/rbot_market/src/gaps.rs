@@ -0,0 +1,53 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use pyo3::{pyfunction, Bound, PyAny};
+use pyo3_polars::PyDataFrame;
+
+use rbot_lib::common::{MarketConfig, MicroSec, TimeChunk};
+use rbot_lib::db::{gap_chunks_df, GAP_REPORT_ALLOW_SIZE};
+
+/// 複数のマーケットについて直近ndays分のDBの空白区間をまとめて返す。
+/// columns: exchange, symbol, gap_start, gap_end, duration
+/// gap_chunksを持たないマーケット(bitbank, bitflyer)は警告を出して飛ばす。
+#[pyfunction]
+#[pyo3(name = "report_gaps", signature = (markets, *, ndays=2, allow_size=GAP_REPORT_ALLOW_SIZE))]
+pub fn py_report_gaps(
+    markets: Vec<Bound<PyAny>>,
+    ndays: i64,
+    allow_size: MicroSec,
+) -> anyhow::Result<PyDataFrame> {
+    let mut gaps: Vec<(String, String, TimeChunk)> = vec![];
+
+    for market in markets.iter() {
+        if !market.hasattr("gap_chunks")? {
+            log::warn!(
+                "report_gaps: {} does not support gap_chunks, skipped",
+                market.get_type()
+            );
+            continue;
+        }
+
+        let config = market.getattr("config")?.extract::<MarketConfig>()?;
+
+        let chunks = market
+            .call_method1("gap_chunks", (ndays, allow_size))?
+            .extract::<Vec<(MicroSec, MicroSec)>>()?;
+
+        log::debug!(
+            "report_gaps: {}/{} {} gaps",
+            config.exchange_name,
+            config.trade_symbol,
+            chunks.len()
+        );
+
+        for (start, end) in chunks {
+            gaps.push((
+                config.exchange_name.clone(),
+                config.trade_symbol.clone(),
+                TimeChunk { start, end },
+            ));
+        }
+    }
+
+    Ok(PyDataFrame(gap_chunks_df(&gaps)?))
+}
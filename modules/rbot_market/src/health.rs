@@ -0,0 +1,75 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use pyo3::{pyclass, pymethods};
+use rbot_lib::common::NOW;
+use rbot_lib::net::{measure_ws_latency, RestApi};
+
+use anyhow::Context;
+
+const WS_CONNECT_TIMEOUT_SEC: u64 = 10;
+
+/// 発注せずに接続とAPIキーを確認した結果
+#[pyclass]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HealthStatus {
+    /// サーバ時刻取得の往復時間
+    #[pyo3(get)]
+    pub rest_latency_ms: u64,
+    /// WebSocket接続にかかった時間(接続できなかった場合は0)
+    #[pyo3(get)]
+    pub ws_latency_ms: u64,
+    /// サーバ時刻 - ローカル時刻
+    #[pyo3(get)]
+    pub server_time_delta_ms: i64,
+    #[pyo3(get)]
+    pub api_key_valid: bool,
+    #[pyo3(get)]
+    pub permissions: Vec<String>,
+}
+
+#[pymethods]
+impl HealthStatus {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "{{\"rest_latency_ms\": {}, \"ws_latency_ms\": {}, \"server_time_delta_ms\": {}, \"api_key_valid\": {}, \"permissions\": {:?}}}",
+            self.rest_latency_ms,
+            self.ws_latency_ms,
+            self.server_time_delta_ms,
+            self.api_key_valid,
+            self.permissions
+        )
+    }
+}
+
+/// RESTでサーバ時刻を取得して往復時間と時差を測り、ws_urlへの接続時間とAPIキーの権限を確認する。
+/// RESTに接続できない場合はエラー。WebSocketやAPIキーの失敗はHealthStatusに反映する。
+pub async fn health_check<T: RestApi>(api: &T, ws_url: &str) -> anyhow::Result<HealthStatus> {
+    let start = NOW();
+    let server_time = api
+        .get_server_time()
+        .await
+        .with_context(|| format!("health_check: REST ping error"))?;
+    let end = NOW();
+
+    let mut status = HealthStatus {
+        rest_latency_ms: ((end - start) / 1_000) as u64,
+        server_time_delta_ms: (server_time - (start + end) / 2) / 1_000,
+        ..Default::default()
+    };
+
+    match measure_ws_latency(ws_url, WS_CONNECT_TIMEOUT_SEC).await {
+        Ok(latency) => status.ws_latency_ms = (latency / 1_000) as u64,
+        Err(e) => log::warn!("health_check: websocket connect error {}: {:?}", ws_url, e),
+    }
+
+    match api.get_api_permissions().await {
+        Ok(permissions) => {
+            status.api_key_valid = true;
+            status.permissions = permissions;
+        }
+        Err(e) => log::warn!("health_check: api key error: {:?}", e),
+    }
+
+    Ok(status)
+}
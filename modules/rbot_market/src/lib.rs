@@ -1,4 +1,11 @@
+mod divergence;
+mod gaps;
+mod health;
 mod market;
+mod stream;
 
+pub use divergence::*;
+pub use gaps::*;
+pub use health::*;
 pub use market::*;
-
+pub use stream::*;
@@ -4,27 +4,49 @@ use crossbeam_channel::Sender;
 use pyo3::IntoPy;
 use pyo3::Py;
 use pyo3::PyAny;
+use pyo3::PyObject;
 use pyo3::PyResult;
 use pyo3::Python;
 use rbot_lib::common::convert_klines_to_trades;
-use rbot_lib::common::flush_log;
 use rbot_lib::common::time_string;
 use rbot_lib::common::AccountCoins;
+use rbot_lib::common::BarType;
+use rbot_lib::common::Coin;
 use rbot_lib::common::LogStatus;
 use rbot_lib::common::MarketMessage;
 
 use rbot_lib::common::MultiMarketMessage;
 use rbot_lib::common::ExchangeConfig;
+use rbot_lib::common::Symbol;
+
+use crate::TradeStream;
+use crate::OhlcvStream;
+use crate::{health_check, HealthStatus};
+use rbot_lib::common::ConsoleProgressCallback;
+use rbot_lib::common::ProgressCallback;
 use rbot_lib::common::PyRestBar;
 use rbot_lib::common::FLOOR_SEC;
+use rbot_lib::common::{DAYS, FLOOR_DAY};
 use rbot_lib::common::MICRO_SECOND;
+use rbot_lib::db::apply_column_style;
 use rbot_lib::db::convert_timems_to_datetime;
+use rbot_lib::db::tick_direction_df;
+use rbot_lib::db::CacheStats;
+use rbot_lib::db::Ohlcvb;
+use rbot_lib::db::RangeStats;
+use rbot_lib::db::TradeSummary;
+use rbot_lib::db::ColumnStyle;
+use rbot_lib::db::CsvSchema;
+use rbot_lib::db::DownloadQueue;
+use rbot_lib::db::SpreadLogger;
 use rbot_lib::db::TradeDataFrame;
 use rbot_lib::db::TradeDb;
+use rbot_lib::db::TRADE_STREAM_CHANNEL_SIZE;
 use rbot_lib::net::BroadcastMessage;
 use rbot_lib::net::RestPage;
 use rbot_lib::net::WebSocketClient;
 use rust_decimal_macros::dec;
+use std::path::Path;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::sleep;
 use std::time::Duration;
@@ -33,9 +55,12 @@ use tokio_stream::Stream;
 use tokio_stream::StreamExt as _;
 
 use pyo3_polars::PyDataFrame;
+use pyo3_polars::PyLazyFrame;
 use rbot_lib::common::BoardItem;
+use rbot_lib::common::BoardEventStream;
 use rbot_lib::common::OrderBook;
 use rbot_lib::net::RestApi;
+use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
 
 use anyhow::anyhow;
@@ -44,8 +69,9 @@ use anyhow::Context;
 
 use rbot_lib::{
     common::{
-        AccountPair, MarketConfig, MarketStream, MicroSec, Order, OrderSide, OrderType, Trade,
-        MARKET_HUB, NOW,
+        AccountPair, MarketConfig, MarketError, MarketStream, MicroSec, Order, OrderSide, OrderStatus, OrderType, TimeInForce, Trade,
+        MARKET_HUB, NOW, CLOCK_SKEW_WARN_THRESHOLD, set_server_time_offset,
+        apply_instrument_cache, get_instrument_cache, set_instrument_cache,
     },
     db::df::KEY,
 };
@@ -74,10 +100,93 @@ pub fn extract_or_generate_config(exchange_name: &str, config: &PyAny) -> anyhow
         let symbol = config.extract::<String>()?;
         return Ok(ExchangeConfig::open_exchange_market(exchange_name, &symbol)?);
     }
+    else if *name == *"Symbol" || *name == *"builtins.Symbol" {
+        let symbol = config.extract::<Symbol>()?;
+        return Ok(ExchangeConfig::open_exchange_market(exchange_name, &symbol.get_unified())?);
+    }
 
     Err(anyhow!("unsupported type {:?}", name))
 }
 
+/// アーカイブの範囲と重なるDBのデータを削除する
+/// 取引所との時差(サーバ時刻 - ローカル時刻)を測る。サーバ時刻は往復の中間時点の値とみなす。
+/// 測った時差は署名付きリクエストの時刻補正(SERVER_NOW)に使う。
+pub async fn measure_clock_skew<T: RestApi>(api: &T) -> anyhow::Result<MicroSec> {
+    let start = NOW();
+    let server_time = api.get_server_time().await?;
+    let end = NOW();
+
+    let skew = server_time - (start + end) / 2;
+
+    let exchange_name = api.get_exchange().get_exchange_name();
+    set_server_time_offset(&exchange_name, skew);
+
+    if CLOCK_SKEW_WARN_THRESHOLD < skew.abs() {
+        log::warn!(
+            "local clock differs from {} server by {}[ms]. check the system clock(NTP).",
+            exchange_name,
+            skew / 1_000
+        );
+    }
+
+    Ok(skew)
+}
+
+/// 取引所の銘柄情報(tick size, 最小数量, 契約乗数)を取得してキャッシュする。
+/// キャッシュ済みの場合はforceを指定しない限り通信しない。
+pub async fn fetch_instrument_info<T: RestApi>(
+    api: &T,
+    config: &MarketConfig,
+    force: bool,
+) -> anyhow::Result<MarketConfig> {
+    let production = api.get_exchange().is_production();
+
+    if !force {
+        if get_instrument_cache(production, config).is_some() {
+            return Ok(apply_instrument_cache(production, config));
+        }
+    }
+
+    let fetched = api
+        .get_instrument_info(config)
+        .await
+        .with_context(|| format!("fetch_instrument_info error: {}", config.trade_symbol))?;
+
+    if fetched.price_unit != config.price_unit || fetched.size_unit != config.size_unit || fetched.min_size != config.min_size {
+        log::info!(
+            "instrument info updated {}: price_unit {}=>{}, size_unit {}=>{}, min_size {}=>{}",
+            config.trade_symbol,
+            config.price_unit,
+            fetched.price_unit,
+            config.size_unit,
+            fetched.size_unit,
+            config.min_size,
+            fetched.min_size
+        );
+    }
+
+    set_instrument_cache(production, &fetched);
+
+    Ok(fetched)
+}
+
+fn expire_db_before_archive_end(db: &mut TradeDataFrame) -> anyhow::Result<()> {
+    let archive_end = db.get_archive_end_time();
+
+    // delete old data from db.
+    if archive_end != 0 {
+        let expire =
+            TradeDb::expire_control_message(0, archive_end + 1, true, "download archive");
+
+        log::debug!("expire: {:?}", expire);
+
+        let tx = db.open_channel()?;
+        tx.send(expire)?;
+    }
+
+    Ok(())
+}
+
 pub trait OrderInterface {
     fn set_enable_order_feature(&mut self, enable_order: bool);
     fn get_enable_order_feature(&self) -> bool;
@@ -90,6 +199,8 @@ pub trait OrderInterface {
         price: Decimal,
         size: Decimal,
         client_order_id: Option<&str>,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>>;
 
     fn market_order(
@@ -98,6 +209,7 @@ pub trait OrderInterface {
         side: &str,
         size: Decimal,
         client_order_id: Option<&str>,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>>;
     fn dry_market_order(
         &self,
@@ -114,6 +226,99 @@ pub trait OrderInterface {
     fn get_account(&self, market_config: &MarketConfig) -> anyhow::Result<AccountPair>;
 }
 
+/// 発注前の検証。取引所には送信せず、丸めた価格・サイズで送信するはずのOrder(status=ServerWait)を返す。
+/// 残高の確認はcheck_order_balance(OrderInterfaceImpl::validate_orderで行う)。
+pub fn preview_order(
+    market_config: &MarketConfig,
+    side: &str,
+    price: Decimal,
+    size: Decimal,
+    order_type: OrderType,
+    client_order_id: Option<&str>,
+    time_in_force: TimeInForce,
+    reduce_only: bool,
+) -> anyhow::Result<Order> {
+    let order_side = OrderSide::from(side);
+    if order_side != OrderSide::Buy && order_side != OrderSide::Sell {
+        return Err(MarketError::invalid_order(&format!("invalid order side {:?}", side)).into());
+    }
+
+    let size = market_config.round_size(size)?;
+    if size <= dec![0.0] {
+        return Err(MarketError::invalid_order(&format!("order size must be positive size={}", size)).into());
+    }
+
+    if order_type == OrderType::Market && time_in_force == TimeInForce::PostOnly {
+        return Err(MarketError::invalid_order("market order can not be PostOnly").into());
+    }
+
+    let price = if order_type == OrderType::Market {
+        dec![0.0]
+    } else {
+        let price = market_config.round_price(price)?;
+        if price <= dec![0.0] {
+            return Err(MarketError::invalid_order(&format!("order price must be positive price={}", price)).into());
+        }
+        price
+    };
+
+    let mut order = Order::new(
+        &market_config.trade_category,
+        &market_config.trade_symbol,
+        NOW(),
+        "",
+        client_order_id.unwrap_or_default(),
+        order_side,
+        order_type,
+        OrderStatus::ServerWait,
+        price,
+        size,
+    );
+    order.reduce_only = reduce_only;
+    order.quote_vol = price * size;
+
+    Ok(order)
+}
+
+/// 発注に必要な残高があるかを確認する。
+/// spotは買いならhome通貨(指値×数量)、売りならforeign通貨(数量)の利用可能額と比べる。
+/// 成行の買いは価格がわからないため、home通貨の利用可能額があることだけを確認する。
+/// 先物の証拠金はレバレッジによるので、reduce onlyでない注文で決済通貨の利用可能額がない場合のみエラーにする。
+pub fn check_order_balance(market_config: &MarketConfig, order: &Order, coins: &AccountCoins) -> anyhow::Result<()> {
+    let free = |symbol: &str| {
+        coins
+            .coins
+            .iter()
+            .find(|c| c.symbol == symbol)
+            .map(|c| c.free)
+            .unwrap_or(dec![0.0])
+    };
+
+    let (coin, required) = if market_config.trade_category == "spot" {
+        if order.order_side == OrderSide::Buy {
+            (&market_config.home_currency, order.order_price * order.order_size)
+        } else {
+            (&market_config.foreign_currency, order.order_size)
+        }
+    } else {
+        if order.reduce_only {
+            return Ok(());
+        }
+        (&market_config.settle_currency, dec![0.0])
+    };
+
+    let available = free(coin);
+    if available <= dec![0.0] || available < required {
+        return Err(MarketError::invalid_order(&format!(
+            "insufficient balance {}: required={} available={}",
+            coin, required, available
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
 pub trait OrderInterfaceImpl<T>
 where
     T: RestApi,
@@ -131,21 +336,61 @@ where
         size: Decimal,
         order_type: OrderType,
         client_order_id: Option<&str>,
+        position_idx: Option<u8>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>> {
         let order_side = OrderSide::from(side);
 
         let api = self.get_restapi();
-        api.new_order(
+        api.new_order_with_position_idx(
             &market_config,
             order_side,
             price,
             size,
             order_type,
             client_order_id,
+            position_idx,
+            time_in_force,
+            reduce_only,
         )
         .await
     }
 
+    /// dry_run用。発注と同じく銘柄情報のキャッシュを反映して検証し、残高も確認する。
+    /// 残高の取得以外の通信はせず、送信するはずのOrder(status=ServerWait)を返す。
+    async fn validate_order(
+        &self,
+        market_config: &MarketConfig,
+        side: &str,
+        price: Decimal,
+        size: Decimal,
+        order_type: OrderType,
+        client_order_id: Option<&str>,
+        time_in_force: TimeInForce,
+        reduce_only: bool,
+    ) -> anyhow::Result<Order> {
+        let api = self.get_restapi();
+        let production = api.get_exchange().is_production();
+        let market_config = &apply_instrument_cache(production, market_config);
+
+        let order = preview_order(
+            market_config,
+            side,
+            price,
+            size,
+            order_type,
+            client_order_id,
+            time_in_force,
+            reduce_only,
+        )?;
+
+        let coins = api.get_account().await?;
+        check_order_balance(market_config, &order, &coins)?;
+
+        Ok(order)
+    }
+
     //------ REST API ----
     async fn limit_order(
         &self,
@@ -154,8 +399,37 @@ where
         price: Decimal,
         size: Decimal,
         client_order_id: Option<&str>,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
+    ) -> anyhow::Result<Vec<Order>> {
+        self.limit_order_with_position_idx(
+            market_config,
+            side,
+            price,
+            size,
+            client_order_id,
+            None,
+            time_in_force,
+            reduce_only,
+        )
+        .await
+    }
+
+    /// ヘッジモードのポジション(position_idx)を指定する指値注文。Noneならlimit_orderと同じ。
+    async fn limit_order_with_position_idx(
+        &self,
+        market_config: &MarketConfig,
+        side: &str,
+        price: Decimal,
+        size: Decimal,
+        client_order_id: Option<&str>,
+        position_idx: Option<u8>,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>> {
         check_if_enable_order!(self);
+        let production = self.get_restapi().get_exchange().is_production();
+        let market_config = &apply_instrument_cache(production, market_config);
         let price = market_config.round_price(price)?;
         let size = market_config.round_size(size)?;
 
@@ -166,6 +440,9 @@ where
             size,
             OrderType::Limit,
             client_order_id,
+            position_idx,
+            time_in_force.unwrap_or_default(),
+            reduce_only,
         )
         .await
     }
@@ -176,8 +453,25 @@ where
         side: &str,
         size: Decimal,
         client_order_id: Option<&str>,
+        reduce_only: bool,
+    ) -> anyhow::Result<Vec<Order>> {
+        self.market_order_with_position_idx(market_config, side, size, client_order_id, None, reduce_only)
+            .await
+    }
+
+    /// ヘッジモードのポジション(position_idx)を指定する成行注文。Noneならmarket_orderと同じ。
+    async fn market_order_with_position_idx(
+        &self,
+        market_config: &MarketConfig,
+        side: &str,
+        size: Decimal,
+        client_order_id: Option<&str>,
+        position_idx: Option<u8>,
+        reduce_only: bool,
     ) -> anyhow::Result<Vec<Order>> {
         check_if_enable_order!(self);
+        let production = self.get_restapi().get_exchange().is_production();
+        let market_config = &apply_instrument_cache(production, market_config);
         let size = market_config.round_size(size)?;
 
         self.make_order(
@@ -187,6 +481,9 @@ where
             size,
             OrderType::Market,
             client_order_id,
+            position_idx,
+            TimeInForce::GTC,
+            reduce_only,
         )
         .await
     }
@@ -216,12 +513,27 @@ where
         api.open_orders(market_config).await
     }
 
+    async fn get_order_status(&self, market_config: &MarketConfig, order_id: &str) -> anyhow::Result<Order> {
+        let api = self.get_restapi();
+
+        api.get_order_status(market_config, order_id)
+            .await
+            .with_context(|| format!("Error in get_order_status: {:?}", &order_id))
+    }
+
     async fn get_account(&self) -> anyhow::Result<AccountCoins> {
         let api = self.get_restapi();
 
         api.get_account().await
     }
 
+    /// 取引所によらず共通の形式(Coin: volume=総額, free=利用可能額)で残高を返す。
+    async fn get_balances(&self) -> anyhow::Result<Vec<Coin>> {
+        let account = self.get_account().await?;
+
+        Ok(account.coins)
+    }
+
     async fn async_start_user_stream(&mut self) -> anyhow::Result<()>;
 }
 
@@ -247,6 +559,7 @@ pub trait MarketInterface {
         &mut self,
         start_time: MicroSec,
         end_time: MicroSec,
+        style: ColumnStyle,
     ) -> anyhow::Result<PyDataFrame>;
     fn ohlcvv(
         &mut self,
@@ -259,7 +572,23 @@ pub trait MarketInterface {
         start_time: MicroSec,
         end_time: MicroSec,
         window_sec: i64,
+        style: ColumnStyle,
+    ) -> anyhow::Result<PyDataFrame>;
+    fn cvd(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        absolute: bool,
     ) -> anyhow::Result<PyDataFrame>;
+    fn ohlcv_csv(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        path: &str,
+        iso_time: bool,
+    ) -> anyhow::Result<i64>;
     fn vap(
         &mut self,
         start_time: MicroSec,
@@ -270,7 +599,15 @@ pub trait MarketInterface {
     fn get_board_json(&self, size: usize) -> anyhow::Result<String>;
     fn get_board(&mut self) -> anyhow::Result<(PyDataFrame, PyDataFrame)>;
     fn get_board_vec(&self) -> anyhow::Result<(Vec<BoardItem>, Vec<BoardItem>)>;
+    fn get_board_grouped(&self, group: Decimal) -> anyhow::Result<(PyDataFrame, PyDataFrame)>;
     fn get_edge_price(&self) -> anyhow::Result<(Decimal, Decimal)>;
+    fn start_spread_logging(&mut self, interval_ms: u64) -> anyhow::Result<()>;
+    fn stop_spread_logging(&mut self);
+    fn get_spread_history(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<PyDataFrame>;
     fn get_running(&self) -> bool;
     fn vacuum(&self);
     fn get_file_name(&self) -> String; // get db file path
@@ -286,6 +623,13 @@ pub trait MarketInterface {
         archive_only: bool,
         low_priority: bool,
     ) -> i64;
+    fn download_range(
+        &mut self,
+        start_time: &PyAny,
+        end_time: &PyAny,
+        force: bool,
+        verbose: bool,
+    ) -> anyhow::Result<i64>;
     fn download_latest(&mut self, verbose: bool) -> anyhow::Result<i64>;
     fn download_gap(&mut self, verbose: bool) -> anyhow::Result<i64>;
     fn expire_unfix_data(&mut self) -> anyhow::Result<()>;
@@ -378,16 +722,65 @@ where
         Ok((start_time, end_time))
     }
 
+    /// 直近ndays分のDBの空白区間と、前後の約定頻度から推定した欠損件数
+    fn gap_report(&self, ndays: i64) -> anyhow::Result<PyDataFrame> {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+
+        let now = NOW();
+        let df = lock.gap_report(now - DAYS(ndays), now)?;
+
+        Ok(PyDataFrame(df))
+    }
+
+    /// 直近ndays分のDBの空白区間(start, end)
+    fn gap_chunks(&self, ndays: i64, allow_size: MicroSec) -> anyhow::Result<Vec<(MicroSec, MicroSec)>> {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+
+        let now = NOW();
+        let chunks = lock.select_gap_chunks(now - DAYS(ndays), now, allow_size)?;
+
+        Ok(chunks.into_iter().map(|c| (c.start, c.end)).collect())
+    }
+
+    /// [start_time, end_time)の価格/出来高の集計をDBのSQLで求める
+    fn range_stats(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<RangeStats> {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+
+        lock.range_stats(start_time, end_time)
+    }
+
+    /// [start_time, end_time)のDB上の約定件数(DataFrameを作らない)
+    fn count(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<u64> {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+
+        lock.count_records(start_time, end_time)
+    }
+
+    /// [start_time, end_time)の件数内訳と出来高をDBのSQLで求める
+    fn summary(&self, start_time: MicroSec, end_time: MicroSec) -> anyhow::Result<TradeSummary> {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+
+        lock.summary(start_time, end_time)
+    }
+
     fn select_trades(
         &mut self,
         start_time: MicroSec,
         end_time: MicroSec,
+        style: ColumnStyle,
     ) -> anyhow::Result<PyDataFrame> {
         let db = self.get_db();
         let mut lock = db.lock().unwrap();
 
-        let mut df = lock.fetch_cache_df(start_time, end_time)?;
+        let df = lock.fetch_cache_df(start_time, end_time)?;
+        let mut df = tick_direction_df(&df)?;
         convert_timems_to_datetime(&mut df)?;
+        apply_column_style(&mut df, style)?;
 
         Ok(PyDataFrame(df))
     }
@@ -444,6 +837,40 @@ where
         Ok(PyDataFrame(df))
     }
 
+    fn select_large_trades(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        let min_size = min_size
+            .to_f64()
+            .ok_or_else(|| anyhow!("invalid min_size {}", min_size))?;
+
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        let mut df = lock.fetch_large_trades_df(start_time, end_time, min_size)?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
+    }
+
+    fn ohlcv_large(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        min_size: Decimal,
+    ) -> anyhow::Result<PyDataFrame> {
+        let min_size = min_size
+            .to_f64()
+            .ok_or_else(|| anyhow!("invalid min_size {}", min_size))?;
+
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        lock.py_ohlcv_large_polars(start_time, end_time, window_sec, min_size)
+    }
+
     fn ohlcvv(
         &mut self,
         start_time: MicroSec,
@@ -460,10 +887,56 @@ where
         start_time: MicroSec,
         end_time: MicroSec,
         window_sec: i64,
+        style: ColumnStyle,
+        day_start_offset_sec: i64,
     ) -> anyhow::Result<PyDataFrame> {
         let db = self.get_db();
         let mut lock = db.lock().unwrap();
-        lock.py_ohlcv_polars(start_time, end_time, window_sec)
+        let mut df = lock
+            .py_ohlcv_polars_with_offset(start_time, end_time, window_sec, day_start_offset_sec)?
+            .0;
+        apply_column_style(&mut df, style)?;
+
+        Ok(PyDataFrame(df))
+    }
+
+    /// collectするまで集計しないLazyFrameを返す
+    fn ohlcv_lazy(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+    ) -> anyhow::Result<PyLazyFrame> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        let lazy = lock.ohlcv_lazy(start_time, end_time, window_sec)?;
+
+        Ok(PyLazyFrame(lazy))
+    }
+
+    fn cvd(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        absolute: bool,
+    ) -> anyhow::Result<PyDataFrame> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        lock.py_cvd_polars(start_time, end_time, window_sec, absolute)
+    }
+
+    fn ohlcv_csv(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        path: &str,
+        iso_time: bool,
+    ) -> anyhow::Result<i64> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        lock.ohlcv_csv(start_time, end_time, window_sec, path, iso_time)
     }
 
     fn vap(
@@ -477,6 +950,36 @@ where
         lock.py_vap(start_time, end_time, price_unit)
     }
 
+    /// キャッシュの先読み（tokio runtime内で呼ぶこと）
+    fn prefetch(&mut self, start_time: MicroSec, end_time: MicroSec) {
+        let db = self.get_db();
+        let _ = TradeDataFrame::prefetch(&db, start_time, end_time);
+    }
+
+    fn cache_hit_rate(&self) -> f64 {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+        lock.cache_hit_rate()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+        lock.cache_stats()
+    }
+
+    fn get_cache_max_rows(&self) -> usize {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+        lock.get_cache_max_rows()
+    }
+
+    fn set_cache_max_rows(&mut self, max_rows: usize) -> anyhow::Result<()> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        lock.set_cache_max_rows(max_rows)
+    }
+
     fn start_time(&mut self) -> MicroSec {
         let db = self.get_db();
         let lock = db.lock().unwrap();
@@ -503,6 +1006,93 @@ where
 
     fn get_order_book(&self) -> Arc<RwLock<OrderBook>>;
 
+    /// 板の更新ごとに価格レベルのadd/cancel/modifyを受け取る(スプーフィング検出などの研究用)
+    fn board_events(&self) -> BoardEventStream {
+        self.get_order_book().write().unwrap().open_event_channel()
+    }
+
+    fn get_spread_logger(&mut self) -> &mut Option<SpreadLogger>;
+
+    /// 板の最良気配をinterval_msごとにspread_logテーブルへ記録する
+    async fn async_start_spread_logging(&mut self, interval_ms: u64) -> anyhow::Result<()> {
+        self.async_stop_spread_logging().await;
+
+        let spread_db = {
+            let db = self.get_db();
+            let lock = db.lock().unwrap();
+            lock.open_spread_db()?
+        };
+
+        let orderbook = self.get_order_book();
+        let logger = SpreadLogger::start(spread_db, interval_ms, move || {
+            orderbook.read().unwrap().get_edge_price()
+        });
+
+        *self.get_spread_logger() = Some(logger);
+
+        Ok(())
+    }
+
+    async fn async_stop_spread_logging(&mut self) {
+        if let Some(mut logger) = self.get_spread_logger().take() {
+            logger.stop().await;
+        }
+    }
+
+    fn get_spread_history(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> anyhow::Result<PyDataFrame> {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+        let mut df = lock.select_spread_df(start_time, end_time)?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
+    }
+
+    /// OHLCV + spread。spread loggingで記録した期間が必要。
+    fn ohlcvb(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<Ohlcvb> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        let df = lock.ohlcvb_df(start_time, end_time, window_sec)?;
+
+        Ok(Ohlcvb::new(start_time, end_time, window_sec, df))
+    }
+
+    /// Kyle's lambda(出来高あたりの価格インパクト)
+    fn kyles_lambda(&mut self, start_time: MicroSec, end_time: MicroSec, window_sec: i64) -> anyhow::Result<f64> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        lock.kyles_lambda(start_time, end_time, window_sec)
+    }
+
+    fn rolling_kyles_lambda(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        window_sec: i64,
+        step_sec: i64,
+    ) -> anyhow::Result<PyDataFrame> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        let mut df = lock.rolling_kyles_lambda(start_time, end_time, window_sec, step_sec)?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
+    }
+
+    /// 約定代金(price * size)がthresholdに達するごとに区切った足
+    fn dollar_bars(&mut self, start_time: MicroSec, end_time: MicroSec, threshold: Decimal) -> anyhow::Result<PyDataFrame> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+        let mut df = lock.tick_bars(start_time, end_time, BarType::DollarBar(threshold))?;
+        convert_timems_to_datetime(&mut df)?;
+
+        Ok(PyDataFrame(df))
+    }
+
     async fn async_get_board(&mut self) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
         let orderbook = self.get_order_book();
 
@@ -546,6 +1136,17 @@ where
         return Ok((PyDataFrame(bids), PyDataFrame(asks)));
     }
 
+    /// 約定をpythonのasync iteratorで受け取る。open_market_streamで配信を開始しておくこと。
+    /// 直近dedup_window件の約定idで重複を除く(0で無効)。
+    fn trade_stream(&self, dedup_window: usize) -> anyhow::Result<TradeStream> {
+        TradeStream::subscribe(&self.get_config(), dedup_window)
+    }
+
+    /// window_sec幅の足をpythonのasync iteratorで受け取る。約定ごとに差分で更新する。
+    fn subscribe_ohlcv(&self, window_sec: i64, dedup_window: usize) -> anyhow::Result<OhlcvStream> {
+        OhlcvStream::subscribe(&self.get_config(), window_sec, dedup_window)
+    }
+
     fn get_board_json(&self, size: usize) -> anyhow::Result<String> {
         let orderbook = self.get_order_book();
 
@@ -572,6 +1173,49 @@ where
         Ok((bids, asks))
     }
 
+    /// 板をshape(2, depth, 2)のnumpy配列(side, level, [price, size])で返す。sideは0がbids、1がasks。
+    fn get_board_tensor(&self, py: Python, depth: usize) -> PyObject {
+        let orderbook = self.get_order_book();
+        let lock = orderbook.read().unwrap();
+
+        lock.get_board_pyarray(py, depth)
+    }
+
+    /// groupは呼値(price_unit)の倍数に切り捨てて使う
+    fn get_board_grouped(&self, group: Decimal) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
+        let config = self.get_config();
+        let group = config
+            .round_price(group)
+            .with_context(|| format!("group({}) is smaller than price_unit({})", group, config.price_unit))?;
+
+        let orderbook = self.get_order_book();
+
+        let (bids, asks) = {
+            let lock = orderbook.read().unwrap();
+            lock.get_board_grouped(group)?
+        };
+
+        Ok((PyDataFrame(bids), PyDataFrame(asks)))
+    }
+
+    async fn async_server_time(&self) -> anyhow::Result<MicroSec> {
+        self.get_restapi().get_server_time().await
+    }
+
+    /// 発注せずにREST/WebSocketの接続とAPIキーを確認する
+    async fn async_health_check(&self, ws_url: &str) -> anyhow::Result<HealthStatus> {
+        health_check(self.get_restapi(), ws_url).await
+    }
+
+    /// 取引所の銘柄情報を取得(キャッシュ)したMarketConfig。以後の発注の丸めに使われる。
+    async fn async_fetch_instrument_info(&self, force: bool) -> anyhow::Result<MarketConfig> {
+        fetch_instrument_info(self.get_restapi(), &self.get_config(), force).await
+    }
+
+    async fn async_clock_skew(&self) -> anyhow::Result<MicroSec> {
+        measure_clock_skew(self.get_restapi()).await
+    }
+
     async fn async_get_edge_price(&mut self) -> anyhow::Result<(Decimal, Decimal)> {
         let orderbook = self.get_order_book();
 
@@ -596,6 +1240,30 @@ where
         lock.open_channel()
     }
 
+    /// DBへの書き込みスレッドが動作しているか
+    fn is_db_thread_running(&self) -> bool {
+        let db = self.get_db();
+        let lock = db.lock().unwrap();
+
+        lock.is_db_thread_running()
+    }
+
+    /// 書き込みスレッドを新しいDB接続で起動し直す
+    fn restart_db_thread(&mut self) -> anyhow::Result<()> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+
+        lock.restart_db_thread()
+    }
+
+    /// 取引所のUIなどからダウンロードした約定履歴CSVをDBへ取り込む
+    fn import_csv(&mut self, path: &str, schema: CsvSchema) -> anyhow::Result<u64> {
+        let db = self.get_db();
+        let mut lock = db.lock().unwrap();
+
+        lock.import_csv(Path::new(path), schema)
+    }
+
     async fn async_download_recent_trades(
         &self,
         market_config: &MarketConfig,
@@ -611,30 +1279,42 @@ where
         &mut self,
         time_from: MicroSec,
         time_to: MicroSec,
+    ) -> anyhow::Result<(MicroSec, MicroSec, MarketStream)> {
+        self.open_backtest_channel_timed(time_from, time_to, 0.0)
+    }
+
+    /// open_backtest_channelと同じだが、Tradeの時刻間隔どおりspeed倍速で流す。
+    /// speed=0.0は待たずに流す。
+    fn open_backtest_channel_timed(
+        &mut self,
+        time_from: MicroSec,
+        time_to: MicroSec,
+        speed: f64,
     ) -> anyhow::Result<(MicroSec, MicroSec, MarketStream)> {
         let (sender, market_stream) = MarketStream::open();
 
-        let mut archive = {
+        let (dates, mut receiver) = {
             let db = self.get_db();
-            let trade_dataframe = db.lock().unwrap();
-            trade_dataframe.get_archive()
-        };
+            let mut trade_dataframe = db.lock().unwrap();
+            let dates = trade_dataframe.get_archive().select_dates(time_from, time_to)?;
+            let receiver = if 0.0 < speed {
+                trade_dataframe.select_stream_timed(time_from, time_to, speed)
+            } else {
+                trade_dataframe.stream_to_channel(time_from, time_to, TRADE_STREAM_CHANNEL_SIZE)
+            };
 
-        let dates = archive.select_dates(time_from, time_to)?;
+            (dates, receiver)
+        };
 
         let actual_start = dates[0];
         let actual_end = dates[dates.len() - 1];
 
         std::thread::spawn(move || {
-            let result = archive.foreach(time_from, time_to, &mut |trade| {
-                let message: MarketMessage = trade.into();
-                sender.send(message)?;
-
-                Ok(())
-            });
-
-            if result.is_err() {
-                log::error!("Error in select: {:?}", result.err().unwrap());
+            while let Some(message) = receiver.blocking_recv() {
+                if sender.send(message).is_err() {
+                    log::debug!("backtest channel closed");
+                    break;
+                }
             }
         });
 
@@ -649,6 +1329,7 @@ where
         force_archive: bool,
         force_recent: bool,
         verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
     ) -> anyhow::Result<()>
     where
         U: WebSocketClient + 'static,
@@ -657,14 +1338,34 @@ where
                 ndays, connect_ws, force, force_archive, force_recent, verbose
         );
         let force_recent = if force { true } else { force_recent };
+        let force_archive = if force { true } else { force_archive };
+
+        // 同じsymbolのダウンロードが実行中なら終わるのを待ち、必要な範囲がそろっていればスキップする
+        let key = DownloadQueue::key(&self.get_config());
+        let slot = DownloadQueue::global().acquire(&key).await;
+
+        if slot.waited && !force_archive && !force_recent {
+            let required_start = FLOOR_DAY(NOW()) - DAYS(ndays - 1);
+
+            if let Ok((start, _end)) = MarketImpl::get_archive_info(self) {
+                if 0 < start && start <= required_start {
+                    log::debug!("{} is already downloaded by other request", key);
+                    return Ok(());
+                }
+            }
+        }
 
         self.async_download_realtime::<U>(connect_ws, force_recent, verbose)
             .await?;
 
-        let force_archive = if force { true } else { force_archive };
-        self.async_download_archive(ndays, force_archive, verbose)
+        self.async_download_archive(ndays, force_archive, verbose, progress)
             .await?;
 
+        if verbose {
+            let report = MarketImpl::gap_report(self, ndays)?;
+            println!("gap report: {}", report.0);
+        }
+
         Ok(())
     }
 
@@ -673,6 +1374,7 @@ where
         ndays: i64,
         force: bool,
         verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
     ) -> anyhow::Result<i64> {
         let db = self.get_db();
         let api = self.get_restapi();
@@ -685,32 +1387,60 @@ where
 
         let mut lock = lock.unwrap();
 
-        let count = lock.download_archive(api, ndays, force, verbose).await?;
-        let archive_end = lock.get_archive_end_time();
+        let count = lock
+            .download_archive(api, ndays, force, verbose, progress)
+            .await?;
+
+        expire_db_before_archive_end(&mut lock)?;
 
-        // delete old data from db.
-        if archive_end != 0 {
-            let expire =
-                TradeDb::expire_control_message(0, archive_end + 1, true, "download archive");
+        Ok(count)
+    }
 
-            log::debug!("expire: {:?}", expire);
+    /// start_time〜end_timeの日のアーカイブだけをダウンロードする
+    async fn async_download_archive_range(
+        &self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        force: bool,
+        verbose: bool,
+        progress: Option<Box<dyn ProgressCallback>>,
+    ) -> anyhow::Result<i64> {
+        let db = self.get_db();
+        let api = self.get_restapi();
+        let lock = db.lock();
 
-            let tx = lock.open_channel()?;
-            tx.send(expire)?;
+        if lock.is_err() {
+            log::error!("db get lock failure ");
+            return Err(anyhow!("db get lock error"));
         }
 
+        let mut lock = lock.unwrap();
+
+        let count = lock
+            .download_archive_range(api, start_time, end_time, force, verbose, progress)
+            .await?;
+
+        expire_db_before_archive_end(&mut lock)?;
+
         Ok(count)
     }
 
     async fn async_download_latest(&mut self, verbose: bool) -> anyhow::Result<(i64, i64)> {
-        if verbose {
-            println!("async_download_lastest");
-            flush_log();
-        }
+        // verboseの時はstderrへ進捗を出す
+        let progress: Option<Box<dyn ProgressCallback>> = if verbose {
+            Some(Box::new(ConsoleProgressCallback))
+        } else {
+            None
+        };
 
         let api = self.get_restapi();
         let config = self.get_config().clone();
 
+        let source = format!("recent trades {}", config.trade_symbol);
+        if let Some(p) = &progress {
+            p.on_file_start(&source);
+        }
+
         let mut trades = api.get_recent_trades(&config).await?;
         trades.sort_by(|t1, t2| t1.time.cmp(&t2.time));
         let rec = trades.len() as i64;
@@ -721,11 +1451,12 @@ where
 
         trades[0].status = LogStatus::UnFixStart;
 
-        if verbose {
-            println!("from rec: {:?}", trades[0].__str__());
-            println!("to   rec: {:?}", trades[(rec as usize) - 1].__str__());
-            println!("rec: {}", rec);
-            flush_log();
+        log::debug!("from rec: {:?}", trades[0].__str__());
+        log::debug!("to   rec: {:?}", trades[(rec as usize) - 1].__str__());
+
+        if let Some(p) = &progress {
+            p.on_file_done(&source, rec);
+            p.on_total_progress(1, 1);
         }
         let tx = self.open_db_channel()?;
 
@@ -997,3 +1728,85 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod market_test {
+    use super::*;
+
+    #[test]
+    fn test_preview_order() -> anyhow::Result<()> {
+        let mut config = MarketConfig::default();
+        config.trade_symbol = "BTCUSDT".to_string();
+        config.price_unit = dec![0.5];
+        config.size_unit = dec![0.001];
+        config.min_size = dec![0.001];
+
+        let order = preview_order(
+            &config,
+            "Buy",
+            dec![60000.3],
+            dec![0.0123],
+            OrderType::Limit,
+            Some("preview-1"),
+            TimeInForce::GTC,
+            false,
+        )?;
+        assert_eq!(order.status, OrderStatus::ServerWait);
+        assert_eq!(order.order_price, dec![60000.0]);
+        assert_eq!(order.order_size, dec![0.012]);
+        assert_eq!(order.client_order_id, "preview-1");
+        assert_eq!(order.order_id, "");
+
+        // 最小サイズ未満
+        assert!(preview_order(&config, "Buy", dec![60000.0], dec![0.0001], OrderType::Limit, None, TimeInForce::GTC, false).is_err());
+        // 不明なside
+        assert!(preview_order(&config, "Hold", dec![60000.0], dec![0.01], OrderType::Limit, None, TimeInForce::GTC, false).is_err());
+        // 成行のPostOnly
+        assert!(preview_order(&config, "Sell", dec![0.0], dec![0.01], OrderType::Market, None, TimeInForce::PostOnly, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_order_balance() -> anyhow::Result<()> {
+        let coin = |symbol: &str, free: Decimal| {
+            let mut coin = Coin::default();
+            coin.symbol = symbol.to_string();
+            coin.volume = free;
+            coin.free = free;
+            coin
+        };
+        let mut coins = AccountCoins::new();
+        coins.push(coin("USDT", dec![1000.0]));
+        coins.push(coin("BTC", dec![0.01]));
+
+        let mut config = MarketConfig::default();
+        config.trade_category = "spot".to_string();
+        config.trade_symbol = "BTCUSDT".to_string();
+        config.home_currency = "USDT".to_string();
+        config.foreign_currency = "BTC".to_string();
+        config.settle_currency = "USDT".to_string();
+        config.price_unit = dec![0.5];
+        config.size_unit = dec![0.001];
+        config.min_size = dec![0.001];
+
+        let order = |config: &MarketConfig, side: &str, price: Decimal, size: Decimal, reduce_only: bool| {
+            preview_order(config, side, price, size, OrderType::Limit, None, TimeInForce::GTC, reduce_only).unwrap()
+        };
+
+        // spot: 買いはhome通貨、売りはforeign通貨
+        assert!(check_order_balance(&config, &order(&config, "Buy", dec![50000.0], dec![0.02], false), &coins).is_ok());
+        assert!(check_order_balance(&config, &order(&config, "Buy", dec![60000.0], dec![0.02], false), &coins).is_err());
+        assert!(check_order_balance(&config, &order(&config, "Sell", dec![60000.0], dec![0.01], false), &coins).is_ok());
+        assert!(check_order_balance(&config, &order(&config, "Sell", dec![60000.0], dec![0.02], false), &coins).is_err());
+
+        // 先物: 決済通貨がなければエラー(reduce onlyは除く)
+        config.trade_category = "linear".to_string();
+        assert!(check_order_balance(&config, &order(&config, "Buy", dec![60000.0], dec![1.0], false), &coins).is_ok());
+        config.settle_currency = "USDC".to_string();
+        assert!(check_order_balance(&config, &order(&config, "Buy", dec![60000.0], dec![1.0], false), &coins).is_err());
+        assert!(check_order_balance(&config, &order(&config, "Sell", dec![60000.0], dec![1.0], true), &coins).is_ok());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::RecvTimeoutError;
+
+use pyo3::{exceptions::PyStopAsyncIteration, pyclass, pymethods, Bound, PyAny, PyRef, PyResult, Python};
+use tokio::sync::{mpsc, Mutex};
+
+use rbot_lib::common::{time_string, MarketConfig, MarketMessage, MicroSec, OhlcvBar, Trade, FLOOR_SEC, MARKET_HUB};
+
+const TRADE_STREAM_BUFFER_SIZE: usize = 4096;
+const OHLCV_STREAM_BUFFER_SIZE: usize = 4096;
+/// 重複判定のために覚えておく直近の約定idの数
+pub const TRADE_DEDUP_WINDOW: usize = 4096;
+/// 転送スレッドがpython側のstreamの破棄を確認する間隔
+const STREAM_CLOSE_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// MARKET_HUBから次の約定を取り出す。約定が来なくても、転送先`tx`の受け手が破棄されたら
+/// STREAM_CLOSE_CHECK_INTERVAL以内にNoneを返して転送スレッドを終わらせる。
+fn recv_trade<T>(receiver: &crossbeam_channel::Receiver<MarketMessage>, tx: &mpsc::Sender<T>) -> Option<Trade> {
+    loop {
+        if tx.is_closed() {
+            log::debug!("stream closed");
+            return None;
+        }
+
+        match receiver.recv_timeout(STREAM_CLOSE_CHECK_INTERVAL) {
+            Ok(MarketMessage::Trade(trade)) => return Some(trade),
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+/// RESTの補完とWSで同じ約定が2回流れてこないよう、直近window件の約定idで重複を除く。
+/// windowが0またはidが空の約定は常に通す。
+#[derive(Debug, Clone)]
+pub struct TradeDedup {
+    window: usize,
+    ids: VecDeque<String>,
+    seen: HashSet<String>,
+}
+
+impl TradeDedup {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            ids: VecDeque::with_capacity(window),
+            seen: HashSet::with_capacity(window),
+        }
+    }
+
+    /// 初めて見る約定ならtrueを返して記録する。
+    pub fn check(&mut self, trade: &Trade) -> bool {
+        if self.window == 0 || trade.id.is_empty() {
+            return true;
+        }
+
+        if self.seen.contains(&trade.id) {
+            log::debug!("duplicated trade id={}", trade.id);
+            return false;
+        }
+
+        if self.ids.len() == self.window {
+            if let Some(old) = self.ids.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+
+        self.ids.push_back(trade.id.clone());
+        self.seen.insert(trade.id.clone());
+
+        true
+    }
+}
+
+/// `async for trade in market.trade_stream():` で約定を受け取るためのasync iterator
+#[pyclass]
+pub struct TradeStream {
+    receiver: Arc<Mutex<mpsc::Receiver<Trade>>>,
+}
+
+impl TradeStream {
+    pub fn channel(buffer_size: usize) -> (mpsc::Sender<Trade>, Self) {
+        let (tx, rx) = mpsc::channel(buffer_size);
+
+        (
+            tx,
+            Self {
+                receiver: Arc::new(Mutex::new(rx)),
+            },
+        )
+    }
+
+    /// MARKET_HUBからconfigの銘柄の約定だけを取り出して流す。
+    /// python側でstreamが破棄されると転送を終了する。
+    pub fn subscribe(config: &MarketConfig, dedup_window: usize) -> anyhow::Result<Self> {
+        let receiver = MARKET_HUB.subscribe(
+            &config.exchange_name,
+            &config.trade_category,
+            &config.trade_symbol,
+            "",
+        )?;
+
+        let (tx, stream) = Self::channel(TRADE_STREAM_BUFFER_SIZE);
+
+        std::thread::spawn(move || {
+            let mut dedup = TradeDedup::new(dedup_window);
+
+            while let Some(trade) = recv_trade(&receiver, &tx) {
+                if !dedup.check(&trade) {
+                    continue;
+                }
+
+                if tx.blocking_send(trade).is_err() {
+                    log::debug!("trade stream closed");
+                    break;
+                }
+            }
+        });
+
+        Ok(stream)
+    }
+}
+
+#[pymethods]
+impl TradeStream {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let receiver = self.receiver.clone();
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            match receiver.lock().await.recv().await {
+                Some(trade) => Ok(trade),
+                None => Err(PyStopAsyncIteration::new_err("trade stream closed")),
+            }
+        })
+    }
+}
+
+/// 約定を1件ずつ受け取り、現在の足を差分で更新する。
+/// 足の境界を越えた時は確定した足(closed=true)を先に返す。
+#[derive(Debug, Clone)]
+pub struct LiveOHLCV {
+    window_sec: i64,
+    current: Option<OhlcvBar>,
+}
+
+impl LiveOHLCV {
+    pub fn new(window_sec: i64) -> Self {
+        Self {
+            window_sec,
+            current: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<OhlcvBar> {
+        self.current.clone()
+    }
+
+    /// [確定した足(あれば), 更新中の足]を返す。現在の足より古い約定は無視する。
+    pub fn update(&mut self, trade: &Trade) -> Vec<OhlcvBar> {
+        let timestamp = FLOOR_SEC(trade.time, self.window_sec);
+        let mut bars = vec![];
+
+        match self.current.as_mut() {
+            Some(bar) if bar.timestamp == timestamp => {
+                bar.update(trade);
+            }
+            Some(bar) if timestamp < bar.timestamp => {
+                log::debug!("ignore old trade {:?} < {:?}", time_string(trade.time), time_string(bar.timestamp));
+                return bars;
+            }
+            Some(bar) => {
+                let mut closed = bar.clone();
+                closed.closed = true;
+                bars.push(closed);
+
+                *bar = OhlcvBar::new(timestamp, trade);
+            }
+            None => {
+                self.current = Some(OhlcvBar::new(timestamp, trade));
+            }
+        }
+
+        bars.push(self.current.clone().unwrap());
+
+        bars
+    }
+}
+
+/// `async for bar in market.subscribe_ohlcv(60):` で足を受け取るためのasync iterator。
+/// 約定ごとに更新中の足を、足の切り替わりでは確定した足を先に流す。
+#[pyclass]
+pub struct OhlcvStream {
+    receiver: Arc<Mutex<mpsc::Receiver<OhlcvBar>>>,
+}
+
+impl OhlcvStream {
+    pub fn channel(buffer_size: usize) -> (mpsc::Sender<OhlcvBar>, Self) {
+        let (tx, rx) = mpsc::channel(buffer_size);
+
+        (
+            tx,
+            Self {
+                receiver: Arc::new(Mutex::new(rx)),
+            },
+        )
+    }
+
+    pub fn subscribe(config: &MarketConfig, window_sec: i64, dedup_window: usize) -> anyhow::Result<Self> {
+        let receiver = MARKET_HUB.subscribe(
+            &config.exchange_name,
+            &config.trade_category,
+            &config.trade_symbol,
+            "",
+        )?;
+
+        let (tx, stream) = Self::channel(OHLCV_STREAM_BUFFER_SIZE);
+
+        std::thread::spawn(move || {
+            let mut ohlcv = LiveOHLCV::new(window_sec);
+            let mut dedup = TradeDedup::new(dedup_window);
+
+            while let Some(trade) = recv_trade(&receiver, &tx) {
+                if !dedup.check(&trade) {
+                    continue;
+                }
+
+                for bar in ohlcv.update(&trade) {
+                    if tx.blocking_send(bar).is_err() {
+                        log::debug!("ohlcv stream closed");
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(stream)
+    }
+}
+
+#[pymethods]
+impl OhlcvStream {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let receiver = self.receiver.clone();
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            match receiver.lock().await.recv().await {
+                Some(bar) => Ok(bar),
+                None => Err(PyStopAsyncIteration::new_err("ohlcv stream closed")),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod stream_test {
+    use super::*;
+    use pyo3::{types::PyDict, Py};
+    use rbot_lib::common::{LogStatus, OrderSide, SEC};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_recv_trade_stops_when_stream_dropped() {
+        let (hub_tx, hub_rx) = crossbeam_channel::unbounded();
+        let (tx, stream) = TradeStream::channel(16);
+
+        let trade = Trade::new(1, OrderSide::Buy, Decimal::from(100), Decimal::from(1), LogStatus::UnFix, "1");
+        hub_tx.send(MarketMessage::Message("skip".to_string())).unwrap();
+        hub_tx.send(MarketMessage::Trade(trade.clone())).unwrap();
+        assert_eq!(recv_trade(&hub_rx, &tx), Some(trade));
+
+        // 約定が来ないまま(hub_txは生きている)streamを破棄しても転送スレッドは終わる
+        let handle = std::thread::spawn(move || recv_trade(&hub_rx, &tx));
+        drop(stream);
+
+        assert_eq!(handle.join().unwrap(), None);
+
+        drop(hub_tx);
+    }
+
+    #[test]
+    fn test_async_for_trade_stream() -> anyhow::Result<()> {
+        let (tx, stream) = TradeStream::channel(16);
+
+        for i in 0..5 {
+            tx.blocking_send(Trade::new(
+                i,
+                OrderSide::Buy,
+                Decimal::from(100 + i),
+                Decimal::from(1),
+                LogStatus::UnFix,
+                &format!("{}", i),
+            ))?;
+        }
+        drop(tx);
+
+        Python::with_gil(|py| {
+            let locals = PyDict::new_bound(py);
+            locals.set_item("stream", Py::new(py, stream)?)?;
+
+            py.run_bound(
+                r#"
+import asyncio
+
+async def consume(s):
+    return [t.id async for t in s]
+
+result = asyncio.run(consume(stream))
+"#,
+                None,
+                Some(&locals),
+            )?;
+
+            let result: Vec<String> = locals.get_item("result")?.unwrap().extract()?;
+            assert_eq!(result, vec!["0", "1", "2", "3", "4"]);
+
+            Ok::<(), anyhow::Error>(())
+        })
+    }
+
+    #[test]
+    fn test_trade_dedup() {
+        let trade = |id: &str| {
+            Trade::new(0, OrderSide::Buy, Decimal::from(100), Decimal::from(1), LogStatus::UnFix, id)
+        };
+
+        let mut dedup = TradeDedup::new(2);
+        assert!(dedup.check(&trade("a")));
+        assert!(dedup.check(&trade("b")));
+        assert!(!dedup.check(&trade("a")));
+        assert!(!dedup.check(&trade("b")));
+
+        // windowからあふれた古いidは忘れる
+        assert!(dedup.check(&trade("c")));
+        assert!(dedup.check(&trade("a")));
+        assert!(!dedup.check(&trade("c")));
+
+        // idのない約定は常に通す
+        assert!(dedup.check(&trade("")));
+        assert!(dedup.check(&trade("")));
+
+        // window=0では重複除去しない
+        let mut dedup = TradeDedup::new(0);
+        assert!(dedup.check(&trade("a")));
+        assert!(dedup.check(&trade("a")));
+    }
+
+    #[test]
+    fn test_live_ohlcv() {
+        let trade = |time: MicroSec, price: i64, size: i64| {
+            Trade::new(time, OrderSide::Buy, Decimal::from(price), Decimal::from(size), LogStatus::UnFix, "")
+        };
+
+        let mut ohlcv = LiveOHLCV::new(60);
+
+        let bars = ohlcv.update(&trade(SEC(60), 100, 1));
+        assert_eq!(bars.len(), 1);
+        assert!(!bars[0].closed);
+
+        ohlcv.update(&trade(SEC(70), 120, 2));
+        ohlcv.update(&trade(SEC(80), 90, 3));
+        let bars = ohlcv.update(&trade(SEC(119), 110, 4));
+        assert_eq!(bars.len(), 1);
+
+        // 古い約定は無視
+        assert!(ohlcv.update(&trade(SEC(59), 1, 1)).is_empty());
+
+        let bars = ohlcv.update(&trade(SEC(120), 105, 1));
+        assert_eq!(bars.len(), 2);
+
+        assert_eq!(
+            bars[0],
+            OhlcvBar {
+                timestamp: SEC(60),
+                open: Decimal::from(100),
+                high: Decimal::from(120),
+                low: Decimal::from(90),
+                close: Decimal::from(110),
+                volume: Decimal::from(10),
+                count: 4,
+                closed: true,
+            }
+        );
+
+        assert_eq!(bars[1].timestamp, SEC(120));
+        assert_eq!(bars[1].open, Decimal::from(105));
+        assert!(!bars[1].closed);
+        assert_eq!(ohlcv.current(), Some(bars[1].clone()));
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use anyhow::anyhow;
+use pyo3::{pyclass, pymethods};
+use rbot_lib::common::MicroSec;
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal,
+};
+
+/// 損益を記録する間隔のデフォルト
+pub const PNL_SAMPLE_INTERVAL_SEC: i64 = 60;
+/// 記録する損益の上限。超えたら間引いて記録間隔を倍にする。
+pub const MAX_PNL_SAMPLES: usize = 100_000;
+
+/// 時価評価した損益のサンプル
+#[derive(Debug, Clone, PartialEq)]
+pub struct PnlSample {
+    pub time: MicroSec,
+    /// マーケットの価格(仲値)
+    pub price: Decimal,
+    /// 確定損益(手数料控除後)+含み損益
+    pub equity: Decimal,
+}
+
+/// 1つおきに間引く(先頭と間隔は保つ)
+pub fn downsample_pnl_samples(samples: &mut Vec<PnlSample>) {
+    let mut index = 0;
+    samples.retain(|_| {
+        let keep = index % 2 == 0;
+        index += 1;
+        keep
+    });
+}
+
+/// 損益をマーケットの値動きによる部分(beta)とそれ以外(alpha)に分けた結果
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributionReport {
+    #[pyo3(get)]
+    pub total_pnl: Decimal,
+    #[pyo3(get)]
+    pub beta_pnl: Decimal,
+    #[pyo3(get)]
+    pub alpha_pnl: Decimal,
+    #[pyo3(get)]
+    pub beta: f64,
+    #[pyo3(get)]
+    pub r_squared: f64,
+}
+
+#[pymethods]
+impl AttributionReport {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "AttributionReport(total_pnl={}, beta_pnl={}, alpha_pnl={}, beta={:.4}, r_squared={:.4})",
+            self.total_pnl,
+            self.beta_pnl.round_dp(8),
+            self.alpha_pnl.round_dp(8),
+            self.beta,
+            self.r_squared
+        )
+    }
+}
+
+impl AttributionReport {
+    /// サンプル間の損益の変化をマーケットのリターンで回帰し、beta_pnl = beta * Σリターン、
+    /// alpha_pnl = total_pnl - beta_pnl とする。
+    pub fn calc(samples: &[PnlSample]) -> anyhow::Result<Self> {
+        if samples.len() < 3 {
+            return Err(anyhow!(
+                "not enough pnl samples for attribution: {}",
+                samples.len()
+            ));
+        }
+
+        let mut returns: Vec<f64> = vec![];
+        let mut pnl: Vec<f64> = vec![];
+
+        for w in samples.windows(2) {
+            if w[0].price <= Decimal::ZERO {
+                continue;
+            }
+
+            returns.push(((w[1].price - w[0].price) / w[0].price).to_f64().unwrap());
+            pnl.push((w[1].equity - w[0].equity).to_f64().unwrap());
+        }
+
+        let n = returns.len() as f64;
+        if n < 2.0 {
+            return Err(anyhow!("not enough price data for attribution"));
+        }
+
+        let mean_x = returns.iter().sum::<f64>() / n;
+        let mean_y = pnl.iter().sum::<f64>() / n;
+
+        let mut cov = 0.0;
+        let mut var_x = 0.0;
+        let mut var_y = 0.0;
+        for (x, y) in returns.iter().zip(pnl.iter()) {
+            cov += (x - mean_x) * (y - mean_y);
+            var_x += (x - mean_x) * (x - mean_x);
+            var_y += (y - mean_y) * (y - mean_y);
+        }
+
+        // 価格が動いていなければ全てalpha
+        let beta = if var_x == 0.0 { 0.0 } else { cov / var_x };
+        let r_squared = if var_x == 0.0 || var_y == 0.0 {
+            0.0
+        } else {
+            cov * cov / (var_x * var_y)
+        };
+
+        let total_pnl = samples.last().unwrap().equity - samples.first().unwrap().equity;
+        let beta_pnl = Decimal::from_f64(beta * returns.iter().sum::<f64>()).unwrap_or_default();
+
+        Ok(Self {
+            total_pnl,
+            beta_pnl,
+            alpha_pnl: total_pnl - beta_pnl,
+            beta,
+            r_squared,
+        })
+    }
+}
+
+#[cfg(test)]
+mod attribution_test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn samples(prices: &[Decimal], equity: &[Decimal]) -> Vec<PnlSample> {
+        prices
+            .iter()
+            .zip(equity.iter())
+            .enumerate()
+            .map(|(i, (p, e))| PnlSample {
+                time: i as MicroSec,
+                price: *p,
+                equity: *e,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_attribution_calc() -> anyhow::Result<()> {
+        let prices = [dec![100], dec![101], dec![100], dec![102], dec![103]];
+
+        // 値動きと無関係に増える損益は全てalpha
+        let flat = samples(&prices, &[dec![0], dec![1], dec![2], dec![3], dec![4]]);
+        let report = AttributionReport::calc(&flat)?;
+        assert_eq!(report.total_pnl, dec![4]);
+        assert_eq!(report.beta, 0.0);
+        assert_eq!(report.alpha_pnl, dec![4]);
+
+        // ノーポジションなら損益は0
+        let zero = samples(&prices, &[dec![0]; 5]);
+        let report = AttributionReport::calc(&zero)?;
+        assert_eq!(report.total_pnl, dec![0]);
+        assert_eq!(report.beta, 0.0);
+        assert_eq!(report.r_squared, 0.0);
+
+        assert!(AttributionReport::calc(&flat[..2]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_downsample_pnl_samples() {
+        let prices: Vec<Decimal> = (0..5).map(Decimal::from).collect();
+        let mut s = samples(&prices, &prices);
+
+        downsample_pnl_samples(&mut s);
+        assert_eq!(s.iter().map(|s| s.time).collect::<Vec<_>>(), vec![0, 2, 4]);
+    }
+}
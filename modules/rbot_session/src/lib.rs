@@ -3,6 +3,10 @@ mod session;
 mod runner;
 mod python_if;
 mod logger;
+mod sizer;
+mod multimarket;
+mod store;
+mod attribution;
 
 #[cfg(test)]
 mod mod_test;
@@ -12,4 +16,8 @@ pub use session::*;
 pub use runner::*;
 pub use python_if::*;
 pub use logger::*;
+pub use sizer::*;
+pub use multimarket::*;
+pub use store::*;
+pub use attribution::*;
 
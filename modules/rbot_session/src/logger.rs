@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     fs::{File, OpenOptions},
     io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
 };
 
 use polars::{datatypes::TimeUnit, export::num::ToPrimitive, frame::DataFrame, lazy::{dsl::col, frame::IntoLazy}, prelude::NamedFrom, series::Series};
@@ -9,7 +10,9 @@ use pyo3::{pyclass, pymethods, PyResult};
 use pyo3_polars::PyDataFrame;
 use serde_derive::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
-use rbot_lib::common::{ordervec_to_dataframe, AccountPair, MicroSec, Order};
+use rbot_lib::common::{ordervec_to_dataframe, time_string, AccountPair, MicroSec, Order};
+use anyhow::Context;
+use rusqlite::{params, Connection};
 
 #[skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
@@ -71,6 +74,16 @@ pub struct Profit {
     pub total_profit: f64,
 }
 
+/// ストラテジーが記録する判断理由(metadataは任意のJSONなど)
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Decision {
+    #[serde(rename = "m")]
+    pub message: String,
+    #[serde(rename = "M")]
+    pub metadata: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum LogMessage {
     #[serde(rename = "O")]
@@ -82,7 +95,9 @@ pub enum LogMessage {
     #[serde(rename = "I")]
     SystemIndicator(Indicator),
     #[serde(rename = "P")]
-    Profit(Profit)
+    Profit(Profit),
+    #[serde(rename = "D")]
+    Decision(Decision),
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
@@ -206,6 +221,37 @@ pub fn account_logrec_to_df(accounts: Vec<SingleLogRecord>) -> DataFrame {
 }
 
 
+pub fn decision_logrec_to_df(decisions: Vec<SingleLogRecord>) -> DataFrame {
+    let mut timestamp = Vec::<MicroSec>::new();
+    let mut message = Vec::<String>::new();
+    let mut metadata = Vec::<Option<String>>::new();
+
+    for rec in decisions {
+        match rec.data {
+            LogMessage::Decision(decision) => {
+                timestamp.push(rec.timestamp);
+                message.push(decision.message);
+                metadata.push(decision.metadata);
+            }
+            _ => {
+                log::warn!("skip non decision record: {:?}", rec.data);
+            }
+        }
+    }
+
+    let timestamp = Series::new("timestamp", timestamp);
+    let message = Series::new("message", message);
+    let metadata = Series::new("metadata", metadata);
+
+    let mut df = DataFrame::new(vec![timestamp, message, metadata]).unwrap();
+
+    let time = df.column("timestamp").unwrap().i64().unwrap().clone();
+    let date_time = time.into_datetime(TimeUnit::Microseconds, None);
+    let df = df.with_column(date_time).unwrap();
+
+    return df.clone();
+}
+
 impl Into<LogRecord> for Vec<SingleLogRecord> {
     fn into(self) -> LogRecord {
         let mut result = LogRecord::new(0);
@@ -232,6 +278,9 @@ pub struct Logger {
     user_indicator: HashMap<String, Vec<TimeIndicator>>,
     system_indicator: HashMap<String, Vec<TimeIndicator>>,
     account: Vec<SingleLogRecord>,
+    decision: Vec<SingleLogRecord>,
+    decision_db: Option<Connection>,
+    decision_db_path: Option<PathBuf>,
     log_file: Option<File>,
     log_buffer: Option<LogRecord>,
 }
@@ -251,6 +300,9 @@ impl Logger {
             user_indicator: HashMap::new(),
             system_indicator: HashMap::new(),
             account: vec![],
+            decision: vec![],
+            decision_db: None,
+            decision_db_path: None,
             log_file: None,
             log_buffer: None,
         }
@@ -264,6 +316,7 @@ impl Logger {
         self.user_indicator.clear();
         self.system_indicator.clear();
         self.account.clear();
+        self.decision.clear();
     }
 
     pub fn open_log(&mut self, path: &str) -> Result<(), std::io::Error> {
@@ -315,6 +368,8 @@ impl Logger {
         // save account status
         self.save_log_records(&self.account.clone())?;
 
+        self.save_log_records(&self.decision.clone())?;
+
         self.flush_buffer()?;
 
         Ok(())
@@ -371,6 +426,103 @@ impl Logger {
         self.log_system_indicator(timestamp, "position", position_change, Some(position), Some(order_id), Some(transaction_id), Some(log_id))             
     }
 
+    /// ストラテジーの判断(例 "RSI signal")と付随情報(JSON文字列など)を記録する
+    /// open_decision_db済みであればdecisionsテーブルにも書き込む。
+    #[pyo3(signature = (timestamp, message, metadata=None))]
+    pub fn log_decision(
+        &mut self,
+        timestamp: MicroSec,
+        message: &str,
+        metadata: Option<&str>,
+    ) -> anyhow::Result<()> {
+        if let Some(db) = &self.decision_db {
+            db.execute(
+                "insert into decisions (time_stamp, message, metadata) values (?1, ?2, ?3)",
+                params![timestamp, message, metadata],
+            )?;
+        }
+
+        let decision = Decision {
+            message: message.to_string(),
+            metadata: metadata.map(|m| m.to_string()),
+        };
+
+        self.log_message(timestamp, &LogMessage::Decision(decision))?;
+
+        Ok(())
+    }
+
+    /// start <= timestamp < endの判断記録(0は制限なし)
+    /// decisionsテーブルがあればそこから読み、なければメモリ上の記録を返す。
+    #[pyo3(signature = (start=0, end=0))]
+    pub fn get_decisions(&self, start: MicroSec, end: MicroSec) -> anyhow::Result<PyDataFrame> {
+        let decisions = match &self.decision_db {
+            Some(db) => Self::select_decisions(db, start, end)?,
+            None => self
+                .decision
+                .iter()
+                .filter(|d| start <= d.timestamp && (end == 0 || d.timestamp < end))
+                .cloned()
+                .collect(),
+        };
+
+        Ok(PyDataFrame(decision_logrec_to_df(decisions)))
+    }
+
+    /// 約定・注文と判断記録を時刻順に並べた表
+    pub fn _repr_html_(&self) -> String {
+        let mut rows: Vec<(MicroSec, String, String)> = vec![];
+
+        for rec in self.order.iter() {
+            if let LogMessage::Order(order) = &rec.data {
+                rows.push((
+                    rec.timestamp,
+                    "order".to_string(),
+                    format!(
+                        "{:?} {:?} {} @ {} ({})",
+                        order.side, order.status, order.order_size, order.order_price, order.order_id
+                    ),
+                ));
+            }
+        }
+
+        let decisions = match &self.decision_db {
+            Some(db) => Self::select_decisions(db, 0, 0).unwrap_or_else(|e| {
+                log::warn!("select decisions error: {:?}", e);
+                self.decision.clone()
+            }),
+            None => self.decision.clone(),
+        };
+
+        for rec in decisions {
+            if let LogMessage::Decision(decision) = rec.data {
+                rows.push((
+                    rec.timestamp,
+                    "decision".to_string(),
+                    match decision.metadata {
+                        Some(metadata) => format!("{} {}", decision.message, metadata),
+                        None => decision.message,
+                    },
+                ));
+            }
+        }
+
+        rows.sort_by_key(|(timestamp, _, _)| *timestamp);
+
+        let mut html = "<table><caption>Timeline</caption><tr><th>time</th><th>kind</th><th>detail</th></tr>".to_string();
+        for (timestamp, kind, detail) in rows {
+            html += &format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                time_string(timestamp),
+                kind,
+                html_escape(&detail)
+            );
+        }
+        html += "</table>";
+
+        html
+    }
+
     #[getter]
     pub fn get_orders(&self) -> PyResult<PyDataFrame> {
         let orders = self
@@ -639,11 +791,70 @@ impl Logger {
             user_indicator: self.user_indicator.clone(),
             system_indicator: self.system_indicator.clone(),
             account: self.account.clone(),
+            decision: self.decision.clone(),
+            decision_db: self.decision_db_path.as_ref().and_then(|path| {
+                Connection::open(path)
+                    .map_err(|e| log::warn!("decision db reopen error {:?}: {:?}", path, e))
+                    .ok()
+            }),
+            decision_db_path: self.decision_db_path.clone(),
             log_file: None,
             log_buffer: None,
         }
     }
 
+    /// 判断記録をpathのSQLiteファイル(TradeTableと同じファイル)のdecisionsテーブルにも保存する
+    pub fn open_decision_db(&mut self, path: &Path) -> anyhow::Result<()> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("decision db open error {:?}", path))?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS decisions (
+            time_stamp  INTEGER,
+            message     TEXT,
+            metadata    TEXT
+        )",
+            (),
+        )?;
+
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS decisions_time_stamp ON decisions(time_stamp)",
+            (),
+        )?;
+
+        self.decision_db = Some(connection);
+        self.decision_db_path = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    fn select_decisions(
+        db: &Connection,
+        start: MicroSec,
+        end: MicroSec,
+    ) -> anyhow::Result<Vec<SingleLogRecord>> {
+        let end = if end == 0 { MicroSec::MAX } else { end };
+
+        let mut statement = db.prepare(
+            "select time_stamp, message, metadata from decisions
+            where ?1 <= time_stamp and time_stamp < ?2 order by time_stamp",
+        )?;
+
+        let records = statement
+            .query_map(params![start, end], |row| {
+                Ok(SingleLogRecord {
+                    timestamp: row.get(0)?,
+                    data: LogMessage::Decision(Decision {
+                        message: row.get(1)?,
+                        metadata: row.get(2)?,
+                    }),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(records)
+    }
+
     pub fn log_message(
         &mut self,
         timestamp: MicroSec,
@@ -718,6 +929,9 @@ impl Logger {
             }
             LogMessage::Profit(_) => {
             }
+            LogMessage::Decision(_) => {
+                self.decision.push(log_record);
+            }
             /*
               _ => {
                   log::error!("not supported message type");
@@ -790,6 +1004,10 @@ impl Logger {
     }
 }
 
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
 impl Drop for Logger {
     fn drop(&mut self) {
         let _ = self.close_log();
@@ -862,6 +1080,92 @@ mod logger_tests {
     }
 
 
+    #[test]
+    fn test_log_decision() -> anyhow::Result<()> {
+        let mut logger = Logger::new(true);
+
+        for i in 0..10 {
+            let metadata = format!(r#"{{"rsi": {}}}"#, 20 + i);
+            let metadata = if i % 2 == 0 { Some(metadata.as_str()) } else { None };
+            logger.log_decision(1_000 + i, &format!("signal-{}", i), metadata)?;
+        }
+
+        let df = logger.get_decisions(0, 0)?.0;
+        assert_eq!(df.shape().0, 10);
+        assert_eq!(df.column("message")?.str()?.get(3), Some("signal-3"));
+        assert_eq!(df.column("metadata")?.str()?.get(0), Some(r#"{"rsi": 20}"#));
+        assert_eq!(df.column("metadata")?.str()?.get(1), None);
+
+        let df = logger.get_decisions(1_002, 1_005)?.0;
+        assert_eq!(df.shape().0, 3);
+        assert_eq!(df.column("message")?.str()?.get(0), Some("signal-2"));
+
+        // ログファイルからも復元できる
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test_log_decision");
+        let path = path.to_str().unwrap();
+        logger.dump(path)?;
+        let mut logger2 = Logger::new(true);
+        logger2.restore(path.to_string())?;
+        assert_eq!(logger2.get_decisions(0, 0)?.0.shape().0, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_decision_db() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("linear-BTCUSDT.db");
+
+        let mut logger = Logger::new(false);
+        logger.open_decision_db(&path)?;
+
+        for i in 0..10 {
+            logger.log_decision(1_000 + i, &format!("signal-{}", i), Some(r#"{"rsi": 28.5}"#))?;
+        }
+
+        let df = logger.get_decisions(1_002, 1_005)?.0;
+        assert_eq!(df.shape().0, 3);
+        assert_eq!(df.column("message")?.str()?.get(0), Some("signal-2"));
+
+        // 別の接続から同じファイルのdecisionsテーブルを読める
+        let mut logger2 = Logger::new(false);
+        logger2.open_decision_db(&path)?;
+        let df = logger2.get_decisions(0, 0)?.0;
+        assert_eq!(df.shape().0, 10);
+        assert_eq!(df.column("metadata")?.str()?.get(9), Some(r#"{"rsi": 28.5}"#));
+
+        let html = logger2._repr_html_();
+        assert!(html.contains("signal-9"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decision_logrec_to_df_skip_other_records() {
+        let records = vec![
+            SingleLogRecord::new(
+                1,
+                &LogMessage::Decision(Decision {
+                    message: "buy".to_string(),
+                    metadata: None,
+                }),
+            ),
+            SingleLogRecord::new(2, &LogMessage::Profit(Profit {
+                log_id: 0,
+                open_position: 0.0,
+                close_position: 0.0,
+                position: 0.0,
+                profit: 0.0,
+                fee: 0.0,
+                total_profit: 0.0,
+            })),
+        ];
+
+        let df = decision_logrec_to_df(records);
+        assert_eq!(df.shape().0, 1);
+    }
+
     #[test]
     fn test_logger() {
         let mut logger = Logger::new(true);
@@ -0,0 +1,252 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use std::thread;
+
+use crossbeam_channel::{unbounded, Receiver};
+use pyo3::{pyclass, pymethods};
+use rbot_lib::common::{MarketConfig, MarketMessage, Order, OrderSide, OrderStatus};
+use rbot_lib::net::BroadcastMessage;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+/// 複数マーケットのstreamをexchange/category/symbol付きのBroadcastMessageに変換して一本にまとめる。
+/// すべての入力streamが閉じると出力も閉じる。
+pub fn merge_market_receivers(
+    receivers: Vec<(MarketConfig, Receiver<MarketMessage>)>,
+) -> Receiver<BroadcastMessage> {
+    let (sender, merged) = unbounded();
+
+    for (config, receiver) in receivers {
+        let sender = sender.clone();
+
+        thread::spawn(move || {
+            while let Ok(msg) = receiver.recv() {
+                let message = BroadcastMessage {
+                    exchange: config.exchange_name.clone(),
+                    category: config.trade_category.clone(),
+                    symbol: config.trade_symbol.clone(),
+                    msg,
+                };
+
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    merged
+}
+
+/// マーケットごとの仮想ポジションと損益
+#[pyclass]
+#[derive(Debug, Clone, Default)]
+pub struct MarketPosition {
+    #[pyo3(get)]
+    pub exchange: String,
+    #[pyo3(get)]
+    pub symbol: String,
+    #[pyo3(get)]
+    pub position: Decimal,
+    #[pyo3(get)]
+    pub average_price: Decimal,
+    #[pyo3(get)]
+    pub last_price: Decimal,
+    #[pyo3(get)]
+    pub profit: Decimal,
+    #[pyo3(get)]
+    pub total_profit: Decimal,
+    maker_fee: Decimal,
+    taker_fee: Decimal,
+}
+
+#[pymethods]
+impl MarketPosition {
+    /// last_priceで評価した未実現損益
+    #[getter]
+    pub fn unrealized_profit(&self) -> Decimal {
+        if self.last_price == dec![0.0] {
+            return dec![0.0];
+        }
+
+        (self.last_price - self.average_price) * self.position
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "{{\"exchange\": \"{}\", \"symbol\": \"{}\", \"position\": {}, \"average_price\": {}, \"profit\": {}, \"total_profit\": {}}}",
+            self.exchange, self.symbol, self.position, self.average_price, self.profit, self.total_profit
+        )
+    }
+}
+
+impl MarketPosition {
+    pub fn new(config: &MarketConfig) -> Self {
+        Self {
+            exchange: config.exchange_name.clone(),
+            symbol: config.trade_symbol.clone(),
+            maker_fee: config.maker_fee,
+            taker_fee: config.taker_fee,
+            ..Default::default()
+        }
+    }
+
+    /// 約定した注文でポジションを更新し、手数料控除後の損益を返す
+    pub fn update(&mut self, order: &Order) -> Decimal {
+        if order.status != OrderStatus::Filled && order.status != OrderStatus::PartiallyFilled {
+            return dec![0.0];
+        }
+
+        let size = match order.order_side {
+            OrderSide::Buy => order.execute_size,
+            OrderSide::Sell => -order.execute_size,
+            _ => {
+                log::error!("Unknown order side: {:?}", order.order_side);
+                return dec![0.0];
+            }
+        };
+
+        let price = order.execute_price;
+        let mut profit = dec![0.0];
+
+        if self.position == dec![0.0] || self.position.is_sign_positive() == size.is_sign_positive()
+        {
+            let total_cost = self.average_price * self.position + price * size;
+            self.position += size;
+            self.average_price = total_cost / self.position;
+        } else {
+            // 反対売買。ポジションを超えた分はドテンとして新規に建てる
+            let close_size = if size.abs() <= self.position.abs() {
+                -size
+            } else {
+                self.position
+            };
+
+            profit = (price - self.average_price) * close_size;
+            self.position -= close_size;
+
+            let open_size = size + close_size;
+            if open_size != dec![0.0] {
+                self.position = open_size;
+                self.average_price = price;
+            } else if self.position == dec![0.0] {
+                self.average_price = dec![0.0];
+            }
+        }
+
+        let fee = if order.is_maker {
+            price * order.execute_size * self.maker_fee
+        } else {
+            price * order.execute_size * self.taker_fee
+        };
+
+        let total_profit = profit - fee;
+
+        self.profit += profit;
+        self.total_profit += total_profit;
+
+        total_profit
+    }
+}
+
+#[cfg(test)]
+mod multimarket_test {
+    use super::*;
+    use rbot_lib::common::OrderType;
+
+    fn filled(side: OrderSide, price: Decimal, size: Decimal) -> Order {
+        let mut order = Order::default();
+        order.order_side = side;
+        order.order_type = OrderType::Market;
+        order.status = OrderStatus::Filled;
+        order.execute_price = price;
+        order.execute_size = size;
+
+        order
+    }
+
+    #[test]
+    fn test_market_position() {
+        let mut position = MarketPosition::default();
+
+        position.update(&filled(OrderSide::Buy, dec![100.0], dec![1.0]));
+        position.update(&filled(OrderSide::Buy, dec![200.0], dec![1.0]));
+        assert_eq!(position.position, dec![2.0]);
+        assert_eq!(position.average_price, dec![150.0]);
+
+        // 1だけ決済
+        let profit = position.update(&filled(OrderSide::Sell, dec![250.0], dec![1.0]));
+        assert_eq!(profit, dec![100.0]);
+        assert_eq!(position.position, dec![1.0]);
+
+        // ドテン
+        position.update(&filled(OrderSide::Sell, dec![50.0], dec![3.0]));
+        assert_eq!(position.position, dec![-2.0]);
+        assert_eq!(position.average_price, dec![50.0]);
+        assert_eq!(position.profit, dec![0.0]);
+
+        position.last_price = dec![40.0];
+        assert_eq!(position.unrealized_profit(), dec![20.0]);
+    }
+
+    #[test]
+    fn test_merge_market_receivers() -> anyhow::Result<()> {
+        let (s1, r1) = unbounded();
+        let (s2, r2) = unbounded();
+
+        let mut config1 = MarketConfig::default();
+        config1.exchange_name = "BYBIT".to_string();
+        config1.trade_symbol = "BTCUSDT".to_string();
+
+        let mut config2 = MarketConfig::default();
+        config2.exchange_name = "BINANCE".to_string();
+        config2.trade_symbol = "ETHUSDT".to_string();
+
+        let merged = merge_market_receivers(vec![(config1, r1), (config2, r2)]);
+
+        s1.send(MarketMessage::make_message("a"))?;
+        s2.send(MarketMessage::make_message("b"))?;
+        drop(s1);
+        drop(s2);
+
+        let mut messages: Vec<BroadcastMessage> = merged.iter().collect();
+        messages.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].filter("BYBIT", "", "BTCUSDT"));
+        assert!(messages[1].filter("BINANCE", "", "ETHUSDT"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_after_warm_up() -> anyhow::Result<()> {
+        let (sender, receiver) = unbounded();
+
+        for m in ["1", "2", "3"] {
+            sender.send(MarketMessage::make_message(m))?;
+        }
+
+        // warm upではreceiverを直接読み、その後に統合する
+        assert_eq!(receiver.recv()?, MarketMessage::make_message("1"));
+
+        let merged = merge_market_receivers(vec![(MarketConfig::default(), receiver.clone())]);
+
+        sender.send(MarketMessage::make_message("4"))?;
+        drop(sender);
+
+        // 残りのメッセージは欠落せず順序通りに届く
+        let messages: Vec<MarketMessage> = merged.iter().map(|m| m.msg).collect();
+        assert_eq!(
+            messages,
+            vec![
+                MarketMessage::make_message("2"),
+                MarketMessage::make_message("3"),
+                MarketMessage::make_message("4"),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -195,6 +195,9 @@ impl OrderList {
                 break;
             }
 
+            // 板に残っている注文が約定するのでMaker
+            self.list[0].is_maker = true;
+
             if remain_size < self.list[0].remain_size {
                 // consume all remain_size, order is not filled.
                 self.list[0].status = OrderStatus::PartiallyFilled;
@@ -1,6 +1,6 @@
 // Copyright(c) 2022-2024. yasstake. All rights reserved.
 
-use pyo3::{types::PyAnyMethods, Bound, PyAny};
+use pyo3::{pyclass, pymethods, types::PyAnyMethods, Bound, Py, PyAny, PyResult};
 
 /// Checks if a given Python object has a method with the specified name.
 pub fn has_method(agent: &Bound<PyAny>, method_name: &str) -> bool {
@@ -12,3 +12,40 @@ pub fn has_method(agent: &Bound<PyAny>, method_name: &str) -> bool {
         return false;
     }
 }
+
+/// on_tickだけを持つエージェント。Pythonの関数をそのままバックテストに渡すために使う。
+#[pyclass]
+pub struct TickCallbackAgent {
+    callback: Py<PyAny>,
+}
+
+impl TickCallbackAgent {
+    pub fn new(callback: &Bound<PyAny>) -> Self {
+        Self {
+            callback: callback.clone().unbind(),
+        }
+    }
+}
+
+#[pymethods]
+impl TickCallbackAgent {
+    /// regimeはRunner.regime_window_secを設定した場合のみ渡される
+    #[pyo3(signature = (session, side, price, size, regime=None))]
+    pub fn on_tick(
+        &self,
+        session: &Bound<PyAny>,
+        side: &Bound<PyAny>,
+        price: f64,
+        size: f64,
+        regime: Option<i8>,
+    ) -> PyResult<()> {
+        let py = session.py();
+
+        match regime {
+            Some(regime) => self.callback.call1(py, (session, side, price, size, regime))?,
+            None => self.callback.call1(py, (session, side, price, size))?,
+        };
+
+        Ok(())
+    }
+}
@@ -8,7 +8,9 @@ use pyo3::{
 };
 use rust_decimal::{prelude::ToPrimitive, Decimal};
 
-use super::{has_method, ExecuteMode, Session};
+use super::{has_method, merge_market_receivers, ExecuteMode, Logger, Session, TickCallbackAgent};
+
+use anyhow::anyhow;
 
 use rbot_lib::{
     common::{
@@ -16,9 +18,11 @@ use rbot_lib::{
          time_string, AccountCoins, MarketConfig, MarketMessage, MarketStream, MicroSec, Order, PyRunningBar, 
          Trade, FLOOR_SEC, MARKET_HUB, MICRO_SECOND, NOW, SEC
     },
-    net::{UdpReceiver, UdpSender},
+    db::{FundingRateTable, FundingSimulator},
+    net::{BroadcastMessage, UdpReceiver, UdpSender},
 };
 
+use rbot_market::{TradeDedup, TRADE_DEDUP_WINDOW};
 use rbot_server::start_board_server;
 
 #[pyclass]
@@ -28,6 +32,7 @@ pub struct Runner {
     has_on_clock: bool,
     has_on_tick: bool,
     has_on_update: bool,
+    has_on_pattern: bool,
 
     has_account_update: bool,
     #[pyo3(get)]
@@ -45,12 +50,40 @@ pub struct Runner {
     on_tick_count: i64,
     on_update_count: i64,
     on_account_update_count: i64,
+    on_pattern_count: i64,
     last_print_tick_time: MicroSec,
     last_print_loop_count: i64,
     last_print_real_time: MicroSec,
 
     backtest_start_time: MicroSec,
     backtest_end_time: MicroSec,
+    /// バックテスト時に先読みするキャッシュの幅(sec). 0の場合は先読みしない
+    #[pyo3(get, set)]
+    cache_prefetch_window: i64,
+    next_prefetch_time: MicroSec,
+
+    /// on_patternで判定するローソク足の幅(sec). 足が確定するごとに判定する
+    #[pyo3(get, set)]
+    pattern_window_sec: i64,
+    pattern_clock: MicroSec,
+
+    /// regime判定に使うローソク足の幅(sec). 0以外の場合はon_tickの5番目の引数にregimeを渡す
+    #[pyo3(get, set)]
+    regime_window_sec: i64,
+    /// regime判定(ADX)の期間(本数)
+    #[pyo3(get, set)]
+    regime_window: usize,
+    #[pyo3(get)]
+    current_regime: i8,
+    regime_clock: MicroSec,
+
+    /// バックテスト/Dry runでのfunding精算(enable_funding_simulationで有効にする)
+    funding: Option<FundingSimulator>,
+
+    /// Dry run/Realで直近何件の約定idで重複を除くか(0で無効)。
+    /// RESTの補完とWSで同じ約定が届いてもon_tickは1回だけ呼ぶ。
+    #[pyo3(get, set)]
+    trade_dedup_window: usize,
 
     execute_mode: ExecuteMode,
     agent_id: String,
@@ -70,6 +103,7 @@ impl Runner {
             has_on_tick: false,
             has_on_clock: false,
             has_on_update: false,
+            has_on_pattern: false,
             has_account_update: false,
             start_timestamp: 0,
             execute_time: -1, // -1 means infinite loop
@@ -82,6 +116,7 @@ impl Runner {
             on_tick_count: 0,
             on_update_count: 0,
             on_account_update_count: 0,
+            on_pattern_count: 0,
             verbose: false,
             last_print_tick_time: 0,
             last_print_loop_count: 0,
@@ -89,6 +124,20 @@ impl Runner {
 
             backtest_start_time: 0,
             backtest_end_time: 0,
+            cache_prefetch_window: 60 * 60 * 6,
+            next_prefetch_time: 0,
+
+            pattern_window_sec: 60,
+            pattern_clock: 0,
+
+            regime_window_sec: 0,
+            regime_window: 14,
+            current_regime: 0,
+            regime_clock: 0,
+
+            funding: None,
+
+            trade_dedup_window: TRADE_DEDUP_WINDOW,
 
             agent_id: "".to_string(),
             config: MarketConfig::default(),
@@ -103,6 +152,7 @@ impl Runner {
         self.on_tick_count = 0;
         self.on_update_count = 0;
         self.on_account_update_count = 0;
+        self.on_pattern_count = 0;
 
         self.start_timestamp = 0;
         self.last_timestamp = 0;
@@ -112,6 +162,25 @@ impl Runner {
         self.last_print_tick_time = 0;
         self.last_print_loop_count = 0;
         self.last_print_real_time = 0;
+
+        self.next_prefetch_time = 0;
+        self.pattern_clock = 0;
+        self.current_regime = 0;
+        self.regime_clock = 0;
+
+        if let Some(funding) = &mut self.funding {
+            funding.reset();
+        }
+    }
+
+    /// 8時間ごとの精算時刻にposition * mark_price(直近の約定価格) * funding rateを残高に反映する。
+    /// rate_tableにデータがない時刻はdefault_rate(0.01%)を使う。
+    pub fn enable_funding_simulation(&mut self, rate_table: FundingRateTable) {
+        self.funding = Some(FundingSimulator::new(rate_table));
+    }
+
+    pub fn disable_funding_simulation(&mut self) {
+        self.funding = None;
     }
 
     #[pyo3(signature = (*, exchange, market, agent, start_time=0, end_time=0, execute_time=0, verbose=false, log_memory=true, log_file=None))]
@@ -160,6 +229,43 @@ impl Runner {
         )
     }
 
+    /// DBに保存された[start_time, end_time)の約定をon_tick(session, side, price, size)に順に流す。
+    /// 注文はバックテストと同じく約定ストリームに対してダミー執行され、結果のLoggerを返す。
+    #[pyo3(signature = (*, exchange, market, on_tick, start_time=0, end_time=0, verbose=false))]
+    pub fn replay(
+        &mut self,
+        exchange: &Bound<PyAny>,
+        market: &Bound<PyAny>,
+        on_tick: &Bound<PyAny>,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        verbose: bool,
+    ) -> anyhow::Result<Logger> {
+        if !on_tick.is_callable() {
+            return Err(anyhow!("on_tick is not callable"));
+        }
+
+        let agent = Bound::new(on_tick.py(), TickCallbackAgent::new(on_tick))?;
+
+        self.reset_count();
+
+        let session = self.back_test(
+            exchange,
+            market,
+            agent.as_any(),
+            start_time,
+            end_time,
+            0,
+            verbose,
+            true,
+            None,
+        )?;
+
+        let log = Python::with_gil(|py| session.borrow(py).get_log());
+
+        Ok(log)
+    }
+
     #[pyo3(signature = (*, exchange, market, agent, log_memory=false, execute_time=0, verbose=false, log_file=None, client=false, no_download=false))]
     pub fn dry_run(
         &mut self,
@@ -375,17 +481,19 @@ impl Runner {
         self.has_on_clock = has_method(agent, "on_clock");
         self.has_on_tick = has_method(agent, "on_tick");
         self.has_on_update = has_method(agent, "on_update");
+        self.has_on_pattern = has_method(agent, "on_pattern");
         self.has_account_update = has_method(agent, "on_account_update");
 
         if (!self.has_on_init)
             && (!self.has_on_clock)
             && (!self.has_on_tick)
             && (!self.has_on_update)
+            && (!self.has_on_pattern)
             && (!self.has_account_update)
         {
-            log::error!("Agent has no method to call. Please implement at least one of on_init, on_clock, on_tick, on_update, on_account_update");
+            log::error!("Agent has no method to call. Please implement at least one of on_init, on_clock, on_tick, on_update, on_pattern, on_account_update");
             return Err(PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(
-                "Agent has no method to call. Please implement at least one of on_init, on_clock, on_tick, on_update, on_account_update",
+                "Agent has no method to call. Please implement at least one of on_init, on_clock, on_tick, on_update, on_pattern, on_account_update",
             ));
         }
 
@@ -406,6 +514,10 @@ impl Runner {
                 "has_on_update:      {}",
                 if self.has_on_update { "YES" } else { " no  " }
             );
+            println!(
+                "has_on_pattern:     {}",
+                if self.has_on_pattern { "YES" } else { " no  " }
+            );
             println!(
                 "has_account_update: {}",
                 if self.has_account_update {
@@ -514,6 +626,7 @@ impl Runner {
         F: FnMut(&Py<Session>, i64),
     {
         self.start_timestamp = 0;
+        self.next_prefetch_time = 0;
 
         let object = exchange.as_borrowed();
         let exchange_status = object.getattr("production").unwrap();
@@ -587,9 +700,22 @@ impl Runner {
         self.call_agent_on_init(&agent, &py_session)?;
         let interval_sec = self.get_clock_interval(&py_session)?;
 
+        // バックテストはDBから読むので重複はない
+        let mut dedup = TradeDedup::new(if self.execute_mode == ExecuteMode::BackTest {
+            0
+        } else {
+            self.trade_dedup_window
+        });
+
         // warm up loop
         let mut warm_up_step: i64 = 1;
         while let Ok(message) = receiver.recv() {
+            if let MarketMessage::Trade(trade) = &message {
+                if !dedup.check(trade) {
+                    continue;
+                }
+            }
+
             self.execute_message_update_session(&py_session, &message)?;
 
             log::debug!("warm up loop {:?}:{:?}", warm_up_step, message);
@@ -605,15 +731,46 @@ impl Runner {
             warm_up_step += 1;
         }
 
+        // on_initでadd_marketしたマーケットがあればprimaryと一本にまとめて受信する。
+        // 統合スレッドはprimaryのreceiverも読むので、warm upで直接読み終えてから購読する。
+        let merged = self.subscribe_additional_markets(&py_session, receiver, client_mode)?;
+
         // main loop
         let mut remain_time: i64 = 0;
         let loop_start_time = NOW();
 
-        while let Ok(message) = receiver.recv() {
+        loop {
+            let message = match &merged {
+                None => match receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+                Some(merged) => match merged.recv() {
+                    Ok(message) if message.filter(&self.exchange_name, &self.category, &self.symbol) => {
+                        message.msg
+                    }
+                    Ok(message) => {
+                        self.execute_broadcast_message(&py_session, &message);
+                        continue;
+                    }
+                    Err(_) => break,
+                },
+            };
+
+            if let MarketMessage::Trade(trade) = &message {
+                if !dedup.check(trade) {
+                    continue;
+                }
+            }
+
             //------- MAIN LOOP ---------
             self.execute_message(&py_session, agent, &message, interval_sec)?;
             self.loop_count += 1;
 
+            if self.execute_mode == ExecuteMode::BackTest {
+                self.prefetch_cache(market);
+            }
+
             //-------print status etc.
             // break if the running time exceeceds the loop_duration
             if self.start_timestamp == 0 {
@@ -687,6 +844,76 @@ impl Runner {
         Ok(py_session)
     }
 
+    /// session.add_marketで追加したマーケットのstreamを購読し、primaryのstreamと一本にまとめる。
+    /// 追加マーケットがない場合はNone。バックテストでは追加マーケットを再生しない。
+    /// 以後primaryのreceiverは統合スレッドが読むため、戻り値がSomeならreceiverを直接読まないこと。
+    fn subscribe_additional_markets(
+        &self,
+        py_session: &Py<Session>,
+        receiver: &Receiver<MarketMessage>,
+        client_mode: bool,
+    ) -> anyhow::Result<Option<Receiver<BroadcastMessage>>> {
+        let markets = Python::with_gil(|py| py_session.borrow(py).additional_markets(py));
+
+        if markets.is_empty() {
+            return Ok(None);
+        }
+
+        if self.execute_mode == ExecuteMode::BackTest {
+            log::warn!("additional markets are not replayed in backtest: {}", markets.len());
+            return Ok(None);
+        }
+
+        let mut receivers = vec![(self.config.clone(), receiver.clone())];
+
+        for (config, market) in markets {
+            let exchange = &config.exchange_name;
+            let category = &config.trade_category;
+            let symbol = &config.trade_symbol;
+
+            let r = if client_mode {
+                UdpReceiver::open_channel(exchange, category, symbol, &self.agent_id)?
+            } else {
+                Python::with_gil(|py| market.call_method0(py, "open_market_stream"))?;
+                MARKET_HUB.subscribe(exchange, category, symbol, &self.agent_id)?
+            };
+
+            log::info!("subscribe additional market {} {} {}", exchange, category, symbol);
+            receivers.push((config, r));
+        }
+
+        Ok(Some(merge_market_receivers(receivers)))
+    }
+
+    /// primary以外のマーケットのMessageをsessionに反映する
+    fn execute_broadcast_message(&self, py_session: &Py<Session>, message: &BroadcastMessage) {
+        Python::with_gil(|py| {
+            let mut session = py_session.borrow_mut(py);
+            session.on_broadcast_message(message);
+        });
+    }
+
+    /// 次のcache_prefetch_window秒分のデータを先読みする（窓の半分を過ぎたら次を読む）
+    fn prefetch_cache(&mut self, market: &Bound<PyAny>) {
+        if self.cache_prefetch_window <= 0 || self.last_timestamp < self.next_prefetch_time {
+            return;
+        }
+
+        if !has_method(market, "prefetch") {
+            return;
+        }
+
+        let window = SEC(self.cache_prefetch_window);
+        let start_time = self.last_timestamp;
+        let end_time = start_time + window;
+
+        if let Err(e) = market.call_method1("prefetch", (start_time, end_time)) {
+            log::warn!("prefetch error {:?}", e);
+        }
+
+        self.next_prefetch_time = start_time + window / 2;
+    }
+
     fn get_profit(&self, py_session: &Py<Session>) -> Decimal {
         let profit = Python::with_gil(|py| {
             let profit = py_session.getattr(py, "total_profit").unwrap();
@@ -751,6 +978,7 @@ impl Runner {
             println!("on_tick count: {}", self.on_tick_count);
             println!("on_clock count: {}", self.on_clock_count);
             println!("on_update count: {}", self.on_update_count);
+            println!("on_pattern count: {}", self.on_pattern_count);
             println!("on_account_update count: {}", self.on_account_update_count);
         }
     }
@@ -832,6 +1060,51 @@ impl Runner {
             }
         }
 
+        // パターン判定も足の確定時(Session更新前)に行う
+        if self.has_on_pattern && self.pattern_window_sec != 0 {
+            if let MarketMessage::Trade(trade) = message {
+                let new_clock = FLOOR_SEC(trade.time, self.pattern_window_sec);
+
+                if self.pattern_clock == 0 {
+                    self.pattern_clock = new_clock;
+                } else if self.pattern_clock < new_clock {
+                    self.pattern_clock = new_clock;
+
+                    self.call_agent_on_pattern(py, agent, py_session, trade)?;
+                }
+            }
+        }
+
+        // regimeも足の確定時に更新し、次の足の間のon_tickに渡す
+        if self.regime_window_sec != 0 {
+            if let MarketMessage::Trade(trade) = message {
+                let new_clock = FLOOR_SEC(trade.time, self.regime_window_sec);
+
+                if self.regime_clock == 0 {
+                    self.regime_clock = new_clock;
+                } else if self.regime_clock < new_clock {
+                    self.regime_clock = new_clock;
+
+                    let mut session = py_session.borrow_mut(*py);
+                    self.current_regime = session.last_bar_regime(self.regime_window_sec, self.regime_window)?;
+                }
+            }
+        }
+
+        // 精算時刻を過ぎたらSession更新前のポジションでfundingを精算する
+        if self.execute_mode == ExecuteMode::BackTest || self.execute_mode == ExecuteMode::Dry {
+            if let (Some(funding), MarketMessage::Trade(trade)) = (&mut self.funding, message) {
+                let due = funding.due(trade.time);
+
+                if !due.is_empty() {
+                    let mut session = py_session.borrow_mut(*py);
+                    for (_funding_time, rate) in due {
+                        session.apply_funding(rate, trade.price);
+                    }
+                }
+            }
+        }
+
         // on_clockの後にsessionを更新する。
         let mut session = py_session.borrow_mut(*py);
         let new_orders = session.on_message(&message);
@@ -850,6 +1123,14 @@ impl Runner {
                     self.call_agent_on_update(py, agent, py_session, order)?;
                 }
             }
+            MarketMessage::ReconciledOrder(_order) => {
+                // 照合で反映した差分だけを通知する
+                if self.has_on_update {
+                    for order in &new_orders {
+                        self.call_agent_on_update(py, agent, py_session, order)?;
+                    }
+                }
+            }
             MarketMessage::Account(account) => {
                 // IN Real run, account message is from user stream.
                 // AccountUpdateはFilledかPartiallyFilledのみ発生。
@@ -857,6 +1138,12 @@ impl Runner {
                     self.call_agent_on_account_update(py, agent, py_session, &account)?;
                 }
             }
+            MarketMessage::PositionUpdate(_position) => {
+                // session.current_positionに反映済み
+            }
+            MarketMessage::Fill(_fill) => {
+                // session.fillsに反映済み
+            }
             _ => {
                 log::warn!("Invalid message type: {:?}", message);
             }
@@ -941,7 +1228,11 @@ impl Runner {
         let price = trade.price.to_f64().unwrap();
         let size = trade.size.to_f64().unwrap();
 
-        agent.call_method1("on_tick", (session, trade.order_side, price, size))?;
+        if self.regime_window_sec != 0 {
+            agent.call_method1("on_tick", (session, trade.order_side, price, size, self.current_regime))?;
+        } else {
+            agent.call_method1("on_tick", (session, trade.order_side, price, size))?;
+        }
         self.on_tick_count += 1;
         Ok(())
     }
@@ -979,6 +1270,27 @@ impl Runner {
         Ok(())
     }
 
+    fn call_agent_on_pattern(
+        self: &mut Self,
+        py: &Python,
+        agent: &Bound<PyAny>,
+        py_session: &Py<Session>,
+        trade: &Trade,
+    ) -> anyhow::Result<()> {
+        let patterns = {
+            let mut session = py_session.borrow_mut(*py);
+            session.last_bar_patterns(self.pattern_window_sec)?
+        };
+
+        for pattern in patterns {
+            let session = py_session.borrow_mut(*py);
+            agent.call_method1("on_pattern", (session, pattern, trade.clone()))?;
+            self.on_pattern_count += 1;
+        }
+
+        Ok(())
+    }
+
     fn call_agent_on_account_update(
         self: &mut Self,
         py: &Python,
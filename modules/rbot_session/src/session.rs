@@ -1,9 +1,11 @@
 // Copyright(c) 2022-2024. yasstake. All rights reserved.
 
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Mutex;
-use std::{collections::VecDeque, sync::Arc};
+use std::{collections::HashMap, collections::HashSet, collections::VecDeque, sync::Arc};
 
 use pyo3::{pyclass, pymethods, PyAny, Python};
+use pyo3::types::IntoPyDict;
 
 use pyo3_polars::PyDataFrame;
 use rbot_lib::common::{short_time_string, write_agent_messsage, get_agent_message, FLOOR_SEC};
@@ -11,18 +13,54 @@ use rbot_server::get_rest_orderbook;
 use rust_decimal::{prelude::ToPrimitive, Decimal};
 use rust_decimal_macros::dec;
 
-use super::{Logger, OrderList};
+use super::{
+    downsample_pnl_samples, AttributionReport, Logger, MarketPosition, OrderList, PnlSample, StrategyStore, MAX_PNL_SAMPLES,
+    PNL_SAMPLE_INTERVAL_SEC,
+};
 use pyo3::prelude::*;
 use rbot_lib::{
+    net::BroadcastMessage,
     common::{
         date_string, get_orderbook, hour_string, min_string, time_string, AccountCoins,
-        AccountPair, MarketConfig, MarketMessage, MicroSec, Order, OrderBookList, OrderSide,
-        OrderStatus, OrderType, Trade, NOW, SEC
+        AccountPair, Fill, fillvec_to_dataframe, LogStatus, MarketConfig, MarketMessage, MicroSec, Order,
+        OrderBookList, OrderSide, OrderStatus, OrderType, PositionInfo, TimeInForce, Trade, MARKET_HUB,
+        NOW, SEC, detect_patterns, last_patterns, PATTERN_LOOKBACK_BARS, detect_regime, REGIME
     },
-    db::TradeDataFrame,
+    db::{db_full_path, db_path_root, funding_payment, HeatMap, TradeDataFrame},
 };
 
 use anyhow::anyhow;
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// 発注直後で取引所のオープンオーダーに載っていない可能性がある時間(秒)
+const RECONCILE_GRACE_SEC: i64 = 10;
+
+/// client_order_idの最大長(Bybitのorder_link_id, Binanceのclient order idは36文字まで)
+const MAX_ORDER_ID_LEN: usize = 36;
+
+/// client_order_idのprefixを検証する。英数字とハイフンのみ。
+/// 発行するid(`{prefix}-{local_id}`)が36文字に収まるよう、prefixは36 - 1 - local_id_len文字以内。
+fn validate_order_id_prefix(prefix: &str, local_id_len: usize) -> anyhow::Result<()> {
+    let max_len = MAX_ORDER_ID_LEN.saturating_sub(local_id_len + 1);
+
+    if prefix.len() > max_len {
+        return Err(anyhow!(
+            "order id prefix is too long ({} > {}, order id is limited to {} chars): {}",
+            prefix.len(),
+            max_len,
+            MAX_ORDER_ID_LEN,
+            prefix
+        ));
+    }
+
+    if let Some(c) = prefix.chars().find(|c| !(c.is_ascii_alphanumeric() || *c == '-')) {
+        return Err(anyhow!("invalid character {:?} in order id prefix: {}", c, prefix));
+    }
+
+    Ok(())
+}
 
 #[derive(Debug, Clone, PartialEq)]
 #[pyclass]
@@ -56,6 +94,58 @@ impl ExecuteMode {
     }
 }
 
+/// Session復旧用のスナップショット（save_snapshot/restore_snapshot）
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SessionSnapshot {
+    pub session_name: String,
+    pub timestamp: MicroSec,
+    pub buy_orders: Vec<Order>,
+    pub sell_orders: Vec<Order>,
+    pub psudo_position: Decimal,
+    pub average_price: Decimal,
+    pub current_position: Vec<PositionInfo>,
+    pub real_account: AccountCoins,
+    pub psudo_account: AccountCoins,
+}
+
+impl SessionSnapshot {
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("snapshot write error {:?}", path))?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("snapshot read error {:?}", path))?;
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// スナップショットの注文を取引所のオープンオーダーと突き合わせる。
+    /// 取引所に存在する注文は取引所側の内容で返し、存在しない注文はCanceledとして返す。
+    /// returns (alive orders, canceled orders)
+    pub fn reconcile_orders(orders: &Vec<Order>, open_orders: &Vec<Order>) -> (Vec<Order>, Vec<Order>) {
+        let mut alive: Vec<Order> = vec![];
+        let mut canceled: Vec<Order> = vec![];
+
+        for order in orders {
+            match open_orders.iter().find(|o| o.order_id == order.order_id) {
+                Some(open_order) => alive.push(open_order.clone()),
+                None => {
+                    let mut order = order.clone();
+                    order.status = OrderStatus::Canceled;
+                    canceled.push(order);
+                }
+            }
+        }
+
+        (alive, canceled)
+    }
+}
+
 #[pyclass(name = "Session")]
 #[derive(Debug)]
 pub struct Session {
@@ -68,9 +158,14 @@ pub struct Session {
     sell_orders: OrderList,
     real_account: AccountCoins,
     psudo_account: AccountCoins,
+    /// (symbol, position_idx) -> 取引所のポジション。ヘッジモードでは同じsymbolに買い(1)と売り(2)がある。
+    current_position: HashMap<(String, i64), PositionInfo>,
+    fills: Vec<Fill>,
     exchange: Py<PyAny>,
     current_timestamp: MicroSec,
     current_clock_time: MicroSec,
+    /// 処理中の約定が4本足から合成したもの(LogStatus::KlineFill)なら手数料を計算しない
+    kline_fill_tick: bool,
     pub session_name: String,
     order_number: i64,
     transaction_number: i64,
@@ -81,6 +176,8 @@ pub struct Session {
     pub profit: Decimal,
     #[pyo3(get)]
     pub total_profit: Decimal,
+    /// バックテストで精算したfundingの累計(正は支払い)
+    funding_paid: Decimal,
 
     commission_home_sum: Decimal,
     commission_foreign_sum: Decimal,
@@ -111,6 +208,58 @@ pub struct Session {
     limit_sell_count: i64,
 
     log: Logger,
+
+    /// primary以外に登録したマーケット(symbol -> market)
+    markets: HashMap<MarketKey, SessionMarket>,
+
+    store: Option<Py<StrategyStore>>,
+
+    /// 手数料の換算に使う通貨ごとのquote建て価格(BNBなど)
+    asset_prices: HashMap<String, Decimal>,
+
+    /// client_order_idごとの有効期限(秒)
+    order_ttl: HashMap<String, i64>,
+    /// set_order_ttlしていない注文の有効期限(秒)。0は無期限。
+    default_order_ttl_sec: i64,
+    /// 期限切れでキャンセルを出した注文(order_id)。約定/キャンセルの通知待ち。
+    ttl_canceled: HashSet<String>,
+
+    /// 定期照合(start_reconciliation_loop)の実行中フラグ
+    reconciliation_running: Arc<AtomicBool>,
+    /// 定期照合でオープンオーダーの取得に失敗した回数
+    reconciliation_errors: Arc<AtomicU64>,
+
+    /// 複数の戦略で口座を共有するときにclient_order_idの先頭に付ける文字列
+    order_id_prefix: String,
+
+    /// このセッションのオープンオーダー(order_id -> Order)。WSの注文通知で更新する。
+    /// MARKET_HUBはis_my_orderで絞り込んで配信するため、他のセッションの注文は含まない。
+    open_orders: HashMap<String, Order>,
+
+    /// pnl_sample_intervalごとに時価評価した損益(performance_attributionで使う)
+    pnl_samples: Vec<PnlSample>,
+    /// 損益を記録する間隔。記録数がMAX_PNL_SAMPLESに達すると倍になる。
+    pnl_sample_interval: MicroSec,
+    last_pnl_sample_time: MicroSec,
+}
+
+/// Sessionに追加登録したマーケット
+#[derive(Debug)]
+/// (exchange, category, symbol)
+type MarketKey = (String, String, String);
+
+fn market_key(config: &MarketConfig) -> MarketKey {
+    (
+        config.exchange_name.clone(),
+        config.trade_category.clone(),
+        config.trade_symbol.clone(),
+    )
+}
+
+struct SessionMarket {
+    config: MarketConfig,
+    market: Py<PyAny>,
+    position: MarketPosition,
 }
 
 #[pymethods]
@@ -159,9 +308,12 @@ impl Session {
             sell_orders: OrderList::new(OrderSide::Sell),
             real_account: AccountCoins::default(),
             psudo_account: AccountCoins::default(),
+            current_position: HashMap::new(),
+            fills: vec![],
             exchange: exchange.extract().unwrap(),
             current_timestamp: 0,
             current_clock_time: 0,
+            kline_fill_tick: false,
             session_name,
             order_number: 0,
             transaction_number: 0,
@@ -170,6 +322,7 @@ impl Session {
             average_price: dec![0.0],
             profit: dec![0.0],
             total_profit: dec![0.0],
+            funding_paid: dec![0.0],
 
             commission_home_sum: dec![0.0],
             commission_foreign_sum: dec![0.0],
@@ -200,10 +353,45 @@ impl Session {
             client_mode: client_mode,
 
             log: Logger::new(log_memory),
+
+            markets: HashMap::new(),
+
+            store: None,
+
+            asset_prices: HashMap::new(),
+
+            order_ttl: HashMap::new(),
+            default_order_ttl_sec: 0,
+            ttl_canceled: HashSet::new(),
+
+            reconciliation_running: Arc::new(AtomicBool::new(false)),
+            reconciliation_errors: Arc::new(AtomicU64::new(0)),
+
+            order_id_prefix: "".to_string(),
+
+            open_orders: HashMap::new(),
+
+            pnl_samples: vec![],
+            pnl_sample_interval: SEC(PNL_SAMPLE_INTERVAL_SEC),
+            last_pnl_sample_time: 0,
         };
 
         session.load_order_list().unwrap();
 
+        // バックテスト以外はTradeTableと同じDBファイルに判断記録を残す
+        if session.execute_mode != ExecuteMode::BackTest {
+            let path = db_full_path(
+                &session.market_config.exchange_name,
+                &session.market_config.trade_category,
+                &session.market_config.trade_symbol,
+                production,
+            );
+
+            if let Err(e) = session.log.open_decision_db(&path) {
+                log::warn!("open decision db error: {:?}", e);
+            }
+        }
+
         return session;
     }
 
@@ -279,6 +467,53 @@ impl Session {
         Ok(PyDataFrame(vap))
     }
 
+    /// 価格×時間の約定量(time_bin, price_bin, buy_volume, sell_volume)。pivotしてseaborn.heatmapへ渡せる。
+    #[pyo3(signature = (start_time, end_time, time_bins=100, price_bins=50))]
+    pub fn heat_map(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        time_bins: usize,
+        price_bins: usize,
+    ) -> anyhow::Result<PyDataFrame> {
+        let heat_map = self.heat_map_view(start_time, end_time, time_bins, price_bins)?;
+
+        Ok(PyDataFrame(heat_map.to_dataframe()?))
+    }
+
+    /// heat_mapと同じ集計。Jupyterでは_repr_html_でそのまま表示される。
+    #[pyo3(signature = (start_time, end_time, time_bins=100, price_bins=50))]
+    pub fn heat_map_view(
+        &mut self,
+        start_time: MicroSec,
+        end_time: MicroSec,
+        time_bins: usize,
+        price_bins: usize,
+    ) -> anyhow::Result<HeatMap> {
+        let db = self.get_db(None)?;
+        let mut lock = db.lock().unwrap();
+
+        lock.heat_map(start_time, end_time, time_bins, price_bins)
+    }
+
+    /// 戦略の状態を保存するKey-Valueストア(マーケットのDBディレクトリのstrategy_state.db)
+    #[getter]
+    pub fn get_store(&mut self, py: Python) -> anyhow::Result<Py<StrategyStore>> {
+        if self.store.is_none() {
+            let path = db_path_root(
+                &self.market_config.exchange_name,
+                &self.market_config.trade_category,
+                &self.market_config.trade_symbol,
+                self.production,
+            )
+            .join("strategy_state.db");
+
+            self.store = Some(Py::new(py, StrategyStore::open(&path)?)?);
+        }
+
+        Ok(self.store.as_ref().unwrap().clone_ref(py))
+    }
+
     #[getter]
     pub fn get_timestamp(&self) -> MicroSec {
         self.current_timestamp
@@ -289,6 +524,22 @@ impl Session {
         self.current_clock_time = timestamp;
     }
 
+    #[getter]
+    pub fn get_pnl_sample_interval(&self) -> MicroSec {
+        self.pnl_sample_interval
+    }
+
+    #[setter]
+    pub fn set_pnl_sample_interval(&mut self, interval: MicroSec) -> anyhow::Result<()> {
+        if interval <= 0 {
+            return Err(anyhow!("pnl_sample_interval must be greater than 0: {}", interval));
+        }
+
+        self.pnl_sample_interval = interval;
+
+        Ok(())
+    }
+
     #[getter]
     pub fn get_board(&self) -> anyhow::Result<(PyDataFrame, PyDataFrame)> {
         self.select_board(None)
@@ -390,11 +641,64 @@ impl Session {
         )
     }
 
+    /// 注文リスト、ポジション、口座情報をJSONファイルへ保存する
+    pub fn save_snapshot(&self, path: PathBuf) -> anyhow::Result<()> {
+        self.snapshot().save(&path)
+    }
+
+    /// save_snapshotで保存した状態を復元する（Realモードでは取引所の注文と突き合わせる）
+    #[pyo3(name = "restore_snapshot")]
+    pub fn restore_from_snapshot(&mut self, path: PathBuf) -> anyhow::Result<()> {
+        let snapshot = SessionSnapshot::load(&path)?;
+        self.restore(snapshot)
+    }
+
     #[getter]
     pub fn get_position(&self) -> f64 {
         self.psudo_position.to_f64().unwrap()
     }
 
+    /// 現在のポジションをmark_priceで評価した想定元本(絶対値)。
+    /// linearはquote建て、inverseはbase建て。
+    pub fn position_notional(&self, mark_price: Decimal) -> Decimal {
+        self.market_config.notional(self.psudo_position, mark_price)
+    }
+
+    /// 現在のポジションに必要な証拠金の目安(想定元本 / leverage)。
+    /// mark_priceを省略した場合は最後に受信した板の中値で評価する。
+    #[pyo3(signature = (leverage, mark_price=None))]
+    pub fn estimated_margin(&self, leverage: Decimal, mark_price: Option<Decimal>) -> anyhow::Result<Decimal> {
+        if leverage <= dec![0.0] {
+            return Err(anyhow!("leverage must be positive: {}", leverage));
+        }
+
+        let mark_price = match mark_price {
+            Some(price) => price,
+            None => {
+                if self.bid_edge == dec![0.0] || self.ask_edge == dec![0.0] {
+                    return Err(anyhow!("no market price received yet; specify mark_price"));
+                }
+                (self.bid_edge + self.ask_edge) / dec![2.0]
+            }
+        };
+
+        Ok(self.position_notional(mark_price) / leverage)
+    }
+
+    #[getter]
+    pub fn get_current_position(&self) -> HashMap<(String, i64), PositionInfo> {
+        self.current_position.clone()
+    }
+
+    /// 約定明細(Realでは取引所から、BackTest/Dryではダミー約定から生成)
+    pub fn get_fills(&self) -> Vec<Fill> {
+        self.fills.clone()
+    }
+
+    pub fn fills_dataframe(&self) -> anyhow::Result<PyDataFrame> {
+        Ok(PyDataFrame(fillvec_to_dataframe(&self.fills)))
+    }
+
     #[getter]
     pub fn get_psudo_account(&self) -> AccountCoins {
         self.psudo_account.clone()
@@ -430,6 +734,22 @@ impl Session {
         }
     }
 
+    /// 現在時刻で判断理由をログに記録する(例 session.log_decision("RSI signal", json.dumps({"rsi": 28.5})))
+    #[pyo3(signature = (message, metadata=None))]
+    pub fn log_decision(&mut self, message: &str, metadata: Option<&str>) {
+        let timestamp = self.calc_log_timestamp();
+
+        let r = self.log.log_decision(timestamp, message, metadata);
+        if r.is_err() {
+            log::error!("log_decision error: {:?}", r);
+        }
+    }
+
+    /// 注文と判断記録のタイムライン(Jupyter表示用)
+    pub fn _repr_html_(&self) -> String {
+        self.log._repr_html_()
+    }
+
     pub fn expire_order(&mut self, ttl_sec: i64) -> bool {
         let mut has_expire = false;
 
@@ -456,6 +776,227 @@ impl Session {
         has_expire
     }
 
+    /// 指値注文が約定しないままttl_sec経過したら自動でキャンセルする。
+    /// バックテストは仮想時刻、リアルは実時刻で判定する。ttl_secが0の場合は解除。
+    pub fn set_order_ttl(&mut self, client_order_id: &str, ttl_sec: i64) {
+        if ttl_sec <= 0 {
+            self.order_ttl.remove(client_order_id);
+        } else {
+            self.order_ttl.insert(client_order_id.to_string(), ttl_sec);
+        }
+    }
+
+    /// 以後発行するclient_order_idの先頭にprefixを付ける。空文字で解除。
+    /// 英数字とハイフン以外の文字を含む場合、prefixを付けたidが36文字を超える場合はエラー。
+    pub fn set_order_id_prefix(&mut self, prefix: &str) -> anyhow::Result<()> {
+        validate_order_id_prefix(prefix, self.local_order_id(self.order_number + 1).len())?;
+        self.order_id_prefix = prefix.to_string();
+
+        Ok(())
+    }
+
+    #[getter]
+    pub fn get_order_id_prefix(&self) -> String {
+        self.order_id_prefix.clone()
+    }
+
+    /// このSessionのprefixが付いたclient_order_idを(prefix, local_id)に分ける。
+    /// prefix未設定または他の戦略の注文ならNone。
+    pub fn parse_client_order_id(&self, id: &str) -> Option<(String, String)> {
+        if self.order_id_prefix.is_empty() {
+            return None;
+        }
+
+        id.strip_prefix(&self.order_id_prefix)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .map(|local_id| (self.order_id_prefix.clone(), local_id.to_string()))
+    }
+
+    #[getter]
+    pub fn get_default_order_ttl_sec(&self) -> i64 {
+        self.default_order_ttl_sec
+    }
+
+    /// set_order_ttlしていない全ての注文に適用する有効期限(0は無期限)
+    #[setter]
+    pub fn set_default_order_ttl_sec(&mut self, ttl_sec: i64) {
+        self.default_order_ttl_sec = ttl_sec;
+    }
+
+    /// 有効期限を過ぎた注文をキャンセルし、キャンセルを出した注文を返す。
+    /// 通常はTradeを受信するたびに呼ばれる。
+    pub fn cancel_expired_orders(&mut self) -> Vec<Order> {
+        if self.order_ttl.is_empty() && self.default_order_ttl_sec == 0 {
+            return vec![];
+        }
+
+        let now = if self.execute_mode == ExecuteMode::Real {
+            NOW()
+        } else {
+            self.current_timestamp
+        };
+
+        let mut orders = self.buy_orders.get();
+        orders.extend(self.sell_orders.get());
+
+        // 約定/キャンセル済みで一覧から消えた注文は管理対象から外す
+        let open_ids: HashSet<String> = orders.iter().map(|o| o.order_id.clone()).collect();
+        self.ttl_canceled.retain(|id| open_ids.contains(id));
+
+        let expired = Self::select_expired_orders(
+            &orders,
+            &self.order_ttl,
+            self.default_order_ttl_sec,
+            &self.ttl_canceled,
+            now,
+        );
+
+        let mut canceled = vec![];
+
+        for order in expired {
+            let result = self.cancel_order(&order.order_id);
+            let is_none = match &result {
+                Ok(r) => Python::with_gil(|py| r.is_none(py)),
+                Err(_) => true,
+            };
+
+            // キャンセルに失敗した注文は次回も選ばれるよう管理対象に入れない。
+            // 期限と同時に約定した場合は約定通知で一覧から消えるので再試行されない。
+            if is_none {
+                log::warn!("cancel_expired_orders: cancel failed(retry next time): {:?}", order);
+            } else {
+                log::debug!("cancel_expired_orders: cancel order: {:?}", order);
+                self.ttl_canceled.insert(order.order_id.clone());
+                canceled.push(order);
+            }
+        }
+
+        canceled
+    }
+
+    /// 取引所のオープンオーダーと照合し、WebSocketで取りこぼした約定/キャンセルを反映する。
+    /// 反映した注文を返す(Realモード以外は何もしない)。
+    pub fn reconcile_orders(&mut self) -> anyhow::Result<Vec<Order>> {
+        if self.execute_mode != ExecuteMode::Real {
+            return Ok(vec![]);
+        }
+
+        let open_orders = self.fetch_open_orders()?;
+        let updated = Self::reconcile_open_orders(
+            &self.session_name,
+            &self.local_orders(),
+            &open_orders,
+            NOW(),
+            |order_id| self.fetch_order_status(order_id),
+        );
+
+        let mut applied = vec![];
+        for order in updated.iter() {
+            log::warn!("reconcile_orders: missed order update {:?}", order);
+            if let Some(order) = self.apply_reconciled_order(order) {
+                applied.push(order);
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// オープンオーダーのキャッシュ(WSの注文通知で更新)。RESTには問い合わせない。
+    /// WSの遅延・取りこぼしがあると取引所の状態とずれることがある(結果整合)。
+    /// ずれが気になる場合はsync_open_ordersで取引所の一覧に合わせ直す。
+    pub fn get_open_orders(&self) -> Vec<Order> {
+        let mut orders: Vec<Order> = self.open_orders.values().cloned().collect();
+        orders.sort_by(|a, b| a.create_time.cmp(&b.create_time));
+
+        orders
+    }
+
+    /// RESTでオープンオーダーを取得してキャッシュを置き換え、
+    /// このセッションの注文はreconcile_ordersと同様に取りこぼした更新を反映する。
+    /// Realモード以外はキャッシュをそのまま返す。
+    pub fn sync_open_orders(&mut self) -> anyhow::Result<Vec<Order>> {
+        if self.execute_mode != ExecuteMode::Real {
+            return Ok(self.get_open_orders());
+        }
+
+        let open_orders = self.fetch_open_orders()?;
+
+        let updated = Self::reconcile_open_orders(
+            &self.session_name,
+            &self.local_orders(),
+            &open_orders,
+            NOW(),
+            |order_id| self.fetch_order_status(order_id),
+        );
+        for order in updated.iter() {
+            log::warn!("sync_open_orders: missed order update {:?}", order);
+            self.apply_reconciled_order(order);
+        }
+
+        // WSで更新するキャッシュと揃えるため、他のセッションの注文は含めない
+        self.open_orders = open_orders
+            .into_iter()
+            .filter(|o| o.is_my_order(&self.session_name))
+            .map(|o| (o.order_id.clone(), o))
+            .collect();
+
+        Ok(self.get_open_orders())
+    }
+
+    /// interval_secごとにバックグラウンドでオープンオーダーを照合する。
+    /// 差分はMARKET_HUBにMarketMessage::ReconciledOrderとして流し、Runner経由でon_messageに反映される。
+    /// 照合中にWSで届いた約定と二重に反映しないよう、約定量の差し引きはon_messageの時点の状態で行う。
+    pub fn start_reconciliation_loop(slf: Py<Self>, py: Python, interval_sec: u64) -> anyhow::Result<()> {
+        if interval_sec == 0 {
+            return Err(anyhow!("interval_sec must be greater than 0"));
+        }
+
+        let (running, errors) = {
+            let session = slf.borrow(py);
+            (session.reconciliation_running.clone(), session.reconciliation_errors.clone())
+        };
+
+        if running.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!("reconciliation loop is already running"));
+        }
+
+        std::thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                std::thread::sleep(std::time::Duration::from_secs(interval_sec));
+
+                if !running.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                match Self::detect_missed_updates(&slf) {
+                    Ok(messages) => {
+                        for message in messages {
+                            if let Err(e) = MARKET_HUB.publish(message) {
+                                log::error!("reconciliation: publish error {:?}", e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        errors.fetch_add(1, Ordering::SeqCst);
+                        log::warn!("reconciliation: get_open_orders error {:?}", e);
+                    }
+                }
+            }
+            log::debug!("reconciliation loop stopped");
+        });
+
+        Ok(())
+    }
+
+    pub fn stop_reconciliation_loop(&self) {
+        self.reconciliation_running.store(false, Ordering::SeqCst);
+    }
+
+    #[getter]
+    pub fn get_reconciliation_errors(&self) -> u64 {
+        self.reconciliation_errors.load(Ordering::SeqCst)
+    }
+
     pub fn cancel_order(&mut self, order_id: &str) -> PyResult<Py<PyAny>> {
         if self.execute_mode == ExecuteMode::BackTest || self.execute_mode == ExecuteMode::Dry {
             self.dummy_cancel_order(order_id)
@@ -504,7 +1045,75 @@ impl Session {
         })
     }
     
-    pub fn market_order(&mut self, side: String, size: Decimal) -> Result<Vec<Order>, PyErr> {
+    /// 指値注文の価格・数量を変更する。変更はAmendedとして注文履歴に記録する。
+    /// sizeは約定済みを含めた注文数量。注文が見つからない場合はNone
+    #[pyo3(signature = (order_id, price, size=None))]
+    pub fn amend_order(
+        &mut self,
+        order_id: &str,
+        price: Decimal,
+        size: Option<Decimal>,
+    ) -> PyResult<Option<Order>> {
+        let current = if let Some(order) = self.buy_orders.get_item_by_id(order_id) {
+            order
+        } else if let Some(order) = self.sell_orders.get_item_by_id(order_id) {
+            order
+        } else {
+            log::error!("amend_order: order not found: {}", order_id);
+            return Ok(None);
+        };
+
+        let price = self.market_config.round_price(price)?;
+        let size = match size {
+            Some(size) => Some(self.market_config.round_size(size)?),
+            None => None,
+        };
+
+        if self.execute_mode == ExecuteMode::Real {
+            Python::with_gil(|py| {
+                self.exchange.call_method1(
+                    py,
+                    "amend_order",
+                    (self.market_config.clone(), order_id, price, size),
+                )
+            })?;
+        }
+
+        let mut amended =
+            Self::make_amended_order(&current, price, size, self.current_timestamp)?;
+        amended.update_balance(&self.market_config);
+
+        self.log_id += 1;
+        amended.log_id = self.log_id;
+        if self.log(&amended).is_err() {
+            log::error!("log order error{:?}", amended);
+        }
+
+        // リスト上は元の状態(New/PartiallyFilled)のまま価格・数量だけ変える
+        let mut listed = amended.clone();
+        listed.status = current.status;
+        if listed.order_side == OrderSide::Buy {
+            self.buy_orders.update(listed);
+        } else {
+            self.sell_orders.update(listed);
+        }
+
+        Ok(Some(amended))
+    }
+
+    #[pyo3(signature = (side, size, reduce_only=false))]
+    pub fn market_order(
+        &mut self,
+        side: String,
+        size: Decimal,
+        reduce_only: bool,
+    ) -> Result<Vec<Order>, PyErr> {
+        let size = if reduce_only && self.execute_mode != ExecuteMode::Real {
+            self.reducible_size(OrderSide::from(&side), size)
+        } else {
+            size
+        };
+
         let new_size = self.market_config.round_size(size);
         if new_size.is_err() {
             log::warn!("market order size trunc into zero {:?} -> {:?}", size, new_size);
@@ -522,22 +1131,32 @@ impl Session {
         }
 
         match self.execute_mode {
-            ExecuteMode::Real => self.real_market_order(side, size),
-            ExecuteMode::BackTest => self.dummy_market_order(side, size),
-            ExecuteMode::Dry => self.dry_market_order(side, size),
+            ExecuteMode::Real => self.real_market_order(side, size, reduce_only),
+            ExecuteMode::BackTest => self.dummy_market_order(side, size, reduce_only),
+            ExecuteMode::Dry => self.dry_market_order(side, size, reduce_only),
         }
     }
 
-    pub fn real_market_order(&mut self, side: String, size: Decimal) -> Result<Vec<Order>, PyErr> {
+    #[pyo3(signature = (side, size, reduce_only=false))]
+    pub fn real_market_order(
+        &mut self,
+        side: String,
+        size: Decimal,
+        reduce_only: bool,
+    ) -> Result<Vec<Order>, PyErr> {
         log::debug!("market_order: side={:}, size={}", &side, size);
 
-        let local_id = self.new_order_id();
+        let local_id = self.new_order_id()?;
 
         let r = Python::with_gil(|py| {
-            let result = self.exchange.call_method1(
+            let kwargs = vec![("reduce_only", reduce_only)];
+            let kwargs = kwargs.into_py_dict_bound(py);
+
+            let result = self.exchange.call_method_bound(
                 py,
                 "market_order",
                 (self.market_config.clone(), side, size, local_id),
+                Some(&kwargs),
             );
 
             match result {
@@ -580,9 +1199,15 @@ impl Session {
         return execute_price;
     }
 
-    pub fn dry_market_order(&mut self, side: String, size: Decimal) -> Result<Vec<Order>, PyErr> {
+    #[pyo3(signature = (side, size, reduce_only=false))]
+    pub fn dry_market_order(
+        &mut self,
+        side: String,
+        size: Decimal,
+        reduce_only: bool,
+    ) -> Result<Vec<Order>, PyErr> {
 
-        let local_id = self.new_order_id();
+        let local_id = self.new_order_id()?;
         let order_side = OrderSide::from(&side);
 
         let transaction_id = self.dummy_transaction_id();
@@ -594,7 +1219,7 @@ impl Session {
             get_orderbook(&path)?
         };
 
-        let order = orderbook.dry_market_order(
+        let mut order = orderbook.dry_market_order(
             self.current_timestamp,
             &local_id.clone(),
             &local_id.clone(),
@@ -604,14 +1229,24 @@ impl Session {
             &transaction_id,
         )?;
 
+        for o in order.iter_mut() {
+            o.reduce_only = reduce_only;
+        }
+
         self.push_dummy_q(&order.clone());
 
         Ok(order)
     }
 
-    pub fn dummy_market_order(&mut self, side: String, size: Decimal) -> Result<Vec<Order>, PyErr> {
+    #[pyo3(signature = (side, size, reduce_only=false))]
+    pub fn dummy_market_order(
+        &mut self,
+        side: String,
+        size: Decimal,
+        reduce_only: bool,
+    ) -> Result<Vec<Order>, PyErr> {
 
-        let local_id = self.new_order_id();
+        let local_id = self.new_order_id()?;
         let order_side = OrderSide::from(&side);
 
         let execute_price = self.calc_dummy_execute_price_by_slip(order_side);
@@ -632,6 +1267,7 @@ impl Session {
         order.transaction_id = self.dummy_transaction_id();
         order.update_time = self.current_timestamp;
         order.is_maker = false;
+        order.reduce_only = reduce_only;
 
         order.execute_size = size;
         order.remain_size = dec![0.0];
@@ -644,12 +1280,21 @@ impl Session {
         Ok(orders)
     }
 
+    #[pyo3(signature = (side, price, size, time_in_force=None, reduce_only=false))]
     pub fn limit_order(
         &mut self,
         side: String,
         price: Decimal,
         size: Decimal,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
     ) -> Result<Vec<Order>, PyErr> {
+        let size = if reduce_only && self.execute_mode != ExecuteMode::Real {
+            self.reducible_size(OrderSide::from(&side), size)
+        } else {
+            size
+        };
+
         let new_size = self.market_config.round_size(size);
         if new_size.is_err() {
             log::warn!("limit order size trunc into zero {:?} -> {:?}", size, new_size);
@@ -664,23 +1309,26 @@ impl Session {
         }
 
         if self.execute_mode == ExecuteMode::BackTest || self.execute_mode == ExecuteMode::Dry {
-            return self.dummy_limit_order(side, price, size);
+            return self.dummy_limit_order(side, price, size, time_in_force, reduce_only);
         } else {
-            return self.real_limit_order(side, price, size);
+            return self.real_limit_order(side, price, size, time_in_force, reduce_only);
         }
     }
 
+    #[pyo3(signature = (side, price, size, time_in_force=None, reduce_only=false))]
     pub fn real_limit_order(
         &mut self,
         side: String,
         price: Decimal,
         size: Decimal,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
     ) -> Result<Vec<Order>, PyErr> {
         let price = self.market_config.round_price(price)?;
         let size = self.market_config.round_size(size)?;
 
         // first push order to order list
-        let local_id = self.new_order_id();
+        let local_id = self.new_order_id()?;
 
         log::debug!(
             "limit_order: side={:?}, size={}, price={}",
@@ -691,10 +1339,18 @@ impl Session {
 
         // then call market.limit_order
         let r = Python::with_gil(|py| {
-            let result = self.exchange.call_method1(
+            // position_idxなど取引所固有の引数があるため、time_in_forceはキーワードで渡す
+            let kwargs = vec![
+                ("time_in_force", time_in_force.into_py(py)),
+                ("reduce_only", reduce_only.into_py(py)),
+            ];
+            let kwargs = kwargs.into_py_dict_bound(py);
+
+            let result = self.exchange.call_method_bound(
                 py,
                 "limit_order",
                 (self.market_config.clone(), side, price, size, local_id),
+                Some(&kwargs),
             );
 
             match result {
@@ -729,17 +1385,20 @@ impl Session {
         return r;
     }
 
+    #[pyo3(signature = (side, price, size, time_in_force=None, reduce_only=false))]
     pub fn dummy_limit_order(
         &mut self,
         side: String,
         price: Decimal,
         size: Decimal,
+        time_in_force: Option<TimeInForce>,
+        reduce_only: bool,
     ) -> Result<Vec<Order>, PyErr> {
         let price = self.market_config.round_price(price)?;
         let size = self.market_config.round_size(size)?;
 
         // first push order to order list
-        let local_id = self.new_order_id();
+        let local_id = self.new_order_id()?;
 
         let order_side = OrderSide::from(&side);
 
@@ -764,33 +1423,163 @@ impl Session {
         );
 
         order.is_maker = true;
+        order.reduce_only = reduce_only;
 
-        self.push_dummy_q(&vec![order.clone()]);
+        let time_in_force = time_in_force.unwrap_or_default();
+        let (fillable_size, fill_price) = self.dummy_fillable_size(order_side, price, size)?;
+        let orders = Self::apply_time_in_force(order, time_in_force, fillable_size, fill_price);
 
-        return Ok(vec![order]);
+        self.push_dummy_q(&orders);
+
+        return Ok(orders);
     }
 
-    pub fn update_psudo_account_by_order(&mut self, order: &Order) -> bool {
-        self.psudo_account.apply_order(&self.market_config, order);
+    /// ポジション * mark_price * rateをhome currencyの残高から引く(負の場合は受け取り)。精算額を返す。
+    pub fn apply_funding(&mut self, rate: Decimal, mark_price: Decimal) -> Decimal {
+        let payment = funding_payment(self.psudo_position, mark_price, rate);
 
-        if order.status == OrderStatus::Filled || order.status == OrderStatus::PartiallyFilled {
-            return true;
-        } else {
-            return false;
+        if payment != dec![0.0] {
+            let home_currency = self.market_config.home_currency.clone();
+            self.psudo_account.diff_update(&home_currency, -payment, -payment, dec![0.0]);
+            self.funding_paid += payment;
         }
+
+        payment
     }
 
-    pub fn print(&mut self, m: &str) {
-        let message = format!("[{}] {}", short_time_string(self.current_timestamp), m);
-        write_agent_messsage(&message);
+    pub fn total_funding_paid(&self) -> Decimal {
+        self.funding_paid
     }
 
-    #[getter]
-    pub fn get_message(&mut self) -> Vec<String> {
-        get_agent_message()
+    /// 手数料をquote通貨に換算するための価格を設定する(例: set_asset_price("BNB", 500))
+    pub fn set_asset_price(&mut self, asset: &str, price: Decimal) {
+        self.asset_prices.insert(asset.to_string(), price);
     }
 
-    #[getter]
+    pub fn get_asset_price(&self, asset: &str) -> Option<Decimal> {
+        self.asset_prices.get(asset).cloned()
+    }
+
+    /// ペア・裁定取引用にマーケットを追加する。注文はsession.market(symbol)から直接出す。
+    /// マーケットは(exchange, category, symbol)で区別する。RunnerはDry/Realモードで追加したマーケットのstreamも受信する。
+    pub fn add_market(&mut self, market: &Bound<PyAny>) -> anyhow::Result<()> {
+        let config: MarketConfig = market.getattr("config")?.extract()?;
+        let key = market_key(&config);
+
+        if key == market_key(&self.market_config) {
+            return Err(anyhow!("{:?} is primary market of this session", key));
+        }
+
+        let position = MarketPosition::new(&config);
+
+        self.markets.insert(
+            key,
+            SessionMarket {
+                config,
+                market: market.clone().unbind(),
+                position,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// add_marketで登録したsymbolのマーケット。
+    /// 同じsymbolを複数の取引所・カテゴリで登録した場合はexchange, categoryで指定する。
+    #[pyo3(signature = (symbol, exchange=None, category=None))]
+    pub fn market(
+        &self,
+        py: Python,
+        symbol: &str,
+        exchange: Option<&str>,
+        category: Option<&str>,
+    ) -> anyhow::Result<Py<PyAny>> {
+        let m = self.find_market(symbol, exchange, category)?;
+
+        Ok(m.market.clone_ref(py))
+    }
+
+    #[pyo3(signature = (symbol, exchange=None, category=None))]
+    pub fn market_config(
+        &self,
+        symbol: &str,
+        exchange: Option<&str>,
+        category: Option<&str>,
+    ) -> anyhow::Result<MarketConfig> {
+        let config = &self.market_config;
+        if symbol == config.trade_symbol
+            && exchange.map_or(true, |e| e == config.exchange_name)
+            && category.map_or(true, |c| c == config.trade_category)
+        {
+            return Ok(config.clone());
+        }
+
+        let m = self.find_market(symbol, exchange, category)?;
+
+        Ok(m.config.clone())
+    }
+
+    #[pyo3(signature = (symbol, exchange=None, category=None))]
+    pub fn market_position(
+        &self,
+        symbol: &str,
+        exchange: Option<&str>,
+        category: Option<&str>,
+    ) -> anyhow::Result<MarketPosition> {
+        let m = self.find_market(symbol, exchange, category)?;
+
+        Ok(m.position.clone())
+    }
+
+    /// primaryを含む登録済みマーケットのsymbol
+    #[getter]
+    pub fn get_market_symbols(&self) -> Vec<String> {
+        let mut symbols = vec![self.market_config.trade_symbol.clone()];
+        let mut others: Vec<MarketKey> = self.markets.keys().cloned().collect();
+        others.sort();
+        symbols.extend(others.into_iter().map(|(_, _, symbol)| symbol));
+
+        symbols
+    }
+
+    /// 損益をマーケットの値動きで説明できる部分(beta_pnl)とそれ以外(alpha_pnl)に分ける。
+    /// pnl_sample_intervalごとに記録した時価評価損益の変化を、primaryマーケットの仲値のリターンで回帰する。
+    pub fn performance_attribution(&self) -> anyhow::Result<AttributionReport> {
+        AttributionReport::calc(&self.pnl_samples)
+    }
+
+    /// primaryと追加マーケットの損益(手数料控除後)合計
+    #[getter]
+    pub fn get_total_profit_all(&self) -> Decimal {
+        self.total_profit
+            + self
+                .markets
+                .values()
+                .map(|m| m.position.total_profit)
+                .sum::<Decimal>()
+    }
+
+    pub fn update_psudo_account_by_order(&mut self, order: &Order) -> bool {
+        self.psudo_account.apply_order(&self.market_config, order);
+
+        if order.status == OrderStatus::Filled || order.status == OrderStatus::PartiallyFilled {
+            return true;
+        } else {
+            return false;
+        }
+    }
+
+    pub fn print(&mut self, m: &str) {
+        let message = format!("[{}] {}", short_time_string(self.current_timestamp), m);
+        write_agent_messsage(&message);
+    }
+
+    #[getter]
+    pub fn get_message(&mut self) -> Vec<String> {
+        get_agent_message()
+    }
+
+    #[getter]
     pub fn get_limit_buy_count(&self) -> i64 {
         self.limit_buy_count
     }
@@ -890,6 +1679,90 @@ impl Session {
         true
     }
 
+    /// add_marketで登録したマーケット(primaryを除く)のconfigとマーケットオブジェクト
+    pub fn additional_markets(&self, py: Python) -> Vec<(MarketConfig, Py<PyAny>)> {
+        let mut markets: Vec<(MarketConfig, Py<PyAny>)> = self
+            .markets
+            .values()
+            .map(|m| (m.config.clone(), m.market.clone_ref(py)))
+            .collect();
+        markets.sort_by_key(|(config, _)| market_key(config));
+
+        markets
+    }
+
+    fn find_market(
+        &self,
+        symbol: &str,
+        exchange: Option<&str>,
+        category: Option<&str>,
+    ) -> anyhow::Result<&SessionMarket> {
+        let found: Vec<&SessionMarket> = self
+            .markets
+            .values()
+            .filter(|m| m.config.trade_symbol == symbol)
+            .filter(|m| exchange.map_or(true, |e| e == m.config.exchange_name))
+            .filter(|m| category.map_or(true, |c| c == m.config.trade_category))
+            .collect();
+
+        match found.len() {
+            0 => Err(anyhow!("market {} is not registered", symbol)),
+            1 => Ok(found[0]),
+            _ => Err(anyhow!(
+                "market {} is registered in multiple exchanges/categories, specify exchange and category",
+                symbol
+            )),
+        }
+    }
+
+    /// 複数マーケットの統合チャネルからのMessageを(exchange, category, symbol)で振り分ける。
+    /// primaryマーケット宛はon_messageで処理し、それ以外は各マーケットのポジションを更新する。
+    pub fn on_broadcast_message(&mut self, message: &BroadcastMessage) -> Vec<Order> {
+        if message.filter(
+            &self.market_config.exchange_name,
+            &self.market_config.trade_category,
+            &self.market_config.trade_symbol,
+        ) {
+            return self.on_message(&message.msg);
+        }
+
+        let session_name = self.session_name.clone();
+
+        let key = (
+            message.exchange.clone(),
+            message.category.clone(),
+            message.symbol.clone(),
+        );
+
+        let market = match self.markets.get_mut(&key) {
+            Some(m) => m,
+            _ => {
+                log::debug!("on_broadcast_message: unknown market {:?}", message);
+                return vec![];
+            }
+        };
+
+        match &message.msg {
+            MarketMessage::Trade(trade) => {
+                market.position.last_price = trade.price;
+
+                if self.current_timestamp < trade.time {
+                    self.current_timestamp = trade.time;
+                }
+            }
+            MarketMessage::Order(order) => {
+                if order.is_my_order(&session_name) {
+                    market.position.update(order);
+                }
+            }
+            _ => {
+                log::debug!("on_broadcast_message: ignored {:?}", message);
+            }
+        }
+
+        vec![]
+    }
+
     /// Message処理
     /// Dummyのときは、Tradeで約定情報を受け取り、約定キューに追加する。
     pub fn on_message(&mut self, message: &MarketMessage) -> Vec<Order> {
@@ -899,6 +1772,7 @@ impl Session {
             MarketMessage::Trade(trade) => {
                 log::debug!("on_message: trade={:?}", trade);
                 new_orders = self.on_tick(trade);
+                self.cancel_expired_orders();
 
                 // ダミーモードの場合は約定キューからの処理が発生する。
                 if 0 < new_orders.len() {
@@ -910,6 +1784,8 @@ impl Session {
                 return new_orders;
             }
             MarketMessage::Order(order) => {
+                self.update_open_order_cache(order);
+
                 if !order.is_my_order(&self.session_name) {
                     log::debug!("on_message: skip my order: {:?}", order);
                     return vec![];
@@ -919,6 +1795,17 @@ impl Session {
                 log::debug!("on_message: order={:?}", order);
                 self.on_order_update(&mut order);
             }
+            MarketMessage::ReconciledOrder(order) => {
+                if !order.is_my_order(&self.session_name) {
+                    log::debug!("on_message: skip other's reconciled order: {:?}", order);
+                    return vec![];
+                }
+
+                log::debug!("on_message: reconciled order={:?}", order);
+                if let Some(order) = self.apply_reconciled_order(order) {
+                    new_orders.push(order);
+                }
+            }
             MarketMessage::Account(coins) => {
                 log::debug!("on_message: account={:?}", coins);
 
@@ -927,6 +1814,22 @@ impl Session {
             MarketMessage::Orderbook(orderbook) => {
                 log::warn!("IGNORED MESSAGE: on_message: orderbook={:?}", orderbook);
             }
+            MarketMessage::PositionUpdate(position) => {
+                log::debug!("on_message: position={:?}", position);
+
+                self.on_position_update(position);
+            }
+            MarketMessage::Fill(fill) => {
+                if !fill.is_my_fill(&self.session_name) {
+                    log::debug!("on_message: skip other's fill: {:?}", fill);
+                    return vec![];
+                }
+
+                self.fills.push(fill.clone());
+            }
+            MarketMessage::Ticker(ticker) => {
+                log::debug!("on_message: ticker={:?}", ticker);
+            }
             MarketMessage::Message(message) => {
                 log::warn!("IGNORED MESSAGE: on_message: message={:?}", message);
             }
@@ -967,9 +1870,412 @@ impl Session {
 }
 
 impl Session {
+    /// 直近で確定した足で成立しているローソク足パターン名
+    pub fn last_bar_patterns(&mut self, interval_sec: i64) -> anyhow::Result<Vec<String>> {
+        let ohlcv = self.ohlcv(interval_sec, PATTERN_LOOKBACK_BARS, None)?;
+        let df = detect_patterns(&ohlcv.0)?;
+
+        last_patterns(&df)
+    }
+
+    /// 直近の確定足までで判定したregime(+1:上昇トレンド, -1:下降トレンド, 0:レンジ)
+    pub fn last_bar_regime(&mut self, interval_sec: i64, window: usize) -> anyhow::Result<i8> {
+        let ohlcv = self.ohlcv(interval_sec, (window * 3) as i64, None)?;
+        let df = detect_regime(&ohlcv.0, window)?;
+
+        if df.height() == 0 {
+            return Ok(0);
+        }
+
+        Ok(df.column(REGIME)?.i8()?.get(df.height() - 1).unwrap_or(0))
+    }
+
+    /// 注文通知でオープンオーダーのキャッシュを更新する。
+    /// 変更通知(Amended)は価格・数量だけを持つので、既存の注文に反映する。
+    fn update_open_order_cache(&mut self, order: &Order) {
+        if order.symbol != self.market_config.trade_symbol || !order.is_my_order(&self.session_name) {
+            return;
+        }
+
+        match order.status {
+            OrderStatus::New | OrderStatus::PartiallyFilled => {
+                self.open_orders.insert(order.order_id.clone(), order.clone());
+            }
+            OrderStatus::Amended => {
+                if let Some(cached) = self.open_orders.get_mut(&order.order_id) {
+                    if order.order_price != dec![0.0] {
+                        cached.order_price = order.order_price;
+                    }
+                    if order.order_size != dec![0.0] {
+                        cached.order_size = order.order_size;
+                    }
+                    cached.update_time = order.update_time;
+                }
+            }
+            _ => {
+                self.open_orders.remove(&order.order_id);
+            }
+        }
+    }
+
+    fn local_orders(&self) -> Vec<Order> {
+        let mut orders = self.buy_orders.get();
+        orders.extend(self.sell_orders.get());
+        orders
+    }
+
+    /// Sessionの借用は注文一覧のコピーの間だけにし、取引所への問い合わせ中はRunnerをブロックしない。
+    fn detect_missed_updates(slf: &Py<Self>) -> anyhow::Result<Vec<BroadcastMessage>> {
+        let (exchange, config, session_name, local) = Python::with_gil(|py| -> anyhow::Result<_> {
+            let session = slf
+                .try_borrow(py)
+                .map_err(|e| anyhow!("session is busy: {:?}", e))?;
+
+            if session.execute_mode != ExecuteMode::Real {
+                return Err(anyhow!("reconciliation is only for Real mode"));
+            }
+
+            Ok((
+                session.exchange.clone_ref(py),
+                session.market_config.clone(),
+                session.session_name.clone(),
+                session.local_orders(),
+            ))
+        })?;
+
+        let open_orders = Python::with_gil(|py| {
+            let orders = exchange.call_method1(py, "get_open_orders", (config.clone(),))?;
+            orders.extract::<Vec<Order>>(py)
+        })?;
+        let open_orders: Vec<Order> = open_orders
+            .into_iter()
+            .filter(|o| o.symbol == config.trade_symbol)
+            .collect();
+
+        let updated = Self::reconcile_open_orders(&session_name, &local, &open_orders, NOW(), |order_id| {
+            Python::with_gil(|py| -> anyhow::Result<Order> {
+                let order = exchange.call_method1(py, "get_order_status", (config.clone(), order_id))?;
+                Ok(order.extract::<Order>(py)?)
+            })
+        });
+
+        Ok(updated
+            .into_iter()
+            .map(|order| {
+                log::warn!("reconciliation: missed order update {:?}", order);
+                BroadcastMessage {
+                    exchange: config.exchange_name.clone(),
+                    category: config.trade_category.clone(),
+                    symbol: config.trade_symbol.clone(),
+                    msg: MarketMessage::ReconciledOrder(order),
+                }
+            })
+            .collect())
+    }
+
+    /// ローカルの注文と取引所のオープンオーダーの差分(取引所での現在の注文。execute_sizeは累計)。
+    /// 反映するときはapply_reconciled_orderでその時点のローカルの状態との差分にする。
+    /// オープンオーダーに見当たらない注文は約定かキャンセルか区別できないため、
+    /// query_orderで注文履歴を問い合わせて最終的な状態と約定量を反映する(失敗した場合は次回に持ち越す)。
+    /// 発注直後は取引所の一覧に載っていないことがあるので、RECONCILE_GRACE_SEC以内の注文は除く。
+    fn reconcile_open_orders<F>(
+        session_name: &str,
+        local: &Vec<Order>,
+        open_orders: &Vec<Order>,
+        now: MicroSec,
+        mut query_order: F,
+    ) -> Vec<Order>
+    where
+        F: FnMut(&str) -> anyhow::Result<Order>,
+    {
+        let mut updated = vec![];
+
+        for order in local.iter().filter(|o| o.is_my_order(session_name)) {
+            let remote = match open_orders.iter().find(|o| o.order_id == order.order_id) {
+                Some(remote) => remote.clone(),
+                None => {
+                    if now < order.create_time + SEC(RECONCILE_GRACE_SEC) {
+                        continue;
+                    }
+
+                    match query_order(&order.order_id) {
+                        Ok(remote) => remote,
+                        Err(e) => {
+                            log::warn!("reconcile: failed to query order status {}: {:?}", order.order_id, e);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            if Self::reconciled_delta(order, &remote).is_none() {
+                continue;
+            }
+
+            updated.push(remote);
+        }
+
+        updated
+    }
+
+    /// 取引所の注文(execute_sizeは累計)のうち、ローカルの注文に未反映の部分。
+    /// execute_sizeはローカルで反映済みの分を差し引いた今回の約定量にする。変化がなければNone。
+    fn reconciled_delta(local: &Order, remote: &Order) -> Option<Order> {
+        if remote.status == local.status && remote.execute_size <= local.execute_size {
+            return None;
+        }
+
+        let mut order = remote.clone();
+        order.execute_size = (remote.execute_size - local.execute_size).max(dec![0.0]);
+
+        Some(order)
+    }
+
+    /// 照合で取得した注文を現在のローカルの状態と比べて反映し、反映した注文を返す。
+    /// WSで先に反映済み(完了して一覧から消えた場合も含む)ならNone。
+    fn apply_reconciled_order(&mut self, remote: &Order) -> Option<Order> {
+        let local = self
+            .local_orders()
+            .into_iter()
+            .find(|o| o.order_id == remote.order_id)?;
+
+        let mut order = Self::reconciled_delta(&local, remote)?;
+        self.on_order_update(&mut order);
+
+        Some(order)
+    }
+
+    /// create_timeから有効期限を過ぎた注文。キャンセル済み(ttl_canceled)は除く。
+    fn select_expired_orders(
+        orders: &Vec<Order>,
+        order_ttl: &HashMap<String, i64>,
+        default_ttl_sec: i64,
+        ttl_canceled: &HashSet<String>,
+        now: MicroSec,
+    ) -> Vec<Order> {
+        orders
+            .iter()
+            .filter(|o| !ttl_canceled.contains(&o.order_id))
+            .filter(|o| {
+                let ttl_sec = order_ttl
+                    .get(&o.client_order_id)
+                    .cloned()
+                    .unwrap_or(default_ttl_sec);
+
+                0 < ttl_sec && o.create_time + SEC(ttl_sec) <= now
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// reduce only注文で減らせるポジションの数量（ポジションと同じ方向なら0）
+    fn reducible_size(&self, side: OrderSide, size: Decimal) -> Decimal {
+        Self::calc_reducible_size(side, size, self.psudo_position)
+    }
+
+    fn calc_reducible_size(side: OrderSide, size: Decimal, position: Decimal) -> Decimal {
+        let reducible = match side {
+            OrderSide::Buy if position < dec![0.0] => -position,
+            OrderSide::Sell if dec![0.0] < position => position,
+            _ => dec![0.0],
+        };
+
+        size.min(reducible)
+    }
+
+    /// reduce only注文の約定がポジションを反転させないよう約定数量を切り詰める
+    /// 切り詰めた残りはキャンセル扱いとする
+    fn clip_reduce_only_fill(order: &mut Order, position: Decimal) {
+        if order.status != OrderStatus::Filled && order.status != OrderStatus::PartiallyFilled {
+            return;
+        }
+
+        let execute_size =
+            Self::calc_reducible_size(order.order_side, order.execute_size, position);
+
+        if execute_size == order.execute_size {
+            return;
+        }
+
+        log::debug!(
+            "reduce only order clipped: {:?} -> {:?} (position={:?})",
+            order.execute_size,
+            execute_size,
+            position
+        );
+
+        order.execute_size = execute_size;
+        order.remain_size = dec![0.0];
+        order.quote_vol = order.execute_price * order.execute_size;
+        order.status = if execute_size == dec![0.0] {
+            OrderStatus::Canceled
+        } else {
+            OrderStatus::Filled
+        };
+    }
+
+    /// 価格・数量を変更した注文(Amended)を作る。数量は約定済みの数量より大きくなければエラー
+    fn make_amended_order(
+        order: &Order,
+        price: Decimal,
+        size: Option<Decimal>,
+        timestamp: MicroSec,
+    ) -> anyhow::Result<Order> {
+        let mut amended = order.clone();
+        amended.order_price = price;
+
+        if let Some(size) = size {
+            let executed = order.order_size - order.remain_size;
+            if size <= executed {
+                return Err(anyhow!(
+                    "amend size {} must be greater than executed size {}",
+                    size,
+                    executed
+                ));
+            }
+            amended.order_size = size;
+            amended.remain_size = size - executed;
+        }
+
+        amended.status = OrderStatus::Amended;
+        amended.update_time = timestamp;
+
+        Ok(amended)
+    }
+
+    /// 指値注文が板に対して即時に約定できる数量と、その約定価格
+    /// BackTestでは板がないため、最良気配に届いていれば全量を最良気配で約定できるとみなす
+    fn dummy_fillable_size(
+        &mut self,
+        side: OrderSide,
+        price: Decimal,
+        size: Decimal,
+    ) -> anyhow::Result<(Decimal, Decimal)> {
+        if self.execute_mode == ExecuteMode::Dry {
+            let orderbook = if self.client_mode {
+                get_rest_orderbook(&self.market_config)?
+            } else {
+                let path = OrderBookList::make_path(&self.market_config);
+                get_orderbook(&path)?
+            };
+
+            let fillable_size = orderbook.fillable_size(side, price).min(size);
+            // 板を食った分の平均価格で約定させる
+            let fill_price = orderbook.fill_price(side, price, fillable_size).unwrap_or(price);
+
+            return Ok((fillable_size, fill_price));
+        }
+
+        // 板の情報がまだない場合は交差しないものとする
+        let (crossed, fill_price) = if side == OrderSide::Buy {
+            (
+                self.ask_edge != dec![0.0] && self.ask_edge <= price,
+                self.ask_edge,
+            )
+        } else {
+            (price <= self.bid_edge, self.bid_edge)
+        };
+
+        Ok(if crossed {
+            (size, fill_price)
+        } else {
+            (dec![0.0], price)
+        })
+    }
+
+    /// time in forceに従って新規指値注文を即時約定分とキャンセル分に分ける。
+    /// 板と交差して即時約定する分はTaker、板に残る分はMaker(約定時にconsume_tradeでMakerになる)。
+    /// 即時約定分は指値ではなく板の価格(fill_price)で約定する。
+    fn apply_time_in_force(
+        order: Order,
+        time_in_force: TimeInForce,
+        fillable_size: Decimal,
+        fill_price: Decimal,
+    ) -> Vec<Order> {
+        let size = order.order_size;
+
+        let execute_size = match time_in_force {
+            TimeInForce::GTC => {
+                if fillable_size == dec![0.0] {
+                    return vec![order];
+                }
+                // 約定できない残りは板に残る
+                fillable_size.min(size)
+            }
+            TimeInForce::PostOnly => {
+                if fillable_size == dec![0.0] {
+                    return vec![order];
+                }
+                // Takerになるのでキャンセル
+                dec![0.0]
+            }
+            TimeInForce::IOC => fillable_size.min(size),
+            TimeInForce::FOK => {
+                if size <= fillable_size {
+                    size
+                } else {
+                    dec![0.0]
+                }
+            }
+        };
+
+        let mut orders = vec![];
+
+        if execute_size != dec![0.0] {
+            let mut filled = order.clone();
+            filled.status = if execute_size == size {
+                OrderStatus::Filled
+            } else {
+                OrderStatus::PartiallyFilled
+            };
+            filled.is_maker = false;
+            filled.execute_price = fill_price;
+            filled.execute_size = execute_size;
+            filled.remain_size = size - execute_size;
+            filled.quote_vol = filled.execute_price * filled.execute_size;
+            orders.push(filled);
+        }
+
+        if execute_size != size && time_in_force != TimeInForce::GTC {
+            let mut canceled = order;
+            canceled.status = OrderStatus::Canceled;
+            canceled.remain_size = size - execute_size;
+            orders.push(canceled);
+        }
+
+        orders
+    }
+
+    /// pnl_sample_intervalごとに確定損益(手数料控除後)と仲値での含み損益を記録する。板がなければ記録しない。
+    fn sample_pnl(&mut self, timestamp: MicroSec) {
+        if self.bid_edge <= dec![0.0] || self.ask_edge <= dec![0.0] {
+            return;
+        }
+
+        if self.last_pnl_sample_time != 0 && timestamp < self.last_pnl_sample_time + self.pnl_sample_interval {
+            return;
+        }
+        self.last_pnl_sample_time = timestamp;
+
+        if MAX_PNL_SAMPLES <= self.pnl_samples.len() {
+            downsample_pnl_samples(&mut self.pnl_samples);
+            self.pnl_sample_interval *= 2;
+        }
+
+        let price = (self.bid_edge + self.ask_edge) / dec![2.0];
+        let unrealized = self.psudo_position * (price - self.average_price);
+
+        self.pnl_samples.push(PnlSample {
+            time: timestamp,
+            price,
+            equity: self.total_profit + unrealized,
+        });
+    }
+
     /// 約定情報の処理
     fn on_tick(&mut self, tick: &Trade) -> Vec<Order> {
         self.current_timestamp = tick.time;
+        self.kline_fill_tick = tick.status == LogStatus::KlineFill;
 
         if tick.order_side == OrderSide::Buy {
             self.ask_edge = tick.price;
@@ -983,6 +2289,8 @@ impl Session {
             }
         }
 
+        self.sample_pnl(tick.time);
+
         if self.execute_mode == ExecuteMode::BackTest || self.execute_mode == ExecuteMode::Dry {
             return self.execute_dummuy_tick(tick);
         } else {
@@ -1000,17 +2308,39 @@ impl Session {
         };
     }
 
+    pub fn on_position_update(&mut self, position: &PositionInfo) {
+        self.current_position.insert(
+            (position.symbol.clone(), position.position_idx),
+            position.clone(),
+        );
+    }
+
     pub fn on_order_update(&mut self, order: &mut Order) {
         if !order.is_my_order(&self.session_name) {
             log::debug!("on_order_update: skip my order: {:?}", order);
             return;
         }
 
+        self.update_open_order_cache(order);
+
         self.log_id += 1;
         order.log_id = self.log_id;
         order.update_balance(&self.market_config);
         self.update_psudo_position(order);
 
+        if self.execute_mode != ExecuteMode::Real
+            && (order.status == OrderStatus::Filled || order.status == OrderStatus::PartiallyFilled)
+            && order.execute_size != dec![0.0]
+        {
+            self.fills
+                .push(Fill::from_dummy_order(order, &self.market_config));
+        }
+
+        if order.status == OrderStatus::Filled || order.status == OrderStatus::Canceled {
+            self.order_ttl.remove(&order.client_order_id);
+            self.ttl_canceled.remove(&order.order_id);
+        }
+
         if order.order_side == OrderSide::Buy {
             if order.status == OrderStatus::Filled || order.status == OrderStatus::Canceled {
                 self.buy_orders.remove(&order.order_id);
@@ -1032,13 +2362,118 @@ impl Session {
         };
     }
 
-    fn new_order_id(&mut self) -> String {
+    fn local_order_id(&self, order_number: i64) -> String {
+        format!("{}-{}{:04}", self.session_name, self.session_id, order_number)
+    }
+
+    fn new_order_id(&mut self) -> anyhow::Result<String> {
         self.order_number += 1;
 
-        format!(
-            "{}-{}{:04}",
-            self.session_name, self.session_id, self.order_number
-        )
+        let local_id = self.local_order_id(self.order_number);
+
+        if self.order_id_prefix.is_empty() {
+            return Ok(local_id);
+        }
+
+        let order_id = format!("{}-{}", self.order_id_prefix, local_id);
+        if order_id.len() > MAX_ORDER_ID_LEN {
+            return Err(anyhow!(
+                "order id is too long ({} > {}): {}",
+                order_id.len(),
+                MAX_ORDER_ID_LEN,
+                order_id
+            ));
+        }
+
+        Ok(order_id)
+    }
+
+    pub fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            session_name: self.session_name.clone(),
+            timestamp: self.current_timestamp,
+            buy_orders: self.buy_orders.get(),
+            sell_orders: self.sell_orders.get(),
+            psudo_position: self.psudo_position,
+            average_price: self.average_price,
+            current_position: self.current_position.values().cloned().collect(),
+            real_account: self.real_account.clone(),
+            psudo_account: self.psudo_account.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: SessionSnapshot) -> anyhow::Result<()> {
+        let mut orders = snapshot.buy_orders.clone();
+        orders.extend(snapshot.sell_orders.clone());
+
+        // Realモードでは取引所のオープンオーダーと突き合わせる
+        let (alive, canceled) = if self.execute_mode == ExecuteMode::Real {
+            let open_orders = self.fetch_open_orders()?;
+            SessionSnapshot::reconcile_orders(&orders, &open_orders)
+        } else {
+            (orders, vec![])
+        };
+
+        self.buy_orders.clear();
+        self.sell_orders.clear();
+
+        for order in alive {
+            match order.order_side {
+                OrderSide::Buy => self.buy_orders.update_or_insert(&order),
+                OrderSide::Sell => self.sell_orders.update_or_insert(&order),
+                _ => log::error!("Unknown order side: {:?}", order.order_side),
+            }
+        }
+
+        for order in canceled {
+            log::warn!("order in snapshot is not found in exchange, mark Canceled: {:?}", order);
+            let _ = self.log.log_order(self.current_timestamp, &order);
+        }
+
+        self.psudo_position = snapshot.psudo_position;
+        self.average_price = snapshot.average_price;
+        self.current_position = snapshot
+            .current_position
+            .into_iter()
+            .map(|p| ((p.symbol.clone(), p.position_idx), p))
+            .collect();
+        self.real_account = snapshot.real_account;
+        self.psudo_account = snapshot.psudo_account;
+
+        if self.current_timestamp < snapshot.timestamp {
+            self.current_timestamp = snapshot.timestamp;
+        }
+
+        Ok(())
+    }
+
+    fn fetch_order_status(&self, order_id: &str) -> anyhow::Result<Order> {
+        let config = self.market_config.clone();
+
+        let order = Python::with_gil(|py| {
+            let order = self
+                .exchange
+                .call_method1(py, "get_order_status", (config, order_id))?;
+            order.extract::<Order>(py)
+        })?;
+
+        Ok(order)
+    }
+
+    fn fetch_open_orders(&self) -> anyhow::Result<Vec<Order>> {
+        let config = self.market_config.clone();
+
+        let orders = Python::with_gil(|py| {
+            let orders = self
+                .exchange
+                .call_method1(py, "get_open_orders", (config.clone(),))?;
+            orders.extract::<Vec<Order>>(py)
+        })?;
+
+        Ok(orders
+            .into_iter()
+            .filter(|o| o.symbol == config.trade_symbol)
+            .collect())
     }
 
     fn load_order_list(&mut self) -> Result<(), PyErr> {
@@ -1065,6 +2500,8 @@ impl Session {
                             continue;
                         }
 
+                        self.open_orders.insert(order.order_id.clone(), order.clone());
+
                         if !order.is_my_order(&self.session_name) {
                             continue;
                         }
@@ -1121,11 +2558,7 @@ impl Session {
             log::error!("Unknown order side: {:?}", order.order_side)
         }
 
-        let fee = if order.is_maker {
-            order.execute_price * order.execute_size * self.market_config.maker_fee
-        } else {
-            order.execute_price * order.execute_size * self.market_config.taker_fee
-        };
+        let fee = self.calc_fee(order);
 
         let total_profit = profit - fee;
 
@@ -1140,6 +2573,44 @@ impl Session {
         self.total_profit += total_profit;
     }
 
+    /// quote建ての手数料。第三の通貨で払った場合はasset_pricesで換算する。
+    fn calc_fee(&self, order: &Order) -> Decimal {
+        if self.kline_fill_tick && self.execute_mode != ExecuteMode::Real {
+            return dec![0.0];
+        }
+
+        if order.is_third_asset_commission(&self.market_config) {
+            // 設定値がなければadd_marketで登録した<asset><quote>マーケットの最新価格を使う
+            let price = self.asset_prices.get(&order.commission_asset).cloned().or_else(|| {
+                let symbol = format!("{}{}", order.commission_asset, self.market_config.home_currency);
+                self.markets
+                    .values()
+                    .find(|m| {
+                        m.config.trade_symbol == symbol
+                            && m.config.exchange_name == self.market_config.exchange_name
+                    })
+                    .map(|m| m.position.last_price)
+                    .filter(|p| *p != dec![0.0])
+            });
+
+            match order.commission_in_quote(&self.market_config, price) {
+                Some(fee) => return fee,
+                None => {
+                    log::warn!(
+                        "no price for commission asset {}, use estimated fee",
+                        order.commission_asset
+                    );
+                }
+            }
+        }
+
+        if order.is_maker {
+            order.execute_price * order.execute_size * self.market_config.maker_fee
+        } else {
+            order.execute_price * order.execute_size * self.market_config.taker_fee
+        }
+    }
+
     /// returns position change
     pub fn open_position(&mut self, price: Decimal, position: Decimal) {
         let total_cost = (self.average_price * self.psudo_position) + (price * position);
@@ -1226,7 +2697,20 @@ impl Session {
 
     /// update order balance information accroding to market config
     fn update_dummy_orders(&mut self, orders: &mut Vec<Order>) {
+        let mut position = self.psudo_position;
+
         for o in orders {
+            if o.reduce_only {
+                Self::clip_reduce_only_fill(o, position);
+            }
+            if o.status == OrderStatus::Filled || o.status == OrderStatus::PartiallyFilled {
+                position += if o.order_side == OrderSide::Buy {
+                    o.execute_size
+                } else {
+                    -o.execute_size
+                };
+            }
+
             o.update_time = self.current_timestamp;
             o.update_balance(&self.market_config);
             // TODO: debug
@@ -1315,8 +2799,886 @@ pub fn calc_ohlcv_start(
 #[cfg(test)]
 mod session_tests {
     use super::*;
-    use rbot_lib::common::{init_debug_log, parse_time};
+    use rbot_lib::common::{init_debug_log, parse_time, FeeType};
+
+
+    fn snapshot_order(order_id: &str, side: OrderSide) -> Order {
+        Order::new(
+            "linear",
+            "BTCUSDT",
+            NOW(),
+            order_id,
+            &format!("session-{}", order_id),
+            side,
+            OrderType::Limit,
+            OrderStatus::New,
+            dec![100.0],
+            dec![0.1],
+        )
+    }
+
+    const STUB_SOURCE: &str = "class Exchange:\n    production = False\nclass Market:\n    pass\n";
+
+    /// 照合(reconcile)用。orders/historyを差し替えてオープンオーダーと注文履歴を返す
+    const RECONCILE_STUB_SOURCE: &str = "class Exchange:\n    production = False\n    orders = []\n    history = {}\n    def get_open_orders(self, config):\n        return self.orders\n    def get_order_status(self, config, order_id):\n        return self.history[order_id]\nclass Market:\n    pass\n";
+
+    fn linear_config() -> MarketConfig {
+        let mut config = MarketConfig::default();
+        config.trade_category = "linear".to_string();
+        config.trade_symbol = "BTCUSDT".to_string();
+        config
+    }
+
+    /// Python側のExchange/Marketのスタブ。Marketはconfigだけを持つ
+    fn stub_objects<'py>(
+        py: Python<'py>,
+        source: &str,
+        config: MarketConfig,
+    ) -> anyhow::Result<(Bound<'py, PyAny>, Bound<'py, PyAny>)> {
+        let module = PyModule::from_code_bound(py, source, "session_stub.py", "session_stub")?;
+        let exchange = module.getattr("Exchange")?.call0()?;
+        let market = module.getattr("Market")?.call0()?;
+        market.setattr("config", Py::new(py, config)?)?;
+
+        Ok((exchange, market))
+    }
+
+    fn backtest_session(py: Python, config: MarketConfig) -> anyhow::Result<Session> {
+        let (exchange, market) = stub_objects(py, STUB_SOURCE, config)?;
+
+        Ok(Session::new(&exchange, &market, ExecuteMode::BackTest, false, Some("session"), true))
+    }
+
+    #[test]
+    fn test_select_expired_orders() {
+        let mut order1 = snapshot_order("1", OrderSide::Buy);
+        order1.create_time = SEC(100);
+        let mut order2 = snapshot_order("2", OrderSide::Sell);
+        order2.create_time = SEC(100);
+        let mut order3 = snapshot_order("3", OrderSide::Buy);
+        order3.create_time = SEC(100);
+        let orders = vec![order1, order2, order3];
+
+        let mut ttl = HashMap::new();
+        ttl.insert("session-1".to_string(), 10);
+        let mut canceled = HashSet::new();
+
+        // default ttlなしでは個別指定の注文のみ
+        let expired = Session::select_expired_orders(&orders, &ttl, 0, &canceled, SEC(109));
+        assert!(expired.is_empty());
+        let expired = Session::select_expired_orders(&orders, &ttl, 0, &canceled, SEC(110));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].order_id, "1");
+
+        // default ttlは個別指定より後に効く
+        let expired = Session::select_expired_orders(&orders, &ttl, 30, &canceled, SEC(110));
+        assert_eq!(expired.len(), 1);
+        let expired = Session::select_expired_orders(&orders, &ttl, 30, &canceled, SEC(130));
+        assert_eq!(expired.len(), 3);
+
+        // キャンセル済みは二重にキャンセルしない
+        canceled.insert("1".to_string());
+        let expired = Session::select_expired_orders(&orders, &ttl, 30, &canceled, SEC(130));
+        assert_eq!(expired.len(), 2);
+    }
+
+    #[test]
+    fn test_cancel_expired_orders_retry() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let source = "class Exchange:\n    production = False\n    fail = True\n    def cancel_order(self, config, order_id):\n        if self.fail:\n            raise Exception('cancel failed')\n        return order_id\nclass Market:\n    pass\n";
+            let (exchange, market) = stub_objects(py, source, linear_config())?;
+            let mut session = Session::new(&exchange, &market, ExecuteMode::Real, false, Some("session"), true);
+            session.set_default_order_ttl_sec(10);
+
+            let mut buy = snapshot_order("1", OrderSide::Buy);
+            buy.create_time = SEC(1);
+            session.on_order_update(&mut buy);
+
+            // キャンセルに失敗した注文は次回再び選ばれる
+            assert!(session.cancel_expired_orders().is_empty());
+            assert!(session.ttl_canceled.is_empty());
+
+            exchange.setattr("fail", false)?;
+            let canceled = session.cancel_expired_orders();
+            assert_eq!(canceled.len(), 1);
+            assert_eq!(canceled[0].order_id, "1");
+
+            // キャンセル済みは二重に出さない
+            assert!(session.cancel_expired_orders().is_empty());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_position_update_hedge_mode() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let mut session = backtest_session(py, linear_config())?;
+
+            let position = |side: OrderSide, size: Decimal, position_idx: i64| {
+                let mut position = PositionInfo::default();
+                position.symbol = "BTCUSDT".to_string();
+                position.side = side;
+                position.size = size;
+                position.position_idx = position_idx;
+                position
+            };
+
+            // ヘッジモードでは買いと売りのポジションを別々に持つ
+            session.on_position_update(&position(OrderSide::Buy, dec![0.1], 1));
+            session.on_position_update(&position(OrderSide::Sell, dec![0.2], 2));
+            session.on_position_update(&position(OrderSide::Buy, dec![0.3], 1));
+
+            let positions = session.get_current_position();
+            assert_eq!(positions.len(), 2);
+            assert_eq!(positions[&("BTCUSDT".to_string(), 1)].size, dec![0.3]);
+            assert_eq!(positions[&("BTCUSDT".to_string(), 2)].get_position(), dec![-0.2]);
+
+            // スナップショットから戻しても同じキーになる
+            let snapshot = session.snapshot();
+            assert_eq!(snapshot.current_position.len(), 2);
+            session.current_position.clear();
+            session.restore(snapshot)?;
+            assert_eq!(session.get_current_position(), positions);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() -> anyhow::Result<()> {
+        let position = vec![PositionInfo::default()];
+
+        let snapshot = SessionSnapshot {
+            session_name: "session".to_string(),
+            timestamp: NOW(),
+            buy_orders: vec![snapshot_order("1", OrderSide::Buy)],
+            sell_orders: vec![snapshot_order("2", OrderSide::Sell)],
+            psudo_position: dec![0.5],
+            average_price: dec![25000.1],
+            current_position: position,
+            real_account: AccountCoins::default(),
+            psudo_account: AccountCoins::default(),
+        };
+
+        let path = std::env::temp_dir().join(format!("rbot_snapshot_test_{}.json", NOW()));
+        snapshot.save(&path)?;
+        let loaded = SessionSnapshot::load(&path)?;
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(snapshot, loaded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_reconcile_orders() {
+        let orders = vec![
+            snapshot_order("1", OrderSide::Buy),
+            snapshot_order("2", OrderSide::Sell),
+        ];
+        let mut open = snapshot_order("1", OrderSide::Buy);
+        open.status = OrderStatus::PartiallyFilled;
+
+        let (alive, canceled) = SessionSnapshot::reconcile_orders(&orders, &vec![open]);
+        assert_eq!(alive.len(), 1);
+        assert_eq!(alive[0].status, OrderStatus::PartiallyFilled);
+        assert_eq!(canceled.len(), 1);
+        assert_eq!(canceled[0].order_id, "2");
+        assert_eq!(canceled[0].status, OrderStatus::Canceled);
+    }
+
+    #[test]
+    fn test_reconcile_open_orders() {
+        let mut order1 = snapshot_order("1", OrderSide::Buy);
+        order1.create_time = SEC(100);
+        let mut order2 = snapshot_order("2", OrderSide::Sell);
+        order2.create_time = SEC(100);
+        let mut order3 = snapshot_order("3", OrderSide::Sell);
+        order3.create_time = SEC(100);
+        let local = vec![order1.clone(), order2, order3];
+
+        let mut open1 = order1.clone();
+        open1.status = OrderStatus::PartiallyFilled;
+        open1.execute_size = dec![0.04];
+        let open3 = snapshot_order("3", OrderSide::Sell);
+
+        let mut canceled2 = snapshot_order("2", OrderSide::Sell);
+        canceled2.status = OrderStatus::Canceled;
+        let query = |order_id: &str| -> anyhow::Result<Order> {
+            match order_id {
+                "2" => Ok(canceled2.clone()),
+                _ => Err(anyhow!("unknown order {}", order_id)),
+            }
+        };
+
+        // 発注直後は一覧に載っていなくても問い合わせない
+        let updated = Session::reconcile_open_orders(
+            "session",
+            &local,
+            &vec![open1.clone(), open3.clone()],
+            SEC(105),
+            |order_id| panic!("unexpected query {}", order_id),
+        );
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].order_id, "1");
+        assert_eq!(updated[0].execute_size, dec![0.04]);
+
+        // 一覧にない注文は注文履歴の状態を反映する
+        let updated = Session::reconcile_open_orders("session", &local, &vec![open1.clone(), open3.clone()], SEC(200), query);
+        assert_eq!(updated.len(), 2);
+        assert_eq!(updated[1].order_id, "2");
+        assert_eq!(updated[1].status, OrderStatus::Canceled);
+
+        // 問い合わせに失敗した注文は次回に持ち越す
+        let updated = Session::reconcile_open_orders(
+            "session",
+            &local,
+            &vec![open1.clone(), open3],
+            SEC(200),
+            |order_id| Err(anyhow!("network error {}", order_id)),
+        );
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].order_id, "1");
+
+        // 取りこぼした約定は履歴の累計約定量のまま返し、反映時に反映済みの分を差し引く
+        let mut local1 = open1.clone();
+        local1.execute_size = dec![0.04];
+        let mut filled = open1;
+        filled.status = OrderStatus::Filled;
+        filled.execute_size = dec![0.1];
+        let updated = Session::reconcile_open_orders("session", &vec![local1.clone()], &vec![], SEC(200), |_| Ok(filled.clone()));
+        assert_eq!(updated[0].status, OrderStatus::Filled);
+        assert_eq!(updated[0].execute_size, dec![0.1]);
 
+        let delta = Session::reconciled_delta(&local1, &updated[0]).unwrap();
+        assert_eq!(delta.execute_size, dec![0.06]);
+        assert!(Session::reconciled_delta(&filled, &filled).is_none());
+    }
+
+    #[test]
+    fn test_reconcile_missed_fill_updates_profit() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let (exchange, market) = stub_objects(py, RECONCILE_STUB_SOURCE, linear_config())?;
+            let mut session = Session::new(&exchange, &market, ExecuteMode::Real, false, Some("session"), true);
+
+            let mut buy = snapshot_order("1", OrderSide::Buy);
+            buy.status = OrderStatus::Filled;
+            buy.execute_price = dec![100.0];
+            buy.execute_size = dec![0.1];
+            session.on_order_update(&mut buy);
+
+            // 発注直後の猶予(RECONCILE_GRACE_SEC)を過ぎた注文
+            let mut sell = snapshot_order("2", OrderSide::Sell);
+            sell.order_price = dec![110.0];
+            sell.create_time = SEC(1);
+            session.on_order_update(&mut sell);
+            assert_eq!(session.sell_orders.len(), 1);
+
+            // WebSocketで取りこぼした約定(約定済みなのでオープンオーダーには載らず、注文履歴にのみある)
+            let mut filled = sell.clone();
+            filled.status = OrderStatus::Filled;
+            filled.execute_price = dec![110.0];
+            filled.execute_size = dec![0.1];
+            filled.remain_size = dec![0.0];
+            let history = pyo3::types::PyDict::new_bound(py);
+            history.set_item("2", filled.into_py(py))?;
+            exchange.setattr("orders", Vec::<Order>::new().into_py(py))?;
+            exchange.setattr("history", history)?;
+
+            let updated = session.reconcile_orders()?;
+            assert_eq!(updated.len(), 1);
+            assert_eq!(session.sell_orders.len(), 0);
+            assert_eq!(session.psudo_position, dec![0.0]);
+            assert_eq!(session.profit, dec![1.0]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_reconciled_order_after_ws_fill() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let (exchange, market) = stub_objects(py, STUB_SOURCE, linear_config())?;
+            let mut session = Session::new(&exchange, &market, ExecuteMode::Real, false, Some("session"), true);
+
+            let mut buy = snapshot_order("1", OrderSide::Buy);
+            buy.order_price = dec![100.0];
+            session.on_order_update(&mut buy);
+
+            // 照合で取得した時点では0.04だけ約定
+            let mut partial = buy.clone();
+            partial.status = OrderStatus::PartiallyFilled;
+            partial.execute_price = dec![100.0];
+            partial.execute_size = dec![0.04];
+            partial.remain_size = dec![0.06];
+
+            // 照合のメッセージが届く前にWSで同じ約定が反映される
+            session.on_message(&MarketMessage::Order(partial.clone()));
+            assert_eq!(session.psudo_position, dec![0.04]);
+
+            let applied = session.on_message(&MarketMessage::ReconciledOrder(partial.clone()));
+            assert!(applied.is_empty());
+            assert_eq!(session.psudo_position, dec![0.04]);
+
+            // 残りの約定はWSで取りこぼし、照合で累計0.1の約定として届く
+            let mut filled = partial;
+            filled.status = OrderStatus::Filled;
+            filled.execute_size = dec![0.1];
+            filled.remain_size = dec![0.0];
+
+            let applied = session.on_message(&MarketMessage::ReconciledOrder(filled.clone()));
+            assert_eq!(applied.len(), 1);
+            assert_eq!(applied[0].execute_size, dec![0.06]);
+            assert_eq!(session.psudo_position, dec![0.1]);
+            assert_eq!(session.buy_orders.len(), 0);
+
+            // 同じ照合結果が重複して届いても反映しない
+            let applied = session.on_message(&MarketMessage::ReconciledOrder(filled));
+            assert!(applied.is_empty());
+            assert_eq!(session.psudo_position, dec![0.1]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_position_notional_and_margin() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let mut session = backtest_session(py, linear_config())?;
+            session.psudo_position = dec![-0.5];
+
+            assert_eq!(session.position_notional(dec![60000.0]), dec![30000.0]);
+            assert_eq!(session.estimated_margin(dec![10.0], Some(dec![60000.0]))?, dec![3000.0]);
+            assert!(session.estimated_margin(dec![0.0], Some(dec![60000.0])).is_err());
+
+            // 価格未受信
+            assert!(session.estimated_margin(dec![10.0], None).is_err());
+            session.bid_edge = dec![59999.0];
+            session.ask_edge = dec![60001.0];
+            assert_eq!(session.estimated_margin(dec![10.0], None)?, dec![3000.0]);
+
+            // inverseはbase建て
+            session.market_config.trade_category = "inverse".to_string();
+            session.psudo_position = dec![30000.0];
+            assert_eq!(session.position_notional(dec![60000.0]), dec![0.5]);
+            assert_eq!(session.estimated_margin(dec![5.0], Some(dec![60000.0]))?, dec![0.1]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_multi_market_key() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let market_of = |exchange_name: &str, category: &str| -> anyhow::Result<Bound<PyAny>> {
+                let mut config = linear_config();
+                config.exchange_name = exchange_name.to_string();
+                config.trade_category = category.to_string();
+                let (_exchange, market) = stub_objects(py, STUB_SOURCE, config)?;
+                Ok(market)
+            };
+
+            let (exchange, _market) = stub_objects(py, STUB_SOURCE, linear_config())?;
+            let primary = market_of("BYBIT", "linear")?;
+            let mut session = Session::new(&exchange, &primary, ExecuteMode::BackTest, false, Some("session"), true);
+
+            // 同じsymbolでも取引所・カテゴリが違えば別のマーケット
+            assert!(session.add_market(&market_of("BYBIT", "linear")?).is_err());
+            session.add_market(&market_of("BYBIT", "spot")?)?;
+            session.add_market(&market_of("BINANCE", "spot")?)?;
+            assert_eq!(session.get_market_symbols().len(), 3);
+
+            // 取引所・カテゴリで区別できない場合はエラー
+            assert!(session.market_position("BTCUSDT", None, Some("spot")).is_err());
+
+            let trade = |price| {
+                MarketMessage::Trade(Trade::new(SEC(100), OrderSide::Buy, price, dec![1.0], LogStatus::UnFix, "t"))
+            };
+            let message = |exchange: &str, category: &str, msg| BroadcastMessage {
+                exchange: exchange.to_string(),
+                category: category.to_string(),
+                symbol: "BTCUSDT".to_string(),
+                msg,
+            };
+
+            session.on_broadcast_message(&message("BYBIT", "spot", trade(dec![100.0])));
+            session.on_broadcast_message(&message("BINANCE", "spot", trade(dec![101.0])));
+
+            let bybit = session.market_position("BTCUSDT", Some("BYBIT"), Some("spot"))?;
+            let binance = session.market_position("BTCUSDT", Some("BINANCE"), None)?;
+            assert_eq!(bybit.last_price, dec![100.0]);
+            assert_eq!(binance.last_price, dec![101.0]);
+
+            // primary宛はon_messageで処理する
+            assert_eq!(session.market_config("BTCUSDT", None, None)?.trade_category, "linear");
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_validate_order_id_prefix() {
+        assert!(validate_order_id_prefix("", 20).is_ok());
+        assert!(validate_order_id_prefix("mm-01", 20).is_ok());
+        assert!(validate_order_id_prefix(&"a".repeat(15), 20).is_ok());
+
+        // prefix-local_idが36文字を超える
+        assert!(validate_order_id_prefix(&"a".repeat(16), 20).is_err());
+        assert!(validate_order_id_prefix(&"a".repeat(36), 20).is_err());
+        assert!(validate_order_id_prefix("mm_01", 20).is_err());
+        assert!(validate_order_id_prefix("mm 01", 20).is_err());
+        assert!(validate_order_id_prefix("mm.01", 20).is_err());
+        assert!(validate_order_id_prefix("注文", 20).is_err());
+    }
+
+    #[test]
+    fn test_order_id_prefix() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let mut config = linear_config();
+            config.price_unit = dec![0.1];
+            config.size_unit = dec![0.001];
+            let mut session = backtest_session(py, config)?;
+
+            assert!(session.set_order_id_prefix("bad_prefix").is_err());
+            assert_eq!(session.get_order_id_prefix(), "");
+
+            session.set_order_id_prefix("mm-01")?;
+            let orders = session.dummy_limit_order("Buy".to_string(), dec![100.0], dec![0.1], None, false)?;
+            let id = orders[0].client_order_id.clone();
+            assert!(id.starts_with("mm-01-session-"));
+
+            let (prefix, local_id) = session.parse_client_order_id(&id).unwrap();
+            assert_eq!(prefix, "mm-01");
+            assert!(local_id.starts_with("session-"));
+
+            assert!(session.parse_client_order_id("other-session-abc0001").is_none());
+            assert!(session.parse_client_order_id("mm-012-session-abc0001").is_none());
+
+            // prefix付きの注文もこのSessionの注文として約定する
+            assert!(orders[0].is_my_order("session"));
+            let tick = |time: i64, price: Decimal| {
+                MarketMessage::Trade(Trade::new(SEC(time), OrderSide::Sell, price, dec![1.0], LogStatus::UnFix, ""))
+            };
+            session.on_message(&tick(1, dec![99.0]));
+            session.on_message(&tick(2, dec![99.0]));
+
+            assert_eq!(session.psudo_position, dec![0.1]);
+            let fills = session.get_fills();
+            assert_eq!(fills.len(), 1);
+            assert_eq!(fills[0].client_order_id, id);
+
+            // 30文字のprefixは36文字を超えるidになるのでエラー
+            assert!(session.set_order_id_prefix(&"a".repeat(30)).is_err());
+            assert_eq!(session.get_order_id_prefix(), "mm-01");
+
+            // 収まる最長のprefixなら発行したidはちょうど36文字
+            let local_len = session.local_order_id(session.order_number + 1).len();
+            let long_prefix = "a".repeat(36 - 1 - local_len);
+            session.set_order_id_prefix(&long_prefix)?;
+            let orders = session.dummy_limit_order("Buy".to_string(), dec![100.0], dec![0.1], None, false)?;
+            assert_eq!(orders[0].client_order_id.len(), 36);
+            assert!(orders[0].client_order_id.starts_with(&long_prefix));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_open_order_cache() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let mut session = backtest_session(py, linear_config())?;
+            assert!(session.get_open_orders().is_empty());
+
+            let mut mine = snapshot_order("1", OrderSide::Buy);
+            mine.create_time = SEC(2);
+            let mut other = snapshot_order("2", OrderSide::Sell);
+            other.client_order_id = "other-2".to_string();
+            other.create_time = SEC(1);
+            let mut other_symbol = snapshot_order("3", OrderSide::Sell);
+            other_symbol.symbol = "ETHUSDT".to_string();
+
+            session.on_message(&MarketMessage::Order(mine.clone()));
+            session.on_message(&MarketMessage::Order(other.clone()));
+            session.on_message(&MarketMessage::Order(other_symbol));
+
+            // 他のセッションの注文は含まない
+            let orders = session.get_open_orders();
+            assert_eq!(orders.len(), 1);
+            assert_eq!(orders[0].order_id, "1");
+
+            let mut second = snapshot_order("4", OrderSide::Sell);
+            second.create_time = SEC(1);
+            session.on_message(&MarketMessage::Order(second.clone()));
+
+            // 作成順に並ぶ
+            let orders = session.get_open_orders();
+            assert_eq!(orders.len(), 2);
+            assert_eq!(orders[0].order_id, "4");
+            assert_eq!(orders[1].order_id, "1");
+
+            let mut amended = mine.clone();
+            amended.status = OrderStatus::Amended;
+            amended.order_price = dec![101.0];
+            amended.order_size = dec![0.0];
+            session.on_message(&MarketMessage::Order(amended));
+
+            let orders = session.get_open_orders();
+            assert_eq!(orders[1].order_price, dec![101.0]);
+            assert_eq!(orders[1].order_size, dec![0.1]);
+
+            let mut filled = second.clone();
+            filled.status = OrderStatus::Filled;
+            session.on_message(&MarketMessage::Order(filled));
+
+            let orders = session.sync_open_orders()?;
+            assert_eq!(orders.len(), 1);
+            assert_eq!(orders[0].order_id, "1");
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_sync_open_orders_reconcile() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let (exchange, market) = stub_objects(py, RECONCILE_STUB_SOURCE, linear_config())?;
+            let mut session = Session::new(&exchange, &market, ExecuteMode::Real, false, Some("session"), true);
+
+            let mut buy = snapshot_order("1", OrderSide::Buy);
+            session.on_order_update(&mut buy);
+            // 発注直後の猶予(RECONCILE_GRACE_SEC)を過ぎた注文
+            let mut sell = snapshot_order("2", OrderSide::Sell);
+            sell.order_price = dec![110.0];
+            sell.create_time = SEC(1);
+            session.on_order_update(&mut sell);
+
+            // 買いはWSで取りこぼした一部約定がオープンオーダーに載っている
+            let mut partial = buy.clone();
+            partial.status = OrderStatus::PartiallyFilled;
+            partial.execute_price = dec![100.0];
+            partial.execute_size = dec![0.04];
+            partial.remain_size = dec![0.06];
+            // 売りは約定済みでオープンオーダーには載らず、注文履歴にのみある
+            let mut filled = sell.clone();
+            filled.status = OrderStatus::Filled;
+            filled.execute_price = dec![110.0];
+            filled.execute_size = dec![0.1];
+            filled.remain_size = dec![0.0];
+            let mut other = snapshot_order("9", OrderSide::Sell);
+            other.client_order_id = "other-9".to_string();
+
+            let history = pyo3::types::PyDict::new_bound(py);
+            history.set_item("2", filled.into_py(py))?;
+            exchange.setattr("orders", vec![partial, other].into_py(py))?;
+            exchange.setattr("history", history)?;
+
+            let orders = session.sync_open_orders()?;
+            assert_eq!(orders.len(), 1);
+            assert_eq!(orders[0].order_id, "1");
+            assert_eq!(orders[0].execute_size, dec![0.04]);
+
+            assert_eq!(session.buy_orders.len(), 1);
+            assert_eq!(session.sell_orders.len(), 0);
+            assert_eq!(session.psudo_position, dec![0.04] - dec![0.1]);
+
+            // 同じ内容で取り直しても二重に反映しない
+            session.sync_open_orders()?;
+            assert_eq!(session.psudo_position, dec![0.04] - dec![0.1]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_apply_time_in_force() {
+        let order = snapshot_order("1", OrderSide::Buy);
+
+        // GTC: 板と交差しなければそのまま板に残る(Maker)
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::GTC, dec![0.0], dec![99.0]);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].status, OrderStatus::New);
+
+        // 交差する場合は即時約定(Taker)
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::GTC, dec![0.1], dec![99.0]);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].status, OrderStatus::Filled);
+        assert!(!orders[0].is_maker);
+        // 約定価格は指値ではなく板の価格
+        assert_eq!(orders[0].execute_price, dec![99.0]);
+        assert_eq!(orders[0].quote_vol, dec![99.0] * dec![0.1]);
+
+        // 一部だけ交差する場合、残りは板に残る
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::GTC, dec![0.04], dec![99.0]);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].status, OrderStatus::PartiallyFilled);
+        assert_eq!(orders[0].execute_size, dec![0.04]);
+        assert_eq!(orders[0].remain_size, dec![0.06]);
+
+        // PostOnly: Takerになる場合はキャンセル
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::PostOnly, dec![0.0], dec![99.0]);
+        assert_eq!(orders[0].status, OrderStatus::New);
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::PostOnly, dec![0.05], dec![99.0]);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].status, OrderStatus::Canceled);
+
+        // IOC: 約定できる分だけ約定して残りはキャンセル
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::IOC, dec![0.04], dec![99.0]);
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders[0].status, OrderStatus::PartiallyFilled);
+        assert_eq!(orders[0].execute_size, dec![0.04]);
+        assert_eq!(orders[1].status, OrderStatus::Canceled);
+        assert_eq!(orders[1].remain_size, dec![0.06]);
+
+        // FOK: 全量約定できなければ全てキャンセル
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::FOK, dec![0.04], dec![99.0]);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].status, OrderStatus::Canceled);
+        let orders = Session::apply_time_in_force(order.clone(), TimeInForce::FOK, dec![0.1], dec![99.0]);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].status, OrderStatus::Filled);
+        assert_eq!(orders[0].execute_size, dec![0.1]);
+    }
+
+    #[test]
+    fn test_backtest_maker_taker_fee() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let mut config = linear_config();
+            config.price_unit = dec![0.1];
+            config.size_unit = dec![0.001];
+            config.fee_type = FeeType::Home;
+            config.maker_fee = dec![0.0001];
+            config.taker_fee = dec![0.0006];
+            let mut session = backtest_session(py, config)?;
+
+            let tick = |time: i64, side: OrderSide, price: Decimal| {
+                MarketMessage::Trade(Trade::new(SEC(time), side, price, dec![1.0], LogStatus::UnFix, ""))
+            };
+
+            session.on_message(&tick(1, OrderSide::Buy, dec![100.0]));
+            session.on_message(&tick(2, OrderSide::Sell, dec![99.0]));
+
+            // 板より内側に入らない指値は板に残り、約定時はMaker
+            let orders = session.dummy_limit_order("Buy".to_string(), dec![98.0], dec![0.1], None, false)?;
+            assert_eq!(orders[0].status, OrderStatus::New);
+            session.on_message(&tick(3, OrderSide::Sell, dec![99.0]));
+
+            let filled = session.on_message(&tick(4, OrderSide::Sell, dec![97.0]));
+            assert_eq!(filled.len(), 1);
+            assert_eq!(filled[0].status, OrderStatus::Filled);
+            assert!(filled[0].is_maker);
+
+            // askを超える買い指値は即時約定しTaker。約定価格はask
+            let orders = session.dummy_limit_order("Buy".to_string(), dec![101.0], dec![0.1], None, false)?;
+            assert_eq!(orders.len(), 1);
+            assert_eq!(orders[0].status, OrderStatus::Filled);
+            assert!(!orders[0].is_maker);
+            assert_eq!(orders[0].execute_price, dec![100.0]);
+            session.on_message(&tick(5, OrderSide::Sell, dec![97.0]));
+
+            let fills = session.get_fills();
+            assert_eq!(fills.len(), 2);
+            assert!(fills[0].is_maker);
+            assert_eq!(fills[0].fee_rate, dec![0.0001]);
+            assert_eq!(fills[0].fee, dec![98.0] * dec![0.1] * dec![0.0001]);
+            assert!(!fills[1].is_maker);
+            assert_eq!(fills[1].fee_rate, dec![0.0006]);
+            assert_eq!(fills[1].fee, dec![100.0] * dec![0.1] * dec![0.0006]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_backtest_funding() -> anyhow::Result<()> {
+        use rbot_lib::common::HHMM;
+        use rbot_lib::db::{FundingRateTable, FundingSimulator, DEFAULT_FUNDING_RATE};
+
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let mut config = linear_config();
+            config.home_currency = "USDT".to_string();
+            config.foreign_currency = "BTC".to_string();
+            config.price_unit = dec![0.1];
+            config.size_unit = dec![0.001];
+            let mut session = backtest_session(py, config.clone())?;
+
+            const BASE: MicroSec = 1724371200_000_000; // 2024-08-23 00:00:00 UTC
+            let tick = |time: MicroSec, side: OrderSide, price: Decimal| {
+                Trade::new(time, side, price, dec![1.0], LogStatus::UnFix, "")
+            };
+            let home_volume = |session: &Session| session.get_psudo_account().extract_pair(&config).home.volume;
+
+            session.on_message(&MarketMessage::Trade(tick(BASE + HHMM(0, 10), OrderSide::Buy, dec![100.0])));
+            session.on_message(&MarketMessage::Trade(tick(BASE + HHMM(0, 20), OrderSide::Sell, dec![99.0])));
+
+            // 0.1のlongを持つ
+            let orders = session.dummy_limit_order("Buy".to_string(), dec![101.0], dec![0.1], None, false)?;
+            assert_eq!(orders[0].status, OrderStatus::Filled);
+            session.on_message(&MarketMessage::Trade(tick(BASE + HHMM(0, 25), OrderSide::Sell, dec![99.0])));
+            assert_eq!(session.psudo_position, dec![0.1]);
+
+            let mut table = FundingRateTable::new(DEFAULT_FUNDING_RATE);
+            table.add(BASE + HHMM(16, 0), dec![-0.0002]);
+            let mut simulator = FundingSimulator::new(table);
+
+            // Runnerと同じく約定ごとに精算時刻を確認し、過ぎていればその約定価格で精算する
+            let mut balances = vec![];
+            for (h, price) in [(0, dec![100.0]), (4, dec![100.0]), (8, dec![110.0]), (12, dec![120.0]), (16, dec![130.0])] {
+                let trade = tick(BASE + HHMM(h, 30), OrderSide::Sell, price);
+
+                for (_time, rate) in simulator.due(trade.time) {
+                    session.apply_funding(rate, trade.price);
+                }
+                session.on_message(&MarketMessage::Trade(trade));
+
+                balances.push(home_volume(&session));
+            }
+
+            // 00:30, 04:30は精算なし
+            assert_eq!(balances[1], balances[0]);
+            // 08:00の精算(08:30の約定で反映): 0.1 * 110 * 0.0001を支払う
+            assert_eq!(balances[2], balances[1] - dec![0.0011]);
+            assert_eq!(balances[3], balances[2]);
+            // 16:00はrate -0.0002で受け取り: 0.1 * 130 * 0.0002
+            assert_eq!(balances[4], balances[3] + dec![0.0026]);
+
+            assert_eq!(session.total_funding_paid(), dec![0.0011] - dec![0.0026]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_performance_attribution() -> anyhow::Result<()> {
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| -> anyhow::Result<()> {
+            let mut config = linear_config();
+            config.price_unit = dec![0.1];
+            config.size_unit = dec![0.001];
+            config.maker_fee = dec![0.0];
+            config.taker_fee = dec![0.0];
+            let mut session = backtest_session(py, config)?;
+            // 売買のtickが揃った時点(2秒ごと)の損益を記録する
+            session.set_pnl_sample_interval(SEC(2))?;
+
+            // 仲値がpriceになるよう売買両方のtickを流す
+            let mut time = 0;
+            let mut move_to = |session: &mut Session, price: Decimal| {
+                time += 1;
+                session.on_message(&MarketMessage::Trade(Trade::new(
+                    SEC(time), OrderSide::Sell, price - dec![0.05], dec![1.0], LogStatus::UnFix, "",
+                )));
+                time += 1;
+                session.on_message(&MarketMessage::Trade(Trade::new(
+                    SEC(time), OrderSide::Buy, price + dec![0.05], dec![1.0], LogStatus::UnFix, "",
+                )));
+            };
+
+            move_to(&mut session, dec![100.0]);
+            assert!(session.performance_attribution().is_err());
+
+            // 最初に買って持ち続けるだけの戦略
+            session.dummy_market_order("Buy".to_string(), dec![1.0], false)?;
+            move_to(&mut session, dec![100.0]);
+            assert_eq!(session.psudo_position, dec![1.0]);
+
+            let prices = [
+                dec![100.0], dec![101.0], dec![100.5], dec![102.0], dec![101.5],
+                dec![103.0], dec![102.0], dec![104.0], dec![103.5], dec![105.0],
+            ];
+            for price in prices.iter() {
+                move_to(&mut session, *price);
+            }
+
+            let report = session.performance_attribution()?;
+            let market_return = (dec![105.0] - dec![100.0]) / dec![100.0];
+            let expected_beta_pnl = (market_return * dec![100.0] * session.psudo_position).to_f64().unwrap();
+
+            // 買う前の記録(損益0)から最後の仲値での時価評価まで
+            assert_eq!(report.total_pnl, dec![105.0] - session.average_price);
+            assert!((report.beta_pnl.to_f64().unwrap() - expected_beta_pnl).abs() < 0.1);
+            assert!(report.alpha_pnl.to_f64().unwrap().abs() < 0.1);
+            assert!(report.r_squared > 0.99);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_make_amended_order() {
+        let mut order = snapshot_order("amend-1", OrderSide::Buy);
+        order.order_size = dec![1.0];
+        order.remain_size = dec![0.4];
+        order.status = OrderStatus::PartiallyFilled;
+
+        let amended = Session::make_amended_order(&order, dec![101.0], Some(dec![2.0]), 10).unwrap();
+        assert_eq!(amended.status, OrderStatus::Amended);
+        assert_eq!(amended.order_price, dec![101.0]);
+        assert_eq!(amended.order_size, dec![2.0]);
+        assert_eq!(amended.remain_size, dec![1.4]);
+        assert_eq!(amended.update_time, 10);
+
+        // 数量を指定しなければ価格だけ変わる
+        let amended = Session::make_amended_order(&order, dec![99.0], None, 10).unwrap();
+        assert_eq!(amended.order_size, dec![1.0]);
+        assert_eq!(amended.remain_size, dec![0.4]);
+
+        // 約定済み以下には減らせない
+        assert!(Session::make_amended_order(&order, dec![99.0], Some(dec![0.6]), 10).is_err());
+    }
+
+    #[test]
+    fn test_reduce_only() {
+        // ロングポジションはSellでのみ減らせる
+        assert_eq!(Session::calc_reducible_size(OrderSide::Sell, dec![0.3], dec![0.1]), dec![0.1]);
+        assert_eq!(Session::calc_reducible_size(OrderSide::Sell, dec![0.05], dec![0.1]), dec![0.05]);
+        assert_eq!(Session::calc_reducible_size(OrderSide::Buy, dec![0.3], dec![0.1]), dec![0.0]);
+        // ショートポジションはBuyでのみ減らせる
+        assert_eq!(Session::calc_reducible_size(OrderSide::Buy, dec![0.3], dec![-0.2]), dec![0.2]);
+        assert_eq!(Session::calc_reducible_size(OrderSide::Sell, dec![0.3], dec![0.0]), dec![0.0]);
+
+        // 約定時にポジションを超える分は切り詰めて残りはキャンセル
+        let mut order = snapshot_order("1", OrderSide::Sell);
+        order.reduce_only = true;
+        order.status = OrderStatus::Filled;
+        order.execute_price = dec![100.0];
+        order.execute_size = dec![0.1];
+        Session::clip_reduce_only_fill(&mut order, dec![0.04]);
+        assert_eq!(order.status, OrderStatus::Filled);
+        assert_eq!(order.execute_size, dec![0.04]);
+        assert_eq!(order.remain_size, dec![0.0]);
+
+        let mut order = snapshot_order("2", OrderSide::Buy);
+        order.reduce_only = true;
+        order.status = OrderStatus::PartiallyFilled;
+        order.execute_size = dec![0.05];
+        Session::clip_reduce_only_fill(&mut order, dec![0.04]);
+        assert_eq!(order.status, OrderStatus::Canceled);
+        assert_eq!(order.execute_size, dec![0.0]);
+    }
 
     #[test]
     fn test_calc_ohlcv_start() -> anyhow::Result<()>{
@@ -0,0 +1,130 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use pyo3::pyfunction;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+const BPS: Decimal = dec![10000];
+
+/// ポジションサイズ計算のヘルパー
+pub struct PositionSizer;
+
+impl PositionSizer {
+    /// 損切りにかかった時の損失が口座残高のrisk_per_trade_bps以下になるポジションサイズ(建玉金額)
+    pub fn fixed_fractional(
+        account_balance: Decimal,
+        risk_per_trade_bps: u32,
+        stop_distance_bps: u32,
+    ) -> Decimal {
+        if stop_distance_bps == 0 || account_balance <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+
+        let risk = account_balance * Decimal::from(risk_per_trade_bps) / BPS;
+
+        risk * BPS / Decimal::from(stop_distance_bps)
+    }
+
+    /// Kelly基準(f = p - (1 - p) / b)にfractionをかけた値。マイナスの場合は0。
+    pub fn kelly_criterion(win_rate: f64, win_loss_ratio: f64, fraction: f64) -> f64 {
+        if win_loss_ratio <= 0.0 {
+            return 0.0;
+        }
+
+        let kelly = win_rate - (1.0 - win_rate) / win_loss_ratio;
+
+        (kelly * fraction).max(0.0)
+    }
+
+    /// レバレッジ上限で持てる最大ポジション(lot_sizeの倍数に切り捨て)
+    pub fn max_position_by_leverage(
+        account: Decimal,
+        leverage: u32,
+        price: Decimal,
+        lot_size: Decimal,
+    ) -> Decimal {
+        if price <= Decimal::ZERO || lot_size <= Decimal::ZERO || account <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+
+        let size = account * Decimal::from(leverage) / price;
+
+        (size / lot_size).floor() * lot_size
+    }
+}
+
+#[pyfunction]
+#[pyo3(name = "fixed_fractional")]
+pub fn py_fixed_fractional(
+    account_balance: Decimal,
+    risk_per_trade_bps: u32,
+    stop_distance_bps: u32,
+) -> Decimal {
+    PositionSizer::fixed_fractional(account_balance, risk_per_trade_bps, stop_distance_bps)
+}
+
+#[pyfunction]
+#[pyo3(name = "kelly_criterion")]
+pub fn py_kelly_criterion(win_rate: f64, win_loss_ratio: f64, fraction: f64) -> f64 {
+    PositionSizer::kelly_criterion(win_rate, win_loss_ratio, fraction)
+}
+
+#[pyfunction]
+#[pyo3(name = "max_position_by_leverage")]
+pub fn py_max_position_by_leverage(
+    account: Decimal,
+    leverage: u32,
+    price: Decimal,
+    lot_size: Decimal,
+) -> Decimal {
+    PositionSizer::max_position_by_leverage(account, leverage, price, lot_size)
+}
+
+#[cfg(test)]
+mod sizer_test {
+    use super::*;
+
+    #[test]
+    fn test_fixed_fractional() {
+        // 残高10000, 1%リスク, 損切り2% -> 5000
+        assert_eq!(
+            PositionSizer::fixed_fractional(dec![10000], 100, 200),
+            dec![5000]
+        );
+
+        // 0.5%リスク, 損切り0.25% -> 残高の2倍
+        assert_eq!(
+            PositionSizer::fixed_fractional(dec![1234.5], 50, 25),
+            dec![2469]
+        );
+
+        assert_eq!(PositionSizer::fixed_fractional(dec![10000], 100, 0), dec![0]);
+    }
+
+    #[test]
+    fn test_kelly_criterion() {
+        // p=0.6, b=1 -> 0.2
+        let f = PositionSizer::kelly_criterion(0.6, 1.0, 1.0);
+        assert!((f - 0.2).abs() < 1e-9);
+
+        let f = PositionSizer::kelly_criterion(0.6, 1.0, 0.5);
+        assert!((f - 0.1).abs() < 1e-9);
+
+        assert_eq!(PositionSizer::kelly_criterion(0.3, 1.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_max_position_by_leverage() {
+        // 1000 * 10 / 60000 = 0.1666.. -> 0.166
+        assert_eq!(
+            PositionSizer::max_position_by_leverage(dec![1000], 10, dec![60000], dec![0.001]),
+            dec![0.166]
+        );
+
+        assert_eq!(
+            PositionSizer::max_position_by_leverage(dec![1000], 10, dec![60000], dec![0]),
+            dec![0]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright(c) 2024. yasstake. All rights reserved.
+// ABSOLUTELY NO WARRANTY.
+
+use std::path::Path;
+
+use anyhow::Context;
+use pyo3::prelude::*;
+use pyo3::{pyclass, pymethods};
+use rbot_lib::common::{MicroSec, NOW};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// 再起動をまたいで戦略の状態(最終注文ID、平均価格など)を保存するKey-Valueストア
+/// 値はserde_jsonでシリアライズしてBLOBとして保存する。
+#[pyclass]
+#[derive(Debug)]
+pub struct StrategyStore {
+    connection: Connection,
+}
+
+impl StrategyStore {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("strategy store open error {:?}", path))?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS strategy_state (
+            key         TEXT primary key,
+            value       BLOB,
+            updated_at  INTEGER
+        )",
+            (),
+        )?;
+
+        Ok(Self { connection })
+    }
+
+    pub fn set(&self, key: &str, value: impl Serialize) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(&value)?;
+        Self::write(&self.connection, key, &bytes)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        match Self::read(&self.connection, key)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 読み出し、fで計算した値の書き込みを一つのトランザクションで行い、書き込んだ値を返す。
+    pub fn atomic_update<T>(&mut self, key: &str, f: impl Fn(Option<T>) -> T) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let tx = self
+            .connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let current = match Self::read(&tx, key)? {
+            Some(bytes) => Some(serde_json::from_slice(&bytes)?),
+            None => None,
+        };
+
+        let value = f(current);
+        Self::write(&tx, key, &serde_json::to_vec(&value)?)?;
+        tx.commit()?;
+
+        Ok(value)
+    }
+
+    pub fn updated_at(&self, key: &str) -> anyhow::Result<Option<MicroSec>> {
+        let updated_at = self
+            .connection
+            .query_row(
+                "select updated_at from strategy_state where key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(updated_at)
+    }
+
+    fn read(connection: &Connection, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let value = connection
+            .query_row(
+                "select value from strategy_state where key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(value)
+    }
+
+    fn write(connection: &Connection, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        connection.execute(
+            "insert or replace into strategy_state (key, value, updated_at) values (?1, ?2, ?3)",
+            params![key, bytes, NOW()],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl StrategyStore {
+    /// Pythonの値はjsonモジュールで変換する(dict, list, 数値, 文字列など)
+    #[pyo3(name = "set")]
+    pub fn py_set(&self, py: Python, key: &str, value: &Bound<PyAny>) -> anyhow::Result<()> {
+        let json: String = py
+            .import_bound("json")?
+            .call_method1("dumps", (value,))?
+            .extract()?;
+
+        Self::write(&self.connection, key, json.as_bytes())
+    }
+
+    #[pyo3(name = "get")]
+    pub fn py_get(&self, py: Python, key: &str) -> anyhow::Result<PyObject> {
+        match Self::read(&self.connection, key)? {
+            Some(bytes) => {
+                let json = String::from_utf8(bytes)?;
+                let value = py.import_bound("json")?.call_method1("loads", (json,))?;
+
+                Ok(value.unbind())
+            }
+            None => Ok(py.None()),
+        }
+    }
+
+    pub fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        let count = self
+            .connection
+            .execute("delete from strategy_state where key = ?1", params![key])?;
+
+        Ok(0 < count)
+    }
+
+    pub fn keys(&self) -> anyhow::Result<Vec<String>> {
+        let mut statement = self
+            .connection
+            .prepare("select key from strategy_state order by key")?;
+
+        let keys = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod store_test {
+    use super::*;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Leg {
+        symbol: String,
+        prices: Vec<f64>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct State {
+        last_order_id: Option<String>,
+        legs: HashMap<String, Leg>,
+        count: i64,
+    }
+
+    #[test]
+    fn test_nested_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("store.db");
+
+        let mut legs = HashMap::new();
+        legs.insert(
+            "long".to_string(),
+            Leg {
+                symbol: "BTCUSDT".to_string(),
+                prices: vec![60000.5, 60001.0],
+            },
+        );
+        let state = State {
+            last_order_id: Some("abc".to_string()),
+            legs,
+            count: 3,
+        };
+
+        {
+            let store = StrategyStore::open(&path)?;
+            store.set("state", &state)?;
+            assert!(store.updated_at("state")?.is_some());
+        }
+
+        // 開きなおしても読める
+        let store = StrategyStore::open(&path)?;
+        assert_eq!(store.get::<State>("state")?, Some(state));
+        assert_eq!(store.get::<State>("unknown")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_update() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut store = StrategyStore::open(&dir.path().join("store.db"))?;
+
+        for _ in 0..3 {
+            store.atomic_update("count", |v: Option<i64>| v.unwrap_or(0) + 1)?;
+        }
+
+        assert_eq!(store.get::<i64>("count")?, Some(3));
+        assert_eq!(store.keys()?, vec!["count".to_string()]);
+        assert!(store.delete("count")?);
+        assert_eq!(store.get::<i64>("count")?, None);
+
+        Ok(())
+    }
+}
@@ -4,14 +4,17 @@
 
 use pyo3::{pymodule, types::PyModule, wrap_pyfunction, Bound, PyResult};
 use rbot_lib::{common::{
-    get_orderbook, get_orderbook_list, init_debug_log, init_log, time_string, AccountCoins, AccountPair, 
-        BoardItem, FeeType, MarketConfig, Order, OrderSide, OrderStatus, OrderType, 
-        ExchangeConfig, Trade, DAYS, DAYS_BEFORE, FLOOR_SEC, HHMM, MIN, NOW, SEC
-}, db::{__delete_data_root, get_data_root, set_data_root}};
-
-use rbot_session::{Logger, Session, Runner, ExecuteMode};
-use bybit::{Bybit, BybitConfig};
-use binance::{Binance, BinanceConfig};
+    get_orderbook, get_orderbook_list, get_progress_mode, init_debug_log, init_file_log, init_log, set_progress_mode, time_string, ProgressMode, AccountCoins, AccountPair, 
+        BoardDiff, BoardEventStream, BoardEventType, BoardItem, BoardLevelDiff, FeeType, MarketConfig, Order, OrderSide, OrderStatus, OrderType, PositionInfo, TickerInfo, Fill,
+        Channel, ChannelType, ExchangeConfig, KlineInterval, Symbol, SymbolKind, TimeInForce, Trade, py_detect_patterns, py_detect_regime, py_hurst_exponent, DAYS, DAYS_BEFORE, FLOOR_SEC, HHMM, LOCAL_HHMM, MIN, NOW, SEC,
+        set_local_timezone, get_local_timezone, exceptions, OhlcvBar
+}, db::{__delete_data_root, get_data_root, set_data_root, CacheStats, ColumnStyle, Ohlcvb, RangeStats, TradeSummary, FundingRateTable, HeatMap, TimeFormat}, net::{get_http_timeout, set_http_timeout}};
+
+use rbot_session::{Logger, Session, Runner, ExecuteMode, MarketPosition, StrategyStore, AttributionReport, py_fixed_fractional, py_kelly_criterion, py_max_position_by_leverage};
+use rbot_market::{py_report_gaps, DivergenceEvent, DivergenceStream, HealthStatus, OhlcvStream, PriceDivergenceMonitor, TradeStream};
+use bybit::{Bybit, BybitConfig, FeeTier, PositionMode, SpotMarginConfig};
+use binance::{Binance, BinanceCoinm, BinanceCoinmConfig, BinanceConfig, ContractType};
+use hyperliquid::{Hyperliquid, HyperliquidConfig};
 
 // use binance::{Binance, BinanceConfig};
 
@@ -27,9 +30,15 @@ fn rbot(m: &Bound<'_, PyModule>) -> PyResult<()> {
 
     m.add_function(wrap_pyfunction!(get_data_root, m)?)?;
     m.add_function(wrap_pyfunction!(set_data_root, m)?)?;
+    m.add_function(wrap_pyfunction!(set_http_timeout, m)?)?;
+    m.add_function(wrap_pyfunction!(get_http_timeout, m)?)?;
 
     m.add_function(wrap_pyfunction!(init_log, m)?)?;
     m.add_function(wrap_pyfunction!(init_debug_log, m)?)?;
+    m.add_function(wrap_pyfunction!(init_file_log, m)?)?;
+
+    m.add_function(wrap_pyfunction!(set_progress_mode, m)?)?;
+    m.add_function(wrap_pyfunction!(get_progress_mode, m)?)?;
 
     m.add_function(wrap_pyfunction!(get_orderbook_list, m)?)?;
     m.add_function(wrap_pyfunction!(get_orderbook, m)?)?;
@@ -40,42 +49,106 @@ fn rbot(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(DAYS_BEFORE, m)?)?;
     m.add_function(wrap_pyfunction!(DAYS, m)?)?;
     m.add_function(wrap_pyfunction!(HHMM, m)?)?;
+    m.add_function(wrap_pyfunction!(LOCAL_HHMM, m)?)?;
     m.add_function(wrap_pyfunction!(MIN, m)?)?;
     m.add_function(wrap_pyfunction!(SEC, m)?)?;
+    m.add_function(wrap_pyfunction!(set_local_timezone, m)?)?;
+    m.add_function(wrap_pyfunction!(get_local_timezone, m)?)?;
 
     m.add_function(wrap_pyfunction!(FLOOR_SEC, m)?)?;
 
+    m.add_function(wrap_pyfunction!(py_detect_patterns, m)?)?;
+    m.add_function(wrap_pyfunction!(py_detect_regime, m)?)?;
+    m.add_function(wrap_pyfunction!(py_hurst_exponent, m)?)?;
+
+    m.add_function(wrap_pyfunction!(py_fixed_fractional, m)?)?;
+    m.add_function(wrap_pyfunction!(py_kelly_criterion, m)?)?;
+    m.add_function(wrap_pyfunction!(py_max_position_by_leverage, m)?)?;
+    m.add_function(wrap_pyfunction!(py_report_gaps, m)?)?;
+
     m.add_function(wrap_pyfunction!(__delete_data_root, m)?)?;
 
 
     // classes
     m.add_class::<ExchangeConfig>()?;
     m.add_class::<MarketConfig>()?;
+    m.add_class::<ProgressMode>()?;
     m.add_class::<OrderStatus>()?;
     m.add_class::<AccountPair>()?;
     m.add_class::<AccountCoins>()?;    
+    m.add_class::<PositionInfo>()?;
+    m.add_class::<TickerInfo>()?;
+    m.add_class::<Fill>()?;
+    m.add_class::<ColumnStyle>()?;
+    m.add_class::<CacheStats>()?;
+    m.add_class::<RangeStats>()?;
+    m.add_class::<TradeSummary>()?;
+    m.add_class::<Ohlcvb>()?;
+    m.add_class::<HeatMap>()?;
+    m.add_class::<TimeFormat>()?;
+    m.add_class::<FundingRateTable>()?;
+    m.add_class::<MarketPosition>()?;
+    m.add_class::<StrategyStore>()?;
     
     m.add_class::<Logger>()?;
 
     m.add_class::<Order>()?;
     m.add_class::<OrderSide>()?;
     m.add_class::<OrderType>()?;
+    m.add_class::<TimeInForce>()?;
+    m.add_class::<Symbol>()?;
+    m.add_class::<SymbolKind>()?;
     m.add_class::<Trade>()?;
+    m.add_class::<KlineInterval>()?;
+    m.add_class::<TradeStream>()?;
+    m.add_class::<OhlcvStream>()?;
+    m.add_class::<PriceDivergenceMonitor>()?;
+    m.add_class::<DivergenceEvent>()?;
+    m.add_class::<DivergenceStream>()?;
+    m.add_class::<OhlcvBar>()?;
+    m.add_class::<HealthStatus>()?;
     m.add_class::<BoardItem>()?;
+    m.add_class::<BoardDiff>()?;
+    m.add_class::<BoardLevelDiff>()?;
+    m.add_class::<BoardEventType>()?;
+    m.add_class::<BoardEventStream>()?;
 
     m.add_class::<Session>()?;
     m.add_class::<Runner>()?;
     m.add_class::<ExecuteMode>()?;
+    m.add_class::<AttributionReport>()?;
 
     m.add_class::<FeeType>()?;
+    m.add_class::<Channel>()?;
+    m.add_class::<ChannelType>()?;
+
+    // Market exceptions
+    let py = m.py();
+    m.add("MarketError", py.get_type_bound::<exceptions::MarketError>())?;
+    m.add("NetworkError", py.get_type_bound::<exceptions::NetworkError>())?;
+    m.add("AuthError", py.get_type_bound::<exceptions::AuthError>())?;
+    m.add("RateLimited", py.get_type_bound::<exceptions::RateLimited>())?;
+    m.add("InvalidOrder", py.get_type_bound::<exceptions::InvalidOrder>())?;
+    m.add("NotFound", py.get_type_bound::<exceptions::NotFound>())?;
+    m.add("ExchangeError", py.get_type_bound::<exceptions::ExchangeError>())?;
 
     // Binance
     m.add_class::<Binance>()?;
     m.add_class::<BinanceConfig>()?;
+    m.add_class::<BinanceCoinm>()?;
+    m.add_class::<BinanceCoinmConfig>()?;
+    m.add_class::<ContractType>()?;
+
+    // Hyperliquid
+    m.add_class::<Hyperliquid>()?;
+    m.add_class::<HyperliquidConfig>()?;
     
     // ByBit
     m.add_class::<Bybit>()?;
-    m.add_class::<BybitConfig>()?;    
+    m.add_class::<BybitConfig>()?;
+    m.add_class::<FeeTier>()?;
+    m.add_class::<PositionMode>()?;
+    m.add_class::<SpotMarginConfig>()?;
 
 
     Ok(())